@@ -4,14 +4,14 @@ get_max_nodecount () {
 
 get_largest_files () {
     T=$(get_max_nodecount $1)
-    echo $T | cut -d',' -f6
+    echo $T | awk -F',' '{print $NF}'
 }
 
 perform () {
     T=$(get_max_nodecount $1)
-    echo $T | cut -d',' -f6
-    echo $T | cut -d',' -f6 | xargs -I {} ./plot_stgsize.r {}
-    mv "$(echo $T | cut -d',' -f6 | xargs -I {} basename -s .stgsize {})_nodes.png" $1_nodes.png
+    echo $T | awk -F',' '{print $NF}'
+    echo $T | awk -F',' '{print $NF}' | xargs -I {} ./plot_stgsize.r {}
+    mv "$(echo $T | awk -F',' '{print $NF}' | xargs -I {} basename -s .stgsize {})_nodes.png" $1_nodes.png
 }
 
 # perform copter
@@ -0,0 +1,20 @@
+//! Exposes the enabled cargo features and the git revision to the campaign manifest
+use std::process::Command;
+
+fn main() {
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|x| x.to_lowercase()))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=FRET_FEATURES={}", features.join(","));
+
+    let describe = Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .map(|x| String::from_utf8_lossy(&x.stdout).trim().to_string());
+    if let Some(describe) = describe {
+        println!("cargo:rustc-env=FRET_GIT_DESCRIBE={}", describe);
+    }
+}
@@ -4,6 +4,29 @@ int BREAKPOINT() {
   }
 }
 
+// Optional stops which FRET classifies instead of treating them as crashes,
+// the names can be changed with the FUZZ_ABORT, FUZZ_FAIL and IDLE_HANG settings.
+// Reported as a crash
+void FUZZ_ABORT() {
+  for (;;)
+  {
+  }
+}
+
+// A failed test, the execution counts as regular but its testcase is flagged (an objective with --fail-objective)
+void FUZZ_FAIL() {
+  for (;;)
+  {
+  }
+}
+
+// Jump here if the scheduler would idle forever, reported as a timeout
+void IDLE_HANG() {
+  for (;;)
+  {
+  }
+}
+
 int LLVMFuzzerTestOneInput(unsigned int* Data, unsigned int Size) {
   //if (Data[3] == 0) {while(1){}}  // cause a timeout
   for (int i=0; i<Size; i++) {
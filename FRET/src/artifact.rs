@@ -0,0 +1,182 @@
+//! Worst-case inputs packaged for artifact evaluation, written with `fret -k .. -c .. export-artifact -i corpus -o artifacts`.
+//! Every input gets a directory with `input.case`, `input.edit.ron` and the expected times in `expected.toml`,
+//! `verify-artifact` runs them again and compares, see [`verify`].
+use libafl::inputs::{multi::MultipartInput, BytesInput, Input};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+use crate::runner::TargetRunner;
+use crate::systemstate::input_view::{interrupt_times_to_comments, unfold_input};
+use crate::systemstate::target_os::SystemTraceData;
+use crate::time::clock::qemu_icount_shift;
+
+/// File name of the [`Expectation`] in an artifact directory
+pub const EXPECTED_FILE: &str = "expected.toml";
+pub const CASE_FILE: &str = "input.case";
+pub const EDIT_FILE: &str = "input.edit.ron";
+
+/// What an input did when it was exported, everything needed to run it again on the same kernel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Expectation {
+    /// File the input was exported from
+    pub source: PathBuf,
+    pub kernel: PathBuf,
+    pub kernel_sha256: String,
+    pub config: PathBuf,
+    pub select_task: Option<String>,
+    pub icount_shift: u32,
+    /// (source, first tick) of each fuzzed interrupt source, see [`crate::runner::Target`]
+    pub interrupt_config: Vec<(usize, u32)>,
+    /// Runtime in ticks, or the WORT of `select_task`
+    pub exec_ticks: u64,
+    /// Worst response time in ticks of each task
+    pub worts: BTreeMap<String, u64>,
+}
+
+/// Runs `input` and records its times
+fn measure(runner: &mut TargetRunner, input: &MultipartInput<BytesInput>) -> (u64, BTreeMap<String, u64>) {
+    let result = runner.run(input);
+    let worts = result.trace.as_ref()
+        .map(|t| t.worst_jobs_per_task_by_response_time().into_iter().map(|(name, job)| (name, job.response_time())).collect())
+        .unwrap_or_default();
+    (result.exec_ticks, worts)
+}
+
+fn kernel_sha256(kernel: &Path) -> std::io::Result<String> {
+    Ok(format!("{:x}", Sha256::digest(fs::read(kernel)?)))
+}
+
+fn write_entry(dir: &Path, input: &MultipartInput<BytesInput>, expected: &Expectation) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    input.to_file(dir.join(CASE_FILE)).map_err(|e| e.to_string())?;
+    let edit = unfold_input(input);
+    let mut edit_str = ron::to_string(&edit).map_err(|e| e.to_string())?;
+    edit_str.push('\n');
+    edit_str.push_str(&interrupt_times_to_comments(&edit));
+    fs::write(dir.join(EDIT_FILE), edit_str).map_err(|e| e.to_string())?;
+    let toml = toml::to_string(expected).map_err(|e| e.to_string())?;
+    fs::write(dir.join(EXPECTED_FILE), toml).map_err(|e| e.to_string())
+}
+
+/// Runs every input and writes its artifact directory, named after the input file, into `output`. Returns the exit code
+pub fn export(
+    runner: &mut TargetRunner,
+    inputs: Vec<(PathBuf, MultipartInput<BytesInput>)>,
+    output: &Path,
+    select_task: Option<String>,
+) -> i32 {
+    let target = runner.target().clone();
+    let kernel_sha256 = match kernel_sha256(&target.kernel) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Can not read kernel {}: {}", target.kernel.display(), e);
+            return 1;
+        }
+    };
+    let mut failed = 0;
+    for (path, input) in &inputs {
+        let (exec_ticks, worts) = measure(runner, input);
+        let expected = Expectation {
+            source: path.clone(),
+            kernel: target.kernel.clone(),
+            kernel_sha256: kernel_sha256.clone(),
+            config: target.config.clone(),
+            select_task: select_task.clone(),
+            icount_shift: qemu_icount_shift(),
+            interrupt_config: target.interrupt_config.clone(),
+            exec_ticks,
+            worts,
+        };
+        let name = path.file_stem().map_or(String::new(), |x| x.to_string_lossy().to_string());
+        match write_entry(&output.join(&name), input, &expected) {
+            Ok(()) => println!("{}: {} ticks, {} tasks", name, exec_ticks, expected.worts.len()),
+            Err(e) => {
+                eprintln!("{}: can not write artifact: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("Exported {} of {} inputs to {}", inputs.len() - failed, inputs.len(), output.display());
+    if failed > 0 { 1 } else { 0 }
+}
+
+/// One value which differs between an artifact and its run
+struct Deviation {
+    entry: String,
+    metric: String,
+    expected: String,
+    measured: String,
+}
+
+/// Relative difference is at most `tolerance` percent
+fn within(expected: u64, measured: u64, tolerance: f64) -> bool {
+    expected.abs_diff(measured) as f64 <= expected as f64 * tolerance / 100.0
+}
+
+/// Runs every artifact directory in `input` again and compares the runtime and WORTs with its [`Expectation`].
+/// A different kernel hash, icount shift or select task counts as a deviation as well. Prints a table of all deviations and returns the exit code.
+pub fn verify(runner: &mut TargetRunner, input: &Path, tolerance: f64, select_task: Option<String>) -> i32 {
+    let kernel_sha256 = kernel_sha256(&runner.target().kernel).unwrap_or_default();
+    let mut dirs: Vec<PathBuf> = match fs::read_dir(input) {
+        Ok(x) => x.flatten().map(|x| x.path()).filter(|x| x.join(EXPECTED_FILE).is_file()).collect(),
+        Err(e) => {
+            eprintln!("Can not read {}: {}", input.display(), e);
+            return 1;
+        }
+    };
+    dirs.sort();
+    let mut deviations = Vec::new();
+    let mut broken = 0;
+    for dir in &dirs {
+        let entry = dir.file_name().map_or(String::new(), |x| x.to_string_lossy().to_string());
+        let loaded = fs::read_to_string(dir.join(EXPECTED_FILE)).map_err(|e| e.to_string())
+            .and_then(|x| toml::from_str::<Expectation>(&x).map_err(|e| e.to_string()))
+            .and_then(|x| MultipartInput::<BytesInput>::from_file(dir.join(CASE_FILE)).map(|i| (x, i)).map_err(|e| e.to_string()));
+        let (expected, case) = match loaded {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("{}: {}", entry, e);
+                broken += 1;
+                continue;
+            }
+        };
+        let mut deviate = |metric: &str, expected: String, measured: String| {
+            deviations.push(Deviation { entry: entry.clone(), metric: metric.to_string(), expected, measured });
+        };
+        if expected.kernel_sha256 != kernel_sha256 {
+            deviate("kernel_sha256", expected.kernel_sha256.clone(), kernel_sha256.clone());
+        }
+        if expected.icount_shift != qemu_icount_shift() {
+            deviate("icount_shift", expected.icount_shift.to_string(), qemu_icount_shift().to_string());
+        }
+        if expected.select_task != select_task {
+            deviate("select_task", expected.select_task.as_deref().unwrap_or("-").to_string(), select_task.as_deref().unwrap_or("-").to_string());
+        }
+        let (exec_ticks, worts) = measure(runner, &case);
+        if !within(expected.exec_ticks, exec_ticks, tolerance) {
+            deviate("exec_ticks", expected.exec_ticks.to_string(), exec_ticks.to_string());
+        }
+        for (task, wort) in &expected.worts {
+            match worts.get(task) {
+                Some(x) if within(*wort, *x, tolerance) => {}
+                Some(x) => deviate(&format!("wort {}", task), wort.to_string(), x.to_string()),
+                None => deviate(&format!("wort {}", task), wort.to_string(), "-".to_string()),
+            }
+        }
+        for (task, wort) in worts.iter().filter(|x| !expected.worts.contains_key(x.0)) {
+            deviate(&format!("wort {}", task), "-".to_string(), wort.to_string());
+        }
+    }
+    if !deviations.is_empty() {
+        let width = deviations.iter().map(|x| x.entry.len()).max().unwrap_or(0).max(5);
+        let metric_width = deviations.iter().map(|x| x.metric.len()).max().unwrap_or(0).max(6);
+        println!("{:width$}  {:metric_width$}  {:>16}  {:>16}", "entry", "metric", "expected", "measured");
+        for d in &deviations {
+            println!("{:width$}  {:metric_width$}  {:>16}  {:>16}", d.entry, d.metric, d.expected, d.measured);
+        }
+    }
+    let deviating = deviations.iter().map(|x| &x.entry).collect::<std::collections::BTreeSet<_>>().len();
+    println!("Verified {} artifacts, {} deviate, {} unreadable", dirs.len(), deviating, broken);
+    if deviating > 0 || broken > 0 { 1 } else { 0 }
+}
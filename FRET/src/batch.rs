@@ -0,0 +1,168 @@
+//! Fuzzing campaigns of several kernels from one invocation, started with `fret batch`.
+//! Every campaign runs in its own fuzzer process: QEMU can only be initialized once per process,
+//! and this way the corpus, all metadata and statics such as MAX_INPUT_SIZE or the observer maps start fresh for each kernel.
+use clap::Parser;
+use serde::Deserialize;
+use std::{env, fs, process};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+#[derive(Parser, Debug)]
+#[command(name = "batch", about = "Fuzz the kernels of a manifest one after another, or several at a time")]
+pub struct BatchCli {
+    /// ron list of campaigns, see BatchEntry. Relative paths are resolved from the directory of the manifest
+    #[arg(short, long, value_name = "FILE")]
+    pub manifest: PathBuf,
+
+    /// write the dumps and the log of each campaign into this directory, prefixed with the campaign name
+    #[arg(short, long, value_name = "DIR", default_value = ".")]
+    pub output: PathBuf,
+
+    /// number of campaigns running at the same time
+    #[arg(short, long, value_name = "N", default_value_t = 1)]
+    pub jobs: usize,
+
+    /// options given to every campaign ahead of its own, e.g. `-- -t -a -g`
+    #[arg(last = true, value_name = "FUZZER_ARGS")]
+    pub fuzzer_args: Vec<String>,
+}
+
+/// A campaign of the manifest
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchEntry {
+    pub kernel: PathBuf,
+    pub config: PathBuf,
+    /// runtime in seconds
+    pub time: u64,
+    /// prefix of the dumped files, defaults to the file stem of the kernel
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// further options of this campaign, e.g. `["-s", "T1"]`
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl BatchEntry {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.kernel.file_stem().map_or(String::new(), |x| x.to_string_lossy().to_string()))
+    }
+}
+
+/// Reads a manifest, resolving relative paths from its directory. Fails on empty manifests and duplicate names.
+pub fn load_manifest(path: &Path) -> Result<Vec<BatchEntry>, String> {
+    let raw = fs::read_to_string(path).map_err(|e| format!("Can not read manifest {}: {}", path.display(), e))?;
+    let mut entries: Vec<BatchEntry> = ron::from_str(&raw).map_err(|e| format!("Can not parse manifest {}: {}", path.display(), e))?;
+    if entries.is_empty() {
+        return Err(format!("Manifest {} lists no campaigns", path.display()));
+    }
+    let base = path.parent().unwrap_or(Path::new("."));
+    let mut names = Vec::new();
+    for e in entries.iter_mut() {
+        e.kernel = base.join(&e.kernel);
+        e.config = base.join(&e.config);
+        let name = e.name();
+        if name.is_empty() || names.contains(&name) {
+            return Err(format!("Campaign name {:?} of {} is empty or not unique, set a name", name, e.kernel.display()));
+        }
+        names.push(name);
+    }
+    Ok(entries)
+}
+
+/// Command line of a single campaign, equal to a manual `fret -k .. -c .. -n .. fuzz -t ..`
+fn campaign_command(exe: &Path, cli: &BatchCli, entry: &BatchEntry) -> Command {
+    let mut cmd = Command::new(exe);
+    cmd.args(&cli.fuzzer_args)
+        .args(&entry.args)
+        .arg("-k").arg(&entry.kernel)
+        .arg("-c").arg(&entry.config)
+        .arg("-n").arg(cli.output.join(entry.name()))
+        .arg("fuzz")
+        .arg("-t").arg(entry.time.to_string());
+    if let Some(seed) = entry.seed {
+        cmd.arg("-s").arg(seed.to_string());
+    }
+    cmd
+}
+
+/// Starts a campaign with its output going to `<name>.log` in the output directory
+fn spawn_campaign(exe: &Path, cli: &BatchCli, entry: &BatchEntry) -> std::io::Result<Child> {
+    let log = fs::File::create(cli.output.join(format!("{}.log", entry.name())))?;
+    campaign_command(exe, cli, entry)
+        .stdout(Stdio::from(log.try_clone()?))
+        .stderr(Stdio::from(log))
+        .spawn()
+}
+
+/// WORT written by a finished campaign as "ticks us", see the `.wort` dump of the fuzz command
+fn read_wort(cli: &BatchCli, entry: &BatchEntry) -> Option<(u64, u128)> {
+    let raw = fs::read_to_string(cli.output.join(entry.name()).with_extension("wort")).ok()?;
+    let mut fields = raw.split_whitespace();
+    Some((fields.next()?.parse().ok()?, fields.next()?.parse().ok()?))
+}
+
+fn print_summary(cli: &BatchCli, entries: &[BatchEntry], results: &[Option<Result<ExitStatus, String>>]) {
+    println!("{:<24} {:<16} {:>16} {:>12}", "kernel", "status", "wort [ticks]", "wort [us]");
+    for (entry, result) in entries.iter().zip(results) {
+        let status = match result {
+            Some(Ok(s)) if s.success() => "ok".to_string(),
+            Some(Ok(s)) => s.code().map_or("killed".to_string(), |c| format!("exit {}", c)),
+            Some(Err(_)) => "error".to_string(),
+            None => "-".to_string(),
+        };
+        let (ticks, us) = read_wort(cli, entry).map_or(("-".to_string(), "-".to_string()), |(t, u)| (t.to_string(), u.to_string()));
+        println!("{:<24} {:<16} {:>16} {:>12}", entry.name(), status, ticks, us);
+    }
+}
+
+pub fn run() {
+    // the binary name is replaced by "batch" for the usage messages
+    let cli = BatchCli::parse_from(env::args().skip(1));
+    let entries = load_manifest(&cli.manifest).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    fs::create_dir_all(&cli.output).expect("Can not create output directory");
+    let exe = env::current_exe().expect("Can not locate the fuzzer executable");
+
+    let mut results: Vec<Option<Result<ExitStatus, String>>> = vec![None; entries.len()];
+    let mut running: Vec<(usize, Child)> = Vec::new();
+    let mut next = 0;
+    while next < entries.len() || !running.is_empty() {
+        while running.len() < cli.jobs.max(1) && next < entries.len() {
+            let entry = &entries[next];
+            println!("Starting {} for {}s", entry.name(), entry.time);
+            match spawn_campaign(&exe, &cli, entry) {
+                Ok(child) => running.push((next, child)),
+                Err(e) => {
+                    eprintln!("Can not start {}: {}", entry.name(), e);
+                    results[next] = Some(Err(e.to_string()));
+                }
+            }
+            next += 1;
+        }
+        let mut i = 0;
+        while i < running.len() {
+            match running[i].1.try_wait() {
+                Ok(Some(status)) => {
+                    let (index, _) = running.swap_remove(i);
+                    println!("Finished {}: {}", entries[index].name(), status);
+                    results[index] = Some(Ok(status));
+                }
+                Ok(None) => i += 1,
+                Err(e) => {
+                    let (index, _) = running.swap_remove(i);
+                    eprintln!("Lost {}: {}", entries[index].name(), e);
+                    results[index] = Some(Err(e.to_string()));
+                }
+            }
+        }
+        if !running.is_empty() {
+            std::thread::sleep(Duration::from_millis(500));
+        }
+    }
+    print_summary(&cli, &entries, &results);
+}
@@ -0,0 +1,156 @@
+//! Validation of a target configuration without starting QEMU, run with `fret -k .. -c .. check`.
+//! Resolves the symbols, ranges and groups the fuzzer would use, prints them and reports what would fail or misbehave later on.
+use hashbrown::HashMap;
+use itertools::Itertools;
+use libafl_qemu::{elf::EasyElf, GuestAddr};
+use std::{env, ops::Range};
+
+use crate::cli::{get_interrupt_config, get_isr_budgets, Cli};
+use crate::config::{get_target_ranges, get_target_symbols, QemuConfig};
+use crate::fuzzer::MAX_INPUT_SIZE;
+use crate::systemstate::helpers::{symbol_size, try_load_symbol};
+use crate::systemstate::input_view::{num_interrupt_sources, set_interrupt_layout_from_env};
+
+#[cfg(all(feature = "freertos", not(feature = "osek")))]
+use crate::systemstate::target_os::freertos::{config::{get_range_groups, layout_mismatches, REQUIRED_SYMBOLS}, USR_ISR_SYMBOLS};
+#[cfg(feature = "osek")]
+use crate::systemstate::target_os::osek::{config::{get_range_groups, layout_mismatches, REQUIRED_SYMBOLS}, USR_ISR_SYMBOLS};
+
+/// Symbols every target needs, (name, env var renaming it)
+const COMMON_REQUIRED_SYMBOLS: &[(&str, Option<&str>)] = &[
+    ("__APP_CODE_START__", None),
+    ("__APP_CODE_END__", None),
+    ("__API_CODE_START__", None),
+    ("__API_CODE_END__", None),
+    ("trigger_job_done", None),
+    ("FUZZ_INPUT", Some("FUZZ_INPUT")),
+    ("BREAKPOINT", Some("BREAKPOINT")),
+];
+
+/// Findings of a check, errors make the fuzzer fail or misbehave for sure
+#[derive(Debug, Default)]
+struct Findings {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+fn range_size(r: &Range<GuestAddr>) -> u64 {
+    r.end.saturating_sub(r.start) as u64
+}
+
+/// Required symbols which can not be resolved, checked before get_target_symbols which panics on the first one
+fn missing_symbols(elf: &EasyElf) -> Vec<String> {
+    let common = COMMON_REQUIRED_SYMBOLS.iter()
+        .map(|(name, var)| var.and_then(|v| env::var(v).ok()).unwrap_or_else(|| name.to_string()));
+    common.chain(REQUIRED_SYMBOLS.iter().map(|x| x.to_string()))
+        .filter(|name| try_load_symbol(elf, name, false).is_none())
+        .collect()
+}
+
+fn print_tables(elf: &EasyElf, symbols: &HashMap<&'static str, GuestAddr>, ranges: &HashMap<&'static str, Range<GuestAddr>>, groups: &HashMap<&'static str, HashMap<String, Range<GuestAddr>>>) {
+    println!("{:<32} {:>10} {:>8}", "symbol", "address", "size");
+    for (name, addr) in symbols.iter().sorted_by_key(|x| x.0) {
+        // symbols renamed by the config are set as env vars of the same name, e.g. FUZZ_INPUT
+        let size = symbol_size(elf, &env::var(name).unwrap_or_else(|_| name.to_string())).map_or("-".to_string(), |x| x.to_string());
+        println!("{:<32} {:>#10x} {:>8}", name, addr, size);
+    }
+    println!();
+    println!("{:<32} {:>10} {:>10} {:>8}", "range", "start", "end", "size");
+    for (name, r) in ranges.iter().sorted_by_key(|x| x.0) {
+        println!("{:<32} {:>#10x} {:>#10x} {:>8}", name, r.start, r.end, range_size(r));
+    }
+    println!();
+    for (name, group) in groups.iter().sorted_by_key(|x| x.0) {
+        println!("{} ({} functions)", name, group.len());
+    }
+    if let Some(isrs) = groups.get("ISR_FN") {
+        for (name, r) in isrs.iter().sorted_by_key(|x| x.1.start) {
+            println!("  {:<30} {:>#10x} {:>8}", name, r.start, range_size(r));
+        }
+    }
+    println!();
+}
+
+fn check_target(cli: &Cli, elf: &EasyElf, findings: &mut Findings) {
+    let missing = missing_symbols(elf);
+    if !missing.is_empty() {
+        findings.errors.extend(missing.iter().map(|x| format!("Required symbol {} not found", x)));
+        return;
+    }
+    findings.errors.extend(layout_mismatches(elf));
+    if !findings.errors.is_empty() {
+        return;
+    }
+    let symbols = get_target_symbols(elf);
+    let ranges = get_target_ranges(elf, &symbols);
+    for (name, r) in ranges.iter() {
+        if r.start > r.end {
+            findings.errors.push(format!("Range {} ends before it starts: {:#x}..{:#x}", name, r.start, r.end));
+        } else if r.is_empty() {
+            findings.warnings.push(format!("Range {} is empty", name));
+        }
+    }
+    if !findings.errors.is_empty() {
+        return;
+    }
+    let groups = get_range_groups(elf, &symbols, &ranges);
+    print_tables(elf, &symbols, &ranges, &groups);
+
+    if let Some(api) = groups.get("API_FN") {
+        if api.is_empty() {
+            findings.warnings.push("No API functions found in API_CODE".to_string());
+        }
+        for name in api.iter().filter(|x| x.1.is_empty()).map(|x| x.0).sorted() {
+            findings.warnings.push(format!("API function {} has a zero-sized range", name));
+        }
+    }
+
+    let max_input_size = env::var("FUZZ_INPUT_LEN").ok().and_then(|x| x.parse::<usize>().ok()).unwrap_or(unsafe { MAX_INPUT_SIZE });
+    let input_name = env::var("FUZZ_INPUT").unwrap_or_else(|_| "FUZZ_INPUT".to_string());
+    match symbol_size(elf, &input_name) {
+        Some(0) | None => findings.warnings.push("FUZZ_INPUT has no size in the ELF, can not compare it with FUZZ_INPUT_LEN".to_string()),
+        Some(size) if (size as usize) < max_input_size => findings.warnings.push(format!(
+            "FUZZ_INPUT is {} bytes, but inputs are up to {} bytes (FUZZ_INPUT_LEN), the rest overwrites whatever follows it", size, max_input_size)),
+        _ => {}
+    }
+    if env::var("FUZZ_INPUT_LEN").map_or(false, |x| x.parse::<usize>().is_err()) {
+        findings.errors.push("FUZZ_INPUT_LEN is not a number".to_string());
+    }
+
+    let isrs = groups.get("ISR_FN").cloned().unwrap_or_default();
+    set_interrupt_layout_from_env();
+    for (source, _) in get_interrupt_config(&cli.kernel, &cli.config) {
+        if source >= num_interrupt_sources() {
+            findings.errors.push(format!("Interrupt source {} is configured, but the target only has {} sources, see NUM_INTERRUPT_SOURCES", source, num_interrupt_sources()));
+        } else if let Some(handler) = USR_ISR_SYMBOLS.get(source).filter(|x| !isrs.contains_key(**x)) {
+            findings.warnings.push(format!("Interrupt source {} is configured, but its handler {} is missing from the ELF", source, handler));
+        }
+    }
+    for name in get_isr_budgets().keys().sorted() {
+        if !isrs.contains_key(name) {
+            findings.warnings.push(format!("ISR {} has a budget, but is missing from the ELF", name));
+        }
+    }
+
+    if let Err(e) = QemuConfig::from_env().validate() {
+        findings.errors.push(format!("Invalid QEMU configuration: {}", e));
+    }
+}
+
+/// Checks the kernel and config of `cli`, prints the findings and returns the exit code
+pub fn run(cli: &Cli) -> i32 {
+    let mut findings = Findings::default();
+    let mut elf_buffer = Vec::new();
+    match EasyElf::from_file(&cli.kernel, &mut elf_buffer) {
+        Ok(elf) => check_target(cli, &elf, &mut findings),
+        Err(e) => findings.errors.push(format!("Can not load kernel {}: {:?}", cli.kernel.display(), e)),
+    }
+    for w in &findings.warnings {
+        println!("warning: {}", w);
+    }
+    for e in &findings.errors {
+        println!("error: {}", e);
+    }
+    println!("{} errors, {} warnings", findings.errors.len(), findings.warnings.len());
+    if findings.errors.is_empty() { 0 } else { 1 }
+}
@@ -1,5 +1,9 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::path::PathBuf;
+use hashbrown::HashMap;
+
+use crate::seed_template::{FieldKind, TemplateField};
+use crate::systemstate::{feedbacks::ErrorAction, target_os::ErrorClass};
 
 // Argument parsing ================================================================================
 
@@ -30,24 +34,187 @@ pub struct Cli {
     #[arg(short='r', long)]
     pub dump_traces: bool,
 
+    /// states to dump on each side of the first refinement error of a trace, as <dump_name>.error_<class>_<n>.ron (requires --dump-traces)
+    #[arg(long, value_name = "STATES", default_value_t = crate::systemstate::target_os::intervals::DEFAULT_ERROR_CONTEXT)]
+    pub error_context: usize,
+
+    /// only dump traces which set a new worst case, keeping the KEEP most recent ones (requires --dump-traces)
+    #[arg(long, value_name = "KEEP")]
+    pub trace_records: Option<usize>,
+
     /// do graph dumps (if supported)
     #[arg(short='g', long)]
     pub dump_graph: bool,
 
-    /// select a task for measurments
+    /// additionally dump numbered snapshots of the graph every MINUTES while fuzzing, if it changed (requires --dump-graph)
+    #[arg(long, value_name = "MINUTES", requires = "dump_graph")]
+    pub dump_graph_every: Option<u64>,
+
+    /// gzip traces, graphs, time and stgsize dumps, the files get a ".gz" suffix
+    #[arg(long)]
+    pub compress_dumps: bool,
+
+    /// select a task for measurments, several comma-separated tasks are tracked alongside the whole execution
     #[arg(short='s', long)]
     pub select_task: Option<String>,
 
+    /// icount shift of the emulated cpu, one instruction takes 2^shift ns (default 5, or ICOUNT_SHIFT from the config)
+    #[arg(long, value_name = "SHIFT")]
+    pub icount_shift: Option<u32>,
+
+    /// report executions in which an ISR exceeds its tick budget as objectives, otherwise they are ignored
+    #[arg(long)]
+    pub isr_budget_objective: bool,
+
+    /// report executions which stop in the target's FUZZ_FAIL function as objectives, otherwise they only get flagged
+    #[arg(long)]
+    pub fail_objective: bool,
+
+    /// stop executions after this many guest ticks (or ICOUNT_BUDGET from the config), the runtime up to the stop is still measured
+    #[arg(long, value_name = "TICKS")]
+    pub icount_budget: Option<u64>,
+
+    /// report executions which exhaust the --icount-budget as objectives, otherwise they only get flagged
+    #[arg(long)]
+    pub budget_objective: bool,
+
+    /// stop capturing system states after N raw states per execution and mark the trace as truncated
+    #[arg(long, value_name = "N")]
+    pub max_trace_states: Option<usize>,
+
+    /// stop capturing system states TICKS after the first one and mark the trace as truncated
+    #[arg(long, value_name = "TICKS")]
+    pub max_trace_ticks: Option<u64>,
+
+    /// report executions with a truncated trace as objectives, otherwise they are only counted
+    #[arg(long)]
+    pub truncation_objective: bool,
+
+    /// rank the testcases of the stg scheduler by the response time of the selected task instead of the execution time (requires sched_stg)
+    #[arg(long, requires = "select_task")]
+    pub favor_select_task: bool,
+
+    /// when the worst snippets of two jobs overlap, also try the input in which the job with the smaller WOET gain wins
+    #[arg(long)]
+    pub snippet_both_resolutions: bool,
+
+    /// dump inputs whose job order changes by at least this many edits without interrupts (requires divergence_stage)
+    #[arg(long, value_name = "EDITS")]
+    pub divergence_threshold: Option<usize>,
+
+    /// dump per-task response time histograms every SECS seconds
+    #[arg(long, value_name = "SECS")]
+    pub rt_histogram_interval: Option<u64>,
+
+    /// minimize the worst testcase after every N corpus additions (requires minimize_stage)
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub minimize_interval: usize,
+
+    /// accept reductions which lower the runtime (or WORT of the selected task) by at most this many percent
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.0)]
+    pub minimize_tolerance: f64,
+
+    /// percentage of interrupt mutations moving an interrupt to where its task from ISR_WAKES is delayed (requires mutate_stg)
+    #[arg(long, value_name = "PERCENT", default_value_t = 25)]
+    pub wake_probability: usize,
+
+    /// inputs kept per generation, overrides the gensize feature (requires sched_genetic)
+    #[arg(long, value_name = "N")]
+    pub population_size: Option<usize>,
+
+    /// select each survivor of a generation as the fittest of N random candidates instead of keeping the fittest ones (requires sched_genetic)
+    #[arg(long, value_name = "N")]
+    pub tournament_size: Option<usize>,
+
+    /// scale the fitness by 1 + WEIGHT times the share of STG edges the current generation does not take (requires sched_genetic)
+    #[arg(long, value_name = "WEIGHT")]
+    pub novelty_weight: Option<f64>,
+
+    /// lower bound of the improvement factor of each corpus entry, keeps entries without recent improvements selectable (requires sched_improvement)
+    #[arg(long, value_name = "FLOOR")]
+    pub improvement_floor: Option<f64>,
+
+    /// report exploration stats to the monitor every SECS seconds
+    #[arg(long, value_name = "SECS", default_value_t = 3)]
+    pub stats_interval: u64,
+
+    /// serve the progress of the campaign as json on localhost:PORT, from the broker if several cores are used
+    #[arg(long, value_name = "PORT")]
+    pub status_port: Option<u16>,
+
+    /// additional QEMU arguments, appended after all others
+    #[arg(long, value_name = "STRING", allow_hyphen_values = true)]
+    pub qemu_args: Option<String>,
+
+    /// periodically save the STG and icount history to DIR and resume from it on startup
+    #[arg(long, value_name = "DIR")]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// minutes between two checkpoints (requires --checkpoint-dir)
+    #[arg(long, value_name = "MINUTES", default_value_t = 10)]
+    pub checkpoint_interval: u64,
+
+    /// every N executions remove testcases dominated by another testcase in STG indices, runtime and per-task response times
+    #[arg(long, value_name = "N")]
+    pub cull_interval: Option<u64>,
+
+    /// prune the STG once it has more than N nodes, keeping the paths of top rated testcases and recently traversed nodes
+    #[arg(long, value_name = "N")]
+    pub stg_max_nodes: Option<usize>,
+
+    /// prune the STG once the fuzzer uses more than MIB MiB of resident memory
+    #[arg(long, value_name = "MIB")]
+    pub stg_max_rss: Option<u64>,
+
+    /// nodes traversed in the last N executions are not pruned, and N executions pass between two prunings unless the graph is over --stg-max-nodes
+    #[arg(long, value_name = "N", default_value_t = 100000)]
+    pub stg_prune_age: u64,
+
+    /// write the whole graph on each graph dump, or only what was added since the previous one
+    #[arg(long, value_enum, default_value_t = StgDumpMode::Full)]
+    pub stg_dump_mode: StgDumpMode,
+
     #[command(subcommand)]
     pub command: Commands,
 }
+
+impl Cli {
+    /// All tasks given to --select-task
+    pub fn selected_tasks(&self) -> Vec<String> {
+        self.select_task.as_ref().map_or(Vec::new(), |x| {
+            x.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect()
+        })
+    }
+
+    /// The selected task if exactly one is given, measurements cover the whole execution otherwise
+    pub fn single_task(&self) -> Option<String> {
+        let mut tasks = self.selected_tasks();
+        if tasks.len() == 1 { tasks.pop() } else { None }
+    }
+
+    /// Tasks whose WORT is tracked separately, only used if several tasks are selected
+    pub fn task_columns(&self) -> Vec<String> {
+        let tasks = self.selected_tasks();
+        if tasks.len() > 1 { tasks } else { Vec::new() }
+    }
+}
+
+#[derive(ValueEnum,Clone,Copy,Debug,PartialEq)]
+pub enum StgDumpMode {
+    Full,
+    Delta,
+}
+
 #[derive(Subcommand,Clone,Debug)]
 pub enum Commands {
     /// run a single input
     Showmap {
-        /// take this input
+        /// take this input, in any format input_serde reads or a replay script
         #[arg(short, long)]
         input: PathBuf,
+        /// write the program input and the injected interrupt ticks to FILE, see systemstate::replay_script
+        #[arg(long, value_name = "FILE")]
+        emit_replay_script: Option<PathBuf>,
     },
     /// start fuzzing campaign
     Fuzz {
@@ -60,10 +227,138 @@ pub enum Commands {
         /// runtime in seconds
         #[arg(short, long)]
         time: Option<u64>,
+        /// start from N seeds generated from the INPUT_TEMPLATE setting, also written to ./corpus
+        #[arg(long, value_name = "N", conflicts_with = "seed")]
+        seed_template: Option<usize>,
+    },
+    /// run a single input repeatedly and report the timing noise
+    Calibrate {
+        /// take this input, defaults to all zeros
+        #[arg(short, long)]
+        input: Option<PathBuf>,
+        /// number of executions
+        #[arg(short, long, default_value_t = 10)]
+        runs: usize,
+        /// append a summary line to this csv file
+        #[arg(short, long)]
+        append: Option<PathBuf>,
+    },
+    /// replay an input and stop QEMU when an ABB of its worst job starts
+    Debug {
+        /// take this input
+        #[arg(short, long)]
+        input: PathBuf,
+        /// stop at this ABB start address, defaults to the ABB of the task with the highest WOET
+        #[arg(short, long, value_parser = parse_addr)]
+        abb_start: Option<u32>,
+        /// stop during the worst job of this task, defaults to --select-task
+        #[arg(short, long)]
+        task: Option<String>,
+        /// let gdb attach on this port once stopped
+        #[arg(short, long, value_name = "PORT")]
+        gdb: Option<u16>,
+    },
+    /// run a single input and write its trace, jobs and abbs to a directory
+    Replay {
+        /// take this input, in any format input_serde reads
+        #[arg(short, long)]
+        input: PathBuf,
+        /// write trace.ron, jobs.csv, abbs.csv and stg_path.csv into this directory
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+        /// write the program input and the injected interrupt ticks to FILE, see systemstate::replay_script
+        #[arg(long, value_name = "FILE")]
+        emit_replay_script: Option<PathBuf>,
+    },
+    /// shrink an input while keeping its runtime, or the WORT of --select-task
+    Minimize {
+        /// take this input, in any format input_serde reads
+        #[arg(short, long)]
+        input: PathBuf,
+        /// write the minimized .case file here
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// run every input of a directory and write the worst times of all abbs to a csv
+    ReportAbbs {
+        /// take all inputs in this directory, e.g. a corpus or a set of .case files
+        #[arg(short, long, value_name = "DIR")]
+        input: PathBuf,
+        /// write the csv here
+        #[arg(short, long)]
+        output: PathBuf,
+        /// write the mean, standard deviation and coefficient of variation of each abb's execution time instead, most variable first
+        #[arg(long)]
+        variance: bool,
+    },
+    /// resolve the symbols of the kernel and config without starting QEMU, exits nonzero if the fuzzer could not run
+    Check,
+    /// fit the inputs of an earlier campaign to the current kernel and config, for use with SEED_DIR
+    Import {
+        /// take all inputs in this directory, in any format input_serde reads
+        #[arg(short, long, value_name = "DIR")]
+        input: PathBuf,
+        /// write the .case files into this directory, defaults to SEED_DIR or ./corpus
+        #[arg(short, long, value_name = "DIR")]
+        output: Option<PathBuf>,
+        /// drop the parts whose name matches this glob, e.g. "isr_*", can be given several times
+        #[arg(long, value_name = "GLOB")]
+        strip: Vec<String>,
+    },
+    /// write the observed WOET, WORT, ABB WOETs and inter-arrival times per task for a response-time analysis, see systemstate::analysis
+    ExportModel {
+        /// take the STG of this checkpoint directory, see --checkpoint-dir
+        #[arg(long, value_name = "DIR", conflicts_with = "input")]
+        checkpoint: Option<PathBuf>,
+        /// run all inputs in this directory instead, e.g. a corpus or a set of .case files
+        #[arg(short, long, value_name = "DIR", required_unless_present = "checkpoint")]
+        input: Option<PathBuf>,
+        /// write the model here, as JSON if it ends in .json and as RON otherwise
+        #[arg(short, long, default_value = "model.ron")]
+        output: PathBuf,
+    },
+    /// run inputs and package each with its edit format and expected times for artifact evaluation, see artifact.rs
+    ExportArtifact {
+        /// take all inputs in this directory, e.g. a corpus or a set of .case files
+        #[arg(short, long, value_name = "DIR")]
+        input: PathBuf,
+        /// only export the N-th input of the directory, in file name order starting at 0
+        #[arg(long, value_name = "N")]
+        id: Option<usize>,
+        /// write one directory per input here
+        #[arg(short, long, value_name = "DIR")]
+        output: PathBuf,
+    },
+    /// rerun the artifacts written by export-artifact, exits nonzero if a time deviates
+    VerifyArtifact {
+        /// take all artifact directories in this directory
+        #[arg(short, long, value_name = "DIR")]
+        input: PathBuf,
+        /// allowed deviation of the runtime and each WORT
+        #[arg(long, value_name = "PERCENT", default_value_t = 0.0)]
+        tolerance: f64,
+    },
+}
+
+/// Parses an address, either decimal or hex with a 0x prefix
+pub fn parse_addr(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
     }
 }
 
+/// Sets the environment from a config of VAR=VAL lines, or from the row of the kernel in a CSV config, see [`config_settings`]
 pub fn set_env_from_config(kernel : &PathBuf, path : &PathBuf) {
+    for (var, val) in config_settings(kernel, path) {
+        std::env::set_var(var, val);
+    }
+}
+
+/// Reads the settings from a config of VAR=VAL lines, or from the row of the kernel in a CSV config, in the order they are given.
+/// Optional settings, e.g. FUZZ_ERROR=<symbol of the target error variable>, are given as VAR=VAL.
+pub fn config_settings(kernel : &PathBuf, path : &PathBuf) -> Vec<(String,String)> {
+    let mut ret = Vec::new();
     let is_csv = path.as_path().extension().map_or(false, |x| x=="csv");
     if !is_csv {
         let lines = std::fs::read_to_string(path).expect("Config file not found");
@@ -72,7 +367,7 @@ pub fn set_env_from_config(kernel : &PathBuf, path : &PathBuf) {
         );
         for l in lines {
             let pair = l.split_once('=').expect("Non VAR=VAL line in config");
-            std::env::set_var(pair.0, pair.1);
+            ret.push((pair.0.to_string(), pair.1.to_string()));
         }
     } else {
         let mut reader = csv::Reader::from_path(path).expect("CSV read from config failed");
@@ -84,10 +379,16 @@ pub fn set_env_from_config(kernel : &PathBuf, path : &PathBuf) {
             if stem == &rec[0] {
                 println!("Config from file {:?}", rec);
                 found = true;
-                std::env::set_var("FUZZ_MAIN", &rec[1]);
-                std::env::set_var("FUZZ_INPUT", &rec[2]);
-                std::env::set_var("FUZZ_INPUT_LEN", &rec[3]);
-                std::env::set_var("BREAKPOINT", &rec[4]);
+                for (var, col) in [("FUZZ_MAIN", 1), ("FUZZ_INPUT", 2), ("FUZZ_INPUT_LEN", 3), ("BREAKPOINT", 4)] {
+                    ret.push((var.to_string(), rec[col].to_string()));
+                }
+                // column 9 holds further VAR=VAL settings, e.g. the QEMU machine, separated by ';'
+                if let Some(settings) = rec.get(9) {
+                    for pair in settings.split(';').filter(|x| x.len() > 0) {
+                        let pair = pair.split_once('=').expect("Non VAR=VAL setting in config");
+                        ret.push((pair.0.to_string(), pair.1.to_string()));
+                    }
+                }
                 break;
             }
         }
@@ -95,9 +396,12 @@ pub fn set_env_from_config(kernel : &PathBuf, path : &PathBuf) {
             eprintln!("No config found for kernel {:?}", stem);
         }
     }
+    ret
 }
 
-pub fn get_interrupt_config(kernel : &PathBuf, path : &PathBuf) -> Vec<(usize,u32)>{
+/// Reads the interrupt sources from column 6 of the CSV config, formatted as "source#min_inter_arrival_us[#max_burst#window_us];..."
+/// Returns (source, minimum inter-arrival time in us, optional (max_burst, window in us)) per source.
+fn get_interrupt_entries(kernel : &PathBuf, path : &PathBuf) -> Vec<(usize,u32,Option<(usize,u32)>)> {
     let is_csv = path.as_path().extension().map_or(false, |x| x=="csv");
     if !is_csv {
         panic!("Interrupt config must be inside a CSV file");
@@ -108,14 +412,180 @@ pub fn get_interrupt_config(kernel : &PathBuf, path : &PathBuf) -> Vec<(usize,u3
         for r in reader.records() {
             let rec = r.expect("CSV entry error");
             if stem == &rec[0] {
-                let ret = rec[6].split(';').filter(|x| x != &"").map(|x| {
-                    let pair = x.split_once('#').expect("Interrupt config error");
-                    (pair.0.parse().expect("Interrupt config error"), pair.1.parse().expect("Interrupt config error"))
+                return rec[6].split(';').filter(|x| x != &"").map(|x| {
+                    let fields : Vec<&str> = x.split('#').collect();
+                    let burst = match fields.len() {
+                        2 => None,
+                        4 => Some((fields[2].parse().expect("Interrupt config error"), fields[3].parse().expect("Interrupt config error"))),
+                        _ => panic!("Interrupt config error"),
+                    };
+                    (fields[0].parse().expect("Interrupt config error"), fields[1].parse().expect("Interrupt config error"), burst)
                 }).collect();
-                println!("Interrupt config {:?}", ret);
-                return ret;
             }
         }
     }
     return Vec::new();
-}
\ No newline at end of file
+}
+
+pub fn get_interrupt_config(kernel : &PathBuf, path : &PathBuf) -> Vec<(usize,u32)>{
+    let ret : Vec<(usize,u32)> = get_interrupt_entries(kernel, path).into_iter().map(|x| (x.0, x.1)).collect();
+    if ret.len() > 0 {
+        // stderr, input_serde writes its output to stdout
+        eprintln!("Interrupt config {:?}", ret);
+    }
+    ret
+}
+
+/// Optional burst limits of the interrupt sources, source -> (max_burst, window in us): at most max_burst interrupts within any window
+pub fn get_interrupt_burst_limits(kernel : &PathBuf, path : &PathBuf) -> HashMap<usize,(usize,u32)> {
+    let ret : HashMap<usize,(usize,u32)> = get_interrupt_entries(kernel, path).into_iter().filter_map(|x| x.2.map(|b| (x.0, b))).collect();
+    if ret.len() > 0 {
+        println!("Interrupt burst limits {:?}", ret);
+    }
+    ret
+}
+
+/// Reads the tick budget per ISR from the ISR_BUDGETS setting, formatted as "name#ticks;..."
+pub fn get_isr_budgets() -> HashMap<String,u64> {
+    let ret : HashMap<String,u64> = std::env::var("ISR_BUDGETS").unwrap_or_default().split(';').filter(|x| x != &"").map(|x| {
+        let pair = x.split_once('#').expect("ISR budget config error");
+        (pair.0.to_string(), pair.1.parse().expect("ISR budget config error"))
+    }).collect();
+    if ret.len() > 0 {
+        println!("ISR budgets {:?}", ret);
+    }
+    ret
+}
+
+/// Reads the task released by each interrupt source from the ISR_WAKES setting, formatted as "source#task;..."
+pub fn get_isr_wakes() -> HashMap<usize,String> {
+    let ret : HashMap<usize,String> = std::env::var("ISR_WAKES").unwrap_or_default().split(';').filter(|x| x != &"").map(|x| {
+        let pair = x.split_once('#').expect("ISR wake config error");
+        (pair.0.parse().expect("ISR wake config error"), pair.1.to_string())
+    }).collect();
+    if ret.len() > 0 {
+        println!("ISR wakes {:?}", ret);
+    }
+    ret
+}
+
+/// Reads the API calls ending a job from the JOB_DONE_APIS setting, formatted as "name;...", e.g. "vTaskDelay;vTaskDelayUntil".
+/// If set, job responses are inferred from the states instead of recorded by trigger_job_done
+pub fn get_job_done_apis() -> Vec<String> {
+    let ret : Vec<String> = std::env::var("JOB_DONE_APIS").unwrap_or_default().split(';').filter(|x| x != &"").map(String::from).collect();
+    if ret.len() > 0 {
+        println!("Job done APIs {:?}", ret);
+    }
+    ret
+}
+
+/// Reads the response time deadline per task from the TASK_DEADLINES setting, formatted as "name#ticks;..."
+pub fn get_task_deadlines() -> HashMap<String,u64> {
+    let ret : HashMap<String,u64> = std::env::var("TASK_DEADLINES").unwrap_or_default().split(';').filter(|x| x != &"").map(|x| {
+        let pair = x.split_once('#').expect("Task deadline config error");
+        (pair.0.to_string(), pair.1.parse().expect("Task deadline config error"))
+    }).collect();
+    if ret.len() > 0 {
+        println!("Task deadlines {:?}", ret);
+    }
+    ret
+}
+
+/// Reads what SystraceErrorFeedback does with each error class from the ERROR_ACTIONS setting, formatted as "class#ignore|count|objective;...",
+/// e.g. "pairing#count;truncation#ignore". The classes are invalid_read, abb_mismatch, pairing, truncation and utf8_name.
+pub fn get_error_actions() -> Vec<(ErrorClass, ErrorAction)> {
+    let ret : Vec<(ErrorClass, ErrorAction)> = std::env::var("ERROR_ACTIONS").unwrap_or_default().split(';').filter(|x| x != &"").map(|x| {
+        let pair = x.split_once('#').expect("Error action config error");
+        (ErrorClass::from_name(pair.0).expect("Unknown error class"), ErrorAction::from_name(pair.1).expect("Unknown error action"))
+    }).collect();
+    if ret.len() > 0 {
+        println!("Error actions {:?}", ret);
+    }
+    ret
+}
+
+/// Reads the known fields of the program input from the INPUT_TEMPLATE setting, formatted as "name#offset#type[#min#max];...".
+/// The types are u8, u16, u32 (little endian), u16be and u32be, without a range a field takes any value of its type.
+pub fn get_input_template() -> Vec<TemplateField> {
+    let ret : Vec<TemplateField> = std::env::var("INPUT_TEMPLATE").unwrap_or_default().split(';').filter(|x| x != &"").map(|x| {
+        let fields : Vec<&str> = x.split('#').collect();
+        if fields.len() != 3 && fields.len() != 5 {
+            panic!("Input template config error");
+        }
+        let kind = FieldKind::from_name(fields[2]).expect("Unknown template field type");
+        let (min, max) = match fields.len() {
+            5 => (fields[3].parse().expect("Input template config error"), fields[4].parse().expect("Input template config error")),
+            _ => (0, kind.max_value()),
+        };
+        TemplateField { name: fields[0].to_string(), offset: fields[1].parse().expect("Input template config error"), kind, min, max }
+    }).collect();
+    if ret.len() > 0 {
+        println!("Input template {:?}", ret);
+    }
+    ret
+}
+
+/// Reads the fuzzed release phases from the TASK_PHASES setting, formatted as "task#variable[#bound];...".
+/// The variable is a symbol or an address, the phase is kept below the bound, or below the period of the task in TASK_PERIODS.
+pub fn get_task_phases() -> Vec<(String,String,Option<u32>)> {
+    let ret : Vec<(String,String,Option<u32>)> = std::env::var("TASK_PHASES").unwrap_or_default().split(';').filter(|x| x != &"").map(|x| {
+        let fields : Vec<&str> = x.split('#').collect();
+        match fields.len() {
+            2 => (fields[0].to_string(), fields[1].to_string(), None),
+            3 => (fields[0].to_string(), fields[1].to_string(), Some(fields[2].parse().expect("Task phase config error"))),
+            _ => panic!("Task phase config error"),
+        }
+    }).collect();
+    if ret.len() > 0 {
+        println!("Task phases {:?}", ret);
+    }
+    ret
+}
+
+/// Reads the nominal release period per task from the TASK_PERIODS setting, formatted as "name#ticks;..."
+pub fn get_task_periods() -> HashMap<String,u64> {
+    let ret : HashMap<String,u64> = std::env::var("TASK_PERIODS").unwrap_or_default().split(';').filter(|x| x != &"").map(|x| {
+        let pair = x.split_once('#').expect("Task period config error");
+        (pair.0.to_string(), pair.1.parse().expect("Task period config error"))
+    }).collect();
+    if ret.len() > 0 {
+        println!("Task periods {:?}", ret);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupt_entries_with_burst_limits() {
+        let dir = std::env::temp_dir().join(format!("fret-cli-interrupts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("targets.csv");
+        std::fs::write(&config, "kernel,main,input,breakpoint,length,dummy,interrupts\n\
+            other,main,FUZZ_INPUT,trigger_Qemu_break,4096,,0#10\n\
+            waters,main,FUZZ_INPUT,trigger_Qemu_break,4096,,0#100;1#50#3#1000;\n").unwrap();
+        let kernel = PathBuf::from("/tmp/waters.elf");
+        assert_eq!(get_interrupt_config(&kernel, &config), vec![(0, 100), (1, 50)]);
+        assert_eq!(get_interrupt_burst_limits(&kernel, &config), HashMap::from([(1, (3, 1000))]));
+        assert!(get_interrupt_burst_limits(&PathBuf::from("other.elf"), &config).is_empty());
+        assert!(get_interrupt_config(&PathBuf::from("unknown.elf"), &config).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn qemu_machine_from_config_settings() {
+        let dir = std::env::temp_dir().join(format!("fret-cli-settings-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("targets.csv");
+        std::fs::write(&config, "kernel,main,input,length,breakpoint,a,b,c,d,settings\n\
+            board,main,FUZZ_INPUT,4096,trigger_Qemu_break,,,,,QEMU_MACHINE=mps2-an505;QEMU_CPU=cortex-m33;QEMU_DEVICE_ARGS=-device loader,addr=0x1000;\n").unwrap();
+        let settings: HashMap<String,String> = config_settings(&PathBuf::from("/tmp/board.elf"), &config).into_iter().collect();
+        let qemu = crate::config::QemuConfig::from_settings(|var| settings.get(var).cloned());
+        assert_eq!(qemu.to_args(), vec!["-machine", "mps2-an505", "-cpu", "cortex-m33", "-device", "loader,addr=0x1000"]);
+        assert_eq!(settings.get("FUZZ_INPUT_LEN").map(String::as_str), Some("4096"));
+        assert!(config_settings(&PathBuf::from("/tmp/other.elf"), &config).is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
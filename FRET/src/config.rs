@@ -28,7 +28,7 @@ pub fn get_target_symbols(elf: &EasyElf) -> HashMap<&'static str, GuestAddr> {
         load_symbol(&elf, "trigger_job_done", false),
     );
 
-    #[cfg(feature = "freertos")]
+    #[cfg(all(feature = "freertos", not(feature = "osek")))]
     crate::systemstate::target_os::freertos::config::add_target_symbols(elf, &mut addrs);
     
     #[cfg(feature = "osek")]
@@ -45,6 +45,15 @@ pub fn get_target_symbols(elf: &EasyElf) -> HashMap<&'static str, GuestAddr> {
         addrs.insert("FUZZ_MAIN", main_addr);
     }
 
+    // optional, where the input independent setup is done and a nested snapshot can be taken
+    let setup_addr = elf.resolve_symbol(
+        &env::var("FUZZ_SETUP_DONE").unwrap_or_else(|_| "FUZZ_SETUP_DONE".to_owned()),
+        0,
+    );
+    if let Some(setup_addr) = setup_addr {
+        addrs.insert("FUZZ_SETUP_DONE", setup_addr);
+    }
+
     let input_addr = load_symbol(
         &elf,
         &env::var("FUZZ_INPUT").unwrap_or_else(|_| "FUZZ_INPUT".to_owned()),
@@ -68,6 +77,15 @@ pub fn get_target_symbols(elf: &EasyElf) -> HashMap<&'static str, GuestAddr> {
     if let Some(input_counter_ptr) = input_counter_ptr {
         addrs.insert("FUZZ_POINTER", input_counter_ptr);
     }
+    // optional variable the target sets to a nonzero error code when internal invariants break
+    let error_ptr = try_load_symbol(
+        &elf,
+        &env::var("FUZZ_ERROR").unwrap_or_else(|_| "FUZZ_ERROR".to_owned()),
+        true,
+    );
+    if let Some(error_ptr) = error_ptr {
+        addrs.insert("FUZZ_ERROR", error_ptr);
+    }
     addrs.insert(
         "BREAKPOINT",
         elf.resolve_symbol(
@@ -76,6 +94,12 @@ pub fn get_target_symbols(elf: &EasyElf) -> HashMap<&'static str, GuestAddr> {
         )
         .expect("Symbol or env BREAKPOINT not found"),
     );
+    // optional functions classifying other stops of the guest, see GuestExit
+    for name in ["FUZZ_ABORT", "FUZZ_FAIL", "IDLE_HANG"] {
+        if let Some(addr) = elf.resolve_symbol(&env::var(name).unwrap_or_else(|_| name.to_owned()), 0) {
+            addrs.insert(name, addr);
+        }
+    }
 
     addrs
 }
@@ -97,3 +121,121 @@ pub fn get_target_ranges(
 
     ranges
 }
+
+#[cfg(not(feature = "osek"))]
+const DEFAULT_QEMU_MACHINE: (&str, &str) = ("mps2-an385", "cortex-m3");
+#[cfg(feature = "osek")]
+const DEFAULT_QEMU_MACHINE: (&str, &str) = ("KIT_AURIX_TC277_TRB", "tc27x");
+
+/// QEMU machine setup of the target, read from the config environment
+#[derive(Debug, Clone)]
+pub struct QemuConfig {
+    pub machine: String,
+    pub cpu: String,
+    /// Additional device and peripheral arguments
+    pub device_args: Vec<String>,
+    /// Drive backing the snapshots, only used without snapshot_fast
+    pub drive: String,
+}
+
+impl QemuConfig {
+    /// Reads QEMU_MACHINE, QEMU_CPU, QEMU_DEVICE_ARGS and QEMU_DRIVE, defaulting to the usual board of the target system
+    pub fn from_env() -> Self {
+        Self::from_settings(|var| env::var(var).ok())
+    }
+
+    /// Like [`QemuConfig::from_env`], with the settings looked up by `get`
+    pub fn from_settings(get: impl Fn(&str) -> Option<String>) -> Self {
+        QemuConfig {
+            machine: get("QEMU_MACHINE").unwrap_or_else(|| DEFAULT_QEMU_MACHINE.0.to_owned()),
+            cpu: get("QEMU_CPU").unwrap_or_else(|| DEFAULT_QEMU_MACHINE.1.to_owned()),
+            device_args: get("QEMU_DEVICE_ARGS")
+                .map(|x| x.split_whitespace().map(String::from).collect())
+                .unwrap_or_default(),
+            drive: get("QEMU_DRIVE")
+                .unwrap_or_else(|| "if=none,format=qcow2,file=dummy.qcow2".to_owned()),
+        }
+    }
+
+    /// Rejects machines that can not run the selected target system
+    pub fn validate(&self) -> Result<(), String> {
+        if self.machine.is_empty() || self.cpu.is_empty() {
+            return Err("QEMU_MACHINE and QEMU_CPU must not be empty".to_owned());
+        }
+        let is_arm = self.machine.starts_with("mps2") || self.cpu.starts_with("cortex");
+        #[cfg(feature = "osek")]
+        if is_arm {
+            return Err(format!(
+                "The osek target runs on TriCore, but machine {} with cpu {} is an ARM board",
+                self.machine, self.cpu
+            ));
+        }
+        #[cfg(all(feature = "freertos", not(feature = "osek")))]
+        if !is_arm {
+            return Err(format!(
+                "The freertos target runs on Cortex-M, but machine {} with cpu {} is not an ARM board",
+                self.machine, self.cpu
+            ));
+        }
+        Ok(())
+    }
+
+    /// The machine related QEMU arguments
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "-machine".to_owned(),
+            self.machine.clone(),
+            "-cpu".to_owned(),
+            self.cpu.clone(),
+        ];
+        args.extend(self.device_args.iter().cloned());
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(machine: &str, cpu: &str) -> QemuConfig {
+        QemuConfig {
+            machine: machine.to_owned(),
+            cpu: cpu.to_owned(),
+            device_args: vec!["-device".to_owned(), "loader,addr=0x1000".to_owned()],
+            drive: String::new(),
+        }
+    }
+
+    #[test]
+    fn machine_args_come_first() {
+        assert_eq!(
+            machine("mps2-an385", "cortex-m3").to_args(),
+            vec!["-machine", "mps2-an385", "-cpu", "cortex-m3", "-device", "loader,addr=0x1000"]
+        );
+    }
+
+    #[test]
+    fn empty_machine_is_rejected() {
+        assert!(machine("", "cortex-m3").validate().is_err());
+        assert!(machine("mps2-an385", "").validate().is_err());
+    }
+
+    #[test]
+    fn default_machine_is_valid() {
+        assert!(machine(DEFAULT_QEMU_MACHINE.0, DEFAULT_QEMU_MACHINE.1).validate().is_ok());
+    }
+
+    #[cfg(feature = "osek")]
+    #[test]
+    fn osek_rejects_arm_boards() {
+        assert!(machine("mps2-an385", "cortex-m3").validate().is_err());
+        assert!(machine("KIT_AURIX_TC277_TRB", "cortex-m4").validate().is_err());
+    }
+
+    #[cfg(all(feature = "freertos", not(feature = "osek")))]
+    #[test]
+    fn freertos_rejects_other_boards() {
+        assert!(machine("KIT_AURIX_TC277_TRB", "tc27x").validate().is_err());
+        assert!(machine("mps2-an505", "cortex-m33").validate().is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+//! Dump files, optionally gzip compressed
+use std::{fs::{File, OpenOptions}, io::{self, BufRead, BufReader, Read, Write}, path::{Path, PathBuf}, sync::atomic::{AtomicBool, Ordering}};
+
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use serde::Serialize;
+
+static COMPRESS_DUMPS: AtomicBool = AtomicBool::new(false);
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub fn set_compress_dumps(compress: bool) {
+    COMPRESS_DUMPS.store(compress, Ordering::Relaxed);
+}
+
+pub fn compress_dumps() -> bool {
+    COMPRESS_DUMPS.load(Ordering::Relaxed)
+}
+
+/// Path a dump is actually written to, with a ".gz" suffix if dumps are compressed
+pub fn dump_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    if compress_dumps() {
+        let mut p = path.as_os_str().to_owned();
+        p.push(".gz");
+        PathBuf::from(p)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Writer for a dump file. A compressed writer is one gzip member, which is finished on drop.
+pub struct DumpWriter {
+    inner: Box<dyn Write>,
+    is_new: bool,
+}
+
+impl DumpWriter {
+    fn new(file: File) -> Self {
+        let is_new = file.metadata().map_or(false, |m| m.len() == 0);
+        let inner: Box<dyn Write> = if compress_dumps() {
+            Box::new(GzEncoder::new(file, Compression::default()))
+        } else {
+            Box::new(file)
+        };
+        Self { inner, is_new }
+    }
+
+    /// The file was empty before it was opened
+    pub fn is_new(&self) -> bool {
+        self.is_new
+    }
+}
+
+impl Write for DumpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Truncates or creates the dump at `dump_path(path)`
+pub fn create_dump(path: impl AsRef<Path>) -> io::Result<DumpWriter> {
+    Ok(DumpWriter::new(File::create(dump_path(path))?))
+}
+
+/// Opens the dump at `dump_path(path)` for appending. Compressed dumps get a new gzip member for every writer.
+pub fn append_dump(path: impl AsRef<Path>) -> io::Result<DumpWriter> {
+    Ok(DumpWriter::new(OpenOptions::new().create(true).append(true).open(dump_path(path))?))
+}
+
+/// Replaces the dump at `dump_path(path)` with `contents`
+pub fn write_dump(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut file = create_dump(path)?;
+    file.write_all(contents.as_ref())?;
+    file.flush()
+}
+
+/// Serializes `value` into the dump at `dump_path(path)` without building the whole string first
+pub fn write_ron_dump<T: Serialize + ?Sized>(path: impl AsRef<Path>, value: &T) -> io::Result<()> {
+    let mut file = create_dump(path)?;
+    ron::ser::to_writer(&mut file, value).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    file.flush()
+}
+
+/// Opens a dump for reading, gzip compressed files are detected by their header and decompressed while reading
+pub fn open_dump(path: impl AsRef<Path>) -> io::Result<Box<dyn BufRead>> {
+    let mut file = BufReader::new(File::open(path)?);
+    if file.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Reads a whole dump, see `open_dump`
+pub fn read_dump(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    open_dump(path)?.read_to_end(&mut out)?;
+    Ok(out)
+}
@@ -1,51 +1,48 @@
 //! A fuzzer using qemu in systemmode for binary-only coverage of kernels
 //!
 use core::time::Duration;
-use std::{env, path::PathBuf, process::{self, abort}, io::{Read, Write}, fs::{self, OpenOptions}, cmp::{min, max}, mem::transmute_copy, ptr::addr_of_mut, ffi::OsStr};
+use std::{env, path::{Path, PathBuf}, process::{self, abort}, io::{Read, Write}, fs, cmp::{min, max}, mem::transmute_copy, ptr::addr_of_mut, ffi::OsStr};
 use hashbrown::HashMap;
 use libafl_bolts::{
 core_affinity::Cores, ownedref::OwnedMutSlice, rands::StdRand, shmem::{ShMemProvider, StdShMemProvider}, tuples::tuple_list, AsSlice, SimpleStderrLogger
 };
 use libafl::{
-common::{HasMetadata, HasNamedMetadata}, corpus::{Corpus, InMemoryCorpus, OnDiskCorpus}, events::{launcher::Launcher, EventConfig}, executors::ExitKind, feedback_or, feedback_or_fast, feedbacks::{CrashFeedback, MaxMapFeedback, TimeoutFeedback}, fuzzer::{Fuzzer, StdFuzzer}, inputs::{multi::MultipartInput, BytesInput, HasTargetBytes, Input}, monitors::MultiMonitor, observers::{CanTrack, VariableMapObserver}, prelude::{havoc_mutations, minimizer::TopRatedsMetadata, CorpusId, Generator, HitcountsMapObserver, RandBytesGenerator, SimpleEventManager, SimpleMonitor, SimplePrintingMonitor, SimpleRestartingEventManager, StdScheduledMutator}, schedulers::QueueScheduler, stages::StdMutationalStage, state::{HasCorpus, StdState}, Error, Evaluator
+common::{HasMetadata, HasNamedMetadata}, corpus::{Corpus, InMemoryCorpus, OnDiskCorpus}, events::{launcher::Launcher, EventConfig}, executors::{Executor, ExitKind}, feedback_or, feedback_or_fast, feedbacks::{CrashFeedback, MaxMapFeedback, TimeoutFeedback}, fuzzer::{Fuzzer, StdFuzzer}, inputs::{multi::MultipartInput, BytesInput, HasTargetBytes, Input}, monitors::MultiMonitor, observers::{CanTrack, VariableMapObserver}, prelude::{havoc_mutations, minimizer::TopRatedsMetadata, CorpusId, Generator, HitcountsMapObserver, RandBytesGenerator, SimpleEventManager, SimpleMonitor, SimplePrintingMonitor, SimpleRestartingEventManager, StdScheduledMutator}, schedulers::QueueScheduler, stages::StdMutationalStage, state::{HasCorpus, StdState}, Error, Evaluator, ExecutesInput
 };
 use libafl_qemu::{
-elf::EasyElf, emu::Emulator, modules::{edges::{self}, EdgeCoverageModule, FilterList, StdAddressFilter, StdEdgeCoverageModule}, GuestAddr, GuestPhysAddr, QemuExecutor, QemuExitReason, QemuHooks, Regs
+elf::EasyElf, emu::Emulator, modules::{edges::{self}, EdgeCoverageModule, FilterList, StdAddressFilter, StdEdgeCoverageModule}, GuestAddr, GuestPhysAddr, QemuExecutor, QemuHooks
 };
 use libafl_targets::{edges_map_mut_ptr, EDGES_MAP_DEFAULT_SIZE, MAX_EDGES_FOUND};
 use rand::{SeedableRng, StdRng, Rng};
 
-#[cfg(feature = "freertos")]
-use crate::systemstate::target_os::freertos::{config::get_range_groups, qemu_module::FreeRTOSSystemStateHelper, FreeRTOSSystem};
-#[cfg(feature = "freertos")]
-type TargetSystem = FreeRTOSSystem;
-#[cfg(feature = "freertos")]
-type SystemStateHelper = FreeRTOSSystemStateHelper;
-
-#[cfg(feature = "osek")]
-use crate::systemstate::target_os::osek::{config::get_range_groups, qemu_module::OSEKSystemStateHelper, OSEKSystem};
-#[cfg(feature = "osek")]
-type TargetSystem = OSEKSystem;
-#[cfg(feature = "osek")]
-type SystemStateHelper = OSEKSystemStateHelper;
+use crate::runner::{boot_target, Harness, RunnerOptions, SystemStateHelper, Target, TargetRunner, TargetSystem};
 
 use crate::{
-    config::{get_target_ranges, get_target_symbols}, systemstate::{self, feedbacks::{DumpSystraceFeedback, SystraceErrorFeedback}, helpers::{get_function_range, input_bytes_to_interrupt_times, load_symbol, try_load_symbol}, mutational::{InterruptShiftStage, STGSnippetStage}, schedulers::{GenerationScheduler, LongestTraceScheduler}, stg::{stg_map_mut_slice, GraphMaximizerCorpusScheduler, STGEdge, STGNode, StgFeedback, MAX_STG_NUM}}, time::{
-        clock::{ClockTimeFeedback, IcHist, QemuClockIncreaseFeedback, QemuClockObserver, FUZZ_START_TIMESTAMP, QEMU_ICOUNT_SHIFT, QEMU_ISNS_PER_MSEC, QEMU_ISNS_PER_USEC}, qemustate::QemuStateRestoreHelper, worst::{AlwaysTrueFeedback, ExecTimeIncFeedback, RateLimitedMonitor, TimeMaximizerCorpusScheduler, TimeProbMassScheduler, TimeStateMaximizerCorpusScheduler}
+    config::QemuConfig, systemstate::{self, feedbacks::{DeadlineFeedback, DumpSystraceFeedback, ErrorClassStats, PreemptionFeedback, ReleaseStatsFeedback, ResponseTimeHistogramFeedback, StackDepthFeedback, StackHighWaterMetadata, SystraceErrorFeedback, TargetAssertFeedback}, helpers::{abb_report_csv, abb_variance_csv, add_abb_time_stats, get_function_range, load_symbol, merge_abb_profile, try_load_symbol}, input_view::{bytes_part_or_insert, interrupt_part_name, max_num_interrupt, read_input_file, repair_input, set_task_phases, task_phase_bounds, InputFormat, TASK_PHASES_PART}, report::ReleaseStats, analysis::{extract_task_model, merge_jobs, read_checkpoint_model}, divergence::ScheduleDivergenceStage, checkpoint::{load_checkpoint, CheckpointStage}, cull::DominatedCullingStage, prune::StgPruningStage, snapshot::StgSnapshotStage, minimize::{minimize_input, measure_input, WorstCaseMinimizerStage}, mutational::{InterruptShiftStage, STGSnippetStage, TaskPhaseMutator}, replay::{select_break_target, AbbBreakpointModule, BreakTarget}, replay_script::{load_replay_script, loaded_replay_script, read_replay_script, ReplayScript}, schedulers::{GenerationScheduler, LongestTraceScheduler, TimeImprovementScheduler}, stg::{abb_coverage_observer, abb_map_mut_slice, max_stg_num_ptr, set_abb_map, stg_map_mut_slice, GraphMaximizerCorpusScheduler, STGEdge, STGNode, STGNodeMetadata, StgFeedback}}, time::{
+        budget::IcountBudgetModule, calibration::{append_calibration, calibration_report, CalibrationRun}, clock::{drain_rt_hist, drain_timedump, ClockTimeFeedback, IcHist, RtHist, QemuClockIncreaseFeedback, QemuClockObserver, FUZZ_START_TIMESTAMP, DEFAULT_QEMU_ICOUNT_SHIFT, qemu_icount_shift, qemu_isns_per_msec, set_qemu_icount_shift, write_timedump_header}, qemustate::QemuStateRestoreHelper, worst::{set_favored_task, AlwaysTrueFeedback, ExecTimeIncFeedback, RateLimitedMonitor, TimeMaximizerCorpusScheduler, TimeProbMassScheduler, TimeStateMaximizerCorpusScheduler}
     }
 };
 use std::time::SystemTime;
-use petgraph::dot::Dot;
-use crate::systemstate::stg::STGFeedbackState;
+use crate::systemstate::stg::{STGFeedbackState, WorstOrigin};
+use crate::systemstate::target_os::compute_hash;
+use crate::systemstate::target_os::SystemTraceData;
+use crate::systemstate::target_os::capture::TraceLimits;
+use crate::systemstate::target_os::intervals::set_error_context;
+use itertools::Itertools;
 use libafl::inputs::HasMutatorBytes;
 use libafl_qemu::Qemu;
 use crate::cli::Cli;
 use crate::cli::Commands;
+use crate::cli::StgDumpMode;
 use crate::cli::set_env_from_config;
 use clap::Parser;
 use log;
 use rand::RngCore;
 use crate::templates;
+use crate::seed_template::TemplateGenerator;
+use crate::manifest::CampaignManifest;
+use crate::dump::{append_dump, set_compress_dumps, write_dump, write_ron_dump};
+use crate::status::StatusMonitor;
 use std::ops::Range;
 
 // Constants ================================================================================
@@ -54,9 +51,6 @@ pub static mut RNG_SEED: u64 = 1;
 
 pub const FIRST_INT : u32 = 200000;
 
-pub const MAX_NUM_INTERRUPT: usize = 128;
-pub const NUM_INTERRUPT_SOURCES: usize = 6; // Keep in sync with qemu-libafl-bridge/hw/timer/armv7m_systick.c:319 and  FreeRTOS/FreeRTOS/Demo/CORTEX_M3_MPS2_QEMU_GCC/init/startup.c:216
-pub const DO_NUM_INTERRUPT: usize = 128;
 pub static mut MAX_INPUT_SIZE: usize = 1024;
 
 pub fn get_all_fn_symbol_ranges(elf: &EasyElf, range: std::ops::Range<GuestAddr>) -> HashMap<String,std::ops::Range<GuestAddr>> {
@@ -80,12 +74,6 @@ pub fn get_all_fn_symbol_ranges(elf: &EasyElf, range: std::ops::Range<GuestAddr>
     return ret;
 }
 
-#[allow(unused)]
-extern "C" {
-static mut libafl_interrupt_offsets : [[u32; MAX_NUM_INTERRUPT]; NUM_INTERRUPT_SOURCES];
-static mut libafl_num_interrupts : [u64; NUM_INTERRUPT_SOURCES];
-}
-
 
 /// Takes a state, cli and a suffix, writes out the current worst case
 macro_rules! do_dump_case {
@@ -115,16 +103,25 @@ macro_rules! do_dump_times {
 ($state:expr, $cli:expr, $c:expr) => {
     if $cli.dump_times {
         let dump_path = $cli.dump_name.clone().unwrap().with_extension(if $c=="" {"time"} else {$c});
-        let mut file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(dump_path).expect("Could not open timedump");
+        let mut file = append_dump(dump_path).expect("Could not open timedump");
+        write_timedump_header(&mut file, &$cli.task_columns());
         if let Ok(ichist) = $state.metadata_mut::<IcHist>() {
-            for i in ichist.0.drain(..) {
-                writeln!(file, "{},{}", i.0, i.1).expect("Write to dump failed");
-            }
+            drain_timedump(ichist, &mut file);
+        }
+        if let Ok(rt_hist) = $state.metadata_mut::<RtHist>() {
+            let mut file = append_dump($cli.dump_name.clone().unwrap().with_extension("rt_hist")).expect("Could not open response time dump");
+            write_timedump_header(&mut file, &[]);
+            drain_rt_hist(rt_hist, &mut file);
+        }
+        if let Ok(releases) = $state.metadata::<ReleaseStats>() {
+            releases.dump_csv(&$cli.dump_name.clone().unwrap().with_extension("releases.csv")).expect("Could not write release stats");
+        }
+        if let Ok(errors) = $state.metadata::<ErrorClassStats>() {
+            errors.dump_csv(&$cli.dump_name.clone().unwrap().with_extension("errors.csv")).expect("Could not write error stats");
+        }
+        #[cfg(feature = "trace_stg")]
+        if let Ok(stg) = $state.metadata::<STGFeedbackState<TargetSystem>>() {
+            fs::write($cli.dump_name.clone().unwrap().with_extension("abb_variance.csv"), abb_variance_csv(&stg.abb_time_stats)).expect("Could not write abb variance");
         }
     }
 };
@@ -138,11 +135,10 @@ macro_rules! do_dump_stg {
         let dump_path = $cli.dump_name.clone().unwrap().with_extension(if $c=="" {"dot"} else {$c});
         println!("Dumping graph to {:?}", &dump_path);
         if let Ok(md) = $state.metadata_mut::<STGFeedbackState<TargetSystem>>() {
-            let out = md.graph.map(|_i,x| x.color_print(&md.systemstate_index), |_i,x| x.color_print());
-            let outs = Dot::with_config(&out, &[]).to_string();
-            let outs = outs.replace("\\\"","\"");
-            let outs = outs.replace(';',"\\n");
-            fs::write(dump_path,outs).expect("Failed to write graph");
+            let since = if $cli.stg_dump_mode == StgDumpMode::Delta {md.last_dump_size} else {(0,0)};
+            let outs = md.to_dot(since);
+            md.last_dump_size = (md.graph.node_count(), md.graph.edge_count());
+            write_dump(dump_path,outs).expect("Failed to write graph");
         }
     }
 };
@@ -155,11 +151,25 @@ macro_rules! do_dump_toprated {
         {
             let dump_path = $cli.dump_name.clone().unwrap().with_extension(if $c=="" {"toprated"} else {$c});
             println!("Dumping toprated to {:?}", &dump_path);
-            if let Some(md) = $state.metadata_map_mut().get_mut::<TopRatedsMetadata>() {
+            let uniq = if let Some(md) = $state.metadata_map_mut().get_mut::<TopRatedsMetadata>() {
                 let mut uniq: Vec<CorpusId> = md.map.values().map(|x| x.clone()).collect();
                 uniq.sort();
                 uniq.dedup();
-                fs::write(dump_path,ron::to_string(&md.map).expect("Failed to serialize metadata")).expect("Failed to write graph");
+                fs::write(&dump_path,ron::to_string(&md.map).expect("Failed to serialize metadata")).expect("Failed to write graph");
+                uniq
+            } else {
+                Vec::new()
+            };
+            // STG edges of each top rated testcase, see graph2viz --paths
+            #[cfg(feature = "trace_stg")]
+            {
+                let paths: HashMap<usize, Vec<usize>> = uniq.iter().filter_map(|id| {
+                    let tc = $state.corpus().get(*id).ok()?.borrow();
+                    tc.metadata_map().get::<STGNodeMetadata>().map(|md| (id.0, md.edges().iter().map(|e| e.index()).collect()))
+                }).collect();
+                let mut paths_path = dump_path.into_os_string();
+                paths_path.push(".paths");
+                fs::write(paths_path,ron::to_string(&paths).expect("Failed to serialize paths")).expect("Failed to write paths");
             }
         }
     }
@@ -176,8 +186,14 @@ SimpleStderrLogger::set_logger().unwrap();
 let cli = Cli::parse();
 dbg!(&cli);
 set_env_from_config(&cli.kernel, &cli.config);
-let interrupt_config = crate::cli::get_interrupt_config(&cli.kernel, &cli.config);
+if let Commands::Check = cli.command {
+    process::exit(crate::check::run(&cli));
+}
+if let Commands::Import { input, output, strip } = &cli.command {
+    process::exit(crate::import::run(&cli, input, output.as_ref(), strip));
+}
 unsafe {FUZZ_START_TIMESTAMP = SystemTime::now();}
+set_compress_dumps(cli.compress_dumps);
 if cli.dump_name.is_none() && (cli.dump_times || cli.dump_cases || cli.dump_traces || cli.dump_graph) {
     panic!("Dump name not give but dump is requested");
 }
@@ -189,156 +205,265 @@ let cores = Cores::from_cmdline("1").unwrap();
 let corpus_dirs = [PathBuf::from("./corpus")];
 let objective_dir = PathBuf::from(cli.dump_name.clone().map(|x| x.with_extension("crashes")).unwrap_or("./crashes".try_into().unwrap()));
 
-let mut elf_buffer = Vec::new();
-let elf = EasyElf::from_file(
-    &cli.kernel,
-    &mut elf_buffer,
-)
-.unwrap();
-
-let TARGET_SYMBOLS: HashMap<&'static str, GuestAddr> = get_target_symbols(&elf);
-let TARGET_RANGES: HashMap<&'static str, Range<GuestAddr>> = get_target_ranges(&elf, &TARGET_SYMBOLS);
-let TARGET_GROUPS: HashMap<&'static str, HashMap<String, Range<GuestAddr>>> = get_range_groups(&elf, &TARGET_SYMBOLS, &TARGET_RANGES);
-
-unsafe {
-    libafl_num_interrupts = [0; NUM_INTERRUPT_SOURCES];
+let target = Target::load(&cli.kernel, &cli.config).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    process::exit(1)
+});
+unsafe {dbg!(MAX_INPUT_SIZE);}
+let interrupt_config = target.interrupt_config.clone();
+let TARGET_SYMBOLS: &HashMap<&'static str, GuestAddr> = &target.symbols;
+let TARGET_RANGES: &HashMap<&'static str, Range<GuestAddr>> = &target.ranges;
+let TARGET_GROUPS: &HashMap<&'static str, HashMap<String, Range<GuestAddr>>> = &target.groups;
+
+set_qemu_icount_shift(cli.icount_shift.unwrap_or_else(|| env::var("ICOUNT_SHIFT").map_or(DEFAULT_QEMU_ICOUNT_SHIFT, |x| str::parse::<u32>(&x).expect("ICOUNT_SHIFT must be an integer."))));
+set_error_context(cli.error_context);
+if let Commands::ExportModel { checkpoint: Some(dir), output, .. } = &cli.command {
+    match read_checkpoint_model::<TargetSystem>(dir) {
+        Ok(Some(model)) => {
+            model.to_file(output).expect("Can not write task model");
+            println!("Wrote {} tasks to {}", model.tasks.len(), output.display());
+            process::exit(0);
+        }
+        Ok(None) => eprintln!("No STG checkpoint in {}", dir.display()),
+        Err(e) => eprintln!("Could not load checkpoint {}: {}", dir.display(), e),
+    }
+    process::exit(1);
 }
 
-if let Ok(input_len) = env::var("FUZZ_INPUT_LEN") {
-    unsafe {MAX_INPUT_SIZE = str::parse::<usize>(&input_len).expect("FUZZ_INPUT_LEN was not a number");}
+let qemu_config = QemuConfig::from_env();
+if let Err(e) = qemu_config.validate() {
+    eprintln!("Invalid QEMU configuration: {}", e);
+    std::process::exit(1);
 }
-unsafe {dbg!(MAX_INPUT_SIZE);}
 
 if let Ok(seed) = env::var("SEED_RANDOM") {
     unsafe {RNG_SEED = str::parse::<u64>(&seed).expect("SEED_RANDOM must be an integer.");}
 }
 
+if let Some(dump_name) = &cli.dump_name {
+    let manifest = CampaignManifest::collect(&cli.kernel, &interrupt_config, unsafe {MAX_INPUT_SIZE}, unsafe {RNG_SEED}, qemu_icount_shift(), cli.select_task.clone())
+        .expect("Can not read kernel");
+    manifest.write(&dump_name.with_extension("manifest.ron")).expect("Can not write manifest");
+}
+
 
 let denylist: Vec<_> = TARGET_GROUPS["ISR_FN"].values().map(|x| x.clone()).collect();
 let denylist = StdAddressFilter::deny_list(denylist); // do not count isr jumps, which are useless
 
-/// Setup the interrupt inputs. Noop if interrupts are not fuzzed
+/// Setup the interrupt inputs, the task phases and an empty program input if it is missing. The interrupt inputs are a noop if interrupts are not fuzzed
 fn setup_interrupt_inputs(mut input : MultipartInput<BytesInput>, interrupt_config : &Vec<(usize,u32)>, mut random: Option<&mut StdRng>) -> MultipartInput<BytesInput> {
+    bytes_part_or_insert(&mut input);
     #[cfg(feature = "fuzz_int")]
     for (i,_) in interrupt_config {
-        let name = format!("isr_{}_times",i);
+        let name = interrupt_part_name(*i);
         if input.parts_by_name(&name).next().is_none() {
             if let Some(random) = random.as_mut() {
-                input.add_part(name, BytesInput::new((0..MAX_NUM_INTERRUPT).map(|_| (random.next_u32()%(100*QEMU_ISNS_PER_MSEC)).to_le_bytes()).flatten().collect()));
+                input.add_part(name, BytesInput::new((0..max_num_interrupt()).map(|_| (random.next_u32()%(100*qemu_isns_per_msec())).to_le_bytes()).flatten().collect()));
             } else {
-                input.add_part(name, BytesInput::new([0; MAX_NUM_INTERRUPT*4].to_vec()));
+                input.add_part(name, BytesInput::new(vec![0; max_num_interrupt()*4]));
             }
         }
     }
+    if !task_phase_bounds().is_empty() && input.parts_by_name(TASK_PHASES_PART).next().is_none() {
+        let phases : Vec<u32> = task_phase_bounds().iter().map(|x| random.as_mut().map_or(0, |r| r.next_u32() % x)).collect();
+        set_task_phases(&mut input, &phases);
+    }
     input
 }
 
-// Client setup ================================================================================
+/// Reads the input of a single run. The ticks of a replay script are injected as recorded, see [`load_replay_script`]
+fn read_single_input(path: &Path, interrupt_config : &Vec<(usize,u32)>) -> MultipartInput<BytesInput> {
+    if let Some(script) = read_replay_script(path) {
+        println!("Injecting the interrupts of replay script {}", path.display());
+        return load_replay_script(script).to_input();
+    }
+    let (input, format) = read_input_file(path).expect("Can not read input file");
+    println!("Running {:?} input {}", format, path.display());
+    if format == InputFormat::Raw {
+        setup_interrupt_inputs(input, interrupt_config, None)
+    } else {
+        input
+    }
+}
 
-let run_client = |state: Option<_>, mut mgr, _core_id| {
-    // Initialize QEMU
-    let args: Vec<String> = vec![
-        "target/debug/fret",
-        "-icount",
-        &format!("shift={},align=off,sleep=off", QEMU_ICOUNT_SHIFT),
-        "-machine",
-        "mps2-an385",
-        "-cpu",
-        "cortex-m3",
-        "-monitor",
-        "null",
-        "-kernel",
-        &cli.kernel.as_os_str().to_str().expect("kernel path is not a string"),
-        "-serial",
-        "null",
-        "-nographic",
-        "-S",
-        // "-semihosting",
-        // "--semihosting-config",
-        // "enable=on,target=native",
-        #[cfg(not(feature = "snapshot_fast"))]
-        "-snapshot",
-        #[cfg(not(feature = "snapshot_fast"))]
-        "-drive",
-        #[cfg(not(feature = "snapshot_fast"))]
-        "if=none,format=qcow2,file=dummy.qcow2",
-    ].into_iter().map(String::from).collect();
-    let env: Vec<(String, String)> = env::vars().collect();
-    let qemu = Qemu::init(&args).expect("Emulator creation failed");
-
-    if let Some(&main_addr) = TARGET_SYMBOLS.get("FUZZ_MAIN") {
-        qemu.set_breakpoint(main_addr);
-        unsafe {
-            match qemu.run() {
-                Ok(QemuExitReason::Breakpoint(_)) => {}
-                _ => panic!("Unexpected QEMU exit."),
+/// Writes the program input and the injected interrupt ticks of `input` as a [`ReplayScript`]
+fn emit_replay_script(path: &Path, input: &MultipartInput<BytesInput>, interrupt_config : &Vec<(usize,u32)>) {
+    // without fuzzed interrupts the harness injects none
+    let config: &[(usize,u32)] = if cfg!(feature = "fuzz_int") { interrupt_config } else { &[] };
+    let script = match loaded_replay_script() {
+        Some(x) => x.clone(),
+        None => ReplayScript::from_input(input, config).expect("Can not build replay script"),
+    };
+    fs::write(path, script.to_ron()).expect("Can not write replay script");
+    println!("Wrote the replay script to {}", path.display());
+}
+
+/// Files of a corpus directory and its subdirectories, like LibAFL's `load_initial_inputs`.
+/// Hidden files are metadata and locks of an OnDiskCorpus, `quarantine` holds the entries [`load_corpus`] rejected.
+fn corpus_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) => {
+            log::error!("Can not read corpus directory {}: {}", dir.display(), e);
+            return;
+        }
+    };
+    for path in entries.flatten().map(|x| x.path()) {
+        let name = path.file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        if name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            if name != "quarantine" {
+                corpus_files(&path, files);
             }
+        } else if path.is_file() {
+            files.push(path);
         }
-        qemu.remove_breakpoint(main_addr);
     }
+}
 
-    qemu.set_breakpoint(TARGET_SYMBOLS["BREAKPOINT"]); // BREAKPOINT
-
-    let devices = qemu.list_devices();
-    println!("Devices = {devices:?}");
-
-    #[cfg(feature = "snapshot_fast")]
-    let initial_snap = Some(qemu.create_fast_snapshot(true));
-    #[cfg(not(feature = "snapshot_fast"))]
-    let initial_snap = None;
-
-    let harness_input_addr = TARGET_SYMBOLS["FUZZ_INPUT"];
-    let harness_input_length_ptr = TARGET_SYMBOLS.get("FUZZ_LENGTH").copied();
-    let harness_breakpoint = TARGET_SYMBOLS["BREAKPOINT"];
-
-    // The wrapped harness function, calling out to the LLVM-style harness
-    let mut harness = |emulator: &mut Emulator<_, _, _, _, _>, state: &mut _, input: &MultipartInput<BytesInput>| {
-        unsafe {
-            #[cfg(feature = "fuzz_int")]
-            {
-                libafl_interrupt_offsets=[[0;MAX_NUM_INTERRUPT];NUM_INTERRUPT_SOURCES];
-                for &c in &interrupt_config {
-                    let (i,_) = c;
-                    let name = format!("isr_{}_times",i);
-                    let input_bytes = input.parts_by_name(&name).next().map(|x| x.1.bytes()).unwrap_or(&[]);
-                    let t = input_bytes_to_interrupt_times(input_bytes, c);
-                    for j in 0..t.len() {libafl_interrupt_offsets[i][j]=t[j];}
-                    libafl_num_interrupts[i]=t.len() as u64;
+/// Load the corpus entries in `dirs` and fit them to the current target, see [`repair_input`].
+/// Entries which can not be used at all are moved into a `quarantine` subdirectory instead of aborting the campaign.
+fn load_corpus(dirs: &[PathBuf], sources: &[usize]) -> Vec<MultipartInput<BytesInput>> {
+    let mut ret = Vec::new();
+    let (mut repaired, mut quarantined) = (0, 0);
+    for dir in dirs {
+        let mut files = Vec::new();
+        corpus_files(dir, &mut files);
+        for path in files {
+            let loaded = MultipartInput::<BytesInput>::from_file(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|x| repair_input(&x, unsafe { MAX_INPUT_SIZE }, sources).map_err(|e| e.to_string()));
+            match loaded {
+                Ok((input, repairs)) => {
+                    if !repairs.is_empty() {
+                        log::warn!("Repaired corpus entry {}: {}", path.display(), repairs);
+                        repaired += 1;
+                    }
+                    ret.push(input);
+                }
+                Err(e) => {
+                    log::error!("Quarantining corpus entry {}: {}", path.display(), e);
+                    quarantined += 1;
+                    let quarantine = dir.join("quarantine");
+                    if let Err(e) = fs::create_dir_all(&quarantine).and_then(|_| fs::rename(&path, quarantine.join(path.file_name().unwrap()))) {
+                        log::error!("Can not move {} to {}: {}", path.display(), quarantine.display(), e);
+                    }
                 }
-
-                // println!("Load: {:?}", libafl_interrupt_offsets[0..libafl_num_interrupts].to_vec());
             }
+        }
+    }
+    println!("Corpus: {} loaded, {} repaired, {} quarantined", ret.len(), repaired, quarantined);
+    ret
+}
 
-            let mut bytes = input.parts_by_name("bytes").next().unwrap().1.bytes();
-            let mut len = bytes.len();
-            if len > MAX_INPUT_SIZE {
-                bytes = &bytes[0..MAX_INPUT_SIZE];
-                len = MAX_INPUT_SIZE;
-            }
+// Single runs ================================================================================
 
-            // Note: I could not find a difference between write_mem and write_phys_mem for my usecase
-            qemu.write_mem(harness_input_addr, bytes);
-            if let Some(s) = harness_input_length_ptr {
-                qemu.write_mem(s, &(len as u32).to_le_bytes());
+// A single input needs neither a corpus nor an event manager
+let single_runner = || {
+    let options = RunnerOptions {
+        qemu_args: cli.qemu_args.iter().flat_map(|x| x.split_whitespace().map(String::from)).collect(),
+        select_task: cli.single_task(),
+        icount_budget: cli.icount_budget.or_else(|| env::var("ICOUNT_BUDGET").ok().map(|x| str::parse::<u64>(&x).expect("ICOUNT_BUDGET must be an integer."))),
+        trace_limits: TraceLimits { max_states: cli.max_trace_states, max_ticks: cli.max_trace_ticks },
+    };
+    TargetRunner::with_options(target.clone(), &qemu_config, options).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    })
+};
+if let Commands::ExportArtifact { input, id, output } = cli.command.clone() {
+    // Hidden files are metadata and locks of an OnDiskCorpus
+    let paths: Vec<PathBuf> = fs::read_dir(&input).expect("Can not read input directory")
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.is_file() && !x.file_name().map_or(true, |n| n.to_string_lossy().starts_with('.')))
+        .sorted()
+        .collect();
+    let paths = match id {
+        Some(id) => match paths.get(id) {
+            Some(x) => vec![x.clone()],
+            None => {
+                eprintln!("Input {} not found, {} only holds {} inputs", id, input.display(), paths.len());
+                process::exit(1)
             }
-
-            qemu.run();
-
-            // If the execution stops at any point other then the designated breakpoint (e.g. a breakpoint on a panic method) we consider it a crash
-            let mut pcs = (0..qemu.num_cpus())
-                .map(|i| qemu.cpu_from_index(i))
-                .map(|cpu| -> Result<u32, _> { cpu.read_reg(Regs::Pc) });
-            match pcs
-                .find(|pc| (harness_breakpoint..harness_breakpoint + 5).contains(pc.as_ref().unwrap_or(&0)))
-            {
-                Some(_) => ExitKind::Ok,
-                Option::None => ExitKind::Crash,
+        },
+        None => paths,
+    };
+    let mut inputs = Vec::new();
+    for path in paths {
+        match read_input_file(&path) {
+            Ok((x, InputFormat::Raw)) => inputs.push((path, setup_interrupt_inputs(x, &interrupt_config, None))),
+            Ok((x, _)) => inputs.push((path, x)),
+            Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    let mut runner = single_runner();
+    process::exit(crate::artifact::export(&mut runner, inputs, &output, cli.single_task()));
+}
+if let Commands::VerifyArtifact { input, tolerance } = cli.command.clone() {
+    let mut runner = single_runner();
+    process::exit(crate::artifact::verify(&mut runner, &input, tolerance, cli.single_task()));
+}
+if let Commands::Showmap { input, emit_replay_script: script_path } = cli.command.clone() {
+    let mut runner = single_runner();
+    let show_input = read_single_input(&input, &interrupt_config);
+    let result = runner.run(&show_input);
+    println!("{} ticks, {:?} ({:?})", result.exec_ticks, result.exit_kind, result.guest_exit.exit);
+    if let Some(trace) = &result.trace {
+        if cli.dump_times {
+            let timestamp = SystemTime::now().duration_since(unsafe { FUZZ_START_TIMESTAMP }).unwrap().as_millis();
+            #[cfg(feature = "trace_job_response_times")]
+            let worts: Vec<u64> = cli.task_columns().iter().map(|t| trace.wort_of_task(t)).collect();
+            #[cfg(not(feature = "trace_job_response_times"))]
+            let worts: Vec<u64> = cli.task_columns().iter().map(|_| 0).collect();
+            let worts = if worts.is_empty() { Vec::new() } else { vec![worts] };
+            runner.state_mut().add_metadata(IcHist(vec![(result.exec_ticks, timestamp)], (result.exec_ticks, timestamp), worts));
+            write_ron_dump(cli.dump_name.clone().unwrap().with_extension("icounttrace.ron"), trace).expect("Can not write trace");
+        }
+        if cli.dump_traces {
+            write_ron_dump(cli.dump_name.clone().unwrap().with_extension("trace.ron"), trace).expect("Can not write trace");
+        }
+        #[cfg(feature = "trace_stg")]
+        {
+            let mut fbs = STGFeedbackState::<TargetSystem>::default();
+            StgFeedback::<TargetSystem>::update_stg_interval(trace.intervals(), trace.mem_reads(), trace.states_map(), &mut fbs, WorstOrigin::new(0, compute_hash(&show_input)));
+            runner.state_mut().add_metadata(fbs);
+            // non-zero entries of the ABB coverage map, like afl-showmap
+            set_abb_map(trace.intervals());
+            for (i, hits) in unsafe { abb_map_mut_slice() }.iter().enumerate().filter(|x| *x.1 > 0) {
+                println!("{:06}:{}", i, hits);
             }
         }
+    }
+    let state = runner.state_mut();
+    do_dump_times!(state, &cli, "");
+    do_dump_stg!(state, &cli, "");
+    if let Some(p) = script_path {
+        emit_replay_script(&p, &show_input, &interrupt_config);
+    }
+    return;
+}
+
+// Client setup ================================================================================
+
+let run_client = |state: Option<_>, mut mgr, _core_id| {
+    // Initialize QEMU
+    let mut extra_args: Vec<String> = cli.qemu_args.iter().flat_map(|x| x.split_whitespace().map(String::from)).collect();
+    if let Commands::Debug { gdb: Some(port), .. } = &cli.command {
+        extra_args.extend(["-gdb".to_string(), format!("tcp::{}", port)]);
+    }
+    let (qemu, initial_snap) = boot_target(&target, &qemu_config, &extra_args);
+
+    // The wrapped harness function, calling out to the LLVM-style harness
+    let target_harness = Harness::new(qemu, &target);
+    let mut harness = |emulator: &mut Emulator<_, _, _, _, _>, state: &mut _, input: &MultipartInput<BytesInput>| {
+        target_harness.run(input)
     };
 
         // Create an observation channel to keep track of the execution time
-        let clock_time_observer = QemuClockObserver::new("clocktime", &cli.select_task); // if cli.dump_times {cli.dump_name.clone().map(|x| x.with_extension("time"))} else {None}
+        let clock_time_observer = QemuClockObserver::new("clocktime", &cli.single_task()); // if cli.dump_times {cli.dump_name.clone().map(|x| x.with_extension("time"))} else {None}
 
         // Create an observation channel using the coverage map
         #[cfg(feature = "observe_edges")]
@@ -356,14 +481,17 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
         let stg_coverage_observer = unsafe { VariableMapObserver::from_mut_slice(
             "stg",
             stg_map_mut_slice(),
-            addr_of_mut!(MAX_STG_NUM)
+            max_stg_num_ptr()
         )}.track_indices();
+        #[cfg(feature = "observe_systemstate")]
+        let abb_coverage_observer = abb_coverage_observer();
 
         // Feedback to rate the interestingness of an input
         // This one is composed by two Feedbacks in OR
         let mut feedback = feedback_or!(
             // Time feedback, this one does not need a feedback state
-            ClockTimeFeedback::<TargetSystem>::new_with_observer(&clock_time_observer, &cli.select_task, if cli.dump_times {cli.dump_name.clone().map(|x| x.with_extension("time"))} else {None})
+            ClockTimeFeedback::<TargetSystem>::new_with_observer(&clock_time_observer, &cli.single_task(), if cli.dump_times {cli.dump_name.clone().map(|x| x.with_extension("time"))} else {None})
+                .with_task_columns(cli.task_columns())
         );
         #[cfg(feature = "feed_genetic")]
         let mut feedback = feedback_or!(
@@ -386,23 +514,61 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
         #[cfg(all(feature = "observe_systemstate"))]
         let mut feedback = feedback_or!(
             feedback,
-            DumpSystraceFeedback::<TargetSystem>::with_dump(if cli.dump_traces {cli.dump_name.clone()} else {None})
+            {
+                let dump = DumpSystraceFeedback::<TargetSystem>::with_dump(if cli.dump_traces {cli.dump_name.clone()} else {None})
+                    .with_input_usage(TARGET_SYMBOLS["FUZZ_INPUT"], unsafe { MAX_INPUT_SIZE })
+                    .with_error_windows(10);
+                match cli.trace_records {
+                    Some(keep) => dump.with_record_filter(&clock_time_observer, keep),
+                    None => dump,
+                }
+            }
+        );
+        #[cfg(feature = "trace_job_response_times")]
+        let mut feedback = feedback_or!(
+            feedback,
+            ResponseTimeHistogramFeedback::<TargetSystem>::new(
+                cli.rt_histogram_interval.and(cli.dump_name.as_ref().map(|x| x.with_extension("hist.csv"))),
+                Duration::from_secs(cli.rt_histogram_interval.unwrap_or(0).max(1))
+            )
+        );
+        #[cfg(feature = "trace_job_response_times")]
+        let mut feedback = feedback_or!(
+            feedback,
+            ReleaseStatsFeedback::<TargetSystem>::new(crate::cli::get_task_periods())
+        );
+        #[cfg(feature = "feed_stack_depth")]
+        let mut feedback = feedback_or!(
+            feedback,
+            StackDepthFeedback::<TargetSystem>::new()
+        );
+        #[cfg(feature = "feed_preempt")]
+        let mut feedback = feedback_or!(
+            feedback,
+            PreemptionFeedback::<TargetSystem>::new()
         );
         #[cfg(feature = "trace_stg")]
         let mut feedback = feedback_or!(
             feedback,
-            StgFeedback::<TargetSystem>::new(cli.select_task.clone(), if cli.dump_graph {cli.dump_name.clone()} else {None})
+            StgFeedback::<TargetSystem>::new(cli.single_task(), if cli.dump_graph {cli.dump_name.clone()} else {None})
+                .with_tracked_tasks(cli.task_columns())
         );
         #[cfg(feature = "feed_stg_edge")]
         let mut feedback = feedback_or!(
             feedback,
             MaxMapFeedback::new(&stg_coverage_observer)
         );
+        #[cfg(feature = "feed_abb")]
+        let mut feedback = feedback_or!(
+            feedback,
+            MaxMapFeedback::new(&abb_coverage_observer)
+        );
 
         // A feedback to choose if an input is producing an error
-        let mut objective = feedback_or_fast!(CrashFeedback::new(), TimeoutFeedback::new(), SystraceErrorFeedback::<TargetSystem>::new(matches!(cli.command, Commands::Fuzz{..}), Some(10)));
+        let mut objective = feedback_or_fast!(CrashFeedback::new(), TimeoutFeedback::new(), TargetAssertFeedback::new(), DeadlineFeedback::<TargetSystem>::new(crate::cli::get_task_deadlines()), SystraceErrorFeedback::<TargetSystem>::new(matches!(cli.command, Commands::Fuzz{..}), Some(10)).with_isr_budget_objective(cli.isr_budget_objective).with_fail_objective(cli.fail_objective).with_budget_objective(cli.budget_objective).with_truncation_objective(cli.truncation_objective).with_error_actions(crate::cli::get_error_actions()));
 
         // If not restarting, create a State from scratch
+        let fresh_state = state.is_none();
         let mut state = state.unwrap_or_else(|| {
             StdState::new(
                 // RNG
@@ -420,27 +586,54 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
             )
             .unwrap()
         });
+        // A state lost to a crash is resumed from the last checkpoint, a restarted state is newer than any checkpoint
+        if let (true, Commands::Fuzz { .. }, Some(dir)) = (fresh_state, &cli.command, &cli.checkpoint_dir) {
+            match load_checkpoint::<_, TargetSystem>(&mut state, dir) {
+                Ok(true) => println!("Resumed from checkpoint {:?}", dir),
+                Ok(false) => (),
+                Err(e) => eprintln!("Could not load checkpoint {:?}: {}", dir, e),
+            }
+        }
 
         // A minimization+queue policy to get testcasess from the corpus
-        #[cfg(not(any(feature = "sched_afl", feature = "sched_stg", feature = "sched_genetic")))]
+        #[cfg(not(any(feature = "sched_afl", feature = "sched_stg", feature = "sched_genetic", feature = "sched_improvement")))]
         let scheduler = QueueScheduler::new();  // fallback
-        #[cfg(feature = "sched_afl",)]
+        #[cfg(all(feature = "sched_improvement", not(any(feature = "sched_afl", feature = "sched_stg", feature = "sched_genetic"))))]
+        let scheduler = TimeImprovementScheduler::new().with_floor(cli.improvement_floor);
+        #[cfg(all(feature = "sched_afl", not(feature = "sched_improvement")))]
         let scheduler = TimeMaximizerCorpusScheduler::new(&edges_observer,TimeProbMassScheduler::new());
-        #[cfg(feature = "sched_stg")]
+        #[cfg(all(feature = "sched_afl", feature = "sched_improvement"))]
+        let scheduler = TimeMaximizerCorpusScheduler::new(&edges_observer,TimeImprovementScheduler::new().with_floor(cli.improvement_floor));
+        #[cfg(all(feature = "sched_stg", not(feature = "sched_improvement")))]
         let mut scheduler = GraphMaximizerCorpusScheduler::non_metadata_removing(&stg_coverage_observer,TimeProbMassScheduler::new());
+        #[cfg(all(feature = "sched_stg", feature = "sched_improvement"))]
+        let mut scheduler = GraphMaximizerCorpusScheduler::non_metadata_removing(&stg_coverage_observer,TimeImprovementScheduler::new().with_floor(cli.improvement_floor));
         #[cfg(feature = "sched_stg")]
         {
             scheduler.skip_non_favored_prob = 0.8;
+            if let (true, Some(task)) = (cli.favor_select_task, cli.single_task()) {
+                set_favored_task(task);
+            }
         }
         #[cfg(feature = "sched_genetic")]
-        let scheduler = GenerationScheduler::new();
+        let scheduler = GenerationScheduler::new()
+            .with_population_size(cli.population_size)
+            .with_tournament_size(cli.tournament_size)
+            .with_novelty_weight(cli.novelty_weight);
 
         // A fuzzer with feedbacks and a corpus scheduler
         let mut fuzzer = StdFuzzer::new(scheduler, feedback, objective);
 
         let qhelpers = tuple_list!();
         #[cfg(feature = "observe_systemstate")]
-        let qhelpers = (SystemStateHelper::new(&TARGET_SYMBOLS,&TARGET_RANGES,&TARGET_GROUPS), qhelpers);
+        let system_state_helper = SystemStateHelper::new(TARGET_SYMBOLS,TARGET_RANGES,TARGET_GROUPS).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1)
+        });
+        let trace_limits = TraceLimits { max_states: cli.max_trace_states, max_ticks: cli.max_trace_ticks };
+        let qhelpers = (system_state_helper.with_isr_budgets(crate::cli::get_isr_budgets()).with_job_done_apis(crate::cli::get_job_done_apis()).with_trace_limits(trace_limits), qhelpers);
+        #[cfg(feature = "observe_systemstate")]
+        let qhelpers = (AbbBreakpointModule::default(), qhelpers);
         #[cfg(feature = "observe_edges")]
         let qhelpers = (
             StdEdgeCoverageModule::builder()
@@ -448,13 +641,17 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
             .address_filter(denylist)
             .build()
             .unwrap(), qhelpers);//StdEdgeCoverageModule::new(denylist, FilterList::None), qhelpers);
-        let qhelpers = (QemuStateRestoreHelper::with_fast(initial_snap), qhelpers);
+        let icount_budget = cli.icount_budget.or_else(|| env::var("ICOUNT_BUDGET").ok().map(|x| str::parse::<u64>(&x).expect("ICOUNT_BUDGET must be an integer.")));
+        let qhelpers = (IcountBudgetModule::new(icount_budget), qhelpers);
+        let qhelpers = (QemuStateRestoreHelper::with_fast(initial_snap).with_setup_done(target.symbols.get("FUZZ_SETUP_DONE").copied()), qhelpers);
 
         let emulator = Emulator::empty().qemu(qemu).modules(qhelpers).build().unwrap();
 
         let observer_list = tuple_list!();
         #[cfg(feature = "observe_systemstate")]
         let observer_list = (stg_coverage_observer, observer_list);  // must come after clock
+        #[cfg(feature = "observe_systemstate")]
+        let observer_list = (abb_coverage_observer, observer_list);
         #[cfg(feature = "observe_edges")]
         let observer_list = (edges_observer, observer_list);
         let observer_list = (clock_time_observer, observer_list);
@@ -477,37 +674,213 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
         // Setup an havoc mutator with a mutational stage
         let mutator = StdScheduledMutator::new(mutations);
 
-        let stages = (systemstate::report::SchedulerStatsStage::default(),());
+        let stages = (systemstate::report::SchedulerStatsStage::<_,_,_,TargetSystem>::new(Duration::from_secs(cli.stats_interval), cli.single_task()).with_status(cli.status_port.is_some()),());
         let stages = (StdMutationalStage::new(mutator), stages);
         #[cfg(feature = "mutate_stg")]
-        let mut stages = (STGSnippetStage::<_,_,_,TargetSystem>::new(TARGET_SYMBOLS["FUZZ_INPUT"]), stages);
+        let mut stages = (STGSnippetStage::<_,_,_,TargetSystem>::new(TARGET_SYMBOLS["FUZZ_INPUT"]).with_both_resolutions(cli.snippet_both_resolutions), stages);
+        #[cfg(feature = "fuzz_phases")]
+        let mut stages = (StdMutationalStage::new(StdScheduledMutator::new(tuple_list!(TaskPhaseMutator::new()))), stages);
         #[cfg(feature = "fuzz_int")]
-        let mut stages = (InterruptShiftStage::<_,_,_,TargetSystem>::new(&interrupt_config), stages);
-
-        if let Commands::Showmap { input } = cli.command.clone() {
-            let s = input.as_os_str();
-            // let show_input = BytesInput::new(if s=="-" {
-            //         let mut buf = Vec::<u8>::new();
-            //         std::io::stdin().read_to_end(&mut buf).expect("Could not read Stdin");
-            //         buf
-            //     } else if s=="$" {
-            //         env::var("SHOWMAP_TEXTINPUT").expect("SHOWMAP_TEXTINPUT not set").as_bytes().to_owned()
-            //     } else {
-            //         // fs::read(s).expect("Input file for DO_SHOWMAP can not be read")
-            //     });
-            let show_input = match MultipartInput::from_file(input.as_os_str()) {
-                Ok(x) => x,
-                Err(_) => {
+        let mut stages = (InterruptShiftStage::<_,_,_,TargetSystem>::new(&interrupt_config).with_burst_limits(&crate::cli::get_interrupt_burst_limits(&cli.kernel, &cli.config)).with_isr_wakes(&crate::cli::get_isr_wakes(), cli.wake_probability), stages);
+        #[cfg(feature = "divergence_stage")]
+        let mut stages = (ScheduleDivergenceStage::<_,_,_,TargetSystem>::new(cli.divergence_threshold, cli.divergence_threshold.map(|_| cli.dump_name.clone().map(|x| x.with_extension("divergent")).unwrap_or("./divergent".into()))), stages);
+        let mut stages = (CheckpointStage::<_,_,_,TargetSystem>::new(cli.checkpoint_dir.clone(), Duration::from_secs(cli.checkpoint_interval * 60)), stages);
+        let mut stages = (DominatedCullingStage::new(cli.cull_interval), stages);
+        let mut stages = (StgPruningStage::<_,_,_,TargetSystem>::new(cli.stg_max_nodes, cli.stg_max_rss.map(|x| x * 1024 * 1024), cli.stg_prune_age), stages);
+        let mut stages = (StgSnapshotStage::<_,_,_,TargetSystem>::new(cli.dump_name.clone(), cli.dump_graph_every.map(|x| Duration::from_secs(x * 60))), stages);
+        #[cfg(feature = "minimize_stage")]
+        let mut stages = (WorstCaseMinimizerStage::<_,_,_,TargetSystem>::new(cli.minimize_interval, cli.minimize_tolerance, cli.single_task(), cli.dump_name.clone().map(|x| x.with_extension("minimized")).unwrap_or("./minimized".into())), stages);
+
+        if let Commands::Calibrate { input, runs, append } = cli.command.clone() {
+            let calib_input = match input {
+                Some(p) => MultipartInput::from_file(p.as_os_str()).unwrap_or_else(|_| {
                     println!("Interpreting input file as raw input");
-                    setup_interrupt_inputs(MultipartInput::from([("bytes",BytesInput::new(fs::read(input).expect("Can not read input file")))]), &interrupt_config, None)
+                    setup_interrupt_inputs(MultipartInput::from([("bytes",BytesInput::new(fs::read(&p).expect("Can not read input file")))]), &interrupt_config, None)
+                }),
+                Option::None => setup_interrupt_inputs(MultipartInput::from([("bytes",BytesInput::new(vec![0; MAX_INPUT_SIZE]))]), &interrupt_config, None),
+            };
+            let mut results = Vec::with_capacity(runs);
+            for _ in 0..runs {
+                fuzzer.evaluate_input(&mut state, &mut executor, &mut mgr, calib_input.clone()).unwrap();
+                let icount = unsafe {libafl_qemu::sys::icount_get_raw()};
+                let trace = state.metadata::<<TargetSystem as systemstate::target_os::TargetSystem>::TraceData>().expect("TraceData not found");
+                results.push(CalibrationRun::from_trace(icount, trace));
+            }
+            print!("{}", calibration_report(&results));
+            if let Some(p) = append {
+                append_calibration(&p, &cli.kernel, &results).expect("Could not append calibration results");
+            }
+        } else if let Commands::Debug { input, abb_start, task, gdb } = cli.command.clone() {
+            let debug_input = read_single_input(&input, &interrupt_config);
+            // First run to find the ABB instance
+            fuzzer.evaluate_input(&mut state, &mut executor, &mut mgr, debug_input.clone()).unwrap();
+            let trace = state.metadata::<<TargetSystem as systemstate::target_os::TargetSystem>::TraceData>().expect("TraceData not found");
+            let task = task.or(cli.selected_tasks().into_iter().next());
+            match select_break_target(trace, abb_start, task.as_ref()) {
+                Option::None => eprintln!("No instance of the ABB found, select a task or an ABB executed by the input"),
+                Some(target) => {
+                    println!("Target: ABB {:#x} of {} at tick {}, executed {} times before", target.abb_start, target.task, target.tick, target.occurrence);
+                    state.add_metadata(target);
+                    // Second run stops at the ABB instance, no feedbacks involved
+                    executor.run_target(&mut fuzzer, &mut state, &mut mgr, &debug_input).unwrap();
+                    let target = state.metadata::<BreakTarget>().unwrap().clone();
+                    match target.hit {
+                        Option::None => eprintln!("ABB {:#x} was not reached at tick {}", target.abb_start, target.tick),
+                        Some((occurrence, icount)) => {
+                            println!("Stopped at ABB {:#x} of {}, occurrence {}, icount {}", target.abb_start, target.task, occurrence, icount);
+                            let cpu = qemu.cpu_from_index(0);
+                            for r in 0..cpu.num_regs() {
+                                println!("r{:<3} {:#010x}", r, cpu.read_reg::<_, u32>(r).unwrap_or(0));
+                            }
+                            if let Some(port) = gdb {
+                                // Resuming hits a gdb breakpoint right away, QEMU then stays paused until gdb continues it
+                                println!("Waiting for gdb on port {}", port);
+                                unsafe {
+                                    libafl_qemu::sys::libafl_qemu_set_native_breakpoint(target.abb_start.into());
+                                    let _ = qemu.run();
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        } else if let Commands::Replay { input, output, emit_replay_script: script_path } = cli.command.clone() {
+            let replay_input = read_single_input(&input, &interrupt_config);
+            let (_, corpus_id) = fuzzer.evaluate_input(&mut state, &mut executor, &mut mgr, replay_input.clone()).unwrap();
+            if let Some(p) = script_path {
+                emit_replay_script(&p, &replay_input, &interrupt_config);
+            }
+            fs::create_dir_all(&output).expect("Can not create output directory");
+            let trace = state.metadata::<<TargetSystem as systemstate::target_os::TargetSystem>::TraceData>().expect("TraceData not found");
+            fs::write(output.join("trace.ron"), ron::to_string(trace).expect("Can not serialize trace")).expect("Can not write trace");
+            let mut jobs = String::from("name,release,response,exec_ticks,preemptions,preempted_ticks,inferred\n");
+            for j in trace.jobs() {
+                jobs.push_str(&format!("{},{},{},{},{},{},{}\n", j.name, j.release, j.response, j.exec_ticks, j.preemption_count, j.preempted_ticks, j.inferred));
+            }
+            fs::write(output.join("jobs.csv"), jobs).expect("Can not write jobs");
+            let mut abbs = String::from("name,addr,symbol,active,finish,ticks,woet\n");
+            for (name, profile) in trace.select_abb_profile(cli.single_task()).iter().sorted_by_key(|x| x.0) {
+                for (addr, (active, finish, time, woet, symbol)) in profile.iter().sorted_by_key(|x| x.0) {
+                    abbs.push_str(&format!("{},{},{},{},{},{},{}\n", name, addr, symbol.as_deref().unwrap_or(""), active, finish, time, woet));
+                }
+            }
+            fs::write(output.join("abbs.csv"), abbs).expect("Can not write abbs");
+            // The path is only recorded for inputs which enter the corpus, which the first one of a fresh state always does
+            #[cfg(feature = "trace_stg")]
+            if let Some(id) = corpus_id {
+                let testcase = state.corpus().get(id).unwrap().borrow();
+                if let (Ok(path), Ok(stg)) = (testcase.metadata::<STGNodeMetadata>(), state.metadata::<STGFeedbackState<TargetSystem>>()) {
+                    let mut out = String::from("step,node,event,name\n");
+                    for (i, node) in path.nodes().iter().enumerate() {
+                        let (event, name) = path.edges().get(i).map_or((String::new(), String::new()), |e| (format!("{:?}", stg.graph[*e].event), stg.graph[*e].name.to_string()));
+                        out.push_str(&format!("{},{},{},{}\n", i, stg.graph[*node].stable_id(), event, name));
+                    }
+                    fs::write(output.join("stg_path.csv"), out).expect("Can not write stg path");
                 }
+            }
+            println!("Wrote the replay to {}", output.display());
+        } else if let Commands::Minimize { input, output } = cli.command.clone() {
+            let (min_input, format) = read_input_file(&input).expect("Can not read input file");
+            let min_input = if format == InputFormat::Raw {
+                setup_interrupt_inputs(min_input, &interrupt_config, None)
+            } else {
+                min_input
             };
-            fuzzer.evaluate_input(&mut state, &mut executor, &mut mgr, show_input)
-                .unwrap();
-            do_dump_times!(state, &cli, "");
-            do_dump_stg!(state, &cli, "");
-        } else if let Commands::Fuzz { random, time, seed } = cli.command {
-            if let Some(se) = seed {
+            let (minimized, stats) = minimize_input(&min_input, cli.minimize_tolerance, |candidate| {
+                measure_input::<_,_,_,_,TargetSystem>(&mut fuzzer, &mut executor, &mut state, &mut mgr, candidate, &cli.single_task())
+            }).expect("Minimization failed");
+            println!("Minimized {}: {}", input.display(), stats);
+            minimized.to_file(&output).expect("Can not write minimized input");
+        } else if let Commands::ReportAbbs { input, output, variance } = cli.command.clone() {
+            // Hidden files are metadata and locks of an OnDiskCorpus
+            let paths: Vec<PathBuf> = fs::read_dir(&input).expect("Can not read input directory")
+                .filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .filter(|x| x.is_file() && !x.file_name().map_or(true, |n| n.to_string_lossy().starts_with('.')))
+                .sorted()
+                .collect();
+            let mut report = HashMap::new();
+            let mut time_stats = HashMap::new();
+            let mut executed = 0;
+            for path in paths {
+                let (report_input, format) = match read_input_file(&path) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        eprintln!("Skipping {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let report_input = if format == InputFormat::Raw {
+                    setup_interrupt_inputs(report_input, &interrupt_config, None)
+                } else {
+                    report_input
+                };
+                fuzzer.execute_input(&mut state, &mut executor, &mut mgr, &report_input).unwrap();
+                let trace = state.metadata::<<TargetSystem as systemstate::target_os::TargetSystem>::TraceData>().expect("TraceData not found");
+                merge_abb_profile(&mut report, trace.intervals());
+                add_abb_time_stats(&mut time_stats, trace.intervals());
+                executed += 1;
+            }
+            if variance {
+                fs::write(&output, abb_variance_csv(&time_stats)).expect("Can not write abb variance report");
+                println!("Wrote {} abbs of {} inputs to {}", time_stats.len(), executed, output.display());
+            } else {
+                fs::write(&output, abb_report_csv(&report)).expect("Can not write abb report");
+                println!("Wrote {} abbs of {} inputs to {}", report.len(), executed, output.display());
+            }
+        } else if let Commands::ExportModel { input: Some(input), output, .. } = cli.command.clone() {
+            // Like report-abbs, but collecting the worst jobs and releases of all inputs
+            let paths: Vec<PathBuf> = fs::read_dir(&input).expect("Can not read input directory")
+                .filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .filter(|x| x.is_file() && !x.file_name().map_or(true, |n| n.to_string_lossy().starts_with('.')))
+                .sorted()
+                .collect();
+            let mut fbs = STGFeedbackState::<TargetSystem>::default();
+            let mut releases = ReleaseStats::new(crate::cli::get_task_periods());
+            let mut executed = 0;
+            for path in paths {
+                let (model_input, format) = match read_input_file(&path) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        eprintln!("Skipping {}: {}", path.display(), e);
+                        continue;
+                    }
+                };
+                let model_input = if format == InputFormat::Raw {
+                    setup_interrupt_inputs(model_input, &interrupt_config, None)
+                } else {
+                    model_input
+                };
+                fuzzer.execute_input(&mut state, &mut executor, &mut mgr, &model_input).unwrap();
+                let trace = state.metadata::<<TargetSystem as systemstate::target_os::TargetSystem>::TraceData>().expect("TraceData not found");
+                merge_jobs(&mut fbs, trace.jobs());
+                releases.add(trace.jobs());
+                executed += 1;
+            }
+            let model = extract_task_model(&fbs, Some(&releases));
+            model.to_file(&output).expect("Can not write task model");
+            println!("Wrote {} tasks of {} inputs to {}", model.tasks.len(), executed, output.display());
+        } else if let Commands::Fuzz { random, time, seed, seed_template } = cli.command {
+            // Interrupt parts are only kept for the configured sources
+            #[cfg(feature = "fuzz_int")]
+            let corpus_sources: Vec<usize> = interrupt_config.iter().map(|x| x.0).collect();
+            #[cfg(not(feature = "fuzz_int"))]
+            let corpus_sources: Vec<usize> = Vec::new();
+            if let Some(num) = seed_template {
+                let mut generator = TemplateGenerator::new(crate::cli::get_input_template(), unsafe { MAX_INPUT_SIZE }, corpus_sources.clone(), unsafe { RNG_SEED }).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    process::exit(1)
+                });
+                fs::create_dir_all(&corpus_dirs[0]).expect("Can not create corpus directory");
+                for i in 0..num {
+                    let inp = setup_interrupt_inputs(generator.generate(&mut state).unwrap(), &interrupt_config, None);
+                    inp.to_file(corpus_dirs[0].join(format!("template_{:05}.case", i))).expect("Can not write seed");
+                    fuzzer.evaluate_input(&mut state, &mut executor, &mut mgr, inp).unwrap();
+                }
+                println!("We generated {} inputs from the template into {:?}.", num, &corpus_dirs[0]);
+            }
+            else if let Some(se) = seed {
                 unsafe {
                     let mut rng = StdRng::seed_from_u64(se);
                     let bound = 10000;
@@ -521,20 +894,20 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
                 }
             }
             else if let Ok(sf) = env::var("SEED_DIR") {
-                state
-                    .load_initial_inputs(&mut fuzzer, &mut executor, &mut mgr, &[PathBuf::from(&sf)])
-                    .unwrap_or_else(|_| {
-                        println!("Failed to load initial corpus at {:?}", &corpus_dirs);
+                for input in load_corpus(&[PathBuf::from(&sf)], &corpus_sources) {
+                    fuzzer.evaluate_input(&mut state, &mut executor, &mut mgr, input).unwrap_or_else(|_| {
+                        println!("Failed to load initial corpus at {:?}", &sf);
                         process::exit(0);
                     });
+                }
                 println!("We imported {} inputs from seedfile.", state.corpus().count());
             } else if state.corpus().count() < 1 {
-                state
-                    .load_initial_inputs(&mut fuzzer, &mut executor, &mut mgr, &corpus_dirs)
-                    .unwrap_or_else(|_| {
+                for input in load_corpus(&corpus_dirs, &corpus_sources) {
+                    fuzzer.evaluate_input(&mut state, &mut executor, &mut mgr, input).unwrap_or_else(|_| {
                         println!("Failed to load initial corpus at {:?}", &corpus_dirs);
                         process::exit(0);
                     });
+                }
                 println!("We imported {} inputs from disk.", state.corpus().count());
             }
 
@@ -602,6 +975,14 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
                     do_dump_case!(state, &cli, "");
                     do_dump_stg!(state, &cli, "");
                     do_dump_toprated!(state, &cli, "");
+                    // final WORT as "ticks us", summarized by `fret batch`
+                    if let Some(dump_name) = &cli.dump_name {
+                        let wort = state.metadata_map().get::<IcHist>().map_or(0, |x| x.1.0);
+                        fs::write(dump_name.with_extension("wort"), format!("{} {}\n", wort, crate::time::clock::tick_to_time(wort).as_micros())).expect("Can not write wort");
+                        if let Some(stack) = state.metadata_map().get::<StackHighWaterMetadata>() {
+                            fs::write(dump_name.with_extension("stack.csv"), stack.to_csv()).expect("Can not write stack high water marks");
+                        }
+                    }
                 },
             }
         }
@@ -635,7 +1016,7 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
 
     #[cfg(feature = "singlecore")]
     {
-        let monitor = RateLimitedMonitor::new();
+        let monitor = StatusMonitor::new(RateLimitedMonitor::new(), cli.status_port);
         #[cfg(not(feature = "restarting"))]
         {
             let mgr = SimpleEventManager::new(monitor);
@@ -668,7 +1049,7 @@ let run_client = |state: Option<_>, mut mgr, _core_id| {
         let shmem_provider = StdShMemProvider::new().expect("Failed to init shared memory");
 
         // The stats reporter for the broker
-        let monitor = MultiMonitor::new(|s| println!("{}", s));
+        let monitor = StatusMonitor::new(MultiMonitor::new(|s| println!("{}", s)), cli.status_port);
 
         // Build and run a Launcher
         match Launcher::builder()
@@ -0,0 +1,75 @@
+//! Seeds from the corpus of an earlier campaign, run with `fret -k .. -c .. import -i old_dir`.
+//! The inputs are fitted to the interrupt config and FUZZ_INPUT_LEN of the current kernel, see [`migrate_input`],
+//! and written as .case files into a directory the fuzzer reads with SEED_DIR.
+use libafl::inputs::Input;
+use std::{env, fs, path::{Path, PathBuf}};
+
+use crate::cli::{get_interrupt_config, Cli};
+use crate::fuzzer::MAX_INPUT_SIZE;
+use crate::systemstate::input_view::{migrate_input, read_input_file, set_interrupt_layout_from_env};
+
+/// Migrates every file in `input` into `output`, prints what was changed per file and returns the exit code
+pub fn run(cli: &Cli, input: &Path, output: Option<&PathBuf>, strip: &[String]) -> i32 {
+    let output = output.cloned().unwrap_or_else(|| PathBuf::from(env::var("SEED_DIR").unwrap_or_else(|_| "./corpus".to_string())));
+    let max_size = match env::var("FUZZ_INPUT_LEN") {
+        Ok(x) => match x.parse::<usize>() {
+            Ok(x) => x,
+            Err(_) => {
+                eprintln!("FUZZ_INPUT_LEN was not a number");
+                return 1;
+            }
+        },
+        Err(_) => unsafe { MAX_INPUT_SIZE },
+    };
+    set_interrupt_layout_from_env();
+    // interrupt parts are only kept for the configured sources, as when loading a corpus
+    let sources: Vec<usize> = if cfg!(feature = "fuzz_int") {
+        get_interrupt_config(&cli.kernel, &cli.config).iter().map(|x| x.0).collect()
+    } else {
+        Vec::new()
+    };
+
+    let entries = match fs::read_dir(input) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Can not read {}: {}", input.display(), e);
+            return 1;
+        }
+    };
+    if let Err(e) = fs::create_dir_all(&output) {
+        eprintln!("Can not create {}: {}", output.display(), e);
+        return 1;
+    }
+    let (mut imported, mut adjusted, mut failed) = (0, 0, 0);
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|x| x.path()).filter(|x| x.is_file()).collect();
+    paths.sort();
+    for path in paths {
+        let name = path.file_stem().map_or(String::new(), |x| x.to_string_lossy().to_string());
+        let migrated = read_input_file(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|(x, _)| migrate_input(&x, max_size, &sources, strip).map_err(|e| e.to_string()));
+        match migrated {
+            Ok((x, repairs)) => {
+                let target = output.join(format!("{}.case", name));
+                if let Err(e) = x.to_file(&target) {
+                    println!("{}: can not write {}: {}", path.display(), target.display(), e);
+                    failed += 1;
+                    continue;
+                }
+                imported += 1;
+                if repairs.is_empty() {
+                    println!("{}: unchanged", path.display());
+                } else {
+                    adjusted += 1;
+                    println!("{}: {}", path.display(), repairs);
+                }
+            }
+            Err(e) => {
+                println!("{}: skipped, {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    println!("Imported {} inputs into {}, {} adjusted, {} skipped", imported, output.display(), adjusted, failed);
+    if failed > 0 && imported == 0 { 1 } else { 0 }
+}
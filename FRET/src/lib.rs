@@ -1,12 +1,24 @@
 #[cfg(target_os = "linux")]
 mod fuzzer;
 #[cfg(target_os = "linux")]
+pub mod runner;
+#[cfg(target_os = "linux")]
+pub mod dump;
+#[cfg(target_os = "linux")]
 pub mod time;
 #[cfg(target_os = "linux")]
 pub mod systemstate;
 #[cfg(target_os = "linux")]
-mod cli;
+pub mod cli;
 #[cfg(target_os = "linux")]
 pub mod templates;
 #[cfg(target_os = "linux")]
-mod config;
\ No newline at end of file
+mod config;
+#[cfg(target_os = "linux")]
+pub mod seed_template;
+#[cfg(target_os = "linux")]
+pub mod manifest;
+#[cfg(target_os = "linux")]
+pub mod artifact;
+#[cfg(target_os = "linux")]
+pub mod status;
\ No newline at end of file
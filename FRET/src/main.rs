@@ -2,6 +2,10 @@
 #[cfg(target_os = "linux")]
 mod fuzzer;
 #[cfg(target_os = "linux")]
+mod runner;
+#[cfg(target_os = "linux")]
+mod dump;
+#[cfg(target_os = "linux")]
 mod time;
 #[cfg(target_os = "linux")]
 mod systemstate;
@@ -11,10 +15,29 @@ mod cli;
 mod templates;
 #[cfg(target_os = "linux")]
 mod config;
+#[cfg(target_os = "linux")]
+mod seed_template;
+#[cfg(target_os = "linux")]
+mod manifest;
+#[cfg(target_os = "linux")]
+mod batch;
+#[cfg(target_os = "linux")]
+mod status;
+#[cfg(target_os = "linux")]
+mod check;
+#[cfg(target_os = "linux")]
+mod import;
+#[cfg(target_os = "linux")]
+mod artifact;
 
 #[cfg(target_os = "linux")]
 pub fn main() {
-    fuzzer::fuzz();
+    // `fret batch` starts one fuzzer process per kernel, see batch::BatchCli
+    if std::env::args().nth(1).as_deref() == Some("batch") {
+        batch::run();
+    } else {
+        fuzzer::fuzz();
+    }
 }
 
 #[cfg(not(target_os = "linux"))]
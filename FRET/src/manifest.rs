@@ -0,0 +1,55 @@
+//! Description of a campaign, written next to its dumps to tell which build and configuration produced them
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// Everything which changes the behavior of a campaign
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CampaignManifest {
+    /// `git describe` of the fuzzer at build time, if available
+    pub git_describe: Option<String>,
+    /// Enabled cargo features, sorted
+    pub features: Vec<String>,
+    /// Command line of the fuzzer
+    pub args: Vec<String>,
+    pub kernel: PathBuf,
+    pub kernel_sha256: String,
+    /// (source, minimum inter-arrival time) of each interrupt
+    pub interrupt_config: Vec<(usize, u32)>,
+    pub max_input_size: usize,
+    pub rng_seed: u64,
+    pub icount_shift: u32,
+    pub select_task: Option<String>,
+}
+
+impl CampaignManifest {
+    /// Describe the running fuzzer, the remaining settings are taken from the arguments
+    pub fn collect(
+        kernel: &Path,
+        interrupt_config: &[(usize, u32)],
+        max_input_size: usize,
+        rng_seed: u64,
+        icount_shift: u32,
+        select_task: Option<String>,
+    ) -> std::io::Result<Self> {
+        let kernel_sha256 = format!("{:x}", Sha256::digest(std::fs::read(kernel)?));
+        Ok(Self {
+            git_describe: option_env!("FRET_GIT_DESCRIBE").map(String::from),
+            features: env!("FRET_FEATURES").split(',').filter(|x| !x.is_empty()).map(String::from).collect(),
+            args: std::env::args().collect(),
+            kernel: kernel.to_path_buf(),
+            kernel_sha256,
+            interrupt_config: interrupt_config.to_vec(),
+            max_input_size,
+            rng_seed,
+            icount_shift,
+            select_task,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let s = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        std::fs::write(path, s)
+    }
+}
@@ -0,0 +1,427 @@
+//! Running single inputs on the target outside of a campaign, see [`TargetRunner`].
+//! The boot of QEMU and the harness are shared with the fuzzer, so a [`RunResult`] is the same execution a campaign measures.
+//! ```ignore
+//! let mut runner = TargetRunner::new(Path::new("kernel.elf"), Path::new("target.csv"))?;
+//! let result = runner.run(&input);
+//! println!("{} ticks, {:?}", result.exec_ticks, result.trace.map(|t| t.jobs().len()));
+//! ```
+
+use core::time::Duration;
+use std::{
+    env,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use hashbrown::HashMap;
+use libafl::{
+    corpus::InMemoryCorpus,
+    events::NopEventManager,
+    executors::{ExitKind, HasObservers},
+    feedbacks::ConstFeedback,
+    inputs::{multi::MultipartInput, BytesInput},
+    schedulers::QueueScheduler,
+    state::StdState,
+    ExecutesInput, HasMetadata, StdFuzzer,
+};
+use libafl_bolts::{
+    rands::StdRand,
+    tuples::{tuple_list, MatchName},
+};
+use libafl_qemu::{elf::EasyElf, emu::Emulator, FastSnapshotPtr, GuestAddr, Qemu, QemuExecutor, QemuExitReason, Regs};
+
+#[cfg(all(feature = "freertos", not(feature = "osek")))]
+use crate::systemstate::target_os::freertos::{config::get_range_groups, qemu_module::FreeRTOSSystemStateHelper, FreeRTOSSystem};
+#[cfg(feature = "osek")]
+use crate::systemstate::target_os::osek::{config::get_range_groups, qemu_module::OSEKSystemStateHelper, OSEKSystem};
+use crate::{
+    cli::{get_interrupt_config, get_isr_budgets, get_job_done_apis, get_task_periods, get_task_phases, parse_addr, set_env_from_config},
+    config::{get_target_ranges, get_target_symbols, QemuConfig},
+    fuzzer::MAX_INPUT_SIZE,
+    systemstate::{
+        feedbacks::{guest_exit, set_guest_exit, GuestExit, GuestExitMetadata},
+        helpers::try_load_symbol,
+        input_view::{bytes_part, interrupt_times, max_num_interrupt, num_interrupt_sources, set_interrupt_layout_from_env, set_task_phase_bounds, task_phases},
+        replay_script::loaded_replay_script,
+        target_os::capture::TraceLimits,
+    },
+    time::{
+        budget::{budget_exceeded, IcountBudgetModule},
+        clock::{qemu_icount_shift, set_qemu_icount_shift, QemuClockObserver, DEFAULT_QEMU_ICOUNT_SHIFT},
+        qemustate::QemuStateRestoreHelper,
+    },
+};
+
+// osek takes precedence if both are enabled, e.g. by tools reading the traces of either system
+#[cfg(all(feature = "freertos", not(feature = "osek")))]
+pub type TargetSystem = FreeRTOSSystem;
+#[cfg(all(feature = "freertos", not(feature = "osek")))]
+pub type SystemStateHelper = FreeRTOSSystemStateHelper;
+#[cfg(feature = "osek")]
+pub type TargetSystem = OSEKSystem;
+#[cfg(feature = "osek")]
+pub type SystemStateHelper = OSEKSystemStateHelper;
+
+/// Trace of one execution of the [`TargetSystem`]
+pub type TraceData = <TargetSystem as crate::systemstate::target_os::TargetSystem>::TraceData;
+
+// Allocated by the fuzzer, see max_num_interrupt and num_interrupt_sources. Only access them through interrupt_tables
+#[allow(unused)]
+extern "C" {
+/// Interrupt times of source i at [i*libafl_max_num_interrupts..(i+1)*libafl_max_num_interrupts]
+static mut libafl_interrupt_offsets : *mut u32;
+static mut libafl_max_num_interrupts : usize;
+/// Number of valid interrupt times per source
+static mut libafl_num_interrupts : *mut u64;
+static mut libafl_num_interrupt_sources : usize;
+}
+
+/// The interrupt times per source and the number of valid times per source, which the patched QEMU reads at the start of each run.
+/// QEMU can only be initialized once per process, so the tables are process-wide: only one client per process may inject interrupts.
+///
+/// # Safety
+/// [`Target::load`] must have allocated the tables, and the slices must not be held while QEMU runs.
+unsafe fn interrupt_tables<'a>() -> (&'a mut [u32], &'a mut [u64]) {
+    (
+        std::slice::from_raw_parts_mut(libafl_interrupt_offsets, libafl_max_num_interrupts * libafl_num_interrupt_sources),
+        std::slice::from_raw_parts_mut(libafl_num_interrupts, libafl_num_interrupt_sources),
+    )
+}
+
+/// Symbols and interrupt configuration of a kernel
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub kernel: PathBuf,
+    pub config: PathBuf,
+    pub symbols: HashMap<&'static str, GuestAddr>,
+    pub ranges: HashMap<&'static str, Range<GuestAddr>>,
+    pub groups: HashMap<&'static str, HashMap<String, Range<GuestAddr>>>,
+    /// (source, first tick) of each fuzzed interrupt source
+    pub interrupt_config: Vec<(usize, u32)>,
+    /// Fuzzed release phases, in the order of the task_phases part
+    pub task_phases: Vec<TaskPhase>,
+}
+
+/// Variable holding the release phase of a task, e.g. its initial vTaskDelay, see [`crate::cli::get_task_phases`]
+#[derive(Debug, Clone)]
+pub struct TaskPhase {
+    pub task: String,
+    pub addr: GuestAddr,
+    /// Exclusive upper bound of the phase
+    pub bound: u32,
+}
+
+impl Target {
+    /// Reads the symbols of `kernel` with the settings of the environment, which [`set_env_from_config`] fills from `config`.
+    /// Sets the input size and allocates the interrupt tables read by the patched QEMU, so a process can only load one target.
+    pub fn load(kernel: &Path, config: &Path) -> Result<Self, String> {
+        let (kernel, config) = (kernel.to_path_buf(), config.to_path_buf());
+        let interrupt_config = get_interrupt_config(&kernel, &config);
+        let mut elf_buffer = Vec::new();
+        let elf = EasyElf::from_file(&kernel, &mut elf_buffer).map_err(|e| format!("Can not read kernel {}: {:?}", kernel.display(), e))?;
+        let symbols = get_target_symbols(&elf);
+        let ranges = get_target_ranges(&elf, &symbols);
+        let groups = get_range_groups(&elf, &symbols, &ranges);
+        let periods = get_task_periods();
+        let task_phases = get_task_phases().into_iter().map(|(task, var, bound)| {
+            let addr = parse_addr(&var).ok().or_else(|| try_load_symbol(&elf, &var, true))
+                .ok_or_else(|| format!("Phase variable {} of task {} not found", var, task))?;
+            let bound = bound.or_else(|| periods.get(&task).map(|x| *x as u32)).filter(|x| *x > 0)
+                .ok_or_else(|| format!("Task {} has a phase, but neither a bound nor a period in TASK_PERIODS", task))?;
+            Ok(TaskPhase { task, addr, bound })
+        }).collect::<Result<Vec<_>, String>>()?;
+        set_task_phase_bounds(task_phases.iter().map(|x| x.bound).collect());
+
+        if let Ok(input_len) = env::var("FUZZ_INPUT_LEN") {
+            unsafe {MAX_INPUT_SIZE = str::parse::<usize>(&input_len).map_err(|_| "FUZZ_INPUT_LEN was not a number")?;}
+        }
+        set_interrupt_layout_from_env();
+        if let Some((source, _)) = interrupt_config.iter().find(|x| x.0 >= num_interrupt_sources()) {
+            return Err(format!("Interrupt source {} is configured, but the target only has {} sources, see NUM_INTERRUPT_SOURCES", source, num_interrupt_sources()));
+        }
+        unsafe {
+            libafl_interrupt_offsets = Box::leak(vec![0u32; max_num_interrupt() * num_interrupt_sources()].into_boxed_slice()).as_mut_ptr();
+            libafl_max_num_interrupts = max_num_interrupt();
+            libafl_num_interrupts = Box::leak(vec![0u64; num_interrupt_sources()].into_boxed_slice()).as_mut_ptr();
+            libafl_num_interrupt_sources = num_interrupt_sources();
+        }
+        Ok(Self { kernel, config, symbols, ranges, groups, interrupt_config, task_phases })
+    }
+}
+
+/// Starts QEMU on the kernel of `target`, runs it to FUZZ_MAIN and sets the breakpoints on all exits of the harness.
+/// `extra_args` are appended after all others. Returns the emulator and, with snapshot_fast, the snapshot restored before each execution.
+pub fn boot_target(target: &Target, qemu_config: &QemuConfig, extra_args: &[String]) -> (Qemu, Option<FastSnapshotPtr>) {
+    let mut args: Vec<String> = vec![
+        "target/debug/fret",
+        "-icount",
+        &format!("shift={},align=off,sleep=off", qemu_icount_shift()),
+        "-monitor",
+        "null",
+        "-kernel",
+        &target.kernel.as_os_str().to_str().expect("kernel path is not a string"),
+        "-serial",
+        "null",
+        "-nographic",
+        "-S",
+        // "-semihosting",
+        // "--semihosting-config",
+        // "enable=on,target=native",
+        #[cfg(not(feature = "snapshot_fast"))]
+        "-snapshot",
+        #[cfg(not(feature = "snapshot_fast"))]
+        "-drive",
+        #[cfg(not(feature = "snapshot_fast"))]
+        &qemu_config.drive,
+    ].into_iter().map(String::from).collect();
+    args.extend(qemu_config.to_args());
+    args.extend(extra_args.iter().cloned());
+    let qemu = Qemu::init(&args).expect("Emulator creation failed");
+
+    if let Some(&main_addr) = target.symbols.get("FUZZ_MAIN") {
+        qemu.set_breakpoint(main_addr);
+        unsafe {
+            match qemu.run() {
+                Ok(QemuExitReason::Breakpoint(_)) => {}
+                _ => panic!("Unexpected QEMU exit."),
+            }
+        }
+        qemu.remove_breakpoint(main_addr);
+    }
+
+    qemu.set_breakpoint(target.symbols["BREAKPOINT"]); // BREAKPOINT
+    for name in ["FUZZ_ABORT", "FUZZ_FAIL", "IDLE_HANG"] {
+        if let Some(&addr) = target.symbols.get(name) {
+            qemu.set_breakpoint(addr);
+        }
+    }
+
+    let devices = qemu.list_devices();
+    println!("Devices = {devices:?}");
+
+    #[cfg(feature = "snapshot_fast")]
+    let initial_snap = Some(qemu.create_fast_snapshot(true));
+    #[cfg(not(feature = "snapshot_fast"))]
+    let initial_snap = None;
+    (qemu, initial_snap)
+}
+
+/// Writes an input to the target, injects its interrupts and runs the target to one of its exits
+#[derive(Debug, Clone)]
+pub struct Harness {
+    qemu: Qemu,
+    interrupt_config: Vec<(usize, u32)>,
+    input_addr: GuestAddr,
+    input_length_ptr: Option<GuestAddr>,
+    /// (variable, bound) per task phase
+    task_phases: Vec<(GuestAddr, u32)>,
+    exits: [(Option<GuestAddr>, GuestExit); 4],
+}
+
+impl Harness {
+    #[must_use]
+    pub fn new(qemu: Qemu, target: &Target) -> Self {
+        let breakpoint = target.symbols["BREAKPOINT"];
+        Self {
+            qemu,
+            interrupt_config: target.interrupt_config.clone(),
+            input_addr: target.symbols["FUZZ_INPUT"],
+            input_length_ptr: target.symbols.get("FUZZ_LENGTH").copied(),
+            task_phases: target.task_phases.iter().map(|x| (x.addr, x.bound)).collect(),
+            exits: [
+                (Some(breakpoint), GuestExit::Done),
+                (target.symbols.get("FUZZ_ABORT").copied(), GuestExit::Abort),
+                (target.symbols.get("FUZZ_FAIL").copied(), GuestExit::Fail),
+                (target.symbols.get("IDLE_HANG").copied(), GuestExit::IdleHang),
+            ],
+        }
+    }
+
+    /// Runs `input` once and classifies the stop, see [`set_guest_exit`]
+    pub fn run(&self, input: &MultipartInput<BytesInput>) -> ExitKind {
+        let qemu = self.qemu;
+        unsafe {
+            #[cfg(feature = "fuzz_int")]
+            {
+                let max = max_num_interrupt();
+                let (offsets, counts) = interrupt_tables();
+                if let Some(script) = loaded_replay_script() {
+                    script.inject(offsets, counts, max);
+                } else {
+                    offsets.fill(0);
+                    for &c in &self.interrupt_config {
+                        let (i,_) = c;
+                        let t = interrupt_times(input, c).unwrap_or_default();
+                        offsets[i*max..i*max+t.len()].copy_from_slice(&t);
+                        counts[i]=t.len() as u64;
+                    }
+                }
+
+                // println!("Load: {:?}", libafl_interrupt_offsets[0..libafl_num_interrupts].to_vec());
+            }
+
+            // an input without program input (e.g. only interrupt parts) runs with an empty one
+            let mut bytes = bytes_part(input).unwrap_or(&[]);
+            let mut len = bytes.len();
+            if len > MAX_INPUT_SIZE {
+                bytes = &bytes[0..MAX_INPUT_SIZE];
+                len = MAX_INPUT_SIZE;
+            }
+
+            // Note: I could not find a difference between write_mem and write_phys_mem for my usecase
+            qemu.write_mem(self.input_addr, bytes);
+            if let Some(s) = self.input_length_ptr {
+                qemu.write_mem(s, &(len as u32).to_le_bytes());
+            }
+            if !self.task_phases.is_empty() {
+                let phases = task_phases(input);
+                for (i, (addr, bound)) in self.task_phases.iter().enumerate() {
+                    let phase = phases.get(i).copied().unwrap_or(0) % bound;
+                    qemu.write_mem(*addr, &phase.to_le_bytes());
+                }
+            }
+
+            qemu.run();
+
+            // Classify the stop by the breakpoint reached, any other stop (e.g. a breakpoint on a panic method) we consider a crash
+            let pcs : Vec<u32> = (0..qemu.num_cpus())
+                .map(|i| qemu.cpu_from_index(i))
+                .map(|cpu| -> Result<u32, _> { cpu.read_reg(Regs::Pc) })
+                .map(|pc| pc.unwrap_or(0))
+                .collect();
+            let (exit, pc) = self.exits.iter()
+                .find_map(|(addr, exit)| {
+                    let addr = (*addr)?;
+                    pcs.iter().find(|pc| (addr..addr + 5).contains(*pc)).map(|pc| (*exit, *pc))
+                })
+                .unwrap_or((GuestExit::Unknown, pcs.first().copied().unwrap_or(0)));
+            let exit = if budget_exceeded() { GuestExit::BudgetExceeded } else { exit };
+            set_guest_exit(GuestExitMetadata { exit, pc });
+            match exit {
+                GuestExit::Done | GuestExit::Fail | GuestExit::BudgetExceeded => ExitKind::Ok,
+                GuestExit::IdleHang => ExitKind::Timeout,
+                GuestExit::Abort | GuestExit::Unknown => ExitKind::Crash,
+            }
+        }
+    }
+}
+
+/// Everything one execution of [`TargetRunner::run`] observed
+#[derive(Debug)]
+pub struct RunResult {
+    /// Runtime in ticks, or the worst response time of the selected task
+    pub exec_ticks: u64,
+    /// None if no system states were captured
+    pub trace: Option<TraceData>,
+    pub exit_kind: ExitKind,
+    pub guest_exit: GuestExitMetadata,
+}
+
+/// State of a [`TargetRunner`], without any feedbacks the corpora stay empty
+pub type RunnerState = StdState<MultipartInput<BytesInput>, InMemoryCorpus<MultipartInput<BytesInput>>, StdRand, InMemoryCorpus<MultipartInput<BytesInput>>>;
+
+/// Settings of a [`TargetRunner`] beyond the kernel and its config
+#[derive(Debug, Clone, Default)]
+pub struct RunnerOptions {
+    /// Additional QEMU arguments, e.g. to attach gdb
+    pub qemu_args: Vec<String>,
+    /// Measure the worst response time of this task instead of the runtime
+    pub select_task: Option<String>,
+    /// Stop executions after this many ticks, see [`IcountBudgetModule`]
+    pub icount_budget: Option<u64>,
+    pub trace_limits: TraceLimits,
+}
+
+/// Runs single inputs on a target with the snapshot restore, interrupt injection and system state capture of a campaign,
+/// but without a corpus, feedbacks or an event manager. QEMU can only be started once per process, so there is at most one runner.
+pub struct TargetRunner {
+    target: Target,
+    state: RunnerState,
+    /// The executor with its emulator modules, whose types depend on the features
+    exec: Box<dyn FnMut(&mut RunnerState, &MultipartInput<BytesInput>) -> (ExitKind, u64)>,
+}
+
+impl TargetRunner {
+    /// Loads `kernel` with the settings of `config` and boots it, the icount shift is taken from ICOUNT_SHIFT
+    pub fn new(kernel: &Path, config: &Path) -> Result<Self, String> {
+        set_env_from_config(&kernel.to_path_buf(), &config.to_path_buf());
+        let target = Target::load(kernel, config)?;
+        let shift = match env::var("ICOUNT_SHIFT") {
+            Ok(x) => str::parse::<u32>(&x).map_err(|_| "ICOUNT_SHIFT must be an integer.")?,
+            Err(_) => DEFAULT_QEMU_ICOUNT_SHIFT,
+        };
+        set_qemu_icount_shift(shift);
+        let qemu_config = QemuConfig::from_env();
+        qemu_config.validate().map_err(|e| format!("Invalid QEMU configuration: {}", e))?;
+        let options = RunnerOptions {
+            icount_budget: env::var("ICOUNT_BUDGET").ok().and_then(|x| str::parse::<u64>(&x).ok()),
+            ..Default::default()
+        };
+        Self::with_options(target, &qemu_config, options)
+    }
+
+    /// Boots an already loaded target, for callers which set up the environment and the icount shift themselves
+    pub fn with_options(target: Target, qemu_config: &QemuConfig, options: RunnerOptions) -> Result<Self, String> {
+        let (qemu, initial_snap) = boot_target(&target, qemu_config, &options.qemu_args);
+        let target_harness = Harness::new(qemu, &target);
+        // the executor borrows its harness for as long as QEMU runs, which is the rest of the process
+        let harness = Box::leak(Box::new(move |_emulator: &mut Emulator<_, _, _, _, _>, _state: &mut RunnerState, input: &MultipartInput<BytesInput>| target_harness.run(input)));
+
+        let system_state_helper = SystemStateHelper::new(&target.symbols, &target.ranges, &target.groups)?
+            .with_isr_budgets(get_isr_budgets())
+            .with_job_done_apis(get_job_done_apis())
+            .with_trace_limits(options.trace_limits);
+        let modules = tuple_list!(
+            QemuStateRestoreHelper::with_fast(initial_snap).with_setup_done(target.symbols.get("FUZZ_SETUP_DONE").copied()),
+            IcountBudgetModule::new(options.icount_budget),
+            system_state_helper
+        );
+        let emulator = Emulator::empty().qemu(qemu).modules(modules).build().map_err(|e| format!("{:?}", e))?;
+        let observers = tuple_list!(QemuClockObserver::<TargetSystem>::new("clocktime", &options.select_task));
+
+        let mut feedback = ConstFeedback::new(false);
+        let mut objective = ConstFeedback::new(false);
+        let mut state = StdState::new(
+            StdRand::with_seed(0),
+            InMemoryCorpus::new(),
+            InMemoryCorpus::new(),
+            &mut feedback,
+            &mut objective,
+        ).map_err(|e| e.to_string())?;
+        let mut fuzzer = StdFuzzer::new(QueueScheduler::new(), feedback, objective);
+        let mut mgr = NopEventManager::new();
+        let mut executor = QemuExecutor::new(
+            emulator,
+            harness,
+            observers,
+            &mut fuzzer,
+            &mut state,
+            &mut mgr,
+            Duration::from_secs(10),
+        ).map_err(|e| e.to_string())?;
+        executor.break_on_timeout();
+
+        let exec = Box::new(move |state: &mut RunnerState, input: &MultipartInput<BytesInput>| {
+            let exit_kind = fuzzer.execute_input(state, &mut executor, &mut mgr, input).expect("Execution failed");
+            let ticks = executor.observers().match_name::<QemuClockObserver<TargetSystem>>("clocktime").map_or(0, |x| x.last_runtime());
+            (exit_kind, ticks)
+        });
+        Ok(Self { target, state, exec })
+    }
+
+    /// Runs `input` once and takes its trace out of the state
+    pub fn run(&mut self, input: &MultipartInput<BytesInput>) -> RunResult {
+        let (exit_kind, exec_ticks) = (self.exec)(&mut self.state, input);
+        let trace = self.state.remove_metadata::<TraceData>().map(|x| *x);
+        RunResult { exec_ticks, trace, exit_kind, guest_exit: guest_exit() }
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    /// Metadata the emulator modules leave behind besides the trace, e.g. the [`crate::systemstate::feedbacks::TargetErrorMetadata`]
+    pub fn state_mut(&mut self) -> &mut RunnerState {
+        &mut self.state
+    }
+}
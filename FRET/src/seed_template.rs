@@ -0,0 +1,118 @@
+//! Structured seeds for targets whose input has known fields, e.g. length-prefixed frames, run with `fret -k .. -c .. fuzz --seed-template N`.
+//! The fields are read from the INPUT_TEMPLATE setting, see [`crate::cli::get_input_template`], and [`TemplateGenerator`] fills the rest of the input with random bytes.
+use libafl::{generators::Generator, inputs::{multi::MultipartInput, BytesInput}, Error};
+use libafl_bolts::rands::{Rand, StdRand};
+
+use crate::systemstate::input_view::{interrupt_part_name, max_num_interrupt, BYTES_PART};
+use crate::time::clock::qemu_isns_per_msec;
+
+/// Encoding of a template field, all integers are unsigned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    U8,
+    U16,
+    U32,
+    U16Be,
+    U32Be,
+}
+
+impl FieldKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "u8" => Some(Self::U8),
+            "u16" => Some(Self::U16),
+            "u32" => Some(Self::U32),
+            "u16be" => Some(Self::U16Be),
+            "u32be" => Some(Self::U32Be),
+            _ => None,
+        }
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 | Self::U16Be => 2,
+            Self::U32 | Self::U32Be => 4,
+        }
+    }
+
+    pub fn max_value(&self) -> u32 {
+        match self {
+            Self::U8 => u8::MAX as u32,
+            Self::U16 | Self::U16Be => u16::MAX as u32,
+            Self::U32 | Self::U32Be => u32::MAX,
+        }
+    }
+
+    /// Writes `value` to the start of `buf`, which holds at least [`Self::size`] bytes
+    fn write(&self, buf: &mut [u8], value: u32) {
+        match self {
+            Self::U8 => buf[0] = value as u8,
+            Self::U16 => buf[..2].copy_from_slice(&(value as u16).to_le_bytes()),
+            Self::U32 => buf[..4].copy_from_slice(&value.to_le_bytes()),
+            Self::U16Be => buf[..2].copy_from_slice(&(value as u16).to_be_bytes()),
+            Self::U32Be => buf[..4].copy_from_slice(&value.to_be_bytes()),
+        }
+    }
+}
+
+/// A field of the program input, set to a random value within `min..=max` in each seed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateField {
+    pub name: String,
+    /// Byte offset in the program input
+    pub offset: usize,
+    pub kind: FieldKind,
+    pub min: u32,
+    pub max: u32,
+}
+
+/// Generates seeds with the fields of a template, random bytes in between and random interrupt times for the configured sources.
+/// The generator has its own random source, so the seeds only depend on the seed and not on the state of the fuzzer.
+#[derive(Debug, Clone)]
+pub struct TemplateGenerator {
+    fields: Vec<TemplateField>,
+    input_size: usize,
+    interrupt_sources: Vec<usize>,
+    rand: StdRand,
+}
+
+impl TemplateGenerator {
+    /// Fails if a field does not fit into an input of `input_size` bytes or its range does not fit its type
+    pub fn new(fields: Vec<TemplateField>, input_size: usize, interrupt_sources: Vec<usize>, seed: u64) -> Result<Self, String> {
+        for field in &fields {
+            if field.offset + field.kind.size() > input_size {
+                return Err(format!("Template field {} ends at byte {}, but the input only has {} bytes", field.name, field.offset + field.kind.size(), input_size));
+            }
+            if field.min > field.max || field.max > field.kind.max_value() {
+                return Err(format!("Template field {} has the range {}..={}, which does not fit {:?}", field.name, field.min, field.max, field.kind));
+            }
+        }
+        Ok(Self { fields, input_size, interrupt_sources, rand: StdRand::with_seed(seed) })
+    }
+
+    /// Random ticks within the first 100ms, sorted like the times of a real schedule
+    fn interrupt_times(&mut self) -> Vec<u8> {
+        let window = 100 * qemu_isns_per_msec() as usize;
+        let mut times: Vec<u32> = (0..max_num_interrupt()).map(|_| self.rand.between(0, window - 1) as u32).collect();
+        times.sort_unstable();
+        times.into_iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+}
+
+impl<S> Generator<MultipartInput<BytesInput>, S> for TemplateGenerator {
+    fn generate(&mut self, _state: &mut S) -> Result<MultipartInput<BytesInput>, Error> {
+        let mut bytes: Vec<u8> = (0..self.input_size).map(|_| self.rand.next() as u8).collect();
+        for field in &self.fields {
+            let value = self.rand.between(field.min as usize, field.max as usize) as u32;
+            field.kind.write(&mut bytes[field.offset..], value);
+        }
+        let mut input = MultipartInput::from([(BYTES_PART, BytesInput::new(bytes))]);
+        for source in self.interrupt_sources.clone() {
+            let times = self.interrupt_times();
+            input.add_part(interrupt_part_name(source), BytesInput::new(times));
+        }
+        Ok(input)
+    }
+}
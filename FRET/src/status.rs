@@ -0,0 +1,186 @@
+//! Progress of a campaign as a small json document, served over TCP for `--status-port`.
+//! Clients report their [`StatusReport`] as user stats, the [`StatusMonitor`] of the broker (or of the single client) merges them
+//! with its own totals and answers every connection with the latest document, as plain HTTP so `curl` and `nc` both work.
+
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use hashbrown::HashMap;
+use libafl::{common::HasMetadata, prelude::{ClientStats, Monitor}, monitors::UserStatsValue};
+use libafl_bolts::ClientId;
+use serde::{Deserialize, Serialize};
+
+use crate::systemstate::stg::STGFeedbackState;
+use crate::systemstate::target_os::TargetSystem;
+use crate::time::clock::{tick_to_time, IcHist, FUZZ_START_TIMESTAMP};
+
+/// Name of the user stats holding the [`StatusReport`] of a client
+pub const STATUS_STATS: &str = "Status";
+
+/// Minimum time between two updates of the served document
+const STATUS_REFRESH: Duration = Duration::from_secs(1);
+
+/// What a client knows about its progress, from the same metadata the monitor output uses
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub wort_ticks: u64,
+    pub wort_us: u128,
+    /// Unix time in seconds of the last new worst case
+    pub last_improvement: u64,
+    pub stg_nodes: usize,
+    pub stg_edges: usize,
+    /// WORT in ticks of each tracked task, empty without --select-task
+    pub task_wort: HashMap<String, u64>,
+}
+
+impl StatusReport {
+    pub fn from_state<S, SYS>(state: &S) -> Self
+    where
+        S: HasMetadata,
+        SYS: TargetSystem,
+    {
+        let mut report = Self::default();
+        if let Some(hist) = state.metadata_map().get::<IcHist>() {
+            report.wort_ticks = hist.1.0;
+            report.wort_us = tick_to_time(hist.1.0).as_micros();
+            let start = unsafe { FUZZ_START_TIMESTAMP }.duration_since(UNIX_EPOCH).unwrap_or_default();
+            report.last_improvement = (start + Duration::from_millis(hist.1.1 as u64)).as_secs();
+        }
+        if let Some(fbs) = state.metadata_map().get::<STGFeedbackState<SYS>>() {
+            report.stg_nodes = fbs.graph.node_count();
+            report.stg_edges = fbs.graph.edge_count();
+            report.task_wort = fbs.wort_per_task().clone();
+        }
+        report
+    }
+
+    /// Worst of two reports, the STG counts of the larger graph
+    fn merge(mut self, other: Self) -> Self {
+        if other.wort_ticks > self.wort_ticks {
+            self.wort_ticks = other.wort_ticks;
+            self.wort_us = other.wort_us;
+            self.last_improvement = other.last_improvement;
+        }
+        self.stg_nodes = self.stg_nodes.max(other.stg_nodes);
+        self.stg_edges = self.stg_edges.max(other.stg_edges);
+        for (task, wort) in other.task_wort {
+            let w = self.task_wort.entry(task).or_insert(0);
+            *w = wort.max(*w);
+        }
+        self
+    }
+}
+
+/// The served document
+#[derive(Debug, Clone, Serialize)]
+struct StatusDocument {
+    clients: usize,
+    corpus_size: u64,
+    executions: u64,
+    execs_per_sec: f64,
+    #[serde(flatten)]
+    report: StatusReport,
+}
+
+/// Wraps the monitor of the campaign and serves its status on a TCP port, does nothing but display without a port.
+/// The port is opened on the first display, so it is served by the process which ends up monitoring, e.g. the broker of a [`libafl::events::launcher::Launcher`].
+#[derive(Debug, Clone)]
+pub struct StatusMonitor<M> {
+    inner: M,
+    port: Option<u16>,
+    document: Option<Arc<Mutex<String>>>,
+    last: Instant,
+}
+
+impl<M> StatusMonitor<M> {
+    pub fn new(inner: M, port: Option<u16>) -> Self {
+        Self { inner, port, document: None, last: Instant::now() - STATUS_REFRESH }
+    }
+}
+
+impl<M: Monitor> StatusMonitor<M> {
+    fn update_document(&mut self) {
+        let report = self.inner.client_stats().iter()
+            .filter_map(|c: &ClientStats| match c.get_user_stats(STATUS_STATS).map(|x| x.value()) {
+                Some(UserStatsValue::String(s)) => serde_json::from_str::<StatusReport>(s).ok(),
+                _ => None,
+            })
+            .fold(StatusReport::default(), StatusReport::merge);
+        let doc = StatusDocument {
+            clients: self.inner.client_stats().len(),
+            corpus_size: self.inner.corpus_size(),
+            executions: self.inner.total_execs(),
+            execs_per_sec: self.inner.execs_per_sec(),
+            report,
+        };
+        if let (Some(document), Ok(json)) = (&self.document, serde_json::to_string(&doc)) {
+            *document.lock().unwrap() = json;
+        }
+    }
+}
+
+impl<M: Monitor> Monitor for StatusMonitor<M> {
+    fn client_stats_mut(&mut self) -> &mut Vec<ClientStats> {
+        self.inner.client_stats_mut()
+    }
+
+    fn client_stats(&self) -> &[ClientStats] {
+        self.inner.client_stats()
+    }
+
+    fn start_time(&self) -> Duration {
+        self.inner.start_time()
+    }
+
+    fn set_start_time(&mut self, time: Duration) {
+        self.inner.set_start_time(time);
+    }
+
+    fn display(&mut self, event_msg: &str, sender_id: ClientId) {
+        self.inner.display(event_msg, sender_id);
+        if let (None, Some(port)) = (&self.document, self.port) {
+            match serve_status(port) {
+                Ok(document) => self.document = Some(document),
+                Err(e) => {
+                    eprintln!("Can not serve status on port {}: {}", port, e);
+                    self.port = None;
+                }
+            }
+        }
+        let now = Instant::now();
+        if self.document.is_some() && now.duration_since(self.last) >= STATUS_REFRESH {
+            self.last = now;
+            self.update_document();
+        }
+    }
+}
+
+/// Answers each connection on localhost:`port` with the current document, returns the document to update
+fn serve_status(port: u16) -> std::io::Result<Arc<Mutex<String>>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let document = Arc::new(Mutex::new(String::from("{}")));
+    let served = document.clone();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            // the request does not matter, read what is there so HTTP clients do not see a reset
+            let _ = stream.set_read_timeout(Some(Duration::from_millis(100)));
+            let _ = stream.read(&mut [0u8; 1024]);
+            let body = served.lock().unwrap().clone();
+            let _ = write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        }
+    });
+    println!("Serving status on 127.0.0.1:{}", port);
+    Ok(document)
+}
+
+/// Serialized [`StatusReport`] of a client, fired as the [`STATUS_STATS`] user stats
+pub fn status_stats<S, SYS>(state: &S) -> Cow<'static, str>
+where
+    S: HasMetadata,
+    SYS: TargetSystem,
+{
+    Cow::from(serde_json::to_string(&StatusReport::from_state::<S, SYS>(state)).unwrap_or_default())
+}
@@ -0,0 +1,223 @@
+//! Task parameters for classical response-time analysis, observed by the fuzzer, see [`extract_task_model`]
+
+use std::{fs, io, path::Path};
+
+use hashbrown::HashMap;
+use itertools::Itertools;
+use libafl::Error;
+use libafl_qemu::GuestAddr;
+use serde::{Deserialize, Serialize};
+
+use super::checkpoint::{read_postcard, RELEASES_CHECKPOINT, STG_CHECKPOINT};
+use super::helpers::us_from_ticks;
+use super::report::ReleaseStats;
+use super::stg::STGFeedbackState;
+use super::target_os::TargetSystem;
+use super::{RTOSJob, RTOSTask};
+
+/// Worst observed execution time of one ABB within the jobs of a task
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AbbModel {
+    pub start: GuestAddr,
+    /// 0 for tasks, 1 for API calls, 2 for ISRs
+    pub level: u8,
+    /// Start address as `symbol+0xoff`, if the enclosing function is known
+    pub symbol: Option<String>,
+    pub woet: u64,
+}
+
+/// Observed parameters of one task, all times in ticks
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskParams {
+    pub name: String,
+    /// Worst observed execution time of a job, an estimate of C_i
+    pub woet: u64,
+    /// Worst observed response time of a job
+    pub wort: u64,
+    /// Sorted by start address and level
+    pub abbs: Vec<AbbModel>,
+    /// Shortest observed time between two releases, an estimate of T_i for sporadic tasks. Unknown without release statistics
+    pub min_inter_arrival: Option<u64>,
+    /// Nominal period from TASK_PERIODS
+    pub period: Option<u64>,
+    /// Largest observed release jitter, only known with a nominal period
+    pub max_jitter: Option<u64>,
+}
+
+/// Input of a response-time analysis, see [`extract_task_model`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskModel {
+    /// Converts the times of the model to microseconds
+    pub us_per_tick: f64,
+    /// Sorted by name
+    pub tasks: Vec<TaskParams>,
+}
+
+impl TaskModel {
+    pub fn task(&self, name: &str) -> Option<&TaskParams> {
+        self.tasks.iter().find(|x| x.name == name)
+    }
+
+    /// Writes the model as JSON if `path` ends in `.json`, as RON otherwise
+    pub fn to_file(&self, path: &Path) -> io::Result<()> {
+        let out = if path.extension().map_or(false, |x| x == "json") {
+            serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        } else {
+            ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+        };
+        fs::write(path, out)
+    }
+}
+
+/// Collects the per task maxima of [`STGFeedbackState::worst_task_jobs`], which holds one entry per distinct ABB path of a task.
+/// The inter-arrival times and jitter come from `releases`, if the campaign collected them.
+pub fn extract_task_model<SYS>(fbs: &STGFeedbackState<SYS>, releases: Option<&ReleaseStats>) -> TaskModel
+where
+    SYS: TargetSystem,
+{
+    let mut tasks: HashMap<&str, TaskParams> = HashMap::new();
+    let mut abbs: HashMap<&str, HashMap<(GuestAddr, u8), AbbModel>> = HashMap::new();
+    for task in fbs.worst_task_jobs.values() {
+        let params = tasks.entry(task.name.as_str()).or_insert_with(|| TaskParams {
+            name: task.name.clone(),
+            woet: 0,
+            wort: 0,
+            abbs: Vec::new(),
+            min_inter_arrival: None,
+            period: None,
+            max_jitter: None,
+        });
+        params.woet = params.woet.max(task.woet_ticks);
+        params.wort = params.wort.max(task.wort_ticks);
+        let task_abbs = abbs.entry(task.name.as_str()).or_default();
+        for (abb, ticks) in task.abbs.iter().zip(task.woet_per_abb.iter()) {
+            let entry = task_abbs.entry((abb.get_start(), abb.get_level())).or_insert_with(|| AbbModel {
+                start: abb.get_start(),
+                level: abb.get_level(),
+                symbol: abb.get_symbol(),
+                woet: 0,
+            });
+            entry.woet = entry.woet.max(*ticks);
+        }
+    }
+    for (name, params) in tasks.iter_mut() {
+        if let Some(task_abbs) = abbs.remove(name) {
+            params.abbs = task_abbs.into_values().sorted_by_key(|x| (x.start, x.level)).collect();
+        }
+        if let Some(releases) = releases {
+            if let Some(stats) = releases.tasks.get(*name) {
+                params.min_inter_arrival = stats.min_inter_arrival;
+                params.max_jitter = stats.max_jitter;
+            }
+            params.period = releases.periods.get(*name).copied();
+        }
+    }
+    TaskModel {
+        us_per_tick: us_from_ticks(1),
+        tasks: tasks.into_values().sorted_by(|a, b| a.name.cmp(&b.name)).collect(),
+    }
+}
+
+/// Extracts the model from a checkpoint written with `--checkpoint-dir`. Returns None if `dir` holds no STG.
+pub fn read_checkpoint_model<SYS>(dir: &Path) -> Result<Option<TaskModel>, Error>
+where
+    SYS: TargetSystem,
+{
+    let Some(stg) = read_postcard::<STGFeedbackState<SYS>>(&dir.join(STG_CHECKPOINT))? else { return Ok(None) };
+    stg.check_hash_version()?;
+    let releases = read_postcard::<ReleaseStats>(&dir.join(RELEASES_CHECKPOINT))?;
+    Ok(Some(extract_task_model(&stg, releases.as_ref())))
+}
+
+/// Adds the jobs of one trace to [`STGFeedbackState::worst_task_jobs`], like [`super::stg::StgFeedback`] does with the feature trace_job_response_times
+pub fn merge_jobs<SYS>(fbs: &mut STGFeedbackState<SYS>, jobs: &[RTOSJob])
+where
+    SYS: TargetSystem,
+{
+    for job in jobs {
+        match fbs.worst_task_jobs.get_mut(&job.get_hash_cached()) {
+            Some(task) => {
+                task.try_update(job);
+            }
+            None => {
+                fbs.worst_task_jobs.insert(job.get_hash_cached(), RTOSTask::from_instance(job));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systemstate::{target_os::mock::MockSystem, AtomicBasicBlock};
+
+    fn abb(start: GuestAddr, level: u8) -> AtomicBasicBlock {
+        AtomicBasicBlock { start, level, ..Default::default() }
+    }
+
+    fn job(name: &str, release: u64, response: u64, path: &[(GuestAddr, u64)]) -> RTOSJob {
+        RTOSJob {
+            name: name.to_string(),
+            release,
+            response,
+            exec_ticks: path.iter().map(|x| x.1).sum(),
+            ticks_per_abb: path.iter().map(|x| x.1).collect(),
+            abbs: path.iter().map(|x| abb(x.0, if x.0 == 0x200 { 1 } else { 0 })).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// T1 takes two paths through its ABBs, the slower path in response and the slower one in execution differ
+    fn jobs() -> Vec<RTOSJob> {
+        vec![
+            job("T1", 0, 50, &[(0x100, 20), (0x200, 10)]),
+            job("T2", 0, 20, &[(0x400, 10)]),
+            job("T1", 100, 140, &[(0x100, 15), (0x200, 20)]),
+            job("T1", 230, 300, &[(0x100, 5), (0x300, 55)]),
+        ]
+    }
+
+    #[test]
+    fn model_of_a_synthetic_stg() {
+        let mut fbs = STGFeedbackState::<MockSystem>::default();
+        merge_jobs(&mut fbs, &jobs());
+        assert_eq!(fbs.worst_task_jobs.len(), 3);
+        let mut releases = ReleaseStats::new(HashMap::from([("T1".to_string(), 100)]));
+        releases.add(&jobs());
+
+        let model = extract_task_model(&fbs, Some(&releases));
+        assert_eq!(model.us_per_tick, us_from_ticks(1));
+        assert_eq!(model.tasks.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), vec!["T1", "T2"]);
+        let t1 = model.task("T1").unwrap();
+        assert_eq!((t1.woet, t1.wort), (60, 70));
+        // the ABBs of a path keep the times of its worst job, 0x100 took 20 ticks only in a faster job of its path
+        let woets: Vec<_> = t1.abbs.iter().map(|x| (x.start, x.level, x.woet)).collect();
+        assert_eq!(woets, vec![(0x100, 0, 15), (0x200, 1, 20), (0x300, 0, 55)]);
+        assert_eq!((t1.min_inter_arrival, t1.period, t1.max_jitter), (Some(100), Some(100), Some(30)));
+        let t2 = model.task("T2").unwrap();
+        assert_eq!((t2.woet, t2.wort, t2.abbs.len()), (10, 20, 1));
+        assert_eq!((t2.min_inter_arrival, t2.period, t2.max_jitter), (None, None, None));
+
+        // without release statistics only the times are known
+        let model = extract_task_model(&fbs, None);
+        assert_eq!(model.task("T1").map(|x| (x.woet, x.min_inter_arrival, x.period)), Some((60, None, None)));
+        assert!(extract_task_model(&STGFeedbackState::<MockSystem>::default(), None).tasks.is_empty());
+    }
+
+    #[test]
+    fn model_files() {
+        let mut fbs = STGFeedbackState::<MockSystem>::default();
+        merge_jobs(&mut fbs, &jobs());
+        let model = extract_task_model(&fbs, None);
+        let dir = std::env::temp_dir().join(format!("fret-analysis-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("model.json");
+        let ron_file = dir.join("model.ron");
+        model.to_file(&json).unwrap();
+        model.to_file(&ron_file).unwrap();
+        // the tick length may lose its last digit in text
+        assert_eq!(serde_json::from_str::<TaskModel>(&fs::read_to_string(&json).unwrap()).unwrap().tasks, model.tasks);
+        assert_eq!(ron::from_str::<TaskModel>(&fs::read_to_string(&ron_file).unwrap()).unwrap().tasks, model.tasks);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+//! Periodic checkpoints of the STG and the icount history, so a crashed campaign can resume without losing its graph
+
+use core::{marker::PhantomData, time::Duration};
+use std::{fs, path::{Path, PathBuf}};
+
+use libafl::{
+    common::HasMetadata, stages::Stage, state::UsesState, Error
+};
+use libafl_bolts::current_time;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::time::clock::IcHist;
+
+use super::helpers::HASH_VERSION;
+use super::report::ReleaseStats;
+use super::stg::STGFeedbackState;
+use super::target_os::TargetSystem;
+
+/// File name of the serialized [`STGFeedbackState`] in the checkpoint directory
+pub const STG_CHECKPOINT: &str = "stg.postcard";
+/// File name of the serialized [`IcHist`] in the checkpoint directory
+pub const ICHIST_CHECKPOINT: &str = "ichist.postcard";
+/// File name of the serialized [`ReleaseStats`] in the checkpoint directory
+pub const RELEASES_CHECKPOINT: &str = "releases.postcard";
+
+/// Writes `value` to `path` through a temporary file, so a crash while writing keeps the previous checkpoint
+fn write_postcard<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    let bytes = postcard::to_allocvec(value)?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn read_postcard<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let bytes = fs::read(path)?;
+    Ok(Some(postcard::from_bytes(&bytes)?))
+}
+
+/// Serializes the [`STGFeedbackState`], [`IcHist`] and [`ReleaseStats`] of `state` into `dir`, metadata which is not present yet is skipped
+pub fn save_checkpoint<S, SYS>(state: &S, dir: &Path) -> Result<(), Error>
+where
+    S: HasMetadata,
+    SYS: TargetSystem,
+{
+    fs::create_dir_all(dir)?;
+    if let Ok(stg) = state.metadata::<STGFeedbackState<SYS>>() {
+        write_postcard(&dir.join(STG_CHECKPOINT), stg)?;
+    }
+    if let Ok(ichist) = state.metadata::<IcHist>() {
+        write_postcard(&dir.join(ICHIST_CHECKPOINT), ichist)?;
+    }
+    if let Ok(releases) = state.metadata::<ReleaseStats>() {
+        write_postcard(&dir.join(RELEASES_CHECKPOINT), releases)?;
+    }
+    Ok(())
+}
+
+/// Loads a checkpoint written by [`save_checkpoint`] into `state`, replacing the metadata present.
+/// Returns false if `dir` holds no checkpoint.
+pub fn load_checkpoint<S, SYS>(state: &mut S, dir: &Path) -> Result<bool, Error>
+where
+    S: HasMetadata,
+    SYS: TargetSystem,
+{
+    let mut loaded = false;
+    // checkpoints of hash version 0 lack the version field and fail to deserialize
+    let stg = read_postcard::<STGFeedbackState<SYS>>(&dir.join(STG_CHECKPOINT))
+        .map_err(|e| Error::illegal_state(format!("Can not read the STG checkpoint, it may predate hash version {}: {}", HASH_VERSION, e)))?;
+    if let Some(mut stg) = stg {
+        stg.check_hash_version()?;
+        stg.restore_indices();
+        state.add_metadata(stg);
+        loaded = true;
+    }
+    if let Some(ichist) = read_postcard::<IcHist>(&dir.join(ICHIST_CHECKPOINT))? {
+        state.add_metadata(ichist);
+        loaded = true;
+    }
+    if let Some(releases) = read_postcard::<ReleaseStats>(&dir.join(RELEASES_CHECKPOINT))? {
+        state.add_metadata(releases);
+        loaded = true;
+    }
+    Ok(loaded)
+}
+
+/// Writes a checkpoint to `dir` every `interval`, see [`save_checkpoint`]. Does nothing without a `dir`.
+#[derive(Debug, Clone)]
+pub struct CheckpointStage<E, EM, Z, SYS> {
+    dir: Option<PathBuf>,
+    interval: Duration,
+    last_checkpoint: Duration,
+    phantom: PhantomData<(E, EM, Z, SYS)>,
+}
+
+impl<E, EM, Z, SYS> CheckpointStage<E, EM, Z, SYS> {
+    pub fn new(dir: Option<PathBuf>, interval: Duration) -> Self {
+        Self { dir, interval, last_checkpoint: current_time(), phantom: PhantomData }
+    }
+}
+
+impl<E, EM, Z, SYS> UsesState for CheckpointStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+{
+    type State = E::State;
+}
+
+impl<E, EM, Z, SYS> Stage<E, EM, Z> for CheckpointStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+    EM: UsesState<State = Self::State>,
+    Z: UsesState<State = Self::State>,
+    Self::State: HasMetadata,
+    SYS: TargetSystem,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Self::State,
+        _manager: &mut EM,
+    ) -> Result<(), Error> {
+        let Some(dir) = &self.dir else { return Ok(()) };
+        let now = current_time();
+        if now.saturating_sub(self.last_checkpoint) < self.interval {
+            return Ok(());
+        }
+        self.last_checkpoint = now;
+        save_checkpoint::<_, SYS>(state, dir)
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
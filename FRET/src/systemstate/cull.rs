@@ -0,0 +1,145 @@
+//! Removes corpus entries which are dominated by another entry, see [`DominatedCullingStage`].
+
+use core::{marker::PhantomData, time::Duration};
+use std::borrow::Cow;
+
+use hashbrown::{HashMap, HashSet};
+use libafl::{
+    corpus::{Corpus, CorpusId}, events::{Event, EventFirer}, monitors::{AggregatorOps, UserStats, UserStatsValue}, schedulers::{minimizer::{IsFavoredMetadata, TopRatedsMetadata}, RemovableScheduler}, stages::Stage, state::{HasCorpus, HasExecutions, UsesState}, Error, HasMetadata, HasScheduler
+};
+
+use super::stg::STGNodeMetadata;
+
+/// What [`DominatedCullingStage`] compares of a corpus entry
+#[derive(Debug, Clone)]
+pub struct CullCandidate {
+    pub id: CorpusId,
+    /// Sorted and deduplicated indices of the [`STGNodeMetadata`]
+    pub indices: Vec<usize>,
+    pub exec_time: Duration,
+    /// Worst response time of each task with a finished job
+    pub worst_responses: HashMap<String, u64>,
+}
+
+impl CullCandidate {
+    pub fn new(id: CorpusId, exec_time: Duration, metadata: &STGNodeMetadata) -> Self {
+        let mut indices = metadata.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        let mut worst_responses = HashMap::new();
+        for job in metadata.jobs() {
+            let rt = worst_responses.entry(job.name.clone()).or_insert(0);
+            *rt = job.response_time().max(*rt);
+        }
+        Self { id, indices, exec_time, worst_responses }
+    }
+
+    /// `other` covers all indices of `self` and is at least as long, in total and for every task of `self`
+    pub fn dominated_by(&self, other: &Self) -> bool {
+        self.exec_time <= other.exec_time
+            && self.indices.len() <= other.indices.len()
+            && self.worst_responses.iter().all(|(task, rt)| other.worst_responses.get(task).map_or(false, |x| rt <= x))
+            && self.indices.iter().all(|i| other.indices.binary_search(i).is_ok())
+    }
+}
+
+/// Ids of all candidates dominated by another one, of candidates dominating each other the lowest id is kept
+pub fn dominated_entries(candidates: &[CullCandidate]) -> Vec<CorpusId> {
+    candidates.iter()
+        .filter(|a| candidates.iter().any(|b| b.id != a.id && a.dominated_by(b) && (b.id < a.id || !b.dominated_by(a))))
+        .map(|a| a.id)
+        .collect()
+}
+
+/// Every `interval` executions removes the testcases whose [`STGNodeMetadata`] is dominated by another testcase, see [`CullCandidate::dominated_by`].
+/// Favored entries, entries top rated for any index and the current entry are kept.
+/// Testcases without [`STGNodeMetadata`] are never culled. Does nothing without an `interval`.
+#[derive(Debug, Clone)]
+pub struct DominatedCullingStage<E, EM, Z> {
+    interval: Option<u64>,
+    last_executions: u64,
+    /// Entries removed so far, reported as `CulledEntries`
+    removed: u64,
+    phantom: PhantomData<(E, EM, Z)>,
+}
+
+impl<E, EM, Z> DominatedCullingStage<E, EM, Z> {
+    pub fn new(interval: Option<u64>) -> Self {
+        Self { interval, last_executions: 0, removed: 0, phantom: PhantomData }
+    }
+}
+
+impl<E, EM, Z> UsesState for DominatedCullingStage<E, EM, Z>
+where
+    E: UsesState,
+{
+    type State = E::State;
+}
+
+impl<E, EM, Z> Stage<E, EM, Z> for DominatedCullingStage<E, EM, Z>
+where
+    E: UsesState,
+    EM: EventFirer<State = Self::State>,
+    Z: HasScheduler + UsesState<State = Self::State>,
+    Z::Scheduler: RemovableScheduler<Self::Input, Self::State>,
+    Self::State: HasCorpus + HasMetadata + HasExecutions,
+{
+    fn perform(
+        &mut self,
+        fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Self::State,
+        manager: &mut EM,
+    ) -> Result<(), Error> {
+        let Some(interval) = self.interval else { return Ok(()) };
+        let executions = *state.executions();
+        if executions < self.last_executions + interval {
+            return Ok(());
+        }
+        self.last_executions = executions;
+
+        let mut protected: HashSet<CorpusId> = state.metadata_map().get::<TopRatedsMetadata>()
+            .map_or(HashSet::new(), |meta| meta.map.values().copied().collect());
+        protected.extend(*state.corpus().current());
+        let mut candidates = Vec::new();
+        for id in state.corpus().ids() {
+            let testcase = state.corpus().get(id)?.borrow();
+            if testcase.has_metadata::<IsFavoredMetadata>() {
+                protected.insert(id);
+            }
+            if let (Some(exec_time), Ok(metadata)) = (*testcase.exec_time(), testcase.metadata::<STGNodeMetadata>()) {
+                candidates.push(CullCandidate::new(id, exec_time, metadata));
+            }
+        }
+        let mut culled: Vec<CorpusId> = dominated_entries(&candidates).into_iter().filter(|x| !protected.contains(x)).collect();
+        culled.sort_unstable();
+        for id in culled.iter().rev() {
+            let testcase = state.corpus_mut().remove(*id)?;
+            fuzzer.scheduler_mut().on_remove(state, *id, &Some(testcase))?;
+        }
+        if !culled.is_empty() {
+            println!("Culled {} dominated testcases of {}", culled.len(), candidates.len());
+        }
+        self.removed += culled.len() as u64;
+        manager.fire(
+            state,
+            Event::UpdateUserStats {
+                name: Cow::from("CulledEntries"),
+                value: UserStats::new(
+                    UserStatsValue::Number(self.removed),
+                    AggregatorOps::Sum,
+                ),
+                phantom: PhantomData,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
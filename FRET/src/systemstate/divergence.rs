@@ -0,0 +1,143 @@
+//! The [`ScheduleDivergenceStage`] re-executes new corpus entries without interrupts and compares the resulting job order.
+
+use core::marker::PhantomData;
+use std::path::PathBuf;
+
+use libafl::{
+    common::HasMetadata, corpus::{Corpus, HasCurrentCorpusId}, inputs::{HasMutatorBytes, Input, MultipartInput}, prelude::UsesInput, stages::Stage, state::{HasCorpus, HasCurrentTestcase, UsesState}, Error, ExecutesInput
+};
+use serde::{Deserialize, Serialize};
+
+use super::input_view::interrupt_source_of;
+use super::target_os::{SystemTraceData, TargetSystem};
+
+/// How much the job order of a testcase changed when its interrupts were removed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScheduleDivergenceMetadata {
+    /// Edit distance between the job sequences with and without interrupts
+    pub score: usize,
+    /// Number of jobs in the original execution
+    pub num_jobs: usize,
+}
+libafl_bolts::impl_serdeany!(ScheduleDivergenceMetadata);
+
+/// Levenshtein distance over two sequences of task names
+fn edit_distance(a: &[String], b: &[String]) -> usize {
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        curr[0] = i;
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            curr[j] = (prev[j] + 1).min(curr[j - 1] + 1).min(prev[j - 1] + cost);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+/// Task names of the last execution's jobs in release order
+fn job_sequence<S, SYS>(state: &S) -> Vec<String>
+where
+    S: HasMetadata,
+    SYS: TargetSystem,
+{
+    match state.metadata::<SYS::TraceData>() {
+        Ok(trace) => {
+            let mut jobs: Vec<_> = trace.jobs().iter().map(|x| (x.release, x.name.clone())).collect();
+            jobs.sort_by_key(|x| x.0);
+            jobs.into_iter().map(|x| x.1).collect()
+        }
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Re-executes each testcase once with all `isr_*_times` parts emptied and stores the divergence of the job order as [`ScheduleDivergenceMetadata`].
+/// Testcases without interrupts are not executed and get no metadata. Testcases reaching the threshold are copied to `dump_dir`, if given.
+#[derive(Clone, Debug)]
+pub struct ScheduleDivergenceStage<E, EM, Z, SYS> {
+    #[allow(clippy::type_complexity)]
+    phantom: PhantomData<(E, EM, Z, SYS)>,
+    threshold: Option<usize>,
+    dump_dir: Option<PathBuf>,
+}
+
+impl<E, EM, Z, SYS> ScheduleDivergenceStage<E, EM, Z, SYS> {
+    pub fn new(threshold: Option<usize>, dump_dir: Option<PathBuf>) -> Self {
+        if let Some(d) = &dump_dir {
+            std::fs::create_dir_all(d).expect("Could not create divergence directory");
+        }
+        Self { phantom: PhantomData, threshold, dump_dir }
+    }
+}
+
+impl<E, EM, Z, SYS> UsesState for ScheduleDivergenceStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+{
+    type State = E::State;
+}
+
+impl<E, EM, Z, I, SYS> Stage<E, EM, Z> for ScheduleDivergenceStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+    EM: UsesState<State = Self::State>,
+    Z: ExecutesInput<E, EM, State = Self::State>,
+    Self::State: HasCorpus + HasCurrentTestcase + HasCurrentCorpusId + HasMetadata + UsesInput<Input = MultipartInput<I>>,
+    <Self::State as HasCorpus>::Corpus: Corpus<Input = MultipartInput<I>>,
+    I: HasMutatorBytes + Default + Input,
+    SYS: TargetSystem,
+{
+    fn perform(
+        &mut self,
+        fuzzer: &mut Z,
+        executor: &mut E,
+        state: &mut Self::State,
+        manager: &mut EM,
+    ) -> Result<(), Error> {
+        let input = {
+            let current_case = state.current_testcase()?;
+            if current_case.has_metadata::<ScheduleDivergenceMetadata>() {
+                return Ok(());
+            }
+            current_case.input().as_ref().unwrap().clone()
+        };
+        let isr_parts: Vec<usize> = input.names().iter().enumerate()
+            .filter(|(i, name)| interrupt_source_of(name).is_some() && input.parts()[*i].bytes().len() > 0)
+            .map(|(i, _)| i)
+            .collect();
+        if isr_parts.is_empty() {
+            // nothing to remove, the schedule can not diverge. No metadata is added, the original execution is not known.
+            return Ok(());
+        }
+        let mut quiet_input = input.clone();
+        for i in isr_parts {
+            drop(quiet_input.parts_mut()[i].drain(..));
+        }
+
+        // the trace of the original execution is not kept in the testcase, so rerun it as well
+        fuzzer.execute_input(state, executor, manager, &input)?;
+        let original = job_sequence::<_, SYS>(state);
+        fuzzer.execute_input(state, executor, manager, &quiet_input)?;
+        let quiet = job_sequence::<_, SYS>(state);
+        // no jobs at all yields the length of the original sequence
+        let score = edit_distance(&original, &quiet);
+
+        state.current_testcase_mut()?.add_metadata(ScheduleDivergenceMetadata { score, num_jobs: original.len() });
+        if let (Some(t), Some(d)) = (self.threshold, &self.dump_dir) {
+            if score >= t {
+                let id = state.current_corpus_id()?.map_or(0, |x| x.0);
+                let _ = input.to_file(d.join(format!("{}_{}.case", id, score)));
+            }
+        }
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
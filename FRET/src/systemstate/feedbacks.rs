@@ -1,5 +1,6 @@
 use libafl::{
     common::HasMetadata,
+    corpus::Testcase,
     executors::ExitKind,
     feedbacks::Feedback,
     observers::ObserversTuple,
@@ -9,8 +10,14 @@ use libafl::{
     corpus::Corpus,
     inputs::Input,
 };
-use libafl::events::EventFirer;
+use libafl::events::{Event, EventFirer};
+use libafl::monitors::{AggregatorOps, UserStats, UserStatsValue};
+use hashbrown::HashMap;
+use itertools::Itertools;
 use libafl_bolts::Named;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
@@ -18,11 +25,61 @@ use super::target_os::TargetSystem;
 use std::borrow::Cow;
 use std::marker::PhantomData;
 
+use crate::dump::{dump_path, write_ron_dump};
+use crate::systemstate::helpers::input_usage_csv;
+use crate::systemstate::report::ReleaseStats;
 use crate::systemstate::target_os::*;
+use crate::systemstate::target_os::intervals::{error_context, RefinementError};
+use crate::systemstate::ExecInterval;
+use crate::time::clock::{tick_to_time, QemuClockObserver};
 use libafl::prelude::StateInitializer;
 
 //=========================== Debugging Feedback
+/// Worst value seen by [`DumpSystraceFeedback`] in record mode and the trace files written for it, kept in the state to survive restarts
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TraceRecordMetadata {
+    pub best: u64,
+    pub records: usize,
+    pub files: VecDeque<PathBuf>,
+}
+libafl_bolts::impl_serdeany!(TraceRecordMetadata);
+
+/// Error windows written by [`DumpSystraceFeedback`] per [`ErrorClass`], which also numbers the files
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ErrorWindowMetadata {
+    pub dumped: HashMap<ErrorClass, usize>,
+}
+libafl_bolts::impl_serdeany!(ErrorWindowMetadata);
+
+/// The intervals and states around the [`SystemTraceData::first_error`] of a trace, [`error_context`] on each side
+#[derive(Debug, Serialize)]
+pub struct ErrorWindow<'a, S> {
+    pub error: &'a RefinementError,
+    /// Index of the first interval in `intervals`
+    pub first_interval: usize,
+    pub intervals: &'a [ExecInterval],
+    /// The states the intervals start and end at, by hash
+    pub states: HashMap<u64, &'a S>,
+}
+
+impl<'a, S> ErrorWindow<'a, S> {
+    pub fn new<T: SystemTraceData<State = S>>(trace: &'a T) -> Option<Self> {
+        let error = trace.first_error()?;
+        let k = error_context();
+        let intervals = trace.intervals();
+        let first_interval = error.index.saturating_sub(k).min(intervals.len());
+        let intervals = &intervals[first_interval..usize::min(error.index + k + 1, intervals.len())];
+        let states = intervals.iter()
+            .flat_map(|x| [x.start_state, x.end_state])
+            .filter_map(|h| trace.states_map().get(&h).map(|s| (h, s)))
+            .collect();
+        Some(Self { error, first_interval, intervals, states })
+    }
+}
+
 /// A [`Feedback`] meant to dump the system-traces for debugging. Depends on [`QemuSystemStateObserver`]
+/// By default the current trace is dumped every 10 minutes. In record mode a trace is only dumped when the
+/// [`QemuClockObserver`] reports a new worst value, i.e. the total icount or the WORT of the selected task.
 #[derive(Debug)]
 pub struct DumpSystraceFeedback<SYS>
 where
@@ -33,6 +90,12 @@ where
     phantom: PhantomData<SYS>,
     init_time: Instant,
     last_dump: Option<Instant>,
+    /// name of the clock observer and the number of record files to keep, if in record mode
+    record_filter: Option<(Cow<'static, str>, usize)>,
+    /// base address and size of the input, if the input usage should be dumped
+    input_usage: Option<(u32, usize)>,
+    /// number of error windows to dump per error class
+    error_windows: Option<usize>,
 }
 
 impl<S, SYS> StateInitializer<S> for DumpSystraceFeedback<SYS> where SYS: TargetSystem {}
@@ -50,12 +113,56 @@ where
         state: &mut S,
         _manager: &mut EM,
         _input: &I,
-        _observers: &OT,
+        observers: &OT,
         _exit_kind: &ExitKind,
     ) -> Result<bool, Error>
 where {
         match &self.dumpfile {
             Some(s) => {
+                // Only the states around the first refinement error, the whole trace is hard to navigate
+                if let Some(max) = self.error_windows {
+                    let class = state.metadata::<SYS::TraceData>().ok().and_then(|t| t.first_error()).map(|e| e.class);
+                    if let Some(class) = class {
+                        let windows = state.metadata_map_mut().get_or_insert_with(ErrorWindowMetadata::default);
+                        let dumped = windows.dumped.entry(class).or_insert(0);
+                        if *dumped < max {
+                            *dumped += 1;
+                            let windowname = s.with_extension(format!("error_{}_{:04}.ron", class.name(), *dumped));
+                            let trace = state
+                                .metadata::<SYS::TraceData>()
+                                .expect("TraceData not found");
+                            if let Some(window) = ErrorWindow::new(trace) {
+                                write_ron_dump(windowname, &window).expect("Can not dump to file");
+                            }
+                        }
+                    }
+                }
+                if let Some((observer_name, keep)) = &self.record_filter {
+                    let value = observers
+                        .match_name::<QemuClockObserver<SYS>>(observer_name)
+                        .expect("QemuClockObserver not found")
+                        .last_runtime();
+                    let record = state.metadata_map_mut().get_or_insert_with(TraceRecordMetadata::default);
+                    // only serialize the trace if it sets a new record
+                    if value > record.best {
+                        record.best = value;
+                        record.records += 1;
+                        let tracename = s.with_extension(format!("record_{:04}.{}us.trace.ron", record.records, tick_to_time(value).as_micros()));
+                        record.files.push_back(dump_path(&tracename));
+                        let mut outdated = Vec::new();
+                        while record.files.len() > *keep {
+                            outdated.extend(record.files.pop_front());
+                        }
+                        for f in outdated {
+                            // the file may already be gone, e.g. after a restart
+                            let _ = std::fs::remove_file(f);
+                        }
+                        let trace = state
+                            .metadata::<SYS::TraceData>()
+                            .expect("TraceData not found");
+                        write_ron_dump(tracename, trace).expect("Can not dump to file");
+                    }
+                }
                 let time_has_come = self.last_dump.map(|t| Instant::now()-t > Duration::from_secs(600)).unwrap_or(true);
                 if time_has_come {
                     self.last_dump = Some(Instant::now());
@@ -76,16 +183,23 @@ where {
                     }
 
                     // Try dumping the current case
-                    let tracename = s.with_extension("trace.ron");
-                    let trace = state
-                        .metadata::<SYS::TraceData>()
-                        .expect("TraceData not found");
-                    std::fs::write(
-                        tracename,
-                        ron::to_string(trace)
-                            .expect("Error serializing hashmap"),
-                    )
-                    .expect("Can not dump to file");
+                    if self.record_filter.is_none() {
+                        let tracename = s.with_extension("trace.ron");
+                        let trace = state
+                            .metadata::<SYS::TraceData>()
+                            .expect("TraceData not found");
+                        write_ron_dump(tracename, trace).expect("Can not dump to file");
+                    }
+
+                    // Which bytes of the input the current case reads
+                    if let Some((base, size)) = self.input_usage {
+                        let trace = state
+                            .metadata::<SYS::TraceData>()
+                            .expect("TraceData not found");
+                        let (usage, unused) = trace.input_usage(base, size);
+                        std::fs::write(s.with_extension("input_usage.csv"), input_usage_csv(&usage, &unused))
+                            .expect("Can not dump to file");
+                    }
                 }
             }
             Option::None => {
@@ -119,6 +233,9 @@ where
             phantom: PhantomData,
             init_time: std::time::Instant::now(),
             last_dump: None,
+            record_filter: None,
+            input_usage: None,
+            error_windows: None,
         }
     }
     #[allow(unused)]
@@ -129,21 +246,158 @@ where
             phantom: PhantomData,
             init_time: std::time::Instant::now(),
             last_dump: None,
+            record_filter: None,
+            input_usage: None,
+            error_windows: None,
+        }
+    }
+
+    /// Only dump traces which set a new worst value of the given observer, keeping the `keep` most recent records
+    #[must_use]
+    pub fn with_record_filter(mut self, observer: &QemuClockObserver<SYS>, keep: usize) -> Self {
+        self.record_filter = Some((observer.name().clone(), keep));
+        self
+    }
+
+    /// Also dump which bytes of the input at `base` with `size` bytes are read by which task and abb
+    #[must_use]
+    pub fn with_input_usage(mut self, base: u32, size: usize) -> Self {
+        self.input_usage = Some((base, size));
+        self
+    }
+
+    /// Also dump the [`ErrorWindow`] of the first `max` traces with a refinement error of each class,
+    /// as `<dump>.error_<class>_<n>.ron`
+    #[must_use]
+    pub fn with_error_windows(mut self, max: usize) -> Self {
+        self.error_windows = Some(max);
+        self
+    }
+}
+
+//=========================== Guest exits
+
+/// Where the guest stopped, classified by the harness from the breakpoint it reached
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GuestExit {
+    /// The designated `BREAKPOINT`
+    #[default]
+    Done,
+    /// The target's `FUZZ_ABORT` function, reported as a crash
+    Abort,
+    /// The target's `FUZZ_FAIL` function, a failed test that is otherwise treated as a regular execution
+    Fail,
+    /// The target's `IDLE_HANG` loop, reported as a timeout
+    IdleHang,
+    /// Any other stop, reported as a crash
+    Unknown,
+    /// Stopped by the tick budget of `--icount-budget`, treated as a regular execution
+    BudgetExceeded,
+}
+
+/// Classification of the last guest stop, attached to testcases which [`SystraceErrorFeedback`] reports for it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestExitMetadata {
+    pub exit: GuestExit,
+    pub pc: u32,
+}
+libafl_bolts::impl_serdeany!(GuestExitMetadata);
+
+thread_local! {
+    /// Written by the harness and read by the feedbacks of the same client, both on the thread of the executor
+    static LAST_GUEST_EXIT: Cell<GuestExitMetadata> = const { Cell::new(GuestExitMetadata { exit: GuestExit::Done, pc: 0 }) };
+}
+
+/// Called by the harness after each execution
+pub fn set_guest_exit(exit: GuestExitMetadata) {
+    LAST_GUEST_EXIT.set(exit);
+}
+
+/// Classification of the last execution on this thread, see [`set_guest_exit`]
+pub fn guest_exit() -> GuestExitMetadata {
+    LAST_GUEST_EXIT.get()
+}
+
+/// What [`SystraceErrorFeedback`] does with the traces showing an [`ErrorClass`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    Ignore,
+    /// Only add them to the [`ErrorClassStats`]
+    Count,
+    /// Also report them as objectives
+    Objective,
+}
+
+impl ErrorAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ignore" => Some(ErrorAction::Ignore),
+            "count" => Some(ErrorAction::Count),
+            "objective" => Some(ErrorAction::Objective),
+            _ => None,
         }
     }
 }
 
-#[derive(Debug, Default)]
+/// Traces showing each [`ErrorClass`] over all executions and how many of them were reported as objectives, collected by [`SystraceErrorFeedback`].
+/// Kept in the state to survive restarts.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ErrorClassStats {
+    pub executions: u64,
+    pub traces: HashMap<ErrorClass, u64>,
+    pub objectives: HashMap<ErrorClass, u64>,
+}
+libafl_bolts::impl_serdeany!(ErrorClassStats);
+
+impl ErrorClassStats {
+    /// Writes one line per class
+    pub fn dump_csv(&self, path: &std::path::Path) -> std::io::Result<()> {
+        let mut out = String::from("class,traces,executions,objectives\n");
+        for class in ErrorClass::ALL {
+            out.push_str(&format!(
+                "{},{},{},{}\n",
+                class.name(),
+                self.traces.get(&class).copied().unwrap_or(0),
+                self.executions,
+                self.objectives.get(&class).copied().unwrap_or(0)
+            ));
+        }
+        std::fs::write(path, out)
+    }
+}
+
+#[derive(Debug)]
 pub struct SystraceErrorFeedback<SYS>
 where
     SYS: TargetSystem,
 {
     name: Cow<'static, str>,
     dump_case: bool,
+    /// Objectives reported per error class, unlimited if None
     max_reports: Option<usize>,
+    /// Indexed by [`ErrorClass`]
+    error_actions: [ErrorAction; 5],
+    isr_budget_objective: bool,
+    /// Report executions which stopped in `FUZZ_FAIL` as objectives
+    fail_objective: bool,
+    /// Report executions which exhausted the tick budget as objectives
+    budget_objective: bool,
+    /// Whether the first truncated trace was pointed out
+    warned_truncation: bool,
+    /// Failed or budget exhausting execution to flag the testcase with
+    last_fail: Option<GuestExitMetadata>,
+    isr_worst_durations: HashMap<String, u64>,
+    /// Executions in which an ISR invocation exceeded its budget
+    isr_budget_violations: u64,
+    /// Deferred captures, invalid captures and invalid task names summed over all executions
+    capture_totals: (u64, u64, u64),
+    executions: u64,
     phantom: std::marker::PhantomData<SYS>,
 }
 
+/// Executions between two reports of the capture statistics
+const CAPTURE_STATS_INTERVAL: u64 = 1000;
+
 impl<S, SYS> StateInitializer<S> for SystraceErrorFeedback<SYS> where SYS: TargetSystem {}
 
 impl<EM, I, OT, S, SYS> Feedback<EM, I, OT, S> for SystraceErrorFeedback<SYS>
@@ -162,29 +416,152 @@ where
         _exit_kind: &ExitKind,
     ) -> Result<bool, Error>
 where {
+        self.last_fail = Some(guest_exit()).filter(|x| matches!(x.exit, GuestExit::Fail | GuestExit::BudgetExceeded));
+        match self.last_fail.map(|x| x.exit) {
+            Some(GuestExit::Fail) if self.fail_objective => return Ok(true),
+            Some(GuestExit::BudgetExceeded) if self.budget_objective => return Ok(true),
+            _ => {}
+        }
         #[cfg(feature = "trace_stg")]
         {
-            if let Some(m) = self.max_reports {
-                if m <= 0 {
-                    return Ok(false);
+            let trace = state
+                .metadata::<SYS::TraceData>()
+                .expect("TraceData not found");
+            let budget_exceeded = trace.isr_budget_exceeded();
+            let capture_stats = trace.capture_stats();
+            let invalid_task_names = trace.invalid_task_names();
+            if trace.trace_truncated() && !self.warned_truncation {
+                eprintln!("Warning: a trace was truncated, the trace limits were hit or the target ran out of input");
+                self.warned_truncation = true;
+            }
+            let error_classes: Vec<ErrorClass> = trace.error_classes().iter().copied()
+                .filter(|x| self.error_actions[*x as usize] != ErrorAction::Ignore)
+                .collect();
+            if !state.has_metadata::<ErrorClassStats>() {
+                state.add_metadata(ErrorClassStats::default());
+            }
+            let stats = state.metadata_mut::<ErrorClassStats>().unwrap();
+            stats.executions += 1;
+            let mut first_seen = false;
+            for class in &error_classes {
+                let count = stats.traces.entry(*class).or_insert(0);
+                first_seen |= *count == 0;
+                *count += 1;
+            }
+            // Cumulative counters, sent when a class first occurs and then along with the capture statistics
+            if first_seen || (stats.executions % CAPTURE_STATS_INTERVAL == 0 && !stats.traces.is_empty()) {
+                let counts: Vec<(ErrorClass, u64)> = stats.traces.iter().map(|(k, v)| (*k, *v)).sorted_by_key(|x| x.0 as usize).collect();
+                for (class, count) in counts {
+                    _manager.fire(
+                        state,
+                        Event::UpdateUserStats {
+                            name: Cow::from(format!("errors_{}", class.name())),
+                            value: UserStats::new(UserStatsValue::Number(count), AggregatorOps::Sum),
+                            phantom: PhantomData,
+                        },
+                    )?;
+                }
+            }
+            let trace = state
+                .metadata::<SYS::TraceData>()
+                .expect("TraceData not found");
+            let mut isr_updated = false;
+            if let Some(durations) = trace.isr_worst_durations() {
+                for (name, duration) in durations {
+                    let worst = self.isr_worst_durations.entry(name.clone()).or_insert(0);
+                    if *duration > *worst {
+                        *worst = *duration;
+                        isr_updated = true;
+                    }
                 }
-                let need_to_debug = state
-                    .metadata::<SYS::TraceData>()
-                    .expect("TraceData not found")
-                    .need_to_debug();
-                if need_to_debug {
-                    self.max_reports = Some(m - 1);
+            }
+            if isr_updated {
+                let json = serde_json::to_string(&self.isr_worst_durations).unwrap_or_default();
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from("IsrWorst"),
+                        value: UserStats::new(
+                            UserStatsValue::String(Cow::from(json)),
+                            AggregatorOps::None,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+            }
+            if let Some((deferred, invalid)) = capture_stats {
+                self.capture_totals.0 += deferred as u64;
+                self.capture_totals.1 += invalid as u64;
+                self.capture_totals.2 += invalid_task_names as u64;
+                self.executions += 1;
+                if self.executions % CAPTURE_STATS_INTERVAL == 0 {
+                    for (name, value) in [("deferred_captures", self.capture_totals.0), ("invalid_captures", self.capture_totals.1), ("invalid_task_names", self.capture_totals.2)] {
+                        _manager.fire(
+                            state,
+                            Event::UpdateUserStats {
+                                name: Cow::from(name),
+                                value: UserStats::new(UserStatsValue::Number(value), AggregatorOps::Sum),
+                                phantom: PhantomData,
+                            },
+                        )?;
+                    }
                 }
-                return Ok(self.dump_case && need_to_debug);
-            } else {
+            }
+            if budget_exceeded {
+                self.isr_budget_violations += 1;
+                if self.isr_budget_violations == 1 || self.isr_budget_violations % CAPTURE_STATS_INTERVAL == 0 {
+                    _manager.fire(
+                        state,
+                        Event::UpdateUserStats {
+                            name: Cow::from("isr_budget_violations"),
+                            value: UserStats::new(UserStatsValue::Number(self.isr_budget_violations), AggregatorOps::Sum),
+                            phantom: PhantomData,
+                        },
+                    )?;
+                }
+                // otherwise the violation is only counted and the refinement errors are reported as usual
+                if self.dump_case && self.isr_budget_objective {
+                    return Ok(true);
+                }
+            }
+            if !self.dump_case {
                 return Ok(false);
             }
+            let stats = state.metadata_mut::<ErrorClassStats>().unwrap();
+            let mut objective = false;
+            for class in error_classes.iter().filter(|x| self.error_actions[**x as usize] == ErrorAction::Objective) {
+                let reported = stats.objectives.entry(*class).or_insert(0);
+                if self.max_reports.map_or(true, |m| (*reported as usize) < m) {
+                    *reported += 1;
+                    objective = true;
+                }
+            }
+            return Ok(objective);
         }
         #[cfg(not(feature = "trace_stg"))]
         {
             return Ok(false);
         }
     }
+
+    /// Flag testcases of failed executions
+    fn append_metadata(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _observers: &OT,
+        testcase: &mut Testcase<I>,
+    ) -> Result<(), Error> {
+        if let Some(m) = self.last_fail.take() {
+            testcase.metadata_map_mut().insert(m);
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &I) -> Result<(), Error> {
+        self.last_fail = None;
+        Ok(())
+    }
 }
 
 impl<SYS> Named for SystraceErrorFeedback<SYS>
@@ -207,7 +584,645 @@ where
             name: Cow::from(String::from("SystraceErrorFeedback")),
             dump_case,
             max_reports,
+            error_actions: ErrorClass::ALL.map(|x| match x {
+                ErrorClass::AbbMismatch | ErrorClass::ReleaseResponsePairing => ErrorAction::Objective,
+                _ => ErrorAction::Count,
+            }),
+            isr_budget_objective: false,
+            fail_objective: false,
+            budget_objective: false,
+            warned_truncation: false,
+            last_fail: None,
+            isr_worst_durations: HashMap::new(),
+            isr_budget_violations: 0,
+            capture_totals: (0, 0, 0),
+            executions: 0,
             phantom: std::marker::PhantomData,
         }
     }
+
+    /// Report executions exceeding an ISR budget as objectives instead of ignoring them
+    #[must_use]
+    pub fn with_isr_budget_objective(mut self, isr_budget_objective: bool) -> Self {
+        self.isr_budget_objective = isr_budget_objective;
+        self
+    }
+
+    /// Report executions which stopped in the target's `FUZZ_FAIL` function as objectives
+    #[must_use]
+    pub fn with_fail_objective(mut self, fail_objective: bool) -> Self {
+        self.fail_objective = fail_objective;
+        self
+    }
+
+    /// Report executions which were stopped by the tick budget as objectives
+    #[must_use]
+    pub fn with_budget_objective(mut self, budget_objective: bool) -> Self {
+        self.budget_objective = budget_objective;
+        self
+    }
+
+    /// Report executions whose trace was truncated as objectives, otherwise they are only counted
+    #[must_use]
+    pub fn with_truncation_objective(self, truncation_objective: bool) -> Self {
+        if truncation_objective {
+            self.with_error_action(ErrorClass::Truncation, ErrorAction::Objective)
+        } else {
+            self
+        }
+    }
+
+    /// Set what to do with traces showing `class`. By default ABB mismatches and pairing problems are objectives and the other classes are counted.
+    #[must_use]
+    pub fn with_error_action(mut self, class: ErrorClass, action: ErrorAction) -> Self {
+        self.error_actions[class as usize] = action;
+        self
+    }
+
+    /// Apply [`Self::with_error_action`] to each pair, see [`crate::cli::get_error_actions`]
+    #[must_use]
+    pub fn with_error_actions(self, actions: impl IntoIterator<Item = (ErrorClass, ErrorAction)>) -> Self {
+        actions.into_iter().fold(self, |fb, (class, action)| fb.with_error_action(class, action))
+    }
+}
+
+//=========================== Target assertions
+
+/// Error code the target wrote to its `FUZZ_ERROR` variable, along with the icount of the write
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TargetErrorMetadata {
+    pub code: u32,
+    pub tick: u64,
+}
+libafl_bolts::impl_serdeany!(TargetErrorMetadata);
+
+/// A [`Feedback`] reporting executions in which the target signaled a nonzero error code.
+/// Place it before [`SystraceErrorFeedback`] in a fast-or objective, so that such executions do not use up its reports.
+#[derive(Debug)]
+pub struct TargetAssertFeedback {
+    name: Cow<'static, str>,
+    last_error: Option<TargetErrorMetadata>,
+}
+
+impl<S> StateInitializer<S> for TargetAssertFeedback {}
+
+impl<EM, I, OT, S> Feedback<EM, I, OT, S> for TargetAssertFeedback
+where
+    S: State + UsesInput + MaybeHasClientPerfMonitor + HasMetadata,
+    EM: EventFirer<State = S>,
+    OT: ObserversTuple<I, S>,
+{
+    fn is_interesting(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &I,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error> {
+        self.last_error = state
+            .metadata::<TargetErrorMetadata>()
+            .ok()
+            .filter(|x| x.code != 0)
+            .copied();
+        Ok(self.last_error.is_some())
+    }
+
+    /// Attach the error code to the testcase, so crashes can be grouped by code
+    fn append_metadata(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _observers: &OT,
+        testcase: &mut Testcase<I>,
+    ) -> Result<(), Error> {
+        if let Some(m) = self.last_error.take() {
+            testcase.metadata_map_mut().insert(m);
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &I) -> Result<(), Error> {
+        self.last_error = None;
+        Ok(())
+    }
+}
+
+impl Named for TargetAssertFeedback {
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl TargetAssertFeedback {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            name: Cow::from("TargetAssertFeedback"),
+            last_error: None,
+        }
+    }
+}
+
+//=========================== Deadlines
+
+/// The job of an execution which overran its task's deadline the most
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeadlineMissMetadata {
+    pub task: String,
+    pub release: u64,
+    pub response_time: u64,
+    pub deadline: u64,
+    /// Ticks beyond the deadline
+    pub overrun: u64,
+}
+libafl_bolts::impl_serdeany!(DeadlineMissMetadata);
+
+/// A [`Feedback`] reporting executions in which a job's response time exceeds the deadline of its task, meant for the objective.
+/// Tasks without a deadline are never reported.
+#[derive(Debug)]
+pub struct DeadlineFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    name: Cow<'static, str>,
+    deadlines: HashMap<String, u64>,
+    last_miss: Option<DeadlineMissMetadata>,
+    phantom: PhantomData<SYS>,
+}
+
+impl<S, SYS> StateInitializer<S> for DeadlineFeedback<SYS> where SYS: TargetSystem {}
+
+impl<EM, I, OT, S, SYS> Feedback<EM, I, OT, S> for DeadlineFeedback<SYS>
+where
+    S: State + UsesInput + MaybeHasClientPerfMonitor + HasMetadata,
+    EM: EventFirer<State = S>,
+    OT: ObserversTuple<I, S>,
+    SYS: TargetSystem,
+{
+    fn is_interesting(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &I,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error> {
+        self.last_miss = None;
+        if self.deadlines.is_empty() {
+            return Ok(false);
+        }
+        let trace = match state.metadata::<SYS::TraceData>() {
+            Ok(x) => x,
+            Err(_) => return Ok(false),
+        };
+        for job in trace.jobs() {
+            if let Some(deadline) = self.deadlines.get(&job.name) {
+                let response_time = job.response_time();
+                if response_time > *deadline && self.last_miss.as_ref().map_or(true, |x| response_time - deadline > x.overrun) {
+                    self.last_miss = Some(DeadlineMissMetadata {
+                        task: job.name.clone(),
+                        release: job.release,
+                        response_time,
+                        deadline: *deadline,
+                        overrun: response_time - deadline,
+                    });
+                }
+            }
+        }
+        Ok(self.last_miss.is_some())
+    }
+
+    /// Attach the worst overrun to the testcase
+    fn append_metadata(
+        &mut self,
+        _state: &mut S,
+        _manager: &mut EM,
+        _observers: &OT,
+        testcase: &mut Testcase<I>,
+    ) -> Result<(), Error> {
+        if let Some(m) = self.last_miss.take() {
+            testcase.metadata_map_mut().insert(m);
+        }
+        Ok(())
+    }
+
+    fn discard_metadata(&mut self, _state: &mut S, _input: &I) -> Result<(), Error> {
+        self.last_miss = None;
+        Ok(())
+    }
+}
+
+impl<SYS> Named for DeadlineFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<SYS> DeadlineFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    /// `deadlines` maps task names to their response time deadline in ticks
+    #[must_use]
+    pub fn new(deadlines: HashMap<String, u64>) -> Self {
+        Self {
+            name: Cow::from("DeadlineFeedback"),
+            deadlines,
+            last_miss: None,
+            phantom: PhantomData,
+        }
+    }
+}
+
+//=========================== Response time distribution
+
+/// Number of log2-scaled buckets per task
+pub const RT_HISTOGRAM_BUCKETS: usize = 64;
+
+/// Bounds of a histogram bucket in microseconds. Bucket 0 holds responses below 1us, bucket i holds [2^(i-1), 2^i).
+fn rt_bucket_bounds(bucket: usize) -> (u64, u64) {
+    if bucket == 0 {
+        (0, 1)
+    } else if bucket == RT_HISTOGRAM_BUCKETS - 1 {
+        (1 << (bucket - 1), u64::MAX)
+    } else {
+        (1 << (bucket - 1), 1 << bucket)
+    }
+}
+
+fn rt_bucket_of(micros: u64) -> usize {
+    usize::min((u64::BITS - micros.leading_zeros()) as usize, RT_HISTOGRAM_BUCKETS - 1)
+}
+
+/// A [`Feedback`] collecting a response time histogram per task over all executions. Never reports an input as interesting.
+/// The histograms are rewritten to the dump file every `interval`, measured from the start of the campaign.
+#[derive(Debug)]
+pub struct ResponseTimeHistogramFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    name: Cow<'static, str>,
+    dumpfile: Option<PathBuf>,
+    interval: Duration,
+    last_period: u64,
+    histograms: HashMap<String, [u64; RT_HISTOGRAM_BUCKETS]>,
+    phantom: PhantomData<SYS>,
+}
+
+impl<S, SYS> StateInitializer<S> for ResponseTimeHistogramFeedback<SYS> where SYS: TargetSystem {}
+
+impl<EM, I, OT, S, SYS> Feedback<EM, I, OT, S> for ResponseTimeHistogramFeedback<SYS>
+where
+    S: State + UsesInput + MaybeHasClientPerfMonitor + HasMetadata,
+    EM: EventFirer<State = S>,
+    OT: ObserversTuple<I, S>,
+    SYS: TargetSystem,
+{
+    fn is_interesting(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &I,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error> {
+        let Some(dumpfile) = &self.dumpfile else {
+            return Ok(false);
+        };
+        if let Ok(trace) = state.metadata::<SYS::TraceData>() {
+            for job in trace.jobs() {
+                let micros = crate::time::clock::tick_to_time(job.response_time()).as_micros() as u64;
+                let hist = self
+                    .histograms
+                    .entry_ref(&job.name)
+                    .or_insert([0; RT_HISTOGRAM_BUCKETS]);
+                hist[rt_bucket_of(micros)] += 1;
+            }
+        }
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(unsafe { crate::time::clock::FUZZ_START_TIMESTAMP })
+            .unwrap_or_default();
+        let period = (elapsed.as_secs_f64() / self.interval.as_secs_f64()) as u64;
+        if period > self.last_period {
+            self.last_period = period;
+            // write to a temporary file first, so readers never see a partial dump
+            let tmpfile = dumpfile.with_extension("csv.tmp");
+            let mut out = String::from("task,bucket_lo_us,bucket_hi_us,count\n");
+            let mut tasks: Vec<_> = self.histograms.iter().collect();
+            tasks.sort_by(|a, b| a.0.cmp(b.0));
+            for (task, hist) in tasks {
+                for (bucket, count) in hist.iter().enumerate().filter(|x| *x.1 > 0) {
+                    let (lo, hi) = rt_bucket_bounds(bucket);
+                    out.push_str(&format!("{},{},{},{}\n", task, lo, hi, count));
+                }
+            }
+            std::fs::write(&tmpfile, out).expect("Can not dump to file");
+            std::fs::rename(&tmpfile, dumpfile).expect("Can not dump to file");
+        }
+        Ok(false)
+    }
+}
+
+impl<SYS> Named for ResponseTimeHistogramFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<SYS> ResponseTimeHistogramFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    /// Creates a new [`ResponseTimeHistogramFeedback`], which only collects data if `dumpfile` is set
+    pub fn new(dumpfile: Option<PathBuf>, interval: Duration) -> Self {
+        Self {
+            name: Cow::from("ResponseTimeHistogram"),
+            dumpfile,
+            interval,
+            last_period: 0,
+            histograms: HashMap::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+//=========================== Release statistics
+
+/// A [`Feedback`] adding the releases of each execution to the [`ReleaseStats`] of the state. Never reports an input as interesting.
+#[derive(Debug)]
+pub struct ReleaseStatsFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    name: Cow<'static, str>,
+    periods: HashMap<String, u64>,
+    phantom: PhantomData<SYS>,
+}
+
+impl<S, SYS> StateInitializer<S> for ReleaseStatsFeedback<SYS> where SYS: TargetSystem {}
+
+impl<EM, I, OT, S, SYS> Feedback<EM, I, OT, S> for ReleaseStatsFeedback<SYS>
+where
+    S: State + UsesInput + MaybeHasClientPerfMonitor + HasMetadata,
+    EM: EventFirer<State = S>,
+    OT: ObserversTuple<I, S>,
+    SYS: TargetSystem,
+{
+    fn is_interesting(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &I,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error> {
+        let Ok(trace) = state.metadata::<SYS::TraceData>() else {
+            return Ok(false);
+        };
+        if trace.jobs().is_empty() {
+            return Ok(false);
+        }
+        let jobs = trace.jobs().clone();
+        if !state.has_metadata::<ReleaseStats>() {
+            state.add_metadata(ReleaseStats::new(self.periods.clone()));
+        }
+        state.metadata_mut::<ReleaseStats>().unwrap().add(&jobs);
+        Ok(false)
+    }
+}
+
+impl<SYS> Named for ReleaseStatsFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<SYS> ReleaseStatsFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    /// `periods` maps task names to their nominal period in ticks, the release jitter is only computed for these tasks
+    #[must_use]
+    pub fn new(periods: HashMap<String, u64>) -> Self {
+        Self {
+            name: Cow::from("ReleaseStatsFeedback"),
+            periods,
+            phantom: PhantomData,
+        }
+    }
+}
+
+//=========================== Stack usage
+
+/// Least free stack in bytes seen of each task over the campaign, see [`SystemTraceData::stack_high_water`]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct StackHighWaterMetadata {
+    pub min_free: HashMap<String, u32>,
+}
+libafl_bolts::impl_serdeany!(StackHighWaterMetadata);
+
+impl StackHighWaterMetadata {
+    /// One "task,min_free_bytes" line per task, sorted by name
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("task,min_free_bytes\n");
+        for (task, free) in self.min_free.iter().sorted_by_key(|x| x.0) {
+            out.push_str(&format!("{},{}\n", task, free));
+        }
+        out
+    }
+}
+
+/// A [`Feedback`] reporting executions in which a task uses more stack than in all executions before, i.e. its high water mark drops.
+/// The first sighting of a task counts as well.
+#[derive(Debug)]
+pub struct StackDepthFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    name: Cow<'static, str>,
+    phantom: PhantomData<SYS>,
+}
+
+impl<S, SYS> StateInitializer<S> for StackDepthFeedback<SYS> where SYS: TargetSystem {}
+
+impl<EM, I, OT, S, SYS> Feedback<EM, I, OT, S> for StackDepthFeedback<SYS>
+where
+    S: State + UsesInput + MaybeHasClientPerfMonitor + HasMetadata,
+    EM: EventFirer<State = S>,
+    OT: ObserversTuple<I, S>,
+    SYS: TargetSystem,
+{
+    fn is_interesting(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &I,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error> {
+        let Some(high_water) = state.metadata::<SYS::TraceData>().ok().and_then(|x| x.stack_high_water()).cloned() else {
+            return Ok(false);
+        };
+        let campaign = state.metadata_map_mut().get_or_insert_with(StackHighWaterMetadata::default);
+        let mut interesting = false;
+        for (task, free) in high_water {
+            match campaign.min_free.get_mut(&task) {
+                Some(x) if *x <= free => {}
+                Some(x) => {
+                    *x = free;
+                    interesting = true;
+                }
+                None => {
+                    campaign.min_free.insert(task, free);
+                    interesting = true;
+                }
+            }
+        }
+        Ok(interesting)
+    }
+}
+
+impl<SYS> Named for StackDepthFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<SYS> StackDepthFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            name: Cow::from("StackDepthFeedback"),
+            phantom: PhantomData,
+        }
+    }
+}
+
+//=========================== Preemptions
+
+/// Most preemptions suffered by a job of each task over the campaign, see [`RTOSJob::preemption_count`](super::RTOSJob::preemption_count)
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PreemptionMetadata {
+    pub max_preemptions: HashMap<String, u32>,
+}
+libafl_bolts::impl_serdeany!(PreemptionMetadata);
+
+/// A [`Feedback`] reporting executions in which a job of a task is preempted more often than any job of the task before.
+/// The first job of a task counts as well.
+#[derive(Debug)]
+pub struct PreemptionFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    name: Cow<'static, str>,
+    phantom: PhantomData<SYS>,
+}
+
+impl<S, SYS> StateInitializer<S> for PreemptionFeedback<SYS> where SYS: TargetSystem {}
+
+impl<EM, I, OT, S, SYS> Feedback<EM, I, OT, S> for PreemptionFeedback<SYS>
+where
+    S: State + UsesInput + MaybeHasClientPerfMonitor + HasMetadata,
+    EM: EventFirer<State = S>,
+    OT: ObserversTuple<I, S>,
+    SYS: TargetSystem,
+{
+    fn is_interesting(
+        &mut self,
+        state: &mut S,
+        _manager: &mut EM,
+        _input: &I,
+        _observers: &OT,
+        _exit_kind: &ExitKind,
+    ) -> Result<bool, Error> {
+        let Ok(trace) = state.metadata::<SYS::TraceData>() else {
+            return Ok(false);
+        };
+        let mut per_task: HashMap<String, u32> = HashMap::new();
+        for job in trace.jobs() {
+            let max = per_task.entry(job.name.clone()).or_default();
+            *max = (*max).max(job.preemption_count);
+        }
+        let campaign = state.metadata_map_mut().get_or_insert_with(PreemptionMetadata::default);
+        let mut interesting = false;
+        for (task, count) in per_task {
+            match campaign.max_preemptions.get_mut(&task) {
+                Some(x) if *x >= count => {}
+                Some(x) => {
+                    *x = count;
+                    interesting = true;
+                }
+                None => {
+                    campaign.max_preemptions.insert(task, count);
+                    interesting = true;
+                }
+            }
+        }
+        Ok(interesting)
+    }
+}
+
+impl<SYS> Named for PreemptionFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    #[inline]
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<SYS> PreemptionFeedback<SYS>
+where
+    SYS: TargetSystem,
+{
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            name: Cow::from("PreemptionFeedback"),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_exit_is_per_thread() {
+        let fail = GuestExitMetadata { exit: GuestExit::Fail, pc: 0x10 };
+        set_guest_exit(fail);
+        let (before, after) = std::thread::spawn(|| {
+            let before = guest_exit();
+            set_guest_exit(GuestExitMetadata { exit: GuestExit::Abort, pc: 0x20 });
+            (before, guest_exit())
+        })
+        .join()
+        .unwrap();
+        assert_eq!(before, GuestExitMetadata::default());
+        assert_eq!(after, GuestExitMetadata { exit: GuestExit::Abort, pc: 0x20 });
+        assert_eq!(guest_exit(), fail);
+    }
 }
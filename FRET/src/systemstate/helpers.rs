@@ -1,14 +1,16 @@
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
+use itertools::Itertools;
 use libafl_bolts::prelude::{SerdeAny, SerdeAnyMap};
 use libafl_qemu::{elf::EasyElf, read_user_reg_unchecked, GuestAddr, GuestPhysAddr};
-use std::{borrow::Cow, cmp::min, hash::{DefaultHasher, Hash, Hasher}, ops::Range};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cmp::min, ops::Range};
 
 use crate::{
-    fuzzer::{DO_NUM_INTERRUPT, FIRST_INT},
-    time::clock::QEMU_ISNS_PER_USEC,
+    fuzzer::FIRST_INT,
+    time::clock::{qemu_icount_shift, qemu_isns_per_usec},
 };
 
-use super::ExecInterval;
+use super::{input_view::max_num_interrupt, AtomicBasicBlock, ExecInterval};
 
 //============================= API symbols
 
@@ -68,6 +70,35 @@ pub fn try_load_symbol(elf: &EasyElf, symbol: &str, do_translation: bool) -> Opt
     }
 }
 
+/// Address of the program input in `kernel`, by the symbol named in FUZZ_INPUT, resolved like the fuzzer does
+pub fn load_input_base(kernel: &std::path::Path) -> Result<GuestAddr, String> {
+    let mut elf_buffer = Vec::new();
+    let elf = EasyElf::from_file(kernel, &mut elf_buffer).map_err(|e| format!("Can not read kernel {}: {:?}", kernel.display(), e))?;
+    let name = std::env::var("FUZZ_INPUT").unwrap_or_else(|_| "FUZZ_INPUT".to_owned());
+    try_load_symbol(&elf, &name, true).ok_or_else(|| format!("Symbol {} not found", name))
+}
+
+/// Size of a symbol as recorded in the ELF, None if the symbol is missing
+pub fn symbol_size(elf: &EasyElf, symbol: &str) -> Option<u64> {
+    let gob = elf.goblin();
+    gob.syms.iter().find(|x| gob.strtab.get_at(x.st_name) == Some(symbol)).map(|x| x.st_size)
+}
+
+/// Compares the sizes of kernel globals in the ELF with the bindings used to read them.
+/// `expected` lists (symbol, size of the binding, whether the symbol is an array of it). Symbols which are missing or have no size are skipped.
+pub fn symbol_layout_mismatches(elf: &EasyElf, expected: &[(&str, usize, bool)]) -> Vec<String> {
+    expected.iter().filter_map(|(name, size, array)| {
+        let actual = symbol_size(elf, name).filter(|x| *x != 0)? as usize;
+        if *array && actual % size != 0 {
+            Some(format!("{} is {} bytes in the ELF, which is not a multiple of the {} bytes of its binding", name, actual, size))
+        } else if !*array && actual != *size {
+            Some(format!("{} is {} bytes in the ELF, but its binding has {} bytes", name, actual, size))
+        } else {
+            None
+        }
+    }).collect()
+}
+
 /// Returns the address range of a function symbol in the ELF file.
 /// 
 /// # Arguments
@@ -127,6 +158,46 @@ pub fn in_any_range<'a>(
     return None;
 }
 
+/// Resolves addresses to the enclosing function symbol, for human-readable reports.
+#[derive(Debug, Default, Clone)]
+pub struct SymbolResolver {
+    /// Function ranges sorted by start address
+    ranges: Vec<(Range<GuestAddr>, Cow<'static, str>)>,
+}
+
+impl SymbolResolver {
+    /// Collects the function ranges of all target groups, e.g. those built by `get_range_groups`.
+    pub fn from_groups(
+        target_groups: &HashMap<&'static str, HashMap<String, Range<GuestAddr>>>,
+    ) -> Self {
+        let mut ranges: Vec<_> = target_groups
+            .values()
+            .flat_map(|g| g.iter())
+            .map(|(n, r)| (r.clone(), Cow::Owned(n.clone())))
+            .collect();
+        ranges.sort_by_key(|x| x.0.start);
+        ranges.dedup_by(|a, b| a.0 == b.0);
+        Self { ranges }
+    }
+
+    /// Returns the function containing `addr` and the offset of `addr` inside it.
+    ///
+    /// # Arguments
+    /// * `addr` - The address to resolve.
+    ///
+    /// # Returns
+    /// Some((name, offset)) if any function range contains the address, None otherwise.
+    pub fn resolve(&self, addr: GuestAddr) -> Option<(Cow<'static, str>, GuestAddr)> {
+        let idx = self.ranges.partition_point(|(r, _)| r.start <= addr);
+        let (r, name) = self.ranges.get(idx.checked_sub(1)?)?;
+        if r.contains(&addr) {
+            Some((name.clone(), addr - r.start))
+        } else {
+            None
+        }
+    }
+}
+
 //============================= QEMU related utility functions
 
 /// Retrieves the current QEMU instruction count.
@@ -148,6 +219,23 @@ pub fn get_icount(emulator: &libafl_qemu::Qemu) -> u64 {
     }
 }
 
+//============================= Interrupt times
+
+/// Activation times of one interrupt source in ticks, stored as little endian u32s in its input part.
+/// Configured times (e.g. minimum inter-arrival times) are given in microseconds, convert them with [`ticks_from_us`].
+pub type InterruptSchedule = Vec<u32>;
+
+/// Converts microseconds to ticks of the current icount shift, rounding down and saturating at u32::MAX.
+/// Converting back with [`us_from_ticks`] is off by less than one tick.
+pub fn ticks_from_us(us: f64) -> u32 {
+    (us * 1000.0 / (1u64 << qemu_icount_shift()) as f64) as u32
+}
+
+/// Converts ticks of the current icount shift to microseconds, exact for all u32 ticks
+pub fn us_from_ticks(ticks: u32) -> f64 {
+    ((ticks as u64) << qemu_icount_shift()) as f64 / 1000.0
+}
+
 /// Converts input bytes to a vector of interrupt times, enforcing minimum inter-arrival time.
 /// 
 /// # Arguments
@@ -156,11 +244,11 @@ pub fn get_icount(emulator: &libafl_qemu::Qemu) -> u64 {
 /// 
 /// # Returns
 /// A sorted vector of interrupt times.
-pub fn input_bytes_to_interrupt_times(buf: &[u8], config: (usize, u32)) -> Vec<u32> {
+pub fn input_bytes_to_interrupt_times(buf: &[u8], config: (usize, u32)) -> InterruptSchedule {
     let len = buf.len();
     let mut start_tick;
-    let mut ret = Vec::with_capacity(min(DO_NUM_INTERRUPT, len / 4));
-    for i in 0..DO_NUM_INTERRUPT {
+    let mut ret = Vec::with_capacity(min(max_num_interrupt(), len / 4));
+    for i in 0..max_num_interrupt() {
         let mut buf4b: [u8; 4] = [0, 0, 0, 0];
         if len >= (i + 1) * 4 {
             for j in 0usize..4usize {
@@ -182,7 +270,7 @@ pub fn input_bytes_to_interrupt_times(buf: &[u8], config: (usize, u32)) -> Vec<u
             continue;
         }
         for j in i + 1..ret.len() {
-            if ret[j] - ret[i] < (config.1 as f32 * QEMU_ISNS_PER_USEC) as u32 {
+            if ret[j] - ret[i] < ticks_from_us(config.1 as f64) {
                 // ret[j] = u32::saturating_add(ret[i],config.1 * QEMU_ISNS_PER_USEC);
                 ret[j] = 0; // remove the interrupt
                 ret.sort_unstable();
@@ -195,6 +283,24 @@ pub fn input_bytes_to_interrupt_times(buf: &[u8], config: (usize, u32)) -> Vec<u
     ret
 }
 
+/// Decodes all complete interrupt times of an input part and sorts them, without applying any filtering.
+/// Two parts with the same result trigger the same interrupts.
+/// 
+/// # Arguments
+/// * `buf` - The input byte buffer.
+/// 
+/// # Returns
+/// A sorted vector of interrupt times.
+pub fn canonical_interrupt_times(buf: &[u8]) -> InterruptSchedule {
+    let mut times = buf
+        .chunks(4)
+        .filter(|x| x.len() == 4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+    times
+}
+
 /// Converts interrupt times back to input bytes.
 /// 
 /// # Arguments
@@ -239,6 +345,33 @@ pub fn read_rec_return_stackframe(emu: &libafl_qemu::Qemu, lr: GuestAddr) -> Gue
     };
 }
 
+//============================= Hashing
+
+/// Version of the hashes of system states, ABBs, STG nodes and jobs, stored in [`super::stg::STGFeedbackState`].
+/// Bump it whenever [`stable_hasher`] or the hashed fields change, dumps of another version can not be loaded.
+#[cfg(not(feature = "legacy_hash"))]
+pub const HASH_VERSION: u32 = 1;
+/// Dumps without a hash version used std's DefaultHasher, whose output may change with any toolchain upgrade
+#[cfg(feature = "legacy_hash")]
+pub const HASH_VERSION: u32 = 0;
+
+#[cfg(not(feature = "legacy_hash"))]
+pub type StableHasher = twox_hash::XxHash64;
+#[cfg(feature = "legacy_hash")]
+pub type StableHasher = std::collections::hash_map::DefaultHasher;
+
+#[cfg(not(feature = "legacy_hash"))]
+const HASH_SEED: u64 = 0x4652_4554;
+
+/// Hasher of all persistent hashes, XxHash64 with a fixed seed, so dumps stay readable after toolchain upgrades.
+/// The feature legacy_hash switches back to DefaultHasher to read dumps of hash version 0.
+pub fn stable_hasher() -> StableHasher {
+    #[cfg(not(feature = "legacy_hash"))]
+    return twox_hash::XxHash64::with_seed(HASH_SEED);
+    #[cfg(feature = "legacy_hash")]
+    return std::collections::hash_map::DefaultHasher::new();
+}
+
 //============================= Tracing related utility functions
 
 /// Inserts or updates metadata in a map, returning a mutable reference.
@@ -273,12 +406,12 @@ where
 /// * `intervals` - A vector of execution intervals.
 /// 
 /// # Returns
-/// A mapping from task name to ABB address to (interval count, exec count, exec time, woet).
+/// A mapping from task name to ABB address to (interval count, exec count, exec time, woet, symbol).
 #[allow(unused)]
 pub fn abb_profile(
     mut intervals: Vec<ExecInterval>,
-) -> HashMap<Cow<'static, str>, HashMap<u32, (usize, usize, u64, u64)>> {
-    let mut ret: HashMap<Cow<'static, str>, HashMap<u32, (usize, usize, u64, u64)>> = HashMap::new();
+) -> HashMap<Cow<'static, str>, HashMap<u32, (usize, usize, u64, u64, Option<String>)>> {
+    let mut ret: HashMap<Cow<'static, str>, HashMap<u32, (usize, usize, u64, u64, Option<String>)>> = HashMap::new();
     intervals.sort_by_key(|x| x.get_task_name_unchecked());
     intervals
         .chunk_by_mut(|x, y| x.get_task_name_unchecked() == y.get_task_name_unchecked())
@@ -333,6 +466,7 @@ pub fn abb_profile(
                                     y.iter().filter(|x| x.is_abb_end()).count(),
                                     y.iter().map(|z| z.get_exec_time()).sum::<_>(),
                                     abb_woet[&y[0].abb.as_ref().unwrap().start],
+                                    y[0].abb.as_ref().unwrap().get_symbol(),
                                 ),
                             )]),
                         );
@@ -345,6 +479,7 @@ pub fn abb_profile(
                                 y.iter().filter(|x| x.is_abb_end()).count(),
                                 y.iter().map(|z| z.get_exec_time()).sum(),
                                 abb_woet[&y[0].abb.as_ref().unwrap().start],
+                                y[0].abb.as_ref().unwrap().get_symbol(),
                             ),
                         );
                     }
@@ -354,6 +489,144 @@ pub fn abb_profile(
     ret
 }
 
+/// Statistics of one abb over many executions, see [`merge_abb_profile`].
+#[derive(Debug, Default, Clone)]
+pub struct AbbReportEntry {
+    /// All end addresses seen for this abb
+    pub ends: HashSet<GuestAddr>,
+    pub interval_count: usize,
+    pub exec_count: usize,
+    /// Highest summed exec time of the abb within a single execution
+    pub max_exec_time: u64,
+    pub woet: u64,
+}
+
+/// Adds the abb profile of one execution to a campaign-wide report.
+/// 
+/// # Arguments
+/// * `report` - Mapping from (task name, abb start) to the merged statistics.
+/// * `intervals` - Execution intervals of one execution.
+/// 
+/// Counts are summed up, exec time and woet keep their maximum.
+pub fn merge_abb_profile(report: &mut HashMap<(String, GuestAddr), AbbReportEntry>, intervals: &[ExecInterval]) {
+    for (task, profile) in abb_profile(intervals.to_vec()) {
+        for (start, (interval_count, exec_count, exec_time, woet, _)) in profile {
+            let entry = report.entry((task.to_string(), start)).or_default();
+            entry.interval_count += interval_count;
+            entry.exec_count += exec_count;
+            entry.max_exec_time = entry.max_exec_time.max(exec_time);
+            entry.woet = entry.woet.max(woet);
+        }
+    }
+    for i in intervals {
+        if let Some(abb) = &i.abb {
+            if let Some(entry) = report.get_mut(&(i.get_task_name_unchecked().to_string(), abb.get_start())) {
+                entry.ends.extend(abb.get_ends().iter().copied());
+            }
+        }
+    }
+}
+
+/// Renders a report built by [`merge_abb_profile`] as csv.
+/// 
+/// # Returns
+/// One row per task and abb, ends separated by `;`, times in microseconds.
+pub fn abb_report_csv(report: &HashMap<(String, GuestAddr), AbbReportEntry>) -> String {
+    let mut ret = String::from("task,abb_start,abb_ends,interval_count,exec_count,max_exec_us,woet_us\n");
+    let isns_per_usec = qemu_isns_per_usec() as f64;
+    for ((task, start), entry) in report.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+        ret.push_str(&format!("{},{},{},{},{},{:.3},{:.3}\n",
+            task, start, entry.ends.iter().sorted().join(";"), entry.interval_count, entry.exec_count,
+            entry.max_exec_time as f64 / isns_per_usec, entry.woet as f64 / isns_per_usec));
+    }
+    ret
+}
+
+/// Running mean and variance of the execution time of one abb instance, updated with Welford's algorithm
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AbbTimeStats {
+    pub count: u64,
+    /// Mean execution time in ticks
+    pub mean: f64,
+    /// Sum of the squared differences from the mean
+    pub m2: f64,
+}
+
+impl AbbTimeStats {
+    pub fn add(&mut self, ticks: u64) {
+        self.count += 1;
+        let delta = ticks as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (ticks as f64 - self.mean);
+    }
+
+    /// Sample variance in ticks², 0 below two samples
+    pub fn variance(&self) -> f64 {
+        if self.count < 2 { 0.0 } else { self.m2 / (self.count - 1) as f64 }
+    }
+
+    /// Standard deviation relative to the mean, 0 for a mean of 0
+    pub fn coefficient_of_variation(&self) -> f64 {
+        if self.mean > 0.0 { self.variance().sqrt() / self.mean } else { 0.0 }
+    }
+}
+
+/// Adds the execution time of every abb instance in `intervals` to `stats`.
+/// Preempted instances are split over several intervals, their times are summed first.
+pub fn add_abb_time_stats(stats: &mut HashMap<AtomicBasicBlock, AbbTimeStats>, intervals: &[ExecInterval]) {
+    let mut instances: HashMap<usize, (&AtomicBasicBlock, u64)> = HashMap::new();
+    for i in intervals {
+        if let Some(abb) = &i.abb {
+            instances.entry(abb.get_instance_id()).or_insert((abb, 0)).1 += i.get_exec_time();
+        }
+    }
+    for (abb, ticks) in instances.into_values() {
+        match stats.get_mut(abb) {
+            Some(x) => x.add(ticks),
+            None => {
+                let mut x = AbbTimeStats::default();
+                x.add(ticks);
+                stats.insert(abb.clone(), x);
+            }
+        }
+    }
+}
+
+/// Renders the stats of [`add_abb_time_stats`] as csv, the abbs with the highest coefficient of variation first.
+/// Abbs with fewer than two instances are left out, times in microseconds.
+pub fn abb_variance_csv(stats: &HashMap<AtomicBasicBlock, AbbTimeStats>) -> String {
+    let mut ret = String::from("task,abb_start,level,symbol,count,mean_us,std_dev_us,cv\n");
+    let isns_per_usec = qemu_isns_per_usec() as f64;
+    let rows = stats.iter()
+        .filter(|x| x.1.count >= 2)
+        .sorted_by(|a, b| b.1.coefficient_of_variation().total_cmp(&a.1.coefficient_of_variation()).then_with(|| a.0.cmp(b.0)));
+    for (abb, x) in rows {
+        ret.push_str(&format!("{},{},{},{},{},{:.3},{:.3},{:.4}\n",
+            abb.instance_name.as_deref().unwrap_or(""), abb.get_start(), abb.get_level(), abb.get_symbol().unwrap_or_default(), x.count,
+            x.mean / isns_per_usec, x.variance().sqrt() / isns_per_usec, x.coefficient_of_variation()));
+    }
+    ret
+}
+
+/// Renders an input usage report as csv.
+/// 
+/// # Arguments
+/// * `usage` - Mapping from input offset to the (task name, abb start) pairs reading it.
+/// * `unused` - Offsets which were never read.
+/// 
+/// # Returns
+/// One row per offset and reader, offsets without readers have empty task and abb columns.
+pub fn input_usage_csv(usage: &HashMap<u32, HashSet<(Cow<'static, str>, u32)>>, unused: &[u32]) -> String {
+    let mut ret = String::from("offset,task,abb\n");
+    for offset in usage.keys().chain(unused.iter()).copied().sorted_unstable() {
+        match usage.get(&offset) {
+            Some(readers) => readers.iter().sorted().for_each(|(task, abb)| ret.push_str(&format!("{},{},{}\n", offset, task, abb))),
+            None => ret.push_str(&format!("{},,\n", offset)),
+        }
+    }
+    ret
+}
+
 /// Returns an immutable reference from a mutable one.
 /// 
 /// # Arguments
@@ -364,3 +637,32 @@ pub fn abb_profile(
 pub fn unmut<T>(x: &mut T) -> &T {
     &(*x)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length of a tick in microseconds, the tests run with the default icount shift
+    fn tick_us() -> f64 {
+        (1u64 << qemu_icount_shift()) as f64 / 1000.0
+    }
+
+    #[test]
+    fn ticks_and_us_round_trip() {
+        assert_eq!(qemu_icount_shift(), crate::time::clock::DEFAULT_QEMU_ICOUNT_SHIFT);
+        assert_eq!(ticks_from_us(1000.0), 31250);
+        assert_eq!(us_from_ticks(31250), 1000.0);
+        for us in [0.0, 0.02, 0.5, 1.0, 123.456, 1e6] {
+            let back = us_from_ticks(ticks_from_us(us));
+            assert!(back <= us && us - back < tick_us(), "{} -> {}", us, back);
+        }
+    }
+
+    #[test]
+    fn ticks_from_us_saturates() {
+        assert_eq!(ticks_from_us(1e12), u32::MAX);
+        assert_eq!(ticks_from_us(-5.0), 0);
+        // exact for all ticks, no two convert to the same time
+        assert!(us_from_ticks(u32::MAX) > us_from_ticks(u32::MAX - 1));
+    }
+}
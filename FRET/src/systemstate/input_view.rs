@@ -0,0 +1,559 @@
+//! Checked access to the parts of a [`MultipartInput`].
+//! The program input lives in the "bytes" part, the activation times of interrupt source `i` in the "isr_{i}_times" part as little endian u32s.
+
+use std::{env, fmt, fs, path::Path, sync::OnceLock};
+
+use either::Either::{self, Left, Right};
+use hashbrown::HashMap;
+use libafl::inputs::{BytesInput, HasMutatorBytes, Input, MultipartInput};
+use serde::{Deserialize, Serialize};
+
+use crate::time::clock::qemu_icount_shift;
+
+use super::helpers::{canonical_interrupt_times, input_bytes_to_interrupt_times, interrupt_times_to_input_bytes, ticks_from_us, us_from_ticks, InterruptSchedule};
+use super::replay_script::ReplayScript;
+
+/// Name of the part holding the program input
+pub const BYTES_PART: &str = "bytes";
+
+/// Interrupt times per source if MAX_NUM_INTERRUPT is not configured
+pub const DEFAULT_MAX_NUM_INTERRUPT: usize = 128;
+/// Interrupt sources if NUM_INTERRUPT_SOURCES is not configured, as in qemu-libafl-bridge/hw/timer/armv7m_systick.c:319 and FreeRTOS/FreeRTOS/Demo/CORTEX_M3_MPS2_QEMU_GCC/init/startup.c:216
+pub const DEFAULT_NUM_INTERRUPT_SOURCES: usize = 6;
+/// Set once during startup, (interrupt times per source, interrupt sources)
+static INTERRUPT_LAYOUT: OnceLock<(usize, usize)> = OnceLock::new();
+
+/// Name of the part holding the release phase of each task of TASK_PHASES, as little endian u32s in config order
+pub const TASK_PHASES_PART: &str = "task_phases";
+/// Set once during startup, see [`set_task_phase_bounds`]
+static TASK_PHASE_BOUNDS: OnceLock<Vec<u32>> = OnceLock::new();
+
+/// Set the number of interrupt times per source and the number of sources. Must happen before any input is built.
+pub fn set_interrupt_layout(max_num_interrupt: usize, num_interrupt_sources: usize) {
+    let current = *INTERRUPT_LAYOUT.get_or_init(|| (max_num_interrupt, num_interrupt_sources));
+    assert_eq!(current, (max_num_interrupt, num_interrupt_sources), "interrupt layout was already initialized to {:?}", current);
+}
+
+/// Set the interrupt layout from MAX_NUM_INTERRUPT and NUM_INTERRUPT_SOURCES, using the defaults for unset variables
+pub fn set_interrupt_layout_from_env() {
+    let read = |var: &str, default: usize| {
+        env::var(var).map_or(default, |x| str::parse::<usize>(&x).unwrap_or_else(|_| panic!("{} must be an integer.", var)))
+    };
+    set_interrupt_layout(
+        read("MAX_NUM_INTERRUPT", DEFAULT_MAX_NUM_INTERRUPT),
+        read("NUM_INTERRUPT_SOURCES", DEFAULT_NUM_INTERRUPT_SOURCES),
+    );
+}
+
+/// Set the exclusive upper bound of each task phase, in the order of TASK_PHASES. Must happen before any input is built.
+pub fn set_task_phase_bounds(bounds: Vec<u32>) {
+    let current = TASK_PHASE_BOUNDS.get_or_init(|| bounds.clone());
+    assert_eq!(current, &bounds, "task phase bounds were already initialized to {:?}", current);
+}
+
+/// Exclusive upper bound of each task phase, empty if no phases are fuzzed
+pub fn task_phase_bounds() -> &'static [u32] {
+    TASK_PHASE_BOUNDS.get_or_init(Vec::new)
+}
+
+/// Interrupt times per source
+pub fn max_num_interrupt() -> usize {
+    INTERRUPT_LAYOUT.get_or_init(|| (DEFAULT_MAX_NUM_INTERRUPT, DEFAULT_NUM_INTERRUPT_SOURCES)).0
+}
+
+/// Number of interrupt sources of the target
+pub fn num_interrupt_sources() -> usize {
+    INTERRUPT_LAYOUT.get_or_init(|| (DEFAULT_MAX_NUM_INTERRUPT, DEFAULT_NUM_INTERRUPT_SOURCES)).1
+}
+
+/// Errors when accessing parts of a [`MultipartInput`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputError {
+    /// The input has no part with this name
+    MissingPart(String),
+    /// A line of a schedule could not be parsed, see [`schedule_to_input`]
+    InvalidSchedule(usize, String),
+    /// An interrupt time of an [`EditInput`] is neither ticks nor microseconds
+    InvalidTime(String),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputError::MissingPart(name) => write!(f, "input has no part named \"{}\"", name),
+            InputError::InvalidSchedule(line, reason) => write!(f, "schedule line {}: {}", line, reason),
+            InputError::InvalidTime(time) => write!(f, "interrupt time {:?} is neither ticks nor a number with the suffix \"us\"", time),
+        }
+    }
+}
+
+impl std::error::Error for InputError {}
+
+impl From<InputError> for libafl::Error {
+    fn from(e: InputError) -> Self {
+        libafl::Error::illegal_argument(e.to_string())
+    }
+}
+
+/// Name of the part holding the activation times of interrupt source `source`
+pub fn interrupt_part_name(source: usize) -> String {
+    format!("isr_{}_times", source)
+}
+
+/// Inverse of [`interrupt_part_name`], None if `name` does not belong to an interrupt source
+pub fn interrupt_source_of(name: &str) -> Option<usize> {
+    name.strip_prefix("isr_")?.strip_suffix("_times")?.parse().ok()
+}
+
+/// Returns the program input
+pub fn bytes_part<I: HasMutatorBytes>(input: &MultipartInput<I>) -> Result<&[u8], InputError> {
+    input
+        .parts_by_name(BYTES_PART)
+        .next()
+        .map(|(_, x)| x.bytes())
+        .ok_or_else(|| InputError::MissingPart(BYTES_PART.to_string()))
+}
+
+/// Returns the program input for modification
+pub fn bytes_part_mut<I: HasMutatorBytes>(input: &mut MultipartInput<I>) -> Result<&mut [u8], InputError> {
+    input
+        .parts_by_name_mut(BYTES_PART)
+        .next()
+        .map(|(_, x)| x.bytes_mut())
+        .ok_or_else(|| InputError::MissingPart(BYTES_PART.to_string()))
+}
+
+/// Returns the program input for modification, adding an empty one if it is missing
+pub fn bytes_part_or_insert<I: HasMutatorBytes + Default>(input: &mut MultipartInput<I>) -> &mut I {
+    if input.parts_by_name(BYTES_PART).next().is_none() {
+        input.add_part(BYTES_PART.to_string(), I::default());
+    }
+    input.parts_by_name_mut(BYTES_PART).next().unwrap().1
+}
+
+/// Decodes the interrupt times of the source in `config` = (source, minimum inter-arrival time in us), see [`input_bytes_to_interrupt_times`]
+pub fn interrupt_times<I: HasMutatorBytes>(input: &MultipartInput<I>, config: (usize, u32)) -> Result<Vec<u32>, InputError> {
+    let name = interrupt_part_name(config.0);
+    input
+        .parts_by_name(&name)
+        .next()
+        .map(|(_, x)| input_bytes_to_interrupt_times(x.bytes(), config))
+        .ok_or(InputError::MissingPart(name))
+}
+
+/// Returns the raw interrupt part of `source`, adding an empty one if it is missing
+pub fn interrupt_part_mut<I: HasMutatorBytes + Default>(input: &mut MultipartInput<I>, source: usize) -> &mut I {
+    let name = interrupt_part_name(source);
+    if input.parts_by_name(&name).next().is_none() {
+        input.add_part(name.clone(), I::default());
+    }
+    input.parts_by_name_mut(&name).next().unwrap().1
+}
+
+/// Replaces the interrupt times of `source` with the sorted `times`, adding the part if it is missing
+pub fn set_interrupt_times<I: HasMutatorBytes + Default>(input: &mut MultipartInput<I>, source: usize, times: &[u32]) {
+    let mut times = times.to_vec();
+    times.sort_unstable();
+    let part = interrupt_part_mut(input, source);
+    drop(part.drain(..));
+    part.extend(&interrupt_times_to_input_bytes(&times));
+}
+
+/// Decodes the task phases in the order of TASK_PHASES, without reducing them to their bounds. Empty if the input has no such part.
+pub fn task_phases<I: HasMutatorBytes>(input: &MultipartInput<I>) -> Vec<u32> {
+    input
+        .parts_by_name(TASK_PHASES_PART)
+        .next()
+        .map_or(Vec::new(), |(_, x)| x.bytes().chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect())
+}
+
+/// Replaces the task phases, adding the part if it is missing
+pub fn set_task_phases<I: HasMutatorBytes + Default>(input: &mut MultipartInput<I>, phases: &[u32]) {
+    if input.parts_by_name(TASK_PHASES_PART).next().is_none() {
+        input.add_part(TASK_PHASES_PART.to_string(), I::default());
+    }
+    let part = input.parts_by_name_mut(TASK_PHASES_PART).next().unwrap().1;
+    drop(part.drain(..));
+    part.extend(&interrupt_times_to_input_bytes(phases));
+}
+
+/// Changes made by [`repair_input`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Repairs {
+    /// Original length of the program input, if it was truncated
+    pub truncated: Option<usize>,
+    /// Interrupt sources whose parts were dropped because they are not configured
+    pub dropped_sources: Vec<usize>,
+    /// Configured interrupt sources which had no part, added without activations
+    pub added_sources: Vec<usize>,
+    /// Original length of the program input, if it was padded, only done by [`migrate_input`]
+    pub padded: Option<usize>,
+    /// Interrupt sources whose times were sorted or cut to MAX_NUM_INTERRUPT, only done by [`migrate_input`]
+    pub refolded_sources: Vec<usize>,
+    /// Parts removed on request, only done by [`migrate_input`]
+    pub stripped_parts: Vec<String>,
+    /// The input had no program input, an empty one was added
+    pub added_bytes: bool,
+}
+
+impl Repairs {
+    pub fn is_empty(&self) -> bool {
+        self.truncated.is_none() && self.dropped_sources.is_empty() && self.added_sources.is_empty()
+            && self.padded.is_none() && self.refolded_sources.is_empty() && self.stripped_parts.is_empty() && !self.added_bytes
+    }
+}
+
+impl fmt::Display for Repairs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(len) = self.truncated {
+            parts.push(format!("truncated input of {} bytes", len));
+        }
+        if !self.dropped_sources.is_empty() {
+            parts.push(format!("dropped {} unknown interrupt sources {:?}", self.dropped_sources.len(), self.dropped_sources));
+        }
+        if !self.added_sources.is_empty() {
+            parts.push(format!("added {} missing interrupt sources {:?}", self.added_sources.len(), self.added_sources));
+        }
+        if let Some(len) = self.padded {
+            parts.push(format!("padded input of {} bytes", len));
+        }
+        if !self.refolded_sources.is_empty() {
+            parts.push(format!("refolded interrupt times of sources {:?}", self.refolded_sources));
+        }
+        if !self.stripped_parts.is_empty() {
+            parts.push(format!("stripped parts {:?}", self.stripped_parts));
+        }
+        if self.added_bytes {
+            parts.push("added empty program input".to_string());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Fits `input` to the current target: truncates the program input to `max_size` bytes, drops the interrupt parts of
+/// sources which are not in `sources` and adds empty ones for sources without a part. Other parts are kept.
+/// A missing program input is added empty.
+pub fn repair_input<I: HasMutatorBytes + Default + Clone>(
+    input: &MultipartInput<I>,
+    max_size: usize,
+    sources: &[usize],
+) -> Result<(MultipartInput<I>, Repairs), InputError> {
+    let mut repairs = Repairs::default();
+    let len = bytes_part(input).map_or(0, |x| x.len());
+    let mut ret = MultipartInput::new();
+    for (name, part) in input.iter() {
+        match interrupt_source_of(name) {
+            Some(source) if !sources.contains(&source) => repairs.dropped_sources.push(source),
+            _ => ret.add_part(name.clone(), part.clone()),
+        }
+    }
+    if len > max_size {
+        repairs.truncated = Some(len);
+        let (_, part) = ret.parts_by_name_mut(BYTES_PART).next().unwrap();
+        drop(part.drain(max_size..));
+    }
+    if ret.parts_by_name(BYTES_PART).next().is_none() {
+        bytes_part_or_insert(&mut ret);
+        repairs.added_bytes = true;
+    }
+    for &source in sources {
+        if input.parts_by_name(&interrupt_part_name(source)).next().is_none() {
+            interrupt_part_mut(&mut ret, source).extend(&vec![0; max_num_interrupt() * 4]);
+            repairs.added_sources.push(source);
+        }
+    }
+    repairs.dropped_sources.sort_unstable();
+    Ok((ret, repairs))
+}
+
+/// Fits an input of an earlier campaign to the current target: removes the parts matching any of the `strip` globs
+/// (never the program input), repairs the rest with [`repair_input`], pads the program input with zeros to `max_size` bytes
+/// and sorts the interrupt times of each source in `sources`, keeping at most MAX_NUM_INTERRUPT of them.
+pub fn migrate_input<I: HasMutatorBytes + Default + Clone>(
+    input: &MultipartInput<I>,
+    max_size: usize,
+    sources: &[usize],
+    strip: &[String],
+) -> Result<(MultipartInput<I>, Repairs), InputError> {
+    let mut kept = MultipartInput::new();
+    let mut stripped_parts = Vec::new();
+    for (name, part) in input.iter() {
+        if name != BYTES_PART && strip.iter().any(|x| glob_match(x, name)) {
+            stripped_parts.push(name.clone());
+        } else {
+            kept.add_part(name.clone(), part.clone());
+        }
+    }
+    let (mut ret, mut repairs) = repair_input(&kept, max_size, sources)?;
+    repairs.stripped_parts = stripped_parts;
+    let (_, bytes) = ret.parts_by_name_mut(BYTES_PART).next().unwrap();
+    let len = bytes.bytes().len();
+    if len < max_size {
+        repairs.padded = Some(len);
+        bytes.extend(&vec![0; max_size - len]);
+    }
+    for &source in sources {
+        let part = interrupt_part_mut(&mut ret, source);
+        let mut times = canonical_interrupt_times(part.bytes());
+        times.truncate(max_num_interrupt());
+        let folded = interrupt_times_to_input_bytes(&times);
+        if folded != part.bytes() {
+            repairs.refolded_sources.push(source);
+            drop(part.drain(..));
+            part.extend(&folded);
+        }
+    }
+    Ok((ret, repairs))
+}
+
+/// Matches `name` against a pattern in which `*` stands for any sequence and `?` for any single character
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    // positions to retry from after the last `*`
+    let (mut pi, mut ni, mut star) = (0, 0, None);
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|x| *x == '*')
+}
+
+/// An interrupt time of the edit format: ticks, or a string of microseconds with the suffix "us" like "250.5us"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EditTime {
+    Ticks(u32),
+    Micros(String),
+}
+
+impl EditTime {
+    /// The time in ticks, microseconds are rounded down to a whole tick
+    pub fn ticks(&self) -> Result<u32, InputError> {
+        match self {
+            EditTime::Ticks(x) => Ok(*x),
+            EditTime::Micros(x) => x.trim().strip_suffix("us")
+                .and_then(|us| us.trim().parse::<f64>().ok())
+                .filter(|us| us.is_finite() && *us >= 0.0)
+                .map(ticks_from_us)
+                .ok_or_else(|| InputError::InvalidTime(x.clone())),
+        }
+    }
+}
+
+/// An input in the edit format: the program input as bytes, the interrupt parts as decoded times
+pub type EditInput = HashMap<String, Either<Vec<u8>, Vec<EditTime>>>;
+
+/// Builds a [`MultipartInput`] from the edit format
+pub fn fold_input(input: EditInput) -> Result<MultipartInput<BytesInput>, InputError> {
+    let mut res = MultipartInput::new();
+    for (name, data) in input {
+        match data {
+            Left(x) => res.add_part(name, BytesInput::new(x)),
+            Right(x) => {
+                let times = x.iter().map(EditTime::ticks).collect::<Result<InterruptSchedule, _>>()?;
+                match interrupt_source_of(&name) {
+                    Some(source) => set_interrupt_times(&mut res, source, &times),
+                    None => res.add_part(name, BytesInput::new(interrupt_times_to_input_bytes(&times))),
+                }
+            },
+        }
+    }
+    Ok(res)
+}
+
+/// Converts a [`MultipartInput`] to the edit format, the inverse of [`fold_input`]. Interrupt times are sorted and deduplicated
+pub fn unfold_input(input: &MultipartInput<BytesInput>) -> EditInput {
+    let mut res = HashMap::new();
+    for (name, part) in input.iter() {
+        if name == BYTES_PART {
+            res.insert(name.to_string(), Left(part.bytes().to_vec()));
+        } else if name == TASK_PHASES_PART {
+            // phases belong to the tasks in config order, they must not be sorted
+            res.insert(name.to_string(), Right(part.bytes().chunks_exact(4).map(|x| EditTime::Ticks(u32::from_le_bytes(x.try_into().unwrap()))).collect()));
+        } else {
+            res.insert(name.to_string(), Right(canonical_interrupt_times(part.bytes()).into_iter().map(EditTime::Ticks).collect()));
+        }
+    }
+    res
+}
+
+/// Renders the interrupt parts of an edit input as comments, each time in ticks and microseconds
+pub fn interrupt_times_to_comments(input: &EditInput) -> String {
+    let mut out = String::new();
+    let mut parts = input.iter().filter(|x| interrupt_source_of(x.0).is_some()).collect::<Vec<_>>();
+    parts.sort_unstable_by_key(|x| x.0);
+    for (name, data) in parts {
+        if let Right(times) = data {
+            let times = times.iter().filter_map(|x| x.ticks().ok()).map(|x| format!("{} = {}us", x, ticks_to_us(x))).collect::<Vec<_>>();
+            out.push_str(&format!("// {}: {}\n", name, times.join(", ")));
+        }
+    }
+    out
+}
+
+/// Formats ticks as microseconds, exact since one tick takes 2^shift ns
+fn ticks_to_us(ticks: u32) -> String {
+    format!("{:.3}", us_from_ticks(ticks))
+}
+
+/// Inverse of [`ticks_to_us`], None unless `us` is a whole number of ticks that fits a u32
+fn us_to_ticks(us: &str) -> Option<u32> {
+    let (int, frac) = us.split_once('.').unwrap_or((us, ""));
+    if frac.len() > 3 || !frac.chars().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    let ns = int.parse::<u64>().ok()?.checked_mul(1000)?.checked_add(format!("{:0<3}", frac).parse::<u64>().ok()?)?;
+    let shift = qemu_icount_shift();
+    if ns % (1 << shift) != 0 {
+        return None;
+    }
+    u32::try_from(ns >> shift).ok()
+}
+
+/// Renders an input as a schedule: one "name: value" line per part, interrupt parts as their sorted activation times
+/// in microseconds separated by commas, all other parts as hex. Interrupt parts with sorted times survive
+/// [`schedule_to_input`] byte for byte, which holds for all inputs written by the fuzzer.
+pub fn input_to_schedule(input: &MultipartInput<BytesInput>) -> String {
+    let mut out = format!("# interrupt times in us, icount shift {}\n", qemu_icount_shift());
+    for (name, part) in input.iter() {
+        let value = if interrupt_source_of(name).is_some() {
+            canonical_interrupt_times(part.bytes()).into_iter().map(ticks_to_us).collect::<Vec<_>>().join(",")
+        } else {
+            part.bytes().iter().map(|x| format!("{:02x}", x)).collect::<String>()
+        };
+        out.push_str(&format!("{}: {}\n", name, value));
+    }
+    out
+}
+
+/// Parses a schedule written by [`input_to_schedule`], checking that the times of each source do not decrease
+pub fn schedule_to_input(schedule: &str) -> Result<MultipartInput<BytesInput>, InputError> {
+    let mut res = MultipartInput::new();
+    for (num, line) in schedule.lines().enumerate().map(|(i, x)| (i + 1, x.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |reason: &str| InputError::InvalidSchedule(num, reason.to_string());
+        let (name, value) = line.split_once(':').ok_or_else(|| err("expected \"name: value\""))?;
+        let (name, value) = (name.trim(), value.trim());
+        let bytes = if interrupt_source_of(name).is_some() {
+            let times = value.split(',').filter(|x| !x.trim().is_empty())
+                .map(|x| us_to_ticks(x.trim()).ok_or_else(|| err(&format!("{} is not a tick count in us fitting a u32", x.trim()))))
+                .collect::<Result<Vec<u32>, _>>()?;
+            if times.windows(2).any(|w| w[0] > w[1]) {
+                return Err(err("times are not monotonic"));
+            }
+            interrupt_times_to_input_bytes(&times)
+        } else {
+            if value.len() % 2 != 0 {
+                return Err(err("odd number of hex digits"));
+            }
+            (0..value.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| err("invalid hex")))
+                .collect::<Result<Vec<u8>, _>>()?
+        };
+        res.add_part(name.to_string(), BytesInput::new(bytes));
+    }
+    if res.parts_by_name(BYTES_PART).next().is_none() {
+        return Err(InputError::MissingPart(BYTES_PART.to_string()));
+    }
+    Ok(res)
+}
+
+/// Formats of input files, see [`read_input_file`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// A serialized [`MultipartInput`], as written to the corpus
+    Case,
+    /// A [`ReplayScript`], the ticks are stored as interrupt times
+    Replay,
+    /// An [`EditInput`] as ron
+    Edit,
+    /// A [`MultipartInput`] as ron
+    Ron,
+    /// A schedule as written by [`input_to_schedule`]
+    Schedule,
+    /// Only the program input, without interrupt parts
+    Raw,
+}
+
+/// Reads an input file, trying the formats in the order of [`InputFormat`].
+/// Anything else is taken as the raw program input, the caller has to add the interrupt parts.
+pub fn read_input_file(path: &Path) -> std::io::Result<(MultipartInput<BytesInput>, InputFormat)> {
+    if let Ok(x) = MultipartInput::<BytesInput>::from_file(path) {
+        return Ok((x, InputFormat::Case));
+    }
+    let bytes = fs::read(path)?;
+    let input_str = String::from_utf8_lossy(&bytes);
+    if let Ok(x) = ReplayScript::from_ron(&input_str) {
+        return Ok((x.to_input(), InputFormat::Replay));
+    }
+    if let Ok(x) = ron::from_str::<EditInput>(&input_str) {
+        let x = fold_input(x).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        return Ok((x, InputFormat::Edit));
+    }
+    if let Ok(x) = ron::from_str::<MultipartInput<BytesInput>>(&input_str) {
+        return Ok((x, InputFormat::Ron));
+    }
+    if let Ok(x) = schedule_to_input(&input_str) {
+        return Ok((x, InputFormat::Schedule));
+    }
+    Ok((MultipartInput::from([(BYTES_PART, BytesInput::new(bytes))]), InputFormat::Raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An input with interrupt times but no program input, e.g. written by hand or by another tool
+    fn interrupts_only() -> MultipartInput<BytesInput> {
+        let mut input = MultipartInput::new();
+        set_interrupt_times(&mut input, 0, &[2000, 1000]);
+        input
+    }
+
+    fn part<'a>(input: &'a MultipartInput<BytesInput>, name: &str) -> Option<&'a [u8]> {
+        input.parts_by_name(name).next().map(|(_, x)| x.bytes())
+    }
+
+    #[test]
+    fn access_without_program_input() {
+        let mut input = interrupts_only();
+        assert_eq!(bytes_part(&input), Err(InputError::MissingPart(BYTES_PART.to_string())));
+        assert!(bytes_part_mut(&mut input).is_err());
+        // what the harness runs instead
+        assert_eq!(bytes_part(&input).unwrap_or(&[]), &[] as &[u8]);
+        assert!(bytes_part_or_insert(&mut input).bytes().is_empty());
+        assert_eq!(bytes_part(&input), Ok(&[] as &[u8]));
+        assert_eq!(part(&input, &interrupt_part_name(0)), Some(&interrupt_times_to_input_bytes(&[1000, 2000])[..]));
+    }
+
+    #[test]
+    fn repair_adds_program_input() {
+        let input = interrupts_only();
+        let (repaired, repairs) = repair_input(&input, 16, &[0]).unwrap();
+        assert_eq!(repairs, Repairs { added_bytes: true, ..Default::default() });
+        assert_eq!(repairs.to_string(), "added empty program input");
+        assert_eq!(part(&repaired, BYTES_PART), Some(&[] as &[u8]));
+        assert_eq!(part(&repaired, &interrupt_part_name(0)), part(&input, &interrupt_part_name(0)));
+        // nothing left to repair
+        assert!(repair_input(&repaired, 16, &[0]).unwrap().1.is_empty());
+
+        let (migrated, repairs) = migrate_input(&input, 16, &[0], &[]).unwrap();
+        assert!(repairs.added_bytes);
+        assert_eq!(repairs.padded, Some(0));
+        assert_eq!(part(&migrated, BYTES_PART), Some(&[0; 16][..]));
+    }
+}
@@ -0,0 +1,232 @@
+//! Shrinks worst-case inputs while keeping their runtime, see [`minimize_input`] and the [`WorstCaseMinimizerStage`].
+
+use core::marker::PhantomData;
+use std::{fmt, path::PathBuf};
+
+use libafl::{
+    common::HasMetadata, corpus::{Corpus, CorpusId}, inputs::{HasMutatorBytes, Input, MultipartInput}, prelude::UsesInput, stages::Stage, state::{HasCorpus, UsesState}, Error, ExecutesInput
+};
+use serde::{Deserialize, Serialize};
+
+use super::input_view::{bytes_part, bytes_part_mut, interrupt_source_of, BYTES_PART};
+use super::target_os::{SystemTraceData, TargetSystem};
+
+/// Changes made by [`minimize_input`]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MinimizedMetadata {
+    /// Runtime (or WORT of the selected task) of the original input
+    pub baseline: u64,
+    /// Runtime (or WORT of the selected task) of the minimized input
+    pub minimized: u64,
+    /// Bytes cut from the end of the program input
+    pub truncated_bytes: usize,
+    /// Bytes of the program input set to zero
+    pub zeroed_bytes: usize,
+    /// Interrupt times removed over all sources
+    pub removed_interrupts: usize,
+    /// Executions spent
+    pub executions: usize,
+}
+libafl_bolts::impl_serdeany!(MinimizedMetadata);
+
+impl fmt::Display for MinimizedMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {} ticks, truncated {} bytes, zeroed {} bytes, removed {} interrupts in {} executions",
+            self.baseline, self.minimized, self.truncated_bytes, self.zeroed_bytes, self.removed_interrupts, self.executions)
+    }
+}
+
+/// Lowest measurement accepted for a reduction, `tolerance` is given in percent of `baseline`
+fn accepted_floor(baseline: u64, tolerance: f64) -> u64 {
+    (baseline as f64 * (1.0 - tolerance.clamp(0.0, 100.0) / 100.0)).ceil() as u64
+}
+
+/// Greedily truncates the program input, zeroes ranges of it and removes interrupt times.
+/// A reduction is kept if `measure` stays at or above the measurement of `input` minus `tolerance` percent.
+pub fn minimize_input<I, F>(input: &MultipartInput<I>, tolerance: f64, mut measure: F) -> Result<(MultipartInput<I>, MinimizedMetadata), Error>
+where
+    I: HasMutatorBytes + Clone,
+    F: FnMut(&MultipartInput<I>) -> Result<u64, Error>,
+{
+    let mut stats = MinimizedMetadata::default();
+    stats.baseline = measure(input)?;
+    stats.executions = 1;
+    let floor = accepted_floor(stats.baseline, tolerance);
+    let mut best = input.clone();
+    let mut accept = |candidate: &MultipartInput<I>, stats: &mut MinimizedMetadata| -> Result<bool, Error> {
+        stats.executions += 1;
+        Ok(measure(candidate)? >= floor)
+    };
+
+    // Cut the tail in halving steps, keep the step size while it succeeds
+    let mut chunk = bytes_part(&best)?.len() / 2;
+    while chunk > 0 {
+        let len = bytes_part(&best)?.len();
+        if chunk > len {
+            chunk = len / 2;
+            continue;
+        }
+        let mut candidate = best.clone();
+        drop(candidate.parts_by_name_mut(BYTES_PART).next().unwrap().1.drain(len - chunk..));
+        if accept(&candidate, &mut stats)? {
+            best = candidate;
+            stats.truncated_bytes += chunk;
+        } else {
+            chunk /= 2;
+        }
+    }
+
+    // Zero ranges of the remaining bytes, from large to single bytes
+    let len = bytes_part(&best)?.len();
+    let mut chunk = len / 2;
+    while chunk > 0 {
+        for start in (0..len).step_by(chunk) {
+            let range = start..(start + chunk).min(len);
+            if bytes_part(&best)?[range.clone()].iter().all(|x| *x == 0) {
+                continue;
+            }
+            let mut candidate = best.clone();
+            let bytes = bytes_part_mut(&mut candidate)?;
+            let zeroed = bytes[range.clone()].iter().filter(|x| **x != 0).count();
+            bytes[range].fill(0);
+            if accept(&candidate, &mut stats)? {
+                best = candidate;
+                stats.zeroed_bytes += zeroed;
+            }
+        }
+        chunk /= 2;
+    }
+
+    // Remove single interrupt times, latest first
+    let isr_parts: Vec<usize> = best.names().iter().enumerate()
+        .filter(|(_, name)| interrupt_source_of(name).is_some())
+        .map(|(i, _)| i)
+        .collect();
+    for i in isr_parts {
+        let num = best.parts()[i].bytes().len() / 4;
+        for t in (0..num).rev() {
+            let mut candidate = best.clone();
+            drop(candidate.parts_mut()[i].drain(t * 4..t * 4 + 4));
+            if accept(&candidate, &mut stats)? {
+                best = candidate;
+                stats.removed_interrupts += 1;
+            }
+        }
+    }
+
+    stats.minimized = measure(&best)?;
+    stats.executions += 1;
+    Ok((best, stats))
+}
+
+/// Executes `input` and returns its runtime, or the WORT of `select_task` if given
+pub fn measure_input<E, EM, Z, S, SYS>(
+    fuzzer: &mut Z,
+    executor: &mut E,
+    state: &mut S,
+    manager: &mut EM,
+    input: &<S as UsesInput>::Input,
+    select_task: &Option<String>,
+) -> Result<u64, Error>
+where
+    E: UsesState<State = S>,
+    EM: UsesState<State = S>,
+    Z: ExecutesInput<E, EM, State = S>,
+    S: HasMetadata + UsesInput + libafl::state::State,
+    SYS: TargetSystem,
+{
+    // Same baseline as the QemuClockObserver, each restore rewinds the icount to the boot snapshot
+    #[cfg(feature = "snapshot_restore")]
+    let start = 0;
+    #[cfg(not(feature = "snapshot_restore"))]
+    let start = unsafe { libafl_qemu::sys::icount_get_raw() };
+    fuzzer.execute_input(state, executor, manager, input)?;
+    Ok(match select_task {
+        Some(task) => state.metadata::<SYS::TraceData>().map_or(0, |trace| trace.wort_of_task(task)),
+        None => unsafe { libafl_qemu::sys::icount_get_raw() }.saturating_sub(start),
+    })
+}
+
+/// Minimizes the testcase with the highest exec_time once every `interval` corpus additions.
+/// The testcase is marked with [`MinimizedMetadata`] and the minimized input is written to `dump_dir`, the corpus is left unchanged.
+#[derive(Clone, Debug)]
+pub struct WorstCaseMinimizerStage<E, EM, Z, SYS> {
+    #[allow(clippy::type_complexity)]
+    phantom: PhantomData<(E, EM, Z, SYS)>,
+    interval: usize,
+    tolerance: f64,
+    select_task: Option<String>,
+    dump_dir: PathBuf,
+    last_count: usize,
+}
+
+impl<E, EM, Z, SYS> WorstCaseMinimizerStage<E, EM, Z, SYS> {
+    pub fn new(interval: usize, tolerance: f64, select_task: Option<String>, dump_dir: PathBuf) -> Self {
+        std::fs::create_dir_all(&dump_dir).expect("Could not create minimized directory");
+        Self { phantom: PhantomData, interval: interval.max(1), tolerance, select_task, dump_dir, last_count: 0 }
+    }
+}
+
+impl<E, EM, Z, SYS> UsesState for WorstCaseMinimizerStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+{
+    type State = E::State;
+}
+
+impl<E, EM, Z, I, SYS> Stage<E, EM, Z> for WorstCaseMinimizerStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+    EM: UsesState<State = Self::State>,
+    Z: ExecutesInput<E, EM, State = Self::State>,
+    Self::State: HasCorpus + HasMetadata + UsesInput<Input = MultipartInput<I>>,
+    <Self::State as HasCorpus>::Corpus: Corpus<Input = MultipartInput<I>>,
+    I: HasMutatorBytes + Clone + Input,
+    SYS: TargetSystem,
+{
+    fn perform(
+        &mut self,
+        fuzzer: &mut Z,
+        executor: &mut E,
+        state: &mut Self::State,
+        manager: &mut EM,
+    ) -> Result<(), Error> {
+        let count = state.corpus().count();
+        if count < self.last_count + self.interval {
+            return Ok(());
+        }
+        self.last_count = count;
+
+        let worst: Option<CorpusId> = state.corpus().ids()
+            .max_by_key(|id| state.corpus().get(*id).map_or(None, |x| *x.borrow().exec_time()));
+        let Some(id) = worst else { return Ok(()) };
+        let input = {
+            let testcase = state.corpus().get(id)?.borrow();
+            if testcase.has_metadata::<MinimizedMetadata>() {
+                return Ok(());
+            }
+            match testcase.input() {
+                Some(x) => x.clone(),
+                // on-disk corpora only keep the file
+                None => return Ok(()),
+            }
+        };
+
+        let select_task = self.select_task.clone();
+        let (minimized, stats) = minimize_input(&input, self.tolerance, |candidate| {
+            measure_input::<_, _, _, _, SYS>(fuzzer, executor, state, manager, candidate, &select_task)
+        })?;
+        println!("Minimized testcase {}: {}", id, stats);
+        let _ = minimized.to_file(self.dump_dir.join(format!("{}_{}.case", id.0, stats.minimized)));
+        state.corpus().get(id)?.borrow_mut().add_metadata(stats);
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
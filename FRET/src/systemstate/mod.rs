@@ -1,5 +1,4 @@
 //! systemstate referes to the State of a FreeRTOS fuzzing target
-use std::collections::hash_map::DefaultHasher;
 use std::fmt;
 use hashbrown::HashSet;
 use libafl_bolts::HasRefCnt;
@@ -7,16 +6,27 @@ use libafl_qemu::GuestAddr;
 use std::hash::Hasher;
 use std::hash::Hash;
 use hashbrown::HashMap;
+use helpers::stable_hasher;
 use serde::{Deserialize, Serialize};
 use itertools::Itertools;
 use std::borrow::Cow;
 
 pub mod helpers;
+pub mod input_view;
 pub mod feedbacks;
 pub mod schedulers;
 pub mod stg;
 pub mod mutational;
 pub mod report;
+pub mod divergence;
+pub mod minimize;
+pub mod checkpoint;
+pub mod cull;
+pub mod prune;
+pub mod snapshot;
+pub mod analysis;
+pub mod replay;
+pub mod replay_script;
 pub mod target_os;  
 
 //============================= Struct definitions
@@ -124,6 +134,9 @@ pub struct AtomicBasicBlock {
     level: u8,
     instance_id: usize,
     instance_name: Option<Cow<'static, str>>,
+    /// Enclosing function of `start` and the offset into it, not part of the identity of the ABB
+    #[serde(default)]
+    symbol: Option<(Cow<'static, str>, GuestAddr)>,
 }
 
 impl PartialEq for AtomicBasicBlock {
@@ -152,7 +165,8 @@ impl fmt::Display for AtomicBasicBlock {
         for end in &self.ends {
             ends_str.push_str(&format!("0x{:#x}, ", end));
         }
-        write!(f, "ABB {} {{ level: {}, start: 0x{:#x}, ends: [{}]}}", &self.instance_name.as_ref().unwrap_or(&Cow::Owned("".to_owned())), self.level, self.start, ends_str.trim().trim_matches(','))
+        let start_str = self.get_symbol().unwrap_or_else(|| format!("0x{:#x}", self.start));
+        write!(f, "ABB {} {{ level: {}, start: {}, ends: [{}]}}", &self.instance_name.as_ref().unwrap_or(&Cow::Owned("".to_owned())), self.level, start_str, ends_str.trim().trim_matches(','))
     }
 }
 impl fmt::Debug for AtomicBasicBlock {
@@ -161,7 +175,8 @@ impl fmt::Debug for AtomicBasicBlock {
         for end in &self.ends {
             ends_str.push_str(&format!("{:#x}, ", end));
         }
-        write!(f, "ABB {} {{ level: {}, start: 0x{:#x}, ends: [{}]}}", &self.instance_name.as_ref().unwrap_or(&Cow::Owned("".to_owned())), self.level, self.start, ends_str.trim().trim_matches(','))
+        let start_str = self.get_symbol().unwrap_or_else(|| format!("0x{:#x}", self.start));
+        write!(f, "ABB {} {{ level: {}, start: {}, ends: [{}]}}", &self.instance_name.as_ref().unwrap_or(&Cow::Owned("".to_owned())), self.level, start_str, ends_str.trim().trim_matches(','))
     }
 }
 
@@ -181,14 +196,14 @@ impl Ord for AtomicBasicBlock {
             let end1 = if self.ends.len() == 1 { *self.ends.iter().next().unwrap() as u64 } else {
                 let mut temp = self.ends.iter().collect::<Vec<_>>().into_iter().collect::<Vec<&GuestAddr>>();
                 temp.sort_unstable();
-                let mut h = DefaultHasher::new();
+                let mut h = stable_hasher();
                 temp.hash(&mut h);
                 h.finish()
             };
             let end2 = if other.ends.len() == 1 { *self.ends.iter().next().unwrap() as u64 } else {
                 let mut temp = other.ends.iter().collect::<Vec<_>>().into_iter().collect::<Vec<&GuestAddr>>();
                 temp.sort_unstable();
-                let mut h = DefaultHasher::new();
+                let mut h = stable_hasher();
                 temp.hash(&mut h);
                 h.finish()
             };
@@ -202,7 +217,7 @@ impl Ord for AtomicBasicBlock {
 
 impl AtomicBasicBlock {
     pub fn get_hash(&self) -> u64 {
-        let mut s = DefaultHasher::new();
+        let mut s = stable_hasher();
         self.hash(&mut s);
         s.finish()
     }
@@ -214,6 +229,25 @@ impl AtomicBasicBlock {
     pub fn get_start(&self) -> GuestAddr {
         self.start
     }
+
+    pub fn get_ends(&self) -> &HashSet<GuestAddr> {
+        &self.ends
+    }
+
+    /// Distinguishes the executions of an ABB within one trace
+    pub fn get_instance_id(&self) -> usize {
+        self.instance_id
+    }
+
+    /// 0 for tasks, 1 for API calls, 2 for ISRs
+    pub fn get_level(&self) -> u8 {
+        self.level
+    }
+
+    /// Start address as `symbol+0xoff`, if the enclosing function is known
+    pub fn get_symbol(&self) -> Option<String> {
+        self.symbol.as_ref().map(|(name, off)| format!("{}+{:#x}", name, off))
+    }
 }
 
 
@@ -232,6 +266,15 @@ pub struct RTOSJob {
     pub exec_ticks: u64,
     pub ticks_per_abb: Vec<u64>,
     pub abbs: Vec<AtomicBasicBlock>,
+    /// Number of times the job was displaced by another task or an ISR while released
+    #[serde(default)]
+    pub preemption_count: u32,
+    /// Ticks between release and response in which the job was ready but not running
+    #[serde(default)]
+    pub preempted_ticks: u64,
+    /// The response was inferred from the states instead of recorded by trigger_job_done, see JOB_DONE_APIS
+    #[serde(default)]
+    pub inferred: bool,
     hash_cache: u64
 }
 
@@ -249,7 +292,7 @@ impl Hash for RTOSJob {
 impl RTOSJob {
     pub fn get_hash(&mut self) -> u64 {
         if self.hash_cache == 0 {
-            let mut s = DefaultHasher::new();
+            let mut s = stable_hasher();
             self.hash(&mut s);
             self.hash_cache = s.finish();
         }
@@ -257,7 +300,7 @@ impl RTOSJob {
     }
     pub fn get_hash_cached(&self) -> u64 {
         if self.hash_cache == 0 {
-            let mut s = DefaultHasher::new();
+            let mut s = stable_hasher();
             self.hash(&mut s);
             s.finish()
         } else {
@@ -279,6 +322,8 @@ pub struct RTOSTask {
     pub woet_per_abb: Vec<u64>,
     pub abbs: Vec<AtomicBasicBlock>,
     pub wort_ticks: u64,
+    #[serde(default)]
+    pub max_preemption_count: u32,
     hash_cache: u64
 }
 
@@ -297,7 +342,7 @@ impl RTOSTask {
     /// Returns the hash value for the task, computing it if not cached.
     pub fn get_hash(&mut self) -> u64 {
         if self.hash_cache == 0 {
-            let mut s = DefaultHasher::new();
+            let mut s = stable_hasher();
             self.hash(&mut s);
             self.hash_cache = s.finish();
         }
@@ -306,14 +351,15 @@ impl RTOSTask {
     /// Returns the cached hash value for the task.
     pub fn get_hash_cached(&self) -> u64 {
         if self.hash_cache == 0 {
-            let mut s = DefaultHasher::new();
+            let mut s = stable_hasher();
             self.hash(&mut s);
             s.finish()
         } else {
             self.hash_cache
         }
     }
-    /// Update WOET (time, inputs) and WORT (time only) if the new instance is better
+    /// Update WOET (time, inputs), WORT (time only) and the maximum preemption count if the new instance is better
+    /// Returns whether the WOET or WORT changed, a higher preemption count alone is no improvement
     pub fn try_update(&mut self, other: &RTOSJob) -> bool {
         assert_eq!(self.get_hash(), other.get_hash_cached());
         let mut ret = false;
@@ -327,6 +373,9 @@ impl RTOSTask {
             self.wort_ticks = other.response_time();
             ret |= true;
         }
+        if other.preemption_count > self.max_preemption_count {
+            self.max_preemption_count = other.preemption_count;
+        }
         ret
     }
     /// Creates a RTOSTask instance from a given RTOSJob instance.
@@ -339,31 +388,115 @@ impl RTOSTask {
             woet_per_abb: input.ticks_per_abb.clone(),
             abbs: input.abbs.clone(),
             wort_ticks: input.response_time(),
+            max_preemption_count: input.preemption_count,
             hash_cache: c
         }
     }
     /// Maps bytes onto a given RTOSJob instance, returning the differences.
     pub fn map_bytes_onto(&self, input: &RTOSJob, offset: Option<u32>) -> Vec<(u32, u8)> {
-        if input.mem_reads.len() == 0 {
-            return vec![];
-        }
-        let ret = input
-            .mem_reads
-            .iter()
-            .take(self.woet_bytes.len())
-            .enumerate()
-            .filter_map(|(idx, (addr, oldbyte))| {
-                if self.woet_bytes[idx] != *oldbyte {
-                    Some((*addr - offset.unwrap_or_default(), self.woet_bytes[idx]))
-                } else {
-                    None
-                }
-            })
+        let ret = self.propose_bytes(input, offset)
+            .into_iter()
+            .zip(input.mem_reads.iter())
+            .filter(|((_, new), (_, old))| new != old)
+            .map(|(x, _)| x)
             .collect();
         // eprintln!("Mapped: {:?}", ret);
         ret
     }
+    /// The worst bytes at every address read by a given RTOSJob instance, including those it already reads.
+    pub fn propose_bytes(&self, input: &RTOSJob, offset: Option<u32>) -> Vec<(u32, u8)> {
+        input
+            .mem_reads
+            .iter()
+            .zip(self.woet_bytes.iter())
+            .map(|((addr, _), byte)| (*addr - offset.unwrap_or_default(), *byte))
+            .collect()
+    }
+}
+
+/// Bytes a job proposes for the input, see [`RTOSTask::propose_bytes`], with the WOET gain of the job (worst.woet_ticks - job.exec_ticks)
+#[derive(Debug, Clone, Default)]
+pub struct SnippetPatch {
+    pub bytes: Vec<(u32, u8)>,
+    pub gain: u64,
+}
+
+impl SnippetPatch {
+    pub fn new(worst: &RTOSTask, job: &RTOSJob, offset: Option<u32>) -> Self {
+        Self { bytes: worst.propose_bytes(job, offset), gain: worst.woet_ticks.saturating_sub(job.exec_ticks) }
+    }
+}
+
+/// Merges the patches of several jobs into single byte writes, skipping addresses at or beyond `max_len`.
+/// Where jobs read overlapping addresses the job with the larger gain keeps all of its bytes, or the one with the smaller gain if not `prefer_gain`.
+/// Returns the writes by address and the number of addresses at which the patches disagree.
+pub fn merge_snippet_patches(patches: &[SnippetPatch], max_len: usize, prefer_gain: bool) -> (Vec<(u32, u8)>, usize) {
+    let mut order: Vec<&SnippetPatch> = patches.iter().collect();
+    // stable, so jobs of equal gain keep their order in the trace
+    order.sort_by(|a, b| if prefer_gain { b.gain.cmp(&a.gain) } else { a.gain.cmp(&b.gain) });
+    let mut merged: HashMap<u32, u8> = HashMap::new();
+    let mut conflicts = 0;
+    for patch in order {
+        for (addr, byte) in patch.bytes.iter().filter(|x| (x.0 as usize) < max_len) {
+            match merged.get(addr) {
+                Some(b) if b != byte => conflicts += 1,
+                Some(_) => {},
+                None => { merged.insert(*addr, *byte); },
+            }
+        }
+    }
+    (merged.into_iter().sorted_by_key(|x| x.0).collect(), conflicts)
 }
 
 
 // ============================= Per testcase metadata
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A job reading one byte at each of `addrs` and the worst known job of its task, which read `worst_byte` everywhere
+    fn job_pair(addrs: &[u32], exec_ticks: u64, woet_ticks: u64, worst_byte: u8) -> (RTOSTask, RTOSJob) {
+        let job = RTOSJob { mem_reads: addrs.iter().map(|x| (*x, 0)).collect(), exec_ticks, ..Default::default() };
+        let worst = RTOSTask { woet_bytes: vec![worst_byte; addrs.len()], woet_ticks, ..Default::default() };
+        (worst, job)
+    }
+
+    fn patch(addrs: &[u32], exec_ticks: u64, woet_ticks: u64, worst_byte: u8) -> SnippetPatch {
+        let (worst, job) = job_pair(addrs, exec_ticks, woet_ticks, worst_byte);
+        SnippetPatch::new(&worst, &job, Some(0x10))
+    }
+
+    #[test]
+    fn patch_gain_and_offset() {
+        let p = patch(&[0x10, 0x12], 40, 100, 7);
+        assert_eq!((p.bytes, p.gain), (vec![(0, 7), (2, 7)], 60));
+        // a job slower than the worst known one has nothing to gain
+        assert_eq!(patch(&[0x10], 120, 100, 7).gain, 0);
+    }
+
+    #[test]
+    fn overlapping_patches_resolved_by_gain() {
+        let larger = patch(&[0x10, 0x11, 0x12], 40, 100, 1);
+        let smaller = patch(&[0x12, 0x13, 0x14], 40, 50, 2);
+        // independent of the order of the jobs in the trace
+        for patches in [vec![larger.clone(), smaller.clone()], vec![smaller.clone(), larger.clone()]] {
+            assert_eq!(merge_snippet_patches(&patches, 16, true), (vec![(0, 1), (1, 1), (2, 1), (3, 2), (4, 2)], 1));
+            assert_eq!(merge_snippet_patches(&patches, 16, false), (vec![(0, 1), (1, 1), (2, 2), (3, 2), (4, 2)], 1));
+        }
+    }
+
+    #[test]
+    fn merge_edge_cases() {
+        // agreeing bytes are no conflict
+        let (writes, conflicts) = merge_snippet_patches(&[patch(&[0x10, 0x11], 0, 10, 3), patch(&[0x11], 0, 20, 3)], 16, true);
+        assert_eq!((writes, conflicts), (vec![(0, 3), (1, 3)], 0));
+        // jobs of equal gain keep their order in the trace
+        let (writes, conflicts) = merge_snippet_patches(&[patch(&[0x10], 0, 10, 4), patch(&[0x10], 0, 10, 5)], 16, true);
+        assert_eq!((writes, conflicts), (vec![(0, 4)], 1));
+        // addresses beyond the maximum input size are skipped, not counted as conflicts
+        let (writes, conflicts) = merge_snippet_patches(&[patch(&[0x10, 0x14], 0, 10, 1), patch(&[0x14], 0, 20, 2)], 4, true);
+        assert_eq!((writes, conflicts), (vec![(0, 1)], 0));
+        assert_eq!(merge_snippet_patches(&[], 4, true), (vec![], 0));
+    }
+}
@@ -4,7 +4,7 @@
 use core::marker::PhantomData;
 use std::cmp::{max, min};
 
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use libafl_bolts::{rands::{
     random_seed, Rand, StdRand
 }, Named};
@@ -13,13 +13,13 @@ use libafl::{
 };
 use libafl::prelude::State;
 use petgraph::{graph::NodeIndex, graph::{self, DiGraph}};
-use crate::{time::clock::{IcHist, QEMU_ISNS_PER_USEC}, fuzzer::{DO_NUM_INTERRUPT, FIRST_INT, MAX_NUM_INTERRUPT}, systemstate::{stg::{STGFeedbackState, STGNodeMetadata}, CaptureEvent, ExecInterval}};
+use crate::{time::clock::IcHist, fuzzer::{FIRST_INT, MAX_INPUT_SIZE}, systemstate::{helpers::ticks_from_us, input_view::max_num_interrupt, stg::{STGFeedbackState, STGNodeMetadata}, CaptureEvent, ExecInterval}};
 use libafl::state::HasCurrentTestcase;
 use std::borrow::Cow;
 
 use simple_moving_average::SMA;
 
-use super::{helpers::{input_bytes_to_interrupt_times, interrupt_times_to_input_bytes}, stg::{STGEdge, STGNode}, target_os::TargetSystem, RTOSJob};
+use super::{input_view::{bytes_part_mut, bytes_part_or_insert, interrupt_times, set_interrupt_times, set_task_phases, task_phase_bounds, task_phases}, stg::{STGEdge, STGNode}, target_os::TargetSystem, merge_snippet_patches, RTOSJob, SnippetPatch};
 
 // pub static mut MINIMUM_INTER_ARRIVAL_TIME : u32 = 1000 /*us*/ * QEMU_ISNS_PER_USEC; 
 // one isn per 2**4 ns
@@ -61,7 +61,7 @@ where
     let mut new = false;
     let mut new_interrupt_times = Vec::new();
     for (num,&interrupt_time) in interrupt_ticks.iter().enumerate() {
-        let lower_bound = if num==0 {FIRST_INT} else {interrupt_ticks[num-1].saturating_add((config.1 as f32 * QEMU_ISNS_PER_USEC) as u32)};
+        let lower_bound = if num==0 {FIRST_INT} else {interrupt_ticks[num-1].saturating_add(ticks_from_us(config.1 as f64))};
         let next = if interrupt_ticks.len()>num+1 {interrupt_ticks[num+1]} else {u32::MAX};
         for exec_interval in meta.intervals().iter().filter(|x| x.start_tick >= lower_bound as u64 && x.start_tick < next as u64) {
             if !(exec_interval.start_capture.0==CaptureEvent::ISRStart) {  // shortcut to skip interrupt handers without node lookup
@@ -85,12 +85,117 @@ where
     if new {Some(new_interrupt_times)} else {None}
 }
 
+/// Moves one interrupt into an interval during which `task` is delayed, since only then the interrupt can release it and change the schedule.
+/// Interrupt handlers and intervals which are already interrupted are skipped. Returns None if there is no such interval.
+pub fn try_wake_delayed_task<SYS>(interrupt_ticks : &[u32], fbs: &STGFeedbackState<SYS>, meta: &STGNodeMetadata, task: &str, config: (usize, u32), rand: &mut StdRand) -> Option<Vec<u32>>
+where
+    SYS: TargetSystem,
+{
+    let candidates : Vec<&ExecInterval> = meta.intervals().iter()
+        .filter(|x| x.start_capture.0 != CaptureEvent::ISRStart && x.end_tick > FIRST_INT as u64)
+        .filter(|x| !interrupt_ticks.iter().any(|t| (x.start_tick..x.end_tick).contains(&(*t as u64))))
+        .filter(|x| fbs.systemstate_index.get(&x.start_state).map_or(false, |s| s.get_delay_list().iter().any(|t| t.task_name() == task)))
+        .collect();
+    let target = rand.choose(candidates)?;
+    let new_time : u32 = rand.between(max(target.start_tick, FIRST_INT as u64) as usize, target.end_tick as usize).try_into().ok()?;
+    let mut ret = interrupt_ticks.to_vec();
+    if !ret.is_empty() {
+        ret.remove(rand.between(0, ret.len()-1));
+    }
+    // keep the minimum inter-arrival time to the new interrupt
+    let distance = ticks_from_us(config.1 as f64);
+    ret.retain(|t| t.abs_diff(new_time) >= distance);
+    ret.push(new_time);
+    ret.sort_unstable();
+    Some(ret)
+}
+
+/// Drops interrupts from the sorted `times` until no window of `window` ticks holds more than `max_burst` of them.
+/// Earlier interrupts are kept, returns the remaining times.
+pub fn enforce_burst_limit(times: &[u32], max_burst: usize, window: u32) -> Vec<u32> {
+    let mut sorted = times.to_vec();
+    sorted.sort_unstable();
+    let mut kept: Vec<u32> = Vec::with_capacity(sorted.len());
+    for t in sorted {
+        // kept interrupts within (t - window, t]
+        let in_window = kept.iter().rev().take_while(|x| t - **x < window).count();
+        if in_window < max_burst {
+            kept.push(t);
+        }
+    }
+    kept
+}
+
+/// Smoothing factor of the per-source success rate
+const ISR_SOURCE_EMA_ALPHA: f32 = 0.05;
+/// Minimum probability to select any interrupt source, so no source starves
+const ISR_SOURCE_MIN_PROB: f32 = 0.05;
+
+/// Effectiveness of mutating one interrupt source
+#[derive(Clone, Debug)]
+pub struct IsrSourceStats {
+    pub attempts: u64,
+    pub reruns: u64,
+    pub interesting_reruns: u64,
+    /// exponential moving average of reruns leading to a corpus insertion, starts optimistic
+    pub success_ema: f32,
+}
+
+impl Default for IsrSourceStats {
+    fn default() -> Self {
+        Self { attempts: 0, reruns: 0, interesting_reruns: 0, success_ema: 1.0 }
+    }
+}
+
+impl IsrSourceStats {
+    pub fn add_rerun(&mut self, interesting: bool) {
+        self.reruns += 1;
+        if interesting {self.interesting_reruns += 1;}
+        self.success_ema = (1.0 - ISR_SOURCE_EMA_ALPHA) * self.success_ema + ISR_SOURCE_EMA_ALPHA * if interesting {1.0} else {0.0};
+    }
+}
+
+/// Selection probability of each source: a floor of [`ISR_SOURCE_MIN_PROB`] each, the rest proportional to the success rates
+pub fn isr_source_probabilities(stats: &[IsrSourceStats]) -> Vec<f32> {
+    if stats.is_empty() {return Vec::new();}
+    let n = stats.len() as f32;
+    let floor = f32::min(ISR_SOURCE_MIN_PROB, 1.0 / n);
+    let sum : f32 = stats.iter().map(|x| x.success_ema).sum();
+    stats.iter().map(|x| {
+        let share = if sum > 0.0 {x.success_ema / sum} else {1.0 / n};
+        floor + (1.0 - n * floor) * share
+    }).collect()
+}
+
+/// Picks a source index given a uniform sample `r` in [0,1)
+pub fn choose_isr_source(stats: &[IsrSourceStats], r: f32) -> usize {
+    let mut acc = 0.0;
+    for (i, p) in isr_source_probabilities(stats).iter().enumerate() {
+        acc += p;
+        if r < acc {return i;}
+    }
+    stats.len().saturating_sub(1)
+}
+
+/// Names of the mutation strategies of the [`InterruptShiftStage`], indices into its strategy stats
+const INTERRUPT_STRATEGIES: [&str; 4] = ["random", "new_branch", "alternative", "wake"];
+
 /// The default mutational stage
 #[derive(Clone, Debug)]
 pub struct InterruptShiftStage<E, EM, Z, SYS> {
     #[allow(clippy::type_complexity)]
     phantom: PhantomData<(E, EM, Z, SYS)>,
     interrup_config: Vec<(usize,u32)>,
+    /// per entry of interrup_config
+    source_stats: Vec<IsrSourceStats>,
+    /// per entry of interrup_config, (max_burst, window in us)
+    burst_limits: Vec<Option<(usize,u32)>>,
+    /// per entry of interrup_config, the task released by its handler
+    isr_wakes: Vec<Option<String>>,
+    /// percentage of mutations trying [`try_wake_delayed_task`] on sources with a task
+    wake_probability: usize,
+    /// (reruns, interesting reruns) per entry of [`INTERRUPT_STRATEGIES`]
+    strategy_stats: [(u64, u64); 4],
     success: simple_moving_average::SingleSumSMA<f32, f32, 50>
 }
 
@@ -102,7 +207,20 @@ where
     Z::State: MaybeHasClientPerfMonitor + HasCorpus + HasRand,
 {
     pub fn new(config : &Vec<(usize,u32)>) -> Self {
-        Self { phantom: PhantomData, interrup_config: config.clone(), success: simple_moving_average::SingleSumSMA::from_zero(1.0) }
+        Self { phantom: PhantomData, interrup_config: config.clone(), source_stats: vec![IsrSourceStats::default(); config.len()], burst_limits: vec![None; config.len()], isr_wakes: vec![None; config.len()], wake_probability: 0, strategy_stats: [(0, 0); 4], success: simple_moving_average::SingleSumSMA::from_zero(1.0) }
+    }
+
+    /// Limit the interrupts of sources to at most max_burst within a window, given as source -> (max_burst, window in us)
+    pub fn with_burst_limits(mut self, limits: &HashMap<usize,(usize,u32)>) -> Self {
+        self.burst_limits = self.interrup_config.iter().map(|c| limits.get(&c.0).copied()).collect();
+        self
+    }
+
+    /// Move interrupts to where the task released by their handler is delayed in `probability` percent of the mutations, given as source -> task
+    pub fn with_isr_wakes(mut self, wakes: &HashMap<usize,String>, probability: usize) -> Self {
+        self.isr_wakes = self.interrup_config.iter().map(|c| wakes.get(&c.0).cloned()).collect();
+        self.wake_probability = probability.min(100);
+        self
     }
 }
 
@@ -123,13 +241,19 @@ where
     SYS: TargetSystem,
 {
     fn report_stats(&self, state: &mut <InterruptShiftStage<E, EM, Z, SYS> as libafl::state::UsesState>::State, manager: &mut EM) {
+        let per_source = self.interrup_config.iter().zip(self.source_stats.iter()).map(|(c,s)|
+            format!("isr_{}: {}/{}/{} {:.1}%", c.0, s.interesting_reruns, s.reruns, s.attempts, s.success_ema * 100.0)
+        ).collect::<Vec<_>>().join(", ");
+        let per_strategy = INTERRUPT_STRATEGIES.iter().zip(self.strategy_stats.iter()).map(|(n,(reruns,interesting))|
+            format!("{}: {}/{}", n, interesting, reruns)
+        ).collect::<Vec<_>>().join(", ");
         unsafe {
             let _ = manager.fire(
                 state,
                 Event::UpdateUserStats {
                     name: Cow::from("InterruptShiftStage"),
                     value: UserStats::new(
-                        UserStatsValue::String(Cow::from(format!("{} -> {}/{} {:.1}% ", num_stage_execs, sum_interesting_reruns, sum_reruns, sum_interesting_reruns as f32 * 100.0 / sum_reruns as f32))),
+                        UserStatsValue::String(Cow::from(format!("{} -> {}/{} {:.1}% [{}] [{}]", num_stage_execs, sum_interesting_reruns, sum_reruns, sum_interesting_reruns as f32 * 100.0 / sum_reruns as f32, per_source, per_strategy))),
                         AggregatorOps::None,
                     ),
                     phantom: PhantomData,
@@ -169,48 +293,49 @@ where
         // Try many times to find a mutation that is not already in the corpus
         let loopbound = max(1, (self.success.get_average()*100.0) as usize);
         for _ in 0..loopbound {
-            // Choose which isr to mutate
-            let interrup_config = match myrand.choose(&self.interrup_config) {
-                Some(s) => s,
-                Option::None => {
-                    self.report_stats(state, manager);
-                    return Ok(())
-                }
-            };
-            let name = format!("isr_{}_times", interrup_config.0);
+            // Choose which isr to mutate, weighted by how often each source produced new corpus entries
+            let source = choose_isr_source(&self.source_stats, (myrand.next() >> 40) as f32 / (1u64 << 24) as f32);
+            let interrup_config = &self.interrup_config[source];
+            self.source_stats[source].attempts += 1;
             // manager.log(state, LogSeverity::Info, format!("Mutation {}/{}", loopbound, loopcount))?;
 
             let curr_case : std::cell::Ref<Testcase<MultipartInput<_>>> = state.current_testcase()?;
             let curr_input = curr_case.input().as_ref().unwrap();
 
             let mut new_input : MultipartInput<I> = curr_input.clone();
-            let new_interrupt_part : &mut I = if new_input.parts_by_name(&name).next().is_some() {
-                new_input.parts_by_name_mut(&name).next().unwrap()
-            } else {
-                new_input.add_part(String::from(&name), I::default()); new_input.parts_by_name_mut(&name).next().unwrap()
-            }.1;
-            let old_interrupt_times = input_bytes_to_interrupt_times(new_interrupt_part.bytes(), *interrup_config);
-            let mut new_interrupt_times = Vec::with_capacity(MAX_NUM_INTERRUPT);
+            let old_interrupt_times = interrupt_times(&new_input, *interrup_config).unwrap_or_default();
+            let mut new_interrupt_times = Vec::with_capacity(max_num_interrupt());
             let mut do_rerun = false;
+            let mut strategy = 0; // index into INTERRUPT_STRATEGIES
             // if state.rand_mut().between(1, 100) <= 50 // only attempt the mutation half of the time
             {
                 #[cfg(feature = "mutate_stg")]
                 {
                     let metadata = state.metadata_map();
                     let maxtick = {metadata.get::<IcHist>().unwrap().1.0};
-                    drop(new_interrupt_part.drain(..).collect::<Vec<u8>>());
                     {
                         let choice = myrand.between(1,100);
-                        if choice <= 25 || *old_interrupt_times.get(0).unwrap_or(&u32::MAX) as u64 > maxtick {  // 0.5*0.25 = 12.5% of the time fully randomize all interrupts
+                        let wake_task = self.isr_wakes[source].as_ref().filter(|_| myrand.between(1,100) <= self.wake_probability);
+                        if let Some(task) = wake_task {
+                            strategy = 3;
+                            if let (Ok(feedbackstate), Some(meta)) = (state.metadata::<STGFeedbackState<SYS>>(), curr_case.metadata_map().get::<STGNodeMetadata>()) {
+                                if let Some(t) = try_wake_delayed_task(&old_interrupt_times, feedbackstate, meta, task, *interrup_config, &mut myrand) {
+                                    do_rerun = true;
+                                    new_interrupt_times = t;
+                                }
+                            }
+                        }
+                        else if choice <= 25 || *old_interrupt_times.get(0).unwrap_or(&u32::MAX) as u64 > maxtick {  // 0.5*0.25 = 12.5% of the time fully randomize all interrupts
                             do_rerun = true;
                             let hist = metadata.get::<IcHist>().unwrap();
                             let maxtick : u64 = hist.1.0;
                             // let maxtick : u64 = (_input.exec_time().expect("No duration found").as_nanos() >> 4).try_into().unwrap();
-                            for _ in 0..myrand.between(0,min(MAX_NUM_INTERRUPT, (maxtick as usize * 3) / (interrup_config.1 as usize * QEMU_ISNS_PER_USEC as usize * 2))) {
+                            for _ in 0..myrand.between(0,min(max_num_interrupt(), (maxtick as usize * 3) / (ticks_from_us(interrup_config.1 as f64) as usize * 2))) {
                                 new_interrupt_times.push(myrand.between(0, min(maxtick, u32::MAX as u64) as usize).try_into().expect("ticks > u32"));
                             }
                         }
                         else if choice <= 75 { // 0.5 * 0.25 = 12.5% of cases
+                            strategy = 1;
                             let feedbackstate = match state
                                 .metadata::<STGFeedbackState<SYS>>() {
                                     Ok(s) => s,
@@ -274,6 +399,7 @@ where
                         //     }
                         }
                         else {    // old version of the alternative search
+                            strategy = 2;
                             new_interrupt_times = old_interrupt_times.clone();
                             let tmp = curr_case.metadata_map().get::<STGNodeMetadata>();
                             if tmp.is_some() {
@@ -302,10 +428,10 @@ where
                                     let mut ub : u32 = trace.intervals()[trace.intervals().len()-1].end_tick.try_into().expect("ticks > u32");
                                     if i > 0 {
                                         // use the new times, because changes to preceding timings are not accounted for yet
-                                        lb = u32::saturating_add(new_interrupt_times[i-1], (interrup_config.1 as f32 * QEMU_ISNS_PER_USEC) as u32); 
+                                        lb = u32::saturating_add(new_interrupt_times[i-1], ticks_from_us(interrup_config.1 as f64)); 
                                     }
                                     if i < old_interrupt_times.len()-1 {
-                                        ub = u32::saturating_sub(new_interrupt_times[i+1], (interrup_config.1 as f32 * QEMU_ISNS_PER_USEC) as u32);
+                                        ub = u32::saturating_sub(new_interrupt_times[i+1], ticks_from_us(interrup_config.1 as f64));
                                     }
                                     // get old hit and handler
                                     let old_hit = marks.iter().filter(
@@ -370,7 +496,6 @@ where
                                 //     numbers[i] = numbers[i]-start;
                                 //     start = tmp;
                                 // }
-                                new_interrupt_part.extend(&interrupt_times_to_input_bytes(&new_interrupt_times));
                             }
                         }
                     }
@@ -381,18 +506,27 @@ where
                         do_rerun = true;
                         let metadata = state.metadata_map();
                         let maxtick = {metadata.get::<IcHist>().unwrap().1.0};
-                        new_interrupt_times = Vec::with_capacity(MAX_NUM_INTERRUPT);
-                        for i in 0..myrand.between(0,min(MAX_NUM_INTERRUPT, (maxtick as usize * 3) / (interrup_config.1 as usize * QEMU_ISNS_PER_USEC as usize * 2))) {
+                        new_interrupt_times = Vec::with_capacity(max_num_interrupt());
+                        for i in 0..myrand.between(0,min(max_num_interrupt(), (maxtick as usize * 3) / (ticks_from_us(interrup_config.1 as f64) as usize * 2))) {
                             new_interrupt_times.push(myrand.between(0, min(maxtick, u32::MAX as u64) as usize).try_into().expect("ticks > u32"));
                         }
                     }
                 }
-                new_interrupt_part.extend(&interrupt_times_to_input_bytes(&new_interrupt_times));
+                // the hardware can not produce denser bursts, no matter which branch generated the times
+                if let Some((max_burst, window)) = self.burst_limits[source] {
+                    let limited = enforce_burst_limit(&new_interrupt_times, max_burst, ticks_from_us(window as f64));
+                    do_rerun &= limited != old_interrupt_times;
+                    new_interrupt_times = limited;
+                }
+                set_interrupt_times(&mut new_input, interrup_config.0, &new_interrupt_times);
             }
             drop(curr_case);
             if do_rerun {
                 rerun_count+=1;
                 let (_, corpus_idx) = fuzzer.evaluate_input(state, executor, manager, new_input)?;
+                self.source_stats[source].add_rerun(corpus_idx.is_some());
+                self.strategy_stats[strategy].0 += 1;
+                if corpus_idx.is_some() {self.strategy_stats[strategy].1 += 1;}
                 if corpus_idx.is_some() { unsafe{interesting_rerun_count+=1;}} else
                 if corpus_idx.is_none() && loopbound<=0 { break;}
             } else {if loopbound<=0 {break;}}
@@ -426,14 +560,52 @@ where
 }
 
 
-pub fn try_worst_snippets<SYS>(bytes : &[u8], fbs: &STGFeedbackState<SYS>, meta: &STGNodeMetadata) -> Option<Vec<u8>> 
+/// Overwrites the input bytes read by each ABB of the trace with the values of the worst known execution of that ABB.
+/// The worst execution is taken from the STG edge leading into the ABB's node, if several ABBs read the same address the one with the larger WOET wins.
+/// Returns None if no byte was changed.
+pub fn try_worst_snippets<SYS>(bytes : &[u8], fbs: &STGFeedbackState<SYS>, meta: &STGNodeMetadata, input_addr: u32) -> Option<Vec<u8>> 
 where
     SYS: TargetSystem,
 {
+    let mut snippets : HashMap<u32, (u64, u8)> = HashMap::new(); // addr -> (woet, value)
+    let mut last_node : Option<NodeIndex> = None;
+    for interval in meta.intervals().iter() {
+        if interval.abb.is_none() {
+            last_node = None;
+            continue;
+        }
+        let node = match fbs.state_abb_hash_index.get(&interval.get_hash_index()) {
+            Some(n) => *n,
+            Option::None => {last_node = None; continue;}
+        };
+        let worst = match last_node {
+            Some(prev) => fbs.graph.find_edge(prev, node).and_then(|e| fbs.graph[e].worst.as_ref()),
+            // without a predecessor use the worst of all incoming edges
+            Option::None => fbs.graph.edges_directed(node, petgraph::Direction::Incoming)
+                .filter_map(|e| e.weight().worst.as_ref())
+                .max_by_key(|w| w.0),
+        };
+        last_node = Some(node);
+        if let Some((woet, reads)) = worst {
+            let mut seen = HashSet::new();
+            for (addr, value) in reads {
+                // only the first read of an address within the abb determines its value
+                if !seen.insert(*addr) {continue;}
+                match snippets.get(addr) {
+                    Some((w, _)) if w >= woet => {},
+                    _ => {snippets.insert(*addr, (*woet, *value));},
+                }
+            }
+        }
+    }
+    let mut ret = bytes.to_vec();
     let mut new = false;
-    let mut ret = Vec::new();
-    for (num,interval) in meta.intervals().iter().enumerate() {
-        todo!();
+    for (addr, (_, value)) in snippets {
+        let offset = addr.wrapping_sub(input_addr) as usize;
+        if offset < ret.len() && ret[offset] != value {
+            ret[offset] = value;
+            new = true;
+        }
     }
     if new {Some(ret)} else {None}
 }
@@ -448,7 +620,9 @@ static mut num_snippet_success : u64 = 0;
 pub struct STGSnippetStage<E, EM, Z, SYS> {
     #[allow(clippy::type_complexity)]
     phantom: PhantomData<(E, EM, Z, SYS)>,
-    input_addr: u32
+    input_addr: u32,
+    /// On conflicting jobs also evaluate the input in which the job with the smaller WOET gain wins
+    try_both_resolutions: bool,
 }
 
 impl<E, EM, Z, SYS> STGSnippetStage<E, EM, Z, SYS>
@@ -460,7 +634,14 @@ where
     SYS: TargetSystem,
 {
     pub fn new(input_addr: u32) -> Self {
-        Self { phantom: PhantomData, input_addr }
+        Self { phantom: PhantomData, input_addr, try_both_resolutions: false }
+    }
+
+    /// Evaluate both resolutions when the snippets of two jobs overlap, instead of only the one favoring the larger WOET gain
+    #[must_use]
+    pub fn with_both_resolutions(mut self, try_both_resolutions: bool) -> Self {
+        self.try_both_resolutions = try_both_resolutions;
+        self
     }
 }
 
@@ -518,11 +699,12 @@ where
         myrand.set_seed(state.rand_mut().next());
 
         let mut do_rerun = false;
+        let mut alternative = None;
 
         let current_case = state.current_testcase()?;
         let old_input = current_case.input().as_ref().unwrap();
         let mut new_input : MultipartInput<I> = old_input.clone();
-        let new_bytes = new_input.parts_by_name_mut("bytes").next().expect("bytes not found in multipart input").1.bytes_mut();
+        let new_bytes = bytes_part_or_insert(&mut new_input).bytes_mut();
         // dbg!(current_case.metadata_map());
         // eprintln!("Run mutator {}", current_case.metadata_map().get::<STGNodeMetadata>().is_some());
         if let Some(meta) = current_case.metadata_map().get::<STGNodeMetadata>() {
@@ -533,20 +715,37 @@ where
                         panic!("STGfeedbackstate not visible")
                     }
                 };
-            // Maximize all snippets
-            // dbg!(meta.jobs().len());
-            for jobinst in meta.jobs().iter() {
-                match feedbackstate.worst_task_jobs.get(&jobinst.get_hash_cached()) {
-                    Some(worst) => {
-                        let new = worst.map_bytes_onto(jobinst, Some(self.input_addr));
-                        do_rerun |= new.len() > 0;
-                        for (addr, byte) in new {
-                            if (addr as usize) < new_bytes.len() {
-                                new_bytes[addr as usize] = byte;
-                            }
+            if myrand.between(1,100) <= 50 {
+                // Maximize all snippets, jobs reading the same bytes are resolved in favor of the larger WOET gain
+                // dbg!(meta.jobs().len());
+                let patches: Vec<SnippetPatch> = meta.jobs().iter()
+                    .filter_map(|jobinst| feedbackstate.worst_task_jobs.get(&jobinst.get_hash_cached()).map(|worst| SnippetPatch::new(worst, jobinst, Some(self.input_addr))))
+                    .collect();
+                let max_len = new_bytes.len().min(unsafe { MAX_INPUT_SIZE });
+                let (writes, conflicts) = merge_snippet_patches(&patches, max_len, true);
+                if conflicts > 0 && self.try_both_resolutions {
+                    let mut other = old_input.clone();
+                    if let Ok(other_bytes) = bytes_part_mut(&mut other) {
+                        let (other_writes, _) = merge_snippet_patches(&patches, max_len, false);
+                        let mut changed = false;
+                        for (addr, byte) in other_writes {
+                            changed |= other_bytes[addr as usize] != byte;
+                            other_bytes[addr as usize] = byte;
                         }
-                    },
-                    Option::None => {}
+                        if changed {
+                            alternative = Some(other);
+                        }
+                    }
+                }
+                for (addr, byte) in writes {
+                    do_rerun |= new_bytes[addr as usize] != byte;
+                    new_bytes[addr as usize] = byte;
+                }
+            } else {
+                // Maximize per abb
+                if let Some(b) = try_worst_snippets(new_bytes, feedbackstate, meta, self.input_addr) {
+                    new_bytes.copy_from_slice(&b);
+                    do_rerun = true;
                 }
             }
         }
@@ -558,6 +757,11 @@ where
             if corpus_idx.is_some() { unsafe{num_snippet_success+=1};}
             
         }
+        if let Some(other) = alternative {
+            unsafe {num_snippet_rerun+=1;}
+            let (_, corpus_idx) = fuzzer.evaluate_input(state, executor, manager, other)?;
+            if corpus_idx.is_some() { unsafe{num_snippet_success+=1};}
+        }
         self.report_stats(state, manager);
         Ok(())
     }
@@ -580,4 +784,203 @@ where
     SYS: TargetSystem,
 {
     type State = Z::State;
-}
\ No newline at end of file
+}
+
+//======================= Task phase mutator
+
+/// Moves the release phase of one task, see [`crate::runner::TaskPhase`]: either to a random value below its bound,
+/// or by up to a tenth of the bound, wrapping around. Skips if no phases are fuzzed.
+#[derive(Debug, Clone)]
+pub struct TaskPhaseMutator {
+    name: Cow<'static, str>,
+}
+
+impl TaskPhaseMutator {
+    #[must_use]
+    pub fn new() -> Self {
+        Self { name: Cow::from("TaskPhaseMutator") }
+    }
+}
+
+impl Default for TaskPhaseMutator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Named for TaskPhaseMutator {
+    fn name(&self) -> &Cow<'static, str> {
+        &self.name
+    }
+}
+
+impl<I, S> Mutator<MultipartInput<I>, S> for TaskPhaseMutator
+where
+    I: HasMutatorBytes + Default,
+    S: HasRand,
+{
+    fn mutate(&mut self, state: &mut S, input: &mut MultipartInput<I>) -> Result<MutationResult, Error> {
+        let bounds = task_phase_bounds();
+        let Some(n) = std::num::NonZero::new(bounds.len()) else {
+            return Ok(MutationResult::Skipped);
+        };
+        let mut phases = task_phases(input);
+        phases.resize(bounds.len(), 0);
+        let i = state.rand_mut().below(n);
+        let bound = bounds[i];
+        let old = phases[i] % bound;
+        let new = if state.rand_mut().coinflip(0.5) {
+            state.rand_mut().below(std::num::NonZero::new(bound as usize).unwrap()) as u32
+        } else {
+            let step = max(bound / 10, 1) as u64;
+            let delta = state.rand_mut().below(std::num::NonZero::new(2 * step as usize + 1).unwrap()) as u64;
+            ((old as u64 + bound as u64 + delta - step) % bound as u64) as u32
+        };
+        if new == phases[i] {
+            return Ok(MutationResult::Skipped);
+        }
+        phases[i] = new;
+        set_task_phases(input, &phases);
+        Ok(MutationResult::Mutated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systemstate::{target_os::mock::MockSystem, AtomicBasicBlock};
+
+    fn interval(start_state: u64, start: u32) -> ExecInterval {
+        ExecInterval { start_state, abb: Some(AtomicBasicBlock { start: start as _, ..Default::default() }), ..Default::default() }
+    }
+
+    fn worst_edge(woet: u64, reads: &[(u32, u8)]) -> STGEdge {
+        STGEdge { worst: Some((woet, reads.to_vec())), ..Default::default() }
+    }
+
+    /// Three ABBs in a row and the STG nodes of each
+    fn path() -> (STGFeedbackState<MockSystem>, Vec<NodeIndex>, STGNodeMetadata) {
+        let mut fbs = STGFeedbackState::<MockSystem>::default();
+        let intervals = vec![interval(1, 0x100), interval(2, 0x200), interval(3, 0x300)];
+        let nodes: Vec<_> = intervals.iter().map(|i| {
+            let n = fbs.graph.add_node(STGNode::default());
+            fbs.state_abb_hash_index.insert(i.get_hash_index(), n);
+            n
+        }).collect();
+        let meta = STGNodeMetadata::new(nodes.clone(), vec![], vec![], 0, 0, vec![], intervals, vec![]);
+        (fbs, nodes, meta)
+    }
+
+    #[test]
+    fn worst_snippets_follow_the_path() {
+        let (mut fbs, n, meta) = path();
+        let entry = fbs.graph.node_indices().next().unwrap();
+        // without a predecessor the worst incoming edge is used
+        fbs.graph.add_edge(entry, n[0], worst_edge(10, &[(0x1000, 1), (0x2000, 5)]));
+        fbs.graph.add_edge(entry, n[0], worst_edge(4, &[(0x1000, 8)]));
+        fbs.graph.add_edge(n[0], n[1], worst_edge(20, &[(0x1001, 2), (0x1002, 3), (0x1001, 6)]));
+        // not on the path of the trace
+        fbs.graph.add_edge(entry, n[1], worst_edge(100, &[(0x1001, 9)]));
+        // loses the address to the ABB with the larger WOET
+        fbs.graph.add_edge(n[1], n[2], worst_edge(5, &[(0x1002, 7), (0x1003, 4)]));
+
+        let bytes = try_worst_snippets(&[0; 4], &fbs, &meta, 0x1000);
+        assert_eq!(bytes, Some(vec![1, 2, 3, 4]));
+        // nothing left to change
+        assert_eq!(try_worst_snippets(&[1, 2, 3, 4], &fbs, &meta, 0x1000), None);
+    }
+
+    #[test]
+    fn worst_snippets_reject_unknown_paths() {
+        let (mut fbs, n, meta) = path();
+        // no worst times recorded yet
+        fbs.graph.add_edge(n[0], n[1], STGEdge::default());
+        assert_eq!(try_worst_snippets(&[0; 4], &fbs, &meta, 0x1000), None);
+        // reads outside of the input
+        fbs.graph.add_edge(n[1], n[2], worst_edge(5, &[(0x0fff, 1), (0x1004, 1)]));
+        assert_eq!(try_worst_snippets(&[0; 4], &fbs, &meta, 0x1000), None);
+        // nodes of the trace which are not in the graph
+        let unknown = STGNodeMetadata::new(vec![], vec![], vec![], 0, 0, vec![], vec![interval(9, 0x900)], vec![]);
+        assert_eq!(try_worst_snippets(&[0; 4], &STGFeedbackState::<MockSystem>::default(), &unknown, 0x1000), None);
+    }
+
+    /// Most interrupts within any window of `window` ticks
+    fn densest_burst(times: &[u32], window: u32) -> usize {
+        times.iter().map(|t| times.iter().filter(|x| (*t..t.saturating_add(window)).contains(x)).count()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn burst_limit_drops_dense_interrupts() {
+        assert_eq!(enforce_burst_limit(&[], 2, 10), Vec::<u32>::new());
+        assert_eq!(enforce_burst_limit(&[0, 1, 2, 3, 4, 20], 2, 10), vec![0, 1, 20]);
+        // the window is half-open, interrupts exactly one window apart do not share it
+        assert_eq!(enforce_burst_limit(&[0, 5, 10, 15, 20], 2, 10), vec![0, 5, 10, 15, 20]);
+        assert_eq!(enforce_burst_limit(&[30, 0, 9, 10], 1, 10), vec![0, 10, 30]);
+        assert_eq!(enforce_burst_limit(&[5, 5, 5], 2, 1), vec![5, 5]);
+        assert_eq!(enforce_burst_limit(&[1, 2], 0, 10), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn burst_limit_holds_for_random_times() {
+        let mut rand = StdRand::with_seed(7);
+        for _ in 0..200 {
+            let times: Vec<u32> = (0..rand.between(0, 40)).map(|_| rand.between(0, 1000) as u32).collect();
+            let (max_burst, window) = (rand.between(1, 5), rand.between(1, 300) as u32);
+            let kept = enforce_burst_limit(&times, max_burst, window);
+            assert!(densest_burst(&kept, window) <= max_burst, "{:?} {} {}", kept, max_burst, window);
+            // a sorted subsequence of the times, which is left alone if it already keeps the limit
+            let mut sorted = times.clone();
+            sorted.sort_unstable();
+            let mut rest = sorted.iter();
+            assert!(kept.windows(2).all(|x| x[0] <= x[1]) && kept.iter().all(|k| rest.any(|x| x == k)));
+            if densest_burst(&times, window) <= max_burst {
+                assert_eq!(kept, sorted);
+            }
+        }
+    }
+
+    fn source_with_rate(interesting: usize, reruns: usize) -> IsrSourceStats {
+        let mut stats = IsrSourceStats::default();
+        for i in 0..reruns {
+            stats.add_rerun(i < interesting);
+        }
+        stats
+    }
+
+    #[test]
+    fn source_success_rate_is_tracked() {
+        let fresh = IsrSourceStats::default();
+        assert_eq!(fresh.success_ema, 1.0);
+        let stats = source_with_rate(2, 50);
+        assert_eq!((stats.reruns, stats.interesting_reruns), (50, 2));
+        assert!(stats.success_ema < 0.2);
+        let mut recovered = stats.clone();
+        recovered.add_rerun(true);
+        assert!(recovered.success_ema > stats.success_ema);
+    }
+
+    #[test]
+    fn successful_sources_are_preferred() {
+        let stats = vec![source_with_rate(0, 200), source_with_rate(200, 200), IsrSourceStats::default()];
+        let probs = isr_source_probabilities(&stats);
+        assert!((probs.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+        assert!(probs.iter().all(|p| *p >= ISR_SOURCE_MIN_PROB));
+        assert!(probs[1] > probs[0] * 5.0);
+        assert!((probs[1] - probs[2]).abs() < 1e-2);
+        assert_eq!(choose_isr_source(&stats, 0.0), 0);
+        assert_eq!(choose_isr_source(&stats, probs[0] + 0.01), 1);
+        assert_eq!(choose_isr_source(&stats, 0.9999), 2);
+        assert_eq!(choose_isr_source(&stats, 1.0), 2);
+    }
+
+    #[test]
+    fn unsuccessful_sources_are_chosen_uniformly() {
+        let mut stats = vec![IsrSourceStats::default(); 4];
+        for s in stats.iter_mut() {
+            s.success_ema = 0.0;
+        }
+        assert!(isr_source_probabilities(&stats).iter().all(|p| (p - 0.25).abs() < 1e-6));
+        assert!(isr_source_probabilities(&[]).is_empty());
+        assert_eq!(choose_isr_source(&[], 0.5), 0);
+    }
+}
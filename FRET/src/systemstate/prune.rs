@@ -0,0 +1,290 @@
+//! Caps the memory used by the STG, see [`StgPruningStage`].
+
+use core::{marker::PhantomData, time::Duration};
+use std::{borrow::Cow, fs};
+
+use hashbrown::HashSet;
+use libafl::{
+    common::{HasMetadata, HasNamedMetadata}, corpus::{Corpus, CorpusId}, events::{Event, EventFirer}, feedbacks::MapFeedbackMetadata, monitors::{AggregatorOps, UserStats, UserStatsValue}, schedulers::minimizer::TopRatedsMetadata, stages::Stage, state::{HasCorpus, UsesState}, Error
+};
+use libafl_bolts::current_time;
+use petgraph::graph::NodeIndex;
+
+use super::stg::{STGFeedbackState, STGNodeMetadata, STGPruning, STG_MAP_SIZE};
+use super::target_os::TargetSystem;
+
+/// Name of the observer over [`super::stg::stg_map_mut_slice`], which is also the name of the history of its map feedback
+const STG_OBSERVER_NAME: &str = "stg";
+/// Time between two reads of the resident memory
+const RSS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resident memory of this process in bytes, None if it can not be read
+fn resident_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|x| x.starts_with("VmRSS:"))?;
+    let kib = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+/// Translates the history of the STG map feedback, so edges which moved to another index are not reported as new
+fn remap_history<S: HasNamedMetadata>(state: &mut S, pruning: &STGPruning) {
+    let Some(history) = state.named_metadata_map_mut().get_mut::<MapFeedbackMetadata<u16>>(STG_OBSERVER_NAME) else { return };
+    remap_history_map(&mut history.history_map, pruning);
+}
+
+/// Moves each entry of the map history to the new index of its edge, entries of removed edges are dropped
+fn remap_history_map(history: &mut Vec<u16>, pruning: &STGPruning) {
+    let old = std::mem::take(history);
+    *history = vec![0; old.len()];
+    for (from, to) in pruning.edges.iter() {
+        let (from, to) = (from.index() % STG_MAP_SIZE, to.index() % STG_MAP_SIZE);
+        if from < old.len() && to < old.len() {
+            history[to] = history[to].max(old[from]);
+        }
+    }
+}
+
+/// Moves the top rated testcases of the edges to their new indices, those of removed edges are dropped
+fn remap_top_rated(top: &mut TopRatedsMetadata, pruning: &STGPruning) {
+    top.map = top.map.drain().filter_map(|(k, v)| pruning.map_index(k).map(|k| (k, v))).collect();
+}
+
+/// Prunes the [`STGFeedbackState`] once it has more than `max_nodes` nodes or the process more than `max_rss` bytes of resident memory.
+/// Nodes on the path of a top rated testcase and nodes traversed by one of the last `age` traces are kept, see [`STGFeedbackState::prune`].
+/// The [`STGNodeMetadata`] of the corpus, the [`TopRatedsMetadata`] and the history of the STG map feedback are translated to the new indices.
+/// The node cap overrides the age: a graph over it is pruned right away, keeping only the nodes traversed since the last pruning if that was
+/// less than `age` traces ago. The memory cap waits for `age` traces between two prunings, as the resident memory does not shrink right
+/// after a pruning. Does nothing without a cap.
+#[derive(Debug, Clone)]
+pub struct StgPruningStage<E, EM, Z, SYS> {
+    max_nodes: Option<usize>,
+    max_rss: Option<u64>,
+    age: u64,
+    /// Value of [`STGFeedbackState::traces`] at the last pruning
+    last_pruning: u64,
+    last_rss_check: Duration,
+    /// Nodes removed so far, reported as `PrunedNodes`
+    removed: u64,
+    phantom: PhantomData<(E, EM, Z, SYS)>,
+}
+
+impl<E, EM, Z, SYS> StgPruningStage<E, EM, Z, SYS> {
+    pub fn new(max_nodes: Option<usize>, max_rss: Option<u64>, age: u64) -> Self {
+        Self { max_nodes, max_rss, age, last_pruning: 0, last_rss_check: Duration::ZERO, removed: 0, phantom: PhantomData }
+    }
+
+    fn over_cap(&mut self, nodes: usize) -> bool {
+        if self.max_nodes.map_or(false, |x| nodes > x) {
+            return true;
+        }
+        let Some(max_rss) = self.max_rss else { return false };
+        let now = current_time();
+        if now.saturating_sub(self.last_rss_check) < RSS_CHECK_INTERVAL {
+            return false;
+        }
+        self.last_rss_check = now;
+        resident_bytes().map_or(false, |x| x > max_rss)
+    }
+}
+
+impl<E, EM, Z, SYS> UsesState for StgPruningStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+{
+    type State = E::State;
+}
+
+impl<E, EM, Z, SYS> Stage<E, EM, Z> for StgPruningStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+    EM: EventFirer<State = Self::State>,
+    Z: UsesState<State = Self::State>,
+    Self::State: HasCorpus + HasMetadata + HasNamedMetadata,
+    SYS: TargetSystem,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Self::State,
+        manager: &mut EM,
+    ) -> Result<(), Error> {
+        if self.max_nodes.is_none() && self.max_rss.is_none() {
+            return Ok(());
+        }
+        let Some(fbs) = state.metadata_map().get::<STGFeedbackState<SYS>>() else { return Ok(()) };
+        let (nodes, traces) = (fbs.graph.node_count(), fbs.traces());
+        let over_nodes = self.max_nodes.map_or(false, |x| nodes > x);
+        if traces <= self.last_pruning || (traces < self.last_pruning + self.age && !over_nodes) || !self.over_cap(nodes) {
+            return Ok(());
+        }
+        let age = self.age.min(traces - self.last_pruning);
+        self.last_pruning = traces;
+
+        let mut protected: HashSet<NodeIndex> = fbs.recent_nodes(age);
+        let top: HashSet<CorpusId> = state.metadata_map().get::<TopRatedsMetadata>()
+            .map_or(HashSet::new(), |meta| meta.map.values().copied().collect());
+        for id in top {
+            if let Ok(metadata) = state.corpus().get(id)?.borrow().metadata::<STGNodeMetadata>() {
+                protected.extend(metadata.nodes().iter().copied());
+            }
+        }
+        // the closure only sees the node, so protected nodes are identified by the same key as in the state_abb_hash_index
+        let keys: HashSet<(u64, u64)> = protected.iter()
+            .filter_map(|i| fbs.graph.node_weight(*i))
+            .map(|n| (n.state_hash(), n.abb().get_hash()))
+            .collect();
+        let pruning = state.metadata_mut::<STGFeedbackState<SYS>>()?
+            .prune(|n, _, _| keys.contains(&(n.state_hash(), n.abb().get_hash())));
+
+        let ids: Vec<CorpusId> = state.corpus().ids().collect();
+        for id in ids {
+            if let Ok(metadata) = state.corpus().get(id)?.borrow_mut().metadata_mut::<STGNodeMetadata>() {
+                metadata.remap(&pruning);
+            }
+        }
+        // other schedulers index by hashes, which do not depend on the graph indices
+        if cfg!(feature = "sched_stg_edge") {
+            if let Some(top) = state.metadata_map_mut().get_mut::<TopRatedsMetadata>() {
+                remap_top_rated(top, &pruning);
+            }
+        }
+        remap_history(state, &pruning);
+
+        println!("Pruned {} nodes, {} edges and {} system states of the STG, {} nodes left",
+            pruning.removed_nodes, pruning.removed_edges, pruning.removed_states, nodes - pruning.removed_nodes);
+        self.removed += pruning.removed_nodes as u64;
+        manager.fire(
+            state,
+            Event::UpdateUserStats {
+                name: Cow::from("PrunedNodes"),
+                value: UserStats::new(
+                    UserStatsValue::Number(self.removed),
+                    AggregatorOps::Sum,
+                ),
+                phantom: PhantomData,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::HashMap;
+    use petgraph::graph::EdgeIndex;
+    use petgraph::visit::EdgeRef;
+
+    use crate::systemstate::stg::{StgFeedback, WorstOrigin};
+    use crate::systemstate::target_os::mock::{MockSystem, MockTraceBuilder, MOCK_TASK_START_ISR};
+    use crate::systemstate::target_os::SystemTraceData;
+    use crate::systemstate::CaptureEvent;
+
+    /// T1 delays at 10, T2 runs from 20 and delays at 50, T1 runs again from 55
+    fn graph() -> STGFeedbackState<MockSystem> {
+        let trace = MockTraceBuilder::new()
+            .task("T1", 2)
+            .task("T2", 1)
+            .running("T1").ready(&["T2"])
+            .capture(0, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0, 0x100))
+            .capture(10, CaptureEvent::APIStart, "vTaskDelay", (0x110, 0x500))
+            .job_done(10, "T1")
+            .running("T2").ready(&[]).delayed(&["T1"])
+            .capture(20, CaptureEvent::APIEnd, "vTaskDelay", (0x510, 0x200))
+            .capture(50, CaptureEvent::APIStart, "vTaskDelay", (0x210, 0x500))
+            .job_done(50, "T2")
+            .running("T1").delayed(&["T2"])
+            .capture(55, CaptureEvent::APIEnd, "vTaskDelay", (0x510, 0x114))
+            .capture(70, CaptureEvent::End, "trigger_Qemu_break", (0x120, 0))
+            .build();
+        let mut fbs = STGFeedbackState::default();
+        StgFeedback::update_stg_interval(trace.intervals(), trace.mem_reads(), trace.states_map(), &mut fbs, WorstOrigin::new(1, 2));
+        fbs
+    }
+
+    type EdgeSummary = (String, String, String, u64, Option<u64>);
+
+    /// Endpoints, label, traversals and worst time of each edge, by index
+    fn edges(fbs: &STGFeedbackState<MockSystem>) -> Vec<EdgeSummary> {
+        fbs.graph.edge_references().map(|e| {
+            let w = e.weight();
+            (fbs.graph[e.source()].stable_id(), fbs.graph[e.target()].stable_id(), w.label(), w.hit_count, w.worst.as_ref().map(|x| x.0))
+        }).collect()
+    }
+
+    /// Prunes the block of T2
+    fn prune_t2(fbs: &mut STGFeedbackState<MockSystem>) -> STGPruning {
+        fbs.prune(|n, _, _| n.abb().start != 0x200)
+    }
+
+    #[test]
+    fn pruned_edges_keep_their_weights() {
+        let mut fbs = graph();
+        let before = edges(&fbs);
+        let nodes = fbs.graph.node_count();
+        let t2 = fbs.graph.node_weights().filter(|n| n.abb().start == 0x200).count();
+        assert!(t2 > 0);
+        let pruning = prune_t2(&mut fbs);
+        let after = edges(&fbs);
+
+        assert_eq!((pruning.removed_nodes, fbs.graph.node_count()), (t2, nodes - t2));
+        assert!(fbs.graph.node_weights().all(|n| n.abb().start != 0x200));
+        assert_eq!(pruning.removed_edges, before.len() - after.len());
+        assert!(pruning.removed_edges > 0);
+        assert_eq!(pruning.edges.len(), after.len());
+        // every remaining edge moved to its new index with its traversals and worst time
+        for (from, to) in pruning.edges.iter() {
+            assert_eq!(before[from.index()], after[to.index()]);
+        }
+        assert!(after.iter().any(|x| x.4.is_some()));
+        // indices stay consecutive and in order
+        let mut moves: Vec<_> = pruning.edges.iter().map(|(a, b)| (a.index(), b.index())).collect();
+        moves.sort();
+        assert!(moves.iter().enumerate().all(|(n, x)| x.1 == n));
+    }
+
+    #[test]
+    fn history_follows_the_edges() {
+        let mut fbs = graph();
+        let count = fbs.graph.edge_count();
+        let pruning = prune_t2(&mut fbs);
+        // each edge remembers its old index + 1
+        let mut history: Vec<u16> = (1..=count as u16).collect();
+        history.resize(count + 4, 0);
+        remap_history_map(&mut history, &pruning);
+        assert_eq!(history.len(), count + 4);
+        for old in 0..count {
+            match pruning.map_index(old) {
+                Some(new) => {
+                    assert_eq!(pruning.edges[&EdgeIndex::new(old)].index(), new);
+                    assert_eq!(history[new], old as u16 + 1);
+                }
+                None => assert!(!history.contains(&(old as u16 + 1))),
+            }
+        }
+        assert!(history[pruning.edges.len()..].iter().all(|x| *x == 0));
+        assert_eq!(pruning.map_index(count + 10), None);
+    }
+
+    #[test]
+    fn top_rated_of_removed_edges_are_dropped() {
+        let mut fbs = graph();
+        let count = fbs.graph.edge_count();
+        let pruning = prune_t2(&mut fbs);
+        let mut top = TopRatedsMetadata::new();
+        top.map = (0..count).map(|x| (x, CorpusId::from(x))).collect();
+        remap_top_rated(&mut top, &pruning);
+        let expected: HashMap<usize, CorpusId> = pruning.edges.iter().map(|(a, b)| (b.index(), CorpusId::from(a.index()))).collect();
+        assert_eq!(top.map.len(), count - pruning.removed_edges);
+        assert!(expected.iter().all(|(k, v)| top.map.get(k) == Some(v)));
+    }
+}
@@ -0,0 +1,189 @@
+//! Replaying an input up to a selected ABB instance, to inspect the target right before the critical code runs.
+//! A first run determines the ABB instance and its start tick, the re-run stops QEMU the first time the ABB start
+//! is executed at or after that tick. Only a single instruction hook is involved, which keeps the re-run fast.
+
+use hashbrown::HashMap;
+use libafl::{
+    common::HasMetadata,
+    executors::ExitKind,
+    inputs::UsesInput,
+    observers::ObserversTuple,
+};
+use libafl_qemu::{
+    modules::{EmulatorModule, EmulatorModuleTuple, NopAddressFilter, NopPageFilter},
+    EmulatorModules, GuestAddr, Hook,
+};
+use serde::{Deserialize, Serialize};
+
+use super::{helpers::get_icount, target_os::SystemTraceData};
+
+/// Where to stop the re-run, and where it stopped
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BreakTarget {
+    /// Start address of the ABB
+    pub abb_start: GuestAddr,
+    /// Task executing the ABB instance
+    pub task: String,
+    /// Tick at which the ABB instance starts in the first run
+    pub tick: u64,
+    /// Executions of the ABB start before the instance, counted during the first run
+    pub occurrence: usize,
+    /// (occurrence, icount) at which the re-run stopped
+    pub hit: Option<(usize, u64)>,
+}
+libafl_bolts::impl_serdeany!(BreakTarget);
+
+/// Selects the ABB instance to stop at.
+/// Without `abb_start` the ABB with the highest WOET of `task` is chosen. The instance is the longest one
+/// during the worst job of `task`, or during the whole trace if no task is given.
+pub fn select_break_target<T: SystemTraceData>(trace: &T, abb_start: Option<GuestAddr>, task: Option<&String>) -> Option<BreakTarget> {
+    let window = match task {
+        Some(t) => {
+            let job = trace.worst_jobs_per_task_by_response_time().remove(t)?;
+            job.release..job.response
+        }
+        None => 0..u64::MAX,
+    };
+    let abb_start = match abb_start {
+        Some(a) => a,
+        None => {
+            let profile = trace.select_abb_profile(task.cloned());
+            let abbs = profile.get(task?.as_str())?;
+            *abbs.iter().max_by_key(|(_, (_, _, _, woet, _))| *woet)?.0
+        }
+    };
+    // sum up the execution time of each instance of the abb in the window
+    let mut instances: HashMap<usize, (u64, u64, String)> = HashMap::new();
+    for i in trace.intervals() {
+        let Some(abb) = i.abb.as_ref() else { continue };
+        if abb.start != abb_start || i.end_tick <= window.start || i.start_tick >= window.end {
+            continue;
+        }
+        if task.map_or(false, |t| i.get_task_name().map_or(true, |n| n != t.as_str())) {
+            continue;
+        }
+        let e = instances
+            .entry(abb.instance_id)
+            .or_insert_with(|| (i.start_tick, 0, i.get_task_name_unchecked().into_owned()));
+        e.0 = e.0.min(i.start_tick);
+        e.1 += i.get_exec_time();
+    }
+    let (_, (tick, _, task)) = instances.into_iter().max_by_key(|(_, (tick, time, _))| (*time, u64::MAX - *tick))?;
+    // instances of the same abb started earlier, each of them executed the start address once
+    let mut starts: Vec<_> = trace
+        .intervals()
+        .iter()
+        .filter_map(|i| i.abb.as_ref().filter(|x| x.start == abb_start).map(|x| (x.instance_id, i.start_tick)))
+        .filter(|(_, t)| *t < tick)
+        .map(|(id, _)| id)
+        .collect();
+    starts.sort_unstable();
+    starts.dedup();
+    Some(BreakTarget {
+        abb_start,
+        task,
+        tick,
+        occurrence: starts.len(),
+        hit: None,
+    })
+}
+
+/// A Qemu module which stops the execution at the [`BreakTarget`] found in the state metadata.
+/// Noop while there is none.
+#[derive(Debug, Default)]
+pub struct AbbBreakpointModule {
+    /// (abb start, tick) the hook is armed for
+    armed: Option<(GuestAddr, u64)>,
+    /// Address the hook is installed at
+    installed: Option<GuestAddr>,
+    hits: usize,
+    hit: Option<(usize, u64)>,
+    address_filter: NopAddressFilter,
+    page_filter: NopPageFilter,
+}
+
+impl<S> EmulatorModule<S> for AbbBreakpointModule
+where
+    S: UsesInput + Unpin + HasMetadata,
+{
+    fn pre_exec<ET>(
+        &mut self,
+        emulator_modules: &mut EmulatorModules<ET, S>,
+        state: &mut S,
+        _input: &S::Input,
+    ) where
+        ET: EmulatorModuleTuple<S>,
+    {
+        self.armed = state.metadata::<BreakTarget>().ok().map(|x| (x.abb_start, x.tick));
+        self.hits = 0;
+        self.hit = None;
+        if let Some((addr, _)) = self.armed {
+            if self.installed != Some(addr) {
+                emulator_modules.instructions(addr, Hook::Function(abb_break_hook::<ET, S>), false);
+                self.installed = Some(addr);
+            }
+        }
+    }
+
+    fn post_exec<OT, ET>(
+        &mut self,
+        _emulator_modules: &mut EmulatorModules<ET, S>,
+        state: &mut S,
+        _input: &S::Input,
+        _observers: &mut OT,
+        _exit_kind: &mut ExitKind,
+    ) where
+        OT: ObserversTuple<S::Input, S>,
+        ET: EmulatorModuleTuple<S>,
+    {
+        if let Ok(target) = state.metadata_mut::<BreakTarget>() {
+            target.hit = self.hit;
+        }
+    }
+
+    type ModuleAddressFilter = NopAddressFilter;
+
+    type ModulePageFilter = NopPageFilter;
+
+    fn address_filter(&self) -> &Self::ModuleAddressFilter {
+        &self.address_filter
+    }
+
+    fn address_filter_mut(&mut self) -> &mut Self::ModuleAddressFilter {
+        &mut self.address_filter
+    }
+
+    fn page_filter(&self) -> &Self::ModulePageFilter {
+        &self.page_filter
+    }
+
+    fn page_filter_mut(&mut self) -> &mut Self::ModulePageFilter {
+        &mut self.page_filter
+    }
+}
+
+/// Count the executions of the abb start, stop once the target tick is reached
+fn abb_break_hook<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
+    _state: Option<&mut S>,
+    pc: GuestAddr,
+) where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
+{
+    let emulator = hooks.qemu();
+    let Some(h) = hooks.modules_mut().match_first_type_mut::<AbbBreakpointModule>() else {
+        return;
+    };
+    let Some((addr, tick)) = h.armed else { return };
+    if pc != addr || h.hit.is_some() {
+        return;
+    }
+    let icount = get_icount(&emulator);
+    if icount >= tick {
+        h.hit = Some((h.hits, icount));
+        emulator.current_cpu().expect("No cpu is executing").trigger_breakpoint();
+    } else {
+        h.hits += 1;
+    }
+}
@@ -0,0 +1,111 @@
+//! Self-contained artifacts to reproduce an execution outside of a campaign, see [`ReplayScript`].
+//!
+//! The interrupt times of an input are decoded with the interrupt config of the campaign before the patched QEMU injects them,
+//! so the bytes of an input alone do not reproduce its schedule. A replay script stores the decoded ticks instead:
+//! ```sh
+//! fret -k kernel.elf -c target.csv showmap -i worst.case --emit-replay-script worst.replay.ron
+//! input_serde -c worst.case -k kernel.elf --config target.csv -f replay > worst.replay.ron
+//! ```
+//! Any subcommand running a single input accepts the script in place of an input and injects exactly its ticks, e.g. to attach gdb:
+//! ```sh
+//! ICOUNT_SHIFT=<icount_shift of the script> fret -k kernel.elf -c target.csv debug -i worst.replay.ron --gdb 1234
+//! ```
+//! fret then starts the patched QEMU like `qemu-system-arm -icount shift=<icount_shift>,align=off,sleep=off -kernel kernel.elf -S -gdb tcp::1234`
+//! with the machine options of the config, writes `bytes` to FUZZ_INPUT and fills the interrupt tables with [`ReplayScript::inject`].
+//! A QEMU without the FRET patches ignores the tables, there the interrupts have to be raised by hand at the ticks of the script.
+
+use std::{collections::BTreeMap, fs, path::Path, sync::OnceLock};
+
+use libafl::inputs::{BytesInput, HasMutatorBytes, MultipartInput};
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::time::clock::qemu_icount_shift;
+
+use super::input_view::{bytes_part, interrupt_times, set_interrupt_times, InputError, BYTES_PART};
+
+/// Format version of [`ReplayScript`], raised on incompatible changes
+pub const REPLAY_SCRIPT_VERSION: u32 = 1;
+
+/// Program input and the exact ticks at which each interrupt source fires
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayScript {
+    /// See [`REPLAY_SCRIPT_VERSION`]
+    pub version: u32,
+    /// Icount shift of the run, the ticks only match with the same shift
+    pub icount_shift: u32,
+    /// Program input, written to FUZZ_INPUT
+    pub bytes: Vec<u8>,
+    /// Ticks of each interrupt source as injected by QEMU, ordered by source for a stable output
+    pub interrupts: BTreeMap<usize, Vec<u32>>,
+}
+
+impl ReplayScript {
+    /// Decodes the interrupt times of `input` as the harness does with the interrupt `config` of the campaign
+    pub fn from_input<I: HasMutatorBytes>(input: &MultipartInput<I>, config: &[(usize, u32)]) -> Result<Self, InputError> {
+        let interrupts = config.iter().map(|c| (c.0, interrupt_times(input, *c).unwrap_or_default())).collect();
+        Ok(Self {
+            version: REPLAY_SCRIPT_VERSION,
+            icount_shift: qemu_icount_shift(),
+            bytes: bytes_part(input)?.to_vec(),
+            interrupts,
+        })
+    }
+
+    /// An input with the program input and interrupt times of the script. Decoding it with a different interrupt config may move the ticks,
+    /// only [`ReplayScript::inject`] is exact.
+    pub fn to_input(&self) -> MultipartInput<BytesInput> {
+        let mut input = MultipartInput::from([(BYTES_PART, BytesInput::new(self.bytes.clone()))]);
+        for (source, ticks) in self.interrupts.iter() {
+            set_interrupt_times(&mut input, *source, ticks);
+        }
+        input
+    }
+
+    pub fn to_ron(&self) -> String {
+        ron::ser::to_string_pretty(self, PrettyConfig::default()).expect("Can not serialize replay script")
+    }
+
+    /// Parses a script, rejecting other versions
+    pub fn from_ron(s: &str) -> Result<Self, String> {
+        let script: Self = ron::from_str(s).map_err(|e| e.to_string())?;
+        if script.version != REPLAY_SCRIPT_VERSION {
+            return Err(format!("replay script version {} is not supported, expected {}", script.version, REPLAY_SCRIPT_VERSION));
+        }
+        Ok(script)
+    }
+
+    /// Fills the interrupt tables read by the patched QEMU, `offsets` holds `max` ticks per source and `counts` the number of ticks per source.
+    /// Sources beyond the tables are skipped and ticks beyond `max` are dropped.
+    pub fn inject(&self, offsets: &mut [u32], counts: &mut [u64], max: usize) {
+        offsets.fill(0);
+        counts.fill(0);
+        for (source, ticks) in self.interrupts.iter().filter(|x| *x.0 < counts.len()) {
+            let n = ticks.len().min(max);
+            offsets[source * max..source * max + n].copy_from_slice(&ticks[..n]);
+            counts[*source] = n as u64;
+        }
+    }
+}
+
+/// The script whose ticks are injected instead of the interrupt times of the inputs, set for single runs
+static LOADED_SCRIPT: OnceLock<ReplayScript> = OnceLock::new();
+
+/// Reads the script at `path`, None if it is not a replay script
+pub fn read_replay_script(path: &Path) -> Option<ReplayScript> {
+    let s = fs::read_to_string(path).ok()?;
+    ReplayScript::from_ron(&s).ok()
+}
+
+/// Makes the harness inject the ticks of `script` for every following execution. Warns if the icount shift differs from the current one.
+pub fn load_replay_script(script: ReplayScript) -> &'static ReplayScript {
+    if script.icount_shift != qemu_icount_shift() {
+        eprintln!("Warning: the replay script was recorded with icount shift {}, but the current shift is {}", script.icount_shift, qemu_icount_shift());
+    }
+    LOADED_SCRIPT.get_or_init(|| script)
+}
+
+/// The script set by [`load_replay_script`]
+pub fn loaded_replay_script() -> Option<&'static ReplayScript> {
+    LOADED_SCRIPT.get()
+}
@@ -30,26 +30,43 @@ use std::hash::Hash;
 use libafl_bolts::HasLen;
 use libafl::prelude::mutational::MutatedTransform;
 use libafl::prelude::FeedbackFactory;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use hashbrown::HashMap;
+use std::path::Path;
 use libafl::prelude::ObserversTuple;
 use libafl::prelude::HasObservers;
 use libafl::HasFeedback;
 use libafl::ExecutesInput;
 use libafl::ExecutionProcessor;
 
+use crate::status::{status_stats, STATUS_STATS};
 use crate::time::clock::{tick_to_time, time_to_tick, IcHist};
+#[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+use crate::time::qemustate::SnapshotStatsMetadata;
+
+use super::schedulers::{GeneticMetadata, ImprovementMetadata};
+use super::RTOSJob;
+use super::stg::STGFeedbackState;
+use super::target_os::intervals::IsrActivationStats;
+use super::target_os::TargetSystem;
 
 /// The [`AflStatsStage`] is a simple stage that computes and reports some stats.
+/// Also reports how the exploration of the STG progresses, see [`SchedulerStatsStage::exploration_stats`].
 #[derive(Debug, Clone)]
-pub struct SchedulerStatsStage<E, EM, Z> {
+pub struct SchedulerStatsStage<E, EM, Z, SYS> {
     last_report_time: Duration,
     // the interval that we report all stats
     stats_report_interval: Duration,
+    select_task: Option<String>,
+    // the exploration stats of the last report, only fired again if they changed
+    last_exploration: String,
+    // fire the progress read by the status server, see crate::status
+    report_status: bool,
 
-    phantom: PhantomData<(E, EM, Z)>,
+    phantom: PhantomData<(E, EM, Z, SYS)>,
 }
 
-impl<E, EM, Z> UsesState for SchedulerStatsStage<E, EM, Z>
+impl<E, EM, Z, SYS> UsesState for SchedulerStatsStage<E, EM, Z, SYS>
 where
     E: UsesState,
 {
@@ -69,7 +86,7 @@ where
 //     // Self::State: HasImported + HasCorpus + HasMetadata,
 //     // <E as UsesState>::State: HasMetadata+HasImported+UsesState,
 // {
-impl<E, EM, IP, Z> Stage<E, EM, Z> for SchedulerStatsStage<E, EM, Z>
+impl<E, EM, IP, Z, SYS> Stage<E, EM, Z> for SchedulerStatsStage<E, EM, Z, SYS>
 where
     Z: HasScheduler + ExecutionProcessor<EM, E::Observers> + ExecutesInput<E, EM> + HasFeedback,
     Z::Scheduler: RemovableScheduler<Self::Input, Self::State>,
@@ -85,6 +102,7 @@ where
     // M: Mutator<Self::Input, Self::State>,
     // IP: MutatedTransformPost<Self::State> + Clone,
     <<Self as UsesState>::State as HasCorpus>::Corpus: Corpus<Input = Self::Input>, // delete me
+    SYS: TargetSystem,
 {
     fn perform(
         &mut self,
@@ -105,6 +123,36 @@ where
         let cur = current_time();
 
         if cur.checked_sub(self.last_report_time).unwrap_or_default() > self.stats_report_interval {
+            self.last_report_time = cur;
+            let exploration = self.exploration_stats(state);
+            if exploration != self.last_exploration {
+                self.last_exploration = exploration.clone();
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from("Exploration"),
+                        value: UserStats::new(
+                            UserStatsValue::String(Cow::from(exploration)),
+                            AggregatorOps::None,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+            }
+            if let Some(meta) = state.metadata_map().get::<ImprovementMetadata>() {
+                let top = meta.top(5).iter().map(|(id, x)| format!("{}:{:.3}", usize::from(*id), x)).join(" ");
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from("Improvement"),
+                        value: UserStats::new(
+                            UserStatsValue::String(Cow::from(top)),
+                            AggregatorOps::None,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+            }
             let wort = tick_to_time(state.metadata_map().get::<IcHist>().unwrap_or(&IcHist::default()).1.0);
             if let Some(meta) = state.metadata_map().get::<TopRatedsMetadata>() {
                 let kc = meta.map.keys().count();
@@ -138,7 +186,6 @@ where
                     self.own_finds_size,
                     self.imported_size
                 );
-                self.last_report_time = cur;
                 // Experimental pruning
                 #[cfg(any(feature = "sched_stg",feature = "sched_afl"))]
                 {
@@ -191,6 +238,88 @@ where
                     );
                 }
             }
+            #[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+            if let Some(stats) = state.metadata_map().get::<SnapshotStatsMetadata>() {
+                let total = stats.fast_restores + stats.full_restores;
+                let speedup = stats.speedup().map_or("-".to_string(), |x| format!("{:.2}x", x));
+                let fast_restores = stats.fast_restores;
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from(if cfg!(feature = "snapshot_at_first_task") { "FirstTaskSnapshot" } else { "SetupSnapshot" }),
+                        value: UserStats::new(
+                            UserStatsValue::String(Cow::from(format!("{}/{} restores, {} execs/sec", fast_restores, total, speedup))),
+                            AggregatorOps::None,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+            }
+            if let Some(gm) = state.metadata_map().get::<GeneticMetadata>() {
+                let (gen, size, diversity) = (gm.gen, gm.current_gen.len(), gm.diversity);
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from("Generation"),
+                        value: UserStats::new(
+                            UserStatsValue::Number(gen as u64),
+                            AggregatorOps::Max,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from("PopulationDiversity"),
+                        value: UserStats::new(
+                            UserStatsValue::String(Cow::from(format!("{:.1}% of {}", diversity * 100.0, size))),
+                            AggregatorOps::None,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+            }
+            if let Some(releases) = state.metadata_map().get::<ReleaseStats>().filter(|x| !x.tasks.is_empty()).map(ReleaseStats::summary) {
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from("Releases"),
+                        value: UserStats::new(
+                            UserStatsValue::String(Cow::from(releases)),
+                            AggregatorOps::None,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+            }
+            if let Some(interrupts) = state.metadata_map().get::<IsrActivationStats>().map(interrupt_stats) {
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from("Interrupts"),
+                        value: UserStats::new(
+                            UserStatsValue::String(Cow::from(interrupts)),
+                            AggregatorOps::None,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+            }
+            if self.report_status {
+                let status = status_stats::<_, SYS>(state);
+                _manager.fire(
+                    state,
+                    Event::UpdateUserStats {
+                        name: Cow::from(STATUS_STATS),
+                        value: UserStats::new(
+                            UserStatsValue::String(status),
+                            AggregatorOps::None,
+                        ),
+                        phantom: PhantomData,
+                    },
+                )?;
+            }
         }
 
         Ok(())
@@ -209,24 +338,172 @@ where
     }
 }
 
-impl<E, EM, Z> SchedulerStatsStage<E, EM, Z> {
+impl<E, EM, Z, SYS> SchedulerStatsStage<E, EM, Z, SYS> {
     /// create a new instance of the [`AflStatsStage`]
     #[must_use]
-    pub fn new(interval: Duration) -> Self {
+    pub fn new(interval: Duration, select_task: Option<String>) -> Self {
         Self {
             stats_report_interval: interval,
+            select_task,
             ..Default::default()
         }
     }
+
+    /// Also fire the [`crate::status::StatusReport`] of each interval, for `--status-port`
+    #[must_use]
+    pub fn with_status(mut self, report_status: bool) -> Self {
+        self.report_status = report_status;
+        self
+    }
+}
+
+impl<E, EM, Z, SYS> SchedulerStatsStage<E, EM, Z, SYS>
+where
+    SYS: TargetSystem,
+{
+    /// Summarizes the STG size, the number of aggregated paths, the WORTs in us, the corpus size and the share of favored entries.
+    /// Everything but the favored entries is kept up to date by the feedbacks, so this does not scan the corpus.
+    fn exploration_stats<S>(&self, state: &S) -> String
+    where
+        S: HasMetadata + HasCorpus,
+    {
+        let corpus_size = state.corpus().count();
+        let mut out = match state.metadata_map().get::<STGFeedbackState<SYS>>() {
+            Some(fbs) => {
+                let mut out = format!(
+                    "n:{} e:{} paths:{} wort:{}us",
+                    fbs.graph.node_count(),
+                    fbs.graph.edge_count(),
+                    fbs.aggregated_path_count(),
+                    tick_to_time(fbs.worst_total()).as_micros()
+                );
+                if let Some(task) = self.select_task.as_ref() {
+                    out.push_str(&format!(" {}:{}us", task, tick_to_time(fbs.wort()).as_micros()));
+                }
+                out
+            }
+            Option::None => String::from("n:0 e:0 paths:0"),
+        };
+        out.push_str(&format!(" corpus:{}", corpus_size));
+        if let Some(meta) = state.metadata_map().get::<TopRatedsMetadata>() {
+            // top rated entries are a subset of the corpus, the map only has one entry per observed map index
+            let favored = meta.map.values().unique().count();
+            out.push_str(&format!(" fav:{:.1}%", favored as f32 * 100.0 / usize::max(corpus_size, 1) as f32));
+        }
+        out
+    }
+}
+
+/// Activations per ISR and the share of executions in which it fired, e.g. "vTimerISR:1520(98.2%)"
+fn interrupt_stats(stats: &IsrActivationStats) -> String {
+    stats.activations.iter().sorted_by_key(|x| x.0).map(|(name, count)| {
+        let with = stats.executions_with.get(name).copied().unwrap_or(0);
+        format!("{}:{}({:.1}%)", name, count, with as f32 * 100.0 / u64::max(stats.executions, 1) as f32)
+    }).join(" ")
+}
+
+/// Release statistics of one task, see [`ReleaseStats`]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TaskReleaseStats {
+    pub jobs: u64,
+    /// Shortest time between two consecutive releases of an execution
+    pub min_inter_arrival: Option<u64>,
+    /// Sum and number of the inter-arrival times, for the average
+    pub inter_arrival_sum: u64,
+    pub inter_arrivals: u64,
+    /// Largest release jitter of an execution, only known with a nominal period
+    pub max_jitter: Option<u64>,
+}
+
+impl TaskReleaseStats {
+    pub fn avg_inter_arrival(&self) -> Option<u64> {
+        (self.inter_arrivals > 0).then(|| self.inter_arrival_sum / self.inter_arrivals)
+    }
+}
+
+/// Inter-arrival times and release jitter per task over all executions, collected by [`super::feedbacks::ReleaseStatsFeedback`].
+/// Kept in the state to survive restarts.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ReleaseStats {
+    pub tasks: HashMap<String, TaskReleaseStats>,
+    /// Nominal period per task in ticks, see [`crate::cli::get_task_periods`]
+    pub periods: HashMap<String, u64>,
+}
+libafl_bolts::impl_serdeany!(ReleaseStats);
+
+impl ReleaseStats {
+    pub fn new(periods: HashMap<String, u64>) -> Self {
+        Self { tasks: HashMap::new(), periods }
+    }
+
+    /// Adds the jobs of one execution.
+    /// The release jitter of a task with a nominal period P is the spread of the offsets `release_k - (release_0 + k * P)` of its jobs.
+    pub fn add(&mut self, jobs: &[RTOSJob]) {
+        let mut releases: HashMap<&str, Vec<u64>> = HashMap::new();
+        for job in jobs {
+            releases.entry(job.name.as_str()).or_default().push(job.release);
+        }
+        for (name, mut r) in releases {
+            r.sort_unstable();
+            let stats = self.tasks.entry_ref(name).or_default();
+            stats.jobs += r.len() as u64;
+            for w in r.windows(2) {
+                let d = w[1] - w[0];
+                stats.min_inter_arrival = Some(stats.min_inter_arrival.map_or(d, |m| m.min(d)));
+                stats.inter_arrival_sum += d;
+                stats.inter_arrivals += 1;
+            }
+            if let Some(period) = self.periods.get(name).filter(|p| **p > 0) {
+                let offsets = r.iter().enumerate().map(|(k, x)| (x - r[0]) as i64 - (k as u64 * period) as i64);
+                if let Some((min, max)) = offsets.minmax().into_option() {
+                    let jitter = (max - min) as u64;
+                    stats.max_jitter = Some(stats.max_jitter.map_or(jitter, |m| m.max(jitter)));
+                }
+            }
+        }
+    }
+
+    /// Jobs, min/avg inter-arrival and jitter per task, e.g. "T1:120 ia:1000/1200us jit:30us"
+    pub fn summary(&self) -> String {
+        let us = |x: Option<u64>| x.map_or("-".to_string(), |t| tick_to_time(t).as_micros().to_string());
+        self.tasks.iter().sorted_by_key(|x| x.0).map(|(name, s)| {
+            let mut out = format!("{}:{} ia:{}/{}us", name, s.jobs, us(s.min_inter_arrival), us(s.avg_inter_arrival()));
+            if s.max_jitter.is_some() {
+                out.push_str(&format!(" jit:{}us", us(s.max_jitter)));
+            }
+            out
+        }).join(" ")
+    }
+
+    /// Writes one line per task, all times in ticks, empty fields if unknown
+    pub fn dump_csv(&self, path: &Path) -> std::io::Result<()> {
+        let opt = |x: Option<u64>| x.map_or(String::new(), |t| t.to_string());
+        let mut out = String::from("task,jobs,min_inter_arrival,avg_inter_arrival,period,max_jitter\n");
+        for (name, s) in self.tasks.iter().sorted_by_key(|x| x.0) {
+            out.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                name,
+                s.jobs,
+                opt(s.min_inter_arrival),
+                opt(s.avg_inter_arrival()),
+                opt(self.periods.get(name).copied()),
+                opt(s.max_jitter)
+            ));
+        }
+        std::fs::write(path, out)
+    }
 }
 
-impl<E, EM, Z> Default for SchedulerStatsStage<E, EM, Z> {
+impl<E, EM, Z, SYS> Default for SchedulerStatsStage<E, EM, Z, SYS> {
     /// the default instance of the [`AflStatsStage`]
     #[must_use]
     fn default() -> Self {
         Self {
             last_report_time: current_time(),
             stats_report_interval: Duration::from_secs(3),
+            select_task: None,
+            last_exploration: String::new(),
+            report_status: false,
             phantom: PhantomData,
         }
     }
@@ -4,15 +4,19 @@
 use core::marker::PhantomData;
 use std::{cmp::{max, min}, mem::swap};
 
+use hashbrown::{HashMap, HashSet};
+use itertools::Itertools;
+use petgraph::graph::EdgeIndex;
+
 use serde::{Deserialize, Serialize};
 
 use libafl_bolts::{rands::Rand, AsIter, HasLen};
 use libafl::{
-    common::HasMetadata, corpus::{Corpus, Testcase}, inputs::UsesInput, prelude::{CanTrack, CorpusId, RemovableScheduler}, schedulers::{minimizer::DEFAULT_SKIP_NON_FAVORED_PROB, Scheduler, TestcaseScore }, state::{HasCorpus, HasRand, State, UsesState}, Error, SerdeAny
+    common::HasMetadata, corpus::{Corpus, Testcase}, inputs::UsesInput, observers::ObserversTuple, prelude::{CanTrack, CorpusId, RemovableScheduler}, schedulers::{minimizer::DEFAULT_SKIP_NON_FAVORED_PROB, Scheduler, TestcaseScore }, state::{HasCorpus, HasRand, State, UsesState}, Error, SerdeAny
     
 };
 
-use crate::time::worst::MaxTimeFavFactor;
+use crate::time::worst::{MaxTimeFavFactor, TimeProbFactor};
 
 use super::{stg::STGNodeMetadata, target_os::*};
 
@@ -152,19 +156,33 @@ pub struct GeneticMetadata {
     pub current_gen: Vec<(usize, f64)>,
     pub current_cursor: usize,
     pub next_gen: Vec<(usize, f64)>,
-    pub gen: usize
+    pub gen: usize,
+    /// Share of the current generation with a distinct STG path
+    #[serde(default)]
+    pub diversity: f64,
 }
 
 impl GeneticMetadata {
     fn new(current_gen: Vec<(usize, f64)>, next_gen: Vec<(usize, f64)>) -> Self {
-        Self {current_gen, current_cursor: 0, next_gen, gen: 0}
+        Self {current_gen, current_cursor: 0, next_gen, gen: 0, diversity: 0.0}
     }
 }
 
+/// The STG edges taken by a testcase, empty if it has no [`STGNodeMetadata`]
+fn stg_edges_of<S: HasCorpus>(state: &S, idx: usize) -> Vec<EdgeIndex> {
+    state.corpus().get(idx.into()).ok()
+        .and_then(|tc| tc.borrow().metadata_map().get::<STGNodeMetadata>().map(|m| m.edges().clone()))
+        .unwrap_or_default()
+}
+
 #[derive(Debug, Clone)]
 pub struct GenerationScheduler<S> {
     phantom: PhantomData<S>,
     gen_size: usize,
+    /// Select the survivors by tournaments of this size instead of keeping the fittest
+    tournament_size: Option<usize>,
+    /// Scale the fitness of a candidate by 1 + weight * share of its STG edges not taken by the current generation
+    novelty_weight: Option<f64>,
 }
 
 impl<S> UsesState for GenerationScheduler<S>
@@ -176,7 +194,7 @@ where
 
 impl<I, S> Scheduler<I, S> for GenerationScheduler<S>
 where
-    S: State + HasCorpus + HasMetadata,
+    S: State + HasCorpus + HasMetadata + HasRand,
     <<S as HasCorpus>::Corpus as libafl::corpus::Corpus>::Input: Clone,
 {
     /// get first element in current gen,
@@ -189,33 +207,55 @@ where
         let gm = state.metadata_map_mut().get_mut::<GeneticMetadata>().expect("Corpus Scheduler empty");
         // println!("index: {} curr: {:?} next: {:?} gen: {} corp: {}", gm.current_cursor, gm.current_gen.len(), gm.next_gen.len(), gm.gen,
         // c);
-        match gm.current_gen.get(gm.current_cursor) {
-            Some(c) => {
-                _current_len = gm.current_gen.len();
-                gm.current_cursor+=1;
-                // println!("normal next: {}", (*c).0);
-                return Ok((*c).0.into())
-            },
+        if let Some(c) = gm.current_gen.get(gm.current_cursor) {
+            gm.current_cursor+=1;
+            // println!("normal next: {}", (*c).0);
+            return Ok((*c).0.into())
+        }
+        swap(&mut to_remove, &mut gm.current_gen);
+        let mut candidates = std::mem::take(&mut gm.next_gen);
+        if candidates.len() == 0 {panic!("Corpus is empty");}
+        if let Some(weight) = self.novelty_weight {
+            // reward paths the current generation does not take
+            let population_edges : HashSet<EdgeIndex> = to_remove.iter().flat_map(|x| stg_edges_of(state, x.0)).collect();
+            for c in candidates.iter_mut() {
+                let edges = stg_edges_of(state, c.0);
+                let novel = edges.iter().filter(|e| !population_edges.contains(*e)).count();
+                c.1 *= 1.0 + weight * novel as f64 / max(edges.len(), 1) as f64;
+            }
+        }
+        let mut survivors = match self.tournament_size {
             Option::None => {
-                swap(&mut to_remove, &mut gm.current_gen);
-                swap(&mut gm.next_gen, &mut gm.current_gen);
-                gm.current_gen.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                // gm.current_gen.reverse();
-                if gm.current_gen.len() == 0 {panic!("Corpus is empty");}
-                let d : Vec<(usize, f64)> = gm.current_gen.drain(min(gm.current_gen.len(), self.gen_size)..).collect();
+                candidates.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                let d : Vec<(usize, f64)> = candidates.drain(min(candidates.len(), self.gen_size)..).collect();
                 to_remove.extend(d);
-                // move all indices to the left, since all other indices will be deleted
-                gm.current_gen.sort_by(|a,b| a.0.cmp(&(*b).0)); // in order of the corpus index
-                // for i in 0..gm.current_gen.len() {
-                //     gm.current_gen[i] = (i, gm.current_gen[i].1);
-                // }
-                _to_return = gm.current_gen.get(0).unwrap().0;
-                // assert_eq!(to_return, 0);
-                gm.current_cursor=1;
-                gm.gen+=1;
-                _current_len = gm.current_gen.len();
+                candidates
+            },
+            Some(k) => {
+                let mut survivors = Vec::with_capacity(self.gen_size);
+                while survivors.len() < self.gen_size && !candidates.is_empty() {
+                    let n = std::num::NonZero::new(candidates.len()).unwrap();
+                    let winner = (0..k).map(|_| state.rand_mut().below(n))
+                        .min_by(|a, b| candidates[*a].1.partial_cmp(&candidates[*b].1).unwrap())
+                        .unwrap();
+                    survivors.push(candidates.swap_remove(winner));
+                }
+                to_remove.extend(candidates);
+                survivors
             }
         };
+        // move all indices to the left, since all other indices will be deleted
+        survivors.sort_by(|a,b| a.0.cmp(&(*b).0)); // in order of the corpus index
+        let paths : HashSet<Vec<EdgeIndex>> = survivors.iter().map(|x| stg_edges_of(state, x.0)).collect();
+        let diversity = paths.len() as f64 / survivors.len() as f64;
+        let gm = state.metadata_map_mut().get_mut::<GeneticMetadata>().unwrap();
+        gm.current_gen = survivors;
+        _to_return = gm.current_gen.get(0).unwrap().0;
+        // assert_eq!(to_return, 0);
+        gm.current_cursor=1;
+        gm.gen+=1;
+        gm.diversity = diversity;
+        _current_len = gm.current_gen.len();
         // removing these elements will move all indices left by to_remove.len()
         // to_remove.sort_by(|x,y| x.0.cmp(&(*y).0));
         // to_remove.reverse();
@@ -321,7 +361,233 @@ impl<S> GenerationScheduler<S>
         let gen_size= 1000;
         Self {
             phantom: PhantomData,
-            gen_size
+            gen_size,
+            tournament_size: None,
+            novelty_weight: None,
+        }
+    }
+
+    /// Keep this many inputs per generation instead of the gensize feature
+    #[must_use]
+    pub fn with_population_size(mut self, size: Option<usize>) -> Self {
+        if let Some(size) = size {
+            self.gen_size = max(size, 1);
+        }
+        self
+    }
+
+    /// Select the survivors by tournaments of `size` random candidates, see [`GenerationScheduler::tournament_size`]
+    #[must_use]
+    pub fn with_tournament_size(mut self, size: Option<usize>) -> Self {
+        self.tournament_size = size.map(|x| max(x, 1));
+        self
+    }
+
+    /// Reward candidates taking STG edges the current generation does not, see [`GenerationScheduler::novelty_weight`]
+    #[must_use]
+    pub fn with_novelty_weight(mut self, weight: Option<f64>) -> Self {
+        self.novelty_weight = weight;
+        self
+    }
+}
+
+//==========================================================================================
+
+/// Weight of the latest round in the moving average of an [`ImprovementWeightedScheduler`]
+pub const DEFAULT_IMPROVEMENT_ALPHA: f64 = 0.2;
+/// Lower bound of the improvement factor of an [`ImprovementWeightedScheduler`]
+pub const DEFAULT_IMPROVEMENT_FLOOR: f64 = 0.05;
+
+/// An entry of the [`ImprovementMetadata`]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ImprovementEntry {
+    /// Score of the testcase, see [`TestcaseScore`]
+    pub score: f64,
+    /// Moving average of interesting children / generated children over the rounds the testcase was scheduled
+    pub improvement: f64,
+}
+
+/// A state metadata holding the selection weights of the [`ImprovementWeightedScheduler`]
+#[derive(Debug, Serialize, Deserialize, SerdeAny, Default)]
+pub struct ImprovementMetadata {
+    pub entries: HashMap<CorpusId, ImprovementEntry>,
+}
+
+impl ImprovementMetadata {
+    /// The `n` testcases with the highest improvement average
+    pub fn top(&self, n: usize) -> Vec<(CorpusId, f64)> {
+        self.entries.iter()
+            .map(|(id, e)| (*id, e.improvement))
+            .sorted_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)))
+            .take(n)
+            .collect()
+    }
+}
+
+/// Samples the corpus like [`libafl::schedulers::ProbabilitySamplingScheduler`], but multiplies the score of `F` with the
+/// moving average of the share of interesting children of each testcase. The average is updated whenever a round of
+/// scheduling the testcase ends and never drops below `floor`, so testcases without recent improvements are still selected.
+/// New testcases start at 1.0.
+#[derive(Debug, Clone)]
+pub struct ImprovementWeightedScheduler<F> {
+    alpha: f64,
+    floor: f64,
+    /// Executions of children of the current testcase this round
+    generated: usize,
+    /// Children of the current testcase added to the corpus this round
+    interesting: usize,
+    phantom: PhantomData<F>,
+}
+
+pub type TimeImprovementScheduler<S> = ImprovementWeightedScheduler<TimeProbFactor<S>>;
+
+impl<F> ImprovementWeightedScheduler<F> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            alpha: DEFAULT_IMPROVEMENT_ALPHA,
+            floor: DEFAULT_IMPROVEMENT_FLOOR,
+            generated: 0,
+            interesting: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Keep the improvement factor of each testcase at least `floor`, see [`DEFAULT_IMPROVEMENT_FLOOR`]
+    #[must_use]
+    pub fn with_floor(mut self, floor: Option<f64>) -> Self {
+        if let Some(floor) = floor {
+            self.floor = floor.clamp(f64::MIN_POSITIVE, 1.0);
+        }
+        self
+    }
+
+    /// Folds the share of interesting children of the current round into the average of the current testcase
+    fn finish_round<S: HasCorpus + HasMetadata>(&mut self, state: &mut S) {
+        let generated = std::mem::take(&mut self.generated);
+        let interesting = std::mem::take(&mut self.interesting);
+        let (Some(id), true) = (*state.corpus().current(), generated > 0) else {
+            return;
+        };
+        let share = f64::min(interesting as f64 / generated as f64, 1.0);
+        if let Some(e) = state.metadata_map_mut().get_mut::<ImprovementMetadata>().and_then(|m| m.entries.get_mut(&id)) {
+            e.improvement = (1.0 - self.alpha) * e.improvement + self.alpha * share;
+        }
+    }
+
+    fn score<S>(state: &S, idx: CorpusId) -> Result<f64, Error>
+    where
+        F: TestcaseScore<S>,
+        S: HasCorpus,
+    {
+        let mut tc = state.corpus().get(idx)?.borrow_mut();
+        F::compute(state, &mut *tc)
+    }
+}
+
+impl<F> Default for ImprovementWeightedScheduler<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I, S, F> Scheduler<I, S> for ImprovementWeightedScheduler<F>
+where
+    F: TestcaseScore<S>,
+    S: HasCorpus + HasMetadata + HasRand,
+    S::Corpus: Corpus<Input = I>,
+{
+    /// Count a child of the current testcase and start the new testcase at the highest improvement
+    fn on_add(&mut self, state: &mut S, idx: CorpusId) -> Result<(), Error> {
+        if state.corpus().current().is_some() {
+            self.interesting += 1;
+        }
+        let score = Self::score(state, idx)?;
+        let entry = ImprovementEntry { score, improvement: 1.0 };
+        if let Some(meta) = state.metadata_map_mut().get_mut::<ImprovementMetadata>() {
+            meta.entries.insert(idx, entry);
+        } else {
+            state.add_metadata(ImprovementMetadata { entries: HashMap::from([(idx, entry)]) });
+        }
+        Ok(())
+    }
+
+    /// Every execution while a testcase is scheduled is a child of it
+    fn on_evaluation<OT>(&mut self, state: &mut S, _input: &I, _observers: &OT) -> Result<(), Error>
+    where
+        OT: ObserversTuple<I, S>,
+    {
+        if state.corpus().current().is_some() {
+            self.generated += 1;
         }
+        Ok(())
+    }
+
+    fn next(&mut self, state: &mut S) -> Result<CorpusId, Error> {
+        if state.corpus().count() == 0 {
+            return Err(Error::empty(String::from("No entries in corpus")));
+        }
+        self.finish_round(state);
+        let floor = self.floor;
+        let weights : Vec<(CorpusId, f64)> = state.metadata_map().get::<ImprovementMetadata>()
+            .ok_or_else(|| Error::key_not_found("ImprovementMetadata not found".to_string()))?
+            .entries.iter()
+            .map(|(id, e)| (*id, e.score * e.improvement.max(floor)))
+            .sorted_by_key(|x| x.0)
+            .collect();
+        let total : f64 = weights.iter().map(|x| x.1).sum();
+        let idx = if total > 0.0 && total.is_finite() {
+            let mut threshold = state.rand_mut().next_float() * total;
+            weights.iter().find(|x| {
+                threshold -= x.1;
+                threshold < 0.0
+            }).unwrap_or(weights.last().unwrap()).0
+        } else {
+            weights[state.rand_mut().below(std::num::NonZero::new(weights.len()).unwrap())].0
+        };
+        self.set_current_scheduled(state, Some(idx))?;
+        Ok(idx)
+    }
+
+    fn set_current_scheduled(
+        &mut self,
+        state: &mut S,
+        next_id: Option<CorpusId>,
+    ) -> Result<(), Error> {
+        *state.corpus_mut().current_mut() = next_id;
+        Ok(())
+    }
+}
+
+impl<I, S, F> RemovableScheduler<I, S> for ImprovementWeightedScheduler<F>
+where
+    F: TestcaseScore<S>,
+    S: HasCorpus + HasMetadata + HasRand,
+    S::Corpus: Corpus<Input = I>,
+{
+    /// Keeps the improvement average of the replaced testcase
+    fn on_replace(
+        &mut self,
+        state: &mut S,
+        idx: CorpusId,
+        _prev: &Testcase<I>,
+    ) -> Result<(), Error> {
+        let score = Self::score(state, idx)?;
+        if let Some(e) = state.metadata_map_mut().get_mut::<ImprovementMetadata>().and_then(|m| m.entries.get_mut(&idx)) {
+            e.score = score;
+        }
+        Ok(())
+    }
+
+    fn on_remove(
+        &mut self,
+        state: &mut S,
+        idx: CorpusId,
+        _testcase: &Option<Testcase<I>>,
+    ) -> Result<(), Error> {
+        if let Some(meta) = state.metadata_map_mut().get_mut::<ImprovementMetadata>() {
+            meta.entries.remove(&idx);
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,124 @@
+//! Numbered dumps of the STG while fuzzing, see [`StgSnapshotStage`].
+
+use core::{marker::PhantomData, time::Duration};
+use std::{path::PathBuf, sync::mpsc::{sync_channel, SyncSender, TrySendError}, thread::{self, JoinHandle}, time::SystemTime};
+
+use libafl::{
+    common::HasMetadata, stages::Stage, state::UsesState, Error
+};
+
+use crate::{dump::{dump_path, write_dump}, time::clock::FUZZ_START_TIMESTAMP};
+
+use super::stg::STGFeedbackState;
+use super::target_os::TargetSystem;
+
+/// Snapshots waiting to be written, further snapshots are skipped while the writer is behind
+const SNAPSHOT_QUEUE: usize = 2;
+
+/// Path of snapshot number `n` of the graph dump `base`, e.g. `name.003.dot`
+fn snapshot_path(base: &PathBuf, n: usize) -> PathBuf {
+    base.with_extension(format!("{:03}.dot", n))
+}
+
+/// Dumps the STG every `interval` since [`FUZZ_START_TIMESTAMP`] as `name.000.dot`, `name.001.dot`, ... next to the final graph dump,
+/// but only if nodes or edges were added since the last snapshot. The files are written by a background thread, so fuzzing is not blocked by disk I/O.
+/// Numbering continues after the highest existing snapshot, so a restarted client does not overwrite earlier ones.
+#[derive(Debug)]
+pub struct StgSnapshotStage<E, EM, Z, SYS> {
+    base: PathBuf,
+    interval: Option<Duration>,
+    /// Number of the next snapshot
+    next: usize,
+    /// Intervals since the start of the campaign at the last check
+    last_slot: u64,
+    /// Nodes and edges at the last snapshot
+    last_size: (usize, usize),
+    writer: Option<(SyncSender<(PathBuf, String)>, JoinHandle<()>)>,
+    phantom: PhantomData<(E, EM, Z, SYS)>,
+}
+
+impl<E, EM, Z, SYS> StgSnapshotStage<E, EM, Z, SYS> {
+    /// Snapshots of the graph dump `base`, none if `base` or `interval` is missing
+    pub fn new(base: Option<PathBuf>, interval: Option<Duration>) -> Self {
+        let base = base.unwrap_or_default();
+        let interval = interval.filter(|x| !x.is_zero() && !base.as_os_str().is_empty());
+        let next = if interval.is_some() { (0..).find(|n| !dump_path(snapshot_path(&base, *n)).exists()).unwrap() } else { 0 };
+        let writer = interval.map(|_| {
+            let (sender, receiver) = sync_channel::<(PathBuf, String)>(SNAPSHOT_QUEUE);
+            let handle = thread::spawn(move || {
+                for (path, dot) in receiver {
+                    if let Err(e) = write_dump(&path, dot) {
+                        log::error!("Can not write graph snapshot {}: {}", path.display(), e);
+                    }
+                }
+            });
+            (sender, handle)
+        });
+        Self { base, interval, next, last_slot: 0, last_size: (0, 0), writer, phantom: PhantomData }
+    }
+}
+
+impl<E, EM, Z, SYS> Drop for StgSnapshotStage<E, EM, Z, SYS> {
+    /// Waits for the queued snapshots
+    fn drop(&mut self) {
+        if let Some((sender, handle)) = self.writer.take() {
+            drop(sender);
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<E, EM, Z, SYS> UsesState for StgSnapshotStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+{
+    type State = E::State;
+}
+
+impl<E, EM, Z, SYS> Stage<E, EM, Z> for StgSnapshotStage<E, EM, Z, SYS>
+where
+    E: UsesState,
+    EM: UsesState<State = Self::State>,
+    Z: UsesState<State = Self::State>,
+    Self::State: HasMetadata,
+    SYS: TargetSystem,
+{
+    fn perform(
+        &mut self,
+        _fuzzer: &mut Z,
+        _executor: &mut E,
+        state: &mut Self::State,
+        _manager: &mut EM,
+    ) -> Result<(), Error> {
+        let (Some(interval), Some((sender, _))) = (self.interval, &self.writer) else { return Ok(()) };
+        let elapsed = SystemTime::now().duration_since(unsafe { FUZZ_START_TIMESTAMP }).unwrap_or_default();
+        let slot = (elapsed.as_millis() / interval.as_millis()) as u64;
+        if slot <= self.last_slot {
+            return Ok(());
+        }
+        self.last_slot = slot;
+        let Some(md) = state.metadata_map().get::<STGFeedbackState<SYS>>() else { return Ok(()) };
+        let size = (md.graph.node_count(), md.graph.edge_count());
+        if size == self.last_size {
+            return Ok(());
+        }
+        let path = snapshot_path(&self.base, self.next);
+        match sender.try_send((path.clone(), md.to_dot((0, 0)))) {
+            Ok(()) => {
+                self.last_size = size;
+                self.next += 1;
+            },
+            Err(TrySendError::Full(_)) => log::warn!("Skipping graph snapshot {}, the previous ones are still being written", path.display()),
+            Err(TrySendError::Disconnected(_)) => log::error!("Skipping graph snapshot {}, the writer stopped", path.display()),
+        }
+        Ok(())
+    }
+
+    fn should_restart(&mut self, _state: &mut Self::State) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn clear_progress(&mut self, _state: &mut Self::State) -> Result<(), Error> {
+        Ok(())
+    }
+}
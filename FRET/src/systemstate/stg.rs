@@ -14,21 +14,24 @@ use libafl::schedulers::MinimizerScheduler;
 use libafl_bolts::HasRefCnt;
 use serde::de::DeserializeOwned;
 use std::path::PathBuf;
-use libafl::corpus::Testcase;
-use std::collections::hash_map::DefaultHasher;
+use std::time::{Duration, Instant};
+use std::cell::Cell;
+use libafl::corpus::{Corpus, Testcase};
+use libafl::state::HasCorpus;
 use std::hash::Hasher;
 use std::hash::Hash;
-use libafl::events::EventFirer;
+use libafl::events::{Event, EventFirer};
+use libafl::monitors::{AggregatorOps, UserStats, UserStatsValue};
 use libafl::state::MaybeHasClientPerfMonitor;
 use libafl::feedbacks::Feedback;
 use libafl_bolts::Named;
 use libafl::Error;
 use hashbrown::HashMap;
-use libafl::{executors::ExitKind, observers::ObserversTuple, common::HasMetadata};
+use libafl::{executors::ExitKind, observers::{ObserversTuple, StdMapObserver}, common::HasMetadata};
 use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
 
-use super::helpers::metadata_insert_or_update_get;
+use super::helpers::{add_abb_time_stats, metadata_insert_or_update_get, stable_hasher, AbbTimeStats, HASH_VERSION};
 use super::target_os::SystemState;
 use super::AtomicBasicBlock;
 use super::CaptureEvent;
@@ -41,9 +44,9 @@ use petgraph::Direction;
 
 use crate::time::clock::QemuClockObserver;
 use crate::time::clock::FUZZ_START_TIMESTAMP;
-use crate::time::worst::MaxTimeFavFactor;
+use crate::time::worst::SelectTaskFavFactor;
 use std::time::SystemTime;
-use std::{fs::OpenOptions, io::Write};
+use std::io::Write;
 use std::borrow::Cow;
 use std::ops::Deref;
 use std::ops::DerefMut;
@@ -89,11 +92,22 @@ where SYS: TargetSystem {
         label
     }
     fn get_hash(&self) -> u64 {
-        let mut s = DefaultHasher::new();
+        let mut s = stable_hasher();
         self.state.hash(&mut s);
         self.abb.hash(&mut s);
         s.finish()
     }
+    /// Hash of the system state, the key into [`STGFeedbackState::systemstate_index`]
+    pub fn state_hash(&self) -> u64 {
+        self.state
+    }
+    pub fn abb(&self) -> &AtomicBasicBlock {
+        &self.abb
+    }
+    /// Identifier derived from the same key as [`STGFeedbackState::state_abb_hash_index`], equal across runs and processes
+    pub fn stable_id(&self) -> String {
+        format!("n{:016x}_{:016x}", self.state, self.abb.get_hash())
+    }
 }
 impl<SYS> PartialEq for STGNode<SYS> 
 where
@@ -110,10 +124,46 @@ pub struct STGEdge
     pub event: CaptureEvent,
     pub name: Cow<'static, str>,
     pub worst: Option<(u64, Vec<(u32, u8)>)>,
+    /// Input which set `worst`, see [`STGEdge::worst_origin`]
+    #[serde(default)]
+    pub worst_origin: Option<WorstOrigin>,
+    /// Number of traversals over the whole campaign
+    #[serde(default)]
+    pub hit_count: u64,
+    /// Sum of the execution times of all traversals
+    #[serde(default)]
+    pub total_time: u64,
+    /// Value of [`STGFeedbackState::traces`] at the last traversal
+    #[serde(default)]
+    pub last_traversal: u64,
+}
+
+/// Input which set the worst time of an edge. Only ids are kept, the testcase may have been removed from the corpus since.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WorstOrigin {
+    /// Corpus id the input got, or would have gotten if it was not added
+    pub id: u64,
+    /// [`compute_hash`] of the input
+    pub input_hash: u64,
+    /// Wallclock time the worst time was set, in milliseconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl WorstOrigin {
+    pub fn new(id: u64, input_hash: u64) -> Self {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |x| x.as_millis() as u64);
+        Self { id, input_hash, timestamp }
+    }
 }
 
 impl STGEdge {
-    pub fn _pretty_print(&self) -> String {
+    /// Worst time of the edge and the input which set it, None for graphs dumped before origins were recorded
+    pub fn worst_origin(&self) -> Option<(u64, WorstOrigin)> {
+        Some((self.worst.as_ref()?.0, self.worst_origin?))
+    }
+
+    /// Event and name, identifying the edge among the edges between two nodes
+    pub fn label(&self) -> String {
         let mut short = match self.event {
             CaptureEvent::APIStart => "Call: ",
             CaptureEvent::APIEnd => "Ret: ",
@@ -125,8 +175,11 @@ impl STGEdge {
         short.push_str(&self.name);
         short
     }
+    pub fn _pretty_print(&self) -> String {
+        format!("{}\n{}x {}", self.label(), self.hit_count, self.total_time)
+    }
     pub fn color_print(&self) -> String {
-        let mut short = self.name.to_string();
+        let mut short = format!("{}\n{}x {}", self.name, self.hit_count, self.total_time);
         short.push_str(match self.event {
             CaptureEvent::APIStart => "\", color=\"blue",
             CaptureEvent::APIEnd => "\", color=\"black",
@@ -170,12 +223,33 @@ where
     exitpoint: NodeIndex,
     // Metadata about aggregated traces. aggegated meaning, order has been removed
     wort: u64,
+    /// Longest total execution, independent of the selected task
+    #[serde(default)]
+    worst_total: u64,
     wort_per_aggegated_path: HashMap<Vec<AtomicBasicBlock>,u64>,
     wort_per_abb_path: HashMap<u64,u64>,
     wort_per_stg_path: HashMap<u64,u64>,
     worst_abb_exec_count: HashMap<AtomicBasicBlock, usize>,
     // Metadata about job instances
     pub worst_task_jobs: HashMap<u64, RTOSTask>,
+    /// Number of nodes and edges at the last dot dump
+    #[serde(default)]
+    pub last_dump_size: (usize, usize),
+    /// Sum of [`STGEdge::hit_count`] over all edges
+    #[serde(default)]
+    edges_traversed: u64,
+    /// WORT of each task tracked by [`StgFeedback::with_tracked_tasks`]
+    #[serde(default)]
+    wort_per_task: HashMap<String, u64>,
+    /// Number of traces added to the graph
+    #[serde(default)]
+    traces: u64,
+    /// [`HASH_VERSION`] of the hashes in the graph and its indices, 0 for dumps written before it was recorded
+    #[serde(default)]
+    hash_version: u32,
+    /// Execution time statistics of each ABB over all traces, to tell input dependent ABBs from noisy ones
+    #[serde(default)]
+    pub abb_time_stats: HashMap<AtomicBasicBlock, AbbTimeStats>,
 }
 
 libafl_bolts::impl_serdeany!(STGFeedbackState<SYS: SerdeAny+TargetSystem>);
@@ -216,6 +290,7 @@ where
             entrypoint,
             exitpoint,
             wort: 0,
+            worst_total: 0,
             wort_per_aggegated_path: HashMap::new(),
             wort_per_abb_path: HashMap::new(),
             wort_per_stg_path: HashMap::new(),
@@ -223,6 +298,12 @@ where
             systemstate_index,
             state_abb_hash_index,
             worst_task_jobs: HashMap::new(),
+            last_dump_size: (0, 0),
+            edges_traversed: 0,
+            wort_per_task: HashMap::new(),
+            traces: 0,
+            hash_version: HASH_VERSION,
+            abb_time_stats: HashMap::new(),
         }
     }
 }
@@ -237,6 +318,152 @@ where
     }
 }
 
+impl<SYS> STGFeedbackState<SYS>
+where 
+    SYS: TargetSystem,
+{
+    /// Worst observed runtime in ticks, this is the WORT of the selected task if there is one
+    pub fn wort(&self) -> u64 {
+        self.wort
+    }
+
+    /// Worst observed total runtime in ticks
+    pub fn worst_total(&self) -> u64 {
+        self.worst_total
+    }
+
+    /// Number of edge traversals over the whole campaign
+    pub fn edges_traversed(&self) -> u64 {
+        self.edges_traversed
+    }
+
+    /// Renders the graph in dot format, see [`stg_to_dot`]
+    pub fn to_dot(&self, since: (usize, usize)) -> String {
+        stg_to_dot(&self.graph, |x| x.color_print(&self.systemstate_index), |x| x.color_print(), since)
+    }
+
+    /// WORT of each tracked task, see [`StgFeedback::with_tracked_tasks`]
+    pub fn wort_per_task(&self) -> &HashMap<String, u64> {
+        &self.wort_per_task
+    }
+
+    /// Number of traces added to the graph, the clock of [`STGEdge::last_traversal`]
+    pub fn traces(&self) -> u64 {
+        self.traces
+    }
+
+    /// Number of distinct aggregated abb paths
+    pub fn aggregated_path_count(&self) -> usize {
+        self.wort_per_aggegated_path.len()
+    }
+
+    /// Follows `edges`, e.g. the [`STGNodeMetadata::edges`] of a testcase, and returns each edge along with the node it leaves.
+    /// Edges which are not part of this graph are skipped.
+    pub fn reconstruct_path(&self, edges: &[EdgeIndex]) -> Vec<(STGNode<SYS>, STGEdge)> {
+        edges.iter().filter_map(|e| {
+            let (a, _) = self.graph.edge_endpoints(*e)?;
+            Some((self.graph[a].clone(), self.graph[*e].clone()))
+        }).collect()
+    }
+
+    /// Fails if the hashes of a deserialized graph were computed by another hasher than [`stable_hasher`], so its states and jobs would never match new traces
+    pub fn check_hash_version(&self) -> Result<(), Error> {
+        if self.hash_version == HASH_VERSION {
+            return Ok(());
+        }
+        let hint = if self.hash_version == 0 { "read it with a build with the feature legacy_hash" } else { "read it with the release that wrote it" };
+        Err(Error::illegal_state(format!("The STG has hash version {}, but this build uses hash version {}, {}", self.hash_version, HASH_VERSION, hint)))
+    }
+
+    /// Prepares a deserialized graph for further fuzzing.
+    /// The indices of a [`DiGraph`] survive serialization, so the observer map entries of the edges stay the same.
+    /// The node lookup tables are only rebuilt if they do not match the graph.
+    pub fn restore_indices(&mut self) {
+        let consistent = self.stgnode_index.iter().all(|(h, i)| self.graph.node_weight(*i).map_or(false, |n| n.get_hash() == *h))
+            && self.state_abb_hash_index.iter().all(|((s, a), i)| self.graph.node_weight(*i).map_or(false, |n| n.state == *s && n.abb.get_hash() == *a));
+        if !consistent {
+            eprintln!("Warning: STG node indices do not match the graph, rebuilding them");
+            self.stgnode_index = self.graph.node_indices().map(|i| (self.graph[i].get_hash(), i)).collect();
+            self.state_abb_hash_index = self.graph.node_indices().map(|i| ((self.graph[i].state, self.graph[i].abb.get_hash()), i)).collect();
+        }
+        let edges = self.graph.edge_count();
+        set_max_stg_num(usize::max(max_stg_num(), usize::min(edges, STG_MAP_SIZE)));
+        STG_MAP_COLLISIONS.set(usize::max(stg_map_collisions(), edges.saturating_sub(STG_MAP_SIZE)));
+    }
+
+    /// Nodes with an edge traversed by one of the last `traces` traces
+    pub fn recent_nodes(&self, traces: u64) -> HashSet<NodeIndex> {
+        let since = self.traces.saturating_sub(traces);
+        self.graph.edge_indices()
+            .filter(|e| self.graph[*e].last_traversal > since)
+            .flat_map(|e| {
+                let (a, b) = self.graph.edge_endpoints(e).unwrap();
+                [a, b]
+            })
+            .collect()
+    }
+
+    /// Removes all nodes for which `keep` returns false, along with their edges and the system states no remaining node refers to.
+    /// `keep` is called with the node, the number of its edges and the worst time of those edges. The entry and exit point are always kept.
+    /// Node and edge indices are compacted in order, so the edges stay consecutive. Everything that addresses the graph by index,
+    /// e.g. the [`STGNodeMetadata`] of the corpus, has to be translated with the returned [`STGPruning`].
+    pub fn prune(&mut self, keep: impl Fn(&STGNode<SYS>, usize, Option<u64>) -> bool) -> STGPruning {
+        let kept: HashSet<NodeIndex> = self.graph.node_indices().filter(|i| {
+            if *i == self.entrypoint || *i == self.exitpoint {
+                return true;
+            }
+            let (degree, worst) = self.graph.edges_directed(*i, Direction::Incoming)
+                .chain(self.graph.edges_directed(*i, Direction::Outgoing))
+                .fold((0, None), |(d, w), e| (d + 1, w.max(e.weight().worst.as_ref().map(|x| x.0))));
+            keep(&self.graph[*i], degree, worst)
+        }).collect();
+        let mut nodes = HashMap::new();
+        for i in self.graph.node_indices().filter(|x| kept.contains(x)) {
+            nodes.insert(i, NodeIndex::new(nodes.len()));
+        }
+        let mut edges = HashMap::new();
+        for e in self.graph.edge_indices() {
+            let (a, b) = self.graph.edge_endpoints(e).unwrap();
+            if kept.contains(&a) && kept.contains(&b) {
+                edges.insert(e, EdgeIndex::new(edges.len()));
+            }
+        }
+        let removed_nodes = self.graph.node_count() - nodes.len();
+        let removed_edges = self.graph.edge_count() - edges.len();
+        // filter_map keeps the order of the remaining nodes and edges, which the maps above rely on
+        self.graph = self.graph.filter_map(|i, n| kept.contains(&i).then(|| n.clone()), |_, e| Some(e.clone()));
+        self.entrypoint = nodes[&self.entrypoint];
+        self.exitpoint = nodes[&self.exitpoint];
+        self.stgnode_index = self.graph.node_indices().map(|i| (self.graph[i].get_hash(), i)).collect();
+        self.state_abb_hash_index = self.graph.node_indices().map(|i| ((self.graph[i].state, self.graph[i].abb.get_hash()), i)).collect();
+        let states: HashSet<u64> = self.graph.node_weights().map(|x| x.state).collect();
+        let removed_states = self.systemstate_index.len();
+        self.systemstate_index.retain(|k, _| states.contains(k));
+        let removed_states = removed_states - self.systemstate_index.len();
+        // the indices of earlier dumps are gone, the next dump starts over
+        self.last_dump_size = (0, 0);
+        STG_MAP_COLLISIONS.set(self.graph.edge_count().saturating_sub(STG_MAP_SIZE));
+        STGPruning { nodes, edges, removed_nodes, removed_edges, removed_states }
+    }
+}
+
+/// Result of [`STGFeedbackState::prune`], maps the indices of the remaining nodes and edges from before to after the pruning
+#[derive(Debug, Default, Clone)]
+pub struct STGPruning {
+    pub nodes: HashMap<NodeIndex, NodeIndex>,
+    pub edges: HashMap<EdgeIndex, EdgeIndex>,
+    pub removed_nodes: usize,
+    pub removed_edges: usize,
+    pub removed_states: usize,
+}
+
+impl STGPruning {
+    /// Translates an observer map index, None if the edge was removed
+    pub fn map_index(&self, index: usize) -> Option<usize> {
+        self.edges.get(&EdgeIndex::new(index)).map(|x| x.index())
+    }
+}
+
 // Wrapper around Vec<RefinedFreeRTOSSystemState> to attach as Metadata
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct STGNodeMetadata {
@@ -303,6 +530,20 @@ impl STGNodeMetadata {
     pub fn jobs(&self) -> &Vec<RTOSJob> {
         &self.jobs
     }
+
+    /// Translates the path to the graph after a [`STGFeedbackState::prune`].
+    /// The path is cut before its first removed node or edge, so that nodes and edges stay aligned.
+    pub fn remap(&mut self, pruning: &STGPruning) {
+        self.nodes = self.nodes.iter().map_while(|x| pruning.nodes.get(x).copied()).collect();
+        self.edges = self.edges.iter().map_while(|x| pruning.edges.get(x).copied()).collect();
+        self.edges.truncate(self.nodes.len().saturating_sub(1));
+        #[cfg(feature = "sched_stg_edge")]
+        {
+            self.indices = self.edges.iter().map(|x| x.index()).collect();
+            self.indices.sort_unstable();
+            self.indices.dedup();
+        }
+    }
 }
 
 impl Deref for STGNodeMetadata {
@@ -332,8 +573,9 @@ impl HasRefCnt for STGNodeMetadata {
 
 libafl_bolts::impl_serdeany!(STGNodeMetadata);
 
+/// Ranks by execution time, or by the response time of the task given to [`crate::time::worst::set_favored_task`]
 pub type GraphMaximizerCorpusScheduler<CS, O> =
-    MinimizerScheduler<CS, MaxTimeFavFactor,STGNodeMetadata,O>;
+    MinimizerScheduler<CS, SelectTaskFavFactor,STGNodeMetadata,O>;
 
 // AI generated, human verified
 /// Count the occurrences of each element in a vector, assumes the vector is sorted
@@ -366,13 +608,129 @@ where
     counts
 }
 
+//============================= Dot export
+
+/// Renders an STG in dot format, naming nodes by [`STGNode::stable_id`].
+/// Only nodes and edges with an index of at least `since` (nodes, edges) are written, nodes from earlier dumps are only referenced.
+/// Nodes and edges are only removed by [`STGFeedbackState::prune`], which resets the dump size, and `(0, 0)` yields the whole graph.
+pub fn stg_to_dot<SYS>(
+    graph: &DiGraph<STGNode<SYS>, STGEdge>,
+    node_label: impl Fn(&STGNode<SYS>) -> String,
+    edge_label: impl Fn(&STGEdge) -> String,
+    since: (usize, usize),
+) -> String
+where
+    SYS: TargetSystem,
+{
+    let escape = |x: String| x.replace('\n', "\\n").replace(';', "\\n");
+    let mut out = String::from("digraph {\n");
+    for i in graph.node_indices().filter(|x| x.index() >= since.0) {
+        let node = &graph[i];
+        out.push_str(&format!("    {} [ label = \"{}\" ]\n", node.stable_id(), escape(node_label(node))));
+    }
+    for e in graph.edge_indices().filter(|x| x.index() >= since.1) {
+        let (a, b) = graph.edge_endpoints(e).unwrap();
+        out.push_str(&format!(
+            "    {} -> {} [ label = \"{}\" ]\n",
+            graph[a].stable_id(),
+            graph[b].stable_id(),
+            escape(edge_label(&graph[e]))
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
 //============================= Graph Feedback
 
 pub const STG_MAP_SIZE: usize = 1<<20;
-pub static mut STG_MAP: [u16; STG_MAP_SIZE] = [0; STG_MAP_SIZE];
-pub static mut MAX_STG_NUM: usize = 0;
+const STG_MAP_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Observer maps of the client running on this thread, so several clients in one process never fill each other's maps.
+/// The maps are leaked, the observers keep pointers to them until the process exits. Every thread which touches them leaks about 2 MiB,
+/// so clients have to run on threads which live as long as the process, as those of the launcher do.
+/// Only the feedbacks write them and only the observers read them, both on the thread of the client.
+struct ObserverMaps {
+    /// Edge counts of the last trace, see [`set_observer_map`]
+    stg: *mut u16,
+    /// Number of used entries of `stg`, the length of the observer over it
+    stg_len: *mut usize,
+    /// See [`set_abb_map`]
+    abb: *mut u8,
+}
+
+thread_local! {
+    static OBSERVER_MAPS: ObserverMaps = ObserverMaps {
+        stg: Box::leak(vec![0u16; STG_MAP_SIZE].into_boxed_slice()).as_mut_ptr(),
+        stg_len: Box::leak(Box::new(0usize)),
+        abb: Box::leak(vec![0u8; ABB_MAP_SIZE].into_boxed_slice()).as_mut_ptr(),
+    };
+    /// Number of edge indices beyond [`STG_MAP_SIZE`], which share an entry with another edge
+    static STG_MAP_COLLISIONS: Cell<usize> = const { Cell::new(0) };
+    static STG_MAP_LAST_WARNING: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// The STG map of this thread, the caller must not keep the slice across the execution of the observer
+unsafe fn stg_map<'a>() -> &'a mut [u16] {
+    OBSERVER_MAPS.with(|m| std::slice::from_raw_parts_mut(m.stg, STG_MAP_SIZE))
+}
+
 pub unsafe fn stg_map_mut_slice<'a>() -> OwnedMutSlice<'a, u16> {
-    OwnedMutSlice::from_raw_parts_mut(STG_MAP.as_mut_ptr(), STG_MAP.len())
+    OBSERVER_MAPS.with(|m| OwnedMutSlice::from_raw_parts_mut(m.stg, STG_MAP_SIZE))
+}
+
+/// Length of the STG map of this thread, for the [`libafl::observers::VariableMapObserver`] over [`stg_map_mut_slice`]
+pub fn max_stg_num_ptr() -> *mut usize {
+    OBSERVER_MAPS.with(|m| m.stg_len)
+}
+
+/// Number of used entries of the STG map of this thread
+pub fn max_stg_num() -> usize {
+    unsafe { *max_stg_num_ptr() }
+}
+
+fn set_max_stg_num(num: usize) {
+    unsafe { *max_stg_num_ptr() = num; }
+}
+
+/// Number of edges of this thread's graph sharing an entry of the STG map with another edge
+pub fn stg_map_collisions() -> usize {
+    STG_MAP_COLLISIONS.get()
+}
+
+//============================= ABB Coverage
+
+pub const ABB_MAP_SIZE: usize = 1<<16;
+
+/// AFL-style hit counts of the ABBs executed in the last run on this thread, indexed by [`abb_map_index`]
+pub unsafe fn abb_map_mut_slice<'a>() -> OwnedMutSlice<'a, u8> {
+    OBSERVER_MAPS.with(|m| OwnedMutSlice::from_raw_parts_mut(m.abb, ABB_MAP_SIZE))
+}
+
+/// Map observer over [`abb_map_mut_slice`], which is filled by [`StgFeedback`]
+pub type AbbCoverageObserver<'a> = StdMapObserver<'a, u8, false>;
+
+pub fn abb_coverage_observer<'a>() -> AbbCoverageObserver<'a> {
+    unsafe { StdMapObserver::from_mut_slice("abb", abb_map_mut_slice()) }
+}
+
+/// Entry of an ABB in the ABB map, by start address and level
+pub fn abb_map_index(abb: &AtomicBasicBlock) -> usize {
+    get_generic_hash(&(abb.get_start(), abb.get_level())) as usize % ABB_MAP_SIZE
+}
+
+/// Fills the ABB map of this thread with the number of executed instances of each ABB in `intervals`, saturating at 255
+pub fn set_abb_map(intervals: &[ExecInterval]) {
+    let mut seen = HashSet::new();
+    let map = unsafe { OBSERVER_MAPS.with(|m| std::slice::from_raw_parts_mut(m.abb, ABB_MAP_SIZE)) };
+    map.fill(0);
+    for abb in intervals.iter().filter_map(|x| x.abb.as_ref()) {
+        // preempted instances are split over several intervals
+        if seen.insert((abb.get_start(), abb.get_level(), abb.get_instance_id())) {
+            let slot = abb_map_index(abb);
+            map[slot] = map[slot].saturating_add(1);
+        }
+    }
 }
 
 /// A Feedback reporting novel System-State Transitions. Depends on [`QemuSystemStateObserver`]
@@ -394,6 +752,8 @@ where
     last_job_trace: Option<Vec<RTOSJob>>, // only set, if it was interesting
     dump_path: Option<PathBuf>,
     select_task: Option<String>,
+    #[serde(default)]
+    tracked_tasks: Vec<String>,
     _phantom_data: PhantomData<SYS>,
 }
 #[cfg(feature = "feed_stg")]
@@ -430,26 +790,39 @@ pub const INTEREST_JOB_RT : bool = false;
 #[cfg(not(feature = "feed_job_woet"))]
 pub const INTEREST_JOB_ET : bool = false;
 
-fn set_observer_map(trace : &Vec<EdgeIndex>) {
+/// Fills the observer map with the edge counts of a trace.
+/// Edges are numbered consecutively, so once the graph has more than [`STG_MAP_SIZE`] edges the surplus indices are folded into the map.
+/// Returns true if the number of colliding edges grew.
+fn set_observer_map(trace : &Vec<EdgeIndex>) -> bool {
     // dbg!(trace);
-    unsafe {
-        for i in 0..MAX_STG_NUM {
-            STG_MAP[i] = 0;
-        }
-        for i in trace {
-            if MAX_STG_NUM < i.index() {
-                MAX_STG_NUM = i.index();
-            }
-            STG_MAP[i.index()] = STG_MAP[i.index()].saturating_add(1);
-        }
+    let map = unsafe { stg_map() };
+    map[..max_stg_num()].fill(0);
+    let mut max_index = None;
+    for i in trace {
+        max_index = max_index.max(Some(i.index()));
+        let slot = i.index() % STG_MAP_SIZE;
+        map[slot] = map[slot].saturating_add(1);
     }
+    let Some(max_index) = max_index else {
+        return false;
+    };
+    set_max_stg_num(usize::max(max_stg_num(), usize::min(max_index + 1, STG_MAP_SIZE)));
+    if max_index < STG_MAP_SIZE + stg_map_collisions() {
+        return false;
+    }
+    STG_MAP_COLLISIONS.set(max_index + 1 - STG_MAP_SIZE);
+    if STG_MAP_LAST_WARNING.get().map_or(true, |x| x.elapsed() > STG_MAP_WARNING_INTERVAL) {
+        STG_MAP_LAST_WARNING.set(Some(Instant::now()));
+        eprintln!("Warning: the STG has more than {} edges, {} edges share an observer map entry", STG_MAP_SIZE, stg_map_collisions());
+    }
+    true
 }
 
 fn get_generic_hash<H>(input: &H) -> u64
     where
         H: Hash,
 {
-    let mut s = DefaultHasher::new();
+    let mut s = stable_hasher();
     input.hash(&mut s);
     s.finish()
 }
@@ -489,6 +862,13 @@ where
         s
     }
 
+    /// Also consider an input interesting if it increases the WORT of any of `tasks`
+    #[must_use]
+    pub fn with_tracked_tasks(mut self, tasks: Vec<String>) -> Self {
+        self.tracked_tasks = tasks;
+        self
+    }
+
     /// params:
     /// tarce of intervals
     /// hashtable of states
@@ -498,7 +878,8 @@ where
     /// newly discovered node?
     /// side effect:
     /// the graph gets new nodes and edge
-    fn update_stg_interval(trace: &Vec<ExecInterval>, read_trace: &Vec<Vec<(u32, u8)>>, table: &HashMap<u64, SYS::State>, fbs: &mut STGFeedbackState<SYS>) -> (Vec<(NodeIndex, u64)>, Vec<(EdgeIndex, u64)>, bool, bool) {
+    /// `origin` is recorded with each worst time set by the trace
+    pub fn update_stg_interval(trace: &Vec<ExecInterval>, read_trace: &Vec<Vec<(u32, u8)>>, table: &HashMap<u64, SYS::State>, fbs: &mut STGFeedbackState<SYS>, origin: WorstOrigin) -> (Vec<(NodeIndex, u64)>, Vec<(EdgeIndex, u64)>, bool, bool) {
         let mut return_node_trace = vec![(fbs.entrypoint, 0)]; // Assuming entrypoint timestamp is 0
         let mut return_edge_trace = vec![];
         let mut interesting = false;
@@ -507,6 +888,7 @@ where
             return (return_node_trace, return_edge_trace, interesting, updated);
         }
         let mut instance_time = execinterval_to_abb_instances(trace, read_trace);
+        add_abb_time_stats(&mut fbs.abb_time_stats, trace);
         // add all missing state+abb combinations to the graph
         for (_i,interval) in trace.iter().enumerate() { // Iterate intervals
             let start_s = table[&interval.start_state].clone();
@@ -532,27 +914,39 @@ where
             // connect in graph if edge not present
             let e = fbs.graph.edges_directed(return_node_trace[return_node_trace.len()-1].0, Direction::Outgoing).find(|x| petgraph::visit::EdgeRef::target(x) == next_idx);
             if let Some(e_) = e {
-                return_edge_trace.push((petgraph::visit::EdgeRef::id(&e_), interval.start_tick));
+                let e_ = e_.id();
+                return_edge_trace.push((e_, interval.start_tick));
+                let weight = fbs.graph.edge_weight_mut(e_).unwrap();
+                weight.hit_count += 1;
+                weight.last_traversal = fbs.traces + 1;
                 if let Some((time, accesses)) = instance_time.get_mut(&interval.abb.as_ref().unwrap().instance_id) {
-                    let ref_ = &mut fbs.graph.edge_weight_mut(e_.id()).unwrap().worst;
+                    weight.total_time += *time;
+                    let ref_ = &mut weight.worst;
                     if ref_.is_some() {
                         let w = ref_.as_mut().unwrap();
                         if w.0 < *time {
                             *w = (*time, accesses.clone());
+                            weight.worst_origin = Some(origin);
                             interesting |= INTEREST_EDGE_WEIGHT;
                         };
                     } else {
                         *ref_ = Some((*time, accesses.clone()));
+                        weight.worst_origin = Some(origin);
                     }
                 }
             } else {
-                let mut e__ = STGEdge{event: interval.start_capture.0, name: interval.start_capture.1.clone(), worst: None};
+                let mut e__ = STGEdge{event: interval.start_capture.0, name: interval.start_capture.1.clone(), worst: None, worst_origin: None, hit_count: 1, total_time: 0, last_traversal: fbs.traces + 1};
                 if e__.is_abb_end() {
                     if let Some((time,accesses)) = instance_time.get_mut(&interval.abb.as_ref().unwrap().instance_id) {
                         e__.worst = Some((*time, accesses.clone()));
+                        e__.worst_origin = Some(origin);
+                        e__.total_time = *time;
                     }
                 }
                 let e_ = fbs.graph.add_edge(return_node_trace[return_node_trace.len()-1].0, next_idx, e__);
+                // The observer map and the scheduler metadata address edges by index. This only holds while edges are appended and removed only by prune,
+                // which is also what keeps the indices intact when the feedback state is serialized.
+                debug_assert_eq!(e_.index(), fbs.graph.edge_count() - 1, "STG edge indices are not consecutive");
                 return_edge_trace.push((e_, interval.start_tick));
                 interesting |= INTEREST_EDGE;
                 updated = true;
@@ -560,10 +954,17 @@ where
             return_node_trace.push((next_idx, interval.start_tick));
         }
         // every path terminates at the end
-        if !fbs.graph.neighbors_directed(return_node_trace[return_node_trace.len()-1].0, Direction::Outgoing).any(|x| x == fbs.exitpoint) {
-            let mut e__ = STGEdge { event: CaptureEvent::End, name: Cow::Borrowed("End"), worst: None };
+        let last_time = instance_time.get(&trace[trace.len()-1].abb.as_ref().unwrap().instance_id).map_or(0, |x| x.0);
+        if let Some(e_) = fbs.graph.find_edge(return_node_trace[return_node_trace.len()-1].0, fbs.exitpoint) {
+            let weight = fbs.graph.edge_weight_mut(e_).unwrap();
+            weight.hit_count += 1;
+            weight.total_time += last_time;
+            weight.last_traversal = fbs.traces + 1;
+        } else {
+            let mut e__ = STGEdge { event: CaptureEvent::End, name: Cow::Borrowed("End"), worst: None, worst_origin: None, hit_count: 1, total_time: last_time, last_traversal: fbs.traces + 1 };
             if let Some((time, accesses)) = instance_time.get_mut(&trace[trace.len()-1].abb.as_ref().unwrap().instance_id) {
                 e__.worst = Some((*time, accesses.clone()));
+                e__.worst_origin = Some(origin);
             }
             let e_ = fbs.graph.add_edge(return_node_trace[return_node_trace.len()-1].0, fbs.exitpoint, e__);
             return_edge_trace.push((e_, trace[trace.len()-1].start_tick));
@@ -571,6 +972,8 @@ where
             updated = true;
         }
         return_node_trace.push((fbs.exitpoint, trace[trace.len()-1].start_tick));
+        fbs.edges_traversed += trace.len() as u64 + 1;
+        fbs.traces += 1;
         (return_node_trace, return_edge_trace, interesting, updated)
     }
 
@@ -593,11 +996,12 @@ where
 
 impl<EM, I, OT, S, SYS> Feedback<EM, I, OT, S> for StgFeedback<SYS>
 where
-    S: State + UsesInput + MaybeHasClientPerfMonitor + HasNamedMetadata + HasMetadata,
+    S: State + UsesInput + MaybeHasClientPerfMonitor + HasNamedMetadata + HasMetadata + HasCorpus,
     S::Input: Default,
     EM: EventFirer<State = S>,
     OT: ObserversTuple<I, S>,
     SYS: TargetSystem,
+    I: Hash,
 {
     #[allow(clippy::wrong_self_convention)]
     fn is_interesting(
@@ -616,6 +1020,8 @@ where
         let clock_observer = observers.match_name::<QemuClockObserver<SYS>>("clocktime")
             .expect("QemuClockObserver not found");
         let last_runtime = clock_observer.last_runtime();
+        // the clock observer measures the selected task if there is one
+        let total_runtime = if self.select_task.is_some() {trace.intervals().last().map_or(0, |x| x.end_tick)} else {last_runtime};
 
         #[cfg(feature = "trace_job_response_times")]
         let worst_jobs_rt = trace.worst_jobs_per_task_by_response_time();
@@ -626,18 +1032,32 @@ where
         #[cfg(feature = "trace_job_response_times")]
         let last_runtime = if let Some(t) = self.select_task.as_ref() {worst_select_job.map_or(0, |x| x.response_time())} else {last_runtime};
 
+        // the id the input gets if it is added to the corpus
+        let origin = WorstOrigin::new(state.corpus().peek_free_id().0 as u64, compute_hash(_input));
         let feedbackstate = state.metadata_map_mut().get_or_insert_with(||{
                 STGFeedbackState::<SYS>::default()
             });
 
         // --------------------------------- Update STG
-        let (mut nodetrace, mut edgetrace, mut interesting, mut updated) = StgFeedback::update_stg_interval(trace.intervals(), &trace.mem_reads(), trace.states_map(), feedbackstate);
+        let (mut nodetrace, mut edgetrace, mut interesting, mut updated) = StgFeedback::update_stg_interval(trace.intervals(), &trace.mem_reads(), trace.states_map(), feedbackstate, origin);
+
+        feedbackstate.worst_total = u64::max(feedbackstate.worst_total, total_runtime);
 
         // the longest running case is always intersting
         if last_runtime > feedbackstate.wort {
             feedbackstate.wort = last_runtime;
             interesting |= true;
         }
+        // as is a new worst response of any tracked task
+        #[cfg(feature = "trace_job_response_times")]
+        for task in self.tracked_tasks.iter() {
+            let rt = worst_jobs_rt.get(task).map_or(0, |x| x.response_time());
+            let wort = feedbackstate.wort_per_task.entry(task.clone()).or_insert(0);
+            if rt > *wort {
+                *wort = rt;
+                interesting = true;
+            }
+        }
 
         #[cfg(feature = "trace_job_response_times")]
         if let Some(worst_instance) = worst_select_job {
@@ -651,7 +1071,10 @@ where
         }
 
         #[cfg(feature = "feed_stg")]
-        set_observer_map(&edgetrace.iter().map(|x| x.0).collect::<Vec<_>>());
+        let map_collisions_grew = set_observer_map(&edgetrace.iter().map(|x| x.0).collect::<Vec<_>>());
+        #[cfg(not(feature = "feed_stg"))]
+        let map_collisions_grew = false;
+        set_abb_map(trace.intervals());
 
         // --------------------------------- Update job instances
         #[cfg(feature = "trace_job_response_times")]
@@ -771,17 +1194,25 @@ where
         if let Some(dp) = &self.dump_path {
             if updated {
                 let timestamp = SystemTime::now().duration_since(unsafe {FUZZ_START_TIMESTAMP}).unwrap().as_millis();
-                let mut file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .append(true)
-                    .open(dp).expect("Could not open stgsize");
-                    writeln!(file, "{},{},{},{},{}", feedbackstate.graph.edge_count(), feedbackstate.graph.node_count(), feedbackstate.wort_per_aggegated_path.len(),feedbackstate.wort_per_stg_path.len(), timestamp).expect("Write to dump failed");
+                let mut file = crate::dump::append_dump(dp).expect("Could not open stgsize");
+                    writeln!(file, "{},{},{},{},{},{}", feedbackstate.graph.edge_count(), feedbackstate.graph.node_count(), feedbackstate.wort_per_aggegated_path.len(),feedbackstate.wort_per_stg_path.len(), timestamp, feedbackstate.edges_traversed()).expect("Write to dump failed");
             }
         }
         // Re-add trace data
         state.add_metadata(trace);
+        if map_collisions_grew {
+            _manager.fire(
+                state,
+                Event::UpdateUserStats {
+                    name: Cow::from("stg_map_collisions"),
+                    value: UserStats::new(
+                        UserStatsValue::Number(stg_map_collisions() as u64),
+                        AggregatorOps::Max,
+                    ),
+                    phantom: PhantomData,
+                },
+            )?;
+        }
         Ok(interesting)
     }
 
@@ -807,4 +1238,144 @@ where
     fn name(&self) -> &Cow<'static, str> {
         &self.name
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systemstate::feedbacks::{guest_exit, set_guest_exit, GuestExit, GuestExitMetadata};
+    use crate::systemstate::target_os::mock::{MockSystem, MockTraceBuilder, MockTraceMetadata, MOCK_TASK_START_ISR};
+    use libafl_qemu::GuestAddr;
+
+    fn interval_of(start: u32, instance_id: usize) -> ExecInterval {
+        ExecInterval { abb: Some(AtomicBasicBlock { start: start as _, instance_id, ..Default::default() }), ..Default::default() }
+    }
+
+    /// The ABB map of this thread
+    fn abb_map() -> Vec<u8> {
+        OBSERVER_MAPS.with(|m| unsafe { std::slice::from_raw_parts(m.abb, ABB_MAP_SIZE) }.to_vec())
+    }
+
+    #[test]
+    fn observer_maps_are_per_thread() {
+        let main_abb = interval_of(0x100, 0);
+        set_abb_map(&[main_abb.clone()]);
+        set_max_stg_num(3);
+        unsafe { stg_map() }[0] = 7;
+
+        let other_abb = interval_of(0x200, 0);
+        let other = other_abb.clone();
+        let (fresh, other_map, other_len) = std::thread::spawn(move || {
+            // a second client starts with empty maps
+            let fresh = max_stg_num() == 0 && abb_map().iter().all(|x| *x == 0) && unsafe { stg_map() }.iter().all(|x| *x == 0);
+            set_abb_map(&[other.clone(), interval_of(0x200, 1)]);
+            set_max_stg_num(5);
+            (fresh, abb_map(), max_stg_num())
+        })
+        .join()
+        .unwrap();
+        assert!(fresh);
+        assert_eq!(other_len, 5);
+        let main_slot = abb_map_index(main_abb.abb.as_ref().unwrap());
+        let other_slot = abb_map_index(other_abb.abb.as_ref().unwrap());
+        assert_ne!(main_slot, other_slot);
+        assert_eq!((other_map[main_slot], other_map[other_slot]), (0, 2));
+
+        // the other thread did not touch the maps of this one
+        let map = abb_map();
+        assert_eq!((map[main_slot], map[other_slot]), (1, 0));
+        assert_eq!(max_stg_num(), 3);
+        assert_eq!(unsafe { stg_map() }[0], 7);
+    }
+
+    #[test]
+    fn edges_beyond_the_map_are_folded() {
+        // run on a fresh thread, so the maps and the collision count start empty
+        std::thread::spawn(|| {
+            let edges = |x: &[usize]| x.iter().map(|i| EdgeIndex::new(*i)).collect::<Vec<_>>();
+            assert!(!set_observer_map(&edges(&[0, 3, 3])));
+            assert_eq!((max_stg_num(), stg_map_collisions()), (4, 0));
+            assert_eq!(&unsafe { stg_map() }[..4], &[1, 0, 0, 2]);
+
+            // edge STG_MAP_SIZE+3 shares the entry of edge 3
+            assert!(set_observer_map(&edges(&[1, 3, STG_MAP_SIZE + 3])));
+            assert_eq!((max_stg_num(), stg_map_collisions()), (STG_MAP_SIZE, 4));
+            let map = unsafe { stg_map() };
+            assert_eq!(&map[..4], &[0, 1, 0, 2]);
+            assert!(map[4..].iter().all(|x| *x == 0));
+
+            // the collision count only grows with new edges
+            assert!(!set_observer_map(&edges(&[STG_MAP_SIZE + 1])));
+            assert_eq!(stg_map_collisions(), 4);
+            assert_eq!(&unsafe { stg_map() }[..4], &[0, 1, 0, 0]);
+            assert!(set_observer_map(&edges(&[2 * STG_MAP_SIZE])));
+            assert_eq!(stg_map_collisions(), STG_MAP_SIZE + 1);
+            assert_eq!(unsafe { stg_map() }[0], 1);
+
+            // an empty trace clears the map but keeps the counts
+            assert!(!set_observer_map(&Vec::new()));
+            assert!(unsafe { stg_map() }.iter().all(|x| *x == 0));
+            assert_eq!((max_stg_num(), stg_map_collisions()), (STG_MAP_SIZE, STG_MAP_SIZE + 1));
+        })
+        .join()
+        .unwrap();
+    }
+
+    /// T1 and T2 take turns `rounds` times, each ABB starts at an offset from `base`
+    fn trace_at(base: GuestAddr, rounds: u64) -> MockTraceMetadata {
+        let mut trace = MockTraceBuilder::new()
+            .task("T1", 2)
+            .task("T2", 1)
+            .running("T1").ready(&["T2"])
+            .capture(0, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0, base));
+        for r in 0..rounds {
+            let t = 100 * r;
+            trace = trace
+                .capture(t + 10, CaptureEvent::APIStart, "vTaskDelay", (base + 0x10, base + 0x400))
+                .running("T2").ready(&[]).delayed(&["T1"])
+                .capture(t + 20, CaptureEvent::APIEnd, "vTaskDelay", (base + 0x410, base + 0x100))
+                .capture(t + 50, CaptureEvent::APIStart, "vTaskDelay", (base + 0x110, base + 0x400))
+                .running("T1").delayed(&["T2"])
+                .capture(t + 55, CaptureEvent::APIEnd, "vTaskDelay", (base + 0x410, base + 0x14));
+        }
+        trace.capture(100 * rounds + 70, CaptureEvent::End, "trigger_Qemu_break", (base + 0x20, 0)).build()
+    }
+
+    /// One client executing the mock target `executions` times, as its executor and feedbacks would: the harness reports the exit,
+    /// the feedback updates the STG and fills the observer maps, which the observers read back.
+    fn client(trace: &MockTraceMetadata, pc: u32, executions: usize, start: &std::sync::Barrier) -> usize {
+        let mut fbs = STGFeedbackState::<MockSystem>::default();
+        let exit = GuestExitMetadata { exit: GuestExit::Done, pc };
+        let abbs: HashSet<usize> = trace.intervals().iter().filter_map(|x| x.abb.as_ref()).map(abb_map_index).collect();
+        start.wait();
+        for _ in 0..executions {
+            set_guest_exit(exit);
+            let (_, edges, _, _) = StgFeedback::update_stg_interval(trace.intervals(), trace.mem_reads(), trace.states_map(), &mut fbs, WorstOrigin::default());
+            let edges: Vec<EdgeIndex> = edges.into_iter().map(|x| x.0).collect();
+            set_observer_map(&edges);
+            set_abb_map(trace.intervals());
+            std::thread::yield_now();
+
+            assert_eq!(guest_exit(), exit);
+            let mut expected = vec![0u16; max_stg_num()];
+            edges.iter().for_each(|e| expected[e.index()] += 1);
+            assert_eq!(&unsafe { stg_map() }[..max_stg_num()], &expected[..]);
+            assert!(abb_map().iter().enumerate().all(|(i, x)| (*x > 0) == abbs.contains(&i)));
+        }
+        fbs.graph.edge_count()
+    }
+
+    #[test]
+    fn two_clients_execute_concurrently() {
+        let traces = [trace_at(0x1000, 1), trace_at(0x8000, 3)];
+        let start = std::sync::Barrier::new(2);
+        let edges = std::thread::scope(|s| {
+            let clients: Vec<_> = traces.iter().zip([1, 2]).map(|(t, pc)| s.spawn(|| client(t, pc, 200, &start))).collect();
+            clients.into_iter().map(|x| x.join().unwrap()).collect::<Vec<_>>()
+        });
+        // each graph only holds the edges of its own client
+        assert!(edges[0] > 0 && edges[1] > 0);
+        let alone = std::thread::scope(|s| s.spawn(|| client(&traces[0], 1, 1, &std::sync::Barrier::new(1))).join().unwrap());
+        assert_eq!(edges[0], alone);
+    }
+}
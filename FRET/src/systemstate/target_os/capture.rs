@@ -0,0 +1,274 @@
+//! Per-execution capture buffers of the system state helpers.
+//! Each helper owns a [`CaptureBuffer`] which its QEMU hooks reach through the module tuple,
+//! so two emulators in one process never write into the same trace.
+
+use std::ops::Range;
+
+use libafl_qemu::GuestAddr;
+
+use crate::systemstate::helpers::get_icount;
+
+/// What the capture hooks read from the emulator, see [`CaptureBuffer::on_read`]
+pub trait CaptureHost {
+    /// Instructions executed so far
+    fn icount(&self) -> u64;
+    /// Byte of guest memory at `addr`
+    fn read_byte(&self, addr: GuestAddr) -> u8;
+}
+
+impl CaptureHost for libafl_qemu::Qemu {
+    fn icount(&self) -> u64 {
+        get_icount(self)
+    }
+
+    fn read_byte(&self, addr: GuestAddr) -> u8 {
+        let mut buf: [u8; 1] = [0];
+        unsafe {
+            let _ = self.read_mem(addr, &mut buf);
+        }
+        buf[0]
+    }
+}
+
+/// Limits beyond which the states of an execution are no longer captured, see [`CaptureBuffer::accept`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraceLimits {
+    /// Maximum number of raw states per execution
+    pub max_states: Option<usize>,
+    /// Maximum ticks between the first and the last captured state
+    pub max_ticks: Option<u64>,
+}
+
+/// Data collected by the hooks of a helper during one execution
+#[derive(Debug, Clone)]
+pub struct CaptureBuffer<R> {
+    /// Input memory, only reads from this range are recorded
+    pub input_mem: Range<GuestAddr>,
+    /// Raw system states in the order of capture
+    pub states: Vec<R>,
+    /// Input reads as (address, value) since the last captured state
+    pub mem_reads: Vec<(GuestAddr, u8)>,
+    /// Job completions as (icount, task name)
+    pub jobs_done: Vec<(u64, String)>,
+    pub limits: TraceLimits,
+    /// States were dropped by the limits or the target ran out of input, so the trace is incomplete
+    pub truncated: bool,
+    /// Task names of `jobs_done` which were not utf8
+    pub invalid_task_names: usize,
+    first_tick: Option<u64>,
+}
+
+impl<R> CaptureBuffer<R> {
+    #[must_use]
+    pub fn new(input_mem: Range<GuestAddr>) -> Self {
+        Self {
+            input_mem,
+            states: Vec::new(),
+            mem_reads: Vec::new(),
+            jobs_done: Vec::new(),
+            limits: TraceLimits::default(),
+            truncated: false,
+            invalid_task_names: 0,
+            first_tick: None,
+        }
+    }
+
+    /// Forget everything captured by the previous execution
+    pub fn clear(&mut self) {
+        self.states.clear();
+        self.mem_reads.clear();
+        self.jobs_done.clear();
+        self.truncated = false;
+        self.invalid_task_names = 0;
+        self.first_tick = None;
+    }
+
+    /// Whether a state captured at `tick` fits the limits, marks the trace as truncated otherwise
+    pub fn accept(&mut self, tick: u64) -> bool {
+        let first = *self.first_tick.get_or_insert(tick);
+        let fits = self.limits.max_states.map_or(true, |m| self.states.len() < m)
+            && self.limits.max_ticks.map_or(true, |m| tick.saturating_sub(first) <= m);
+        self.truncated |= !fits;
+        fits
+    }
+
+    /// Marks the trace as truncated if the input counter of the target reached the end of the input, all later reads returned zeros
+    pub fn check_input_counter(&mut self, counter: u32) {
+        if counter as u64 >= (self.input_mem.end - self.input_mem.start) as u64 {
+            self.truncated = true;
+        }
+    }
+
+    /// Body of the read hooks, records the byte read at `addr` if it belongs to the input
+    pub fn on_read(&mut self, host: &impl CaptureHost, addr: GuestAddr) {
+        if self.input_mem.contains(&addr) {
+            self.mem_reads.push((addr, host.read_byte(addr)));
+        }
+    }
+
+    /// Body of the job done hooks, records the completion of a job of `task` now
+    pub fn on_job_done(&mut self, host: &impl CaptureHost, task: String) {
+        self.jobs_done.push((host.icount(), task));
+    }
+
+    /// Takes the reads which belong to the state captured next
+    pub fn take_reads(&mut self) -> Vec<(GuestAddr, u8)> {
+        std::mem::take(&mut self.mem_reads)
+    }
+
+    /// Takes all captured states
+    pub fn take_states(&mut self) -> Vec<R> {
+        std::mem::take(&mut self.states)
+    }
+
+    /// Takes all recorded job completions
+    pub fn take_jobs(&mut self) -> Vec<(u64, String)> {
+        std::mem::take(&mut self.jobs_done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Emulator of the tests, its memory holds `seed ^ addr` at each address
+    struct MockHost {
+        seed: u8,
+        icount: Cell<u64>,
+    }
+
+    impl CaptureHost for MockHost {
+        fn icount(&self) -> u64 {
+            self.icount.get()
+        }
+
+        fn read_byte(&self, addr: GuestAddr) -> u8 {
+            self.seed ^ addr as u8
+        }
+    }
+
+    /// A helper with its emulator, the hooks reach the buffer of the helper of their own emulator like through its module tuple
+    struct MockEmulator {
+        host: MockHost,
+        helper: CaptureBuffer<u64>,
+    }
+
+    impl MockEmulator {
+        fn new(seed: u8, input_mem: Range<GuestAddr>) -> Self {
+            Self { host: MockHost { seed, icount: Cell::new(0) }, helper: CaptureBuffer::new(input_mem) }
+        }
+
+        /// Runs a script of reads and job completions, one per tick, yielding in between so that two emulators interleave
+        fn run(&mut self, task: &str, reads: &[GuestAddr]) {
+            for (n, addr) in reads.iter().enumerate() {
+                self.host.icount.set(n as u64 * 10);
+                self.helper.on_read(&self.host, *addr);
+                if n % 2 == 1 {
+                    self.helper.on_job_done(&self.host, task.to_string());
+                }
+                std::thread::yield_now();
+            }
+        }
+    }
+
+    #[test]
+    fn two_helpers_do_not_share_captures() {
+        let reads: Vec<GuestAddr> = (0..200).map(|x| 0x1000 + (x % 0x40)).collect();
+        let (a, b) = std::thread::scope(|s| {
+            let a = s.spawn(|| {
+                let mut e = MockEmulator::new(0x11, 0x1000..0x1010);
+                e.run("A", &reads);
+                e.helper
+            });
+            let b = s.spawn(|| {
+                let mut e = MockEmulator::new(0x22, 0x1020..0x1040);
+                e.run("B", &reads);
+                e.helper
+            });
+            (a.join().unwrap(), b.join().unwrap())
+        });
+        for (buffer, seed, task) in [(&a, 0x11, "A"), (&b, 0x22, "B")] {
+            let expected: Vec<_> = reads.iter().filter(|x| buffer.input_mem.contains(*x)).map(|x| (*x, seed ^ *x as u8)).collect();
+            assert!(!expected.is_empty());
+            assert_eq!(buffer.mem_reads, expected);
+            let jobs: Vec<_> = (0..100).map(|n| ((2 * n + 1) * 10, task.to_string())).collect();
+            assert_eq!(buffer.jobs_done, jobs);
+            assert!(buffer.states.is_empty());
+        }
+    }
+
+    #[test]
+    fn clear_resets_the_execution() {
+        let mut buffer: CaptureBuffer<u64> = CaptureBuffer::new(0x1000..0x1010);
+        buffer.states.push(1);
+        buffer.mem_reads.push((0x1000, 7));
+        buffer.jobs_done.push((1000, "T1".to_string()));
+        buffer.clear();
+        assert!(buffer.states.is_empty() && buffer.mem_reads.is_empty() && buffer.jobs_done.is_empty());
+        assert_eq!(buffer.input_mem, 0x1000..0x1010);
+    }
+
+    #[test]
+    fn take_empties_the_buffer() {
+        let mut buffer: CaptureBuffer<u64> = CaptureBuffer::new(0x1000..0x1010);
+        buffer.states.extend([1, 2]);
+        buffer.mem_reads.push((0x1004, 3));
+        buffer.jobs_done.push((10, "T1".to_string()));
+        assert_eq!(buffer.take_reads(), vec![(0x1004, 3)]);
+        assert_eq!(buffer.take_states(), vec![1, 2]);
+        assert_eq!(buffer.take_jobs(), vec![(10, "T1".to_string())]);
+        assert!(buffer.states.is_empty() && buffer.mem_reads.is_empty() && buffer.jobs_done.is_empty());
+    }
+
+    #[test]
+    fn limits_truncate_the_trace() {
+        let mut buffer: CaptureBuffer<u64> = CaptureBuffer::new(0x1000..0x1010);
+        buffer.limits = TraceLimits { max_states: Some(2), max_ticks: None };
+        for tick in [5, 6] {
+            assert!(buffer.accept(tick));
+            buffer.states.push(tick);
+        }
+        assert!(!buffer.truncated);
+        assert!(!buffer.accept(7));
+        assert!(buffer.truncated);
+
+        // ticks count from the first capture
+        let mut buffer: CaptureBuffer<u64> = CaptureBuffer::new(0x1000..0x1010);
+        buffer.limits = TraceLimits { max_states: None, max_ticks: Some(100) };
+        assert!(buffer.accept(1000));
+        assert!(buffer.accept(1100));
+        assert!(!buffer.accept(1101));
+        assert!(buffer.truncated);
+    }
+
+    #[test]
+    fn clear_resets_the_truncation() {
+        let mut buffer: CaptureBuffer<u64> = CaptureBuffer::new(0x1000..0x1010);
+        buffer.limits = TraceLimits { max_states: None, max_ticks: Some(10) };
+        assert!(buffer.accept(1000));
+        assert!(!buffer.accept(2000));
+        buffer.clear();
+        assert!(!buffer.truncated);
+        // the limits are kept, the first tick is taken anew
+        assert!(buffer.accept(2000));
+        assert!(!buffer.accept(2011));
+    }
+
+    #[test]
+    fn input_counter_at_the_end_truncates() {
+        let mut buffer: CaptureBuffer<u64> = CaptureBuffer::new(0x1000..0x1010);
+        buffer.check_input_counter(15);
+        assert!(!buffer.truncated);
+        buffer.check_input_counter(16);
+        assert!(buffer.truncated);
+    }
+
+    #[test]
+    fn clear_resets_the_invalid_task_names() {
+        let mut buffer: CaptureBuffer<u64> = CaptureBuffer::new(0x1000..0x1010);
+        buffer.invalid_task_names = 1;
+        buffer.clear();
+        assert_eq!(buffer.invalid_task_names, 0);
+    }
+}
@@ -3,24 +3,33 @@ use serde::{Deserialize, Serialize};
 // Manual Types
 use libafl_qemu::Qemu;
 
+/// Pointer of the guest, 32 bit on Cortex-M and 64 bit with the guest_64bit feature
+#[cfg(not(feature = "guest_64bit"))]
+pub type GuestPointer = u32;
+#[cfg(feature = "guest_64bit")]
+pub type GuestPointer = u64;
+/// Base type of the port, as wide as a pointer on all supported ports. Ticks are assumed to have the same width.
+pub type GuestBase = GuestPointer;
+
 /*========== Start of generated Code =============*/
-pub type char_ptr = ::std::os::raw::c_uint;
-pub type ListItem_t_ptr = ::std::os::raw::c_uint;
-pub type StackType_t_ptr = ::std::os::raw::c_uint;
-pub type void_ptr = ::std::os::raw::c_uint;
-pub type tskTaskControlBlock_ptr = ::std::os::raw::c_uint;
-pub type xLIST_ptr = ::std::os::raw::c_uint;
-pub type xLIST_ITEM_ptr = ::std::os::raw::c_uint;
+// generated for 32 bit, the widths of the pointers and base types are replaced by the aliases above
+pub type char_ptr = GuestPointer;
+pub type ListItem_t_ptr = GuestPointer;
+pub type StackType_t_ptr = GuestPointer;
+pub type void_ptr = GuestPointer;
+pub type tskTaskControlBlock_ptr = GuestPointer;
+pub type xLIST_ptr = GuestPointer;
+pub type xLIST_ITEM_ptr = GuestPointer;
 /* automatically generated by rust-bindgen 0.59.2 */
 
 pub type __uint8_t = ::std::os::raw::c_uchar;
 pub type __uint16_t = ::std::os::raw::c_ushort;
 pub type __uint32_t = ::std::os::raw::c_uint;
-pub type StackType_t = u32;
-pub type UBaseType_t = ::std::os::raw::c_uint;
-pub type TickType_t = u32;
+pub type StackType_t = GuestBase;
+pub type UBaseType_t = GuestBase;
+pub type TickType_t = GuestBase;
 #[repr(C)]
-#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, Hash)]
 pub struct xLIST_ITEM {
     pub xItemValue: TickType_t,
     pub pxNext: xLIST_ITEM_ptr,
@@ -30,7 +39,7 @@ pub struct xLIST_ITEM {
 }
 pub type ListItem_t = xLIST_ITEM;
 #[repr(C)]
-#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, Hash)]
 pub struct xMINI_LIST_ITEM {
     pub xItemValue: TickType_t,
     pub pxNext: xLIST_ITEM_ptr,
@@ -38,7 +47,7 @@ pub struct xMINI_LIST_ITEM {
 }
 pub type MiniListItem_t = xMINI_LIST_ITEM;
 #[repr(C)]
-#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, Hash)]
 pub struct xLIST {
     pub uxNumberOfItems: UBaseType_t,
     pub pxIndex: ListItem_t_ptr,
@@ -68,7 +77,7 @@ pub struct xTASK_STATUS {
 }
 pub type TaskStatus_t = xTASK_STATUS;
 #[repr(C)]
-#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, Hash)]
 pub struct tskTaskControlBlock {
     pub pxTopOfStack: StackType_t_ptr,
     pub xStateListItem: ListItem_t,
@@ -3,37 +3,70 @@ use libafl_qemu::{elf::EasyElf, GuestAddr};
 
 use crate::{
     fuzzer::get_all_fn_symbol_ranges,
-    systemstate::{helpers::{get_function_range, load_symbol}, target_os::freertos::ISR_SYMBOLS},
+    systemstate::{helpers::{get_function_range, load_symbol, symbol_layout_mismatches, symbol_size, try_load_symbol}, target_os::freertos::{bindings::{void_ptr, List_t, UBaseType_t}, set_num_prios, DEFAULT_NUM_PRIOS, ISR_SYMBOLS, QUEUE_SYMBOL_PREFIX}},
 };
 
+/// Kernel globals required for system state observation
+pub const REQUIRED_SYMBOLS: &[&str] = &[
+    "pxCurrentTCB",
+    "pxReadyTasksLists",
+    "pxDelayedTaskList",
+    "pxOverflowDelayedTaskList",
+    "uxSchedulerSuspended",
+    "xSchedulerRunning",
+    "uxCriticalNesting",
+];
+
+/// Kernel globals whose size in the ELF does not match the bindings, e.g. because the kernel was built for another pointer width
+pub fn layout_mismatches(elf: &EasyElf) -> Vec<String> {
+    let mut ret = symbol_layout_mismatches(elf, &[
+        ("pxCurrentTCB", std::mem::size_of::<void_ptr>(), false),
+        ("pxReadyTasksLists", std::mem::size_of::<List_t>(), true),
+        ("pxDelayedTaskList", std::mem::size_of::<void_ptr>(), false),
+        ("pxOverflowDelayedTaskList", std::mem::size_of::<void_ptr>(), false),
+        ("xDelayedTaskList1", std::mem::size_of::<List_t>(), false),
+        ("uxSchedulerSuspended", std::mem::size_of::<UBaseType_t>(), false),
+        ("uxCriticalNesting", std::mem::size_of::<UBaseType_t>(), false),
+    ]);
+    let lists = symbol_size(elf, "pxReadyTasksLists").filter(|x| *x != 0).map(|x| x as usize / std::mem::size_of::<List_t>());
+    if let (Some(prios), Some(lists)) = (std::env::var("MAX_PRIORITIES").ok().and_then(|x| str::parse::<usize>(&x).ok()), lists) {
+        if prios != lists {
+            ret.push(format!("MAX_PRIORITIES is {}, but pxReadyTasksLists holds {} lists", prios, lists));
+        }
+    }
+    ret
+}
+
+/// configMAX_PRIORITIES of the kernel: MAX_PRIORITIES from the config, otherwise the number of lists in pxReadyTasksLists.
+/// Falls back to [`DEFAULT_NUM_PRIOS`] if the ELF has no symbol sizes.
+pub fn num_priorities(elf: &EasyElf) -> usize {
+    if let Ok(prios) = std::env::var("MAX_PRIORITIES") {
+        return str::parse::<usize>(&prios).expect("MAX_PRIORITIES must be an integer.");
+    }
+    match symbol_size(elf, "pxReadyTasksLists").filter(|x| *x != 0) {
+        Some(size) => size as usize / std::mem::size_of::<List_t>(),
+        None => DEFAULT_NUM_PRIOS,
+    }
+}
+
 // Add os-specific symbols to the target symbol hashmap
 pub fn add_target_symbols(elf: &EasyElf, addrs: &mut HashMap<&'static str, GuestAddr>) {
-    // required for system state observation
-    addrs.insert("pxCurrentTCB", load_symbol(&elf, "pxCurrentTCB", false)); // loads to the address specified in elf, without respecting program headers
-    addrs.insert(
-        "pxReadyTasksLists",
-        load_symbol(&elf, "pxReadyTasksLists", false),
-    );
-    addrs.insert(
-        "pxDelayedTaskList",
-        load_symbol(&elf, "pxDelayedTaskList", false),
-    );
-    addrs.insert(
-        "pxOverflowDelayedTaskList",
-        load_symbol(&elf, "pxOverflowDelayedTaskList", false),
-    );
-    addrs.insert(
-        "uxSchedulerSuspended",
-        load_symbol(&elf, "uxSchedulerSuspended", false),
-    );
-    addrs.insert(
-        "xSchedulerRunning",
-        load_symbol(&elf, "xSchedulerRunning", false),
-    );
-    addrs.insert(
-        "uxCriticalNesting",
-        load_symbol(&elf, "uxCriticalNesting", false),
-    );
+    for name in REQUIRED_SYMBOLS {
+        addrs.insert(*name, load_symbol(&elf, name, false)); // loads to the address specified in elf, without respecting program headers
+    }
+    debug_assert!(layout_mismatches(elf).is_empty(), "Bindings do not match the kernel: {:?}", layout_mismatches(elf));
+    set_num_prios(num_priorities(elf));
+    // optional queue handles, e.g. FREERTOS_QUEUES=xQueueA,xSemaphoreB
+    if let Ok(queues) = std::env::var("FREERTOS_QUEUES") {
+        for name in queues.split(',').filter(|x| !x.is_empty()) {
+            match try_load_symbol(&elf, name, false) {
+                Some(addr) => {
+                    addrs.insert(Box::leak(format!("{}{}", QUEUE_SYMBOL_PREFIX, name).into_boxed_str()), addr);
+                }
+                None => eprintln!("Queue handle {} not found", name),
+            }
+        }
+    }
 }
 
 
@@ -42,7 +75,7 @@ pub fn get_range_groups(
     elf: &EasyElf,
     _addrs: &HashMap<&'static str, GuestAddr>,
     ranges: &HashMap<&'static str, std::ops::Range<GuestAddr>>,
-) -> HashMap<&'static str, hashbrown::HashMap<String, std::ops::Range<u32>>> {
+) -> HashMap<&'static str, hashbrown::HashMap<String, std::ops::Range<GuestAddr>>> {
     let api_range = ranges.get("API_CODE").unwrap();
     let app_range = ranges.get("APP_CODE").unwrap();
 
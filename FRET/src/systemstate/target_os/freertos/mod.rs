@@ -1,7 +1,8 @@
 use libafl_qemu::GuestAddr;
-use qemu_module::{FreeRTOSSystemStateHelper, MEM_READ};
+use qemu_module::FreeRTOSSystemStateHelper;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::{
     impl_emu_lookup,
@@ -14,10 +15,22 @@ pub mod config;
 use bindings::*;
 
 use super::QemuLookup;
+use super::intervals::RefinementError;
 use crate::systemstate::target_os::*;
 
 // Constants
-const NUM_PRIOS: usize = 15;
+/// configMAX_PRIORITIES of kernels which do not tell, see [`config::num_priorities`]
+pub const DEFAULT_NUM_PRIOS: usize = 15;
+static NUM_PRIOS: AtomicUsize = AtomicUsize::new(DEFAULT_NUM_PRIOS);
+
+/// Number of ready lists of the target, set when its symbols are loaded
+pub fn num_prios() -> usize {
+    NUM_PRIOS.load(Ordering::Relaxed)
+}
+
+pub fn set_num_prios(num_prios: usize) {
+    NUM_PRIOS.store(num_prios, Ordering::Relaxed);
+}
 
 //============================================================================= Outside interface
 
@@ -67,7 +80,7 @@ impl SystemState for FreeRTOSSystemState {
 
 //============================================================================= Data structures
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Hash)]
 pub enum FreeRTOSStruct {
     TCB_struct(TCB_t),
     List_struct(List_t),
@@ -75,12 +88,49 @@ pub enum FreeRTOSStruct {
     List_MiniItem_struct(MiniListItem_t),
 }
 
-impl_emu_lookup!(TCB_t);
-impl_emu_lookup!(List_t);
-impl_emu_lookup!(ListItem_t);
-impl_emu_lookup!(MiniListItem_t);
-impl_emu_lookup!(void_ptr);
-impl_emu_lookup!(TaskStatus_t);
+impl_emu_lookup!(TCB_t { pxTopOfStack, xStateListItem, xEventListItem, uxPriority, pxStack, pcTaskName, uxBasePriority, uxMutexesHeld, ulNotifiedValue, ucNotifyState, ucStaticallyAllocated, ucDelayAborted });
+impl_emu_lookup!(List_t { uxNumberOfItems, pxIndex, xListEnd });
+impl_emu_lookup!(ListItem_t { xItemValue, pxNext, pxPrevious, pvOwner, pvContainer });
+impl_emu_lookup!(MiniListItem_t { xItemValue, pxNext, pxPrevious });
+impl_emu_lookup!(TaskStatus_t { xHandle, pcTaskName, xTaskNumber, eCurrentState, uxCurrentPriority, uxBasePriority, ulRunTimeCounter, pxStackBase, usStackHighWaterMark });
+impl_emu_lookup!(QueueHead_t { pcHead, pcWriteTo, u, xTasksWaitingToSend, xTasksWaitingToReceive, uxMessagesWaiting, uxLength, uxItemSize });
+
+// kernel pointers are read into addresses of the emulator
+const _: () = assert!(std::mem::size_of::<GuestPointer>() == std::mem::size_of::<GuestAddr>(), "the guest_64bit feature does not match the GuestAddr of libafl_qemu");
+
+/// Prefix of the target symbols naming queue handles, see [`config::add_target_symbols`]
+pub const QUEUE_SYMBOL_PREFIX: &str = "QUEUE:";
+
+/// The leading fields of a queue, as laid out by queue.c, which does not export `Queue_t`
+#[allow(non_camel_case_types, non_snake_case)]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct QueueHead_t {
+    pub pcHead: void_ptr,
+    pub pcWriteTo: void_ptr,
+    /// Union of the read pointers of a queue and the mutex holder of a semaphore
+    pub u: [void_ptr; 2],
+    pub xTasksWaitingToSend: List_t,
+    pub xTasksWaitingToReceive: List_t,
+    pub uxMessagesWaiting: UBaseType_t,
+    pub uxLength: UBaseType_t,
+    pub uxItemSize: UBaseType_t,
+}
+
+/// Occupancy of a queue or semaphore
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct RefinedQueue {
+    pub name: String,
+    pub messages_waiting: u32,
+    pub waiting_senders: u32,
+    pub waiting_receivers: u32,
+}
+
+impl fmt::Display for RefinedQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}(s{} r{})", self.name, self.messages_waiting, self.waiting_senders, self.waiting_receivers)
+    }
+}
 
 pub const ISR_SYMBOLS: &'static [&'static str] = &[
     // ISRs
@@ -167,12 +217,11 @@ fn read_freertos_list(
         }
         // assert_eq!(next_item.pvContainer,target);
         let new_next_index = next_item.pxNext;
-        let next_tcb: TCB_t = QemuLookup::lookup(emulator, next_item.pvOwner);
-        // println!("TCB at {}: {:?}",next_item.pvOwner,next_tcb);
-        systemstate.dumping_ground.insert(
-            next_item.pvOwner,
-            FreeRTOSStruct::TCB_struct(next_tcb.clone()),
-        );
+        // a TCB is only read once per capture
+        systemstate
+            .dumping_ground
+            .entry(next_item.pvOwner)
+            .or_insert_with(|| FreeRTOSStruct::TCB_struct(QemuLookup::lookup(emulator, next_item.pvOwner)));
         systemstate
             .dumping_ground
             .insert(next_index, FreeRTOSStruct::List_Item_struct(next_item));
@@ -188,23 +237,80 @@ fn read_freertos_list(
     return (read, true);
 }
 
+/// Reads the ready, delay and queue state of the kernel into `systemstate`, marking it invalid if a list is being modified
+fn read_kernel_lists(
+    systemstate: &mut RawFreeRTOSSystemState,
+    emulator: &libafl_qemu::Qemu,
+    h: &FreeRTOSSystemStateHelper,
+) {
+    let listbytes: GuestAddr =
+        GuestAddr::try_from(std::mem::size_of::<freertos::List_t>()).unwrap();
+    // Extract delay list
+    let mut target: GuestAddr = h.delay_queue;
+    target = QemuLookup::lookup(emulator, target);
+    let _temp = read_freertos_list(systemstate, emulator, target);
+    systemstate.delay_list = _temp.0;
+    systemstate.read_invalid |= !_temp.1;
+
+    // Extract delay list overflow
+    if let Some(mut target) = h.delay_queue_overflow {
+        target = QemuLookup::lookup(emulator, target);
+        let _temp = read_freertos_list(systemstate, emulator, target);
+        systemstate.delay_list_overflow = _temp.0;
+        systemstate.read_invalid |= !_temp.1;
+    }
+
+    // Extract suspended tasks (infinite wait), seems broken, always appreas to be modified
+    // let mut target : GuestAddr = h.suspended_queue;
+    // target = QemuLookup::lookup(emulator, target);
+    // systemstate.suspended_list = read_freertos_list(systemstate, emulator, target);
+
+    // Extract priority lists
+    systemstate.prio_ready_lists.clear();
+    for i in 0..h.num_prios {
+        let target: GuestAddr = listbytes * GuestAddr::try_from(i).unwrap() + h.ready_queues;
+        let _temp = read_freertos_list(systemstate, emulator, target);
+        systemstate.prio_ready_lists.push(_temp.0);
+        systemstate.read_invalid |= !_temp.1;
+    }
+    // a priority beyond the ready lists means the TCBs were read from the wrong place or while being modified
+    systemstate.read_invalid |= systemstate.dumping_ground.values().any(|x| matches!(x, FreeRTOSStruct::TCB_struct(t) if t.uxPriority as usize >= h.num_prios));
+
+    // Extract the occupancy of the configured queues, handles which are not yet created are skipped
+    for (name, handle) in h.queue_handles.iter() {
+        let queue: void_ptr = QemuLookup::lookup(emulator, *handle);
+        if queue == 0 {
+            continue;
+        }
+        let head: QueueHead_t = QemuLookup::lookup(emulator, queue);
+        systemstate.queues.push(RefinedQueue {
+            name: name.to_string(),
+            messages_waiting: head.uxMessagesWaiting as u32,
+            waiting_senders: head.xTasksWaitingToSend.uxNumberOfItems as u32,
+            waiting_receivers: head.xTasksWaitingToReceive.uxNumberOfItems as u32,
+        });
+    }
+}
+
 /// Triggers the collection of a FreeRTOS system state snapshot at a given event.
 ///
 /// # Arguments
 /// * `emulator` - The QEMU emulator instance.
 /// * `edge` - A tuple of (from, to) addresses representing the edge.
 /// * `event` - The capture event type.
-/// * `h` - The FreeRTOS system state helper.
+/// * `h` - The FreeRTOS system state helper, which receives the snapshot.
 #[inline]
 fn trigger_collection(
     emulator: &libafl_qemu::Qemu,
     edge: (GuestAddr, GuestAddr),
     event: CaptureEvent,
-    h: &FreeRTOSSystemStateHelper,
+    h: &mut FreeRTOSSystemStateHelper,
 ) {
-    let listbytes: GuestAddr =
-        GuestAddr::try_from(std::mem::size_of::<freertos::List_t>()).unwrap();
-    let mut systemstate = RawFreeRTOSSystemState::default();
+    // the final state is always captured, it closes the trace
+    if event != CaptureEvent::End && !h.capture.accept(get_icount(emulator)) {
+        return;
+    }
+    let mut systemstate = h.raw_pool.pop().unwrap_or_default();
 
     match event {
         CaptureEvent::APIStart => {
@@ -238,6 +344,7 @@ fn trigger_collection(
 
     let curr_tcb_addr: freertos::void_ptr = QemuLookup::lookup(emulator, h.tcb_addr);
     if curr_tcb_addr == 0 {
+        h.raw_pool.push(systemstate.recycle());
         return;
     };
 
@@ -247,45 +354,49 @@ fn trigger_collection(
     let _running: void_ptr = QemuLookup::lookup(emulator, h.scheduler_running_addr);
 
     systemstate.current_tcb = QemuLookup::lookup(emulator, curr_tcb_addr);
+    systemstate.read_invalid |= systemstate.current_tcb.uxPriority as usize >= h.num_prios;
     // During ISRs it is only safe to extract structs if they are not currently being modified
     if systemstate.capture_point.0 == CaptureEvent::APIStart
         || systemstate.capture_point.0 == CaptureEvent::APIEnd
         || (critical == 0 && suspended == 0)
     {
-        // Extract delay list
-        let mut target: GuestAddr = h.delay_queue;
-        target = QemuLookup::lookup(emulator, target);
-        let _temp = read_freertos_list(&mut systemstate, emulator, target);
-        systemstate.delay_list = _temp.0;
-        systemstate.read_invalid |= !_temp.1;
-
-        // Extract delay list overflow
-        let mut target: GuestAddr = h.delay_queue_overflow;
-        target = QemuLookup::lookup(emulator, target);
-        let _temp = read_freertos_list(&mut systemstate, emulator, target);
-        systemstate.delay_list_overflow = _temp.0;
-        systemstate.read_invalid |= !_temp.1;
-
-        // Extract suspended tasks (infinite wait), seems broken, always appreas to be modified
-        // let mut target : GuestAddr = h.suspended_queue;
-        // target = QemuLookup::lookup(emulator, target);
-        // systemstate.suspended_list = read_freertos_list(&mut systemstate, emulator, target);
-
-        // Extract priority lists
-        for i in 0..NUM_PRIOS {
-            let target: GuestAddr = listbytes * GuestAddr::try_from(i).unwrap() + h.ready_queues;
-            let _temp = read_freertos_list(&mut systemstate, emulator, target);
-            systemstate.prio_ready_lists[i] = _temp.0;
-            systemstate.read_invalid |= !_temp.1;
-        }
+        read_kernel_lists(&mut systemstate, emulator, h);
     } else {
         systemstate.read_invalid = true;
+        // Retried when the critical section is left, see [`qemu_module::critical_exit_hook`]
+        if !h.critical_exit_addrs.is_empty() {
+            h.deferred_captures.push(h.capture.states.len());
+        }
     }
-    systemstate.mem_reads = unsafe { MEM_READ.take().unwrap_or_default() };
+    systemstate.mem_reads = h.capture.take_reads();
+
+    h.capture.states.push(systemstate);
+}
 
-    unsafe {
-        CURRENT_SYSTEMSTATE_VEC.push(systemstate);
+/// Reads the kernel lists for all captures deferred by [`trigger_collection`], once no critical section is active.
+/// The captures keep the icount of their original event.
+/// Returns false if the critical section is still active.
+fn complete_deferred_captures(emulator: &libafl_qemu::Qemu, h: &mut FreeRTOSSystemStateHelper) -> bool {
+    let critical: void_ptr = QemuLookup::lookup(emulator, h.critical_addr);
+    let suspended: void_ptr = QemuLookup::lookup(emulator, h.scheduler_lock_addr);
+    if critical != 0 || suspended != 0 {
+        return false;
+    }
+    let mut lists = h.raw_pool.pop().unwrap_or_default();
+    read_kernel_lists(&mut lists, emulator, h);
+    for index in h.deferred_captures.drain(..) {
+        if let Some(systemstate) = h.capture.states.get_mut(index) {
+            systemstate.prio_ready_lists = lists.prio_ready_lists.clone();
+            systemstate.delay_list = lists.delay_list;
+            systemstate.delay_list_overflow = lists.delay_list_overflow;
+            systemstate.dumping_ground.extend(lists.dumping_ground.iter().map(|(k, v)| (*k, *v)));
+            systemstate.queues = lists.queues.clone();
+            systemstate.read_invalid = lists.read_invalid || systemstate.current_tcb.uxPriority as usize >= h.num_prios;
+            systemstate.deferred = true;
+        }
     }
+    h.raw_pool.push(lists.recycle());
+    true
 }
 
 /// Raw info Dump from Qemu
@@ -293,18 +404,45 @@ fn trigger_collection(
 pub struct RawFreeRTOSSystemState {
     qemu_tick: u64,
     current_tcb: TCB_t,
-    prio_ready_lists: [freertos::List_t; NUM_PRIOS],
+    /// One list per priority, lowest first
+    prio_ready_lists: Vec<freertos::List_t>,
     delay_list: freertos::List_t,
     delay_list_overflow: freertos::List_t,
-    dumping_ground: HashMap<u32, freertos::FreeRTOSStruct>,
+    dumping_ground: HashMap<GuestPointer, freertos::FreeRTOSStruct>,
     read_invalid: bool,
     input_counter: u32,
     edge: (GuestAddr, GuestAddr),
     capture_point: (CaptureEvent, Cow<'static, str>),
     mem_reads: Vec<(u32, u8)>,
+    queues: Vec<RefinedQueue>,
+    /// The lists were read at the end of the critical section active during the capture
+    deferred: bool,
+}
+
+impl RawFreeRTOSSystemState {
+    /// Resets all fields, but keeps the allocations of the dumping ground and ready lists for the next capture
+    fn recycle(mut self) -> Self {
+        let mut dumping_ground = std::mem::take(&mut self.dumping_ground);
+        dumping_ground.clear();
+        let mut prio_ready_lists = std::mem::take(&mut self.prio_ready_lists);
+        prio_ready_lists.clear();
+        Self { dumping_ground, prio_ready_lists, ..Default::default() }
+    }
+
+    /// Hash of everything the refinement reads, but not of the context like the tick or capture point.
+    /// The entries of the dumping ground are combined independent of their order.
+    fn refinement_digest(&self) -> u64 {
+        let mut h = stable_hasher();
+        self.current_tcb.hash(&mut h);
+        self.prio_ready_lists.hash(&mut h);
+        self.delay_list.hash(&mut h);
+        self.delay_list_overflow.hash(&mut h);
+        self.read_invalid.hash(&mut h);
+        self.queues.hash(&mut h);
+        self.dumping_ground.iter().fold(0u64, |acc, x| acc.wrapping_add(compute_hash(&x))).hash(&mut h);
+        h.finish()
+    }
 }
-/// List of system state dumps from EmulatorModules
-static mut CURRENT_SYSTEMSTATE_VEC: Vec<RawFreeRTOSSystemState> = vec![];
 
 /// A reduced version of freertos::TCB_t
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -342,7 +480,20 @@ impl Hash for RefinedTCB {
     }
 }
 
+/// Name of a task from the `pcTaskName` of its TCB, along with whether it was valid utf8.
+/// Names read mid-modification or non-ASCII names of the target may be garbage, their invalid bytes are replaced.
+pub fn task_name_lossy(raw: [i8; 10]) -> (String, bool) {
+    let tmp = unsafe { std::mem::transmute::<[i8; 10], [u8; 10]>(raw) };
+    let name = String::from_utf8_lossy(&tmp).chars().filter(|x| *x != '\0').collect::<String>();
+    (name, std::str::from_utf8(&tmp).is_ok())
+}
+
 impl RefinedTCB {
+    /// Whether the task name of `input` is valid utf8, see [`task_name_lossy`]
+    pub fn has_valid_name(input: &TCB_t) -> bool {
+        task_name_lossy(input.pcTaskName).1
+    }
+
     /// Constructs a `RefinedTCB` from a raw FreeRTOS TCB struct reference.
     ///
     /// # Arguments
@@ -351,21 +502,13 @@ impl RefinedTCB {
     /// # Returns
     /// A new `RefinedTCB` instance.
     pub fn from_tcb(input: &TCB_t) -> Self {
-        unsafe {
-            let tmp = std::mem::transmute::<[i8; 10], [u8; 10]>(input.pcTaskName);
-            let name: String = std::str::from_utf8(&tmp)
-                .expect("TCB name was not utf8")
-                .chars()
-                .filter(|x| *x != '\0')
-                .collect::<String>();
-            Self {
-                task_name: name,
-                priority: input.uxPriority,
-                base_priority: input.uxBasePriority,
-                mutexes_held: input.uxMutexesHeld,
-                notify_value: input.ulNotifiedValue[0],
-                notify_state: input.ucNotifyState[0],
-            }
+        Self {
+            task_name: task_name_lossy(input.pcTaskName).0,
+            priority: input.uxPriority as u32,
+            base_priority: input.uxBasePriority as u32,
+            mutexes_held: input.uxMutexesHeld as u32,
+            notify_value: input.ulNotifiedValue[0],
+            notify_state: input.ucNotifyState[0],
         }
     }
     /// Constructs a `RefinedTCB` from a raw FreeRTOS TCB struct (by value).
@@ -376,21 +519,13 @@ impl RefinedTCB {
     /// # Returns
     /// A new `RefinedTCB` instance.
     pub fn from_tcb_owned(input: TCB_t) -> Self {
-        unsafe {
-            let tmp = std::mem::transmute::<[i8; 10], [u8; 10]>(input.pcTaskName);
-            let name: String = std::str::from_utf8(&tmp)
-                .expect("TCB name was not utf8")
-                .chars()
-                .filter(|x| *x != '\0')
-                .collect::<String>();
-            Self {
-                task_name: name,
-                priority: input.uxPriority,
-                base_priority: input.uxBasePriority,
-                mutexes_held: input.uxMutexesHeld,
-                notify_value: input.ulNotifiedValue[0],
-                notify_state: input.ucNotifyState[0],
-            }
+        Self {
+            task_name: task_name_lossy(input.pcTaskName).0,
+            priority: input.uxPriority as u32,
+            base_priority: input.uxBasePriority as u32,
+            mutexes_held: input.uxMutexesHeld as u32,
+            notify_value: input.ulNotifiedValue[0],
+            notify_state: input.ucNotifyState[0],
         }
     }
 }
@@ -402,13 +537,19 @@ pub struct FreeRTOSSystemState {
     ready_list_after: Vec<RefinedTCB>,
     delay_list_after: Vec<RefinedTCB>,
     read_invalid: bool,
+    /// Occupancy of the queues in FREERTOS_QUEUES, only part of the hash with `do_hash_queue_state`
+    #[serde(default)]
+    queues: Vec<RefinedQueue>,
 }
 impl PartialEq for FreeRTOSSystemState {
     fn eq(&self, other: &Self) -> bool {
-        self.current_task == other.current_task
+        let ret = self.current_task == other.current_task
             && self.ready_list_after == other.ready_list_after
             && self.delay_list_after == other.delay_list_after
-            && self.read_invalid == other.read_invalid
+            && self.read_invalid == other.read_invalid;
+        #[cfg(feature = "do_hash_queue_state")]
+        let ret = ret && self.queues == other.queues;
+        ret
     }
 }
 
@@ -418,6 +559,8 @@ impl Hash for FreeRTOSSystemState {
         self.ready_list_after.hash(state);
         self.delay_list_after.hash(state);
         self.read_invalid.hash(state);
+        #[cfg(feature = "do_hash_queue_state")]
+        self.queues.hash(state);
     }
 }
 impl FreeRTOSSystemState {
@@ -434,6 +577,12 @@ impl FreeRTOSSystemState {
         for j in self.delay_list_after.iter() {
             ret.push_str(format!(" {}", j.task_name).as_str());
         }
+        if !self.queues.is_empty() {
+            ret.push_str("\nQ");
+            for q in self.queues.iter() {
+                ret.push_str(format!(" {}", q).as_str());
+            }
+        }
         ret
     }
     /// Computes a hash for the system state.
@@ -441,7 +590,7 @@ impl FreeRTOSSystemState {
     /// # Returns
     /// The hash value as a u64.
     pub fn get_hash(&self) -> u64 {
-        let mut h = DefaultHasher::new();
+        let mut h = stable_hasher();
         self.hash(&mut h);
         h.finish()
     }
@@ -468,17 +617,16 @@ impl fmt::Display for FreeRTOSSystemState {
             self.current_task.task_name,
             ready,
             delay
-        )
+        )?;
+        if !self.queues.is_empty() {
+            let queues = self.queues.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(" ");
+            write!(f, " | Queues: {}", queues)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub(crate)struct FreeRTOSSystemStateContext {
-    pub qemu_tick: u64,
-    pub capture_point: (CaptureEvent, Cow<'static, str>),
-    pub edge: (GuestAddr, GuestAddr),
-    pub mem_reads: Vec<(u32, u8)>,
-}
+pub(crate) use super::intervals::SystemStateContext as FreeRTOSSystemStateContext;
 
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
@@ -492,6 +640,26 @@ pub struct FreeRTOSTraceMetadata
     indices: Vec<usize>, // Hashed enumeration of States
     tcref: isize,
     need_to_debug: bool,
+    #[serde(default)]
+    isr_worst_durations: HashMap<String, u64>,
+    #[serde(default)]
+    isr_budget_exceeded: bool,
+    #[serde(default)]
+    deferred_captures: usize,
+    #[serde(default)]
+    invalid_captures: usize,
+    #[serde(default)]
+    isr_activations: HashMap<String, Vec<u64>>,
+    #[serde(default)]
+    trace_truncated: bool,
+    #[serde(default)]
+    invalid_task_names: usize,
+    #[serde(default)]
+    stack_high_water: HashMap<String, u32>,
+    #[serde(default)]
+    error_classes: Vec<ErrorClass>,
+    #[serde(default)]
+    first_error: Option<RefinementError>,
 }
 impl FreeRTOSTraceMetadata
 {
@@ -499,18 +667,24 @@ impl FreeRTOSTraceMetadata
     ///
     /// # Arguments
     /// * `trace` - Vector of system states.
+    /// * `hashes` - The [`compute_hash`] of each state.
     /// * `intervals` - Vector of execution intervals.
     /// * `mem_reads` - Vector of memory reads.
     /// * `jobs` - Vector of RTOS jobs.
     /// * `need_to_debug` - Whether the current trace should be dumped for debugging purposes.
+    /// * `isr_worst_durations` - Longest invocation of each ISR in ticks.
+    /// * `isr_budget_exceeded` - Whether an ISR invocation exceeded its tick budget.
+    /// * `deferred_captures` - Number of captures whose lists were read at the end of a critical section.
+    /// * `invalid_captures` - Number of captures whose lists could not be read.
+    /// * `isr_activations` - Start ticks of the invocations of each ISR.
+    /// * `trace_truncated` - Whether the capture stopped early, see [`crate::systemstate::target_os::capture::CaptureBuffer::truncated`].
+    /// * `invalid_task_names` - Number of task names which were not utf8, see [`task_name_lossy`].
+    /// * `stack_high_water` - Least free stack bytes of each task.
     ///
     /// # Returns
     /// A new `FreeRTOSTraceMetadata` instance.
-    pub fn new(trace: Vec<<FreeRTOSTraceMetadata as SystemTraceData>::State>, intervals: Vec<ExecInterval>, mem_reads: Vec<Vec<(u32, u8)>>, jobs: Vec<RTOSJob>, need_to_debug: bool) -> Self {
-        let hashes : Vec<_> = trace
-            .iter()
-            .map(|x| compute_hash(&x) as usize)
-            .collect();
+    pub fn new(trace: Vec<<FreeRTOSTraceMetadata as SystemTraceData>::State>, hashes: Vec<u64>, intervals: Vec<ExecInterval>, mem_reads: Vec<Vec<(u32, u8)>>, jobs: Vec<RTOSJob>, need_to_debug: bool, isr_worst_durations: HashMap<String, u64>, isr_budget_exceeded: bool, deferred_captures: usize, invalid_captures: usize, isr_activations: HashMap<String, Vec<u64>>, trace_truncated: bool, invalid_task_names: usize, stack_high_water: HashMap<String, u32>) -> Self {
+        let hashes : Vec<_> = hashes.into_iter().map(|x| x as usize).collect();
         let trace_map = HashMap::from_iter(trace.into_iter().zip(hashes.iter()).map(|(x, y)| (*y as u64, x)));
         Self {
             trace_length: hashes.len(),  // TODO make this configurable
@@ -521,8 +695,32 @@ impl FreeRTOSTraceMetadata
             indices: hashes,
             tcref: 0,
             need_to_debug: need_to_debug,
+            isr_worst_durations,
+            isr_budget_exceeded,
+            deferred_captures,
+            invalid_captures,
+            isr_activations,
+            trace_truncated,
+            invalid_task_names,
+            stack_high_water,
+            error_classes: Vec::new(),
+            first_error: None,
         }
     }
+
+    /// Records the problems the refinement ran into, see [`SystemTraceData::error_classes`]
+    #[must_use]
+    pub fn with_error_classes(mut self, error_classes: Vec<ErrorClass>) -> Self {
+        self.error_classes = error_classes;
+        self
+    }
+
+    /// Records the earliest problem of the refinement, see [`SystemTraceData::first_error`]
+    #[must_use]
+    pub fn with_first_error(mut self, first_error: Option<RefinementError>) -> Self {
+        self.first_error = first_error;
+        self
+    }
 }
 
 impl HasRefCnt for FreeRTOSTraceMetadata
@@ -567,6 +765,42 @@ impl SystemTraceData for FreeRTOSTraceMetadata
     fn need_to_debug(&self) -> bool {
         self.need_to_debug
     }
+
+    fn isr_worst_durations(&self) -> Option<&HashMap<String, u64>> {
+        Some(&self.isr_worst_durations)
+    }
+
+    fn isr_budget_exceeded(&self) -> bool {
+        self.isr_budget_exceeded
+    }
+
+    fn capture_stats(&self) -> Option<(usize, usize)> {
+        Some((self.deferred_captures, self.invalid_captures))
+    }
+
+    fn isr_activations(&self) -> Option<&HashMap<String, Vec<u64>>> {
+        Some(&self.isr_activations)
+    }
+
+    fn trace_truncated(&self) -> bool {
+        self.trace_truncated
+    }
+
+    fn invalid_task_names(&self) -> usize {
+        self.invalid_task_names
+    }
+
+    fn stack_high_water(&self) -> Option<&HashMap<String, u32>> {
+        Some(&self.stack_high_water)
+    }
+
+    fn error_classes(&self) -> &[ErrorClass] {
+        &self.error_classes
+    }
+
+    fn first_error(&self) -> Option<&RefinementError> {
+        self.first_error.as_ref()
+    }
 }
 
 libafl_bolts::impl_serdeany!(FreeRTOSTraceMetadata);
@@ -588,3 +822,32 @@ pub(crate) fn get_task_names(trace: &Vec<FreeRTOSSystemState>) -> HashSet<String
     }
     ret
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_name(bytes: &[u8]) -> [i8; 10] {
+        let mut name = [0i8; 10];
+        name.iter_mut().zip(bytes).for_each(|(d, s)| *d = *s as i8);
+        name
+    }
+
+    #[test]
+    fn task_names_of_raw_bytes() {
+        assert_eq!(task_name_lossy(raw_name(b"T1")), ("T1".to_string(), true));
+        // names of the full length have no terminating zero
+        assert_eq!(task_name_lossy(raw_name(b"0123456789")), ("0123456789".to_string(), true));
+        assert_eq!(task_name_lossy(raw_name("Tä".as_bytes())), ("Tä".to_string(), true));
+        assert_eq!(task_name_lossy(raw_name(&[b'T', 0xff, b'1', 0xc3])), ("T\u{fffd}1\u{fffd}".to_string(), false));
+    }
+
+    #[test]
+    fn invalid_task_name_does_not_panic() {
+        let tcb = TCB_t { pcTaskName: raw_name(&[0x80, b'x']), uxPriority: 3, ..Default::default() };
+        assert!(!RefinedTCB::has_valid_name(&tcb));
+        let refined = RefinedTCB::from_tcb(&tcb);
+        assert_eq!((refined.task_name.as_str(), refined.priority), ("\u{fffd}x", 3));
+        assert_eq!(RefinedTCB::from_tcb_owned(tcb), refined);
+    }
+}
@@ -1,6 +1,4 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::{borrow::Cow, collections::VecDeque};
+use std::borrow::Cow;
 use std::ops::Range;
 
 use freertos::{FreeRTOSTraceMetadata, USR_ISR_SYMBOLS};
@@ -13,19 +11,23 @@ use libafl::{
 use libafl_qemu::{
     modules::{EmulatorModule, EmulatorModuleTuple, NopAddressFilter, NopPageFilter},
     sys::TCGTemp,
-    EmulatorModules, GuestAddr, Hook, MemAccessInfo,
+    EmulatorModules, GuestAddr, Hook, HookId, InstructionHookId, MemAccessInfo,
 };
 
 use crate::{fuzzer::MAX_INPUT_SIZE, systemstate::{
-    helpers::{get_icount, in_any_range, read_rec_return_stackframe},
-    target_os::{freertos::FreeRTOSStruct::*, *},
-    AtomicBasicBlock, CaptureEvent, RTOSJob,
-}};
+    feedbacks::TargetErrorMetadata,
+    helpers::{get_icount, in_any_range, read_rec_return_stackframe, SymbolResolver},
+    target_os::{capture::{CaptureBuffer, TraceLimits}, freertos::FreeRTOSStruct::*, intervals::{get_jobs, get_release_response_pairs, infer_responses, isr_activations, states2intervals_with_hashes, IsrActivationStats, RefinementError}, *},
+    CaptureEvent,
+}, time::qemustate::snapshot_epoch};
+#[cfg(feature = "snapshot_at_first_task")]
+use crate::time::qemustate::TaskStartMetadata;
 
 use super::{
     bindings::{self, *},
-    compute_hash, trigger_collection, ExecInterval, FreeRTOSStruct, FreeRTOSSystemState,
-    FreeRTOSSystemStateContext, RawFreeRTOSSystemState, RefinedTCB, CURRENT_SYSTEMSTATE_VEC,
+    trigger_collection, ExecInterval, FreeRTOSStruct, FreeRTOSSystemState,
+    FreeRTOSSystemStateContext, RawFreeRTOSSystemState, RefinedTCB, QUEUE_SYMBOL_PREFIX,
+    complete_deferred_captures,
 };
 
 //============================= Qemu Helper
@@ -46,21 +48,76 @@ pub struct FreeRTOSSystemStateHelper {
     // FreeRTOS specific addresses
     pub tcb_addr: GuestAddr,
     pub ready_queues: GuestAddr,
+    // Number of lists at ready_queues, configMAX_PRIORITIES of the kernel
+    pub num_prios: usize,
     pub delay_queue: GuestAddr,
-    pub delay_queue_overflow: GuestAddr,
+    // Absent if the kernel is built without tick overflow handling
+    pub delay_queue_overflow: Option<GuestAddr>,
     pub scheduler_lock_addr: GuestAddr,
     pub scheduler_running_addr: GuestAddr,
     pub critical_addr: GuestAddr,
-    pub job_done_addrs: GuestAddr,
+    // Absent if the target does not signal job completion, disables job tracing
+    pub job_done_addrs: Option<GuestAddr>,
+    // Blocking API calls which end a job, infers the responses instead of job_done_addrs if not empty
+    pub job_done_apis: Vec<String>,
+    // Queue handles whose occupancy is captured, by name
+    pub queue_handles: Vec<(Cow<'static, str>, GuestAddr)>,
+    // Address of the optional target error variable
+    pub error_addr: Option<GuestAddr>,
+    // Address of the optional input counter of the target
+    pub input_counter_addr: Option<GuestAddr>,
+    // Tick budget per ISR name
+    pub isr_budgets: HashMap<String, u64>,
+    // Function symbols for annotating ABBs
+    pub symbols: SymbolResolver,
+    // Trace of the current execution, filled by the hooks
+    pub capture: CaptureBuffer<RawFreeRTOSSystemState>,
+    // Cleared raw states of earlier executions, reused by the captures to keep their allocations
+    pub raw_pool: Vec<RawFreeRTOSSystemState>,
+    // Refined states of the prefix shared by the previous executions
+    pub refine_cache: RefineCache,
+    // Icount of the last write to the error variable, whose value is inspected on the next write or at the end of the execution
+    pub error_last_write: Option<u64>,
+    // Functions ending critical sections, captures inside a critical section are retried when they return
+    pub critical_exit_addrs: Vec<GuestAddr>,
+    // Indices of captures in a critical section whose lists are not read yet
+    pub deferred_captures: Vec<usize>,
+    // Hooks on the entries of the critical exit functions, active while captures are deferred
+    pub critical_exit_hooks: Vec<InstructionHookId>,
+    // One-shot hooks on the return addresses of critical exit functions
+    pub deferred_return_hooks: Vec<(GuestAddr, InstructionHookId)>,
+    // First nonzero error code written by the target and the icount of the write
+    pub target_error: Option<(u32, u64)>,
+    // The hooks filter by address ranges themselves
+    address_filter: NopAddressFilter,
+    page_filter: NopPageFilter,
 }
 
 impl FreeRTOSSystemStateHelper {
-    #[must_use]
+    /// Fails with a list of all required symbols missing from the target
     pub fn new(
         target_symbols: &HashMap<&'static str, GuestAddr>,
         target_ranges: &HashMap<&'static str, Range<GuestAddr>>,
         target_groups: &HashMap<&'static str, HashMap<String, Range<GuestAddr>>>,
-    ) -> Self {
+    ) -> Result<Self, String> {
+        let mut missing = Vec::new();
+        if !target_ranges.contains_key("APP_CODE") {
+            missing.push("APP_CODE");
+        }
+        for group in ["API_FN", "ISR_FN"] {
+            if !target_groups.contains_key(group) {
+                missing.push(group);
+            }
+        }
+        for sym in ["FUZZ_INPUT", "pxCurrentTCB", "pxReadyTasksLists", "pxDelayedTaskList", "uxSchedulerSuspended", "xSchedulerRunning", "uxCriticalNesting"] {
+            if !target_symbols.contains_key(sym) {
+                missing.push(sym);
+            }
+        }
+        if !missing.is_empty() {
+            return Err(format!("Target is missing required symbols: {}", missing.join(", ")));
+        }
+
         let app_range = target_ranges.get("APP_CODE").unwrap().clone();
 
         let api_fn_ranges : Vec<_> = target_groups.get("API_FN").unwrap().iter().sorted_by_key(|x|x.1.start).map(|(n,r)| (Cow::Borrowed(Box::leak(n.clone().into_boxed_str())),r.clone())).collect();
@@ -73,13 +130,28 @@ impl FreeRTOSSystemStateHelper {
         let tcb_addr = *target_symbols.get("pxCurrentTCB").unwrap();
         let ready_queues = *target_symbols.get("pxReadyTasksLists").unwrap();
         let delay_queue = *target_symbols.get("pxDelayedTaskList").unwrap();
-        let delay_queue_overflow = *target_symbols.get("pxOverflowDelayedTaskList").unwrap();
+        let delay_queue_overflow = target_symbols.get("pxOverflowDelayedTaskList").copied();
         let scheduler_lock_addr = *target_symbols.get("uxSchedulerSuspended").unwrap();
         let scheduler_running_addr = *target_symbols.get("xSchedulerRunning").unwrap();
         let critical_addr = *target_symbols.get("uxCriticalNesting").unwrap();
-        let job_done_addrs = *target_symbols.get("trigger_job_done").unwrap();
+        let job_done_addrs = target_symbols.get("trigger_job_done").copied();
+        #[cfg(feature = "trace_job_response_times")]
+        if job_done_addrs.is_none() {
+            eprintln!("Symbol trigger_job_done not found, job response times are only traced with JOB_DONE_APIS");
+        }
+        let error_addr = target_symbols.get("FUZZ_ERROR").copied();
+        let input_counter_addr = target_symbols.get("FUZZ_POINTER").copied();
+        let critical_exit_addrs = ["vPortExitCritical", "xTaskResumeAll"]
+            .iter()
+            .filter_map(|x| target_symbols.get(x).copied())
+            .collect();
+        let queue_handles = target_symbols
+            .iter()
+            .filter_map(|(k, v)| k.strip_prefix(QUEUE_SYMBOL_PREFIX).map(|n| (Cow::Borrowed(n), *v)))
+            .sorted()
+            .collect();
 
-        FreeRTOSSystemStateHelper {
+        Ok(FreeRTOSSystemStateHelper {
             app_range,
             api_fn_addrs,
             api_fn_ranges,
@@ -88,13 +160,52 @@ impl FreeRTOSSystemStateHelper {
             input_mem,
             tcb_addr,
             ready_queues,
+            num_prios: super::num_prios(),
             delay_queue,
             delay_queue_overflow,
             scheduler_lock_addr,
             scheduler_running_addr,
             critical_addr,
             job_done_addrs,
-        }
+            job_done_apis: Vec::new(),
+            queue_handles,
+            error_addr,
+            input_counter_addr,
+            isr_budgets: HashMap::new(),
+            symbols: SymbolResolver::from_groups(target_groups),
+            capture: CaptureBuffer::new(input_mem.clone()),
+            raw_pool: Vec::new(),
+            refine_cache: RefineCache::default(),
+            error_last_write: None,
+            target_error: None,
+            critical_exit_addrs,
+            deferred_captures: Vec::new(),
+            critical_exit_hooks: Vec::new(),
+            deferred_return_hooks: Vec::new(),
+            address_filter: NopAddressFilter,
+            page_filter: NopPageFilter,
+        })
+    }
+
+    /// Set the maximum ticks each ISR invocation may take
+    #[must_use]
+    pub fn with_isr_budgets(mut self, isr_budgets: HashMap<String, u64>) -> Self {
+        self.isr_budgets = isr_budgets;
+        self
+    }
+
+    /// Infer the job responses from calls to `apis` which block the task, e.g. vTaskDelay, for targets without trigger_job_done
+    #[must_use]
+    pub fn with_job_done_apis(mut self, apis: Vec<String>) -> Self {
+        self.job_done_apis = apis;
+        self
+    }
+
+    /// Stop capturing states of an execution beyond the `limits`
+    #[must_use]
+    pub fn with_trace_limits(mut self, limits: TraceLimits) -> Self {
+        self.capture.limits = limits;
+        self
     }
 }
 
@@ -114,11 +225,13 @@ where
             Hook::Function(trace_jmp::<ET, S>),
         );
         #[cfg(feature = "trace_job_response_times")]
-        emulator_modules.instructions(
-            self.job_done_addrs,
-            Hook::Function(job_done_hook::<ET, S>),
-            false,
-        );
+        if let Some(job_done_addrs) = self.job_done_addrs.filter(|_| self.job_done_apis.is_empty()) {
+            emulator_modules.instructions(
+                job_done_addrs,
+                Hook::Function(job_done_hook::<ET, S>),
+                false,
+            );
+        }
         #[cfg(feature = "trace_reads")]
         emulator_modules.reads(
             Hook::Function(gen_read_is_input::<ET, S>),
@@ -128,7 +241,16 @@ where
             Hook::Empty,
             Hook::Function(trace_reads::<ET, S>),
         );
-        unsafe { INPUT_MEM = self.input_mem.clone() };
+        if self.error_addr.is_some() {
+            emulator_modules.writes(
+                Hook::Function(gen_error_write::<ET, S>),
+                Hook::Function(trace_error_write::<ET, S>),
+                Hook::Function(trace_error_write::<ET, S>),
+                Hook::Function(trace_error_write::<ET, S>),
+                Hook::Function(trace_error_write::<ET, S>),
+                Hook::Function(trace_error_write_n::<ET, S>),
+            );
+        }
     }
 
     // TODO: refactor duplicate code
@@ -140,13 +262,17 @@ where
     ) where
         ET: EmulatorModuleTuple<S>,
     {
-        unsafe {
-            CURRENT_SYSTEMSTATE_VEC.clear();
-            JOBS_DONE.clear();
-        }
+        self.capture.clear();
+        self.error_last_write = None;
+        self.target_error = None;
+        self.deferred_captures.clear();
+        remove_deferred_hooks(self);
         if state.has_metadata::<FreeRTOSTraceMetadata>() {
             state.remove_metadata::<FreeRTOSTraceMetadata>();
         }
+        if state.has_metadata::<TargetErrorMetadata>() {
+            state.remove_metadata::<TargetErrorMetadata>();
+        }
     }
 
     fn post_exec<OT, ET>(
@@ -161,7 +287,13 @@ where
         ET: EmulatorModuleTuple<S>,
     {
         let mut need_to_debug = false;
-        if unsafe { CURRENT_SYSTEMSTATE_VEC.len() } == 0 {
+        let mut error_classes = Vec::new();
+        // The last write to the error variable has not been inspected yet
+        check_error_write(&emulator_modules.qemu(), self);
+        if let Some((code, tick)) = self.target_error {
+            _state.add_metadata(TargetErrorMetadata { code, tick });
+        }
+        if self.capture.states.is_empty() {
             eprintln!("No system states captured, aborting");
             return;
         }
@@ -169,92 +301,96 @@ where
         trigger_collection(&emulator_modules.qemu(), (0, 0), CaptureEvent::End, self);
         let c = emulator_modules.qemu().cpu_from_index(0);
         let pc = c.read_reg::<i32>(15).unwrap();
-        let last = unsafe { CURRENT_SYSTEMSTATE_VEC.last_mut().unwrap() };
+        let last = self.capture.states.last_mut().unwrap();
         last.edge = (pc, 0);
         last.capture_point =(CaptureEvent::End, Cow::Borrowed("Breakpoint"));
+        if let Some(addr) = self.input_counter_addr {
+            self.capture.check_input_counter(QemuLookup::lookup(&emulator_modules.qemu(), addr));
+        }
         // Find the first ISREnd of vPortSVCHandler (start of the first task) and drop anything before
-        unsafe {
-            let mut index = 0;
-            while index < CURRENT_SYSTEMSTATE_VEC.len() {
-                if CaptureEvent::ISREnd == CURRENT_SYSTEMSTATE_VEC[index].capture_point.0
-                    && CURRENT_SYSTEMSTATE_VEC[index].capture_point.1 == "xPortPendSVHandler"
-                {
-                    break;
-                }
-                index += 1;
+        let states = &mut self.capture.states;
+        let mut index = 0;
+        while index < states.len() {
+            if CaptureEvent::ISREnd == states[index].capture_point.0
+                && states[index].capture_point.1 == "xPortPendSVHandler"
+            {
+                break;
             }
-            drop(CURRENT_SYSTEMSTATE_VEC.drain(..index));
-            if CURRENT_SYSTEMSTATE_VEC.len() == 1 {
-                eprintln!("No system states captured, aborting");
-                return;
+            index += 1;
+        }
+        #[cfg(feature = "snapshot_at_first_task")]
+        if index < states.len() && !_state.has_metadata::<TaskStartMetadata>() {
+            if let Some((_, r)) = self.isr_fn_ranges.iter().find(|(n, _)| n == "xPortPendSVHandler") {
+                _state.add_metadata(TaskStartMetadata {
+                    isr_entry: r.start,
+                    tick: states[index].qemu_tick,
+                    input_read: cfg!(feature = "trace_reads")
+                        .then(|| states[..=index].iter().any(|s| !s.mem_reads.is_empty())),
+                });
             }
         }
+        drop(states.drain(..index));
+        if states.len() == 1 {
+            eprintln!("No system states captured, aborting");
+            return;
+        }
+        let deferred_captures = states.iter().filter(|s| s.deferred).count();
+        let invalid_captures = states.iter().filter(|s| s.read_invalid).count();
         // Start refining the state trace
-        let (refined_states, metadata) =
-            refine_system_states(unsafe { CURRENT_SYSTEMSTATE_VEC.split_off(0) });
-        let (intervals, mem_reads, dumped_states, success) =
-            states2intervals(refined_states.clone(), metadata);
+        self.refine_cache.check_epoch(snapshot_epoch());
+        let (refined_states, hashes, metadata, invalid_task_names, stack_high_water) = refine_system_states(&mut self.capture.states, &mut self.raw_pool, &mut self.refine_cache);
+        let invalid_task_names = invalid_task_names + self.capture.invalid_task_names;
+        let (intervals, mem_reads, dumped_states, abb_error, isr_worst_durations) =
+            states2intervals_with_hashes(refined_states.clone(), &hashes, metadata, "xPortPendSVHandler", &self.symbols);
+        let success = abb_error.is_none();
+        #[allow(unused_mut)]
+        let mut first_error = abb_error;
         need_to_debug |= !success;
+        if !success {
+            error_classes.push(ErrorClass::AbbMismatch);
+        }
+        if invalid_captures > 0 {
+            error_classes.push(ErrorClass::InvalidRead);
+        }
+        if invalid_task_names > 0 {
+            error_classes.push(ErrorClass::Utf8Name);
+        }
+        if self.capture.truncated {
+            error_classes.push(ErrorClass::Truncation);
+        }
+        let isr_budget_exceeded = isr_worst_durations
+            .iter()
+            .any(|(name, duration)| self.isr_budgets.get(name).map_or(false, |budget| duration > budget));
+        let isr_activations = isr_activations(&intervals);
+        if !_state.has_metadata::<IsrActivationStats>() {
+            _state.add_metadata(IsrActivationStats::default());
+        }
+        _state.metadata_mut::<IsrActivationStats>().unwrap().add(&isr_activations);
         #[cfg(not(feature = "trace_job_response_times"))]
         let jobs = Vec::new();
         #[cfg(feature = "trace_job_response_times")]
-        let jobs = {
+        let jobs = if self.job_done_addrs.is_none() && self.job_done_apis.is_empty() {
+            Vec::new()
+        } else {
             let releases = get_releases(&intervals, &dumped_states);
-            let responses = unsafe { JOBS_DONE.split_off(0) };
-            let (job_spans, do_report) = get_release_response_pairs(&releases, &responses);
-            need_to_debug |= do_report;
-
-            let jobs : Vec<RTOSJob> = job_spans
-                .into_iter()
-                .map(|x| {
-                    let intervals_of_job_x = intervals
-                        .iter()
-                        .enumerate()
-                        .filter(|y| {
-                            y.1.start_tick <= x.1
-                                && y.1.end_tick >= x.0
-                                && x.2 == y.1.get_task_name_unchecked()
-                        })
-                        .map(|(idx, x)| (x, &mem_reads[idx]))
-                        .collect::<Vec<_>>();
-
-                    let (abbs, rest): (Vec<_>, Vec<_>) = intervals_of_job_x
-                        .chunk_by(|a, b| {
-                            a.0.abb
-                                .as_ref()
-                                .unwrap()
-                                .instance_eq(b.0.abb.as_ref().unwrap())
-                        })
-                        .into_iter() // group by abb
-                        .map(|intervals| {
-                            (
-                                intervals[0].0.abb.as_ref().unwrap().clone(),
-                                (
-                                    intervals.iter().fold(0, |sum, z| sum + z.0.get_exec_time()),
-                                    intervals.iter().fold(Vec::new(), |mut sum, z| {
-                                        sum.extend(z.1.iter());
-                                        sum
-                                    }),
-                                ),
-                            )
-                        })
-                        .unzip();
-                    let (ticks_per_abb, mem_reads_per_abb): (Vec<_>, Vec<_>) = rest.into_iter().unzip();
-                    RTOSJob {
-                        name: x.2,
-                        mem_reads: mem_reads_per_abb.into_iter().flatten().collect(), // TODO: add read values
-                        release: x.0,
-                        response: x.1,
-                        exec_ticks: ticks_per_abb.iter().sum(),
-                        ticks_per_abb: ticks_per_abb,
-                        abbs: abbs,
-                        hash_cache: 0,
-                    }
-                })
-                .collect::<Vec<_>>();
+            let inferred = !self.job_done_apis.is_empty();
+            let responses = if inferred {
+                infer_responses(&intervals, &dumped_states, &self.job_done_apis)
+            } else {
+                self.capture.take_jobs()
+            };
+            let (job_spans, unpaired) = get_release_response_pairs(&releases, &responses);
+            need_to_debug |= unpaired.is_some();
+            if let Some(tick) = unpaired {
+                error_classes.push(ErrorClass::ReleaseResponsePairing);
+                first_error = RefinementError::earlier(first_error, Some(RefinementError::at_tick(ErrorClass::ReleaseResponsePairing, &intervals, tick)));
+            }
+
+            let mut jobs = get_jobs(job_spans, &intervals, &mem_reads, &dumped_states);
+            jobs.iter_mut().for_each(|j| j.inferred = inferred);
             jobs
         };
-        _state.add_metadata(FreeRTOSTraceMetadata::new(refined_states, intervals, mem_reads, jobs, need_to_debug));
+        _state.add_metadata(FreeRTOSTraceMetadata::new(refined_states, hashes, intervals, mem_reads, jobs, need_to_debug, isr_worst_durations, isr_budget_exceeded, deferred_captures, invalid_captures, isr_activations, self.capture.truncated, invalid_task_names, stack_high_water).with_error_classes(error_classes).with_first_error(first_error));
     }
 
     type ModuleAddressFilter = NopAddressFilter;
@@ -262,26 +398,24 @@ where
     type ModulePageFilter = NopPageFilter;
 
     fn address_filter(&self) -> &Self::ModuleAddressFilter {
-        todo!()
+        &self.address_filter
     }
 
     fn address_filter_mut(&mut self) -> &mut Self::ModuleAddressFilter {
-        todo!()
+        &mut self.address_filter
     }
 
     fn page_filter(&self) -> &Self::ModulePageFilter {
-        todo!()
+        &self.page_filter
     }
 
     fn page_filter_mut(&mut self) -> &mut Self::ModulePageFilter {
-        todo!()
+        &mut self.page_filter
     }
 }
 
 //============================= Trace job response times
 
-pub static mut JOBS_DONE: Vec<(u64, String)> = vec![];
-
 pub fn job_done_hook<QT, S>(
     hooks: &mut EmulatorModules<QT, S>,
     _state: Option<&mut S>,
@@ -292,23 +426,17 @@ pub fn job_done_hook<QT, S>(
 {
     let emulator = hooks.qemu();
     let h = hooks
-        .modules()
-        .match_first_type::<FreeRTOSSystemStateHelper>()
+        .modules_mut()
+        .match_first_type_mut::<FreeRTOSSystemStateHelper>()
         .expect("QemuSystemHelper not found in helper tupel");
     let curr_tcb_addr: bindings::void_ptr = super::QemuLookup::lookup(&emulator, h.tcb_addr);
     if curr_tcb_addr == 0 {
         return;
     };
     let current_tcb: TCB_t = super::QemuLookup::lookup(&emulator, curr_tcb_addr);
-    let tmp = unsafe { std::mem::transmute::<[i8; 10], [u8; 10]>(current_tcb.pcTaskName) };
-    let name: String = std::str::from_utf8(&tmp)
-        .expect("TCB name was not utf8")
-        .chars()
-        .filter(|x| *x != '\0')
-        .collect::<String>();
-    unsafe {
-        JOBS_DONE.push((get_icount(&emulator), name));
-    }
+    let (name, valid) = super::task_name_lossy(current_tcb.pcTaskName);
+    h.capture.invalid_task_names += usize::from(!valid);
+    h.capture.on_job_done(&emulator, name);
 }
 
 //============================= Trace interrupt service routines
@@ -323,11 +451,12 @@ pub fn exec_isr_hook<QT, S>(
 {
     let emulator = hooks.qemu();
     let h = hooks
-        .modules()
-        .match_first_type::<FreeRTOSSystemStateHelper>()
+        .modules_mut()
+        .match_first_type_mut::<FreeRTOSSystemStateHelper>()
         .expect("QemuSystemHelper not found in helper tupel");
     let src = read_rec_return_stackframe(&emulator, 0xfffffffc);
     trigger_collection(&emulator, (src, pc), CaptureEvent::ISRStart, h);
+    arm_deferred_capture(hooks);
     // println!("Exec ISR Call {:#x} {:#x} {}", src, pc, get_icount(emulator));
 }
 
@@ -383,11 +512,11 @@ pub fn trace_jmp<QT, S>(
     S: UsesInput,
     QT: EmulatorModuleTuple<S>,
 {
+    let emulator = hooks.qemu();
     let h = hooks
-        .modules()
-        .match_first_type::<FreeRTOSSystemStateHelper>()
+        .modules_mut()
+        .match_first_type_mut::<FreeRTOSSystemStateHelper>()
         .expect("QemuSystemHelper not found in helper tupel");
-    let emulator = hooks.qemu();
     if id == 1 {
         // API call
         trigger_collection(&emulator, (src, dest), CaptureEvent::APIStart, h);
@@ -416,6 +545,94 @@ pub fn trace_jmp<QT, S>(
         trigger_collection(&emulator, edge, CaptureEvent::ISREnd, h);
         // println!("Exec ISR Return Edge {:#x} {:#x} {}", src, dest, get_icount(emulator));
     }
+    arm_deferred_capture(hooks);
+}
+
+//============================= Deferred captures
+
+/// Hooks the critical exit functions if a capture was deferred and they are not hooked yet
+fn arm_deferred_capture<QT, S>(hooks: &mut EmulatorModules<QT, S>)
+where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
+{
+    let h = hooks
+        .modules()
+        .match_first_type::<FreeRTOSSystemStateHelper>()
+        .expect("QemuSystemHelper not found in helper tupel");
+    if h.deferred_captures.is_empty() || !h.critical_exit_hooks.is_empty() {
+        return;
+    }
+    let addrs = h.critical_exit_addrs.clone();
+    let ids: Vec<_> = addrs
+        .into_iter()
+        .filter_map(|addr| hooks.instructions(addr, Hook::Function(critical_exit_hook::<QT, S>), true))
+        .collect();
+    hooks
+        .modules_mut()
+        .match_first_type_mut::<FreeRTOSSystemStateHelper>()
+        .unwrap()
+        .critical_exit_hooks = ids;
+}
+
+/// Removes all hooks placed for deferred captures
+fn remove_deferred_hooks(h: &mut FreeRTOSSystemStateHelper) {
+    for id in h.critical_exit_hooks.drain(..) {
+        id.remove(true);
+    }
+    for (_, id) in h.deferred_return_hooks.drain(..) {
+        id.remove(true);
+    }
+}
+
+/// Entry of a function which may end a critical section, hooks its return address once
+pub fn critical_exit_hook<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
+    _state: Option<&mut S>,
+    _pc: GuestAddr,
+) where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
+{
+    let emulator = hooks.qemu();
+    let ret = emulator.cpu_from_index(0).read_reg::<_, u32>(14).unwrap_or(0) as GuestAddr & !1;
+    let h = hooks
+        .modules()
+        .match_first_type::<FreeRTOSSystemStateHelper>()
+        .expect("QemuSystemHelper not found in helper tupel");
+    if h.deferred_return_hooks.iter().any(|(addr, _)| *addr == ret) {
+        return;
+    }
+    if let Some(id) = hooks.instructions(ret, Hook::Function(deferred_capture_hook::<QT, S>), true) {
+        hooks
+            .modules_mut()
+            .match_first_type_mut::<FreeRTOSSystemStateHelper>()
+            .unwrap()
+            .deferred_return_hooks
+            .push((ret, id));
+    }
+}
+
+/// Return from a critical exit function, completes the deferred captures if no critical section is left active
+pub fn deferred_capture_hook<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
+    _state: Option<&mut S>,
+    pc: GuestAddr,
+) where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
+{
+    let emulator = hooks.qemu();
+    let h = hooks
+        .modules_mut()
+        .match_first_type_mut::<FreeRTOSSystemStateHelper>()
+        .expect("QemuSystemHelper not found in helper tupel");
+    if complete_deferred_captures(&emulator, h) {
+        remove_deferred_hooks(h);
+    } else if let Some(i) = h.deferred_return_hooks.iter().position(|(addr, _)| *addr == pc) {
+        // Nested section, wait for the next exit
+        h.deferred_return_hooks.swap_remove(i).1.remove(true);
+    }
 }
 
 //============================= Read Hooks
@@ -443,9 +660,6 @@ where
     return None;
 }
 
-static mut INPUT_MEM: Range<GuestAddr> = 0..0;
-pub static mut MEM_READ: Option<Vec<(GuestAddr, u8)>> = None;
-
 #[allow(unused)]
 pub fn trace_reads<QT, S>(
     hooks: &mut EmulatorModules<QT, S>,
@@ -457,28 +671,94 @@ pub fn trace_reads<QT, S>(
     S: UsesInput,
     QT: EmulatorModuleTuple<S>,
 {
-    if unsafe { INPUT_MEM.contains(&addr) } {
-        let emulator = hooks.qemu();
-        let mut buf: [u8; 1] = [0];
-        unsafe {
-            emulator.read_mem(addr, &mut buf);
+    let emulator = hooks.qemu();
+    let Some(h) = hooks
+        .modules_mut()
+        .match_first_type_mut::<FreeRTOSSystemStateHelper>()
+    else {
+        return;
+    };
+    h.capture.on_read(&emulator, addr);
+}
+
+//============================= Target error variable
+
+/// The address of a store is only known when it runs, so every store is instrumented, including those of the kernel and ISRs.
+/// [`trace_error_write_n`] filters on the address of the error variable.
+pub fn gen_error_write<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
+    _state: Option<&mut S>,
+    _pc: GuestAddr,
+    _addr: *mut TCGTemp,
+    _info: MemAccessInfo,
+) -> Option<u64>
+where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
+{
+    hooks
+        .modules()
+        .match_first_type::<FreeRTOSSystemStateHelper>()
+        .and_then(|h| h.error_addr)
+        .map(|_| 1)
+}
+
+/// Write hooks run before the store, so the value of the previous write is read here
+fn check_error_write(emulator: &libafl_qemu::Qemu, h: &mut FreeRTOSSystemStateHelper) {
+    if h.target_error.is_some() {
+        return;
+    }
+    if let (Some(addr), Some(tick)) = (h.error_addr, h.error_last_write) {
+        let code: u32 = QemuLookup::lookup(emulator, addr);
+        if code != 0 {
+            h.target_error = Some((code, tick));
         }
-        if unsafe { MEM_READ.is_none() } {
-            unsafe { MEM_READ = Some(Vec::from([(addr, buf[0])])) };
-        } else {
-            unsafe { MEM_READ.as_mut().unwrap().push((addr, buf[0])) };
+    }
+}
+
+pub fn trace_error_write<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
+    _state: Option<&mut S>,
+    _id: u64,
+    addr: GuestAddr,
+) where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
+{
+    trace_error_write_n(hooks, _state, _id, addr, 4);
+}
+
+pub fn trace_error_write_n<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
+    _state: Option<&mut S>,
+    _id: u64,
+    addr: GuestAddr,
+    size: usize,
+) where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
+{
+    let emulator = hooks.qemu();
+    let Some(h) = hooks
+        .modules_mut()
+        .match_first_type_mut::<FreeRTOSSystemStateHelper>()
+    else {
+        return;
+    };
+    if let Some(error_addr) = h.error_addr {
+        if addr < error_addr + 4 && error_addr < addr + size as GuestAddr {
+            check_error_write(&emulator, h);
+            h.error_last_write = Some(get_icount(&emulator));
         }
-        // println!("exec_read {:x} {}", addr, size);
     }
 }
 
 //============================= Parsing helpers
 
-/// Parse a List_t containing TCB_t into Vec<TCB_t> from cache. Consumes the elements from cache
-fn tcb_list_to_vec_cached(list: List_t, dump: &mut HashMap<u32, FreeRTOSStruct>) -> Vec<TCB_t> {
-    let mut ret: Vec<TCB_t> = Vec::new();
+/// Parse a List_t containing TCB_t from cache and append them to `ret`. Consumes the elements from cache
+fn tcb_list_to_vec_cached(list: List_t, dump: &mut HashMap<GuestPointer, FreeRTOSStruct>, ret: &mut Vec<TCB_t>) {
     if list.uxNumberOfItems == 0 {
-        return ret;
+        return;
     }
     let last_list_item = match dump
         .remove(&list.pxIndex)
@@ -527,375 +807,154 @@ fn tcb_list_to_vec_cached(list: List_t, dump: &mut HashMap<u32, FreeRTOSStruct>)
         next_index = next_list_item.pxNext;
     }
     ret.push(last_tcb);
-    ret
 }
 
 //============================= State refinement
 
-/// Drains a List of raw SystemStates to produce a refined trace
-/// returns:
-/// - a Vec of FreeRTOSSystemState
-/// - a Vec of FreeRTOSSystemStateContext (qemu_tick, (capture_event, capture_name), edge, mem_reads)
-fn refine_system_states(
-    mut input: Vec<RawFreeRTOSSystemState>,
-) -> (Vec<FreeRTOSSystemState>, Vec<FreeRTOSSystemStateContext>) {
-    let mut ret = (Vec::<_>::new(), Vec::<_>::new());
-    for mut i in input.drain(..) {
-        let cur = RefinedTCB::from_tcb_owned(i.current_tcb);
-        // println!("Refine: {} {:?} {:?} {:x}-{:x}", cur.task_name, i.capture_point.0, i.capture_point.1.to_string(), i.edge.0, i.edge.1);
-        // collect ready list
-        let mut collector = Vec::<RefinedTCB>::new();
-        for j in i.prio_ready_lists.into_iter().rev() {
-            let mut tmp = tcb_list_to_vec_cached(j, &mut i.dumping_ground)
-                .iter()
-                .map(|x| RefinedTCB::from_tcb(x))
-                .collect();
-            collector.append(&mut tmp);
+/// Notes the bytes between the saved stack pointer of `tcb` and the end of its stack, the free stack of its task for stacks growing down.
+/// The saved stack pointer of the running task is the one of its last switch, tasks with garbled names are skipped.
+fn note_stack_free(tcb: &TCB_t, stack_free: &mut Vec<(String, u32)>) {
+    let (name, valid) = super::task_name_lossy(tcb.pcTaskName);
+    if !valid || tcb.pxStack == 0 || tcb.pxTopOfStack == 0 {
+        return;
+    }
+    stack_free.push((name, tcb.pxTopOfStack.saturating_sub(tcb.pxStack) as u32));
+}
+
+/// A refined state and what its raw state adds to the other results of [`refine_system_states`]
+#[derive(Debug, Clone)]
+struct RefinedEntry {
+    state: FreeRTOSSystemState,
+    /// [`compute_hash`] of the state
+    hash: u64,
+    invalid_names: usize,
+    /// Free stack bytes per task, see [`note_stack_free`]
+    stack_free: Vec<(String, u32)>,
+}
+
+/// Refined states of the raw states most executions start with, e.g. the boot and setup of the target.
+/// The raw states are compared by a digest chained over the prefix, so a cached state is only reused if all states before it matched as well.
+/// The cache is filled from one execution and shrinks to the prefix it shares with each later one, it is refilled once empty.
+/// It is dropped when executions start from a different snapshot, a different kernel always comes with a new helper.
+#[derive(Debug, Default)]
+pub struct RefineCache {
+    /// [`snapshot_epoch`] of the cached execution
+    epoch: u64,
+    /// Digest of the raw states up to and including each entry
+    digests: Vec<u64>,
+    entries: Vec<RefinedEntry>,
+}
+
+impl RefineCache {
+    /// Drops the entries if executions start from a different snapshot than the cached one
+    pub fn check_epoch(&mut self, epoch: u64) {
+        if self.epoch != epoch {
+            self.digests.clear();
+            self.entries.clear();
+            self.epoch = epoch;
         }
-        // collect delay list
-        let mut delay_list: Vec<RefinedTCB> =
-            tcb_list_to_vec_cached(i.delay_list, &mut i.dumping_ground)
-                .iter()
-                .map(|x| RefinedTCB::from_tcb(x))
-                .collect();
-        let mut delay_list_overflow: Vec<RefinedTCB> =
-            tcb_list_to_vec_cached(i.delay_list_overflow, &mut i.dumping_ground)
-                .iter()
-                .map(|x| RefinedTCB::from_tcb(x))
-                .collect();
-        delay_list.append(&mut delay_list_overflow);
-        delay_list.sort_by(|a, b| a.task_name.cmp(&b.task_name));
-
-        ret.0.push(FreeRTOSSystemState {
-            current_task: cur,
-            ready_list_after: collector,
-            delay_list_after: delay_list,
-            read_invalid: i.read_invalid,
-            // input_counter: i.input_counter,//+IRQ_INPUT_BYTES_NUMBER,
-        });
-        ret.1.push(FreeRTOSSystemStateContext {
-            qemu_tick: i.qemu_tick,
-            capture_point: (i.capture_point.0, i.capture_point.1),
-            edge: i.edge,
-            mem_reads: i.mem_reads,
-        });
     }
-    return ret;
+
+    /// Number of cached states
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
 }
 
-/// Transform the states and metadata into a list of ExecIntervals, along with a HashMap of states, a list of HashSets marking memory reads and a bool indicating success
-/// returns:
-/// - a Vec of ExecIntervals
-/// - a Vec of HashSets marking memory reads during these intervals
-/// - a HashMap of ReducedFreeRTOSSystemStates by hash
-/// - a bool indicating success
-fn states2intervals(
-    trace: Vec<FreeRTOSSystemState>,
-    meta: Vec<FreeRTOSSystemStateContext>,
-) -> (
-    Vec<ExecInterval>,
-    Vec<Vec<(u32, u8)>>,
-    HashMap<u64, FreeRTOSSystemState>,
-    bool,
-) {
-    if trace.len() == 0 {
-        return (Vec::new(), Vec::new(), HashMap::new(), true);
+/// Refines one raw state, `scratch` holds the TCBs of the list currently refined and is shared by all states
+fn refine_state(i: &mut RawFreeRTOSSystemState, scratch: &mut Vec<TCB_t>) -> RefinedEntry {
+    let mut invalid_names = usize::from(!RefinedTCB::has_valid_name(&i.current_tcb));
+    let mut stack_free = Vec::new();
+    note_stack_free(&i.current_tcb, &mut stack_free);
+    let cur = RefinedTCB::from_tcb_owned(i.current_tcb);
+    // println!("Refine: {} {:?} {:?} {:x}-{:x}", cur.task_name, i.capture_point.0, i.capture_point.1.to_string(), i.edge.0, i.edge.1);
+    // collect ready list, highest priority first
+    scratch.clear();
+    for j in i.prio_ready_lists.iter().rev() {
+        tcb_list_to_vec_cached(*j, &mut i.dumping_ground, scratch);
     }
-    let mut isr_stack: VecDeque<u8> = VecDeque::from([]); // 2+ = ISR, 1 = systemcall, 0 = APP. Trace starts with an ISREnd and executes the app
-
-    let mut level_of_task: HashMap<&str, u8> = HashMap::new();
-
-    let mut ret: Vec<ExecInterval> = vec![];
-    let mut reads: Vec<Vec<(u32, u8)>> = vec![];
-    let mut edges: Vec<(u32, u32)> = vec![];
-    let mut last_hash: u64 = compute_hash(&trace[0]);
-    let mut table: HashMap<u64, FreeRTOSSystemState> = HashMap::new();
-    table.insert(last_hash, trace[0].clone());
-    for i in 0..trace.len() - 1 {
-        let curr_name = trace[i].current_task().task_name().as_str();
-        // let mut interval_name = curr_name;  // Name of the interval, either the task name or the isr/api funtion name
-        let level = match meta[i].capture_point.0 {
-            CaptureEvent::APIEnd => {
-                // API end always exits towards the app
-                if !level_of_task.contains_key(curr_name) {
-                    level_of_task.insert(curr_name, 0);
-                }
-                *level_of_task.get_mut(curr_name).unwrap() = 0;
-                0
-            }
-            CaptureEvent::APIStart => {
-                // API start can only be called in the app
-                if !level_of_task.contains_key(curr_name) {
-                    // Should not happen, apps start from an ISR End. Some input exibited this behavior for unknown reasons
-                    level_of_task.insert(curr_name, 0);
-                }
-                *level_of_task.get_mut(curr_name).unwrap() = 1;
-                // interval_name = &meta[i].2;
-                1
-            }
-            CaptureEvent::ISREnd => {
-                // special case where the next block is an app start
-                if !level_of_task.contains_key(curr_name) {
-                    level_of_task.insert(curr_name, 0);
-                }
-                // nested isr, TODO: Test level > 2
-                if isr_stack.len() > 1 {
-                    // interval_name = ""; // We can't know which isr is running
-                    isr_stack.pop_back().unwrap();
-                    *isr_stack.back().unwrap()
-                } else {
-                    isr_stack.pop_back();
-                    // possibly go back to an api call that is still running for this task
-                    if level_of_task.get(curr_name).unwrap() == &1 {
-                        // interval_name = ""; // We can't know which api is running
-                    }
-                    *level_of_task.get(curr_name).unwrap()
-                }
-            }
-            CaptureEvent::ISRStart => {
-                // special case for isrs which do not capture their end
-                // if meta[i].2 == "ISR_0_Handler" {
-                //     &2
-                // } else {
-                // regular case
-                // interval_name = &meta[i].2;
-                if isr_stack.len() > 0 {
-                    let l = *isr_stack.back().unwrap();
-                    isr_stack.push_back(l + 1);
-                    l + 1
-                } else {
-                    isr_stack.push_back(2);
-                    2
-                }
-                // }
-            }
-            _ => 100,
-        };
-        // if trace[i].2 == CaptureEvent::End {break;}
-        let next_hash = compute_hash(&trace[i + 1]);
-        if !table.contains_key(&next_hash) {
-            table.insert(next_hash, trace[i + 1].clone());
-        }
-        ret.push(ExecInterval {
-            start_tick: meta[i].qemu_tick,
-            end_tick: meta[i + 1].qemu_tick,
-            start_state: last_hash,
-            end_state: next_hash,
-            start_capture: meta[i].capture_point.clone(),
-            end_capture: meta[i + 1].capture_point.clone(),
-            level: level,
-            abb: None,
-        });
-        reads.push(meta[i + 1].mem_reads.clone());
-        last_hash = next_hash;
-        edges.push((meta[i].edge.1, meta[i + 1].edge.0));
+    invalid_names += scratch.iter().filter(|x| !RefinedTCB::has_valid_name(x)).count();
+    if !i.read_invalid {
+        scratch.iter().for_each(|x| note_stack_free(x, &mut stack_free));
+    }
+    let collector: Vec<RefinedTCB> = scratch.iter().map(RefinedTCB::from_tcb).collect();
+    // collect delay list
+    scratch.clear();
+    tcb_list_to_vec_cached(i.delay_list, &mut i.dumping_ground, scratch);
+    tcb_list_to_vec_cached(i.delay_list_overflow, &mut i.dumping_ground, scratch);
+    invalid_names += scratch.iter().filter(|x| !RefinedTCB::has_valid_name(x)).count();
+    if !i.read_invalid {
+        scratch.iter().for_each(|x| note_stack_free(x, &mut stack_free));
     }
-    let t = add_abb_info(&mut ret, &table, &edges);
-    (ret, reads, table, t)
+    let mut delay_list: Vec<RefinedTCB> = scratch.iter().map(RefinedTCB::from_tcb).collect();
+    delay_list.sort_by(|a, b| a.task_name.cmp(&b.task_name));
+
+    let state = FreeRTOSSystemState {
+        current_task: cur,
+        ready_list_after: collector,
+        delay_list_after: delay_list,
+        read_invalid: i.read_invalid || invalid_names > 0,
+        queues: std::mem::take(&mut i.queues),
+        // input_counter: i.input_counter,//+IRQ_INPUT_BYTES_NUMBER,
+    };
+    RefinedEntry { hash: compute_hash(&state), state, invalid_names, stack_free }
 }
 
-/// Marks which abbs were executed at each interval
-fn add_abb_info(
-    trace: &mut Vec<ExecInterval>,
-    table: &HashMap<u64, FreeRTOSSystemState>,
-    edges: &Vec<(u32, u32)>,
-) -> bool {
-    let mut id_count = 0;
-    let mut ret = true;
-    let mut task_has_started: HashSet<&String> = HashSet::new();
-    let mut wip_abb_trace: Vec<Rc<RefCell<AtomicBasicBlock>>> = vec![];
-    // let mut open_abb_at_this_task_or_level : HashMap<(u8,&str),usize> = HashMap::new();
-    let mut open_abb_at_this_ret_addr_and_task: HashMap<(u32, &str), usize> = HashMap::new();
-
-    for i in 0..trace.len() {
-        let curr_name = table[&trace[i].start_state].current_task().task_name();
-        // let last : Option<&usize> = last_abb_start_of_task.get(&curr_name);
-
-        // let open_abb = open_abb_at_this_task_or_level.get(&(trace[i].level, if trace[i].level<2 {&curr_name} else {""})).to_owned();  // apps/apis are differentiated by task name, isrs by nested level
-        let open_abb = open_abb_at_this_ret_addr_and_task
-            .get(&(edges[i].0, if trace[i].level < 2 { &curr_name } else { "" }))
-            .to_owned(); // apps/apis are differentiated by task name, isrs by nested level
-
-        // println!("Edge {:x}-{:x}", edges[i].0.unwrap_or(0xffff), edges[i].1.unwrap_or(0xffff));
-
-        match trace[i].start_capture.0 {
-            // generic api abb start
-            CaptureEvent::APIStart => {
-                // assert_eq!(open_abb, None);
-                ret &= open_abb.is_none();
-                open_abb_at_this_ret_addr_and_task.insert(
-                    (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
-                    i,
-                );
-                wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
-                    start: edges[i].0,
-                    ends: HashSet::new(),
-                    level: if trace[i].level < 2 {
-                        trace[i].level
-                    } else {
-                        2
-                    },
-                    instance_id: id_count,
-                    instance_name: Some(trace[i].start_capture.1.clone()),
-                })));
-                id_count += 1;
-            }
-            // generic isr abb start
-            CaptureEvent::ISRStart => {
-                // assert_eq!(open_abb, None);
-                ret &= open_abb.is_none();
-                open_abb_at_this_ret_addr_and_task.insert(
-                    (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
-                    i,
-                );
-                wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
-                    start: edges[i].0,
-                    ends: HashSet::new(),
-                    level: if trace[i].level < 2 {
-                        trace[i].level
-                    } else {
-                        2
-                    },
-                    instance_id: id_count,
-                    instance_name: Some(trace[i].start_capture.1.clone()),
-                })));
-                id_count += 1;
-            }
-            // generic app abb start
-            CaptureEvent::APIEnd => {
-                // assert_eq!(open_abb, None);
-                ret &= open_abb.is_none();
-                open_abb_at_this_ret_addr_and_task.insert(
-                    (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
-                    i,
-                );
-                wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
-                    start: edges[i].0,
-                    ends: HashSet::new(),
-                    level: if trace[i].level < 2 {
-                        trace[i].level
-                    } else {
-                        2
-                    },
-                    instance_id: id_count,
-                    instance_name: if trace[i].level < 2 {
-                        Some(Cow::Owned(curr_name.to_owned()))
-                    } else {
-                        None
-                    },
-                })));
-                id_count += 1;
-            }
-            // generic continued blocks
-            CaptureEvent::ISREnd => {
-                // special case app abb start
-                if trace[i].start_capture.1 == "xPortPendSVHandler"
-                    && !task_has_started.contains(&curr_name)
-                {
-                    // assert_eq!(open_abb, None);
-                    ret &= open_abb.is_none();
-                    wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
-                        start: 0,
-                        ends: HashSet::new(),
-                        level: if trace[i].level < 2 {
-                            trace[i].level
-                        } else {
-                            2
-                        },
-                        instance_id: id_count,
-                        instance_name: Some(Cow::Owned(curr_name.to_owned())),
-                    })));
-                    id_count += 1;
-                    open_abb_at_this_ret_addr_and_task.insert(
-                        (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
-                        i,
-                    );
-                    task_has_started.insert(&curr_name);
-                } else {
-                    if let Some(last) = open_abb_at_this_ret_addr_and_task
-                        .get(&(edges[i].0, if trace[i].level < 2 { &curr_name } else { "" }))
-                    {
-                        let last = last.clone(); // required to drop immutable reference
-                        wip_abb_trace.push(wip_abb_trace[last].clone());
-                        // if the abb is interrupted again, it will need to continue at edge[i].1
-                        open_abb_at_this_ret_addr_and_task.remove(&(
-                            edges[i].0,
-                            if trace[i].level < 2 { &curr_name } else { "" },
-                        ));
-                        open_abb_at_this_ret_addr_and_task.insert(
-                            (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
-                            last,
-                        ); // order matters!
-                    } else {
-                        // panic!();
-                        // println!("Continued block with no start {} {} {:?} {:?} {:x}-{:x} {} {}", curr_name, trace[i].start_tick, trace[i].start_capture, trace[i].end_capture, edges[i].0, edges[i].1, task_has_started.contains(curr_name),trace[i].level);
-                        // println!("{:x?}", open_abb_at_this_ret_addr_and_task);
-                        ret = false;
-                        wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
-                            start: edges[i].1,
-                            ends: HashSet::new(),
-                            level: if trace[i].level < 2 {
-                                trace[i].level
-                            } else {
-                                2
-                            },
-                            instance_id: id_count,
-                            instance_name: if trace[i].level < 1 {
-                                Some(Cow::Owned(curr_name.to_owned()))
-                            } else {
-                                None
-                            },
-                        })));
-                        id_count += 1;
-                    }
-                }
-            }
-            _ => panic!("Undefined block start"),
+/// Drains a List of raw SystemStates to produce a refined trace, the drained states are cleared into `pool`.
+/// The states of the prefix shared with the previous executions are taken from `cache`, only the rest is refined.
+/// returns:
+/// - a Vec of FreeRTOSSystemState
+/// - the [`compute_hash`] of each state
+/// - a Vec of FreeRTOSSystemStateContext (qemu_tick, (capture_event, capture_name), edge, mem_reads)
+/// - the number of task names which were not utf8, the states containing them are marked invalid
+/// - the stack high water mark of each task, the least free stack seen like uxTaskGetStackHighWaterMark, see [`note_stack_free`]
+fn refine_system_states(
+    input: &mut Vec<RawFreeRTOSSystemState>,
+    pool: &mut Vec<RawFreeRTOSSystemState>,
+    cache: &mut RefineCache,
+) -> (Vec<FreeRTOSSystemState>, Vec<u64>, Vec<FreeRTOSSystemStateContext>, usize, HashMap<String, u32>) {
+    let mut ret = (Vec::with_capacity(input.len()), Vec::with_capacity(input.len()), Vec::with_capacity(input.len()), 0, HashMap::new());
+    let mut scratch: Vec<TCB_t> = Vec::new();
+    let cached = cache.len();
+    // Number of leading states found in the cache and the digest of the raw states up to the current one
+    let mut shared = 0;
+    let mut digest = 0;
+    for (n, mut i) in input.drain(..).enumerate() {
+        if cached == 0 || n == shared {
+            digest = compute_hash(&(digest, i.refinement_digest()));
         }
-        match trace[i].end_capture.0 {
-            // generic app abb end
-            CaptureEvent::APIStart => {
-                let _t = &wip_abb_trace[i];
-                RefCell::borrow_mut(&*wip_abb_trace[i])
-                    .ends
-                    .insert(edges[i].1);
-                open_abb_at_this_ret_addr_and_task
-                    .remove(&(edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }));
-            }
-            // generic api abb end
-            CaptureEvent::APIEnd => {
-                RefCell::borrow_mut(&*wip_abb_trace[i])
-                    .ends
-                    .insert(edges[i].1);
-                open_abb_at_this_ret_addr_and_task
-                    .remove(&(edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }));
-            }
-            // generic isr abb end
-            CaptureEvent::ISREnd => {
-                RefCell::borrow_mut(&*wip_abb_trace[i])
-                    .ends
-                    .insert(edges[i].1);
-                open_abb_at_this_ret_addr_and_task
-                    .remove(&(edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }));
-            }
-            // end anything
-            CaptureEvent::End => {
-                RefCell::borrow_mut(&*wip_abb_trace[i])
-                    .ends
-                    .insert(edges[i].1);
-                open_abb_at_this_ret_addr_and_task
-                    .remove(&(edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }));
-            }
-            CaptureEvent::ISRStart => (),
-            _ => panic!("Undefined block end"),
+        let entry = if n == shared && n < cached && cache.digests[n] == digest {
+            shared += 1;
+            cache.entries[n].clone()
+        } else {
+            refine_state(&mut i, &mut scratch)
+        };
+        if cached == 0 {
+            cache.digests.push(digest);
+            cache.entries.push(entry.clone());
+        }
+        for (name, free) in entry.stack_free {
+            let high_water = ret.4.entry(name).or_insert(free);
+            *high_water = (*high_water).min(free);
         }
-        // println!("{} {} {:x}-{:x} {:x}-{:x} {:?} {:?} {}",curr_name, trace[i].level, edges[i].0, edges[i].1, ((*wip_abb_trace[i])).borrow().start, ((*wip_abb_trace[i])).borrow().ends.iter().next().unwrap_or(&0xffff), trace[i].start_capture, trace[i].end_capture, trace[i].start_tick);
-        // println!("{:x?}", open_abb_at_this_ret_addr_and_task);
+        ret.0.push(entry.state);
+        ret.1.push(entry.hash);
+        ret.2.push(FreeRTOSSystemStateContext {
+            qemu_tick: i.qemu_tick,
+            capture_point: std::mem::take(&mut i.capture_point),
+            edge: i.edge,
+            mem_reads: std::mem::take(&mut i.mem_reads),
+        });
+        ret.3 += entry.invalid_names;
+        pool.push(i.recycle());
     }
-    // drop(open_abb_at_this_task_or_level);
-
-    for i in 0..trace.len() {
-        trace[i].abb = Some((*wip_abb_trace[i]).borrow().clone());
+    if cached > 0 {
+        cache.digests.truncate(shared);
+        cache.entries.truncate(shared);
     }
     return ret;
 }
@@ -1076,90 +1135,389 @@ fn get_releases(
     ret
 }
 
-fn get_release_response_pairs(
-    rel: &Vec<(u64, String)>,
-    resp: &Vec<(u64, String)>,
-) -> (Vec<(u64, u64, String)>, bool) {
-    let mut maybe_error = false;
-    let mut ret = Vec::new();
-    let mut ready: HashMap<&String, u64> = HashMap::new();
-    let mut last_response: HashMap<&String, u64> = HashMap::new();
-    let mut r = rel.iter().peekable();
-    let mut d = resp.iter().peekable();
-    loop {
-        while let Some(peek_rel) = r.peek() {
-            // Fill releases as soon as possible
-            if !ready.contains_key(&peek_rel.1) {
-                ready.insert(&peek_rel.1, peek_rel.0);
-                r.next();
-            } else {
-                if let Some(peek_resp) = d.peek() {
-                    if peek_resp.0 > peek_rel.0 {
-                        // multiple releases before response
-                        // It is unclear which release is real
-                        // maybe_error = true;
-                        // eprintln!("Task {} released multiple times before response ({:.1}ms and {:.1}ms)", peek_rel.1, crate::time::clock::tick_to_time(ready[&peek_rel.1]).as_micros()/1000, crate::time::clock::tick_to_time(peek_rel.0).as_micros()/1000);
-                        // ready.insert(&peek_rel.1, peek_rel.0);
-                        r.next();
-                    } else {
-                        // releases have overtaken responses, wait until the ready list clears up a bit
-                        break;
-                    }
-                } else {
-                    // no more responses
-                    break;
-                }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::RefinedQueue;
+    use crate::systemstate::{target_os::intervals::states2intervals, RTOSJob};
+    use crate::systemstate::target_os::osek::{self, bindings::{Os_TaskDynType, Os_TaskType, TaskStateType, READY, RUNNING, SUSPENDED}, RawOSEKSystemState};
+
+    fn tcb(name: &str, priority: u32) -> TCB_t {
+        let mut t = TCB_t { uxPriority: priority as _, uxBasePriority: priority as _, ..Default::default() };
+        t.pcTaskName.iter_mut().zip(name.bytes()).for_each(|(d, s)| *d = s as i8);
+        t
+    }
+
+    /// Lays out `tasks` as a list at `base` the way `read_freertos_list` finds it, starting at the end marker
+    fn list(dump: &mut HashMap<GuestPointer, FreeRTOSStruct>, base: GuestPointer, tasks: &[TCB_t]) -> List_t {
+        let item = |k: usize| base + 0x20 * (k as GuestPointer + 1);
+        let next = |k: usize| if k + 1 < tasks.len() { item(k + 1) } else { base };
+        dump.insert(base, List_MiniItem_struct(MiniListItem_t { pxNext: if tasks.is_empty() { base } else { item(0) }, ..Default::default() }));
+        for (k, t) in tasks.iter().enumerate() {
+            let owner = base + 0x800 + 0x100 * k as GuestPointer;
+            dump.insert(owner, TCB_struct(*t));
+            dump.insert(item(k), List_Item_struct(ListItem_t { pxNext: next(k), pvOwner: owner, pvContainer: base, ..Default::default() }));
+        }
+        List_t { uxNumberOfItems: tasks.len() as _, pxIndex: base, ..Default::default() }
+    }
+
+    /// A raw state with `current` running, each of `ready` in the ready list of its priority and `delayed` in the delay list
+    fn raw_state(tick: u64, capture: (CaptureEvent, &'static str), edge: (GuestAddr, GuestAddr), current: &str, ready: &[(&str, u32)], delayed: &[(&str, u32)]) -> RawFreeRTOSSystemState {
+        let mut dump = HashMap::new();
+        let prio = ready.iter().chain(delayed).find(|x| x.0 == current).map_or(0, |x| x.1);
+        let num_prios = ready.iter().map(|x| x.1 + 1).max().unwrap_or(1);
+        let prio_ready_lists = (0..num_prios).map(|p| {
+            let tasks: Vec<_> = ready.iter().filter(|x| x.1 == p).map(|x| tcb(x.0, p)).collect();
+            list(&mut dump, 0x1000 * (p as GuestPointer + 1), &tasks)
+        }).collect();
+        let delayed: Vec<_> = delayed.iter().map(|x| tcb(x.0, x.1)).collect();
+        let delay_list = list(&mut dump, 0x1000 * (num_prios as GuestPointer + 1), &delayed);
+        RawFreeRTOSSystemState {
+            qemu_tick: tick,
+            current_tcb: tcb(current, prio),
+            prio_ready_lists,
+            delay_list,
+            dumping_ground: dump,
+            edge,
+            capture_point: (capture.0, Cow::Borrowed(capture.1)),
+            ..Default::default()
+        }
+    }
+
+    /// T1 runs until it delays at tick 10, T2 runs from 20 until the end at 50 and reads an input byte
+    fn freertos_two_tasks() -> Vec<RawFreeRTOSSystemState> {
+        let both = [("T1", 2), ("T2", 1)];
+        let mut trace = vec![
+            raw_state(0, (CaptureEvent::ISREnd, "xPortPendSVHandler"), (0, 0x100), "T1", &both, &[]),
+            raw_state(10, (CaptureEvent::APIStart, "vTaskDelay"), (0x110, 0x500), "T1", &both, &[]),
+            raw_state(20, (CaptureEvent::APIEnd, "vTaskDelay"), (0x510, 0x200), "T2", &[("T2", 1)], &[("T1", 2)]),
+            raw_state(50, (CaptureEvent::End, "Breakpoint"), (0x210, 0), "T2", &[("T2", 1)], &[("T1", 2)]),
+        ];
+        trace[3].mem_reads = vec![(0x2000, 7)];
+        trace
+    }
+
+    fn osek_state(tick: u64, capture: (CaptureEvent, &'static str), edge: (GuestAddr, GuestAddr), states: [TaskStateType; 2]) -> RawOSEKSystemState {
+        RawOSEKSystemState {
+            task_configs: vec![Os_TaskType { index: 0, basePriority: 2, ..Default::default() }, Os_TaskType { index: 1, basePriority: 1, ..Default::default() }],
+            task_dyn_states: states.iter().zip([2, 1]).map(|(s, p)| Os_TaskDynType { state: *s, currentPriority: p, activationCount: 1, ..Default::default() }).collect(),
+            task_names: vec!["T1".to_string(), "T2".to_string()],
+            icount: tick,
+            capture_point: (capture.0, Cow::Borrowed(capture.1)),
+            edge,
+            ..Default::default()
+        }
+    }
+
+    /// The schedule of [`freertos_two_tasks`], T1 terminates instead of delaying
+    fn osek_two_tasks() -> Vec<RawOSEKSystemState> {
+        let mut trace = vec![
+            osek_state(0, (CaptureEvent::ISREnd, "Os_ContextSwitchHandler"), (0, 0x100), [RUNNING, READY]),
+            osek_state(10, (CaptureEvent::APIStart, "TerminateTask"), (0x110, 0x500), [RUNNING, READY]),
+            osek_state(20, (CaptureEvent::APIEnd, "TerminateTask"), (0x510, 0x200), [SUSPENDED, RUNNING]),
+            osek_state(50, (CaptureEvent::End, "Breakpoint"), (0x210, 0), [SUSPENDED, RUNNING]),
+        ];
+        trace[3].mem_reads = vec![(0x2000, 7)];
+        trace
+    }
+
+    type IntervalSummary = Vec<(u64, u64, u8, Option<String>)>;
+    type JobSummary = Vec<(String, u64, u64, u64, usize, u64, Vec<(u32, u8)>)>;
+
+    /// Ticks and levels of the intervals and the tasks running in them, API and ISR names differ between the kernels
+    fn summary(intervals: &[ExecInterval], jobs: &[RTOSJob]) -> (IntervalSummary, JobSummary) {
+        (
+            intervals.iter().map(|x| (x.start_tick, x.end_tick, x.level, (x.level == 0).then(|| x.get_task_name_unchecked().to_string()))).collect(),
+            jobs.iter().map(|x| (x.name.clone(), x.release, x.response, x.exec_ticks, x.abbs.len(), x.preempted_ticks, x.mem_reads.clone())).collect(),
+        )
+    }
+
+    #[test]
+    fn freertos_and_osek_give_the_same_jobs() {
+        let responses = vec![(10, "T1".to_string()), (50, "T2".to_string())];
+
+        let (states, hashes, meta, invalid_names, _) = refine_system_states(&mut freertos_two_tasks(), &mut Vec::new(), &mut RefineCache::default());
+        assert_eq!(invalid_names, 0);
+        let (intervals, reads, table, error, _) = states2intervals_with_hashes(states, &hashes, meta, "xPortPendSVHandler", &SymbolResolver::default());
+        assert!(error.is_none(), "{:?}", error);
+        let (spans, unpaired) = get_release_response_pairs(&get_releases(&intervals, &table), &responses);
+        assert_eq!(unpaired, None);
+        let freertos = summary(&intervals, &get_jobs(spans, &intervals, &reads, &table));
+
+        let (states, meta) = osek::qemu_module::refine_system_states(osek_two_tasks());
+        let (intervals, reads, table, error, _) = states2intervals(states, meta, "Os_ContextSwitchHandler", &SymbolResolver::default());
+        assert!(error.is_none(), "{:?}", error);
+        let (spans, unpaired) = get_release_response_pairs(&osek::qemu_module::get_releases(&intervals, &table), &responses);
+        assert_eq!(unpaired, None);
+        let osek = summary(&intervals, &get_jobs(spans, &intervals, &reads, &table));
+
+        assert_eq!(freertos, osek);
+        assert_eq!(freertos.0, vec![(0, 10, 0, Some("T1".to_string())), (10, 20, 1, None), (20, 50, 0, Some("T2".to_string()))]);
+        let jobs: Vec<_> = freertos.1.iter().map(|x| (x.0.as_str(), x.2 - x.1, x.3)).collect();
+        assert_eq!(jobs, vec![("T1", 10, 10), ("T2", 50, 30)]);
+        assert_eq!(freertos.1[1].6, vec![(0x2000, 7)]);
+    }
+
+    type Refinement = (Vec<FreeRTOSSystemState>, Vec<u64>, Vec<(u64, (CaptureEvent, Cow<'static, str>), (GuestAddr, GuestAddr), Vec<(u32, u8)>)>, usize);
+
+    fn refine(mut trace: Vec<RawFreeRTOSSystemState>, cache: &mut RefineCache) -> Refinement {
+        let (states, hashes, meta, invalid_names, _) = refine_system_states(&mut trace, &mut Vec::new(), cache);
+        (states, hashes, meta.into_iter().map(|x| (x.qemu_tick, x.capture_point, x.edge, x.mem_reads)).collect(), invalid_names)
+    }
+
+    /// Both tasks ready at the start, then each of `schedule` runs for 10 ticks before calling an API
+    fn schedule(start: u64, schedule: &[&str]) -> Vec<RawFreeRTOSSystemState> {
+        let both = [("T1", 2), ("T2", 1)];
+        let mut trace = vec![raw_state(start, (CaptureEvent::ISREnd, "xPortPendSVHandler"), (0, 0x100), "T1", &both, &[])];
+        for (n, task) in schedule.iter().enumerate() {
+            let tick = start + 10 * (n as u64 + 1);
+            let (ready, delayed): (Vec<_>, Vec<_>) = both.into_iter().partition(|x| x.0 == *task);
+            trace.push(raw_state(tick, (CaptureEvent::APIStart, "vTaskDelay"), (0x110, 0x500), task, &ready, &delayed));
+            trace.last_mut().unwrap().mem_reads = vec![(0x2000 + n as u32, n as u8)];
+        }
+        trace
+    }
+
+    #[test]
+    fn cached_prefix_matches_full_refinement() {
+        let mut cache = RefineCache::default();
+        cache.check_epoch(1);
+        let first = schedule(0, &["T1", "T2", "T1", "T2"]);
+        assert_eq!(refine(first.clone(), &mut cache), refine(first.clone(), &mut RefineCache::default()));
+        assert_eq!(cache.len(), 5);
+
+        // shares the first three states, at different ticks, then diverges and meets the first trace again
+        let second = schedule(3, &["T1", "T2", "T2", "T2"]);
+        assert_eq!(refine(second.clone(), &mut cache), refine(second.clone(), &mut RefineCache::default()));
+        assert_eq!(cache.len(), 3);
+        // the cached prefix only holds states both traces agreed on
+        let third = schedule(0, &["T2", "T2", "T1", "T2"]);
+        assert_eq!(refine(third.clone(), &mut cache), refine(third, &mut RefineCache::default()));
+        assert_eq!(cache.len(), 1);
+
+        // a different snapshot drops the cache, the next trace refills it
+        cache.check_epoch(2);
+        assert!(cache.is_empty());
+        assert_eq!(refine(second.clone(), &mut cache), refine(second, &mut RefineCache::default()));
+        assert_eq!(cache.len(), 5);
+    }
+
+    /// Refinement of a single state as it was before raw states and scratch lists were reused, every list into a fresh vector
+    fn refine_fresh(raw: &RawFreeRTOSSystemState) -> FreeRTOSSystemState {
+        let mut dump = raw.dumping_ground.clone();
+        let mut refine_list = |list: List_t| {
+            let mut tcbs = Vec::new();
+            tcb_list_to_vec_cached(list, &mut dump, &mut tcbs);
+            tcbs.iter().map(RefinedTCB::from_tcb).collect::<Vec<_>>()
+        };
+        let ready_list_after = raw.prio_ready_lists.iter().rev().flat_map(|x| refine_list(*x)).collect();
+        let mut delay_list_after = refine_list(raw.delay_list);
+        delay_list_after.append(&mut refine_list(raw.delay_list_overflow));
+        delay_list_after.sort_by(|a, b| a.task_name.cmp(&b.task_name));
+        FreeRTOSSystemState {
+            current_task: RefinedTCB::from_tcb(&raw.current_tcb),
+            ready_list_after,
+            delay_list_after,
+            read_invalid: raw.read_invalid,
+            queues: raw.queues.clone(),
+        }
+    }
+
+    /// Lists of several tasks, an overflowing delay list, queues and an invalid read
+    fn fixture() -> Vec<RawFreeRTOSSystemState> {
+        let mut trace = vec![
+            raw_state(0, (CaptureEvent::ISREnd, "xPortPendSVHandler"), (0, 0x100), "T1", &[("T1", 2), ("T2", 1), ("T3", 1), ("IDLE", 0)], &[]),
+            raw_state(10, (CaptureEvent::APIStart, "vTaskDelay"), (0x110, 0x500), "T1", &[("T2", 1), ("IDLE", 0)], &[("T3", 1), ("T1", 2)]),
+            raw_state(20, (CaptureEvent::ISRStart, "xPortSysTickHandler"), (0x210, 0x600), "T2", &[("T2", 1), ("T3", 1), ("IDLE", 0)], &[("T1", 2)]),
+            raw_state(30, (CaptureEvent::End, "Breakpoint"), (0x610, 0), "IDLE", &[("IDLE", 0)], &[]),
+        ];
+        let overflow = [tcb("T2", 1), tcb("T3", 1)];
+        trace[3].delay_list_overflow = list(&mut trace[3].dumping_ground, 0x9000, &overflow);
+        trace[1].queues = vec![RefinedQueue { name: "q".to_string(), messages_waiting: 2, waiting_senders: 0, waiting_receivers: 1 }];
+        trace[2].read_invalid = true;
+        trace[2].mem_reads = vec![(0x2000, 1), (0x2001, 2)];
+        trace
+    }
+
+    #[test]
+    fn reused_buffers_refine_like_fresh_ones() {
+        let trace = fixture();
+        let expected: Vec<_> = trace.iter().map(refine_fresh).collect();
+        let mut pool = Vec::new();
+        // the second round refines with the scratch list and pool of the first
+        for _ in 0..2 {
+            let (states, hashes, meta, _, _) = refine_system_states(&mut fixture(), &mut pool, &mut RefineCache::default());
+            assert_eq!(states, expected);
+            assert_eq!(hashes, expected.iter().map(compute_hash).collect::<Vec<_>>());
+            let context: Vec<_> = meta.into_iter().map(|x| (x.qemu_tick, x.capture_point, x.edge, x.mem_reads)).collect();
+            let raw_context: Vec<_> = trace.iter().map(|x| (x.qemu_tick, x.capture_point.clone(), x.edge, x.mem_reads.clone())).collect();
+            assert_eq!(context, raw_context);
+        }
+        // drained states are cleared, but keep their allocations
+        assert_eq!(pool.len(), 2 * trace.len());
+        assert!(pool.iter().all(|x| x.dumping_ground.is_empty() && x.dumping_ground.capacity() > 0 && x.prio_ready_lists.is_empty() && x.mem_reads.is_empty() && x.qemu_tick == 0 && !x.read_invalid));
+        assert_eq!(refine_fresh(&pool[0]), refine_fresh(&RawFreeRTOSSystemState::default()));
+    }
+
+    #[test]
+    fn invalid_task_names_mark_the_state() {
+        let mut trace = schedule(0, &["T1"]);
+        trace[1].current_tcb.pcTaskName[1] = -1;
+        let (states, _, _, invalid_names) = refine(trace, &mut RefineCache::default());
+        assert_eq!(invalid_names, 1);
+        assert_eq!((states[0].read_invalid, states[1].read_invalid), (false, true));
+        assert_eq!(states[1].current_task.task_name, "T\u{fffd}");
+    }
+
+    type TargetMaps = (HashMap<&'static str, GuestAddr>, HashMap<&'static str, Range<GuestAddr>>, HashMap<&'static str, HashMap<String, Range<GuestAddr>>>);
+
+    /// Symbols of a kernel with all optional features
+    fn target_maps() -> TargetMaps {
+        let symbols = HashMap::from([
+            ("FUZZ_INPUT", 0x2000), ("pxCurrentTCB", 0x3000), ("pxReadyTasksLists", 0x3010), ("pxDelayedTaskList", 0x3100),
+            ("pxOverflowDelayedTaskList", 0x3104), ("uxSchedulerSuspended", 0x3200), ("xSchedulerRunning", 0x3204),
+            ("uxCriticalNesting", 0x3208), ("trigger_job_done", 0x1800), ("vPortExitCritical", 0x1900),
+        ]);
+        let ranges = HashMap::from([("APP_CODE", 0x1000..0x1800)]);
+        let groups = HashMap::from([
+            ("API_FN", HashMap::from([("vTaskDelay".to_string(), 0x500..0x540)])),
+            ("ISR_FN", HashMap::from([("xPortPendSVHandler".to_string(), 0x600..0x640)])),
+        ]);
+        (symbols, ranges, groups)
+    }
+
+    #[test]
+    fn helper_with_all_symbols() {
+        let (symbols, ranges, groups) = target_maps();
+        let h = FreeRTOSSystemStateHelper::new(&symbols, &ranges, &groups).unwrap();
+        assert_eq!((h.tcb_addr, h.delay_queue, h.delay_queue_overflow, h.job_done_addrs), (0x3000, 0x3100, Some(0x3104), Some(0x1800)));
+        assert_eq!(h.critical_exit_addrs, vec![0x1900]);
+        assert_eq!(h.api_fn_addrs.get(&0x500).map(|x| x.as_ref()), Some("vTaskDelay"));
+    }
+
+    #[test]
+    fn helper_without_optional_symbols() {
+        let (mut symbols, ranges, groups) = target_maps();
+        for sym in ["pxOverflowDelayedTaskList", "trigger_job_done", "vPortExitCritical"] {
+            symbols.remove(sym);
+        }
+        let h = FreeRTOSSystemStateHelper::new(&symbols, &ranges, &groups).unwrap();
+        assert_eq!((h.delay_queue_overflow, h.job_done_addrs), (None, None));
+        assert!(h.critical_exit_addrs.is_empty());
+    }
+
+    #[test]
+    fn helper_lists_all_missing_symbols() {
+        let (mut symbols, mut ranges, mut groups) = target_maps();
+        symbols.remove("pxDelayedTaskList");
+        symbols.remove("uxCriticalNesting");
+        groups.remove("ISR_FN");
+        let err = FreeRTOSSystemStateHelper::new(&symbols, &ranges, &groups).err().unwrap();
+        assert_eq!(err, "Target is missing required symbols: ISR_FN, pxDelayedTaskList, uxCriticalNesting");
+        ranges.clear();
+        let err = FreeRTOSSystemStateHelper::new(&symbols, &ranges, &groups).err().unwrap();
+        assert!(err.ends_with(": APP_CODE, ISR_FN, pxDelayedTaskList, uxCriticalNesting"), "{}", err);
+    }
+
+    /// T1 runs with T2 delayed, a tick interrupt at 20 releases T2. The capture at the interrupt entry shows T2 as ready already.
+    fn torn_tick_interrupt(read_invalid: bool) -> Vec<RawFreeRTOSSystemState> {
+        let mut trace = vec![
+            raw_state(0, (CaptureEvent::ISREnd, "xPortPendSVHandler"), (0, 0x100), "T1", &[("T1", 2)], &[("T2", 1)]),
+            raw_state(10, (CaptureEvent::APIStart, "xQueueReceive"), (0x110, 0x500), "T1", &[("T1", 2)], &[("T2", 1)]),
+            raw_state(12, (CaptureEvent::APIEnd, "xQueueReceive"), (0x510, 0x114), "T1", &[("T1", 2)], &[("T2", 1)]),
+            raw_state(20, (CaptureEvent::ISRStart, "xPortSysTickHandler"), (0x120, 0x600), "T1", &[("T1", 2), ("T2", 1)], &[]),
+            raw_state(25, (CaptureEvent::ISREnd, "xPortSysTickHandler"), (0x610, 0x120), "T1", &[("T1", 2), ("T2", 1)], &[]),
+            raw_state(40, (CaptureEvent::End, "Breakpoint"), (0x130, 0), "T1", &[("T1", 2), ("T2", 1)], &[]),
+        ];
+        trace[3].read_invalid = read_invalid;
+        trace
+    }
+
+    fn releases_of(mut trace: Vec<RawFreeRTOSSystemState>) -> Vec<(u64, String)> {
+        let (states, hashes, meta, _, _) = refine_system_states(&mut trace, &mut Vec::new(), &mut RefineCache::default());
+        let (intervals, _, table, _, _) = states2intervals_with_hashes(states, &hashes, meta, "xPortPendSVHandler", &SymbolResolver::default());
+        assert_eq!(intervals.iter().map(|x| x.start_tick).collect::<Vec<_>>(), vec![0, 10, 12, 20, 25]);
+        get_releases(&intervals, &table)
+    }
+
+    #[test]
+    fn invalid_read_releases_via_fallback() {
+        // the torn capture is skipped, the lists before and after the interrupt show the release of T2
+        assert_eq!(releases_of(torn_tick_interrupt(true)), vec![(0, "T1".to_string()), (25, "T2".to_string())]);
+        // taken at face value, the same capture hides the release
+        assert_eq!(releases_of(torn_tick_interrupt(false)), vec![(0, "T1".to_string())]);
+    }
+
+    /// xorshift64, so the random schedules are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    /// A random running task with every other task ready, delayed or suspended, a random capture point, input reads and queues
+    fn random_state(rng: &mut Rng, tick: u64) -> RawFreeRTOSSystemState {
+        const TASKS: [(&str, u32); 4] = [("T1", 3), ("T2", 2), ("T3", 1), ("IDLE", 0)];
+        const CAPTURES: [(CaptureEvent, &str); 4] = [
+            (CaptureEvent::APIStart, "vTaskDelay"),
+            (CaptureEvent::APIEnd, "xQueueReceive"),
+            (CaptureEvent::ISRStart, "xPortSysTickHandler"),
+            (CaptureEvent::ISREnd, "xPortPendSVHandler"),
+        ];
+        let current = TASKS[rng.below(TASKS.len())];
+        let (mut ready, mut delayed) = (vec![current], vec![]);
+        for task in TASKS.into_iter().filter(|x| x.0 != current.0) {
+            match rng.below(3) {
+                0 => ready.push(task),
+                1 => delayed.push(task),
+                _ => {}
             }
         }
-        if let Some(next_resp) = d.next() {
-            if ready.contains_key(&next_resp.1) {
-                if ready[&next_resp.1] >= next_resp.0 {
-                    if let Some(lr) = last_response.get(&next_resp.1) {
-                        if u128::abs_diff(
-                            crate::time::clock::tick_to_time(next_resp.0).as_micros(),
-                            crate::time::clock::tick_to_time(*lr).as_micros(),
-                        ) > 500
-                        {
-                            // tolerate pending notifications for 500us
-                            maybe_error = true;
-                            // eprintln!("Task {} response at {:.1}ms before next release at {:.1}ms. Fallback to last response at {:.1}ms.", next_resp.1, crate::time::clock::tick_to_time(next_resp.0).as_micros() as f32/1000.0, crate::time::clock::tick_to_time(ready[&next_resp.1]).as_micros() as f32/1000.0, crate::time::clock::tick_to_time(*lr).as_micros() as f32/1000.0);
-                        }
-                        // Sometimes a task is released immediately after a response. This might not be detected.
-                        // Assume that the release occured with the last response
-                        ret.push((*lr, next_resp.0, next_resp.1.clone()));
-                        last_response.insert(&next_resp.1, next_resp.0);
-                    } else {
-                        maybe_error = true;
-                        // eprintln!("Task {} released after response", next_resp.1);
-                    }
-                } else {
-                    // assert!(peek_resp.0 >= ready[&peek_resp.1]);
-                    last_response.insert(&next_resp.1, next_resp.0);
-                    ret.push((ready[&next_resp.1], next_resp.0, next_resp.1.clone()));
-                    ready.remove(&next_resp.1);
-                }
-            } else {
-                if let Some(lr) = last_response.get(&next_resp.1) {
-                    if u128::abs_diff(
-                        crate::time::clock::tick_to_time(next_resp.0).as_micros(),
-                        crate::time::clock::tick_to_time(*lr).as_micros(),
-                    ) > 1000
-                    { // tolerate pending notifications for 1ms
-                         // maybe_error = true;
-                         // eprintln!("Task {} response at {:.1}ms not found in ready list. Fallback to last response at {:.1}ms.", next_resp.1, crate::time::clock::tick_to_time(next_resp.0).as_micros() as f32/1000.0, crate::time::clock::tick_to_time(*lr).as_micros() as f32/1000.0);
-                    }
-                    // Sometimes a task is released immediately after a response (e.g. pending notification). This might not be detected.
-                    // Assume that the release occured with the last response
-                    ret.push((*lr, next_resp.0, next_resp.1.clone()));
-                    last_response.insert(&next_resp.1, next_resp.0);
-                } else {
-                    maybe_error = true;
-                    // eprintln!("Task {} response at {:.1}ms not found in ready list", next_resp.1, crate::time::clock::tick_to_time(next_resp.0).as_micros() as f32/1000.0);
-                }
+        let edge = (0x100 * rng.below(8) as GuestAddr, 0x100 * rng.below(8) as GuestAddr);
+        let mut state = raw_state(tick, CAPTURES[rng.below(CAPTURES.len())], edge, current.0, &ready, &delayed);
+        state.mem_reads = (0..rng.below(3)).map(|_| (0x2000 + rng.below(16) as u32, rng.below(256) as u8)).collect();
+        state.read_invalid = rng.below(8) == 0;
+        if rng.below(4) == 0 {
+            state.queues = vec![RefinedQueue { name: "q".to_string(), messages_waiting: rng.below(3) as u32, waiting_senders: 0, waiting_receivers: rng.below(2) as u32 }];
+        }
+        state
+    }
+
+    #[test]
+    fn cached_refinement_matches_fresh_on_random_schedules() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let mut cache = RefineCache::default();
+        let mut previous: Vec<RawFreeRTOSSystemState> = Vec::new();
+        let mut hits = 0;
+        for n in 0..500u64 {
+            if rng.below(50) == 0 {
+                cache.check_epoch(n);
             }
-        } else {
-            // TODO: should remaining released tasks be counted as finished?
-            return (ret, maybe_error);
+            // like executions from one snapshot, each trace starts with a part of the previous one, possibly at other ticks
+            let shift = 5 * rng.below(3) as u64;
+            let mut trace: Vec<_> = previous[..rng.below(previous.len() + 1)].to_vec();
+            trace.iter_mut().for_each(|x| x.qemu_tick += shift);
+            let mut tick = trace.last().map_or(0, |x| x.qemu_tick);
+            for _ in 0..rng.below(12) {
+                tick += 1 + rng.below(20) as u64;
+                trace.push(random_state(&mut rng, tick));
+            }
+
+            let filled = !cache.is_empty();
+            let cached = refine(trace.clone(), &mut cache);
+            if filled {
+                hits += cache.len();
+            }
+            assert_eq!(cached, refine(trace.clone(), &mut RefineCache::default()), "trace {n}");
+            assert_eq!(cached.0, trace.iter().map(refine_fresh).collect::<Vec<_>>(), "trace {n}");
+            previous = trace;
         }
+        assert!(hits > 0);
     }
 }
@@ -0,0 +1,911 @@
+//! Interval and job reconstruction shared by all target systems.
+//! Each system refines its raw captures into states and [`SystemStateContext`]s, the rest of the trace processing is common.
+//! Nothing in here touches QEMU, the functions can be driven by synthetic traces of any [`SystemState`],
+//! with a [`SymbolResolver`] built by [`SymbolResolver::from_groups`] from hand written ranges.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{borrow::Cow, collections::VecDeque};
+
+use hashbrown::{HashMap, HashSet};
+use libafl_qemu::GuestAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::systemstate::{helpers::SymbolResolver, AtomicBasicBlock, CaptureEvent, ExecInterval, RTOSJob};
+
+use super::{compute_hash, ErrorClass, SystemState, TaskControlBlock};
+
+/// Capture information accompanying each refined state
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SystemStateContext {
+    pub qemu_tick: u64,
+    pub capture_point: (CaptureEvent, Cow<'static, str>),
+    pub edge: (GuestAddr, GuestAddr),
+    pub mem_reads: Vec<(u32, u8)>,
+}
+
+//============================= Refinement errors
+
+/// Default number of states kept on each side of a [`RefinementError`]
+pub const DEFAULT_ERROR_CONTEXT: usize = 25;
+
+static ERROR_CONTEXT: AtomicUsize = AtomicUsize::new(DEFAULT_ERROR_CONTEXT);
+
+/// Sets the number of states kept on each side of a [`RefinementError`], see `--error-context`
+pub fn set_error_context(states: usize) {
+    ERROR_CONTEXT.store(states, Ordering::Relaxed);
+}
+
+pub fn error_context() -> usize {
+    ERROR_CONTEXT.load(Ordering::Relaxed)
+}
+
+/// The first inconsistency found while refining a trace, along with the capture context of the states around it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefinementError {
+    pub class: ErrorClass,
+    /// Interval at which it was detected, it starts at the refined state with the same index
+    pub index: usize,
+    pub tick: u64,
+    /// Open ABBs of [`add_abb_info`] at that point, as return address, task ("" for ISRs) and the interval which opened them
+    pub open_abbs: Vec<(GuestAddr, String, usize)>,
+    /// Index of the first state in `context`
+    pub context_start: usize,
+    /// Capture events, edges and ticks of the refined states around `index`, empty if the error was found after [`states2intervals`]
+    pub context: Vec<SystemStateContext>,
+}
+
+impl RefinementError {
+    fn new(class: ErrorClass, index: usize, tick: u64, open_abbs: &HashMap<(u32, &str), usize>) -> Self {
+        let mut open_abbs: Vec<_> = open_abbs.iter().map(|((addr, task), i)| (*addr, task.to_string(), *i)).collect();
+        open_abbs.sort_by_key(|x| x.2);
+        Self { class, index, tick, open_abbs, context_start: index, context: Vec::new() }
+    }
+
+    /// An error found at `tick` after the intervals were built, e.g. by [`get_release_response_pairs`]
+    pub fn at_tick(class: ErrorClass, intervals: &[ExecInterval], tick: u64) -> Self {
+        let index = intervals.partition_point(|x| x.end_tick <= tick).min(intervals.len().saturating_sub(1));
+        Self { class, index, tick, open_abbs: Vec::new(), context_start: index, context: Vec::new() }
+    }
+
+    /// Keeps the context of the [`error_context`] states on each side
+    fn with_context(mut self, meta: &[SystemStateContext]) -> Self {
+        let k = error_context();
+        self.context_start = self.index.saturating_sub(k).min(meta.len());
+        self.context = meta[self.context_start..usize::min(self.index + k + 1, meta.len())].to_vec();
+        self
+    }
+
+    /// The error found at the earlier interval
+    pub fn earlier(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(if b.index < a.index { b } else { a }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+//============================= Intervals
+
+/// Transform the states and metadata into a list of ExecIntervals, along with a HashMap of states, a list of HashSets marking memory reads and the first inconsistency
+/// returns:
+/// - a Vec of ExecIntervals
+/// - a Vec of HashSets marking memory reads during these intervals
+/// - a HashMap of the states by hash
+/// - the first inconsistency of the abbs, None on success
+/// - a HashMap of the longest invocation of each ISR in ticks
+/// `task_start_isr` names the ISR whose return first enters a task, see [`add_abb_info`]
+pub fn states2intervals<S: SystemState>(
+    trace: Vec<S>,
+    meta: Vec<SystemStateContext>,
+    task_start_isr: &str,
+    symbols: &SymbolResolver,
+) -> (
+    Vec<ExecInterval>,
+    Vec<Vec<(u32, u8)>>,
+    HashMap<u64, S>,
+    Option<RefinementError>,
+    HashMap<String, u64>,
+) {
+    let hashes: Vec<u64> = trace.iter().map(compute_hash).collect();
+    states2intervals_with_hashes(trace, &hashes, meta, task_start_isr, symbols)
+}
+
+/// Like [`states2intervals`], with the [`compute_hash`] of each state already known, e.g. from the cache of refined states of the previous execution
+pub fn states2intervals_with_hashes<S: SystemState>(
+    trace: Vec<S>,
+    hashes: &[u64],
+    meta: Vec<SystemStateContext>,
+    task_start_isr: &str,
+    symbols: &SymbolResolver,
+) -> (
+    Vec<ExecInterval>,
+    Vec<Vec<(u32, u8)>>,
+    HashMap<u64, S>,
+    Option<RefinementError>,
+    HashMap<String, u64>,
+) {
+    if trace.len() == 0 {
+        return (Vec::new(), Vec::new(), HashMap::new(), None, HashMap::new());
+    }
+    let mut isr_stack: VecDeque<(u8, &str)> = VecDeque::from([]); // level and name of each open ISR. 2+ = ISR, 1 = systemcall, 0 = APP. Trace starts with an ISREnd and executes the app
+    let mut isr_start_ticks: Vec<(&str, u64)> = vec![]; // open ISR invocations, parallel to isr_stack
+    let mut api_of_task: HashMap<&str, &str> = HashMap::new(); // API function a task is in while its level is 1
+    let mut names: Vec<&str> = vec![]; // task, API function or ISR running in each interval
+    let mut isr_worst_durations: HashMap<String, u64> = HashMap::new();
+
+    let mut level_of_task: HashMap<&str, u8> = HashMap::new();
+
+    let mut ret: Vec<ExecInterval> = vec![];
+    let mut reads: Vec<Vec<(u32, u8)>> = vec![];
+    let mut edges: Vec<(u32, u32)> = vec![];
+    let mut last_hash: u64 = hashes[0];
+    let mut table: HashMap<u64, S> = HashMap::new();
+    table.insert(last_hash, trace[0].clone());
+    for i in 0..trace.len() - 1 {
+        let curr_name = trace[i].current_task().task_name().as_str();
+        let (level, name) = match meta[i].capture_point.0 {
+            CaptureEvent::APIEnd => {
+                // API end always exits towards the app
+                if !level_of_task.contains_key(curr_name) {
+                    level_of_task.insert(curr_name, 0);
+                }
+                *level_of_task.get_mut(curr_name).unwrap() = 0;
+                api_of_task.remove(curr_name);
+                (0, curr_name)
+            }
+            CaptureEvent::APIStart => {
+                // API start can only be called in the app
+                if !level_of_task.contains_key(curr_name) {
+                    // Should not happen, apps start from an ISR End. Some input exibited this behavior for unknown reasons
+                    level_of_task.insert(curr_name, 0);
+                }
+                *level_of_task.get_mut(curr_name).unwrap() = 1;
+                api_of_task.insert(curr_name, &meta[i].capture_point.1);
+                (1, &*meta[i].capture_point.1)
+            }
+            CaptureEvent::ISREnd => {
+                // special case where the next block is an app start
+                if !level_of_task.contains_key(curr_name) {
+                    level_of_task.insert(curr_name, 0);
+                }
+                // the trace starts inside the first ISR, so there may be nothing to close
+                if let Some((name, start)) = isr_start_ticks.pop() {
+                    let duration = meta[i].qemu_tick - start;
+                    let worst = isr_worst_durations.entry(name.to_string()).or_insert(0);
+                    *worst = u64::max(*worst, duration);
+                }
+                isr_stack.pop_back();
+                if let Some(outer) = isr_stack.back() {
+                    // nested isr, continue the interrupted one
+                    *outer
+                } else {
+                    // possibly go back to an api call that is still running for this task
+                    let level = *level_of_task.get(curr_name).unwrap();
+                    if level == 1 {
+                        (level, api_of_task.get(curr_name).copied().unwrap_or(curr_name))
+                    } else {
+                        (level, curr_name)
+                    }
+                }
+            }
+            CaptureEvent::ISRStart => {
+                // special case for isrs which do not capture their end
+                // if meta[i].2 == "ISR_0_Handler" {
+                //     &2
+                // } else {
+                // regular case
+                isr_start_ticks.push((&meta[i].capture_point.1, meta[i].qemu_tick));
+                let l = isr_stack.back().map_or(2, |x| x.0 + 1);
+                isr_stack.push_back((l, &*meta[i].capture_point.1));
+                (l, &*meta[i].capture_point.1)
+                // }
+            }
+            _ => (100, curr_name),
+        };
+        names.push(name);
+        // if trace[i].2 == CaptureEvent::End {break;}
+        let next_hash = hashes[i + 1];
+        if !table.contains_key(&next_hash) {
+            table.insert(next_hash, trace[i + 1].clone());
+        }
+        ret.push(ExecInterval {
+            start_tick: meta[i].qemu_tick,
+            end_tick: meta[i + 1].qemu_tick,
+            start_state: last_hash,
+            end_state: next_hash,
+            start_capture: meta[i].capture_point.clone(),
+            end_capture: meta[i + 1].capture_point.clone(),
+            level: level,
+            abb: None,
+        });
+        reads.push(meta[i + 1].mem_reads.clone());
+        last_hash = next_hash;
+        edges.push((meta[i].edge.1, meta[i + 1].edge.0));
+    }
+    let error = add_abb_info(&mut ret, &table, &edges, &names, task_start_isr, symbols).map(|e| e.with_context(&meta));
+    (ret, reads, table, error, isr_worst_durations)
+}
+
+/// Start ticks of the intervals opened by each ISR, i.e. the interrupts which actually fired rather than the ones the input requested
+pub fn isr_activations(intervals: &[ExecInterval]) -> HashMap<String, Vec<u64>> {
+    let mut ret: HashMap<String, Vec<u64>> = HashMap::new();
+    for i in intervals.iter().filter(|x| x.start_capture.0 == CaptureEvent::ISRStart) {
+        ret.entry(i.start_capture.1.to_string()).or_default().push(i.start_tick);
+    }
+    ret
+}
+
+/// Interrupts fired per source, summed over all executions from their [`super::SystemTraceData::isr_activations`]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct IsrActivationStats {
+    pub executions: u64,
+    /// Total activations of each ISR
+    pub activations: HashMap<String, u64>,
+    /// Executions in which each ISR fired at least once
+    pub executions_with: HashMap<String, u64>,
+}
+libafl_bolts::impl_serdeany!(IsrActivationStats);
+
+impl IsrActivationStats {
+    pub fn add(&mut self, activations: &HashMap<String, Vec<u64>>) {
+        self.executions += 1;
+        for (name, ticks) in activations.iter().filter(|x| !x.1.is_empty()) {
+            *self.activations.entry(name.clone()).or_insert(0) += ticks.len() as u64;
+            *self.executions_with.entry(name.clone()).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Marks which abbs were executed at each interval
+/// The return from `task_start_isr` opens the first abb of a task that has not been running before
+/// New abbs are annotated with their enclosing function from `symbols`
+/// `names` holds the task, API function or ISR running in each interval, which names blocks continued without a known start
+/// Returns the first interval whose abb could not be matched, None if all matched up
+pub fn add_abb_info<S: SystemState>(
+    trace: &mut Vec<ExecInterval>,
+    table: &HashMap<u64, S>,
+    edges: &Vec<(u32, u32)>,
+    names: &Vec<&str>,
+    task_start_isr: &str,
+    symbols: &SymbolResolver,
+) -> Option<RefinementError> {
+    let mut id_count = 0;
+    let mut first_error: Option<RefinementError> = None;
+    let mut task_has_started: HashSet<&String> = HashSet::new();
+    let mut wip_abb_trace: Vec<Rc<RefCell<AtomicBasicBlock>>> = vec![];
+    // let mut open_abb_at_this_task_or_level : HashMap<(u8,&str),usize> = HashMap::new();
+    let mut open_abb_at_this_ret_addr_and_task: HashMap<(u32, &str), usize> = HashMap::new();
+
+    for i in 0..trace.len() {
+        let curr_name = table[&trace[i].start_state].current_task().task_name();
+        // let last : Option<&usize> = last_abb_start_of_task.get(&curr_name);
+
+        // let open_abb = open_abb_at_this_task_or_level.get(&(trace[i].level, if trace[i].level<2 {&curr_name} else {""})).to_owned();  // apps/apis are differentiated by task name, isrs by nested level
+        let open_abb = open_abb_at_this_ret_addr_and_task
+            .get(&(edges[i].0, if trace[i].level < 2 { &curr_name } else { "" }))
+            .to_owned(); // apps/apis are differentiated by task name, isrs by nested level
+
+        // println!("Edge {:x}-{:x}", edges[i].0.unwrap_or(0xffff), edges[i].1.unwrap_or(0xffff));
+
+        match trace[i].start_capture.0 {
+            // generic api abb start
+            CaptureEvent::APIStart => {
+                // assert_eq!(open_abb, None);
+                if open_abb.is_some() {
+                    first_error.get_or_insert_with(|| RefinementError::new(ErrorClass::AbbMismatch, i, trace[i].start_tick, &open_abb_at_this_ret_addr_and_task));
+                }
+                open_abb_at_this_ret_addr_and_task.insert(
+                    (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
+                    i,
+                );
+                wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
+                    start: edges[i].0,
+                    ends: HashSet::new(),
+                    level: if trace[i].level < 2 {
+                        trace[i].level
+                    } else {
+                        2
+                    },
+                    symbol: symbols.resolve(edges[i].0),
+                    instance_id: id_count,
+                    instance_name: Some(trace[i].start_capture.1.clone()),
+                })));
+                id_count += 1;
+            }
+            // generic isr abb start
+            CaptureEvent::ISRStart => {
+                // assert_eq!(open_abb, None);
+                if open_abb.is_some() {
+                    first_error.get_or_insert_with(|| RefinementError::new(ErrorClass::AbbMismatch, i, trace[i].start_tick, &open_abb_at_this_ret_addr_and_task));
+                }
+                open_abb_at_this_ret_addr_and_task.insert(
+                    (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
+                    i,
+                );
+                wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
+                    start: edges[i].0,
+                    ends: HashSet::new(),
+                    level: if trace[i].level < 2 {
+                        trace[i].level
+                    } else {
+                        2
+                    },
+                    symbol: symbols.resolve(edges[i].0),
+                    instance_id: id_count,
+                    instance_name: Some(trace[i].start_capture.1.clone()),
+                })));
+                id_count += 1;
+            }
+            // generic app abb start
+            CaptureEvent::APIEnd => {
+                // assert_eq!(open_abb, None);
+                if open_abb.is_some() {
+                    first_error.get_or_insert_with(|| RefinementError::new(ErrorClass::AbbMismatch, i, trace[i].start_tick, &open_abb_at_this_ret_addr_and_task));
+                }
+                open_abb_at_this_ret_addr_and_task.insert(
+                    (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
+                    i,
+                );
+                wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
+                    start: edges[i].0,
+                    ends: HashSet::new(),
+                    level: if trace[i].level < 2 {
+                        trace[i].level
+                    } else {
+                        2
+                    },
+                    symbol: symbols.resolve(edges[i].0),
+                    instance_id: id_count,
+                    instance_name: if trace[i].level < 2 {
+                        Some(Cow::Owned(curr_name.to_owned()))
+                    } else {
+                        None
+                    },
+                })));
+                id_count += 1;
+            }
+            // generic continued blocks
+            CaptureEvent::ISREnd => {
+                // special case app abb start
+                if trace[i].start_capture.1 == task_start_isr
+                    && !task_has_started.contains(&curr_name)
+                {
+                    // assert_eq!(open_abb, None);
+                    if open_abb.is_some() {
+                        first_error.get_or_insert_with(|| RefinementError::new(ErrorClass::AbbMismatch, i, trace[i].start_tick, &open_abb_at_this_ret_addr_and_task));
+                    }
+                    wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
+                        start: 0,
+                        ends: HashSet::new(),
+                        level: if trace[i].level < 2 {
+                            trace[i].level
+                        } else {
+                            2
+                        },
+                        symbol: None,
+                        instance_id: id_count,
+                        instance_name: Some(Cow::Owned(curr_name.to_owned())),
+                    })));
+                    id_count += 1;
+                    open_abb_at_this_ret_addr_and_task.insert(
+                        (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
+                        i,
+                    );
+                    task_has_started.insert(&curr_name);
+                } else {
+                    if let Some(last) = open_abb_at_this_ret_addr_and_task
+                        .get(&(edges[i].0, if trace[i].level < 2 { &curr_name } else { "" }))
+                    {
+                        let last = last.clone(); // required to drop immutable reference
+                        wip_abb_trace.push(wip_abb_trace[last].clone());
+                        // if the abb is interrupted again, it will need to continue at edge[i].1
+                        open_abb_at_this_ret_addr_and_task.remove(&(
+                            edges[i].0,
+                            if trace[i].level < 2 { &curr_name } else { "" },
+                        ));
+                        open_abb_at_this_ret_addr_and_task.insert(
+                            (edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }),
+                            last,
+                        ); // order matters!
+                    } else {
+                        // panic!();
+                        // println!("Continued block with no start {} {} {:?} {:?} {:x}-{:x} {} {}", curr_name, trace[i].start_tick, trace[i].start_capture, trace[i].end_capture, edges[i].0, edges[i].1, task_has_started.contains(curr_name),trace[i].level);
+                        // println!("{:x?}", open_abb_at_this_ret_addr_and_task);
+                        first_error.get_or_insert_with(|| RefinementError::new(ErrorClass::AbbMismatch, i, trace[i].start_tick, &open_abb_at_this_ret_addr_and_task));
+                        wip_abb_trace.push(Rc::new(RefCell::new(AtomicBasicBlock {
+                            start: edges[i].1,
+                            ends: HashSet::new(),
+                            level: if trace[i].level < 2 {
+                                trace[i].level
+                            } else {
+                                2
+                            },
+                            symbol: symbols.resolve(edges[i].1),
+                            instance_id: id_count,
+                            instance_name: Some(Cow::Owned(names[i].to_owned())),
+                        })));
+                        id_count += 1;
+                    }
+                }
+            }
+            _ => panic!("Undefined block start"),
+        }
+        match trace[i].end_capture.0 {
+            // generic app abb end
+            CaptureEvent::APIStart => {
+                let _t = &wip_abb_trace[i];
+                RefCell::borrow_mut(&*wip_abb_trace[i])
+                    .ends
+                    .insert(edges[i].1);
+                open_abb_at_this_ret_addr_and_task
+                    .remove(&(edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }));
+            }
+            // generic api abb end
+            CaptureEvent::APIEnd => {
+                RefCell::borrow_mut(&*wip_abb_trace[i])
+                    .ends
+                    .insert(edges[i].1);
+                open_abb_at_this_ret_addr_and_task
+                    .remove(&(edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }));
+            }
+            // generic isr abb end
+            CaptureEvent::ISREnd => {
+                RefCell::borrow_mut(&*wip_abb_trace[i])
+                    .ends
+                    .insert(edges[i].1);
+                open_abb_at_this_ret_addr_and_task
+                    .remove(&(edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }));
+            }
+            // end anything
+            CaptureEvent::End => {
+                RefCell::borrow_mut(&*wip_abb_trace[i])
+                    .ends
+                    .insert(edges[i].1);
+                open_abb_at_this_ret_addr_and_task
+                    .remove(&(edges[i].1, if trace[i].level < 2 { &curr_name } else { "" }));
+            }
+            CaptureEvent::ISRStart => (),
+            _ => panic!("Undefined block end"),
+        }
+        // println!("{} {} {:x}-{:x} {:x}-{:x} {:?} {:?} {}",curr_name, trace[i].level, edges[i].0, edges[i].1, ((*wip_abb_trace[i])).borrow().start, ((*wip_abb_trace[i])).borrow().ends.iter().next().unwrap_or(&0xffff), trace[i].start_capture, trace[i].end_capture, trace[i].start_tick);
+        // println!("{:x?}", open_abb_at_this_ret_addr_and_task);
+    }
+    // drop(open_abb_at_this_task_or_level);
+
+    for i in 0..trace.len() {
+        trace[i].abb = Some((*wip_abb_trace[i]).borrow().clone());
+    }
+    return first_error;
+}
+
+//============================= Jobs
+
+/// Match task releases with their responses
+/// returns the jobs as (release, response, task name) and the tick of the first response which could not be paired unambiguously
+pub fn get_release_response_pairs(
+    rel: &Vec<(u64, String)>,
+    resp: &Vec<(u64, String)>,
+) -> (Vec<(u64, u64, String)>, Option<u64>) {
+    let mut maybe_error: Option<u64> = None;
+    let mut ret = Vec::new();
+    let mut ready: HashMap<&String, u64> = HashMap::new();
+    let mut last_response: HashMap<&String, u64> = HashMap::new();
+    let mut r = rel.iter().peekable();
+    let mut d = resp.iter().peekable();
+    loop {
+        while let Some(peek_rel) = r.peek() {
+            // Fill releases as soon as possible
+            if !ready.contains_key(&peek_rel.1) {
+                ready.insert(&peek_rel.1, peek_rel.0);
+                r.next();
+            } else {
+                if let Some(peek_resp) = d.peek() {
+                    if peek_resp.0 > peek_rel.0 {
+                        // multiple releases before response
+                        // It is unclear which release is real
+                        // maybe_error = true;
+                        // eprintln!("Task {} released multiple times before response ({:.1}ms and {:.1}ms)", peek_rel.1, crate::time::clock::tick_to_time(ready[&peek_rel.1]).as_micros()/1000, crate::time::clock::tick_to_time(peek_rel.0).as_micros()/1000);
+                        // ready.insert(&peek_rel.1, peek_rel.0);
+                        r.next();
+                    } else {
+                        // releases have overtaken responses, wait until the ready list clears up a bit
+                        break;
+                    }
+                } else {
+                    // no more responses
+                    break;
+                }
+            }
+        }
+        if let Some(next_resp) = d.next() {
+            if ready.contains_key(&next_resp.1) {
+                if ready[&next_resp.1] >= next_resp.0 {
+                    if let Some(lr) = last_response.get(&next_resp.1) {
+                        if u128::abs_diff(
+                            crate::time::clock::tick_to_time(next_resp.0).as_micros(),
+                            crate::time::clock::tick_to_time(*lr).as_micros(),
+                        ) > 500
+                        {
+                            // tolerate pending notifications for 500us
+                            maybe_error.get_or_insert(next_resp.0);
+                            // eprintln!("Task {} response at {:.1}ms before next release at {:.1}ms. Fallback to last response at {:.1}ms.", next_resp.1, crate::time::clock::tick_to_time(next_resp.0).as_micros() as f32/1000.0, crate::time::clock::tick_to_time(ready[&next_resp.1]).as_micros() as f32/1000.0, crate::time::clock::tick_to_time(*lr).as_micros() as f32/1000.0);
+                        }
+                        // Sometimes a task is released immediately after a response. This might not be detected.
+                        // Assume that the release occured with the last response
+                        ret.push((*lr, next_resp.0, next_resp.1.clone()));
+                        last_response.insert(&next_resp.1, next_resp.0);
+                    } else {
+                        maybe_error.get_or_insert(next_resp.0);
+                        // eprintln!("Task {} released after response", next_resp.1);
+                    }
+                } else {
+                    // assert!(peek_resp.0 >= ready[&peek_resp.1]);
+                    last_response.insert(&next_resp.1, next_resp.0);
+                    ret.push((ready[&next_resp.1], next_resp.0, next_resp.1.clone()));
+                    ready.remove(&next_resp.1);
+                }
+            } else {
+                if let Some(lr) = last_response.get(&next_resp.1) {
+                    if u128::abs_diff(
+                        crate::time::clock::tick_to_time(next_resp.0).as_micros(),
+                        crate::time::clock::tick_to_time(*lr).as_micros(),
+                    ) > 1000
+                    { // tolerate pending notifications for 1ms
+                         // maybe_error = true;
+                         // eprintln!("Task {} response at {:.1}ms not found in ready list. Fallback to last response at {:.1}ms.", next_resp.1, crate::time::clock::tick_to_time(next_resp.0).as_micros() as f32/1000.0, crate::time::clock::tick_to_time(*lr).as_micros() as f32/1000.0);
+                    }
+                    // Sometimes a task is released immediately after a response (e.g. pending notification). This might not be detected.
+                    // Assume that the release occured with the last response
+                    ret.push((*lr, next_resp.0, next_resp.1.clone()));
+                    last_response.insert(&next_resp.1, next_resp.0);
+                } else {
+                    maybe_error.get_or_insert(next_resp.0);
+                    // eprintln!("Task {} response at {:.1}ms not found in ready list", next_resp.1, crate::time::clock::tick_to_time(next_resp.0).as_micros() as f32/1000.0);
+                }
+            }
+        } else {
+            // TODO: should remaining released tasks be counted as finished?
+            return (ret, maybe_error);
+        }
+    }
+}
+
+/// Responses of a target without trigger_job_done, inferred from the states: a job of a task completes when the task leaves the CPU
+/// during one of the `blocking_apis` and is on the delay list afterwards, e.g. in the vTaskDelay at the end of its loop.
+/// The response is the start of the blocking call, where an instrumented target would call trigger_job_done.
+/// Returns (tick, task name) like the recorded responses, as input of [`get_release_response_pairs`]
+pub fn infer_responses<S: SystemState>(
+    trace: &Vec<ExecInterval>,
+    states: &HashMap<u64, S>,
+    blocking_apis: &[String],
+) -> Vec<(u64, String)> {
+    let mut ret = Vec::new();
+    // API call each task is in and the tick it started
+    let mut api_of_task: HashMap<&str, (&str, u64)> = HashMap::new();
+    for i in trace {
+        let (Some(start), Some(end)) = (states.get(&i.start_state), states.get(&i.end_state)) else { continue };
+        let name = start.current_task().task_name().as_str();
+        match i.start_capture.0 {
+            CaptureEvent::APIStart => {
+                api_of_task.insert(name, (&i.start_capture.1, i.start_tick));
+            }
+            CaptureEvent::APIEnd => {
+                api_of_task.remove(name);
+            }
+            _ => {}
+        }
+        if end.current_task().task_name() == name || !end.get_delay_list().iter().any(|t| t.task_name() == name) {
+            continue;
+        }
+        if let Some((api, tick)) = api_of_task.remove(name) {
+            if blocking_apis.iter().any(|x| x == api) {
+                ret.push((tick, name.to_string()));
+            }
+        }
+    }
+    ret
+}
+
+/// Count the preemptions a job suffered between its release and response.
+/// A job counts as preempted while another task or an ISR executes and the job's task is still ready (or was interrupted while running).
+/// # Arguments
+/// - `span`: the job as (release, response, task name)
+/// - `trace`: the interval trace of the execution
+/// - `states`: the refined states by hash
+/// # Returns
+/// - the number of distinct preemptions
+/// - the total ticks the job spent ready but not executing
+pub fn get_job_preemptions<S: SystemState>(
+    span: &(u64, u64, String),
+    trace: &Vec<ExecInterval>,
+    states: &HashMap<u64, S>,
+) -> (u32, u64) {
+    let (release, response, name) = span;
+    let mut count = 0;
+    let mut ticks = 0;
+    let mut was_running = false;
+    for i in trace
+        .iter()
+        .filter(|x| x.start_tick < *response && x.end_tick > *release)
+    {
+        if i.level < 2 && i.get_task_name_unchecked() == *name {
+            was_running = true;
+            continue;
+        }
+        let waiting = states.get(&i.start_state).map_or(false, |s| {
+            s.current_task().task_name() == name
+                || s.get_ready_lists().iter().any(|t| t.task_name() == name)
+        });
+        if waiting {
+            ticks += u64::min(i.end_tick, *response) - u64::max(i.start_tick, *release);
+            if was_running {
+                count += 1;
+            }
+        }
+        was_running = false;
+    }
+    (count, ticks)
+}
+
+/// Assemble jobs from (release, response, task name) spans, collecting the abbs and memory reads of each job
+pub fn get_jobs<S: SystemState>(
+    job_spans: Vec<(u64, u64, String)>,
+    intervals: &Vec<ExecInterval>,
+    mem_reads: &Vec<Vec<(u32, u8)>>,
+    states: &HashMap<u64, S>,
+) -> Vec<RTOSJob> {
+    job_spans
+        .into_iter()
+        .map(|x| {
+            let intervals_of_job_x = intervals
+                .iter()
+                .enumerate()
+                .filter(|y| {
+                    y.1.start_tick <= x.1
+                        && y.1.end_tick >= x.0
+                        && x.2 == y.1.get_task_name_unchecked()
+                })
+                .map(|(idx, x)| (x, &mem_reads[idx]))
+                .collect::<Vec<_>>();
+
+            let (abbs, rest): (Vec<_>, Vec<_>) = intervals_of_job_x
+                .chunk_by(|a, b| {
+                    a.0.abb
+                        .as_ref()
+                        .unwrap()
+                        .instance_eq(b.0.abb.as_ref().unwrap())
+                })
+                .into_iter() // group by abb
+                .map(|intervals| {
+                    (
+                        intervals[0].0.abb.as_ref().unwrap().clone(),
+                        (
+                            intervals.iter().fold(0, |sum, z| sum + z.0.get_exec_time()),
+                            intervals.iter().fold(Vec::new(), |mut sum, z| {
+                                sum.extend(z.1.iter());
+                                sum
+                            }),
+                        ),
+                    )
+                })
+                .unzip();
+            let (ticks_per_abb, mem_reads_per_abb): (Vec<_>, Vec<_>) = rest.into_iter().unzip();
+            let (preemption_count, preempted_ticks) = get_job_preemptions(&x, intervals, states);
+            RTOSJob {
+                name: x.2,
+                mem_reads: mem_reads_per_abb.into_iter().flatten().collect(), // TODO: add read values
+                release: x.0,
+                response: x.1,
+                exec_ticks: ticks_per_abb.iter().sum(),
+                ticks_per_abb: ticks_per_abb,
+                abbs: abbs,
+                preemption_count,
+                preempted_ticks,
+                inferred: false,
+                hash_cache: 0,
+            }
+        })
+        .collect::<Vec<_>>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::systemstate::target_os::mock::{MockSystemState, MockTraceBuilder, MOCK_TASK_START_ISR};
+    use crate::systemstate::target_os::SystemTraceData;
+
+    type Refined = (Vec<ExecInterval>, Vec<Vec<(u32, u8)>>, HashMap<u64, MockSystemState>, Option<RefinementError>, HashMap<String, u64>);
+
+    fn run(builder: &MockTraceBuilder) -> Refined {
+        let (states, meta) = builder.refined();
+        states2intervals(states, meta, MOCK_TASK_START_ISR, &SymbolResolver::default())
+    }
+
+    /// T1 is interrupted by ISR_A from tick 10 to 14, which returns to `resume`
+    fn interrupted_task(resume: GuestAddr) -> MockTraceBuilder {
+        MockTraceBuilder::new()
+            .task("T1", 1)
+            .running("T1")
+            .capture(0, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0, 0x100))
+            .capture(10, CaptureEvent::ISRStart, "ISR_A", (0x108, 0x800))
+            .capture(14, CaptureEvent::ISREnd, "ISR_A", (0x820, resume))
+            .capture(30, CaptureEvent::End, "trigger_Qemu_break", (0x130, 0))
+    }
+
+    #[test]
+    fn empty_trace() {
+        let (intervals, reads, table, error, isr_worst) =
+            states2intervals(Vec::<MockSystemState>::new(), Vec::new(), MOCK_TASK_START_ISR, &SymbolResolver::default());
+        assert!(intervals.is_empty() && reads.is_empty() && table.is_empty() && isr_worst.is_empty());
+        assert!(error.is_none());
+    }
+
+    #[test]
+    fn interrupted_block_continues() {
+        let (intervals, _, _, error, isr_worst) = run(&interrupted_task(0x108));
+        assert!(error.is_none(), "{:?}", error);
+        let levels: Vec<_> = intervals.iter().map(|x| (x.level, x.get_task_name_unchecked().to_string())).collect();
+        assert_eq!(levels, vec![(0, "T1".to_string()), (2, "ISR_A".to_string()), (0, "T1".to_string())]);
+        let abbs: Vec<_> = intervals.iter().map(|x| x.abb.as_ref().unwrap()).collect();
+        assert!(abbs[0].instance_eq(abbs[2]));
+        assert!(!abbs[0].instance_eq(abbs[1]));
+        assert_eq!(abbs[2].get_ends(), &HashSet::from([0x130]));
+        assert_eq!(isr_worst, HashMap::from([("ISR_A".to_string(), 4)]));
+        assert_eq!(isr_activations(&intervals), HashMap::from([("ISR_A".to_string(), vec![10])]));
+    }
+
+    #[test]
+    fn isr_returning_elsewhere_is_a_mismatch() {
+        let (intervals, _, _, error, _) = run(&interrupted_task(0x200));
+        let error = error.expect("the block after the ISR has no start");
+        assert_eq!((error.class, error.index, error.tick), (ErrorClass::AbbMismatch, 2, 14));
+        // the block of T1 is still open where the ISR interrupted it
+        assert_eq!(error.open_abbs, vec![(0x108, "T1".to_string(), 0)]);
+        assert_eq!(error.context.len(), 4);
+        // the intervals are still built, the block is named after the task
+        assert_eq!(intervals.len(), 3);
+        assert_eq!(intervals[2].get_task_name_unchecked(), "T1");
+    }
+
+    #[test]
+    fn error_at_tick_finds_the_interval() {
+        let (intervals, _, _, _, _) = run(&interrupted_task(0x108));
+        assert_eq!(RefinementError::at_tick(ErrorClass::ReleaseResponsePairing, &intervals, 12).index, 1);
+        assert_eq!(RefinementError::at_tick(ErrorClass::ReleaseResponsePairing, &intervals, 1000).index, 2);
+        let first = RefinementError::at_tick(ErrorClass::AbbMismatch, &intervals, 0);
+        let later = RefinementError::at_tick(ErrorClass::ReleaseResponsePairing, &intervals, 20);
+        assert_eq!(RefinementError::earlier(Some(later), Some(first)).unwrap().class, ErrorClass::AbbMismatch);
+    }
+
+    #[test]
+    fn job_preempted_by_isr() {
+        let (intervals, reads, table, _, _) = run(&interrupted_task(0x108));
+        let span = (0, 30, "T1".to_string());
+        assert_eq!(get_job_preemptions(&span, &intervals, &table), (1, 4));
+        let jobs = get_jobs(vec![span], &intervals, &reads, &table);
+        // both parts of the interrupted block count as one
+        assert_eq!((jobs[0].exec_ticks, jobs[0].abbs.len(), jobs[0].ticks_per_abb.clone()), (26, 1, vec![26]));
+        assert_eq!(jobs[0].response_time(), 30);
+    }
+
+    #[test]
+    fn nested_isrs_interrupting_an_api_call() {
+        // ISR_B interrupts ISR_A, which interrupted xQueueSend of T1
+        let builder = MockTraceBuilder::new()
+            .task("T1", 1)
+            .running("T1")
+            .capture(0, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0, 0x100))
+            .capture(10, CaptureEvent::APIStart, "xQueueSend", (0x110, 0x500))
+            .capture(20, CaptureEvent::ISRStart, "ISR_A", (0x510, 0x800))
+            .capture(30, CaptureEvent::ISRStart, "ISR_B", (0x810, 0x880))
+            .capture(40, CaptureEvent::ISREnd, "ISR_B", (0x890, 0x810))
+            .capture(45, CaptureEvent::ISREnd, "ISR_A", (0x820, 0x510))
+            .capture(50, CaptureEvent::APIEnd, "xQueueSend", (0x520, 0x114))
+            .capture(60, CaptureEvent::End, "trigger_Qemu_break", (0x130, 0));
+        let (intervals, _, _, error, isr_worst) = run(&builder);
+        assert!(error.is_none(), "{:?}", error);
+        let summary: Vec<_> = intervals.iter().map(|x| (x.level, x.get_task_name_unchecked().to_string())).collect();
+        assert_eq!(summary, vec![
+            (0, "T1".to_string()),
+            (1, "xQueueSend".to_string()),
+            (2, "ISR_A".to_string()),
+            (3, "ISR_B".to_string()),
+            (2, "ISR_A".to_string()),
+            (1, "xQueueSend".to_string()),
+            (0, "T1".to_string()),
+        ]);
+        let abbs: Vec<_> = intervals.iter().map(|x| x.abb.as_ref().unwrap()).collect();
+        // the outer ISR and the API call continue their blocks, the app starts a new one after the call
+        assert!(abbs[4].instance_eq(abbs[2]));
+        assert!(abbs[5].instance_eq(abbs[1]));
+        assert!(!abbs[6].instance_eq(abbs[0]));
+        // ABBs of nested ISRs share the ISR level
+        assert_eq!(abbs[3].get_level(), 2);
+        assert_eq!(isr_worst, HashMap::from([("ISR_A".to_string(), 25), ("ISR_B".to_string(), 10)]));
+    }
+
+    /// T1 blocks in `api` at tick 10 and T2 runs until it blocks in `api` at 50, T1 is delayed meanwhile
+    fn blocking_tasks(api: &str) -> MockTraceBuilder {
+        MockTraceBuilder::new()
+            .task("T1", 2)
+            .task("T2", 1)
+            .running("T1").ready(&["T2"])
+            .capture(0, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0, 0x100))
+            .capture(10, CaptureEvent::APIStart, api, (0x110, 0x500))
+            .running("T2").ready(&[]).delayed(&["T1"])
+            .capture(20, CaptureEvent::APIEnd, api, (0x510, 0x200))
+            .capture(50, CaptureEvent::APIStart, api, (0x210, 0x500))
+            .running("T1").delayed(&["T2"])
+            .capture(55, CaptureEvent::APIEnd, api, (0x510, 0x114))
+            .capture(70, CaptureEvent::End, "trigger_Qemu_break", (0x120, 0))
+    }
+
+    fn apis(names: &[&str]) -> Vec<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn infer_responses_of_delaying_tasks() {
+        let builder = blocking_tasks("vTaskDelay");
+        let (intervals, _, table, _, _) = run(&builder);
+        let responses = infer_responses(&intervals, &table, &apis(&["vTaskDelay", "ulTaskNotifyTake"]));
+        assert_eq!(responses, vec![(10, "T1".to_string()), (50, "T2".to_string())]);
+
+        let trace = builder.with_job_done_apis(&["vTaskDelay"]).build();
+        let jobs: Vec<_> = trace.jobs().iter().map(|x| (x.release, x.response, x.name.as_str(), x.inferred)).collect();
+        assert_eq!(jobs, vec![(0, 10, "T1", true), (0, 50, "T2", true)]);
+        assert!(!trace.need_to_debug());
+    }
+
+    #[test]
+    fn infer_no_response_when_preempted() {
+        // an ISR switches to T2 while T1 is still in vTaskDelay, T1 stays ready
+        let builder = MockTraceBuilder::new()
+            .task("T1", 1)
+            .task("T2", 2)
+            .running("T1").ready(&["T2"])
+            .capture(0, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0, 0x100))
+            .capture(10, CaptureEvent::APIStart, "vTaskDelay", (0x110, 0x500))
+            .capture(12, CaptureEvent::ISRStart, MOCK_TASK_START_ISR, (0x508, 0x800))
+            .running("T2").ready(&["T1"])
+            .capture(16, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0x820, 0x200))
+            .capture(30, CaptureEvent::End, "trigger_Qemu_break", (0x230, 0));
+        let (intervals, _, table, error, _) = run(&builder);
+        assert!(error.is_none(), "{:?}", error);
+        assert!(infer_responses(&intervals, &table, &apis(&["vTaskDelay"])).is_empty());
+
+        let trace = builder.with_job_done_apis(&["vTaskDelay"]).build();
+        assert!(trace.jobs().is_empty());
+        assert!(!trace.need_to_debug());
+    }
+
+    #[test]
+    fn infer_only_listed_apis() {
+        let builder = blocking_tasks("xQueueReceive");
+        let (intervals, _, table, _, _) = run(&builder);
+        assert!(infer_responses(&intervals, &table, &apis(&["vTaskDelay"])).is_empty());
+        assert_eq!(infer_responses(&intervals, &table, &apis(&["xQueueReceive"])), vec![(10, "T1".to_string()), (50, "T2".to_string())]);
+
+        let trace = builder.clone().with_job_done_apis(&["vTaskDelay"]).build();
+        assert!(trace.jobs().is_empty());
+        let trace = builder.with_job_done_apis(&["xQueueReceive"]).build();
+        assert!(trace.jobs().iter().all(|x| x.inferred));
+        assert_eq!(trace.jobs().len(), 2);
+    }
+
+    #[test]
+    fn response_at_the_release_tick_is_unpaired() {
+        let rel = vec![(10, "A".to_string())];
+        let (spans, unpaired) = get_release_response_pairs(&rel, &vec![(10, "A".to_string())]);
+        assert!(spans.is_empty());
+        assert_eq!(unpaired, Some(10));
+    }
+
+    #[test]
+    fn pairing_without_responses() {
+        let rel = vec![(0, "A".to_string()), (5, "B".to_string())];
+        assert_eq!(get_release_response_pairs(&rel, &Vec::new()), (Vec::new(), None));
+    }
+}
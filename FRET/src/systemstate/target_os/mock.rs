@@ -0,0 +1,371 @@
+//! A host-only target system without any QEMU dependency.
+//! Traces are scripted with a [`MockTraceBuilder`] and run through the same interval and job reconstruction as the real targets,
+//! so feedbacks, schedulers and the STG can be developed without a kernel image.
+
+use std::borrow::Cow;
+use std::fmt;
+
+use hashbrown::HashMap;
+use libafl_qemu::GuestAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::systemstate::helpers::SymbolResolver;
+use crate::systemstate::{CaptureEvent, ExecInterval, RTOSJob};
+
+use super::intervals::{get_jobs, get_release_response_pairs, infer_responses, states2intervals, SystemStateContext};
+use super::{compute_hash, SystemState, SystemTraceData, TargetSystem, TaskControlBlock};
+
+/// ISR whose return first enters a task in scripted traces
+pub const MOCK_TASK_START_ISR: &str = "MockStartISR";
+
+//============================= Types
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MockSystem {
+    pub raw_trace: Vec<RawMockSystemState>,
+}
+
+impl TargetSystem for MockSystem {
+    type State = MockSystemState;
+    type TCB = MockTCB;
+    type TraceData = MockTraceMetadata;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq, Eq)]
+pub struct MockTCB {
+    pub task_name: String,
+    pub priority: u32,
+}
+
+impl TaskControlBlock for MockTCB {
+    fn task_name(&self) -> &String {
+        &self.task_name
+    }
+    fn task_name_mut(&mut self) -> &mut String {
+        &mut self.task_name
+    }
+}
+
+/// A scripted capture, the lists hold task names in the order they are given
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RawMockSystemState {
+    pub current_task: String,
+    pub ready_list: Vec<String>,
+    pub delay_list: Vec<String>,
+    pub icount: u64,
+    pub capture_point: (CaptureEvent, Cow<'static, str>),
+    pub edge: (GuestAddr, GuestAddr),
+    pub mem_reads: Vec<(u32, u8)>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, PartialEq)]
+pub struct MockSystemState {
+    pub current_task: MockTCB,
+    pub ready_list: Vec<MockTCB>,
+    pub delay_list: Vec<MockTCB>,
+}
+
+impl SystemState for MockSystemState {
+    type TCB = MockTCB;
+
+    fn current_task(&self) -> &Self::TCB {
+        &self.current_task
+    }
+
+    fn current_task_mut(&mut self) -> &mut Self::TCB {
+        &mut self.current_task
+    }
+
+    fn get_ready_lists(&self) -> &Vec<Self::TCB> {
+        &self.ready_list
+    }
+
+    fn get_delay_list(&self) -> &Vec<Self::TCB> {
+        &self.delay_list
+    }
+
+    fn print_lists(&self) -> String {
+        format!("{}", self)
+    }
+}
+
+impl fmt::Display for MockSystemState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ready = self.ready_list.iter().map(|x| x.task_name.as_str()).collect::<Vec<_>>().join(" ");
+        let delay = self.delay_list.iter().map(|x| x.task_name.as_str()).collect::<Vec<_>>().join(" ");
+        write!(f, "Current: {} | Ready: {} | Delay: {}", self.current_task.task_name, ready, delay)
+    }
+}
+
+impl MockSystemState {
+    /// Looks up the priority of each task in `priorities`, unknown tasks get 0
+    pub fn from_raw(raw: &RawMockSystemState, priorities: &HashMap<String, u32>) -> Self {
+        let tcb = |name: &String| MockTCB { task_name: name.clone(), priority: *priorities.get(name).unwrap_or(&0) };
+        MockSystemState {
+            current_task: tcb(&raw.current_task),
+            ready_list: raw.ready_list.iter().map(tcb).collect(),
+            delay_list: raw.delay_list.iter().map(tcb).collect(),
+        }
+    }
+}
+
+//============================= Trace Metadata
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MockTraceMetadata {
+    tcref: isize,
+    states_map: HashMap<u64, MockSystemState>,
+    intervals: Vec<ExecInterval>,
+    mem_reads: Vec<Vec<(u32, u8)>>,
+    jobs: Vec<RTOSJob>,
+    need_debug: bool,
+    isr_worst_durations: HashMap<String, u64>,
+}
+
+impl MockTraceMetadata {
+    pub fn new(
+        trace: Vec<MockSystemState>,
+        intervals: Vec<ExecInterval>,
+        mem_reads: Vec<Vec<(u32, u8)>>,
+        jobs: Vec<RTOSJob>,
+        need_to_debug: bool,
+        isr_worst_durations: HashMap<String, u64>,
+    ) -> Self {
+        let states_map = trace.into_iter().map(|s| (compute_hash(&s), s)).collect();
+        MockTraceMetadata {
+            tcref: 0,
+            states_map,
+            intervals,
+            mem_reads,
+            jobs,
+            need_debug: need_to_debug,
+            isr_worst_durations,
+        }
+    }
+}
+
+impl libafl_bolts::HasRefCnt for MockTraceMetadata {
+    fn refcnt(&self) -> isize {
+        self.tcref
+    }
+    fn refcnt_mut(&mut self) -> &mut isize {
+        &mut self.tcref
+    }
+}
+
+impl SystemTraceData for MockTraceMetadata {
+    type State = MockSystemState;
+
+    fn states(&self) -> Vec<&Self::State> {
+        self.states_map.values().collect()
+    }
+
+    fn states_map(&self) -> &HashMap<u64, Self::State> {
+        &self.states_map
+    }
+
+    fn intervals(&self) -> &Vec<ExecInterval> {
+        &self.intervals
+    }
+
+    fn mem_reads(&self) -> &Vec<Vec<(u32, u8)>> {
+        &self.mem_reads
+    }
+
+    fn jobs(&self) -> &Vec<RTOSJob> {
+        &self.jobs
+    }
+
+    fn trace_length(&self) -> usize {
+        self.intervals.len()
+    }
+
+    fn need_to_debug(&self) -> bool {
+        self.need_debug
+    }
+
+    fn isr_worst_durations(&self) -> Option<&HashMap<String, u64>> {
+        Some(&self.isr_worst_durations)
+    }
+}
+
+libafl_bolts::impl_serdeany!(MockTraceMetadata);
+libafl_bolts::impl_serdeany!(MockTCB);
+libafl_bolts::impl_serdeany!(MockSystemState);
+libafl_bolts::impl_serdeany!(MockSystem);
+
+//============================= Refinement
+
+/// Drains a list of raw states into refined states and their capture context
+pub fn refine_system_states(
+    mut input: Vec<RawMockSystemState>,
+    priorities: &HashMap<String, u32>,
+) -> (Vec<MockSystemState>, Vec<SystemStateContext>) {
+    let mut ret = (Vec::new(), Vec::new());
+    for raw in input.drain(..) {
+        ret.0.push(MockSystemState::from_raw(&raw, priorities));
+        ret.1.push(SystemStateContext {
+            qemu_tick: raw.icount,
+            capture_point: raw.capture_point,
+            edge: raw.edge,
+            mem_reads: raw.mem_reads,
+        });
+    }
+    ret
+}
+
+/// Find all task releases.
+/// Tasks that are ready at the start of the trace are released at its start,
+/// afterwards a task is released whenever it becomes ready or running without being so at the start of an interval.
+pub fn get_releases(
+    trace: &Vec<ExecInterval>,
+    states: &HashMap<u64, MockSystemState>,
+) -> Vec<(u64, String)> {
+    let active = |s: &MockSystemState| -> Vec<String> {
+        std::iter::once(&s.current_task)
+            .chain(s.ready_list.iter())
+            .filter(|x| !x.task_name.is_empty())
+            .map(|x| x.task_name.clone())
+            .collect()
+    };
+    let mut ret = Vec::new();
+    if let Some(first) = trace.first() {
+        let start_state = states.get(&first.start_state).expect("State not found");
+        ret.extend(active(start_state).into_iter().map(|x| (first.start_tick, x)));
+    }
+    for i in trace {
+        let before = active(states.get(&i.start_state).expect("State not found"));
+        let after = active(states.get(&i.end_state).expect("State not found"));
+        ret.extend(after.into_iter().filter(|x| !before.contains(x)).map(|x| (i.end_tick, x)));
+    }
+    ret
+}
+
+//============================= Builder
+
+/// Scripts a raw trace capture by capture.
+/// Each call to [`MockTraceBuilder::capture`] records the task lists as currently set,
+/// job completions are given separately as the `trigger_job_done` hook of the real targets would report them.
+#[derive(Debug, Clone, Default)]
+pub struct MockTraceBuilder {
+    priorities: HashMap<String, u32>,
+    current: RawMockSystemState,
+    raw_trace: Vec<RawMockSystemState>,
+    jobs_done: Vec<(u64, String)>,
+    job_done_apis: Vec<String>,
+    task_start_isr: Option<String>,
+    symbols: SymbolResolver,
+}
+
+impl MockTraceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare a task with its priority, undeclared tasks have priority 0
+    #[must_use]
+    pub fn task(mut self, name: &str, priority: u32) -> Self {
+        self.priorities.insert(name.to_string(), priority);
+        self
+    }
+
+    /// Task running in the following captures
+    #[must_use]
+    pub fn running(mut self, name: &str) -> Self {
+        self.current.current_task = name.to_string();
+        self
+    }
+
+    /// Ready list of the following captures
+    #[must_use]
+    pub fn ready(mut self, names: &[&str]) -> Self {
+        self.current.ready_list = names.iter().map(|x| x.to_string()).collect();
+        self
+    }
+
+    /// Delay list of the following captures
+    #[must_use]
+    pub fn delayed(mut self, names: &[&str]) -> Self {
+        self.current.delay_list = names.iter().map(|x| x.to_string()).collect();
+        self
+    }
+
+    /// Record a capture at `tick`, triggered by `event` of the API function or ISR `name` on the jump `edge`.
+    /// The edges decide how [`super::intervals::add_abb_info`] stitches interrupted blocks together,
+    /// the return edge of an ISR needs to land where its entry edge left.
+    #[must_use]
+    pub fn capture(mut self, tick: u64, event: CaptureEvent, name: &str, edge: (GuestAddr, GuestAddr)) -> Self {
+        let mut raw = self.current.clone();
+        raw.icount = tick;
+        raw.capture_point = (event, Cow::Owned(name.to_string()));
+        raw.edge = edge;
+        self.raw_trace.push(raw);
+        self
+    }
+
+    /// Attach input reads to the last capture
+    #[must_use]
+    pub fn reads(mut self, reads: &[(u32, u8)]) -> Self {
+        if let Some(last) = self.raw_trace.last_mut() {
+            last.mem_reads.extend_from_slice(reads);
+        }
+        self
+    }
+
+    /// Record the completion of a job of `name` at `tick`, ignored if the responses are inferred, see [`Self::with_job_done_apis`]
+    #[must_use]
+    pub fn job_done(mut self, tick: u64, name: &str) -> Self {
+        self.jobs_done.push((tick, name.to_string()));
+        self
+    }
+
+    /// Infer the job responses from the blocking calls of `apis` instead of the recorded completions, like JOB_DONE_APIS
+    #[must_use]
+    pub fn with_job_done_apis(mut self, apis: &[&str]) -> Self {
+        self.job_done_apis = apis.iter().map(|x| x.to_string()).collect();
+        self
+    }
+
+    /// ISR whose return first enters a task, [`MOCK_TASK_START_ISR`] by default
+    #[must_use]
+    pub fn with_task_start_isr(mut self, name: &str) -> Self {
+        self.task_start_isr = Some(name.to_string());
+        self
+    }
+
+    /// Resolve ABB symbols with `symbols`, nothing is resolved by default
+    #[must_use]
+    pub fn with_symbols(mut self, symbols: SymbolResolver) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    /// The raw captures scripted so far
+    pub fn raw_trace(&self) -> &Vec<RawMockSystemState> {
+        &self.raw_trace
+    }
+
+    /// Refined states and their capture context, the input of [`states2intervals`]
+    pub fn refined(&self) -> (Vec<MockSystemState>, Vec<SystemStateContext>) {
+        refine_system_states(self.raw_trace.clone(), &self.priorities)
+    }
+
+    /// Runs the scripted trace through the interval and job reconstruction of the real targets
+    pub fn build(self) -> MockTraceMetadata {
+        let task_start_isr = self.task_start_isr.clone().unwrap_or(MOCK_TASK_START_ISR.to_string());
+        let (refined_states, metadata) = self.refined();
+        let (intervals, mem_reads, dumped_states, abb_error, isr_worst_durations) =
+            states2intervals(refined_states.clone(), metadata, &task_start_isr, &self.symbols);
+        let releases = get_releases(&intervals, &dumped_states);
+        let inferred = !self.job_done_apis.is_empty();
+        let responses = if inferred {
+            infer_responses(&intervals, &dumped_states, &self.job_done_apis)
+        } else {
+            self.jobs_done.clone()
+        };
+        let (job_spans, unpaired) = get_release_response_pairs(&releases, &responses);
+        let mut jobs = get_jobs(job_spans, &intervals, &mem_reads, &dumped_states);
+        jobs.iter_mut().for_each(|j| j.inferred = inferred);
+        MockTraceMetadata::new(refined_states, intervals, mem_reads, jobs, abb_error.is_some() || unpaired.is_some(), isr_worst_durations)
+    }
+}
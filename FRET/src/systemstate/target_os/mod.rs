@@ -1,13 +1,13 @@
 use std::borrow::Cow;
-use std::collections::hash_map::DefaultHasher;
 use std::fmt;
 use hashbrown::HashSet;
 use libafl_bolts::prelude::SerdeAny;
 use libafl_bolts::HasRefCnt;
-use libafl_qemu::Qemu;
+use libafl_qemu::{GuestAddr, Qemu};
 use std::hash::Hasher;
 use std::hash::Hash;
 use hashbrown::HashMap;
+use super::helpers::stable_hasher;
 use serde::{Deserialize, Serialize};
 use itertools::Itertools;
 use std::fmt::Debug;
@@ -21,6 +21,12 @@ pub mod freertos;
 
 pub mod osek;
 
+pub mod mock;
+
+pub mod intervals;
+
+pub mod capture;
+
 //============================= Trait definitions
 
 /// A trait representing a target system, which includes a system state, task control block, and trace data.
@@ -43,6 +49,40 @@ pub trait SystemState: Serialize + Sized + for<'a> Deserialize<'a> + Default + D
     fn print_lists(&self) -> String;
 }
 
+/// Problems the refinement of a trace ran into, recorded in the trace metadata, see [`SystemTraceData::error_classes`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorClass {
+    /// A capture whose kernel lists could not be read
+    InvalidRead,
+    /// The ABBs of the intervals did not start and end consistently
+    AbbMismatch,
+    /// Releases and responses of jobs could not be paired
+    ReleaseResponsePairing,
+    /// The capture stopped early, see [`SystemTraceData::trace_truncated`]
+    Truncation,
+    /// A task name which was not utf8, see [`SystemTraceData::invalid_task_names`]
+    Utf8Name,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 5] = [ErrorClass::InvalidRead, ErrorClass::AbbMismatch, ErrorClass::ReleaseResponsePairing, ErrorClass::Truncation, ErrorClass::Utf8Name];
+
+    /// Name in the ERROR_ACTIONS setting, the monitor and errors.csv
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorClass::InvalidRead => "invalid_read",
+            ErrorClass::AbbMismatch => "abb_mismatch",
+            ErrorClass::ReleaseResponsePairing => "pairing",
+            ErrorClass::Truncation => "truncation",
+            ErrorClass::Utf8Name => "utf8_name",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| x.name() == name)
+    }
+}
+
 pub trait SystemTraceData: Serialize + Sized + for<'a> Deserialize<'a> + Default + Debug + Clone + SerdeAny + HasRefCnt {
     type State: SystemState;
 
@@ -93,11 +133,11 @@ pub trait SystemTraceData: Serialize + Sized + for<'a> Deserialize<'a> + Default
 
     #[inline]
     /// extract computation time spent in each task and abb
-    /// task_name -> (abb_addr -> (interval_count, exec_count, exec_time, woet))
+    /// task_name -> (abb_addr -> (interval_count, exec_count, exec_time, woet, symbol))
     fn select_abb_profile(
         &self,
         select_task: Option<String>,
-    ) -> HashMap<Cow<'static, str>, HashMap<u32, (usize, usize, u64, u64)>> {
+    ) -> HashMap<Cow<'static, str>, HashMap<u32, (usize, usize, u64, u64, Option<String>)>> {
         if let Some(select_task) = select_task.as_ref() {
             // Task selected, only profile this task
             let wjptybrt = self.worst_jobs_per_task_by_response_time();
@@ -121,7 +161,68 @@ pub trait SystemTraceData: Serialize + Sized + for<'a> Deserialize<'a> + Default
         }
     }
 
+    #[inline]
+    /// attribute the bytes of the input to the intervals reading them
+    /// input offset -> {(task_name, abb_start)}, along with the offsets below `input_size` nothing read
+    fn input_usage(
+        &self,
+        input_base: u32,
+        input_size: usize,
+    ) -> (HashMap<u32, HashSet<(Cow<'static, str>, u32)>>, Vec<u32>) {
+        let mut usage: HashMap<u32, HashSet<(Cow<'static, str>, u32)>> = HashMap::new();
+        for (interval, reads) in self.intervals().iter().zip(self.mem_reads().iter()) {
+            let reader = (
+                interval.get_task_name_unchecked(),
+                interval.abb.as_ref().map_or(u32::MAX, |x| x.get_start()),
+            );
+            for (addr, _) in reads {
+                if let Some(offset) = addr.checked_sub(input_base).filter(|x| (*x as usize) < input_size) {
+                    usage.entry(offset).or_default().insert(reader.clone());
+                }
+            }
+        }
+        let unused = (0..input_size as u32).filter(|x| !usage.contains_key(x)).collect();
+        (usage, unused)
+    }
+
     fn need_to_debug(&self) -> bool;
+
+    /// Returns the longest invocation of each ISR in ticks, if the target tracks them.
+    fn isr_worst_durations(&self) -> Option<&HashMap<String, u64>> {
+        None
+    }
+    /// Whether an ISR invocation exceeded its configured tick budget.
+    fn isr_budget_exceeded(&self) -> bool {
+        false
+    }
+    /// Returns the number of deferred and of invalid state captures, if the target defers captures.
+    fn capture_stats(&self) -> Option<(usize, usize)> {
+        None
+    }
+    /// Returns the start ticks of the invocations of each ISR, if the target records them.
+    fn isr_activations(&self) -> Option<&HashMap<String, Vec<u64>>> {
+        None
+    }
+    /// Whether the trace is incomplete, because it hit the trace limits or the target ran out of input.
+    fn trace_truncated(&self) -> bool {
+        false
+    }
+    /// Returns the number of task names which could not be decoded and were replaced, their states are marked invalid.
+    fn invalid_task_names(&self) -> usize {
+        0
+    }
+    /// Returns the least free stack in bytes seen of each task, if the target reads the stack pointers of its tasks.
+    fn stack_high_water(&self) -> Option<&HashMap<String, u32>> {
+        None
+    }
+    /// Returns the classes of problems the refinement of this trace ran into, each at most once.
+    fn error_classes(&self) -> &[ErrorClass] {
+        &[]
+    }
+    /// Returns the earliest inconsistency the refinement of this trace ran into, with the context to debug it.
+    fn first_error(&self) -> Option<&intervals::RefinementError> {
+        None
+    }
 }
 
 
@@ -133,21 +234,72 @@ pub trait TaskControlBlock: Serialize + for<'a> Deserialize<'a> + Default + Debu
 
 //============================= 
 
+/// Whether the guest stores values in big-endian byte order, see the guest_big_endian feature
+pub const GUEST_BIG_ENDIAN: bool = cfg!(feature = "guest_big_endian");
+/// Whether values read from the guest have a different byte order than the host
+pub const GUEST_SWAP_BYTES: bool = GUEST_BIG_ENDIAN != cfg!(target_endian = "big");
+
 /// A trait for looking up data in a QEMU emulation environment.
 pub trait QemuLookup {
-    fn lookup(emu: &Qemu, addr: ::std::os::raw::c_uint) -> Self;
+    fn lookup(emu: &Qemu, addr: GuestAddr) -> Self;
+}
+
+/// Converts a value read from guest memory into the byte order of the host
+pub trait FromGuestEndian {
+    fn from_guest_endian(&mut self);
 }
 
+impl<T: FromGuestEndian, const N: usize> FromGuestEndian for [T; N] {
+    fn from_guest_endian(&mut self) {
+        for x in self.iter_mut() {
+            x.from_guest_endian();
+        }
+    }
+}
+
+macro_rules! impl_guest_scalar {
+    ($($t:ty),*) => {$(
+        impl FromGuestEndian for $t {
+            fn from_guest_endian(&mut self) {
+                if GUEST_SWAP_BYTES {
+                    *self = self.swap_bytes();
+                }
+            }
+        }
+        impl QemuLookup for $t {
+            fn lookup(emu: &Qemu, addr: GuestAddr) -> $t {
+                let mut tmp = [0u8; std::mem::size_of::<$t>()];
+                unsafe {
+                    emu.read_mem(addr.into(), &mut tmp).unwrap();
+                }
+                if GUEST_BIG_ENDIAN { <$t>::from_be_bytes(tmp) } else { <$t>::from_le_bytes(tmp) }
+            }
+        }
+    )*};
+}
+impl_guest_scalar!(u8, i8, u16, i16, u32, i32, u64, i64);
+
+/// Implements [`QemuLookup`] and [`FromGuestEndian`] for a `#[repr(C)]` binding, listing all of its fields.
+/// The struct is read as is and each field is converted to the byte order of the host, so every field type has to implement [`FromGuestEndian`].
+/// A field missing from the list fails to compile. Scalars such as pointers are covered by the implementations for the primitive types.
 #[macro_export]
 macro_rules! impl_emu_lookup {
-    ($struct_name:ident) => {
+    ($struct_name:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::systemstate::target_os::FromGuestEndian for $struct_name {
+            fn from_guest_endian(&mut self) {
+                let $struct_name { $($field),* } = self;
+                $( $crate::systemstate::target_os::FromGuestEndian::from_guest_endian($field); )*
+            }
+        }
         impl $crate::systemstate::target_os::QemuLookup for $struct_name {
-            fn lookup(emu: &Qemu, addr: ::std::os::raw::c_uint) -> $struct_name {
+            fn lookup(emu: &libafl_qemu::Qemu, addr: libafl_qemu::GuestAddr) -> $struct_name {
                 let mut tmp : [u8; std::mem::size_of::<$struct_name>()] = [0u8; std::mem::size_of::<$struct_name>()];
-                unsafe {
+                let mut ret = unsafe {
                     emu.read_mem(addr.into(), &mut tmp).unwrap();
                     std::mem::transmute::<[u8; std::mem::size_of::<$struct_name>()], $struct_name>(tmp)
-                }
+                };
+                $crate::systemstate::target_os::FromGuestEndian::from_guest_endian(&mut ret);
+                ret
             }
         }
     };
@@ -157,7 +309,7 @@ pub fn compute_hash<T>(obj: &T) -> u64
 where
     T: Hash,
 {
-    let mut s = DefaultHasher::new();
+    let mut s = stable_hasher();
     obj.hash(&mut s);
     s.finish()
 }
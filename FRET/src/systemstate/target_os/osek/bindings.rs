@@ -34,11 +34,12 @@ pub type TaskStateType = uint8;
 pub type CoreIdType = uint8;
 
 /* Pointer types (32-bit addresses for TriCore) */
-pub type void_ptr = ::std::os::raw::c_uint;
-pub type TaskType_ptr = ::std::os::raw::c_uint;       // Pointer to Os_TaskType (const*)
-pub type ResourceType_ptr = ::std::os::raw::c_uint;   // Pointer to Os_ResourceType (const*)
-pub type CounterType_ptr = ::std::os::raw::c_uint;    // Pointer to Os_CounterType (const*)
-pub type AlarmType_ptr = ::std::os::raw::c_uint;      // Pointer to Os_AlarmType (const*)
+pub type GuestPointer = uint32;
+pub type void_ptr = GuestPointer;
+pub type TaskType_ptr = GuestPointer;       // Pointer to Os_TaskType (const*)
+pub type ResourceType_ptr = GuestPointer;   // Pointer to Os_ResourceType (const*)
+pub type CounterType_ptr = GuestPointer;    // Pointer to Os_CounterType (const*)
+pub type AlarmType_ptr = GuestPointer;      // Pointer to Os_AlarmType (const*)
 
 /*============================================================================
  * Task States (matching osek.h)
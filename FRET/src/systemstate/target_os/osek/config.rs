@@ -13,39 +13,58 @@ use libafl_qemu::{elf::EasyElf, GuestAddr};
 
 use crate::{
     fuzzer::get_all_fn_symbol_ranges,
-    systemstate::helpers::{get_function_range, load_symbol},
+    systemstate::helpers::{get_function_range, load_symbol, symbol_layout_mismatches},
 };
 
+use super::bindings::{uint32, Os_AlarmDynType, Os_CounterDynType, Os_ResourceDynType, Os_TaskDynType, Os_TaskType, TickType};
 use super::ISR_SYMBOLS;
 
-/// Add OSEK/RTA_OS specific symbols to the target symbol hashmap
-/// These match the globals in osek.h
-pub fn add_target_symbols(elf: &EasyElf, addrs: &mut HashMap<&'static str, GuestAddr>) {
+/// OSEK/RTA_OS specific symbols, these match the globals in osek.h
+pub const REQUIRED_SYMBOLS: &[&str] = &[
     // Task management - dynamic state array
-    addrs.insert("Os_TaskDyn", load_symbol(&elf, "Os_TaskDyn", false));
-    addrs.insert("Os_TaskCount", load_symbol(&elf, "Os_TaskCount", false));
-    addrs.insert("Os_CurrentTask", load_symbol(&elf, "Os_CurrentTask", false));
-    
+    "Os_TaskDyn",
+    "Os_TaskCount",
+    "Os_CurrentTask",
     // Resource management
-    addrs.insert("Os_ResourceDyn", load_symbol(&elf, "Os_ResourceDyn", false));
-    addrs.insert("Os_ResourceCount", load_symbol(&elf, "Os_ResourceCount", false));
-    
+    "Os_ResourceDyn",
+    "Os_ResourceCount",
     // Alarm management
-    addrs.insert("Os_AlarmDyn", load_symbol(&elf, "Os_AlarmDyn", false));
-    addrs.insert("Os_AlarmCount", load_symbol(&elf, "Os_AlarmCount", false));
-    
+    "Os_AlarmDyn",
+    "Os_AlarmCount",
     // Counter management
-    addrs.insert("Os_CounterDyn", load_symbol(&elf, "Os_CounterDyn", false));
-    addrs.insert("Os_CounterCount", load_symbol(&elf, "Os_CounterCount", false));
-    
+    "Os_CounterDyn",
+    "Os_CounterCount",
     // Timing
-    addrs.insert("Os_TickCounter", load_symbol(&elf, "Os_TickCounter", false));
-    
+    "Os_TickCounter",
     // Ready queue (if used)
-    addrs.insert("Os_ReadyQueue", load_symbol(&elf, "Os_ReadyQueue", false));
-    
+    "Os_ReadyQueue",
     // Static task configs (application-defined)
-    addrs.insert("Os_TaskCfg", load_symbol(&elf, "Os_TaskCfg", false));
+    "Os_TaskCfg",
+];
+
+/// Kernel globals whose size in the ELF does not match the bindings in bindings.rs
+pub fn layout_mismatches(elf: &EasyElf) -> Vec<String> {
+    use std::mem::size_of;
+    symbol_layout_mismatches(elf, &[
+        ("Os_TaskDyn", size_of::<Os_TaskDynType>(), true),
+        ("Os_TaskCfg", size_of::<Os_TaskType>(), true),
+        ("Os_ResourceDyn", size_of::<Os_ResourceDynType>(), true),
+        ("Os_AlarmDyn", size_of::<Os_AlarmDynType>(), true),
+        ("Os_CounterDyn", size_of::<Os_CounterDynType>(), true),
+        ("Os_TaskCount", size_of::<uint32>(), false),
+        ("Os_ResourceCount", size_of::<uint32>(), false),
+        ("Os_AlarmCount", size_of::<uint32>(), false),
+        ("Os_CounterCount", size_of::<uint32>(), false),
+        ("Os_TickCounter", size_of::<TickType>(), false),
+    ])
+}
+
+/// Add OSEK/RTA_OS specific symbols to the target symbol hashmap
+pub fn add_target_symbols(elf: &EasyElf, addrs: &mut HashMap<&'static str, GuestAddr>) {
+    for name in REQUIRED_SYMBOLS {
+        addrs.insert(*name, load_symbol(&elf, name, false));
+    }
+    debug_assert!(layout_mismatches(elf).is_empty(), "Bindings do not match the kernel: {:?}", layout_mismatches(elf));
 }
 
 /// Group functions into API, app, and ISR categories
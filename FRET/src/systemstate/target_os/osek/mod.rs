@@ -7,11 +7,12 @@
 use libafl_qemu::GuestAddr;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::fmt;
 use hashbrown::HashMap;
 
 use crate::{
     impl_emu_lookup,
-    systemstate::{helpers::get_icount, CaptureEvent},
+    systemstate::CaptureEvent,
 };
 
 pub mod bindings;
@@ -21,8 +22,9 @@ pub mod qemu_module;
 use bindings::*;
 
 use super::QemuLookup;
+use super::intervals::RefinementError;
 use crate::systemstate::target_os::*;
-use crate::systemstate::{ExecInterval, RTOSJob, AtomicBasicBlock};
+use crate::systemstate::{ExecInterval, RTOSJob};
 
 /*============================================================================
  * Constants
@@ -154,10 +156,12 @@ pub struct RawOSEKSystemState {
     pub tick_count: TickType,
     /// Instruction count at capture time
     pub icount: u64,
-    /// Capture event type
-    pub event: CaptureEvent,
-    /// PC at capture
-    pub pc: GuestAddr,
+    /// Capture event type and name of the API function or ISR
+    pub capture_point: (CaptureEvent, Cow<'static, str>),
+    /// Jump edge that triggered the capture
+    pub edge: (GuestAddr, GuestAddr),
+    /// Input bytes read since the previous capture
+    pub mem_reads: Vec<(u32, u8)>,
 }
 
 /*============================================================================
@@ -208,6 +212,18 @@ impl SystemState for OSEKSystemState {
     }
 }
 
+impl fmt::Display for OSEKSystemState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ready = self.ready_list.iter().map(|x| x.task_name.as_str()).collect::<Vec<_>>().join(" ");
+        let waiting = self.waiting_list.iter().map(|x| x.task_name.as_str()).collect::<Vec<_>>().join(" ");
+        write!(
+            f,
+            "Current: {} | Ready: {} | Waiting: {}",
+            self.current_task.task_name, ready, waiting
+        )
+    }
+}
+
 impl OSEKSystemState {
     pub fn from_raw(raw: &RawOSEKSystemState) -> Self {
         let mut current_task = RefinedTCB::default();
@@ -255,7 +271,7 @@ impl OSEKSystemState {
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct OSEKTraceMetadata {
     /// Reference count for LibAFL
-    ref_cnt: usize,
+    tcref: isize,
     /// Map of state hash to state
     states_map: HashMap<u64, OSEKSystemState>,
     /// Execution intervals
@@ -266,6 +282,21 @@ pub struct OSEKTraceMetadata {
     jobs: Vec<RTOSJob>,
     /// Debug flag
     need_debug: bool,
+    /// Longest invocation of each ISR in ticks
+    #[serde(default)]
+    isr_worst_durations: HashMap<String, u64>,
+    /// Whether an ISR invocation exceeded its tick budget
+    #[serde(default)]
+    isr_budget_exceeded: bool,
+    /// Whether the capture stopped early
+    #[serde(default)]
+    trace_truncated: bool,
+    /// Problems the refinement ran into
+    #[serde(default)]
+    error_classes: Vec<ErrorClass>,
+    /// Earliest of these problems
+    #[serde(default)]
+    first_error: Option<RefinementError>,
 }
 
 impl OSEKTraceMetadata {
@@ -275,6 +306,9 @@ impl OSEKTraceMetadata {
         mem_reads: Vec<Vec<(u32, u8)>>,
         jobs: Vec<RTOSJob>,
         need_to_debug: bool,
+        isr_worst_durations: HashMap<String, u64>,
+        isr_budget_exceeded: bool,
+        trace_truncated: bool,
     ) -> Self {
         let mut states_map = HashMap::new();
         for state in trace {
@@ -282,22 +316,41 @@ impl OSEKTraceMetadata {
             states_map.insert(hash, state);
         }
         OSEKTraceMetadata {
-            ref_cnt: 1,
+            tcref: 0,
             states_map,
             intervals,
             mem_reads,
             jobs,
             need_debug: need_to_debug,
+            isr_worst_durations,
+            isr_budget_exceeded,
+            trace_truncated,
+            error_classes: Vec::new(),
+            first_error: None,
         }
     }
+
+    /// Records the problems the refinement ran into, see [`SystemTraceData::error_classes`]
+    #[must_use]
+    pub fn with_error_classes(mut self, error_classes: Vec<ErrorClass>) -> Self {
+        self.error_classes = error_classes;
+        self
+    }
+
+    /// Records the earliest problem of the refinement, see [`SystemTraceData::first_error`]
+    #[must_use]
+    pub fn with_first_error(mut self, first_error: Option<RefinementError>) -> Self {
+        self.first_error = first_error;
+        self
+    }
 }
 
 impl libafl_bolts::HasRefCnt for OSEKTraceMetadata {
     fn refcnt(&self) -> isize {
-        self.ref_cnt as isize
+        self.tcref
     }
     fn refcnt_mut(&mut self) -> &mut isize {
-        unsafe { &mut *(&mut self.ref_cnt as *mut usize as *mut isize) }
+        &mut self.tcref
     }
 }
 
@@ -312,18 +365,10 @@ impl SystemTraceData for OSEKTraceMetadata {
         &self.states_map
     }
 
-    fn states_map_mut(&mut self) -> &mut HashMap<u64, Self::State> {
-        &mut self.states_map
-    }
-
     fn intervals(&self) -> &Vec<ExecInterval> {
         &self.intervals
     }
 
-    fn intervals_mut(&mut self) -> &mut Vec<ExecInterval> {
-        &mut self.intervals
-    }
-
     fn mem_reads(&self) -> &Vec<Vec<(u32, u8)>> {
         &self.mem_reads
     }
@@ -339,37 +384,51 @@ impl SystemTraceData for OSEKTraceMetadata {
     fn need_to_debug(&self) -> bool {
         self.need_debug
     }
+
+    fn isr_worst_durations(&self) -> Option<&HashMap<String, u64>> {
+        Some(&self.isr_worst_durations)
+    }
+
+    fn isr_budget_exceeded(&self) -> bool {
+        self.isr_budget_exceeded
+    }
+
+    fn trace_truncated(&self) -> bool {
+        self.trace_truncated
+    }
+
+    fn error_classes(&self) -> &[ErrorClass] {
+        &self.error_classes
+    }
+
+    fn first_error(&self) -> Option<&RefinementError> {
+        self.first_error.as_ref()
+    }
 }
 
+libafl_bolts::impl_serdeany!(OSEKTraceMetadata);
+libafl_bolts::impl_serdeany!(RefinedTCB);
+libafl_bolts::impl_serdeany!(OSEKSystemState);
+libafl_bolts::impl_serdeany!(OSEKSystem);
+
 /*============================================================================
  * QEMU Memory Lookups
  *============================================================================*/
 
-impl_emu_lookup!(Os_TaskType);
-impl_emu_lookup!(Os_TaskDynType);
-impl_emu_lookup!(Os_ResourceType);
-impl_emu_lookup!(Os_ResourceDynType);
-impl_emu_lookup!(Os_CounterType);
-impl_emu_lookup!(Os_CounterDynType);
-impl_emu_lookup!(Os_AlarmType);
-impl_emu_lookup!(Os_AlarmDynType);
-impl_emu_lookup!(void_ptr);
-impl_emu_lookup!(TickType);
+impl_emu_lookup!(Os_TaskType { index, basePriority, maxActivations, autostart, stackSize, entry });
+impl_emu_lookup!(Os_TaskDynType { state, currentPriority, activationCount, _pad, eventsSet, eventsWaiting, resourcesHeld });
+impl_emu_lookup!(Os_ResourceType { index, ceilingPriority });
+impl_emu_lookup!(Os_ResourceDynType { owner, prevPriority, isOccupied, _pad });
+impl_emu_lookup!(Os_CounterType { index, _pad, maxAllowedValue, ticksPerBase, minCycle });
+impl_emu_lookup!(Os_CounterDynType { value });
+impl_emu_lookup!(Os_AlarmType { index, _pad1, _pad2, actionType, counter, actionData });
+impl_emu_lookup!(Os_AlarmDynType { isActive, _pad1, _pad2, _pad3, expireTime, cycle });
 
-/*============================================================================
- * System State Context (for capture during execution)
- *============================================================================*/
-
-#[derive(Debug, Clone, Default)]
-pub struct OSEKSystemStateContext {
-    pub current_state: Option<RawOSEKSystemState>,
-    pub last_icount: u64,
-    pub last_pc: GuestAddr,
-}
+// TriCore is 32-bit only
+const _: () = assert!(std::mem::size_of::<GuestPointer>() == std::mem::size_of::<GuestAddr>(), "OSEK bindings assume 32-bit guest pointers");
 
 /*============================================================================
- * Global State Storage
+ * System State Context (for capture during execution)
  *============================================================================*/
 
-/// Thread-local storage for captured system states during fuzzing
-pub static mut CURRENT_SYSTEMSTATE_VEC: Vec<RawOSEKSystemState> = Vec::new();
+pub(crate) use super::intervals::SystemStateContext as OSEKSystemStateContext;
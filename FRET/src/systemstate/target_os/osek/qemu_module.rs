@@ -7,8 +7,6 @@
  *   Os_TaskDyn[], Os_ResourceDyn[], Os_AlarmDyn[], Os_CounterDyn[]
  */
 
-use std::cell::RefCell;
-use std::rc::Rc;
 use std::borrow::Cow;
 use std::ops::Range;
 
@@ -29,16 +27,22 @@ use libafl_qemu::{
 use crate::{
     fuzzer::MAX_INPUT_SIZE,
     systemstate::{
-        helpers::{get_icount, in_any_range, read_rec_return_stackframe},
-        target_os::{osek::bindings::*, compute_hash, QemuLookup},
-        AtomicBasicBlock, CaptureEvent, ExecInterval, RTOSJob,
+        helpers::{get_icount, in_any_range, SymbolResolver},
+        target_os::{
+            capture::{CaptureBuffer, TraceLimits},
+            intervals::{get_jobs, get_release_response_pairs, infer_responses, states2intervals, RefinementError},
+            osek::bindings::*,
+            ErrorClass, QemuLookup,
+        },
+        CaptureEvent, ExecInterval,
     },
 };
+#[cfg(feature = "snapshot_at_first_task")]
+use crate::time::qemustate::TaskStartMetadata;
 
 use super::{
     OSEKSystemState, OSEKSystemStateContext, OSEKTraceMetadata,
-    RawOSEKSystemState, RefinedTCB, CURRENT_SYSTEMSTATE_VEC, JOBS_DONE,
-    ISR_SYMBOLS, USR_ISR_SYMBOLS,
+    RawOSEKSystemState,
 };
 
 /*============================================================================
@@ -74,16 +78,43 @@ pub struct OSEKSystemStateHelper {
     pub counter_dyn_addr: GuestAddr,    // Os_CounterDyn[]
     pub counter_count_addr: GuestAddr,  // Os_CounterCount
     pub tick_counter_addr: GuestAddr,   // Os_TickCounter
-    pub job_done_addr: GuestAddr,       // trigger_job_done
+    pub job_done_addr: Option<GuestAddr>, // trigger_job_done
+    pub job_done_apis: Vec<String>,     // blocking API calls ending a job, replace job_done_addr if not empty
+    pub input_counter_addr: Option<GuestAddr>, // FUZZ_POINTER
+
+    // Tick budget per ISR name
+    pub isr_budgets: HashMap<String, u64>,
+    // Function symbols for annotating ABBs
+    pub symbols: SymbolResolver,
+    // Trace of the current execution, filled by the hooks
+    pub capture: CaptureBuffer<RawOSEKSystemState>,
+    // The hooks filter by address ranges themselves
+    address_filter: NopAddressFilter,
+    page_filter: NopPageFilter,
 }
 
 impl OSEKSystemStateHelper {
-    #[must_use]
     pub fn new(
         target_symbols: &HashMap<&'static str, GuestAddr>,
         target_ranges: &HashMap<&'static str, Range<GuestAddr>>,
         target_groups: &HashMap<&'static str, HashMap<String, Range<GuestAddr>>>,
-    ) -> Self {
+    ) -> Result<Self, String> {
+        let mut missing = Vec::new();
+        if !target_ranges.contains_key("APP_CODE") {
+            missing.push("APP_CODE");
+        }
+        for group in ["API_FN", "ISR_FN"] {
+            if !target_groups.contains_key(group) {
+                missing.push(group);
+            }
+        }
+        if !target_symbols.contains_key("FUZZ_INPUT") {
+            missing.push("FUZZ_INPUT");
+        }
+        if !missing.is_empty() {
+            return Err(format!("Target is missing required symbols: {}", missing.join(", ")));
+        }
+
         let app_range = target_ranges.get("APP_CODE").unwrap().clone();
 
         let api_fn_ranges: Vec<_> = target_groups
@@ -120,17 +151,24 @@ impl OSEKSystemStateHelper {
             .map(|(n, r)| (r.start, n.clone()))
             .collect();
 
+        let job_done_addr = target_symbols.get("trigger_job_done").copied();
+        #[cfg(feature = "trace_job_response_times")]
+        if job_done_addr.is_none() {
+            eprintln!("Symbol trigger_job_done not found, job response times are only traced with JOB_DONE_APIS");
+        }
+
         let input_mem = target_symbols
             .get("FUZZ_INPUT")
             .map(|x| *x..(*x + unsafe { MAX_INPUT_SIZE as GuestAddr }))
             .unwrap();
 
-        OSEKSystemStateHelper {
+        Ok(OSEKSystemStateHelper {
             app_range,
             api_fn_addrs,
             api_fn_ranges,
             isr_fn_addrs,
             isr_fn_ranges,
+            capture: CaptureBuffer::new(input_mem.clone()),
             input_mem,
             task_dyn_addr: *target_symbols.get("Os_TaskDyn").unwrap_or(&0),
             task_count_addr: *target_symbols.get("Os_TaskCount").unwrap_or(&0),
@@ -143,18 +181,40 @@ impl OSEKSystemStateHelper {
             counter_dyn_addr: *target_symbols.get("Os_CounterDyn").unwrap_or(&0),
             counter_count_addr: *target_symbols.get("Os_CounterCount").unwrap_or(&0),
             tick_counter_addr: *target_symbols.get("Os_TickCounter").unwrap_or(&0),
-            job_done_addr: *target_symbols.get("trigger_job_done").unwrap_or(&0),
-        }
+            job_done_addr,
+            job_done_apis: Vec::new(),
+            input_counter_addr: target_symbols.get("FUZZ_POINTER").copied(),
+            isr_budgets: HashMap::new(),
+            symbols: SymbolResolver::from_groups(target_groups),
+            address_filter: NopAddressFilter,
+            page_filter: NopPageFilter,
+        })
     }
-}
 
-/*============================================================================
- * Global State for Hooks
- *============================================================================*/
+    /// Set the maximum ticks each ISR invocation may take
+    #[must_use]
+    pub fn with_isr_budgets(mut self, isr_budgets: HashMap<String, u64>) -> Self {
+        self.isr_budgets = isr_budgets;
+        self
+    }
 
-static mut INPUT_MEM: Range<GuestAddr> = 0..0;
-pub static mut MEM_READ: Vec<(u32, u8)> = Vec::new();
-static mut JOBS_DONE: Vec<(String, u64, u64)> = Vec::new();
+    /// Infer the job responses from calls to `apis` which move the task to the waiting list, e.g. WaitEvent, for targets without trigger_job_done
+    #[must_use]
+    pub fn with_job_done_apis(mut self, apis: Vec<String>) -> Self {
+        self.job_done_apis = apis;
+        self
+    }
+
+    /// Stop capturing states of an execution beyond the `limits`
+    #[must_use]
+    pub fn with_trace_limits(mut self, limits: TraceLimits) -> Self {
+        self.capture.limits = limits;
+        self
+    }
+}
+
+/// The ISR whose return dispatches a task
+const TASK_START_ISR: &str = "Os_ContextSwitchHandler";
 
 /*============================================================================
  * System State Capture
@@ -162,20 +222,34 @@ static mut JOBS_DONE: Vec<(String, u64, u64)> = Vec::new();
 
 /// Read a u32 from QEMU memory
 fn read_u32(emulator: &libafl_qemu::Qemu, addr: GuestAddr) -> u32 {
-    let mut bytes = [0u8; 4];
-    unsafe {
-        let _ = emulator.read_mem(addr.into(), &mut bytes);
-    }
-    u32::from_le_bytes(bytes)
+    QemuLookup::lookup(emulator, addr)
+}
+
+/// GDB number of the TriCore return address register A11, libafl_qemu numbers D0-D15 first, then A0-A15
+const TRICORE_REG_A11: i32 = 27;
+
+/// Where the interrupted code continues. Taking an interrupt on TriCore saves the upper context to a CSA
+/// and puts the interrupted PC into A11, which `rfe` returns to. There is no stack frame as on Cortex-M.
+fn read_isr_return_address(emulator: &libafl_qemu::Qemu) -> GuestAddr {
+    emulator.read_reg(TRICORE_REG_A11).unwrap_or(0)
 }
 
 /// Read the current OSEK system state from QEMU
 fn capture_osek_state(
     emulator: &libafl_qemu::Qemu,
-    helper: &OSEKSystemStateHelper,
+    helper: &mut OSEKSystemStateHelper,
+    edge: (GuestAddr, GuestAddr),
     event: CaptureEvent,
-    pc: GuestAddr,
 ) -> RawOSEKSystemState {
+    let capture_name = match event {
+        CaptureEvent::APIStart => helper.api_fn_addrs.get(&edge.1),
+        CaptureEvent::APIEnd => helper.api_fn_addrs.get(&edge.0),
+        CaptureEvent::ISRStart => helper.isr_fn_addrs.get(&edge.1),
+        CaptureEvent::ISREnd => helper.isr_fn_addrs.get(&edge.0),
+        _ => None,
+    }
+    .cloned()
+    .unwrap_or(Cow::Borrowed(""));
     let icount = get_icount(emulator);
     
     // Read task count
@@ -300,110 +374,187 @@ fn capture_osek_state(
         counter_dyn_states,
         tick_count,
         icount,
-        event,
-        pc,
+        capture_point: (event, capture_name),
+        edge,
+        mem_reads: helper.capture.take_reads(),
     }
 }
 
 /// Trigger system state collection
 pub fn trigger_collection(
     emulator: &libafl_qemu::Qemu,
-    helper: &OSEKSystemStateHelper,
+    edge: (GuestAddr, GuestAddr),
     event: CaptureEvent,
-    pc: GuestAddr,
+    helper: &mut OSEKSystemStateHelper,
 ) {
-    let state = capture_osek_state(emulator, helper, event, pc);
-    unsafe {
-        CURRENT_SYSTEMSTATE_VEC.push(state);
+    // the final state is always captured, it closes the trace
+    if event != CaptureEvent::End && !helper.capture.accept(get_icount(emulator)) {
+        return;
     }
+    let state = capture_osek_state(emulator, helper, edge, event);
+    helper.capture.states.push(state);
 }
 
 /*============================================================================
  * QEMU Hooks
  *============================================================================*/
 
-/// Hook called on ISR entry
-fn exec_isr_hook<ET, S>(
-    _emulator_modules: &mut EmulatorModules<ET, S>,
+/// Record the completion of the current task's job
+fn job_done_hook<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
     _state: Option<&mut S>,
-    pc: GuestAddr,
+    _pc: GuestAddr,
 ) where
-    ET: EmulatorModuleTuple<S>,
-    S: UsesInput + Unpin + HasMetadata,
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
 {
-    // Capture state on ISR entry
-    // Implementation would capture the state here
+    let emulator = hooks.qemu();
+    let h = hooks
+        .modules_mut()
+        .match_first_type_mut::<OSEKSystemStateHelper>()
+        .expect("OSEKSystemStateHelper not found in helper tupel");
+    if h.current_task_addr == 0 {
+        return;
+    }
+    let current_task_idx = read_u32(&emulator, h.current_task_addr) as u8;
+    if current_task_idx == 0xFF {
+        return;
+    }
+    h.capture.on_job_done(&emulator, format!("Task{}", current_task_idx));
 }
 
-/// Hook for jump instructions (syscalls, etc.)
-fn gen_jmp_is_syscall<ET, S>(
-    _emulator_modules: &mut EmulatorModules<ET, S>,
+/// Capture the state on ISR entry
+fn exec_isr_hook<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
     _state: Option<&mut S>,
-    _src: Option<GuestAddr>,
-    _dest: GuestAddr,
-) -> Option<u64>
-where
-    ET: EmulatorModuleTuple<S>,
-    S: UsesInput + Unpin + HasMetadata,
+    pc: GuestAddr,
+) where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
 {
-    // Check if this is a syscall/API entry
-    None
+    let emulator = hooks.qemu();
+    let h = hooks
+        .modules_mut()
+        .match_first_type_mut::<OSEKSystemStateHelper>()
+        .expect("OSEKSystemStateHelper not found in helper tupel");
+    let src = read_isr_return_address(&emulator);
+    trigger_collection(&emulator, (src, pc), CaptureEvent::ISRStart, h);
 }
 
-/// Trace jump execution
-fn trace_jmp<ET, S>(
-    _emulator_modules: &mut EmulatorModules<ET, S>,
+/// Classify jumps: 1 = API call from the application, 2 = API return, 3 = ISR return
+fn gen_jmp_is_syscall<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
     _state: Option<&mut S>,
-    _id: u64,
-    _src: GuestAddr,
-    _dest: GuestAddr,
-) where
-    ET: EmulatorModuleTuple<S>,
-    S: UsesInput + Unpin + HasMetadata,
+    src: GuestAddr,
+    dest: GuestAddr,
+) -> Option<u64>
+where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
 {
-    // Trace jump for coverage
+    if let Some(h) = hooks
+        .modules()
+        .match_first_type::<OSEKSystemStateHelper>()
+    {
+        if h.app_range.contains(&src)
+            && !h.app_range.contains(&dest)
+            && in_any_range(&h.isr_fn_ranges, src).is_none()
+        {
+            if in_any_range(&h.api_fn_ranges, dest).is_some() {
+                return Some(1);
+            }
+        } else if dest == 0 {
+            if in_any_range(&h.api_fn_ranges, src).is_some() {
+                return Some(2);
+            }
+            if in_any_range(&h.isr_fn_ranges, src).is_some() {
+                return Some(3);
+            }
+        }
+    }
+    None
 }
 
-/// Hook for job completion
-fn job_done_hook<ET, S>(
-    emulator_modules: &mut EmulatorModules<ET, S>,
+/// Capture the state on API calls, API returns and ISR returns
+fn trace_jmp<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
     _state: Option<&mut S>,
-    _pc: GuestAddr,
+    src: GuestAddr,
+    dest: GuestAddr,
+    id: u64,
 ) where
-    ET: EmulatorModuleTuple<S>,
-    S: UsesInput + Unpin + HasMetadata,
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
 {
-    // Record job completion for timing analysis
+    let emulator = hooks.qemu();
+    let h = hooks
+        .modules_mut()
+        .match_first_type_mut::<OSEKSystemStateHelper>()
+        .expect("OSEKSystemStateHelper not found in helper tupel");
+    match id {
+        1 => trigger_collection(&emulator, (src, dest), CaptureEvent::APIStart, h),
+        2 => {
+            // Only account for the first call depth of API calls from user space
+            if in_any_range(&h.api_fn_ranges, dest).is_none()
+                && in_any_range(&h.isr_fn_ranges, dest).is_none()
+            {
+                let start = in_any_range(&h.api_fn_ranges, src).unwrap().start;
+                trigger_collection(&emulator, (start, dest), CaptureEvent::APIEnd, h);
+            }
+        }
+        3 => {
+            let start = in_any_range(&h.isr_fn_ranges, src).unwrap().start;
+            let dest = read_isr_return_address(&emulator);
+            trigger_collection(&emulator, (start, dest), CaptureEvent::ISREnd, h);
+        }
+        _ => (),
+    }
 }
 
-/// Check if read is from input memory
-fn gen_read_is_input<ET, S>(
-    _emulator_modules: &mut EmulatorModules<ET, S>,
+/// Only instrument reads from the application
+#[allow(unused)]
+fn gen_read_is_input<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
     _state: Option<&mut S>,
-    _pc: GuestAddr,
-    addr: *mut TCGTemp,
+    pc: GuestAddr,
+    _addr: *mut TCGTemp,
     _info: MemAccessInfo,
 ) -> Option<u64>
 where
-    ET: EmulatorModuleTuple<S>,
-    S: UsesInput + Unpin + HasMetadata,
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
 {
+    if let Some(h) = hooks
+        .modules()
+        .match_first_type::<OSEKSystemStateHelper>()
+    {
+        if h.app_range.contains(&pc) {
+            return Some(1);
+        }
+    }
     None
 }
 
-/// Trace memory reads
-fn trace_reads<ET, S>(
-    _emulator_modules: &mut EmulatorModules<ET, S>,
+/// Record reads from the input memory
+#[allow(unused)]
+fn trace_reads<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
     _state: Option<&mut S>,
     _id: u64,
-    _pc: GuestAddr,
     addr: GuestAddr,
-    size: usize,
+    _size: usize,
 ) where
-    ET: EmulatorModuleTuple<S>,
-    S: UsesInput + Unpin + HasMetadata,
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
 {
-    // Record input memory reads
+    let emulator = hooks.qemu();
+    let Some(h) = hooks
+        .modules_mut()
+        .match_first_type_mut::<OSEKSystemStateHelper>()
+    else {
+        return;
+    };
+    h.capture.on_read(&emulator, addr);
 }
 
 /*============================================================================
@@ -431,11 +582,13 @@ where
         
         // Job completion hook
         #[cfg(feature = "trace_job_response_times")]
-        emulator_modules.instructions(
-            self.job_done_addr,
-            Hook::Function(job_done_hook::<ET, S>),
-            false,
-        );
+        if let Some(job_done_addr) = self.job_done_addr.filter(|_| self.job_done_apis.is_empty()) {
+            emulator_modules.instructions(
+                job_done_addr,
+                Hook::Function(job_done_hook::<ET, S>),
+                false,
+            );
+        }
         
         // Memory read hooks
         #[cfg(feature = "trace_reads")]
@@ -447,10 +600,6 @@ where
             Hook::Empty,
             Hook::Function(trace_reads::<ET, S>),
         );
-        
-        unsafe {
-            INPUT_MEM = self.input_mem.clone();
-        }
     }
 
     fn pre_exec<ET>(
@@ -461,97 +610,199 @@ where
     ) where
         ET: EmulatorModuleTuple<S>,
     {
-        unsafe {
-            CURRENT_SYSTEMSTATE_VEC.clear();
-            JOBS_DONE.clear();
-        }
+        self.capture.clear();
         
         if state.has_metadata::<OSEKTraceMetadata>() {
             state.remove_metadata::<OSEKTraceMetadata>();
         }
     }
 
-    fn post_exec<ET, OT>(
+    fn post_exec<OT, ET>(
         &mut self,
         emulator_modules: &mut EmulatorModules<ET, S>,
         state: &mut S,
         _input: &S::Input,
         _observers: &mut OT,
-        exit_kind: &mut ExitKind,
+        _exit_kind: &mut ExitKind,
     ) where
-        ET: EmulatorModuleTuple<S>,
         OT: ObserversTuple<S::Input, S>,
+        ET: EmulatorModuleTuple<S>,
     {
         let mut need_to_debug = false;
-        if unsafe { CURRENT_SYSTEMSTATE_VEC.len() } == 0 {
+        let mut error_classes = Vec::new();
+        if self.capture.states.is_empty() {
             eprintln!("No system states captured, aborting");
             return;
         }
         
-        // Collect final state
+        // Collect the final system state
         let c = emulator_modules.qemu().cpu_from_index(0);
-        let pc = c.read_reg::<_, u32>(libafl_qemu::regs::Regs::Pc).unwrap() as GuestAddr;
-        trigger_collection(&emulator_modules.qemu(), self, CaptureEvent::End, pc);
-        
-        // Process captured states
-        let raw_states = unsafe { CURRENT_SYSTEMSTATE_VEC.split_off(0) };
-        let mut refined_states = Vec::new();
+        let pc = c.read_reg::<_, u32>(libafl_qemu::regs::Regs::Pc).unwrap_or(0) as GuestAddr;
+        trigger_collection(&emulator_modules.qemu(), (pc, 0), CaptureEvent::End, self);
+        if let Some(addr) = self.input_counter_addr {
+            self.capture.check_input_counter(read_u32(&emulator_modules.qemu(), addr));
+        }
         
-        for raw in &raw_states {
-            refined_states.push(OSEKSystemState::from_raw(raw));
+        // Drop everything before the first dispatch of a task
+        let states = &mut self.capture.states;
+        let index = states
+            .iter()
+            .position(|x| x.capture_point.0 == CaptureEvent::ISREnd && x.capture_point.1 == TASK_START_ISR)
+            .unwrap_or(0);
+        #[cfg(feature = "snapshot_at_first_task")]
+        if states.get(index).map_or(false, |x| x.capture_point.0 == CaptureEvent::ISREnd && x.capture_point.1 == TASK_START_ISR)
+            && !state.has_metadata::<TaskStartMetadata>()
+        {
+            if let Some((_, r)) = self.isr_fn_ranges.iter().find(|(n, _)| n == TASK_START_ISR) {
+                state.add_metadata(TaskStartMetadata {
+                    isr_entry: r.start,
+                    tick: states[index].icount,
+                    input_read: cfg!(feature = "trace_reads")
+                        .then(|| states[..=index].iter().any(|s| !s.mem_reads.is_empty())),
+                });
+            }
+        }
+        drop(states.drain(..index));
+        if states.len() == 1 {
+            eprintln!("No system states captured, aborting");
+            return;
         }
         
-        // Build execution intervals from state transitions
-        let mut intervals = Vec::new();
-        for i in 0..raw_states.len().saturating_sub(1) {
-            let start = &raw_states[i];
-            let end = &raw_states[i + 1];
-            let start_state = &refined_states[i];
-            
-            let task_name = if start.current_task_idx != 0xFF && (start.current_task_idx as usize) < start.task_names.len() {
-                Cow::Owned(start.task_names[start.current_task_idx as usize].clone())
-            } else {
-                Cow::Borrowed("IDLE")
-            };
-            
-            let interval = ExecInterval {
-                start_tick: start.icount,
-                end_tick: end.icount,
-                start_state_hash: compute_hash(start_state),
-                end_state_hash: compute_hash(&refined_states[i + 1]),
-                task_name,
-                abb: AtomicBasicBlock::default(),
-            };
-            intervals.push(interval);
+        // Refine the raw states and cut the trace into intervals
+        let (refined_states, metadata) = refine_system_states(self.capture.take_states());
+        let (intervals, mem_reads, dumped_states, abb_error, isr_worst_durations) =
+            states2intervals(refined_states.clone(), metadata, TASK_START_ISR, &self.symbols);
+        let success = abb_error.is_none();
+        #[allow(unused_mut)]
+        let mut first_error = abb_error;
+        need_to_debug |= !success;
+        if !success {
+            error_classes.push(ErrorClass::AbbMismatch);
         }
+        if self.capture.truncated {
+            error_classes.push(ErrorClass::Truncation);
+        }
+        let isr_budget_exceeded = isr_worst_durations
+            .iter()
+            .any(|(name, duration)| self.isr_budgets.get(name).map_or(false, |budget| duration > budget));
         
-        // Build job records
+        // Build job records, without trigger_job_done every release would stay unanswered
         #[cfg(not(feature = "trace_job_response_times"))]
         let jobs = Vec::new();
         #[cfg(feature = "trace_job_response_times")]
-        let jobs = unsafe {
-            JOBS_DONE.iter().map(|(name, release, response)| RTOSJob {
-                name: name.clone(),
-                release: *release,
-                response: *response,
-                exec_ticks: response - release,
-                preemptions: 0,
-            }).collect()
+        let jobs = if self.job_done_addr.is_none() && self.job_done_apis.is_empty() {
+            Vec::new()
+        } else {
+            let releases = get_releases(&intervals, &dumped_states);
+            let inferred = !self.job_done_apis.is_empty();
+            let responses = if inferred {
+                infer_responses(&intervals, &dumped_states, &self.job_done_apis)
+            } else {
+                self.capture.take_jobs()
+            };
+            let (job_spans, unpaired) = get_release_response_pairs(&releases, &responses);
+            need_to_debug |= unpaired.is_some();
+            if let Some(tick) = unpaired {
+                error_classes.push(ErrorClass::ReleaseResponsePairing);
+                first_error = RefinementError::earlier(first_error, Some(RefinementError::at_tick(ErrorClass::ReleaseResponsePairing, &intervals, tick)));
+            }
+            let mut jobs = get_jobs(job_spans, &intervals, &mem_reads, &dumped_states);
+            jobs.iter_mut().for_each(|j| j.inferred = inferred);
+            jobs
         };
         
-        let metadata = OSEKTraceMetadata::new(
+        state.add_metadata(OSEKTraceMetadata::new(
             refined_states,
             intervals,
-            vec![unsafe { MEM_READ.clone() }],
+            mem_reads,
             jobs,
             need_to_debug,
-        );
-        
-        state.add_metadata(metadata);
-        
-        unsafe {
-            MEM_READ.clear();
-            JOBS_DONE.clear();
-        }
+            isr_worst_durations,
+            isr_budget_exceeded,
+            self.capture.truncated,
+        ).with_error_classes(error_classes).with_first_error(first_error));
+    }
+
+    type ModuleAddressFilter = NopAddressFilter;
+
+    type ModulePageFilter = NopPageFilter;
+
+    fn address_filter(&self) -> &Self::ModuleAddressFilter {
+        &self.address_filter
+    }
+
+    fn address_filter_mut(&mut self) -> &mut Self::ModuleAddressFilter {
+        &mut self.address_filter
+    }
+
+    fn page_filter(&self) -> &Self::ModulePageFilter {
+        &self.page_filter
+    }
+
+    fn page_filter_mut(&mut self) -> &mut Self::ModulePageFilter {
+        &mut self.page_filter
+    }
+}
+
+/*============================================================================
+ * State Refinement
+ *============================================================================*/
+
+/// Drains a list of raw states into refined states and their capture context
+pub(crate) fn refine_system_states(
+    mut input: Vec<RawOSEKSystemState>,
+) -> (Vec<OSEKSystemState>, Vec<OSEKSystemStateContext>) {
+    let mut ret = (Vec::new(), Vec::new());
+    for raw in input.drain(..) {
+        ret.0.push(OSEKSystemState::from_raw(&raw));
+        ret.1.push(OSEKSystemStateContext {
+            qemu_tick: raw.icount,
+            capture_point: raw.capture_point,
+            edge: raw.edge,
+            mem_reads: raw.mem_reads,
+        });
+    }
+    ret
+}
+
+/*============================================================================
+ * Task Release Times
+ *============================================================================*/
+
+/// Find all task releases.
+/// Tasks that are ready at the start of the trace are released at its start,
+/// afterwards a task is released whenever it leaves the suspended state or gains another pending activation.
+pub(crate) fn get_releases(
+    trace: &Vec<ExecInterval>,
+    states: &HashMap<u64, OSEKSystemState>,
+) -> Vec<(u64, String)> {
+    let mut ret = Vec::new();
+    if let Some(first) = trace.first() {
+        let start_state = states.get(&first.start_state).expect("State not found");
+        std::iter::once(&start_state.current_task)
+            .chain(start_state.ready_list.iter())
+            .filter(|x| !x.task_name.is_empty())
+            .for_each(|x| ret.push((first.start_tick, x.task_name.clone())));
+    }
+    for i in trace {
+        let start_state = states.get(&i.start_state).expect("State not found");
+        let end_state = states.get(&i.end_state).expect("State not found");
+        std::iter::once(&end_state.current_task)
+            .chain(end_state.ready_list.iter())
+            .filter(|x| !x.task_name.is_empty())
+            .for_each(|x| {
+                let was_suspended = start_state
+                    .suspended_list
+                    .iter()
+                    .any(|y| y.task_name == x.task_name);
+                let was_activated = std::iter::once(&start_state.current_task)
+                    .chain(start_state.ready_list.iter())
+                    .chain(start_state.waiting_list.iter())
+                    .any(|y| y.task_name == x.task_name && y.activation_count < x.activation_count);
+                if was_suspended || was_activated {
+                    ret.push((i.end_tick, x.task_name.clone()));
+                }
+            });
     }
+    ret
 }
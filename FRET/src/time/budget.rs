@@ -0,0 +1,121 @@
+//! Aborts executions inside QEMU once they run longer than a budget of guest ticks, see [`IcountBudgetModule`].
+//! Unlike the wall-clock timeout of the executor the budget is deterministic, the same input always stops at the same tick.
+
+use libafl::{executors::ExitKind, inputs::UsesInput, observers::ObserversTuple};
+use libafl_qemu::{
+    modules::{EmulatorModule, EmulatorModuleTuple, NopAddressFilter, NopPageFilter},
+    EmulatorModules, Hook,
+};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::systemstate::helpers::get_icount;
+
+/// Set by the block hook, which may run on a vCPU thread of QEMU, and read by the harness.
+/// Shared by the whole process like QEMU itself, so only one client per process may use a budget.
+static BUDGET_EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the last execution was stopped by the [`IcountBudgetModule`], read by the harness to classify the stop
+pub fn budget_exceeded() -> bool {
+    BUDGET_EXCEEDED.load(Ordering::Relaxed)
+}
+
+/// A Qemu module which stops the execution once it ran for `budget` ticks, measured from the same baseline as the clock observer.
+/// Noop without a budget. The check runs at the start of every translation block, so the stop overshoots by at most one block.
+#[derive(Debug, Default)]
+pub struct IcountBudgetModule {
+    budget: Option<u64>,
+    /// Icount at which the current execution stops
+    deadline: u64,
+    address_filter: NopAddressFilter,
+    page_filter: NopPageFilter,
+}
+
+impl IcountBudgetModule {
+    pub fn new(budget: Option<u64>) -> Self {
+        Self { budget, deadline: u64::MAX, address_filter: NopAddressFilter, page_filter: NopPageFilter }
+    }
+}
+
+impl<S> EmulatorModule<S> for IcountBudgetModule
+where
+    S: UsesInput + Unpin,
+{
+    fn first_exec<ET>(&mut self, emulator_modules: &mut EmulatorModules<ET, S>, _state: &mut S)
+    where
+        ET: EmulatorModuleTuple<S>,
+    {
+        if self.budget.is_some() {
+            emulator_modules.blocks(Hook::Empty, Hook::Empty, Hook::Function(budget_block_hook::<ET, S>));
+        }
+    }
+
+    fn pre_exec<ET>(
+        &mut self,
+        emulator_modules: &mut EmulatorModules<ET, S>,
+        _state: &mut S,
+        _input: &S::Input,
+    ) where
+        ET: EmulatorModuleTuple<S>,
+    {
+        BUDGET_EXCEEDED.store(false, Ordering::Relaxed);
+        let Some(budget) = self.budget else { return };
+        // restored snapshots start counting from zero, as in QemuClockObserver
+        let start = if cfg!(feature = "snapshot_restore") { 0 } else { get_icount(&emulator_modules.qemu()) };
+        self.deadline = start.saturating_add(budget);
+    }
+
+    fn post_exec<OT, ET>(
+        &mut self,
+        _emulator_modules: &mut EmulatorModules<ET, S>,
+        _state: &mut S,
+        _input: &S::Input,
+        _observers: &mut OT,
+        _exit_kind: &mut ExitKind,
+    ) where
+        OT: ObserversTuple<S::Input, S>,
+        ET: EmulatorModuleTuple<S>,
+    {
+        self.deadline = u64::MAX;
+    }
+
+    type ModuleAddressFilter = NopAddressFilter;
+
+    type ModulePageFilter = NopPageFilter;
+
+    fn address_filter(&self) -> &Self::ModuleAddressFilter {
+        &self.address_filter
+    }
+
+    fn address_filter_mut(&mut self) -> &mut Self::ModuleAddressFilter {
+        &mut self.address_filter
+    }
+
+    fn page_filter(&self) -> &Self::ModulePageFilter {
+        &self.page_filter
+    }
+
+    fn page_filter_mut(&mut self) -> &mut Self::ModulePageFilter {
+        &mut self.page_filter
+    }
+}
+
+/// Stop the execution once the deadline is reached
+fn budget_block_hook<QT, S>(
+    hooks: &mut EmulatorModules<QT, S>,
+    _state: Option<&mut S>,
+    _id: u64,
+) where
+    S: UsesInput,
+    QT: EmulatorModuleTuple<S>,
+{
+    let emulator = hooks.qemu();
+    let Some(h) = hooks.modules_mut().match_first_type_mut::<IcountBudgetModule>() else {
+        return;
+    };
+    if budget_exceeded() || get_icount(&emulator) < h.deadline {
+        return;
+    }
+    BUDGET_EXCEEDED.store(true, Ordering::Relaxed);
+    emulator.current_cpu().expect("No cpu is executing").trigger_breakpoint();
+}
@@ -0,0 +1,118 @@
+//! Repeated execution of a single input, to measure the timing noise of the setup
+use hashbrown::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::systemstate::target_os::SystemTraceData;
+
+/// Observations of a single calibration run
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationRun {
+    pub icount: u64,
+    /// (task, release, response) of every job in order of release
+    pub jobs: Vec<(String, u64, u64)>,
+}
+
+impl CalibrationRun {
+    pub fn from_trace<T: SystemTraceData>(icount: u64, trace: &T) -> Self {
+        let mut jobs: Vec<_> = trace.jobs().iter().map(|x| (x.name.clone(), x.release, x.response)).collect();
+        jobs.sort_by_key(|x| (x.1, x.2));
+        Self { icount, jobs }
+    }
+
+    /// Worst response time of each task
+    fn worst_response_times(&self) -> HashMap<&str, u64> {
+        let mut ret = HashMap::new();
+        for (name, release, response) in &self.jobs {
+            let e = ret.entry(name.as_str()).or_insert(0);
+            *e = u64::max(*e, response.saturating_sub(*release));
+        }
+        ret
+    }
+}
+
+/// (mean, stddev, min, max)
+fn stats(values: &[u64]) -> (f64, f64, u64, u64) {
+    let n = values.len().max(1) as f64;
+    let mean = values.iter().sum::<u64>() as f64 / n;
+    let var = values.iter().map(|x| (*x as f64 - mean).powi(2)).sum::<f64>() / n;
+    (
+        mean,
+        var.sqrt(),
+        values.iter().copied().min().unwrap_or(0),
+        values.iter().copied().max().unwrap_or(0),
+    )
+}
+
+/// Describes the first run which differs from the first one, and which component diverges first
+fn first_divergence(runs: &[CalibrationRun]) -> Option<String> {
+    let base = runs.first()?;
+    let (i, run) = runs.iter().enumerate().skip(1).find(|(_, x)| *x != base)?;
+    let job = base.jobs.iter().zip(run.jobs.iter()).position(|(a, b)| a != b);
+    Some(match job {
+        Some(j) => format!(
+            "run {} diverges in job timings first: job {} is {:?} instead of {:?}",
+            i, j, run.jobs[j], base.jobs[j]
+        ),
+        None if base.jobs.len() != run.jobs.len() => format!(
+            "run {} diverges in job timings first: {} jobs instead of {}",
+            i,
+            run.jobs.len(),
+            base.jobs.len()
+        ),
+        None => format!(
+            "run {} diverges in icount only: {} instead of {}, job timings are identical",
+            i, run.icount, base.icount
+        ),
+    })
+}
+
+/// Human readable report of mean/stddev/min/max of the icount and the per-task worst response times
+pub fn calibration_report(runs: &[CalibrationRun]) -> String {
+    let mut out = String::new();
+    let icounts: Vec<u64> = runs.iter().map(|x| x.icount).collect();
+    out.push_str("component,mean,stddev,min,max,jitter\n");
+    let (mean, sd, min, max) = stats(&icounts);
+    let icount_jitter = max - min;
+    out.push_str(&format!("icount,{:.1},{:.1},{},{},{}\n", mean, sd, min, max, icount_jitter));
+    let per_run: Vec<_> = runs.iter().map(|x| x.worst_response_times()).collect();
+    let mut tasks: Vec<&str> = per_run.iter().flat_map(|x| x.keys().copied()).collect();
+    tasks.sort();
+    tasks.dedup();
+    for t in tasks {
+        let values: Vec<u64> = per_run.iter().map(|x| x.get(t).copied().unwrap_or(0)).collect();
+        let (mean, sd, min, max) = stats(&values);
+        out.push_str(&format!("wort_{},{:.1},{:.1},{},{},{}\n", t, mean, sd, min, max, max - min));
+    }
+    match first_divergence(runs) {
+        None => out.push_str(&format!("Deterministic: all {} runs are identical\n", runs.len())),
+        Some(d) => out.push_str(&format!("Jitter detected: icount range {}, {}\n", icount_jitter, d)),
+    }
+    out
+}
+
+/// Appends a summary line to a csv file, to track determinism across versions
+pub fn append_calibration(path: &Path, kernel: &Path, runs: &[CalibrationRun]) -> std::io::Result<()> {
+    let new = !path.exists();
+    let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+    if new {
+        writeln!(f, "timestamp,version,kernel,runs,icount_mean,icount_stddev,icount_min,icount_max,deterministic")?;
+    }
+    let icounts: Vec<u64> = runs.iter().map(|x| x.icount).collect();
+    let (mean, sd, min, max) = stats(&icounts);
+    writeln!(
+        f,
+        "{},{},{},{},{:.1},{:.1},{},{},{}",
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        env!("CARGO_PKG_VERSION"),
+        kernel.display(),
+        runs.len(),
+        mean,
+        sd,
+        min,
+        max,
+        first_divergence(runs).is_none()
+    )
+}
@@ -4,7 +4,9 @@ use libafl::{
 };
 use libafl_bolts::Named;
 use serde::{Deserialize, Serialize};
-use std::{fs::OpenOptions, io::Write};
+use std::io::Write;
+
+use crate::dump::{append_dump, write_ron_dump, DumpWriter};
 
 use core::{fmt::Debug, time::Duration};
 use libafl::common::HasMetadata;
@@ -17,6 +19,7 @@ use libafl_bolts::tuples::MatchNameRef;
 use libafl::SerdeAny;
 use std::borrow::Cow;
 use std::path::PathBuf;
+use std::sync::OnceLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::systemstate::helpers::metadata_insert_or_update_get;
@@ -27,21 +30,39 @@ use libafl::prelude::StateInitializer;
 
 pub static mut FUZZ_START_TIMESTAMP: SystemTime = UNIX_EPOCH;
 
-pub const QEMU_ICOUNT_SHIFT: u32 = 5;
-pub const QEMU_ISNS_PER_SEC: u32 = u32::pow(10, 9) / u32::pow(2, QEMU_ICOUNT_SHIFT);
-pub const QEMU_ISNS_PER_MSEC: u32 = QEMU_ISNS_PER_SEC / 1000;
-pub const QEMU_ISNS_PER_USEC: f32 = QEMU_ISNS_PER_SEC as f32 / 1000000.0;
-pub const _QEMU_NS_PER_ISN: u32 = 1 << QEMU_ICOUNT_SHIFT;
+pub const DEFAULT_QEMU_ICOUNT_SHIFT: u32 = 5;
+/// Set once during startup, defaults to [`DEFAULT_QEMU_ICOUNT_SHIFT`] when read before
+static QEMU_ICOUNT_SHIFT: OnceLock<u32> = OnceLock::new();
 pub const _TARGET_SYSCLK_FREQ: u32 = 25 * 1000 * 1000;
-pub const _TARGET_MHZ_PER_MIPS: f32 = _TARGET_SYSCLK_FREQ as f32 / QEMU_ISNS_PER_SEC as f32;
-pub const _TARGET_MIPS_PER_MHZ: f32 = QEMU_ISNS_PER_SEC as f32 / _TARGET_SYSCLK_FREQ as f32;
-pub const _TARGET_SYSCLK_PER_QEMU_SEC: u32 =
-    (_TARGET_SYSCLK_FREQ as f32 * _TARGET_MIPS_PER_MHZ) as u32;
-pub const _QEMU_SYSCLK_PER_TARGET_SEC: u32 =
-    (_TARGET_SYSCLK_FREQ as f32 * _TARGET_MHZ_PER_MIPS) as u32;
+
+/// Set the icount shift used by QEMU. Must happen before any time conversion.
+pub fn set_qemu_icount_shift(shift: u32) {
+    let current = *QEMU_ICOUNT_SHIFT.get_or_init(|| shift);
+    assert_eq!(current, shift, "icount shift was already initialized to {}", current);
+}
+
+pub fn qemu_icount_shift() -> u32 {
+    *QEMU_ICOUNT_SHIFT.get_or_init(|| DEFAULT_QEMU_ICOUNT_SHIFT)
+}
+
+pub fn qemu_isns_per_sec() -> u32 {
+    u32::pow(10, 9) / u32::pow(2, qemu_icount_shift())
+}
+
+pub fn qemu_isns_per_msec() -> u32 {
+    qemu_isns_per_sec() / 1000
+}
+
+pub fn qemu_isns_per_usec() -> f32 {
+    qemu_isns_per_sec() as f32 / 1000000.0
+}
+
+pub fn _qemu_ns_per_isn() -> u32 {
+    1 << qemu_icount_shift()
+}
 
 pub fn tick_to_time(ticks: u64) -> Duration {
-    Duration::from_nanos((ticks * _QEMU_NS_PER_ISN as u64))
+    Duration::from_nanos(ticks * _qemu_ns_per_isn() as u64)
 }
 
 pub fn tick_to_ms(ticks: u64) -> f32 {
@@ -49,7 +70,38 @@ pub fn tick_to_ms(ticks: u64) -> f32 {
 }
 
 pub fn time_to_tick(time: Duration) -> u64 {
-    time.as_nanos() as u64 / _QEMU_NS_PER_ISN as u64
+    time.as_nanos() as u64 / _qemu_ns_per_isn() as u64
+}
+
+/// Start a new timedump file with a comment recording the icount shift, and the column names if tasks have their own columns
+pub fn write_timedump_header(file: &mut DumpWriter, task_columns: &[String]) {
+    if file.is_new() {
+        writeln!(file, "# icount_shift={}", qemu_icount_shift()).expect("Write to dump failed");
+        if !task_columns.is_empty() {
+            writeln!(file, "# icount,timestamp,{}", task_columns.join(",")).expect("Write to dump failed");
+        }
+    }
+}
+
+/// Appends the buffered entries of `hist` to a timedump, followed by the WORTs of the task columns if there are any
+pub fn drain_timedump(hist: &mut IcHist, file: &mut impl Write) {
+    let mut worts = hist.2.drain(..);
+    for (icount, timestamp) in hist.0.drain(..) {
+        match worts.next() {
+            Some(w) => writeln!(file, "{},{},{}", icount, timestamp, w.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",")),
+            None => writeln!(file, "{},{}", icount, timestamp),
+        }
+        .expect("Write to dump failed");
+    }
+}
+
+/// Appends the buffered entries of `hist` to a response time dump in a single write
+pub fn drain_rt_hist(hist: &mut RtHist, file: &mut impl Write) {
+    let mut out = String::new();
+    for (response_time, timestamp) in hist.entries.drain(..) {
+        out.push_str(&format!("{},{}\n", response_time, timestamp));
+    }
+    file.write_all(out.as_bytes()).expect("Write to dump failed");
 }
 
 //========== Metadata
@@ -97,9 +149,21 @@ impl Default for MaxIcountMetadata {
     }
 }
 
-/// A piece of metadata tracking all icounts
+/// A piece of metadata tracking all icounts, along with the WORT of each task column per entry
+#[derive(Debug, Default, SerdeAny, Serialize, Deserialize)]
+pub struct IcHist(pub Vec<(u64, u128)>, pub (u64, u128), pub Vec<Vec<u64>>);
+
+/// Minimum time in ms between two writes to the response time dump
+pub const RT_HIST_FLUSH_MS: u128 = 1000;
+
+/// The response time of the worst job of the selected task in each execution, buffered for the `.rt_hist` dump
 #[derive(Debug, Default, SerdeAny, Serialize, Deserialize)]
-pub struct IcHist(pub Vec<(u64, u128)>, pub (u64, u128));
+pub struct RtHist {
+    /// (response time, timestamp) per execution since the last write
+    pub entries: Vec<(u64, u128)>,
+    /// timestamp of the last write
+    pub last_flush: u128,
+}
 
 //========== Observer
 
@@ -107,8 +171,14 @@ pub struct IcHist(pub Vec<(u64, u128)>, pub (u64, u128));
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct QemuClockObserver<SYS: TargetSystem> {
     name: Cow<'static, str>,
+    /// Baseline of the execution, the absolute icount before the run.
+    /// Zero with `snapshot_restore`, since each restore rewinds the icount to the snapshot taken during boot.
     start_tick: u64,
+    /// Absolute icount after the run, or the response time of the selected task
     end_tick: u64,
+    /// Absolute icount after the run, regardless of the selected task
+    #[serde(default)]
+    end_absolute: u64,
     select_task: Option<String>,
     phantom: std::marker::PhantomData<SYS>,
 }
@@ -121,6 +191,7 @@ impl<SYS: TargetSystem> QemuClockObserver<SYS> {
             name: Cow::from(name),
             start_tick: 0,
             end_tick: 0,
+            end_absolute: 0,
             select_task: select_task.clone(),
             phantom: std::marker::PhantomData,
         }
@@ -129,7 +200,13 @@ impl<SYS: TargetSystem> QemuClockObserver<SYS> {
     /// Gets the runtime for the last execution of this target.
     #[must_use]
     pub fn last_runtime(&self) -> u64 {
-        self.end_tick - self.start_tick
+        self.end_tick.saturating_sub(self.start_tick)
+    }
+
+    /// Gets the absolute icount at the end of the last execution, without subtracting the baseline
+    #[must_use]
+    pub fn last_absolute_icount(&self) -> u64 {
+        self.end_absolute
     }
 }
 
@@ -140,11 +217,14 @@ where
 {
     fn pre_exec(&mut self, _state: &mut S, _input: &I) -> Result<(), Error> {
         self.start_tick = 0;
+        self.end_tick = 0;
+        self.end_absolute = 0;
         // Only remember the pre-run ticks if presistent mode ist used
         #[cfg(not(feature = "snapshot_restore"))]
         unsafe {
             self.start_tick = emu::icount_get_raw();
             self.end_tick = self.start_tick;
+            self.end_absolute = self.start_tick;
         }
         Ok(())
     }
@@ -158,21 +238,28 @@ where
         if _exit_kind != &ExitKind::Ok {
             self.start_tick = 0;
             self.end_tick = 0;
+            self.end_absolute = 0;
             return Ok(());
         }
+        let absolute = unsafe {libafl_qemu::sys::icount_get_raw()};
+        if absolute < self.start_tick {
+            log::error!("icount went backwards during the execution: {} -> {}", self.start_tick, absolute);
+        }
+        self.end_absolute = absolute;
         #[cfg(feature = "trace_job_response_times")]
         let icount = {
             if let Some(select) = self.select_task.as_ref() {
                 let trace = state
                     .metadata::<SYS::TraceData>()
                     .expect("TraceData not found");
-                trace.wort_of_task(select)
+                // A response time, already relative to the release
+                trace.wort_of_task(select) + self.start_tick
             } else {
-                unsafe {libafl_qemu::sys::icount_get_raw()}
+                absolute
             }
         };
         #[cfg(not(feature = "trace_job_response_times"))]
-        let icount = unsafe {libafl_qemu::sys::icount_get_raw()};
+        let icount = absolute;
 
         self.end_tick = icount;
         Ok(())
@@ -192,6 +279,7 @@ impl<SYS: TargetSystem> Default for QemuClockObserver<SYS> {
             name: Cow::from(String::from("clock")),
             start_tick: 0,
             end_tick: 0,
+            end_absolute: 0,
             select_task: None,
             phantom: std::marker::PhantomData,
         }
@@ -205,6 +293,9 @@ impl<SYS: TargetSystem> Default for QemuClockObserver<SYS> {
 pub struct ClockTimeFeedback<SYS> {
     exec_time: Option<Duration>,
     select_task: Option<String>,
+    /// Tasks whose WORT is written to the timedump next to the runtime
+    #[serde(default)]
+    task_columns: Vec<String>,
     name: Cow<'static, str>,
     dump_path: Option<PathBuf>,
     phantom: std::marker::PhantomData<SYS>,
@@ -255,19 +346,49 @@ where {
         
         // Dump the icounts to a file
         if let Some(td) = &self.dump_path {
+            #[cfg(feature = "trace_job_response_times")]
+            let worts: Vec<u64> = if self.task_columns.is_empty() {
+                Vec::new()
+            } else {
+                let trace = state
+                    .metadata::<SYS::TraceData>()
+                    .expect("TraceData not found");
+                self.task_columns.iter().map(|t| trace.wort_of_task(t)).collect()
+            };
+            #[cfg(not(feature = "trace_job_response_times"))]
+            let worts: Vec<u64> = self.task_columns.iter().map(|_| 0).collect();
             let metadata = state.metadata_map_mut();
             let timestamp = SystemTime::now()
                 .duration_since(unsafe { FUZZ_START_TIMESTAMP })
                 .unwrap()
                 .as_millis();
+
+            // With a selected task the icount is the response time of its worst job
+            #[cfg(feature = "trace_job_response_times")]
+            if self.select_task.is_some() {
+                let rt_hist = metadata_insert_or_update_get::<RtHist>(metadata, RtHist::default, |_| {});
+                rt_hist.entries.push((icount, timestamp));
+                if timestamp.saturating_sub(rt_hist.last_flush) >= RT_HIST_FLUSH_MS {
+                    let mut file = append_dump(td.with_extension("rt_hist"))
+                        .expect("Could not open response time dump");
+                    write_timedump_header(&mut file, &[]);
+                    drain_rt_hist(rt_hist, &mut file);
+                    rt_hist.last_flush = timestamp;
+                }
+            }
+
             let hist = metadata_insert_or_update_get::<IcHist>(
                 metadata,
                 || IcHist(
                     vec![(icount, timestamp)],
                     (icount, timestamp),
+                    if worts.is_empty() { Vec::new() } else { vec![worts.clone()] },
                 ),
                 |hist| {
                     hist.0.push((icount, timestamp));
+                    if !worts.is_empty() {
+                        hist.2.push(worts.clone());
+                    }
                     if hist.1 .0 < icount {
                         hist.1 = (icount, timestamp);
                     }
@@ -275,17 +396,10 @@ where {
             );
 
             if hist.0.len() >= 100 {
-                let mut file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .append(true)
-                    .open(td)
+                let mut file = append_dump(td)
                     .expect("Could not open timedump");
-                let newv: Vec<(u64, u128)> = Vec::with_capacity(110);
-                for i in std::mem::replace(&mut hist.0, newv).into_iter() {
-                    writeln!(file, "{},{}", i.0, i.1).expect("Write to dump failed");
-                }
+                write_timedump_header(&mut file, &self.task_columns);
+                drain_timedump(hist, &mut file);
             }
 
             // write out the worst case trace
@@ -294,12 +408,7 @@ where {
                 let trace = state
                     .metadata::<SYS::TraceData>()
                     .expect("TraceData not found");
-                std::fs::write(
-                    tracename,
-                    ron::to_string(trace)
-                        .expect("Error serializing hashmap"),
-                )
-                .expect("Can not dump to file");
+                write_ron_dump(tracename, trace).expect("Can not dump to file");
             }
         }
         Ok(false)
@@ -341,6 +450,7 @@ impl<SYS: TargetSystem> ClockTimeFeedback<SYS> {
         Self {
             exec_time: None,
             select_task: select_task,
+            task_columns: Vec::new(),
             name: Cow::from(name.to_string()),
             dump_path: dump_path,
             phantom: std::marker::PhantomData,
@@ -353,11 +463,19 @@ impl<SYS: TargetSystem> ClockTimeFeedback<SYS> {
         Self {
             exec_time: None,
             select_task: select_task.clone(),
+            task_columns: Vec::new(),
             name: observer.name().clone(),
             dump_path: dump_path,
             phantom: std::marker::PhantomData,
         }
     }
+
+    /// Also write the WORT of each of `tasks` to the timedump, in this order
+    #[must_use]
+    pub fn with_task_columns(mut self, tasks: Vec<String>) -> Self {
+        self.task_columns = tasks;
+        self
+    }
 }
 
 /// A [`Feedback`] rewarding increasing the execution cycles on Qemu.
@@ -1,3 +1,5 @@
+pub mod budget;
+pub mod calibration;
 pub mod clock;
 pub mod qemustate;
-pub mod worst;
\ No newline at end of file
+pub mod worst;
\ No newline at end of file
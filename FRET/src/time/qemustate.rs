@@ -3,12 +3,100 @@ use libafl_qemu::modules::NopAddressFilter;
 use libafl_qemu::modules::NopPageFilter;
 use libafl_qemu::sys::CPUArchState;
 use libafl_qemu::FastSnapshotPtr;
+use libafl_qemu::GuestAddr;
 use libafl_qemu::modules::EmulatorModule;
 use libafl_qemu::modules::EmulatorModuleTuple;
 use libafl::executors::ExitKind;
 use libafl_qemu::QemuHooks;
 use libafl_qemu::EmulatorModules;
 use libafl::prelude::ObserversTuple;
+use libafl::HasMetadata;
+#[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+use libafl_qemu::{Qemu, QemuExitReason};
+#[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+use serde::{Deserialize, Serialize};
+#[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+use std::time::{Duration, Instant};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+use crate::{fuzzer::FIRST_INT, systemstate::helpers::get_icount};
+
+/// Counts the snapshots taken or dropped by the [`QemuStateRestoreHelper`], see [`snapshot_epoch`]
+static SNAPSHOT_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Changes whenever executions start from a different snapshot, so caches of earlier executions know they are stale
+pub fn snapshot_epoch() -> u64 {
+    SNAPSHOT_EPOCH.load(Ordering::Relaxed)
+}
+
+#[allow(unused)]
+fn next_snapshot_epoch() {
+    SNAPSHOT_EPOCH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Where the first task was dispatched during an execution, left behind by the system state helper
+#[cfg(feature = "snapshot_at_first_task")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskStartMetadata {
+    /// Entry of the ISR which dispatched the first task
+    pub isr_entry: GuestAddr,
+    /// Tick at which the first task started
+    pub tick: u64,
+    /// Whether the input was read before the first task started, unknown without `trace_reads`
+    pub input_read: Option<bool>,
+}
+#[cfg(feature = "snapshot_at_first_task")]
+libafl_bolts::impl_serdeany!(TaskStartMetadata);
+
+/// Restores from the later snapshot (at the first task or at FUZZ_SETUP_DONE) or the initial one, and the time spent executing after each of them
+#[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SnapshotStatsMetadata {
+    pub fast_restores: u64,
+    pub full_restores: u64,
+    pub fast_time: Duration,
+    pub full_time: Duration,
+}
+#[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+libafl_bolts::impl_serdeany!(SnapshotStatsMetadata);
+
+#[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+impl SnapshotStatsMetadata {
+    /// Execs/sec after the later snapshot relative to execs/sec after the initial one
+    pub fn speedup(&self) -> Option<f64> {
+        if self.fast_restores == 0 || self.full_restores == 0 || self.fast_time.is_zero() {
+            return None;
+        }
+        let fast = self.fast_time.as_secs_f64() / self.fast_restores as f64;
+        let full = self.full_time.as_secs_f64() / self.full_restores as f64;
+        Some(full / fast)
+    }
+}
+
+/// Progress of the snapshot at the start of the first task
+#[cfg(feature = "snapshot_at_first_task")]
+#[derive(Debug, Clone, Copy)]
+enum FirstTaskSnapshot {
+    /// The first execution has not reported the start of the first task yet
+    Pending,
+    /// The prefix is input independent, take the snapshot at the ISR entry before the next execution
+    Armed { isr_entry: GuestAddr, tick: u64 },
+    Taken(FastSnapshotPtr),
+    /// The prefix can not be skipped, always restore the initial snapshot
+    Rejected,
+}
+
+/// Progress of the snapshot at FUZZ_SETUP_DONE, which is nested in the initial snapshot
+#[cfg(feature = "snapshot_at_setup")]
+#[derive(Debug, Clone, Copy)]
+enum SetupSnapshot {
+    /// Taken on the next execution
+    Pending,
+    Taken(FastSnapshotPtr),
+    /// There is no FUZZ_SETUP_DONE or it was not reached before the first interrupt, always restore the initial snapshot
+    Rejected,
+}
 
 // TODO be thread-safe maybe with https://amanieu.github.io/thread_local-rs/thread_local/index.html
 #[derive(Debug)]
@@ -17,7 +105,17 @@ pub struct QemuStateRestoreHelper {
     has_snapshot: bool,
     #[allow(unused)]
     saved_cpu_states: Vec<CPUArchState>,
-    fastsnap: Option<FastSnapshotPtr>
+    fastsnap: Option<FastSnapshotPtr>,
+    #[cfg(feature = "snapshot_at_first_task")]
+    first_task: FirstTaskSnapshot,
+    /// Where the target finished its input independent setup
+    #[cfg(feature = "snapshot_at_setup")]
+    setup_done: Option<GuestAddr>,
+    #[cfg(feature = "snapshot_at_setup")]
+    setup: SetupSnapshot,
+    /// Start of the current execution and whether it started from the later snapshot
+    #[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+    exec_start: Option<(Instant, bool)>,
 }
 
 impl QemuStateRestoreHelper {
@@ -26,7 +124,15 @@ impl QemuStateRestoreHelper {
         Self {
             has_snapshot: false,
             saved_cpu_states: vec![],
-            fastsnap: None
+            fastsnap: None,
+            #[cfg(feature = "snapshot_at_first_task")]
+            first_task: FirstTaskSnapshot::Pending,
+            #[cfg(feature = "snapshot_at_setup")]
+            setup_done: None,
+            #[cfg(feature = "snapshot_at_setup")]
+            setup: SetupSnapshot::Pending,
+            #[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+            exec_start: None,
         }
     }
     #[allow(unused)]
@@ -35,6 +141,135 @@ impl QemuStateRestoreHelper {
         r.fastsnap = fastsnap;
         r
     }
+
+    /// Restore a snapshot taken at `setup_done` (FUZZ_SETUP_DONE) instead of the initial one, skipping the input independent setup.
+    /// Without the feature snapshot_at_setup or the symbol the initial snapshot is used.
+    #[allow(unused_mut)]
+    #[must_use]
+    pub fn with_setup_done(mut self, setup_done: Option<GuestAddr>) -> Self {
+        #[cfg(feature = "snapshot_at_setup")]
+        {
+            self.setup_done = setup_done;
+        }
+        #[cfg(not(feature = "snapshot_at_setup"))]
+        let _ = setup_done;
+        self
+    }
+
+    /// Restore the initial snapshot, taking it if there is none yet. The setup snapshot was taken after the initial one,
+    /// so it is taken again on the next execution.
+    #[cfg(feature = "snapshot_at_setup")]
+    pub fn restore_outer(&mut self, qemu: Qemu) {
+        match self.fastsnap {
+            Some(s) => unsafe { qemu.restore_fast_snapshot(s) },
+            None => {
+                self.fastsnap = Some(qemu.create_fast_snapshot(true));
+                next_snapshot_epoch();
+            }
+        }
+        if matches!(self.setup, SetupSnapshot::Taken(_)) {
+            self.setup = SetupSnapshot::Pending;
+            next_snapshot_epoch();
+        }
+    }
+
+    /// Restore the snapshot at FUZZ_SETUP_DONE, take it first if pending by running there from the initial snapshot.
+    /// Returns false if this execution did not skip the setup.
+    #[cfg(feature = "snapshot_at_setup")]
+    fn restore_setup(&mut self, qemu: Qemu) -> bool {
+        match self.setup {
+            SetupSnapshot::Taken(s) => {
+                unsafe { qemu.restore_fast_snapshot(s) };
+                true
+            }
+            SetupSnapshot::Pending => {
+                let Some(setup_done) = self.setup_done else {
+                    self.setup = SetupSnapshot::Rejected;
+                    return false;
+                };
+                self.restore_outer(qemu);
+                // Run the setup with whatever input is still in memory, it must not read it
+                qemu.set_breakpoint(setup_done);
+                let exit = unsafe { qemu.run() };
+                qemu.remove_breakpoint(setup_done);
+                let icount = get_icount(&qemu);
+                match exit {
+                    Ok(QemuExitReason::Breakpoint(pc)) if pc == setup_done && icount < FIRST_INT as u64 => {
+                        self.setup = SetupSnapshot::Taken(qemu.create_fast_snapshot(true));
+                        next_snapshot_epoch();
+                        eprintln!("Took a snapshot at FUZZ_SETUP_DONE, skipping {} ticks", icount);
+                    }
+                    _ => {
+                        eprintln!("Could not reach FUZZ_SETUP_DONE before the first interrupt ({:?} at {}), using the initial snapshot", exit, icount);
+                        self.setup = SetupSnapshot::Rejected;
+                        self.restore_outer(qemu);
+                    }
+                }
+                // This execution still paid for the setup
+                false
+            }
+            SetupSnapshot::Rejected => false,
+        }
+    }
+
+    /// Restore the snapshot taken at the entry of the ISR which dispatches the first task, take it first if armed.
+    /// Returns false if the initial snapshot needs to be restored instead.
+    ///
+    /// Taking the snapshot runs the prefix inside [`EmulatorModule::pre_exec`], which fires the hooks of all modules.
+    /// This helper has to come first in the module tuple: the system state helpers clear what the prefix captured in their own
+    /// `pre_exec` afterwards, only the edge coverage of the prefix stays in the maps, as it would after a run from the initial snapshot.
+    #[cfg(feature = "snapshot_at_first_task")]
+    fn restore_first_task(&mut self, qemu: Qemu) -> bool {
+        match self.first_task {
+            FirstTaskSnapshot::Taken(s) => {
+                unsafe { qemu.restore_fast_snapshot(s) };
+                true
+            }
+            FirstTaskSnapshot::Armed { isr_entry, tick } => {
+                let Some(initial) = self.fastsnap else { return false };
+                unsafe { qemu.restore_fast_snapshot(initial) };
+                // Run the prefix with whatever input is still in memory, it was shown not to matter
+                qemu.set_breakpoint(isr_entry);
+                let exit = unsafe { qemu.run() };
+                qemu.remove_breakpoint(isr_entry);
+                let icount = get_icount(&qemu);
+                match exit {
+                    Ok(QemuExitReason::Breakpoint(pc)) if pc == isr_entry && icount <= tick => {
+                        self.first_task = FirstTaskSnapshot::Taken(qemu.create_fast_snapshot(true));
+                        next_snapshot_epoch();
+                        eprintln!("Took a snapshot at the first task start, skipping {} ticks", icount);
+                        // This execution still paid for the prefix
+                        false
+                    }
+                    _ => {
+                        eprintln!("Could not reach the first task start ({:?} at {}), using the initial snapshot", exit, icount);
+                        self.first_task = FirstTaskSnapshot::Rejected;
+                        unsafe { qemu.restore_fast_snapshot(initial) };
+                        false
+                    }
+                }
+            }
+            FirstTaskSnapshot::Pending | FirstTaskSnapshot::Rejected => false,
+        }
+    }
+
+    /// Arm the later snapshot once the first execution confirmed an input independent prefix
+    #[cfg(feature = "snapshot_at_first_task")]
+    fn check_first_task<S: HasMetadata>(&mut self, state: &mut S) {
+        if !matches!(self.first_task, FirstTaskSnapshot::Pending) {
+            return;
+        }
+        let Ok(start) = state.metadata::<TaskStartMetadata>() else { return };
+        self.first_task = if start.tick >= FIRST_INT as u64 {
+            eprintln!("First task starts at {} after the first interrupt, using the initial snapshot", start.tick);
+            FirstTaskSnapshot::Rejected
+        } else if start.input_read != Some(false) {
+            eprintln!("Input may be read before the first task starts, using the initial snapshot");
+            FirstTaskSnapshot::Rejected
+        } else {
+            FirstTaskSnapshot::Armed { isr_entry: start.isr_entry, tick: start.tick }
+        };
+    }
 }
 
 impl Default for QemuStateRestoreHelper {
@@ -45,7 +280,7 @@ impl Default for QemuStateRestoreHelper {
 
 impl<S> EmulatorModule<S> for QemuStateRestoreHelper
 where
-    S: UsesInput,
+    S: UsesInput + HasMetadata,
 {
     const HOOKS_DO_SIDE_EFFECTS: bool = true;
     type ModuleAddressFilter = NopAddressFilter;
@@ -63,6 +298,24 @@ where
         ET: EmulatorModuleTuple<S>,
     {
         // unsafe { println!("snapshot post {}",emu::icount_get_raw()) };
+        #[cfg(feature = "snapshot_at_first_task")]
+        self.check_first_task(_state);
+        #[cfg(any(feature = "snapshot_at_first_task", feature = "snapshot_at_setup"))]
+        {
+            if let Some((start, fast)) = self.exec_start.take() {
+                if !_state.has_metadata::<SnapshotStatsMetadata>() {
+                    _state.add_metadata(SnapshotStatsMetadata::default());
+                }
+                let stats = _state.metadata_mut::<SnapshotStatsMetadata>().unwrap();
+                if fast {
+                    stats.fast_restores += 1;
+                    stats.fast_time += start.elapsed();
+                } else {
+                    stats.full_restores += 1;
+                    stats.full_time += start.elapsed();
+                }
+            }
+        }
     }
 
     fn pre_exec<ET>(
@@ -76,10 +329,34 @@ where
         // only restore in pre-exec, to preserve the post-execution state for inspection
         #[cfg(feature = "snapshot_restore")]
         {
+            // Running a prefix to take a later snapshot fires the hooks of the other modules, see restore_first_task
+            #[cfg(feature = "snapshot_at_first_task")]
+            {
+                let start = Instant::now();
+                let fast = self.restore_first_task(_emulator_modules.qemu());
+                self.exec_start = Some((start, fast));
+                // Also continue right after the snapshot if it was just taken
+                if fast || matches!(self.first_task, FirstTaskSnapshot::Taken(_)) {
+                    return;
+                }
+            }
+            #[cfg(feature = "snapshot_at_setup")]
+            {
+                let start = Instant::now();
+                let fast = self.restore_setup(_emulator_modules.qemu());
+                self.exec_start = Some((start, fast));
+                // Also continue right after the snapshot if it was just taken
+                if fast || matches!(self.setup, SetupSnapshot::Taken(_)) {
+                    return;
+                }
+            }
             #[cfg(feature = "snapshot_fast")]
             match self.fastsnap {
                 Some(s) => unsafe { _emulator_modules.qemu().restore_fast_snapshot(s) },
-                None => {self.fastsnap = Some(_emulator_modules.qemu().create_fast_snapshot(true));},
+                None => {
+                    self.fastsnap = Some(_emulator_modules.qemu().create_fast_snapshot(true));
+                    next_snapshot_epoch();
+                },
             }
             #[cfg(not(feature = "snapshot_fast"))]
             if !self.has_snapshot {
@@ -1,7 +1,7 @@
 use core::{fmt::Debug, marker::PhantomData};
 
 use std::{
-    borrow::Cow, ops::Sub, time::{Duration, Instant}
+    borrow::Cow, ops::Sub, sync::OnceLock, time::{Duration, Instant}
 };
 
 use serde::{Serialize, Deserialize};
@@ -20,8 +20,9 @@ use libafl::{
 };
 use libafl_bolts::{ClientId, HasLen, Named};
 
+use crate::systemstate::stg::STGNodeMetadata;
 use crate::systemstate::target_os::TargetSystem;
-use crate::time::clock::QemuClockObserver;
+use crate::time::clock::{tick_to_time, QemuClockObserver};
 
 //=========================== Scheduler
 
@@ -50,6 +51,51 @@ where
     }
 }
 
+/// Task whose response time [`SelectTaskFavFactor`] ranks by, unset ranks by execution time
+static FAVORED_TASK: OnceLock<String> = OnceLock::new();
+
+/// Makes [`SelectTaskFavFactor`] rank testcases by the response time of `task`, only the first call has an effect
+pub fn set_favored_task(task: String) {
+    let _ = FAVORED_TASK.set(task);
+}
+
+/// Ranks testcases by the worst response time of the task set by [`set_favored_task`], so an input which only prolongs
+/// unrelated work does not shadow one which delays the task. Falls back to the execution time if no task is set,
+/// or the task has no job in the [`STGNodeMetadata`] of the testcase.
+#[derive(Debug, Clone)]
+pub struct SelectTaskFavFactor {}
+
+impl<S> TestcaseScore<S> for SelectTaskFavFactor
+where
+    S: HasCorpus,
+{
+    fn compute(
+        _state: &S,
+        entry: &mut Testcase<<S::Corpus as Corpus>::Input>,
+    ) -> Result<f64, Error> {
+        Ok(select_task_score(entry, FAVORED_TASK.get().map(String::as_str)))
+    }
+}
+
+/// Score of [`SelectTaskFavFactor`] for the given task
+fn select_task_score<I>(entry: &Testcase<I>, task: Option<&str>) -> f64 {
+    let response = task.and_then(|task| {
+        entry.metadata_map().get::<STGNodeMetadata>()?
+            .jobs().iter()
+            .filter(|j| j.name == task)
+            .map(|j| j.response_time())
+            .max()
+    });
+    let time = match response {
+        Some(ticks) => tick_to_time(ticks),
+        None => entry
+            .exec_time()
+            .expect("testcase.exec_time is needed for scheduler"),
+    };
+    let tns: i64 = time.as_nanos().try_into().expect("failed to convert time");
+    -tns as f64
+}
+
 pub type LenTimeMaximizerCorpusScheduler<CS, O> =
     MinimizerScheduler<CS, MaxExecsLenFavFactor<<CS as UsesState>::State>, MapIndexesMetadata, O>;
 
@@ -299,3 +345,47 @@ impl Default for RateLimitedMonitor {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use libafl::inputs::BytesInput;
+
+    use super::*;
+    use crate::systemstate::RTOSJob;
+
+    fn job(name: &str, release: u64, response: u64) -> RTOSJob {
+        let mut job = RTOSJob::default();
+        job.name = name.to_owned();
+        job.release = release;
+        job.response = response;
+        job
+    }
+
+    fn testcase(exec_time: Duration, jobs: Vec<RTOSJob>) -> Testcase<BytesInput> {
+        let mut tc = Testcase::new(BytesInput::new(vec![]));
+        tc.set_exec_time(exec_time);
+        tc.metadata_map_mut().insert(STGNodeMetadata::new(vec![], vec![], vec![], 0, 0, vec![], vec![], jobs));
+        tc
+    }
+
+    #[test]
+    fn selected_task_response_outranks_exec_time() {
+        // long overall due to background work, but the task responds quickly
+        let background = testcase(Duration::from_millis(10), vec![job("task", 0, 100), job("background", 0, 200_000), job("task", 500, 550)]);
+        // shorter overall, but the task is delayed
+        let delayed = testcase(Duration::from_millis(1), vec![job("task", 0, 1_000), job("background", 0, 10)]);
+        assert!(select_task_score(&background, None) > select_task_score(&delayed, None));
+        assert!(select_task_score(&delayed, Some("task")) > select_task_score(&background, Some("task")));
+        assert_eq!(select_task_score(&background, Some("task")), -(tick_to_time(100).as_nanos() as f64));
+    }
+
+    #[test]
+    fn missing_task_falls_back_to_exec_time() {
+        let tc = testcase(Duration::from_millis(2), vec![job("background", 0, 100)]);
+        assert_eq!(select_task_score(&tc, Some("task")), select_task_score(&tc, None));
+        assert_eq!(select_task_score(&tc, None), -2_000_000.0);
+        let mut without_jobs = Testcase::new(BytesInput::new(vec![]));
+        without_jobs.set_exec_time(Duration::from_millis(3));
+        assert_eq!(select_task_score(&without_jobs, Some("task")), -3_000_000.0);
+    }
+}
@@ -0,0 +1,26 @@
+#!/bin/sh
+# Compare the job responses inferred with JOB_DONE_APIS to the ones recorded by trigger_job_done
+# usage: compare_inferred_jobs.sh <instrumented kernel> <input> [apis]
+# the fuzzer must be built with trace_job_response_times
+
+TEST_KERNEL=${1:-../benchmark/build/waters_seq_full.elf}
+TEST_INPUT=${2:-./waters.case.test}
+TEST_SYMBOLS=../benchmark/target_symbols.csv
+APIS=${3:-"vTaskDelay;vTaskDelayUntil;xQueueReceive;ulTaskNotifyTake"}
+
+rm -rf ./dump/jobs_real ./dump/jobs_inferred
+../target/debug/fret -k "$TEST_KERNEL" -c $TEST_SYMBOLS replay -i "$TEST_INPUT" -o ./dump/jobs_real > /dev/null || exit 1
+JOB_DONE_APIS="$APIS" ../target/debug/fret -k "$TEST_KERNEL" -c $TEST_SYMBOLS replay -i "$TEST_INPUT" -o ./dump/jobs_inferred > /dev/null || exit 1
+
+# name,release,response of each job
+cut -d, -f1-3 ./dump/jobs_real/jobs.csv | tail -n +2 | sort > ./dump/jobs_real/responses.csv
+cut -d, -f1-3 ./dump/jobs_inferred/jobs.csv | tail -n +2 | sort > ./dump/jobs_inferred/responses.csv
+REAL=$(wc -l < ./dump/jobs_real/responses.csv)
+INFERRED=$(wc -l < ./dump/jobs_inferred/responses.csv)
+MATCHED=$(comm -12 ./dump/jobs_real/responses.csv ./dump/jobs_inferred/responses.csv | wc -l)
+echo "Jobs: $REAL recorded, $INFERRED inferred, $MATCHED identical"
+if [ "$REAL" -ne "$MATCHED" ] || [ "$INFERRED" -ne "$MATCHED" ]; then
+    echo "Inferred jobs differ:"
+    diff ./dump/jobs_real/responses.csv ./dump/jobs_inferred/responses.csv
+    exit 1
+fi
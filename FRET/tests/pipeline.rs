@@ -0,0 +1,100 @@
+//! Drives the shared interval and job pipeline of `target_os::intervals` with synthetic traces of the mock target system.
+
+use fret::systemstate::target_os::intervals::{get_jobs, get_release_response_pairs, states2intervals};
+use fret::systemstate::target_os::mock::{get_releases, MockTraceBuilder, MOCK_TASK_START_ISR};
+use fret::systemstate::target_os::SystemTraceData;
+use fret::systemstate::CaptureEvent;
+use fret::systemstate::helpers::SymbolResolver;
+
+/// T1 runs until it delays at tick 10, T2 runs from 20 until it delays at 50, T1 is woken at 55 and runs until the end at 70.
+/// Both jobs are released at the start of the trace.
+fn two_tasks() -> MockTraceBuilder {
+    MockTraceBuilder::new()
+        .task("T1", 2)
+        .task("T2", 1)
+        .running("T1").ready(&["T2"])
+        .capture(0, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0, 0x100))
+        .capture(10, CaptureEvent::APIStart, "vTaskDelay", (0x110, 0x500))
+        .job_done(10, "T1")
+        .running("T2").ready(&[]).delayed(&["T1"])
+        .capture(20, CaptureEvent::APIEnd, "vTaskDelay", (0x510, 0x200))
+        .capture(50, CaptureEvent::APIStart, "vTaskDelay", (0x210, 0x500))
+        .job_done(50, "T2")
+        .running("T1").delayed(&["T2"])
+        .capture(55, CaptureEvent::APIEnd, "vTaskDelay", (0x510, 0x114))
+        .capture(70, CaptureEvent::End, "trigger_Qemu_break", (0x120, 0))
+}
+
+fn pairs(x: &[(u64, &str)]) -> Vec<(u64, String)> {
+    x.iter().map(|(t, n)| (*t, n.to_string())).collect()
+}
+
+#[test]
+fn intervals_of_two_tasks() {
+    let (states, meta) = two_tasks().refined();
+    let (intervals, reads, table, error, isr_worst) = states2intervals(states, meta, MOCK_TASK_START_ISR, &SymbolResolver::default());
+    assert!(error.is_none(), "{:?}", error);
+    assert!(isr_worst.is_empty());
+    assert_eq!(reads.len(), intervals.len());
+    let summary: Vec<_> = intervals.iter().map(|x| (x.start_tick, x.end_tick, x.level, x.get_task_name_unchecked().to_string())).collect();
+    assert_eq!(summary, vec![
+        (0, 10, 0, "T1".to_string()),
+        (10, 20, 1, "vTaskDelay".to_string()),
+        (20, 50, 0, "T2".to_string()),
+        (50, 55, 1, "vTaskDelay".to_string()),
+        (55, 70, 0, "T1".to_string()),
+    ]);
+    // every interval starts and ends at a known state
+    assert!(intervals.iter().all(|x| table.contains_key(&x.start_state) && table.contains_key(&x.end_state)));
+    // T1 continues in a new block after its delay
+    assert_ne!(intervals[0].abb.as_ref().unwrap().get_instance_id(), intervals[4].abb.as_ref().unwrap().get_instance_id());
+}
+
+#[test]
+fn releases_and_jobs_of_two_tasks() {
+    let (states, meta) = two_tasks().refined();
+    let (intervals, reads, table, _, _) = states2intervals(states, meta, MOCK_TASK_START_ISR, &SymbolResolver::default());
+    let releases = get_releases(&intervals, &table);
+    assert_eq!(releases, pairs(&[(0, "T1"), (0, "T2"), (55, "T1")]));
+    let (spans, unpaired) = get_release_response_pairs(&releases, &pairs(&[(10, "T1"), (50, "T2")]));
+    assert_eq!(unpaired, None);
+    assert_eq!(spans, vec![(0, 10, "T1".to_string()), (0, 50, "T2".to_string())]);
+
+    let jobs = get_jobs(spans, &intervals, &reads, &table);
+    let summary: Vec<_> = jobs.iter().map(|x| (x.name.as_str(), x.response_time(), x.exec_ticks, x.abbs.len())).collect();
+    assert_eq!(summary, vec![("T1", 10, 10, 1), ("T2", 50, 30, 1)]);
+    // T2 waits in the ready list while T1 runs and enters its delay, without having run before
+    assert_eq!((jobs[1].preemption_count, jobs[1].preempted_ticks), (0, 20));
+    assert!(jobs.iter().all(|x| !x.inferred));
+}
+
+#[test]
+fn build_runs_the_whole_pipeline() {
+    let trace = two_tasks().build();
+    assert!(!trace.need_to_debug());
+    assert_eq!(trace.trace_length(), 5);
+    assert_eq!(trace.wort_of_task(&"T2".to_string()), 50);
+    assert_eq!(trace.worst_jobs_per_task_by_exec_time()["T2"].exec_ticks, 30);
+}
+
+#[test]
+fn pairing_keeps_the_first_of_multiple_releases() {
+    let (spans, unpaired) = get_release_response_pairs(&pairs(&[(0, "A"), (5, "A")]), &pairs(&[(10, "A")]));
+    assert_eq!(spans, vec![(0, 10, "A".to_string())]);
+    assert_eq!(unpaired, None);
+}
+
+#[test]
+fn pairing_reports_responses_without_release() {
+    let (spans, unpaired) = get_release_response_pairs(&pairs(&[(20, "B")]), &pairs(&[(10, "A"), (30, "B")]));
+    assert_eq!(spans, vec![(20, 30, "B".to_string())]);
+    assert_eq!(unpaired, Some(10));
+}
+
+#[test]
+fn pairing_falls_back_to_the_last_response() {
+    // the second response comes before the release that was detected for it
+    let (spans, unpaired) = get_release_response_pairs(&pairs(&[(0, "A"), (20, "A")]), &pairs(&[(10, "A"), (15, "A")]));
+    assert_eq!(spans, vec![(0, 10, "A".to_string()), (10, 15, "A".to_string())]);
+    assert_eq!(unpaired, None);
+}
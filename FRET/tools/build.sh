@@ -17,10 +17,13 @@ build() {
     fi
 }
 
+build corpus_report
 build edge_compare
 build graph2viz
 build input_serde
 build number_cruncher
 build state2gantt
+build stg_merge
+build trace_diff
 ln -rsf state2gantt/gantt_driver  bin/gantt_driver
-ln -rsf state2gantt/plot_gantt.r  bin/plot_gantt.r
\ No newline at end of file
+ln -rsf state2gantt/plot_gantt.r  bin/plot_gantt.r
\ No newline at end of file
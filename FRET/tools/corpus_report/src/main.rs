@@ -0,0 +1,192 @@
+use clap::Parser;
+use fret::systemstate::helpers::canonical_interrupt_times;
+use hashbrown::HashMap;
+use libafl::inputs::multi::MultipartInput;
+use libafl::inputs::{BytesInput, HasMutatorBytes, Input};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+struct Config {
+    /// Directory containing .case files
+    #[arg(value_name = "DIR")]
+    corpus: PathBuf,
+
+    /// CSV output file
+    #[arg(short, long, value_name = "FILE", default_value = "corpus_report.csv")]
+    output: PathBuf,
+
+    /// Maximum number of differing bits in the bytes part to report a near-duplicate
+    #[arg(short = 't', long, value_name = "BITS", default_value_t = 8)]
+    near_threshold: usize,
+
+    /// Delete all but the first case of each duplicate group
+    #[arg(long)]
+    delete_duplicates: bool,
+}
+
+struct CaseInfo {
+    path: PathBuf,
+    hash: u64,
+    bytes: Vec<u8>,
+    entropy: HashMap<String, f64>,
+}
+
+/// Hash of all parts, where interrupt parts only count with their decoded and sorted times
+fn canonical_hash(input: &MultipartInput<BytesInput>) -> u64 {
+    let mut parts: Vec<(&String, &BytesInput)> = input.iter().collect();
+    parts.sort_by(|a, b| a.0.cmp(b.0));
+    let mut s = DefaultHasher::new();
+    for (name, part) in parts {
+        name.hash(&mut s);
+        if name == "bytes" {
+            part.bytes().hash(&mut s);
+        } else {
+            canonical_interrupt_times(part.bytes()).hash(&mut s);
+        }
+    }
+    s.finish()
+}
+
+/// Shannon entropy in bits per byte
+fn entropy(buf: &[u8]) -> f64 {
+    if buf.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for b in buf {
+        counts[*b as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|x| **x > 0)
+        .map(|x| {
+            let p = *x as f64 / buf.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Number of differing bits, missing bytes of the shorter buffer count as fully different
+fn hamming(a: &[u8], b: &[u8]) -> usize {
+    let common = a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones() as usize).sum::<usize>();
+    common + 8 * a.len().abs_diff(b.len())
+}
+
+fn load_case(path: PathBuf) -> Option<CaseInfo> {
+    let input = match MultipartInput::<BytesInput>::from_file(&path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Skipping {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let bytes = input
+        .parts_by_name("bytes")
+        .next()
+        .map(|(_, x)| x.bytes().to_vec())
+        .unwrap_or_default();
+    let entropy = input
+        .iter()
+        .map(|(name, part)| (name.to_string(), entropy(part.bytes())))
+        .collect();
+    Some(CaseInfo { hash: canonical_hash(&input), path, bytes, entropy })
+}
+
+fn main() {
+    let conf = Config::parse();
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&conf.corpus)
+        .expect("Can not read corpus directory")
+        .filter_map(|x| x.ok().map(|x| x.path()))
+        .filter(|x| x.extension().map_or(false, |e| e == "case"))
+        .collect();
+    paths.sort();
+    let cases: Vec<CaseInfo> = paths.into_iter().filter_map(load_case).collect();
+
+    // Exact duplicates refer to the first case with the same canonical hash
+    let mut first_of_hash: HashMap<u64, usize> = HashMap::new();
+    let duplicate_of: Vec<Option<usize>> = cases
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let first = *first_of_hash.entry(c.hash).or_insert(i);
+            if first != i { Some(first) } else { None }
+        })
+        .collect();
+
+    // Near duplicates refer to the closest earlier case that is not an exact duplicate
+    let near_duplicate_of: Vec<Option<(usize, usize)>> = (0..cases.len())
+        .map(|i| {
+            if duplicate_of[i].is_some() {
+                return None;
+            }
+            (0..i)
+                .filter(|j| duplicate_of[*j].is_none())
+                .map(|j| (j, hamming(&cases[i].bytes, &cases[j].bytes)))
+                .filter(|(_, d)| *d <= conf.near_threshold)
+                .min_by_key(|(_, d)| *d)
+        })
+        .collect();
+
+    let mut part_names: Vec<&String> = cases.iter().flat_map(|c| c.entropy.keys()).collect();
+    part_names.sort();
+    part_names.dedup();
+
+    let mut csv = fs::File::create(&conf.output).expect("Could not create output file");
+    writeln!(
+        csv,
+        "case,duplicate_of,near_duplicate_of,hamming,{}",
+        part_names.iter().map(|x| format!("entropy_{}", x)).collect::<Vec<_>>().join(",")
+    )
+    .expect("Could not write output file");
+    for (i, c) in cases.iter().enumerate() {
+        let name = |x: usize| cases[x].path.file_name().unwrap().to_string_lossy().to_string();
+        writeln!(
+            csv,
+            "{},{},{},{},{}",
+            name(i),
+            duplicate_of[i].map(name).unwrap_or_default(),
+            near_duplicate_of[i].map(|x| name(x.0)).unwrap_or_default(),
+            near_duplicate_of[i].map(|x| x.1.to_string()).unwrap_or_default(),
+            part_names
+                .iter()
+                .map(|p| c.entropy.get(*p).map(|x| format!("{:.3}", x)).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+        .expect("Could not write output file");
+    }
+
+    let num_duplicates = duplicate_of.iter().filter(|x| x.is_some()).count();
+    let num_groups = first_of_hash.values().filter(|x| duplicate_of.iter().any(|y| *y == Some(**x))).count();
+    println!("{} cases, {} distinct", cases.len(), cases.len() - num_duplicates);
+    println!("{} exact duplicates in {} groups", num_duplicates, num_groups);
+    println!(
+        "{} near-duplicates within {} bits",
+        near_duplicate_of.iter().filter(|x| x.is_some()).count(),
+        conf.near_threshold
+    );
+    println!("part,cases,min_entropy,mean_entropy,max_entropy");
+    for p in &part_names {
+        let values: Vec<f64> = cases.iter().filter_map(|c| c.entropy.get(*p).copied()).collect();
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        println!("{},{},{:.3},{:.3},{:.3}", p, values.len(), min, mean, max);
+    }
+
+    if conf.delete_duplicates {
+        for (i, c) in cases.iter().enumerate() {
+            if duplicate_of[i].is_some() {
+                match fs::remove_file(&c.path) {
+                    Ok(_) => eprintln!("Deleted {}", c.path.display()),
+                    Err(e) => eprintln!("Could not delete {}: {}", c.path.display(), e),
+                }
+            }
+        }
+    }
+}
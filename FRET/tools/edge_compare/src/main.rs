@@ -1,9 +1,123 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use clap::Arg;
 use clap::App;
 use std::{env,fs};
 
+type EdgeMap = HashMap<(u64,u64),u64>;
+
+fn load_map(path: &Path) -> Result<EdgeMap, String> {
+    let raw = fs::read(path).map_err(|e| format!("Can not read {}: {}", path.display(), e))?;
+    ron::from_str(&String::from_utf8_lossy(&raw)).map_err(|e| format!("Can not parse {}: {}", path.display(), e))
+}
+
+/// Edges of two maps, split by presence and hit count
+struct Comparison {
+    a_sans_b: Vec<((u64,u64),u64)>,
+    a_and_b: Vec<((u64,u64),u64)>,
+    a_and_b_differ: Vec<((u64,u64),(u64,u64))>,
+    b_sans_a: Vec<((u64,u64),u64)>,
+}
+
+impl Comparison {
+    fn new(hmap_a: &EdgeMap, hmap_b: &EdgeMap) -> Self {
+        let mut c = Comparison { a_sans_b: Vec::new(), a_and_b: Vec::new(), a_and_b_differ: Vec::new(), b_sans_a: Vec::new() };
+        for (edge, hits) in hmap_a {
+            match hmap_b.get(edge) {
+                None => c.a_sans_b.push((*edge, *hits)),
+                Some(x) => if hits == x {
+                    c.a_and_b.push((*edge, *hits));
+                } else {
+                    c.a_and_b_differ.push((*edge, (*hits, *x)));
+                }
+            }
+        }
+        c.b_sans_a = hmap_b.iter().filter(|x| !hmap_a.contains_key(x.0)).map(|(e, h)| (*e, *h)).collect();
+        c.a_sans_b.sort();
+        c.a_and_b.sort();
+        c.a_and_b_differ.sort();
+        c.b_sans_a.sort();
+        c
+    }
+
+    fn union(&self) -> usize {
+        self.a_and_b.len() + self.a_and_b_differ.len() + self.a_sans_b.len() + self.b_sans_a.len()
+    }
+
+    /// Share of edges present in both maps, regardless of their hit counts
+    fn jaccard(&self) -> f64 {
+        if self.union() == 0 {
+            return 1.0;
+        }
+        (self.a_and_b.len() + self.a_and_b_differ.len()) as f64 / self.union() as f64
+    }
+
+    fn print(&self, name_a: &str, name_b: &str, verbose: bool) {
+        println!("{} vs {}", name_a, name_b);
+        if verbose {
+            println!("a_sans_b: {:#?}\na_and_b_differ: {:#?}\nb_sans_a: {:#?}", &self.a_sans_b, &self.a_and_b_differ, &self.b_sans_a);
+        }
+        println!("Stats: a\\b: {} a&=b: {} a&!=b: {} b\\a: {} avb: {} jaccarde: {}",
+            self.a_sans_b.len(), self.a_and_b.len(), self.a_and_b_differ.len(), self.b_sans_a.len(), self.union(), self.jaccard());
+    }
+}
+
+const PAIR_COLUMNS: &str = "a,b,a_sans_b,a_and_b,a_and_b_differ,b_sans_a,union,jaccard";
+
+fn pair_csv(rows: &[(String, String, Comparison)]) -> String {
+    let mut out = format!("{}\n", PAIR_COLUMNS);
+    for (a, b, c) in rows {
+        out.push_str(&format!("{},{},{},{},{},{},{},{}\n", a, b, c.a_sans_b.len(), c.a_and_b.len(), c.a_and_b_differ.len(), c.b_sans_a.len(), c.union(), c.jaccard()));
+    }
+    out
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn pair_json(rows: &[(String, String, Comparison)]) -> String {
+    let objects: Vec<String> = rows.iter().map(|(a, b, c)| {
+        format!("{{\"a\":{},\"b\":{},\"a_sans_b\":{},\"a_and_b\":{},\"a_and_b_differ\":{},\"b_sans_a\":{},\"union\":{},\"jaccard\":{}}}",
+            json_string(a), json_string(b), c.a_sans_b.len(), c.a_and_b.len(), c.a_and_b_differ.len(), c.b_sans_a.len(), c.union(), c.jaccard())
+    }).collect();
+    format!("[{}]\n", objects.join(","))
+}
+
+fn matrix_csv(names: &[String], matrix: &[Vec<f64>]) -> String {
+    let mut out = format!(",{}\n", names.join(","));
+    for (name, row) in names.iter().zip(matrix) {
+        out.push_str(&format!("{},{}\n", name, row.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(",")));
+    }
+    out
+}
+
+fn matrix_json(names: &[String], matrix: &[Vec<f64>]) -> String {
+    let names: Vec<String> = names.iter().map(|x| json_string(x)).collect();
+    let rows: Vec<String> = matrix.iter().map(|r| format!("[{}]", r.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(","))).collect();
+    format!("{{\"names\":[{}],\"jaccard\":[{}]}}\n", names.join(","), rows.join(","))
+}
+
+/// Json if the output file ends in `.json`, csv otherwise
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|x| x == "json")
+}
+
+fn write_output(path: &Path, content: String) {
+    fs::write(path, content).expect("Can not write output");
+    println!("Wrote {}", path.display());
+}
+
+/// Files of a directory by name, subdirectories are ignored
+fn files_by_name(dir: &Path) -> HashMap<String, PathBuf> {
+    fs::read_dir(dir).expect("Can not read directory")
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .filter_map(|p| p.file_name().map(|n| (n.to_string_lossy().to_string(), p.clone())))
+        .collect()
+}
+
 fn main() {
     let res = match App::new("edge_compare")
         .version("0.1.0")
@@ -13,22 +127,53 @@ fn main() {
             Arg::new("a")
                 .short('a')
                 .long("map-a")
-                .required(true)
-                .takes_value(true),
+                .takes_value(true)
+                .requires("b"),
         )
         .arg(
             Arg::new("b")
                 .short('b')
                 .long("map-b")
-                .required(true)
-                .takes_value(true),
+                .takes_value(true)
+                .requires("a"),
+        )
+        .arg(
+            Arg::new("dir-a")
+                .long("dir-a")
+                .takes_value(true)
+                .requires("dir-b")
+                .help("Compare the maps of two directories, matching files by name"),
+        )
+        .arg(
+            Arg::new("dir-b")
+                .long("dir-b")
+                .takes_value(true)
+                .requires("dir-a"),
+        )
+        .arg(
+            Arg::new("maps")
+                .multiple_values(true)
+                .help("Compute the pairwise jaccard similarity of all given maps"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .takes_value(true)
+                .help("Write the results to a .csv or .json file"),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Print the differing edges, not only their counts"),
         )
         .try_get_matches_from(env::args())
     {
         Ok(res) => res,
         Err(err) => {
             println!(
-                "Syntax: {}, --map-a <input> --map-b <input>\n{:?}",
+                "Syntax: {}, --map-a <input> --map-b <input> | --dir-a <dir> --dir-b <dir> | <input>... [--output <file>]\n{:?}",
                 env::current_exe()
                     .unwrap_or_else(|_| "fuzzer".into())
                     .to_string_lossy(),
@@ -38,34 +183,68 @@ fn main() {
         }
     };
 
-    let path_a = PathBuf::from(res.value_of("a").unwrap().to_string());
-    let path_b = PathBuf::from(res.value_of("b").unwrap().to_string());
+    let output = res.value_of("output").map(PathBuf::from);
+    let verbose = res.is_present("verbose");
 
-    let raw_a = fs::read(path_a).expect("Can not read dumped edges a");
-    let hmap_a : HashMap<(u64,u64),u64> = ron::from_str(&String::from_utf8_lossy(&raw_a)).expect("Can not parse HashMap");
-
-    let raw_b = fs::read(path_b).expect("Can not read dumped edges b");
-    let hmap_b : HashMap<(u64,u64),u64> = ron::from_str(&String::from_utf8_lossy(&raw_b)).expect("Can not parse HashMap");
-
-    let mut a_and_b = Vec::<((u64,u64),u64)>::new();
-    let mut a_and_b_differ = Vec::<((u64,u64),(u64,u64))>::new();
-    let mut a_sans_b = Vec::<((u64,u64),u64)>::new();
+    if let Some(maps) = res.values_of("maps") {
+        // Pairwise matrix over a list of maps
+        let mut names = Vec::new();
+        let mut loaded = Vec::new();
+        for p in maps.map(PathBuf::from) {
+            match load_map(&p) {
+                Ok(m) => {
+                    names.push(p.display().to_string());
+                    loaded.push(m);
+                }
+                Err(e) => eprintln!("Skipping: {}", e),
+            }
+        }
+        let matrix: Vec<Vec<f64>> = loaded.iter()
+            .map(|a| loaded.iter().map(|b| Comparison::new(a, b).jaccard()).collect())
+            .collect();
+        match output {
+            Some(o) if is_json(&o) => write_output(&o, matrix_json(&names, &matrix)),
+            Some(o) => write_output(&o, matrix_csv(&names, &matrix)),
+            None => print!("{}", matrix_csv(&names, &matrix)),
+        }
+        return;
+    }
 
-    for i_a in hmap_a.clone() {
-        match hmap_b.get(&i_a.0) {
-            None => a_sans_b.push(i_a),
-            Some(x) => if i_a.1 == *x {
-                a_and_b.push(i_a);
-            } else {
-                a_and_b_differ.push((i_a.0,(i_a.1,*x)));
+    let mut rows = Vec::new();
+    if let (Some(dir_a), Some(dir_b)) = (res.value_of("dir-a"), res.value_of("dir-b")) {
+        let files_a = files_by_name(Path::new(dir_a));
+        let files_b = files_by_name(Path::new(dir_b));
+        let mut names: Vec<&String> = files_a.keys().collect();
+        names.sort();
+        for name in names {
+            let Some(path_b) = files_b.get(name) else {
+                eprintln!("Skipping {}: not present in {}", name, dir_b);
+                continue;
+            };
+            match (load_map(&files_a[name]), load_map(path_b)) {
+                (Ok(a), Ok(b)) => rows.push((files_a[name].display().to_string(), path_b.display().to_string(), Comparison::new(&a, &b))),
+                (Err(e), _) | (_, Err(e)) => eprintln!("Skipping {}: {}", name, e),
             }
         }
+        for name in files_b.keys().filter(|x| !files_a.contains_key(*x)) {
+            eprintln!("Skipping {}: not present in {}", name, dir_a);
+        }
+    } else if let (Some(a), Some(b)) = (res.value_of("a"), res.value_of("b")) {
+        match (load_map(Path::new(a)), load_map(Path::new(b))) {
+            (Ok(hmap_a), Ok(hmap_b)) => rows.push((a.to_string(), b.to_string(), Comparison::new(&hmap_a, &hmap_b))),
+            (Err(e), _) | (_, Err(e)) => eprintln!("{}", e),
+        }
+    } else {
+        eprintln!("Give two maps, two directories or a list of maps, see --help");
+        return;
     }
-    let b_sans_a : Vec<((u64,u64),u64)> = hmap_b.into_iter().filter(|x| !hmap_a.contains_key(&x.0) ).collect();
 
-    println!("a_sans_b: {:#?}\na_and_b_differ: {:#?}\nb_sans_a: {:#?}",&a_sans_b,&a_and_b_differ,&b_sans_a);
-    println!("Stats: a\\b: {} a&=b: {} a&!=b: {} b\\a: {} avb: {} jaccarde: {}",
-    a_sans_b.len(),a_and_b.len(),a_and_b_differ.len(),b_sans_a.len(),
-    a_and_b.len()+a_and_b_differ.len()+a_sans_b.len()+b_sans_a.len(),
-    (a_and_b.len()+a_and_b_differ.len())as f64/(a_and_b.len()+a_and_b_differ.len()+a_sans_b.len()+b_sans_a.len()) as f64);
+    for (a, b, c) in rows.iter() {
+        c.print(a, b, verbose);
+    }
+    match output {
+        Some(o) if is_json(&o) => write_output(&o, pair_json(&rows)),
+        Some(o) => write_output(&o, pair_csv(&rows)),
+        None => (),
+    }
 }
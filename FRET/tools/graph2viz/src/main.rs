@@ -1,22 +1,212 @@
 use std::path::PathBuf;
-use std::{env,fs};
-use fret::systemstate::{stg::STGFeedbackState,stg::STGEdge,target_os::freertos::FreeRTOSSystem};
+use clap::{Parser, ValueEnum};
+use fret::dump::read_dump;
+use fret::systemstate::{stg::STGFeedbackState, stg::STGEdge, stg::STGNode, stg::stg_to_dot, target_os::{freertos::FreeRTOSSystem, osek::OSEKSystem, SystemState, TargetSystem, TaskControlBlock}};
+use hashbrown::{HashMap, HashSet};
+use fret::time::clock::{set_qemu_icount_shift, time_to_tick, DEFAULT_QEMU_ICOUNT_SHIFT};
+use petgraph::graph::{DiGraph, EdgeIndex, NodeIndex};
 use petgraph::Direction::{Outgoing, Incoming};
-use petgraph::dot::{Dot, Config};
+use serde::Serialize;
+use std::time::Duration;
 
-fn main() {
-    let args : Vec<String> = env::args().collect();
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum Format {
+    Dot,
+    Graphml,
+    Json,
+}
+
+#[derive(Parser)]
+struct Config {
+    /// Serialized STGFeedbackState
+    #[arg(value_name = "FILE")]
+    input: PathBuf,
+
+    /// Output format, graphml and json also work for graphs too large for Graphviz
+    #[arg(short, long, value_enum, default_value_t = Format::Dot)]
+    format: Format,
+
+    /// Contract nodes with a single predecessor and successor
+    #[arg(short, long)]
+    simplify: bool,
+
+    /// Target system which recorded the graph
+    #[arg(long, value_name = "OS", default_value = "freertos", value_parser = ["freertos", "osek"])]
+    target_os: String,
+
+    /// STG edges per testcase, as dumped by the fuzzer next to the .toprated file
+    #[arg(long, value_name = "FILE", requires = "case", conflicts_with = "simplify")]
+    paths: Option<PathBuf>,
+
+    /// Corpus id of the testcase whose path is printed and highlighted in red (requires --paths)
+    #[arg(long, value_name = "ID")]
+    case: Option<usize>,
+
+    /// Keep only the nodes in which this task is running and their neighbors
+    #[arg(long, value_name = "NAME", conflicts_with = "paths")]
+    task: Option<String>,
+
+    /// Keep the nodes up to K edges away from the ones of --task, in either direction
+    #[arg(long, value_name = "K", default_value_t = 1, requires = "task")]
+    depth: usize,
+
+    /// Drop edges whose worst time is below US microseconds, edges without a worst time count as 0
+    #[arg(long, value_name = "US", conflicts_with = "paths")]
+    min_worst: Option<u64>,
+
+    /// icount shift the graph was recorded with, to convert --min-worst
+    #[arg(long, value_name = "SHIFT", default_value_t = DEFAULT_QEMU_ICOUNT_SHIFT)]
+    icount_shift: u32,
+}
+
+/// Node of the json node-link format
+#[derive(Serialize)]
+struct JsonNode {
+    id: String,
+    task: String,
+    abb_start: u32,
+    abb_end: u32,
+    level: u8,
+    state_hash: String,
+}
+
+/// Edge of the json node-link format
+#[derive(Serialize)]
+struct JsonLink {
+    source: String,
+    target: String,
+    event: String,
+    name: String,
+    worst: Option<u64>,
+    /// Corpus id and input hash of the testcase which set `worst`
+    worst_case: Option<u64>,
+    worst_input_hash: Option<String>,
+    hit_count: u64,
+    total_time: u64,
+}
+
+#[derive(Serialize)]
+struct JsonGraph {
+    directed: bool,
+    nodes: Vec<JsonNode>,
+    links: Vec<JsonLink>,
+}
+
+/// Name of the task running in the state of `node`, empty if the state is unknown
+fn task_of<SYS: TargetSystem>(node: &STGNode<SYS>, map: &HashMap<u64, SYS::State>) -> String {
+    map.get(&node.state_hash()).map_or(String::new(), |s| s.current_task().task_name().clone())
+}
+
+/// First end address of the ABB, 0xFFFF if it has none, as in [`STGNode::_pretty_print`]
+fn abb_end<SYS: TargetSystem>(node: &STGNode<SYS>) -> u32 {
+    *node.abb().get_ends().iter().next().unwrap_or(&0xFFFF)
+}
+
+/// Edge label with the testcase which set the worst time, e.g. `#12 (3fa2..)`
+fn edge_label(e: &STGEdge) -> String {
+    match e.worst_origin() {
+        Some((_, origin)) => format!("{}\n#{} ({:016x})", e._pretty_print(), origin.id, origin.input_hash),
+        None => e._pretty_print(),
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn to_graphml<SYS: TargetSystem>(g: &DiGraph<STGNode<SYS>, STGEdge>, map: &HashMap<u64, SYS::State>) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+    for (key, domain, ty) in [("task", "node", "string"), ("abb_start", "node", "long"), ("abb_end", "node", "long"), ("level", "node", "int"), ("state_hash", "node", "string"),
+                              ("event", "edge", "string"), ("name", "edge", "string"), ("worst", "edge", "long"),
+                              ("worst_case", "edge", "long"), ("worst_input_hash", "edge", "string"),
+                              ("hit_count", "edge", "long"), ("total_time", "edge", "long")] {
+        out.push_str(&format!("  <key id=\"{0}\" for=\"{1}\" attr.name=\"{0}\" attr.type=\"{2}\"/>\n", key, domain, ty));
+    }
+    out.push_str("  <graph edgedefault=\"directed\">\n");
+    for i in g.node_indices() {
+        let n = &g[i];
+        out.push_str(&format!("    <node id=\"{}\">\n", n.stable_id()));
+        out.push_str(&format!("      <data key=\"task\">{}</data>\n", xml_escape(&task_of(n, map))));
+        out.push_str(&format!("      <data key=\"abb_start\">{}</data>\n", n.abb().get_start()));
+        out.push_str(&format!("      <data key=\"abb_end\">{}</data>\n", abb_end(n)));
+        out.push_str(&format!("      <data key=\"level\">{}</data>\n", n.abb().get_level()));
+        out.push_str(&format!("      <data key=\"state_hash\">{:016x}</data>\n", n.state_hash()));
+        out.push_str("    </node>\n");
+    }
+    for e in g.edge_indices() {
+        let (a, b) = g.edge_endpoints(e).unwrap();
+        let w = &g[e];
+        out.push_str(&format!("    <edge source=\"{}\" target=\"{}\">\n", g[a].stable_id(), g[b].stable_id()));
+        out.push_str(&format!("      <data key=\"event\">{:?}</data>\n", w.event));
+        out.push_str(&format!("      <data key=\"name\">{}</data>\n", xml_escape(&w.name)));
+        if let Some((worst, _)) = &w.worst {
+            out.push_str(&format!("      <data key=\"worst\">{}</data>\n", worst));
+        }
+        if let Some((_, origin)) = w.worst_origin() {
+            out.push_str(&format!("      <data key=\"worst_case\">{}</data>\n", origin.id));
+            out.push_str(&format!("      <data key=\"worst_input_hash\">{:016x}</data>\n", origin.input_hash));
+        }
+        out.push_str(&format!("      <data key=\"hit_count\">{}</data>\n", w.hit_count));
+        out.push_str(&format!("      <data key=\"total_time\">{}</data>\n", w.total_time));
+        out.push_str("    </edge>\n");
+    }
+    out.push_str("  </graph>\n</graphml>\n");
+    out
+}
 
-    let path_a = PathBuf::from(args[1].clone());
-    let raw_a = fs::read(path_a).expect("Can not read dumped traces b");
-    // let path_b = PathBuf::from(args[2].clone());
+fn to_json<SYS: TargetSystem>(g: &DiGraph<STGNode<SYS>, STGEdge>, map: &HashMap<u64, SYS::State>) -> String {
+    let nodes = g.node_indices().map(|i| {
+        let n = &g[i];
+        JsonNode {
+            id: n.stable_id(),
+            task: task_of(n, map),
+            abb_start: n.abb().get_start(),
+            abb_end: abb_end(n),
+            level: n.abb().get_level(),
+            state_hash: format!("{:016x}", n.state_hash()),
+        }
+    }).collect();
+    let links = g.edge_indices().map(|e| {
+        let (a, b) = g.edge_endpoints(e).unwrap();
+        let w = &g[e];
+        JsonLink {
+            source: g[a].stable_id(),
+            target: g[b].stable_id(),
+            event: format!("{:?}", w.event),
+            name: w.name.to_string(),
+            worst: w.worst.as_ref().map(|x| x.0),
+            worst_case: w.worst_origin().map(|x| x.1.id),
+            worst_input_hash: w.worst_origin().map(|x| format!("{:016x}", x.1.input_hash)),
+            hit_count: w.hit_count,
+            total_time: w.total_time,
+        }
+    }).collect();
+    serde_json::to_string(&JsonGraph { directed: true, nodes, links }).expect("Can not serialize graph")
+}
 
-    let feedbackstate : STGFeedbackState<FreeRTOSSystem> = ron::from_str(&String::from_utf8_lossy(&raw_a)).expect("Can not parse HashMap");
+/// Keeps the nodes running `task` and the ones up to `depth` edges away from them, and the edges whose worst time is at least `min_worst` ticks
+fn filter<SYS: TargetSystem>(g: &DiGraph<STGNode<SYS>, STGEdge>, map: &HashMap<u64, SYS::State>, task: Option<&str>, depth: usize, min_worst: Option<u64>) -> DiGraph<STGNode<SYS>, STGEdge> {
+    let keep: Option<HashSet<NodeIndex>> = task.map(|task| {
+        let mut keep: HashSet<NodeIndex> = g.node_indices().filter(|i| task_of(&g[*i], map) == task).collect();
+        if keep.is_empty() {
+            eprintln!("No node runs task {}", task);
+        }
+        let mut frontier: Vec<NodeIndex> = keep.iter().copied().collect();
+        for _ in 0..depth {
+            frontier = frontier.iter().flat_map(|i| g.neighbors_undirected(*i)).filter(|n| keep.insert(*n)).collect();
+        }
+        keep
+    });
+    g.filter_map(
+        |i, n| keep.as_ref().map_or(true, |k| k.contains(&i)).then(|| n.clone()),
+        |_, e| min_worst.map_or(true, |m| e.worst.as_ref().map_or(0, |x| x.0) >= m).then(|| e.clone()),
+    )
+}
 
+/// Contracts straight-line nodes and prints statistics about the remaining branches
+fn simplify<SYS: TargetSystem>(g: &mut DiGraph<STGNode<SYS>, STGEdge>) {
     let mut splits = 0;
     let mut unites = 0;
-    let mut g = feedbackstate.graph;
-    dbg!(g.node_count());
     let mut straight = 0;
     let mut stub = 0;
     let mut done = false;
@@ -56,16 +246,85 @@ fn main() {
     dbg!(unites);
     dbg!(straight);
     dbg!(stub);
+}
+
+/// Edges of the testcase `case` in a .paths dump
+fn load_path(path: &PathBuf, case: usize) -> Vec<EdgeIndex> {
+    let raw = read_dump(path).expect("Can not read dumped paths");
+    let paths : HashMap<usize, Vec<usize>> = ron::from_str(&String::from_utf8_lossy(&raw)).expect("Can not parse paths");
+    let Some(edges) = paths.get(&case) else {
+        panic!("Testcase {} not found, dumped are {:?}", case, paths.keys().collect::<Vec<_>>());
+    };
+    edges.iter().map(|x| EdgeIndex::new(*x)).collect()
+}
+
+/// Prints the ABBs along a path with the worst times of the edges leaving them
+fn print_path<SYS: TargetSystem>(feedbackstate: &STGFeedbackState<SYS>, edges: &[EdgeIndex]) {
+    let path = feedbackstate.reconstruct_path(edges);
+    eprintln!("{} of {} edges found in the graph", path.len(), edges.len());
+    for (i, (node, edge)) in path.iter().enumerate() {
+        eprintln!("{:>4} {:<24} l{} {:x}-{:x} {:<32} worst {} from {}",
+            i, task_of(node, &feedbackstate.systemstate_index), node.abb().get_level(), node.abb().get_start(), abb_end(node),
+            edge.label(), edge.worst.as_ref().map_or("-".to_string(), |x| x.0.to_string()),
+            edge.worst_origin().map_or("-".to_string(), |x| format!("#{}", x.1.id)));
+    }
+}
+
+fn export<SYS: TargetSystem>(raw: &str, conf: &Config) -> String {
+    let feedbackstate : STGFeedbackState<SYS> = ron::from_str(raw).expect("Can not parse HashMap");
+    if let Err(e) = feedbackstate.check_hash_version() {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let path = match (&conf.paths, conf.case) {
+        (Some(p), Some(case)) => load_path(p, case),
+        _ => Vec::new(),
+    };
+    if !path.is_empty() {
+        print_path(&feedbackstate, &path);
+    }
+    let map = feedbackstate.systemstate_index;
+    let mut g = feedbackstate.graph;
+    dbg!(g.node_count());
+    let original = (g.node_count(), g.edge_count());
+    if conf.task.is_some() || conf.min_worst.is_some() {
+        let min_worst = conf.min_worst.map(|us| time_to_tick(Duration::from_micros(us)));
+        g = filter(&g, &map, conf.task.as_deref(), conf.depth, min_worst);
+    }
+    if conf.simplify {
+        simplify(&mut g);
+    }
+    eprintln!("Kept {} of {} nodes and {} of {} edges", g.node_count(), original.0, g.edge_count(), original.1);
+    // the labels only get references into the graph, so the path is recognized by address
+    let path_edges : HashSet<*const STGEdge> = path.iter().filter_map(|e| g.edge_weight(*e)).map(|x| x as *const _).collect();
+    let path_nodes : HashSet<*const STGNode<SYS>> = path.iter().filter_map(|e| g.edge_endpoints(*e))
+        .flat_map(|(a, b)| [a, b]).map(|x| &g[x] as *const _).collect();
+    const HIGHLIGHT: &str = "\", color=\"red\", penwidth=\"3";
+    match conf.format {
+        // node ids match the ones in the dumps written by the fuzzer
+        Format::Dot => stg_to_dot(
+            &g,
+            |n| if path_nodes.contains(&(n as *const _)) {n._pretty_print(&map) + HIGHLIGHT} else {n._pretty_print(&map)},
+            // |n| format!("{} {:?}",n.get_taskname(),n.get_input_counts().iter().min().unwrap_or(&0)),
+            |e| if path_edges.contains(&(e as *const _)) {edge_label(e) + HIGHLIGHT} else {edge_label(e)},
+            (0, 0),
+        ),
+        Format::Graphml => to_graphml(&g, &map),
+        Format::Json => to_json(&g, &map),
+    }
+}
+
+fn main() {
+    let conf = Config::parse();
+    set_qemu_icount_shift(conf.icount_shift);
+
+    let raw = read_dump(&conf.input).expect("Can not read dumped graph");
+    let raw = String::from_utf8_lossy(&raw);
 
-    let newgraph = g.map(
-        |_, n| n._pretty_print(),
-        // |_, n| format!("{} {:?}",n.get_taskname(),n.get_input_counts().iter().min().unwrap_or(&0)),
-        |_, e| e,
-    );
-    // let tempg = format!("{:?}",Dot::with_config(&newgraph, &[Config::EdgeNoLabel]));
-    let f = format!("{:?}",Dot::with_config(&newgraph, &[Config::EdgeNoLabel]));
-    let f = f.replace("\\\\n", "\n");
-    let f = f.replace("\\\"", "");
+    let f = match conf.target_os.as_str() {
+        "osek" => export::<OSEKSystem>(&raw, &conf),
+        _ => export::<FreeRTOSSystem>(&raw, &conf),
+    };
     println!("{}",f);
 
 }
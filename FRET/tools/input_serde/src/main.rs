@@ -1,9 +1,9 @@
-use either::Either::{self, Left, Right};
+use either::Either::Left;
 use hashbrown::HashMap;
 use rand::rngs::StdRng;
 use std::path::PathBuf;
 use std::{env,fs};
-use fret::systemstate::{ExecInterval, RTOSJob, target_os::SystemTraceData, target_os::freertos::FreeRTOSTraceMetadata, target_os::SystemState, target_os::TaskControlBlock, helpers::interrupt_times_to_input_bytes};
+use fret::systemstate::{ExecInterval, RTOSJob, target_os::SystemTraceData, target_os::freertos::FreeRTOSTraceMetadata, target_os::SystemState, target_os::TaskControlBlock, helpers::{interrupt_times_to_input_bytes, load_input_base}, replay_script::ReplayScript, input_view::{bytes_part_or_insert, fold_input, unfold_input, input_to_schedule, interrupt_times_to_comments, interrupt_part_name, max_num_interrupt, num_interrupt_sources, read_input_file, repair_input, schedule_to_input, set_interrupt_layout_from_env, EditInput, InputFormat, BYTES_PART}};
 use libafl::inputs::multi::MultipartInput;
 use libafl::inputs::{BytesInput, Input};
 use std::io::Write;
@@ -12,11 +12,6 @@ use itertools::{assert_equal, join, Itertools};
 use rand::RngCore;
 use libafl::inputs::HasMutatorBytes;
 
-const MAX_NUM_INTERRUPT: usize = 128;
-const NUM_INTERRUPT_SOURCES: usize = 6; // Keep in sync with qemu-libafl-bridge/hw/timer/armv7m_systick.c:319 and  FreeRTOS/FreeRTOS/Demo/CORTEX_M3_MPS2_QEMU_GCC/init/startup.c:216
-pub const QEMU_ICOUNT_SHIFT: u32 = 5;
-pub const QEMU_ISNS_PER_SEC: u32 = u32::pow(10, 9) / u32::pow(2, QEMU_ICOUNT_SHIFT);
-pub const QEMU_ISNS_PER_USEC: f32 = QEMU_ISNS_PER_SEC as f32 / 1000000.0;
 
 #[derive(Parser)]
 struct Config {
@@ -28,52 +23,134 @@ struct Config {
     #[arg(short, long, value_name = "FORMAT")]
     input_format: Option<String>,
 
-    /// Output format
+    /// Output format: edit, ron, schedule, case or replay. A replay script decodes the interrupt times with the config of --kernel and --config,
+    /// or without a minimum inter-arrival time for the --sources
     #[arg(short, long, value_name = "FORMAT", default_value = "edit")]
     format: String,
+
+    /// icount shift the input was fuzzed with
+    #[arg(long, value_name = "SHIFT", default_value_t = fret::time::clock::DEFAULT_QEMU_ICOUNT_SHIFT)]
+    icount_shift: u32,
+
+    /// Fit the input to the current target: truncate it to --max-size, keep only the interrupts of --sources
+    #[arg(long)]
+    repair: bool,
+
+    /// Maximum size of the program input, used by --repair
+    #[arg(long, value_name = "BYTES", default_value_t = 1024)]
+    max_size: usize,
+
+    /// Comma separated list of the configured interrupt sources, used by --repair
+    #[arg(long, value_name = "SOURCES", value_delimiter = ',')]
+    sources: Vec<usize>,
+
+    /// Fuzzer config of the target, MAX_NUM_INTERRUPT and NUM_INTERRUPT_SOURCES are taken from it
+    #[arg(long, value_name = "FILE", requires = "kernel")]
+    config: Option<PathBuf>,
+
+    /// Kernel the fuzzer config belongs to
+    #[arg(short, long, value_name = "FILE")]
+    kernel: Option<PathBuf>,
+
+    /// Trace of the same input, labels the regions of the program input with the tasks reading them. FUZZ_INPUT is resolved in --kernel
+    #[arg(long, value_name = "FILE", requires = "kernel")]
+    annotate_with_trace: Option<PathBuf>,
+
+    /// Set the byte a task reads at a relative offset, counted in the order of its reads: job=TaskA,offset=4,value=0xff (requires --annotate-with-trace)
+    #[arg(long, value_name = "PATCH", value_parser = parse_patch, requires = "annotate_with_trace")]
+    patch: Vec<Patch>,
 }
 
-/// Setup the interrupt inputs. Noop if interrupts are not fuzzed
-fn setup_interrupt_inputs(mut input : MultipartInput<BytesInput>) -> MultipartInput<BytesInput> {
-    for i in 0..MAX_NUM_INTERRUPT {
-        let name = format!("isr_{}_times",i);
-        if input.parts_by_name(&name).next().is_none() {
-            input.add_part(name, BytesInput::new([0; MAX_NUM_INTERRUPT*4].to_vec()));
+/// A byte to set, see `--patch`
+#[derive(Clone, Debug)]
+struct Patch {
+    job: String,
+    offset: usize,
+    value: u8,
+}
+
+fn parse_patch(s: &str) -> Result<Patch, String> {
+    let (mut job, mut offset, mut value) = (None, None, None);
+    for field in s.split(',') {
+        match field.split_once('=') {
+            Some(("job", x)) => job = Some(x.to_string()),
+            Some(("offset", x)) => offset = Some(x.parse::<usize>().map_err(|e| format!("offset {}: {}", x, e))?),
+            Some(("value", x)) => value = Some(match x.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => x.parse::<u8>(),
+            }.map_err(|e| format!("value {}: {}", x, e))?),
+            _ => return Err(format!("Unknown field {}, expected job=NAME,offset=N,value=BYTE", field)),
         }
     }
-    input
+    match (job, offset, value) {
+        (Some(job), Some(offset), Some(value)) => Ok(Patch { job, offset, value }),
+        _ => Err("A patch needs job, offset and value".to_string()),
+    }
 }
 
-fn unfold_input(input : &MultipartInput<BytesInput>) -> HashMap<String,Either<Vec<u8>,Vec<u32>>> {
-    let mut res = HashMap::new();
-    for (name, part) in input.iter() {
-        if name == "bytes" {
-            res.insert(name.to_string(),Left(part.bytes().to_vec()));
-        } else {
-            // let times = unsafe{std::mem::transmute::<&[u8], &[u32]>(&part.bytes()[0..4*(part.bytes().len()/4)])}.to_vec();
-            eprintln!("name {} len {}", name, part.bytes().len());
-            let mut times = part.bytes().chunks(4).filter(|x| x.len()==4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect::<Vec<_>>();
-            times.sort_unstable();
-            res.insert(name.to_string(),Right(times));
+/// Offsets into the program input read by each task, in the order of their first read
+fn reads_per_task<T: SystemTraceData>(trace: &T, input_base: u32, input_size: usize) -> HashMap<String, Vec<u32>> {
+    let mut ret: HashMap<String, Vec<u32>> = HashMap::new();
+    for (interval, reads) in trace.intervals().iter().zip(trace.mem_reads().iter()) {
+        let offsets = ret.entry(interval.get_task_name_unchecked().to_string()).or_default();
+        for (addr, _) in reads {
+            if let Some(offset) = addr.checked_sub(input_base).filter(|x| (*x as usize) < input_size) {
+                if !offsets.contains(&offset) {
+                    offsets.push(offset);
+                }
+            }
         }
     }
-    res
+    ret.retain(|_, v| !v.is_empty());
+    ret
 }
 
-fn fold_input(input : HashMap<String,Either<Vec<u8>,Vec<u32>>>) -> MultipartInput<BytesInput> {
-    let mut res = MultipartInput::new();
-    for (name, data) in input {
-        match data {
-            Left(x) => res.add_part(name, BytesInput::new(x)),
-            Right(x) => res.add_part(name, BytesInput::new(interrupt_times_to_input_bytes(&x))),
+/// Consecutive offsets of the program input read by the same tasks, as (start..end, tasks). Unread regions have no tasks.
+fn input_regions(reads: &HashMap<String, Vec<u32>>, input_size: usize) -> Vec<(std::ops::Range<usize>, Vec<String>)> {
+    let mut readers: Vec<Vec<String>> = vec![Vec::new(); input_size];
+    for (task, offsets) in reads.iter().sorted_by_key(|x| x.0) {
+        for o in offsets {
+            readers[*o as usize].push(task.clone());
+        }
+    }
+    let mut ret: Vec<(std::ops::Range<usize>, Vec<String>)> = Vec::new();
+    for (offset, tasks) in readers.into_iter().enumerate() {
+        match ret.last_mut() {
+            Some((range, last)) if *last == tasks => range.end = offset + 1,
+            _ => ret.push((offset..offset + 1, tasks)),
         }
     }
-    res
+    ret
 }
 
+/// One line per region, as RON comments
+fn regions_to_comments(regions: &[(std::ops::Range<usize>, Vec<String>)]) -> String {
+    let mut out = format!("// Offsets of \"{}\" read by each task\n", BYTES_PART);
+    for (range, tasks) in regions {
+        out.push_str(&format!("// {:>5}..{:<5} {}\n", range.start, range.end, if tasks.is_empty() { "-".to_string() } else { tasks.join(", ") }));
+    }
+    out
+}
+
+/// Setup the interrupt inputs. Noop if interrupts are not fuzzed
+fn setup_interrupt_inputs(mut input : MultipartInput<BytesInput>) -> MultipartInput<BytesInput> {
+    bytes_part_or_insert(&mut input);
+    for i in 0..num_interrupt_sources() {
+        let name = interrupt_part_name(i);
+        if input.parts_by_name(&name).next().is_none() {
+            input.add_part(name, BytesInput::new(vec![0; max_num_interrupt()*4]));
+        }
+    }
+    input
+}
 
 fn main() {
     let conf = Config::parse();
+    fret::time::clock::set_qemu_icount_shift(conf.icount_shift);
+    if let (Some(config), Some(kernel)) = (&conf.config, &conf.kernel) {
+        fret::cli::set_env_from_config(kernel, config);
+    }
+    set_interrupt_layout_from_env();
     let show_input = match conf.input_format {
         Some(x) => {
             match x.as_str() {
@@ -85,7 +162,7 @@ fn main() {
                     let bytes = fs::read(conf.case).expect("Can not read input file");
                     let input_str = String::from_utf8_lossy(&bytes);
                     eprintln!("Interpreting input file as custom edit input");
-                    fold_input(ron::from_str::<HashMap<String,Either<Vec<u8>,Vec<u32>>>>(&input_str).expect("Failed to parse input"))
+                    fold_input(ron::from_str::<EditInput>(&input_str).expect("Failed to parse input")).expect("Invalid edit input")
                 },
                 "ron" => {
                     let bytes = fs::read(conf.case).expect("Can not read input file");
@@ -93,57 +170,125 @@ fn main() {
                     eprintln!("Interpreting input file as raw ron input");
                     ron::from_str::<MultipartInput<BytesInput>>(&input_str).expect("Failed to parse input")
                 },
+                "replay" => {
+                    let bytes = fs::read(conf.case).expect("Can not read input file");
+                    eprintln!("Interpreting input file as replay script");
+                    ReplayScript::from_ron(&String::from_utf8_lossy(&bytes)).expect("Failed to parse input").to_input()
+                },
+                "schedule" => {
+                    let bytes = fs::read(conf.case).expect("Can not read input file");
+                    eprintln!("Interpreting input file as schedule");
+                    schedule_to_input(&String::from_utf8_lossy(&bytes)).expect("Failed to parse input")
+                },
                 "raw" => {
                     let bytes = fs::read(conf.case).expect("Can not read input file");
-                    setup_interrupt_inputs(MultipartInput::from([("bytes",BytesInput::new(bytes))]))
+                    setup_interrupt_inputs(MultipartInput::from([(BYTES_PART,BytesInput::new(bytes))]))
                 },
                 x => panic!("Unknown input format: {}", x),
             }
         }
-        Option::None => match MultipartInput::from_file(conf.case.as_os_str()) {
-            Ok(x) => {
+        Option::None => match read_input_file(&conf.case).expect("Can not read input file") {
+            (x, InputFormat::Case) => {
                 eprintln!("Interpreting input file as multipart input");
                 x
             },
-            Err(_) => {
-                let bytes = fs::read(conf.case).expect("Can not read input file");
-                let input_str = String::from_utf8_lossy(&bytes);
-                match ron::from_str::<HashMap<String,Either<Vec<u8>,Vec<u32>>>>(&input_str) {
-                    Ok(x) => {
-                        eprintln!("Interpreting input file as custom edit input");
-                        fold_input(x)
-                    },
-                    Err(_) => {
-                        match ron::from_str::<MultipartInput<BytesInput>>(&input_str) {
-                            Ok(x) => {
-                                eprintln!("Interpreting input file as raw ron input");
-                                x
-                            },
-                            Err(_) => {
-                                eprintln!("Interpreting input file as raw input");
-                                setup_interrupt_inputs(MultipartInput::from([("bytes",BytesInput::new(bytes))]))
-                            }
-                        }
-                    }
-                }
-            }
+            (x, InputFormat::Replay) => {
+                eprintln!("Interpreting input file as replay script");
+                x
+            },
+            (x, InputFormat::Edit) => {
+                eprintln!("Interpreting input file as custom edit input");
+                x
+            },
+            (x, InputFormat::Ron) => {
+                eprintln!("Interpreting input file as raw ron input");
+                x
+            },
+            (x, InputFormat::Schedule) => {
+                eprintln!("Interpreting input file as schedule");
+                x
+            },
+            (x, InputFormat::Raw) => {
+                eprintln!("Interpreting input file as raw input");
+                setup_interrupt_inputs(x)
+            },
+        }
+    };
+    let show_input = if conf.repair {
+        let (repaired, repairs) = repair_input(&show_input, conf.max_size, &conf.sources).expect("Can not repair input");
+        if repairs.is_empty() {
+            eprintln!("Nothing to repair");
+        } else {
+            eprintln!("Repaired: {}", repairs);
+        }
+        repaired
+    } else {
+        show_input
+    };
+    let annotation = conf.annotate_with_trace.as_ref().map(|path| {
+        let raw = fret::dump::read_dump(path).expect("Can not read dumped trace");
+        let trace = ron::from_str::<FreeRTOSTraceMetadata>(&String::from_utf8_lossy(&raw)).expect("Can not parse trace");
+        let input_base = load_input_base(conf.kernel.as_ref().unwrap()).expect("Can not resolve FUZZ_INPUT");
+        let input_size = show_input.parts_by_name(BYTES_PART).next().map_or(0, |x| x.1.bytes().len());
+        reads_per_task(&trace, input_base, input_size)
+    });
+    let show_input = if conf.patch.is_empty() {
+        show_input
+    } else {
+        let reads = annotation.as_ref().unwrap();
+        let mut edit = unfold_input(&show_input);
+        let Some(Left(bytes)) = edit.get_mut(BYTES_PART) else {
+            panic!("Input has no program input to patch");
+        };
+        for p in &conf.patch {
+            let Some(offsets) = reads.get(&p.job) else {
+                panic!("Task {} reads no input, tasks reading input are {:?}", p.job, reads.keys().sorted().collect::<Vec<_>>());
+            };
+            let Some(offset) = offsets.get(p.offset) else {
+                panic!("Task {} only reads {} bytes of the input", p.job, offsets.len());
+            };
+            eprintln!("Patching offset {} read by {}: {:#x} -> {:#x}", offset, p.job, bytes[*offset as usize], p.value);
+            bytes[*offset as usize] = p.value;
         }
+        fold_input(edit).expect("Invalid edit input")
     };
+    let regions = annotation.map(|reads| input_regions(&reads, show_input.parts_by_name(BYTES_PART).next().map_or(0, |x| x.1.bytes().len())));
+    if let Some(regions) = regions.as_ref().filter(|_| conf.format != "edit") {
+        eprint!("{}", regions_to_comments(regions));
+    }
     // let uf = unfold_input(&show_input);
     // println!("{:?}", show_input);
     match conf.format.as_str() {
         "edit" => {
-            let output = ron::to_string(&unfold_input(&show_input)).expect("Could not serialize input");
+            let edit = unfold_input(&show_input);
+            let output = ron::to_string(&edit).expect("Could not serialize input");
             println!("{}", output);
+            // interrupt times as ticks above, in microseconds below. Edit input accepts both, e.g. [1000, "250.5us"]
+            print!("{}", interrupt_times_to_comments(&edit));
+            // comments keep the annotated input readable as edit input
+            if let Some(regions) = &regions {
+                print!("{}", regions_to_comments(regions));
+            }
         },
         "ron" => {
             let output = ron::to_string(&show_input).expect("Could not serialize input");
             println!("{}", output);
         },
+        "schedule" => {
+            print!("{}", input_to_schedule(&show_input));
+        },
         "case" => {
             let output = postcard::to_allocvec(&show_input).expect("Could not serialize input");
             std::io::stdout().write_all(&output).expect("Could not write output");
         },
+        "replay" => {
+            let config = match (&conf.config, &conf.kernel) {
+                (Some(config), Some(kernel)) => fret::cli::get_interrupt_config(kernel, config),
+                _ => conf.sources.iter().map(|x| (*x, 0)).collect(),
+            };
+            let script = ReplayScript::from_input(&show_input, &config).expect("Can not build replay script");
+            print!("{}", script.to_ron());
+        },
         _ => panic!("Unknown format")
     }
 }
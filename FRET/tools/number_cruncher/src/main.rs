@@ -1,18 +1,15 @@
-use clap::parser::ValueSource;
 use clap::Parser;
-use itertools::Group;
+use flate2::read::MultiGzDecoder;
 use itertools::Itertools;
-use rayon::iter::ParallelBridge;
 use rayon::prelude::*;
-use rayon::result;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
 use std::io::{self, BufRead, BufReader};
 use std::path::Path;
 use std::path::PathBuf;
-use rusqlite::{params, Connection, Result};
-use std::collections::HashMap;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 #[derive(clap::ValueEnum, Clone, PartialEq)]
 enum Endpoint {
@@ -35,40 +32,160 @@ struct Config {
     /// End each group after the first termination
     #[arg(short, long, default_value = "max")]
     end_early: Endpoint,
+
+    /// Only aggregate runs with a `.manifest.ron` next to their `.time` file, and skip cases whose manifests disagree
+    #[arg(long)]
+    require_manifest: bool,
+
+    /// Only write the combos and summary tables, not the sampled watermarks of each combo
+    #[arg(long)]
+    summary_only: bool,
+
+    /// Also read the `case#number.rt_hist` response time dumps into the rt_hist table
+    #[arg(long)]
+    rt_hist: bool,
+}
+
+/// One row of the summary table, see [`summarize`]
+struct Summary {
+    runs: usize,
+    /// Last sampled timestamp all runs reach, respecting --end-early
+    end_timestamp: Option<usize>,
+    median: Option<f64>,
+    mean: Option<f64>,
+    max: Option<usize>,
+    /// Median over the improving runs of the first timestamp at which the watermark exceeds its initial value
+    first_improvement: Option<f64>,
+    /// Area under the median watermark over the sampled timestamps, by the trapezoidal rule
+    auc: Option<f64>,
+}
+
+/// Summarize the sampled watermarks of the runs of one tool, as (timestamp, min, max, median, mean, sdiv) rows,
+/// and the samples of each run. Only the first `rows.len()` samples of each run are considered.
+fn summarize(rows: &[(usize, usize, usize, f64, f64, f64)], runs: &[&[(usize, usize)]]) -> Summary {
+    let last = rows.last();
+    let first_improvements: Vec<usize> = runs
+        .iter()
+        .filter_map(|p| {
+            let p = &p[..rows.len()];
+            let initial = p.first()?.0;
+            p.iter().find(|x| x.0 > initial).map(|x| x.1)
+        })
+        .collect();
+    let auc = rows
+        .windows(2)
+        .map(|w| (w[1].0 - w[0].0) as f64 * (w[0].3 + w[1].3) / 2.0)
+        .sum::<f64>();
+    Summary {
+        runs: runs.len(),
+        end_timestamp: last.map(|x| x.0),
+        median: last.map(|x| x.3),
+        mean: last.map(|x| x.4),
+        max: last.map(|x| x.2),
+        first_improvement: median(&first_improvements),
+        auc: last.map(|_| auc),
+    }
+}
+
+/// The fields of a campaign manifest written by the fuzzer which make results incomparable if they differ
+#[derive(Deserialize, Debug, Clone)]
+struct Manifest {
+    kernel_sha256: String,
+    icount_shift: u32,
+    select_task: Option<String>,
+}
+
+/// `path` without the ".gz" suffix of compressed dumps
+fn uncompressed(path: &Path) -> PathBuf {
+    match path.extension() {
+        Some(e) if e == "gz" => path.with_extension(""),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Open a dump for reading, ".gz" files are decompressed while reading
+fn open_dump(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|e| e == "gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Read the manifest written alongside `time_file`
+fn manifest_of(time_file: &Path) -> std::result::Result<Manifest, String> {
+    let path = uncompressed(time_file).with_extension("manifest.ron");
+    let raw = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    ron::from_str(&raw).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// A manifest field compared by [`manifest_mismatch`], as (name, value of a manifest)
+type ManifestField = (&'static str, fn(&Manifest) -> String);
+
+/// List the fields in which the manifests differ, with the runs having each value. None if all agree.
+fn manifest_mismatch(manifests: &[(&Path, &Manifest)]) -> Option<String> {
+    let fields: [ManifestField; 3] = [
+        ("kernel_sha256", |m| m.kernel_sha256.clone()),
+        ("icount_shift", |m| m.icount_shift.to_string()),
+        ("select_task", |m| format!("{:?}", m.select_task)),
+    ];
+    let mut ret = String::new();
+    for (name, get) in fields {
+        let mut values: BTreeMap<String, Vec<&Path>> = BTreeMap::new();
+        for (path, m) in manifests {
+            values.entry(get(m)).or_default().push(*path);
+        }
+        if values.len() > 1 {
+            ret.push_str(&format!("  {}:\n", name));
+            for (value, paths) in values {
+                ret.push_str(&format!("    {} <- {}\n", value, paths.iter().map(|x| x.display().to_string()).join(", ")));
+            }
+        }
+    }
+    if ret.is_empty() { None } else { Some(ret) }
 }
+/// Collect all `case#number.<ext>` files below `dir`, compressed or not, as (path, fuzzer, case, number).
+/// Other `.<ext>` files are reported and added to `skipped`.
 fn visit_dirs(
     dir: &Path,
-    results: &mut Vec<(PathBuf, String, String, String)>,
+    ext: &str,
+    re: &regex::Regex,
+    results: &mut Vec<(PathBuf, String, String, usize)>,
+    skipped: &mut Vec<PathBuf>,
 ) -> std::io::Result<()> {
     if dir.is_dir() {
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
+            let name = uncompressed(&path);
             if path.is_dir() {
-                visit_dirs(&path, results)?;
-            } else if path.extension().and_then(|s| s.to_str()) == Some("time") {
-                if let Some(file_name) = path.file_name().and_then(|s| s.to_str()) {
-                    let re = regex::Regex::new(r".*#[0-9]+\.time$").unwrap();
-                    if re.is_match(file_name) {
-                        if let Some(dir_name) = path
-                            .parent()
-                            .and_then(|p| p.file_name())
-                            .and_then(|s| s.to_str())
-                        {
-                            {
-                                let mut file_stem =
-                                    path.file_stem().unwrap().to_str().unwrap().split("#");
-                                let case_name = file_stem.next().unwrap();
-                                let case_number = file_stem.next().unwrap();
-                                results.push((
-                                    path.clone(),
-                                    dir_name.to_string(),
-                                    case_name.to_string(),
-                                    case_number.to_string(),
-                                ));
+                visit_dirs(&path, ext, re, results, skipped)?;
+            } else if name.extension().and_then(|s| s.to_str()) == Some(ext) {
+                let captures = name.file_name().and_then(|s| s.to_str()).and_then(|x| re.captures(x));
+                let dir_name = path
+                    .parent()
+                    .and_then(|p| p.file_name())
+                    .and_then(|s| s.to_str());
+                match (captures, dir_name) {
+                    (Some(c), Some(dir_name)) => {
+                        match c[2].parse::<usize>() {
+                            Ok(case_number) => results.push((
+                                path.clone(),
+                                dir_name.to_string(),
+                                c[1].to_string(),
+                                case_number,
+                            )),
+                            Err(_) => {
+                                eprintln!("WARNING Case number out of range: {}", path.display());
+                                skipped.push(path.clone());
                             }
                         }
                     }
+                    _ => {
+                        eprintln!("WARNING Not a name#number.{} file: {}", ext, path.display());
+                        skipped.push(path.clone());
+                    }
                 }
             }
         }
@@ -76,70 +193,198 @@ fn visit_dirs(
     Ok(())
 }
 
-fn maxpoints_of_file(file_path: &Path) -> io::Result<Vec<(usize, usize)>> {
-    let file = File::open(file_path)?;
-    let reader = BufReader::new(file);
+/// The column layout of a `.time` file: the index of the timestamp column and the (name, index) of each value column.
+/// The first value column is the global worst case and has the empty name, so its table keeps the name of the
+/// two-column format, see [`table_name`].
+struct Columns {
+    timestamp: usize,
+    values: Vec<(String, usize)>,
+}
 
-    let mut results = Vec::new();
-    let mut watermark = 0;
-    let mut last_timestamp = 0;
+impl Columns {
+    /// The headerless (worst, timestamp) format, with `n` columns. Extra columns are named by their index.
+    fn headerless(n: usize) -> Self {
+        let values = std::iter::once((String::new(), 0))
+            .chain((2..n).map(|i| (i.to_string(), i)))
+            .collect();
+        Columns { timestamp: 1, values }
+    }
 
-    for line in reader.lines() {
-        let line = line?;
-        let mut parts = line.split(',');
+    /// Parse a header line like `# icount,timestamp,taskA,taskB`, None if it does not name a timestamp column
+    fn from_header(line: &str) -> Option<Self> {
+        let names: Vec<&str> = line.trim_start_matches('#').split(',').map(|x| x.trim()).collect();
+        let timestamp = names.iter().position(|x| *x == "timestamp")?;
+        let values = names
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != timestamp)
+            .enumerate()
+            .map(|(n, (i, name))| (if n == 0 { String::new() } else { name.to_string() }, i))
+            .collect();
+        Some(Columns { timestamp, values })
+    }
+
+    fn len(&self) -> usize {
+        self.values.len() + 1
+    }
+}
+
+/// The (watermark, timestamp) points of each named value column of a `.time` file, see [`maxpoints_of_file`]
+type ColumnPoints = Vec<(String, Vec<(usize, usize)>)>;
 
-        if let (Some(first_str), Some(second_str)) = (parts.next(), parts.next()) {
-            let first: usize = first_str.trim().parse().unwrap();
-            let second: usize = second_str.trim().parse().unwrap();
+/// Read the lines of a `.time` file and keep the points at which the worst case of each value column increased.
+/// Files without a header line are read as (worst, timestamp) lines, a header line like `timestamp,global,taskA`
+/// or `# icount,timestamp,taskA` names the columns. All value columns share the timestamp column.
+/// Malformed lines, lines with a different number of columns and lines with decreasing timestamps are skipped and counted.
+/// The points describe the watermark as a step function on [0, last timestamp]: the first point is at timestamp 0
+/// and holds the value of the first line, the values are non-decreasing and the last point is at the last timestamp.
+/// Returns the points per column, named as in [`Columns`], and the number of skipped lines, or an error if no valid line was found.
+fn maxpoints_of_file(file_path: &Path) -> io::Result<(ColumnPoints, usize)> {
+    let reader = open_dump(file_path)?;
 
-            if first > watermark {
-                results.push((first, second));
-                watermark = first;
+    let mut columns: Option<Columns> = None;
+    let mut results: Vec<Vec<(usize, usize)>> = Vec::new();
+    let mut watermarks: Vec<usize> = Vec::new();
+    let mut last_timestamp = 0;
+    let mut valid_lines = 0;
+    let mut skipped_lines = 0;
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(l) => l,
+            // truncated writes may leave invalid utf8 behind
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                skipped_lines += 1;
+                continue;
+            }
+            // a compressed dump may end in an unfinished gzip member
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        // the header may be a comment, other comments like the icount shift are skipped
+        if columns.is_none() {
+            if let Some(c) = Columns::from_header(&line) {
+                columns = Some(c);
+                continue;
             }
-            last_timestamp = second;
         }
+        if line.starts_with('#') {
+            continue;
+        }
+        let parsed: Option<Vec<usize>> = line.split(',').map(|x| x.trim().parse::<usize>().ok()).collect();
+        let parsed = match parsed {
+            Some(p) if p.len() >= 2 => p,
+            _ => {
+                skipped_lines += 1;
+                continue;
+            }
+        };
+        let columns = columns.get_or_insert_with(|| Columns::headerless(parsed.len()));
+        if parsed.len() != columns.len() || parsed[columns.timestamp] < last_timestamp {
+            skipped_lines += 1;
+            continue;
+        }
+        let timestamp = parsed[columns.timestamp];
+        if valid_lines == 0 {
+            // the first execution is the start of the run
+            for (_, i) in &columns.values {
+                results.push(vec![(parsed[*i], 0)]);
+                watermarks.push(parsed[*i]);
+            }
+        } else {
+            for (c, (_, i)) in columns.values.iter().enumerate() {
+                if parsed[*i] > watermarks[c] {
+                    results[c].push((parsed[*i], timestamp));
+                    watermarks[c] = parsed[*i];
+                }
+            }
+        }
+        last_timestamp = timestamp;
+        valid_lines += 1;
     }
-    if results.len() > 1 {
-        results[0].1 = 0;
-        results.push((results[results.len() - 1].0, last_timestamp));
+    if valid_lines == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no valid lines ({} malformed)", skipped_lines),
+        ));
     }
-    if results.len() == 0 {
-        results.push((0, 0));
-        results.push((0, last_timestamp));
+    if skipped_lines > 0 {
+        eprintln!("WARNING {}: skipped {} malformed lines", file_path.display(), skipped_lines);
     }
+    // the last watermark holds until the end of the run
+    for r in results.iter_mut() {
+        let last = r[r.len() - 1];
+        if last.1 < last_timestamp {
+            r.push((last.0, last_timestamp));
+        }
+    }
+    let names = columns.unwrap().values.into_iter().map(|(name, _)| name);
 
-    Ok(results)
+    Ok((names.zip(results).collect(), skipped_lines))
 }
 
-fn sample_maxpoints(points: &Vec<(usize, usize)>, samples: &Vec<usize>) -> Vec<(usize, usize)> {
-    let mut todo = samples.iter().peekable();
-    let mut ret = Vec::new();
-    for i in 0..points.len() {
-        if todo.peek().is_none() {
-            // Done
-            break;
-        }
-        while let Some(&&peek) = todo.peek() {
-            if peek >= points[i].1 && (i+1 >= points.len() || peek < points[i+1].1) {
-                // End or inside the interval
-                ret.push((points[i].0, peek));
-                todo.next();
-            } else if peek < points[i].1 {
-                if i == 0 {
-                    // Before the first interval, just take the first
-                    ret.push((points[i].0, peek));
-                    todo.next();
-                } else {
-                    // Already passed
-                    eprintln!("WARNING Skipped: {}", todo.next().unwrap());
-                }
-            } else {
-                // Not yet
-                break;
+/// The name of the table of the sampled watermarks of `column`, `case$tool` for the global column and `case$tool$column` otherwise
+fn table_name(case: &str, tool: &str, column: &str) -> String {
+    if column.is_empty() {
+        format!("{}${}", case, tool)
+    } else {
+        format!("{}${}${}", case, tool, column)
+    }
+}
+
+/// Count the executions per response time in the (response time, timestamp) lines of a `.rt_hist` file.
+/// Returns the histogram and the number of malformed lines, or an error if no valid line was found.
+fn rt_hist_of_file(file_path: &Path) -> io::Result<(BTreeMap<usize, usize>, usize)> {
+    let reader = open_dump(file_path)?;
+    let mut hist = BTreeMap::new();
+    let mut skipped_lines = 0;
+    for line in reader.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                skipped_lines += 1;
+                continue;
             }
+            // a compressed dump may end in an unfinished gzip member
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split(',').next().and_then(|x| x.trim().parse::<usize>().ok()) {
+            Some(rt) => *hist.entry(rt).or_insert(0) += 1,
+            None => skipped_lines += 1,
         }
     }
-    ret
+    if hist.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no valid lines ({} malformed)", skipped_lines),
+        ));
+    }
+    Ok((hist, skipped_lines))
+}
+
+/// Sample the watermark of `points`, as returned by [`maxpoints_of_file`], at each of the `samples`.
+/// Each point holds from its timestamp until the next one, so a sample takes the value of the last point at or before it.
+/// Samples after the end of the run keep the final watermark, which allows aggregating runs of different lengths.
+/// Samples before the first point, or without any points, take the value 0, as nothing was measured yet.
+/// Returns exactly one (watermark, sample) pair per sample, in the order of `samples`.
+fn sample_maxpoints(points: &[(usize, usize)], samples: &[usize]) -> Vec<(usize, usize)> {
+    samples
+        .iter()
+        .map(|&t| {
+            // points from maxpoints_of_file start at timestamp 0, other traces may start late
+            match points.partition_point(|p| p.1 <= t) {
+                0 => (0, t),
+                next => (points[next - 1].0, t),
+            }
+        })
+        .collect()
 }
 
 // https://rust-lang-nursery.github.io/rust-cookbook/science/mathematics/statistics.html
@@ -192,104 +437,412 @@ fn std_deviation(data: &[usize]) -> Option<f64> {
     }
 }
 
+/// The sampled watermarks and summary of one column of the runs of one tool on one case, see [`case_stats`]
+struct ToolStats<'a> {
+    case: &'a str,
+    tool: &'a str,
+    column: &'a str,
+    /// (timestamp, min, max, median, mean, sdiv) per sampled timestamp
+    rows: Vec<(usize, usize, usize, f64, f64, f64)>,
+    summary: Summary,
+}
+
+/// One run of a tool on a case, as (case, tool, number, maxpoints)
+type Run<'a> = (&'a String, &'a String, usize, Vec<(usize, usize)>);
+
+/// Sample one `column` of the runs of all tools on `case` and compute the statistics per tool.
+/// Only touches memory, so cases can be processed in parallel.
+fn case_stats<'a>(
+    case: &'a str,
+    column: &'a str,
+    casegroup: &[Run<'a>],
+    end_early: &Endpoint,
+    last_common_point: usize,
+) -> Vec<ToolStats<'a>> {
+    println!("Processing case {} {}: {}", case, column, casegroup.len());
+    let mut timestamps = Vec::new();
+    for (_, _, _, points) in casegroup {
+        timestamps.extend(points.iter().map(|(_, t)| *t));
+    }
+    timestamps.sort();
+    if matches!(end_early, Endpoint::AllMin) {
+        // Dont' sample anything after the shortest run
+        timestamps.retain(|x| x<=&last_common_point);
+    }
+    let least_runtime_per_tool = casegroup.iter().map(|g| (g.1, g.2, g.3.last().unwrap().1)).sorted_by_key(|x| x.0).chunk_by(|x| x.0).into_iter().map(|(tool, toolgroup)| (tool, toolgroup.min_by_key(|y| y.2))).collect::<HashMap<_,_>>();
+    let longest_runtime_per_tool = casegroup.iter().map(|g| (g.1, g.2, g.3.last().unwrap().1)).sorted_by_key(|x| x.0).chunk_by(|x| x.0).into_iter().map(|(tool, toolgroup)| (tool, toolgroup.max_by_key(|y| y.2))).collect::<HashMap<_,_>>();
+    timestamps.dedup();
+    let mut maxpoints_per_tool = casegroup
+        .par_iter()
+        .map(|g| (g.0, g.1, g.2, sample_maxpoints(&g.3, &timestamps)))
+        .collect::<Vec<_>>();
+    maxpoints_per_tool.sort_by_key(|x| x.1); // by tool
+    let mut ret = Vec::new();
+    for (tool, toolgroup) in &maxpoints_per_tool.into_iter().chunk_by(|x| x.1) {
+        let toolgroup = toolgroup.collect::<Vec<_>>();
+        println!("Processing tool {}: {}", tool, toolgroup.len());
+        let mut lowest_common_length = toolgroup
+            .iter()
+            .map(|(_, _, _, points)| points.len())
+            .min()
+            .unwrap();
+        if *end_early == Endpoint::ToolMin {
+            lowest_common_length = timestamps.binary_search(&least_runtime_per_tool[tool].unwrap().2).unwrap();
+        }
+        if *end_early == Endpoint::ToolMax {
+            lowest_common_length = std::cmp::min(lowest_common_length, timestamps.binary_search(&longest_runtime_per_tool[tool].unwrap().2).unwrap());
+        }
+        let time_min_max_med_mean_sdiv : Vec<(usize,usize,usize,f64,f64,f64)> = (0..lowest_common_length)
+            .into_par_iter()
+            .map(|i| {
+                let slice = toolgroup.iter().map(|(_, _, _, p)| p[i].0).collect::<Vec<_>>();
+                assert_eq!(slice.len(), toolgroup.len());
+                (
+                    toolgroup[0].3[i].1,
+                    *slice.iter().min().unwrap_or(&0),
+                    *slice.iter().max().unwrap_or(&0),
+                    median(&slice).unwrap_or(0.0),
+                    mean(&slice).unwrap_or(0.0),
+                    std_deviation(&slice).unwrap_or(0.0),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let runs = toolgroup.iter().map(|(_, _, _, p)| p.as_slice()).collect::<Vec<_>>();
+        let summary = summarize(&time_min_max_med_mean_sdiv, &runs);
+        ret.push(ToolStats { case, tool, column, rows: time_min_max_med_mean_sdiv, summary });
+    }
+    ret
+}
+
+/// (Re)create the combos, columns and summary tables, and the rt_hist table if `rt_hist` is set
+fn create_tables(connection: &Connection, rt_hist: bool) -> rusqlite::Result<()> {
+    connection.execute("DROP TABLE IF EXISTS combos", ())?;
+    connection.execute("CREATE TABLE IF NOT EXISTS combos (casename TEXT, toolname TEXT, fullname TEXT PRIMARY KEY)", ())?;
+    connection.execute("DROP TABLE IF EXISTS summary", ())?;
+    connection.execute("DROP TABLE IF EXISTS columns", ())?;
+    connection.execute("CREATE TABLE IF NOT EXISTS columns (casename TEXT, toolname TEXT, columnname TEXT, fullname TEXT PRIMARY KEY)", ())?;
+    connection.execute("CREATE TABLE IF NOT EXISTS summary (casename TEXT, toolname TEXT, columnname TEXT, runs INTEGER, end_timestamp INTEGER, median REAL, mean REAL, max INTEGER, first_improvement REAL, auc REAL, PRIMARY KEY (casename, toolname, columnname))", ())?;
+    if rt_hist {
+        connection.execute("DROP TABLE IF EXISTS rt_hist", ())?;
+        connection.execute("CREATE TABLE IF NOT EXISTS rt_hist (casename TEXT, toolname TEXT, run INTEGER, response_time INTEGER, count INTEGER, PRIMARY KEY (casename, toolname, run, response_time))", ())?;
+    }
+    Ok(())
+}
+
+/// Insert the statistics into the tables of [`create_tables`], and the sampled watermarks into one table per column unless `summary_only` is set
+fn write_stats(connection: &Connection, stats: &[ToolStats], summary_only: bool) -> rusqlite::Result<()> {
+    for s in stats {
+        let table = table_name(s.case, s.tool, s.column);
+        // combos only lists the global column, the plots compare the tools on it
+        if s.column.is_empty() {
+            connection.execute("INSERT INTO combos (casename, toolname, fullname) VALUES (?, ?, ?)", params![s.case, s.tool, table])?;
+        }
+        connection.execute("INSERT INTO columns (casename, toolname, columnname, fullname) VALUES (?, ?, ?, ?)", params![s.case, s.tool, s.column, table])?;
+        let x = &s.summary;
+        connection.execute(
+            "INSERT INTO summary (casename, toolname, columnname, runs, end_timestamp, median, mean, max, first_improvement, auc) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![s.case, s.tool, s.column, x.runs as i64, x.end_timestamp.map(|t| t as i64), x.median, x.mean, x.max.map(|m| m as i64), x.first_improvement, x.auc],
+        )?;
+        if summary_only {
+            continue;
+        }
+        connection.execute(&format!("DROP TABLE IF EXISTS {}", table), ())?;
+        connection.execute(&format!("CREATE TABLE IF NOT EXISTS {} (timestamp INTEGER PRIMARY KEY, min INTEGER, max INTEGER, median REAL, mean REAL, sdiv REAL)", table), ())?;
+        let mut stmt = connection.prepare(&format!(
+            "INSERT INTO {} (timestamp , min , max , median , mean , sdiv ) VALUES (?, ?, ?, ?, ?, ?)",
+            table
+        ))?;
+        for (timestamp, min, max, median, mean, sdiv) in &s.rows {
+            stmt.execute(params![*timestamp as i64, *min as i64, *max as i64, median, mean, sdiv])?;
+        }
+    }
+    Ok(())
+}
+
 fn main() {
     let conf = Config::parse();
 
     let mut results = Vec::new();
+    let mut skipped_files = Vec::new();
+    let re = regex::Regex::new(r"^(.*)#([0-9]+)\.time$").unwrap();
 
-    if let Err(e) = visit_dirs(&conf.input, &mut results) {
+    if let Err(e) = visit_dirs(&conf.input, "time", &re, &mut results, &mut skipped_files) {
         eprintln!("Error reading directories: {}", e);
     }
+    let mut rt_files = Vec::new();
+    if conf.rt_hist {
+        let re = regex::Regex::new(r"^(.*)#([0-9]+)\.rt_hist$").unwrap();
+        if let Err(e) = visit_dirs(&conf.input, "rt_hist", &re, &mut rt_files, &mut skipped_files) {
+            eprintln!("Error reading directories: {}", e);
+        }
+    }
 
     println!("Files: {:?}", results);
     let mut connection = Connection::open(conf.output).unwrap();
-    connection.execute("DROP TABLE IF EXISTS combos", ()).unwrap();
-    connection.execute("CREATE TABLE IF NOT EXISTS combos (casename TEXT, toolname TEXT, fullname TEXT PRIMARY KEY)", ()).unwrap();
+    create_tables(&connection, conf.rt_hist).unwrap();
 
-    let mut points: Vec<_> = results
+    let parsed: Vec<_> = results
         .par_iter()
-        .map(|(path, fuzzer, case, n)| {
-            (
-                case,
-                fuzzer,
-                n.parse::<usize>().unwrap(),
-                maxpoints_of_file(path).unwrap(),
-            )
-        })
+        .map(|(path, fuzzer, case, n)| (path, case, fuzzer, *n, maxpoints_of_file(path)))
         .collect();
-    let mut last_common_point = points.iter().map(|x| x.3.last().expect(&format!("Missing maxpoint for {}", x.0)).1).min().unwrap();
-    points.sort_by_key(|x| x.0); // by case for grouping
-    for (case, casegroup) in &points.into_iter().chunk_by(|x| x.0) {
-        let casegroup = casegroup.collect::<Vec<_>>();
-        let last_case_point = casegroup.iter().map(|x| x.3.last().unwrap().1).min().unwrap();
-        println!("Processing case {}: {}", case, casegroup.len());
-        let mut timestamps = Vec::new();
-        for (_, _, _, points) in &casegroup {
-            timestamps.extend(points.iter().map(|(_, t)| *t));
+    let mut skipped_lines = 0;
+    let mut points = Vec::new();
+    let mut manifests = Vec::new();
+    for (path, case, fuzzer, n, res) in parsed {
+        let manifest = if conf.require_manifest {
+            match manifest_of(path) {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    eprintln!("WARNING Skipped {}: no usable manifest, {}", path.display(), e);
+                    skipped_files.push(path.clone());
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        match res {
+            Ok((columns, skipped)) => {
+                skipped_lines += skipped;
+                points.extend(columns.into_iter().map(|(column, p)| (case, fuzzer, n, p, column)));
+                manifests.extend(manifest.map(|m| (case, path.as_path(), m)));
+            }
+            Err(e) => {
+                eprintln!("WARNING Skipped {}: {}", path.display(), e);
+                skipped_files.push(path.clone());
+            }
         }
-        timestamps.sort();
-        if matches!(conf.end_early, Endpoint::AllMin) {
-            // Dont' sample anything after the shortest run
-            timestamps = timestamps.into_iter().filter(|x| x<=&last_common_point).collect();
+    }
+    // results of different kernels or timing models can not be compared
+    let mut rejected_cases = HashSet::new();
+    for (case, group) in &manifests.iter().sorted_by_key(|x| x.0).chunk_by(|x| x.0) {
+        let group: Vec<_> = group.map(|(_, path, m)| (*path, m)).collect();
+        if let Some(diff) = manifest_mismatch(&group) {
+            eprintln!("ERROR Not aggregating case {}, the manifests disagree:\n{}", case, diff);
+            rejected_cases.insert(case);
         }
-        let least_runtime_per_tool = casegroup.iter().map(|g| (g.1, g.2, g.3.last().unwrap().1)).sorted_by_key(|x| x.0).chunk_by(|x| x.0).into_iter().map(|(tool, toolgroup)| (tool, toolgroup.min_by_key(|y| y.2))).collect::<HashMap<_,_>>();
-        let longest_runtime_per_tool = casegroup.iter().map(|g| (g.1, g.2, g.3.last().unwrap().1)).sorted_by_key(|x| x.0).chunk_by(|x| x.0).into_iter().map(|(tool, toolgroup)| (tool, toolgroup.max_by_key(|y| y.2))).collect::<HashMap<_,_>>();
-        timestamps.dedup();
-        let mut maxpoints_per_tool = casegroup
-            .par_iter()
-            .map(|g| (g.0, g.1, g.2, sample_maxpoints(&g.3, &timestamps)))
-            .collect::<Vec<_>>();
-        maxpoints_per_tool.sort_by_key(|x| x.1); // by tool
-        for (tool, toolgroup) in &maxpoints_per_tool.into_iter().chunk_by(|x| x.1) {
-            let toolgroup = toolgroup.collect::<Vec<_>>();
-            println!("Processing tool {}: {}", tool, toolgroup.len());
-            let mut lowest_common_length = toolgroup
-                .iter()
-                .map(|(_, _, _, points)| points.len())
-                .min()
-                .unwrap();
-            if conf.end_early == Endpoint::ToolMin {
-                lowest_common_length = timestamps.binary_search(&least_runtime_per_tool[tool].unwrap().2).unwrap();
+    }
+    points.retain(|x| !rejected_cases.contains(&x.0));
+    if points.is_empty() {
+        eprintln!("No valid .time files found");
+        return;
+    }
+    let last_common_point = points.iter().map(|x| x.3.last().unwrap_or_else(|| panic!("Missing maxpoint for {}", x.0)).1).min().unwrap();
+    points.sort_by(|a, b| (a.0, &a.4).cmp(&(b.0, &b.4))); // by case and column for grouping
+    let casegroups: Vec<(&String, String, Vec<_>)> = points
+        .into_iter()
+        .chunk_by(|x| (x.0, x.4.clone()))
+        .into_iter()
+        .map(|((case, column), casegroup)| (case, column, casegroup.map(|(case, tool, n, p, _)| (case, tool, n, p)).collect()))
+        .collect();
+    let stats: Vec<ToolStats> = casegroups
+        .par_iter()
+        .flat_map_iter(|(case, column, casegroup)| case_stats(case, column, casegroup, &conf.end_early, last_common_point))
+        .collect();
+
+    // Save to db, all tables in one transaction
+    let transaction = connection.transaction().unwrap();
+    write_stats(&transaction, &stats, conf.summary_only).unwrap();
+    for (path, fuzzer, case, n) in rt_files.iter().filter(|x| !rejected_cases.contains(&x.2)) {
+        match rt_hist_of_file(path) {
+            Ok((hist, skipped)) => {
+                skipped_lines += skipped;
+                let mut stmt = transaction.prepare("INSERT INTO rt_hist (casename, toolname, run, response_time, count) VALUES (?, ?, ?, ?, ?)").unwrap();
+                for (rt, count) in hist {
+                    stmt.execute(params![case, fuzzer, *n as i64, rt as i64, count as i64]).unwrap();
+                }
             }
-            if conf.end_early == Endpoint::ToolMax {
-                lowest_common_length = std::cmp::min(lowest_common_length, timestamps.binary_search(&longest_runtime_per_tool[tool].unwrap().2).unwrap());
+            Err(e) => {
+                eprintln!("WARNING Skipped {}: {}", path.display(), e);
+                skipped_files.push(path.clone());
             }
-            let time_min_max_med_mean_sdiv : Vec<(usize,usize,usize,f64,f64,f64)> = (0..lowest_common_length)
-                .into_par_iter()
-                .map(|i| {
-                    let slice = toolgroup.iter().map(|(_, _, _, p)| p[i].0).collect::<Vec<_>>();
-                    assert_eq!(slice.len(), toolgroup.len());
-                    (
-                        toolgroup[0].3[i].1,
-                        *slice.iter().min().unwrap_or(&0),
-                        *slice.iter().max().unwrap_or(&0),
-                        median(&slice).unwrap_or(0.0),
-                        mean(&slice).unwrap_or(0.0),
-                        std_deviation(&slice).unwrap_or(0.0),
-                    )
-                })
-                .collect::<Vec<_>>();
+        }
+    }
+    transaction.commit().unwrap();
+    if skipped_lines > 0 || !skipped_files.is_empty() {
+        println!("Skipped {} malformed lines and {} files:", skipped_lines, skipped_files.len());
+        for f in &skipped_files {
+            println!("  {}", f.display());
+        }
+    }
+    if !rejected_cases.is_empty() {
+        println!("Skipped cases with disagreeing manifests: {}", rejected_cases.iter().sorted().join(", "));
+    }
+}
 
-            // Save to db
-            connection.execute("INSERT INTO combos (casename, toolname, fullname) VALUES (?, ?, ?)", (case, tool, format!("{}${}",case, tool))).unwrap();
-            connection.execute(&format!("DROP TABLE IF EXISTS {}${}", case, tool), ()).unwrap();
-            connection.execute(&format!("CREATE TABLE IF NOT EXISTS {}${} (timestamp INTEGER PRIMARY KEY, min INTEGER, max INTEGER, median REAL, mean REAL, sdiv REAL)", case, tool), ()).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
 
-            // Start a transaction
-            let transaction = connection.transaction().unwrap();
+    /// Write `content` to a fresh file in the temp dir, named `name` below a directory unique to the test
+    fn temp_file(test: &str, name: &str, content: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("number_cruncher-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
 
-            let mut stmt = transaction.prepare(&format!(
-                "INSERT INTO {}${} (timestamp , min , max , median , mean , sdiv ) VALUES (?, ?, ?, ?, ?, ?)",
-                case, tool
-            )).unwrap();
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let path = temp_file("malformed_lines", "case#0.time", b"5,0\nfoo,bar\n7,10\n8\n\n9,12,3\n6,20\n4,15\n\xff\xfe,30\n8,40\n");
+        let (columns, skipped) = maxpoints_of_file(&path).unwrap();
+        // the bad numbers, the short line, the extra column, the decreasing timestamp and the invalid utf8
+        assert_eq!(skipped, 5);
+        assert_eq!(columns, vec![(String::new(), vec![(5, 0), (7, 10), (8, 40)])]);
+    }
 
-            for (timestamp, min, max, median, mean, sdiv) in time_min_max_med_mean_sdiv {
-                stmt.execute([(timestamp as i64).to_string(), (min as i64).to_string(), (max as i64).to_string(), median.to_string(), mean.to_string(), sdiv.to_string()]).unwrap();
+    #[test]
+    fn files_without_valid_lines_are_errors() {
+        let path = temp_file("no_valid_lines", "case#0.time", b"# icount shift 4\nfoo\n1\n");
+        let err = maxpoints_of_file(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("2 malformed"), "{}", err);
+        let missing = path.with_file_name("case#1.time");
+        assert_eq!(maxpoints_of_file(&missing).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn misnamed_files_are_skipped() {
+        let good = temp_file("misnamed_files", "case#3.time", b"1,0\n");
+        temp_file("misnamed_files", "case.time", b"1,0\n");
+        temp_file("misnamed_files", "case#99999999999999999999999.time", b"1,0\n");
+        temp_file("misnamed_files", "case#4.other", b"1,0\n");
+        let re = regex::Regex::new(r"^(.*)#([0-9]+)\.time$").unwrap();
+        let (mut results, mut skipped) = (Vec::new(), Vec::new());
+        visit_dirs(good.parent().unwrap(), "time", &re, &mut results, &mut skipped).unwrap();
+        let dir_name = good.parent().unwrap().file_name().unwrap().to_str().unwrap().to_string();
+        assert_eq!(results, vec![(good.clone(), dir_name, "case".to_string(), 3)]);
+        skipped.sort();
+        assert_eq!(skipped, vec![good.with_file_name("case#99999999999999999999999.time"), good.with_file_name("case.time")]);
+    }
+
+    /// The linear scan `sample_maxpoints` used before, correct for sorted samples and points starting at timestamp 0
+    fn sample_maxpoints_linear(points: &[(usize, usize)], samples: &[usize]) -> Vec<(usize, usize)> {
+        let mut todo = samples.iter().peekable();
+        let mut ret = Vec::new();
+        for i in 0..points.len() {
+            while let Some(&&peek) = todo.peek() {
+                if peek >= points[i].1 && (i + 1 >= points.len() || peek < points[i + 1].1) {
+                    ret.push((points[i].0, peek));
+                    todo.next();
+                } else {
+                    break;
+                }
             }
-            drop(stmt);
+        }
+        ret
+    }
+
+    /// Watermark points as written by `maxpoints_of_file`: starting at timestamp 0, values and timestamps non-decreasing
+    fn arb_maxpoints() -> impl Strategy<Value = Vec<(usize, usize)>> {
+        prop::collection::vec((0..10usize, 0..50usize), 1..20).prop_map(|steps| {
+            let (mut value, mut timestamp) = (0, 0);
+            steps
+                .into_iter()
+                .enumerate()
+                .map(|(i, (dv, dt))| {
+                    if i > 0 {
+                        value += dv;
+                        timestamp += dt;
+                    }
+                    (value, timestamp)
+                })
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn sample_maxpoints_matches_linear_scan(points in arb_maxpoints(), mut samples in prop::collection::vec(0..1200usize, 0..40)) {
+            samples.sort();
+            prop_assert_eq!(sample_maxpoints(&points, &samples), sample_maxpoints_linear(&points, &samples));
+        }
+
+        #[test]
+        fn sample_maxpoints_keeps_every_sample(points in arb_maxpoints(), samples in prop::collection::vec(0..1200usize, 0..40)) {
+            let sampled = sample_maxpoints(&points, &samples);
+            prop_assert_eq!(sampled.iter().map(|x| x.1).collect::<Vec<_>>(), samples);
+        }
+    }
+
+    #[test]
+    fn sample_maxpoints_edge_cases() {
+        // nothing measured yet
+        assert_eq!(sample_maxpoints(&[], &[0, 5]), vec![(0, 0), (0, 5)]);
+        assert_eq!(sample_maxpoints(&[(3, 0)], &[]), vec![]);
+        // a single point holds from its timestamp on, and is 0 before it
+        assert_eq!(sample_maxpoints(&[(3, 10)], &[0, 9, 10, 100]), vec![(0, 0), (0, 9), (3, 10), (3, 100)]);
+        // more samples than points, including samples past the end of the run
+        let points = [(1, 0), (4, 20)];
+        assert_eq!(
+            sample_maxpoints(&points, &[0, 5, 19, 20, 21, 1000]),
+            vec![(1, 0), (1, 5), (1, 19), (4, 20), (4, 21), (4, 1000)]
+        );
+    }
 
-            // Commit the transaction
-            transaction.commit().unwrap();
+    /// All rows of all tables, sorted, as text
+    fn dump(connection: &Connection) -> Vec<String> {
+        let tables: Vec<String> = connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name").unwrap()
+            .query_map((), |r| r.get(0)).unwrap()
+            .collect::<rusqlite::Result<_>>().unwrap();
+        let mut ret = Vec::new();
+        for table in tables {
+            let mut stmt = connection.prepare(&format!("SELECT * FROM \"{}\"", table)).unwrap();
+            let n = stmt.column_count();
+            let mut rows: Vec<String> = stmt
+                .query_map((), |r| {
+                    (0..n).map(|i| r.get::<_, rusqlite::types::Value>(i).map(|v| format!("{:?}", v))).collect::<rusqlite::Result<Vec<_>>>()
+                }).unwrap()
+                .map(|r| format!("{}: {}", table, r.unwrap().join(",")))
+                .collect();
+            rows.sort();
+            ret.extend(rows);
+        }
+        ret
+    }
+
+    #[test]
+    fn parallel_stats_match_sequential() {
+        let cases = ["caseA".to_string(), "caseB".to_string()];
+        let tools = ["tool1".to_string(), "tool2".to_string()];
+        let mut casegroups: Vec<(&String, String, Vec<Run>)> = Vec::new();
+        for (c, case) in cases.iter().enumerate() {
+            for column in ["", "taskA"] {
+                let mut runs = Vec::new();
+                for (t, tool) in tools.iter().enumerate() {
+                    for n in 0..3 {
+                        let points = (0..5).map(|i| (10 * i + c + t + n, 100 * i + 7 * n)).chain(std::iter::once((50 + n, 1000))).collect();
+                        runs.push((case, tool, n, points));
+                    }
+                }
+                casegroups.push((case, column.to_string(), runs));
+            }
+        }
+        let last_common_point = 1000;
+        for end_early in [Endpoint::Max, Endpoint::AllMin, Endpoint::ToolMin, Endpoint::ToolMax] {
+            let parallel: Vec<ToolStats> = casegroups
+                .par_iter()
+                .flat_map_iter(|(case, column, casegroup)| case_stats(case, column, casegroup, &end_early, last_common_point))
+                .collect();
+            let sequential: Vec<ToolStats> = casegroups
+                .iter()
+                .flat_map(|(case, column, casegroup)| case_stats(case, column, casegroup, &end_early, last_common_point))
+                .collect();
+            let mut dumps = Vec::new();
+            for stats in [&parallel, &sequential] {
+                let mut connection = Connection::open_in_memory().unwrap();
+                create_tables(&connection, false).unwrap();
+                let transaction = connection.transaction().unwrap();
+                write_stats(&transaction, stats, false).unwrap();
+                transaction.commit().unwrap();
+                dumps.push(dump(&connection));
+            }
+            assert!(dumps[0].iter().any(|x| x.starts_with("caseB$tool2$taskA:")));
+            assert_eq!(dumps[0], dumps[1]);
         }
     }
 }
@@ -1,11 +1,13 @@
 use hashbrown::HashMap;
 use std::borrow::Cow;
+use std::fmt::Display;
 use std::path::PathBuf;
 use std::fs;
-use fret::systemstate::{target_os::SystemTraceData, target_os::freertos::FreeRTOSTraceMetadata, target_os::SystemState, target_os::TaskControlBlock};
+use fret::systemstate::{helpers::input_usage_csv, target_os::intervals::isr_activations, target_os::{compute_hash, SystemTraceData, SystemState, TaskControlBlock}, target_os::freertos::FreeRTOSTraceMetadata, target_os::osek::OSEKTraceMetadata};
 use std::io::Write;
 use clap::Parser;
 use itertools::Itertools;
+use serde::Serialize;
 
 #[derive(Parser)]
 struct Config {
@@ -13,6 +15,10 @@ struct Config {
     #[arg(short, long, value_name = "FILE")]
     input_trace: PathBuf,
 
+    /// Target system which recorded the trace
+    #[arg(long, value_name = "OS", default_value = "freertos", value_parser = ["freertos", "osek"])]
+    target_os: String,
+
     /// Output for activations
     #[arg(short, long, value_name = "FILE")]
     activation: Option<PathBuf>,
@@ -32,15 +38,131 @@ struct Config {
     /// Translate times to microseconds
     #[arg(short, long)]
     micros: bool,
+
+    /// icount shift the trace was recorded with
+    #[arg(long, value_name = "SHIFT", default_value_t = fret::time::clock::DEFAULT_QEMU_ICOUNT_SHIFT)]
+    icount_shift: u32,
+
+    /// Output which input bytes are read by which task and abb
+    #[arg(long, value_name = "FILE", requires = "input_base")]
+    input_usage: Option<PathBuf>,
+
+    /// Address of FUZZ_INPUT in the kernel, decimal or 0x-prefixed hex
+    #[arg(long, value_name = "ADDR", value_parser = parse_addr)]
+    input_base: Option<u32>,
+
+    /// Size of FUZZ_INPUT in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = 1024)]
+    input_size: usize,
+
+    /// Output tasks, execution segments and jobs as a single json document
+    #[arg(short, long, value_name = "FILE")]
+    json: Option<PathBuf>,
+
+    /// Output the ticks at which each interrupt source fired, one row per interrupt
+    #[arg(long, value_name = "FILE")]
+    interrupts: Option<PathBuf>,
+
+    /// Response time deadline per task in ticks, formatted as "name#ticks;..." like TASK_DEADLINES
+    #[arg(short, long, value_name = "MAP", value_parser = parse_deadlines)]
+    deadline: Option<HashMap<String, u64>>,
+}
+
+fn parse_addr(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+fn parse_deadlines(s: &str) -> Result<HashMap<String, u64>, String> {
+    s.split(';').filter(|x| !x.is_empty()).map(|x| {
+        let (name, ticks) = x.split_once('#').ok_or(format!("Missing '#' in {}", x))?;
+        Ok((name.to_string(), ticks.parse().map_err(|e| format!("{}: {}", x, e))?))
+    }).collect()
+}
+
+/// Task of the json document
+#[derive(Serialize)]
+struct JsonTask {
+    name: String,
+    priority: u32,
+}
+
+/// Execution interval of the json document, named after the task at level 0 and after the API function or ISR otherwise
+#[derive(Serialize)]
+struct JsonSegment {
+    name: String,
+    level: u8,
+    start: f32,
+    end: f32,
+    abb_start: Option<u32>,
+    state_hash: String,
+}
+
+/// Job of the json document, deadline_missed is only given with --deadline
+#[derive(Serialize)]
+struct JsonJob {
+    name: String,
+    release: f32,
+    response: f32,
+    deadline_missed: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct JsonGantt {
+    /// "us" with --micros, "ticks" otherwise
+    unit: &'static str,
+    tasks: Vec<JsonTask>,
+    segments: Vec<JsonSegment>,
+    jobs: Vec<JsonJob>,
+}
+
+/// Priorities of a task as shown in the charts
+trait TaskPriority {
+    /// Priority the task is running with
+    fn priority(&self) -> u32;
+    /// Priority the task was configured with
+    fn base_priority(&self) -> u32;
+}
+
+impl TaskPriority for fret::systemstate::target_os::freertos::RefinedTCB {
+    fn priority(&self) -> u32 {
+        self.priority
+    }
+    fn base_priority(&self) -> u32 {
+        self.base_priority
+    }
+}
+
+impl TaskPriority for fret::systemstate::target_os::osek::RefinedTCB {
+    fn priority(&self) -> u32 {
+        self.current_priority as u32
+    }
+    fn base_priority(&self) -> u32 {
+        self.base_priority as u32
+    }
 }
 
 fn main() {
     // let args : Vec<String> = env::args().collect();
-    let mut conf = Config::parse();
+    let conf = Config::parse();
+    fret::time::clock::set_qemu_icount_shift(conf.icount_shift);
 
-    let input_path = conf.input_trace;
-    let raw_input = fs::read(input_path).expect("Can not read dumped traces");
+    let raw_input = fret::dump::read_dump(&conf.input_trace).expect("Can not read dumped traces");
+    let input_str = String::from_utf8_lossy(&raw_input);
+    match conf.target_os.as_str() {
+        "osek" => gantt(ron::from_str::<OSEKTraceMetadata>(&input_str).expect("Can not parse trace"), conf),
+        _ => gantt(ron::from_str::<FreeRTOSTraceMetadata>(&input_str).expect("Can not parse trace"), conf),
+    }
+}
 
+fn gantt<T>(trace: T, mut conf: Config)
+where
+    T: SystemTraceData,
+    T::State: Display,
+    <T::State as SystemState>::TCB: TaskPriority,
+{
     let activation_path = conf.activation;
     let instance_path = conf.response;
     let abb_path = conf.per_task;
@@ -57,13 +179,12 @@ fn main() {
 
 
     // Store priority per task
-    let trace : FreeRTOSTraceMetadata = ron::from_str(&String::from_utf8_lossy(&raw_input)).expect("Can not parse HashMap");
-    // task_name -> (abb_addr -> (interval_count, exec_count, exec_time, woet))
-    let mut abb_profile : HashMap<Cow<'static, str>, HashMap<u32, (usize, usize, u64, u64)>> = trace.select_abb_profile(conf.task.clone());
+    // task_name -> (abb_addr -> (interval_count, exec_count, exec_time, woet, symbol))
+    let mut abb_profile : HashMap<Cow<'static, str>, HashMap<u32, (usize, usize, u64, u64, Option<String>)>> = trace.select_abb_profile(conf.task.clone());
     for s in trace.intervals() {
         if s.level == 0 {
             let t = trace.states_map()[&s.start_state].current_task();
-            level_per_task.insert(t.task_name().clone(),t.base_priority);
+            level_per_task.insert(t.task_name().clone(),t.base_priority());
         }
     }
 
@@ -73,6 +194,7 @@ fn main() {
         println!("Limits: {} - {}",limits.start,limits.end);
     }
 
+    let mut segments = Vec::new();
     let mut intervals = trace.intervals().clone();
     activation_file.as_mut().map(|x| writeln!(x,"start,end,prio,name,state_id,state,abb").expect("Could not write to file"));
     for s in intervals.iter_mut() {
@@ -83,40 +205,87 @@ fn main() {
             s.start_tick = s.start_tick.max(l.start);
             s.end_tick = s.end_tick.min(l.end);
         }
-        let start_tick = if conf.micros {s.start_tick as f32 / fret::time::clock::QEMU_ISNS_PER_USEC} else {s.start_tick as f32};
-        let end_tick = if conf.micros {s.end_tick as f32 / fret::time::clock::QEMU_ISNS_PER_USEC} else {s.end_tick as f32};
+        let start_tick = if conf.micros {s.start_tick as f32 / fret::time::clock::qemu_isns_per_usec()} else {s.start_tick as f32};
+        let end_tick = if conf.micros {s.end_tick as f32 / fret::time::clock::qemu_isns_per_usec()} else {s.end_tick as f32};
         let state = &trace.states_map()[&s.start_state];
+        segments.push(JsonSegment {
+            name: if s.level == 0 { state.current_task().task_name().clone() } else { s.start_capture.1.to_string() },
+            level: s.level,
+            start: start_tick,
+            end: end_tick,
+            abb_start: s.abb.as_ref().map(|x| x.get_start()),
+            state_hash: format!("{:X}", compute_hash(state)>>48),
+        });
         if s.level == 0 {
-            activation_file.as_mut().map(|x| writeln!(x,"{},{},{},{},{:X},{},{}",start_tick,end_tick,trace.states_map()[&s.start_state].current_task().priority,trace.states_map()[&s.start_state].current_task().task_name, state.get_hash()>>48, state, s.abb.as_ref().map(|x| x.get_start()).unwrap_or(u32::MAX) ).expect("Could not write to file"));
+            activation_file.as_mut().map(|x| writeln!(x,"{},{},{},{},{:X},{},{}",start_tick,end_tick,state.current_task().priority(),state.current_task().task_name(), compute_hash(state)>>48, state, s.abb.as_ref().map(|x| x.get_start()).unwrap_or(u32::MAX) ).expect("Could not write to file"));
         } else {
-            activation_file.as_mut().map(|x| writeln!(x,"{},{},-{},{},{:X},{},{}",start_tick,end_tick,s.level,s.start_capture.1, state.get_hash()>>48, state, s.abb.as_ref().map(|x| x.get_start()).unwrap_or(u32::MAX)).expect("Could not write to file"));
+            activation_file.as_mut().map(|x| writeln!(x,"{},{},-{},{},{:X},{},{}",start_tick,end_tick,s.level,s.start_capture.1, compute_hash(state)>>48, state, s.abb.as_ref().map(|x| x.get_start()).unwrap_or(u32::MAX)).expect("Could not write to file"));
         }
     }
 
     let mut jobs = trace.jobs().clone();
     /* Write all job instances from release to response */
-    let instance_file = instance_path.map(|x| std::fs::OpenOptions::new()
+    let mut instance_file = instance_path.map(|x| std::fs::OpenOptions::new()
         .read(false)
         .write(true)
         .create(true)
         .append(false)
         .open(x).expect("Could not create file"));
 
-    if let Some(mut file) = instance_file {
-        writeln!(file,"start,end,prio,name").expect("Could not write to file");
-        for s in jobs.iter_mut() {
-            if limits.as_ref().map(|x| !x.contains(&s.release) && !x.contains(&s.response) ).unwrap_or(false) {
+    let mut json_jobs = Vec::new();
+    instance_file.as_mut().map(|x| writeln!(x,"start,end,prio,name,preemptions,preempted_ticks,inferred").expect("Could not write to file"));
+    for s in jobs.iter_mut() {
+        if limits.as_ref().map(|x| !x.contains(&s.release) && !x.contains(&s.response) ).unwrap_or(false) {
+            continue;
+        }
+        // judged on the whole job, before clipping it to the limits
+        let deadline_missed = conf.deadline.as_ref().map(|d| d.get(&s.name).map_or(false, |x| s.response_time() > *x));
+        if let Some(l) = &limits {
+            if s.release > l.end || s.response < l.start {
                 continue;
             }
-            if let Some(l) = &limits {
-                if s.release > l.end || s.response < l.start {
-                    continue;
-                }
-                s.release = s.release.max(l.start);
-                s.response = s.response.min(l.end);
+            s.release = s.release.max(l.start);
+            s.response = s.response.min(l.end);
+        }
+        instance_file.as_mut().map(|x| writeln!(x,"{},{},{},{},{},{},{}",s.release,s.response,level_per_task[&s.name],s.name,s.preemption_count,s.preempted_ticks,s.inferred).expect("Could not write to file"));
+        json_jobs.push(JsonJob {
+            name: s.name.clone(),
+            release: if conf.micros {s.release as f32 / fret::time::clock::qemu_isns_per_usec()} else {s.release as f32},
+            response: if conf.micros {s.response as f32 / fret::time::clock::qemu_isns_per_usec()} else {s.response as f32},
+            deadline_missed,
+        });
+    }
+
+    /* Write tasks, segments and jobs as one json document */
+    if let Some(path) = &conf.json {
+        let doc = JsonGantt {
+            unit: if conf.micros {"us"} else {"ticks"},
+            tasks: level_per_task.iter().sorted_by_key(|x| x.0).map(|(name, priority)| JsonTask { name: name.clone(), priority: *priority }).collect(),
+            segments,
+            jobs: json_jobs,
+        };
+        fs::write(path, serde_json::to_string(&doc).expect("Can not serialize chart")).expect("Could not write to file");
+    }
+
+    /* Write the firing ticks of each interrupt source */
+    if let Some(path) = &conf.interrupts {
+        // older traces do not record the activations, they are derived from the intervals then
+        let activations = trace.isr_activations().filter(|x| !x.is_empty()).cloned().unwrap_or_else(|| isr_activations(trace.intervals()));
+        let mut out = String::from("name,tick\n");
+        for (name, ticks) in activations.iter().sorted_by_key(|x| x.0) {
+            for t in ticks {
+                let tick = if conf.micros {*t as f32 / fret::time::clock::qemu_isns_per_usec()} else {*t as f32};
+                out.push_str(&format!("{},{}\n", name, tick));
             }
-            writeln!(file,"{},{},{},{}",s.release,s.response,level_per_task[&s.name],s.name).expect("Could not write to file");
         }
+        fs::write(path, out).expect("Could not write to file");
+    }
+
+    /* Write the readers of each input byte */
+    if let (Some(path), Some(base)) = (&conf.input_usage, conf.input_base) {
+        let (usage, unused) = trace.input_usage(base, conf.input_size);
+        println!("Input bytes never read: {}/{}", unused.len(), conf.input_size);
+        fs::write(path, input_usage_csv(&usage, &unused)).expect("Could not write to file");
     }
 
     /* Write all abbs per task */
@@ -132,10 +301,10 @@ fn main() {
         if abb_profile.is_empty() {
             return;
         }
-        writeln!(file,"name,addr,active,finish,micros,woet").expect("Could not write to file");
+        writeln!(file,"name,addr,symbol,active,finish,micros,woet").expect("Could not write to file");
         for (name, rest) in abb_profile.iter_mut().sorted_by_key(|x| x.0) {
-            rest.iter().sorted_by_key(|x| x.0).for_each(|(addr, (active, finish, time, woet))| {
-                writeln!(file,"{},{},{},{},{},{}",name,addr,active,finish,if conf.micros {*time as f64 / fret::time::clock::QEMU_ISNS_PER_USEC as f64} else {*time as f64}, if conf.micros {*woet as f64 / fret::time::clock::QEMU_ISNS_PER_USEC as f64} else {*woet as f64}).expect("Could not write to file");
+            rest.iter().sorted_by_key(|x| x.0).for_each(|(addr, (active, finish, time, woet, symbol))| {
+                writeln!(file,"{},{},{},{},{},{},{}",name,addr,symbol.as_deref().unwrap_or(""),active,finish,if conf.micros {*time as f64 / fret::time::clock::qemu_isns_per_usec() as f64} else {*time as f64}, if conf.micros {*woet as f64 / fret::time::clock::qemu_isns_per_usec() as f64} else {*woet as f64}).expect("Could not write to file");
             });
         }
     }
@@ -0,0 +1,295 @@
+use clap::Parser;
+use fret::systemstate::stg::{STGEdge, STGFeedbackState, STGNode};
+use fret::systemstate::target_os::{freertos::FreeRTOSSystem, osek::OSEKSystem, TargetSystem};
+use hashbrown::{HashMap, HashSet};
+use petgraph::graph::{DiGraph, EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+struct Config {
+    /// Serialized STGFeedbackState of each campaign, as read by graph2viz
+    #[arg(value_name = "FILE", required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Dot output file
+    #[arg(short, long, value_name = "FILE", default_value = "stg_merge.dot")]
+    dot: PathBuf,
+
+    /// CSV output file
+    #[arg(short, long, value_name = "FILE", default_value = "stg_merge.csv")]
+    csv: PathBuf,
+
+    /// Only draw nodes found in at most N campaigns and their immediate neighbors
+    #[arg(long, value_name = "N")]
+    only_unique: Option<usize>,
+
+    /// Target system which recorded the graphs
+    #[arg(long, value_name = "OS", default_value = "freertos", value_parser = ["freertos", "osek"])]
+    target_os: String,
+}
+
+/// Union of all campaign graphs. Nodes are identified by [`STGNode::stable_id`], i.e. (state hash, abb hash),
+/// edges by their endpoints, event and name.
+struct MergedGraph<SYS: TargetSystem> {
+    graph: DiGraph<STGNode<SYS>, STGEdge>,
+    node_index: HashMap<String, NodeIndex>,
+    edge_index: HashMap<(NodeIndex, NodeIndex, String), EdgeIndex>,
+    /// Campaigns containing each node
+    node_campaigns: HashMap<NodeIndex, HashSet<usize>>,
+    /// Worst time of each edge per campaign, 0 if the campaign recorded none
+    edge_worst: HashMap<EdgeIndex, HashMap<usize, u64>>,
+    states: HashMap<u64, SYS::State>,
+}
+
+impl<SYS: TargetSystem> MergedGraph<SYS> {
+    fn new() -> Self {
+        Self {
+            graph: DiGraph::new(),
+            node_index: HashMap::new(),
+            edge_index: HashMap::new(),
+            node_campaigns: HashMap::new(),
+            edge_worst: HashMap::new(),
+            states: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, campaign: usize, fbs: STGFeedbackState<SYS>) {
+        let mut local: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        for i in fbs.graph.node_indices() {
+            let node = &fbs.graph[i];
+            let idx = *self
+                .node_index
+                .entry(node.stable_id())
+                .or_insert_with(|| self.graph.add_node(node.clone()));
+            self.node_campaigns.entry(idx).or_default().insert(campaign);
+            local.insert(i, idx);
+        }
+        for e in fbs.graph.edge_references() {
+            let (a, b) = (local[&e.source()], local[&e.target()]);
+            let w = e.weight();
+            let key = (a, b, w.label());
+            let idx = *self
+                .edge_index
+                .entry(key)
+                .or_insert_with(|| self.graph.add_edge(a, b, STGEdge { event: w.event, name: w.name.clone(), ..Default::default() }));
+            // traversals add up over all campaigns
+            self.graph[idx].hit_count += w.hit_count;
+            self.graph[idx].total_time += w.total_time;
+            let worst = w.worst.as_ref().map_or(0, |x| x.0);
+            let entry = self.edge_worst.entry(idx).or_default().entry(campaign).or_insert(0);
+            *entry = u64::max(*entry, worst);
+        }
+        self.states.extend(fbs.systemstate_index);
+    }
+
+    fn node_count_of(&self, i: NodeIndex) -> usize {
+        self.node_campaigns[&i].len()
+    }
+
+    fn edge_count_of(&self, e: EdgeIndex) -> usize {
+        self.edge_worst[&e].len()
+    }
+
+    /// Nodes found in at most `n` campaigns, plus their neighbors
+    fn rare_neighborhood(&self, n: usize) -> HashSet<NodeIndex> {
+        let mut keep = HashSet::new();
+        for i in self.graph.node_indices().filter(|i| self.node_count_of(*i) <= n) {
+            keep.insert(i);
+            keep.extend(self.graph.neighbors_undirected(i));
+        }
+        keep
+    }
+}
+
+/// Fill color by the share of campaigns an element was found in, rare ones stand out
+fn rarity_color(count: usize, total: usize) -> &'static str {
+    if count >= total {
+        "white"
+    } else if count == 1 {
+        "red"
+    } else if count * 2 <= total {
+        "orange"
+    } else {
+        "yellow"
+    }
+}
+
+fn main() {
+    let conf = Config::parse();
+    match conf.target_os.as_str() {
+        "osek" => merge::<OSEKSystem>(&conf),
+        _ => merge::<FreeRTOSSystem>(&conf),
+    }
+}
+
+fn merge<SYS: TargetSystem>(conf: &Config) {
+    let mut merged = MergedGraph::<SYS>::new();
+    for (i, path) in conf.inputs.iter().enumerate() {
+        let raw = fs::read(path).expect("Can not read feedback state");
+        let fbs: STGFeedbackState<SYS> = ron::from_str(&String::from_utf8_lossy(&raw)).expect("Can not parse feedback state");
+        // node ids mix stored state hashes with recomputed ABB hashes, see STGNode::stable_id
+        if let Err(e) = fbs.check_hash_version() {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        eprintln!("{}: {} nodes, {} edges", path.display(), fbs.graph.node_count(), fbs.graph.edge_count());
+        merged.add(i, fbs);
+    }
+    let total = conf.inputs.len();
+    eprintln!("merged: {} nodes, {} edges", merged.graph.node_count(), merged.graph.edge_count());
+
+    let keep = conf.only_unique.map(|n| merged.rare_neighborhood(n));
+    let drawn = |i: &NodeIndex| keep.as_ref().map_or(true, |k| k.contains(i));
+
+    // same node names as the dot dumps of the fuzzer, so both can be compared
+    let escape = |x: String| x.replace('\n', "\\n").replace(';', "\\n").replace('"', "'");
+    let mut dot = String::from("digraph {\n");
+    for i in merged.graph.node_indices().filter(|i| drawn(i)) {
+        let node = &merged.graph[i];
+        let count = merged.node_count_of(i);
+        dot.push_str(&format!(
+            "    {} [ label = \"{}\\n{}/{}\", shape=box, style=filled, fillcolor=\"{}\" ]\n",
+            node.stable_id(),
+            escape(node._pretty_print(&merged.states)),
+            count,
+            total,
+            rarity_color(count, total)
+        ));
+    }
+    for e in merged.graph.edge_references().filter(|e| drawn(&e.source()) && drawn(&e.target())) {
+        let count = merged.edge_count_of(e.id());
+        dot.push_str(&format!(
+            "    {} -> {} [ label = \"{} {}/{}\", color=\"{}\", penwidth={} ]\n",
+            merged.graph[e.source()].stable_id(),
+            merged.graph[e.target()].stable_id(),
+            escape(e.weight()._pretty_print()),
+            count,
+            total,
+            if count >= total { "black" } else { rarity_color(count, total) },
+            1 + 3 * (total - count) / usize::max(total - 1, 1)
+        ));
+    }
+    dot.push_str("}\n");
+    fs::write(&conf.dot, dot).expect("Could not write dot file");
+
+    // nodes list their campaigns with a 1, edges with their worst time in ticks
+    let mut file = fs::File::create(&conf.csv).expect("Could not create file");
+    let campaigns: Vec<String> = conf.inputs.iter().map(|x| x.file_stem().map_or(String::new(), |x| x.to_string_lossy().replace(',', "_"))).collect();
+    writeln!(file, "kind,source,target,label,count,{}", campaigns.join(",")).expect("Could not write to file");
+    for i in merged.graph.node_indices() {
+        let c = &merged.node_campaigns[&i];
+        let cols: Vec<String> = (0..total).map(|x| if c.contains(&x) { String::from("1") } else { String::new() }).collect();
+        writeln!(file, "node,{},,,{},{}", merged.graph[i].stable_id(), c.len(), cols.join(",")).expect("Could not write to file");
+    }
+    for e in merged.graph.edge_references() {
+        let w = &merged.edge_worst[&e.id()];
+        let cols: Vec<String> = (0..total).map(|x| w.get(&x).map_or(String::new(), |t| t.to_string())).collect();
+        writeln!(
+            file,
+            "edge,{},{},{},{},{}",
+            merged.graph[e.source()].stable_id(),
+            merged.graph[e.target()].stable_id(),
+            e.weight().label().replace(',', "_"),
+            w.len(),
+            cols.join(",")
+        )
+        .expect("Could not write to file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fret::systemstate::stg::{StgFeedback, WorstOrigin};
+    use fret::systemstate::target_os::mock::{MockSystem, MockTraceBuilder, MOCK_TASK_START_ISR};
+    use fret::systemstate::target_os::SystemTraceData;
+    use fret::systemstate::CaptureEvent;
+
+    /// T1 runs until it delays at 10, then T2 runs
+    fn t1_delays() -> MockTraceBuilder {
+        MockTraceBuilder::new()
+            .task("T1", 2)
+            .task("T2", 1)
+            .running("T1").ready(&["T2"])
+            .capture(0, CaptureEvent::ISREnd, MOCK_TASK_START_ISR, (0, 0x100))
+            .capture(10, CaptureEvent::APIStart, "vTaskDelay", (0x110, 0x500))
+            .job_done(10, "T1")
+            .running("T2").ready(&[]).delayed(&["T1"])
+            .capture(20, CaptureEvent::APIEnd, "vTaskDelay", (0x510, 0x200))
+    }
+
+    /// Continues [`t1_delays`] with T2 delaying until T1 is woken again
+    fn both_delay() -> MockTraceBuilder {
+        t1_delays()
+            .capture(50, CaptureEvent::APIStart, "vTaskDelay", (0x210, 0x500))
+            .job_done(50, "T2")
+            .running("T1").delayed(&["T2"])
+            .capture(55, CaptureEvent::APIEnd, "vTaskDelay", (0x510, 0x114))
+            .capture(70, CaptureEvent::End, "trigger_Qemu_break", (0x120, 0))
+    }
+
+    fn campaign(trace: MockTraceBuilder) -> STGFeedbackState<MockSystem> {
+        let trace = trace.build();
+        let mut fbs = STGFeedbackState::default();
+        StgFeedback::<MockSystem>::update_stg_interval(trace.intervals(), trace.mem_reads(), trace.states_map(), &mut fbs, WorstOrigin::default());
+        fbs
+    }
+
+    fn node_ids(fbs: &STGFeedbackState<MockSystem>) -> HashSet<String> {
+        fbs.graph.node_indices().map(|i| fbs.graph[i].stable_id()).collect()
+    }
+
+    /// Traversals of each edge by (source, target, label)
+    fn edge_hits<SYS: TargetSystem>(g: &DiGraph<STGNode<SYS>, STGEdge>) -> HashMap<(String, String, String), u64> {
+        g.edge_references().map(|e| ((g[e.source()].stable_id(), g[e.target()].stable_id(), e.weight().label()), e.weight().hit_count)).collect()
+    }
+
+    #[test]
+    fn merge_counts_campaigns_per_node_and_edge() {
+        let first = campaign(both_delay());
+        let second = campaign(t1_delays().capture(30, CaptureEvent::End, "trigger_Qemu_break", (0x210, 0)));
+        let ids = [node_ids(&first), node_ids(&second)];
+        let hits = [edge_hits(&first.graph), edge_hits(&second.graph)];
+        assert_ne!(ids[0], ids[1]);
+
+        let mut merged = MergedGraph::<MockSystem>::new();
+        merged.add(0, first);
+        merged.add(1, second);
+        assert_eq!(merged.graph.node_count(), ids[0].union(&ids[1]).count());
+        for i in merged.graph.node_indices() {
+            let id = merged.graph[i].stable_id();
+            assert_eq!(merged.node_count_of(i), ids.iter().filter(|x| x.contains(&id)).count());
+            // nodes of a single campaign and their neighbors are drawn with --only-unique 1
+            if merged.node_count_of(i) == 1 {
+                assert!(merged.rare_neighborhood(1).contains(&i));
+            }
+        }
+
+        let merged_hits = edge_hits(&merged.graph);
+        assert_eq!(merged_hits.len(), hits[0].keys().chain(hits[1].keys()).collect::<HashSet<_>>().len());
+        for e in merged.graph.edge_references() {
+            let key = (merged.graph[e.source()].stable_id(), merged.graph[e.target()].stable_id(), e.weight().label());
+            assert_eq!(merged.edge_count_of(e.id()), hits.iter().filter(|x| x.contains_key(&key)).count());
+            assert_eq!(merged_hits[&key], hits.iter().filter_map(|x| x.get(&key)).sum::<u64>());
+        }
+    }
+
+    #[test]
+    fn merging_a_campaign_twice_adds_up_the_traversals() {
+        let single = campaign(both_delay());
+        let (nodes, hits) = (single.graph.node_count(), edge_hits(&single.graph));
+        let mut merged = MergedGraph::<MockSystem>::new();
+        merged.add(0, single);
+        merged.add(1, campaign(both_delay()));
+        assert_eq!(merged.graph.node_count(), nodes);
+        assert!(merged.graph.node_indices().all(|i| merged.node_count_of(i) == 2));
+        assert!(merged.rare_neighborhood(1).is_empty());
+        let merged_hits = edge_hits(&merged.graph);
+        assert_eq!(merged_hits.len(), hits.len());
+        assert!(hits.iter().all(|(k, h)| merged_hits[k] == 2 * h));
+        assert!(merged.graph.edge_indices().all(|e| merged.edge_count_of(e) == 2));
+    }
+}
@@ -0,0 +1,225 @@
+use hashbrown::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::fs;
+use fret::dump::read_dump;
+use fret::systemstate::{CaptureEvent, target_os::{SystemTraceData, SystemState, TaskControlBlock}, target_os::freertos::FreeRTOSTraceMetadata, target_os::osek::OSEKTraceMetadata};
+use clap::{Parser, ValueEnum};
+use itertools::Itertools;
+use serde::de::DeserializeOwned;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum Format {
+    Csv,
+    Table,
+}
+
+/// Compares two traces, e.g. of the same input before and after a kernel change
+#[derive(Parser)]
+struct Config {
+    /// Trace before the change
+    #[arg(value_name = "OLD")]
+    old: PathBuf,
+
+    /// Trace after the change
+    #[arg(value_name = "NEW")]
+    new: PathBuf,
+
+    /// Target system which recorded the traces
+    #[arg(long, value_name = "OS", default_value = "freertos", value_parser = ["freertos", "osek"])]
+    target_os: String,
+
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    /// Symbol map to match ABBs by function and offset instead of address, one "addr [type] name" per line as printed by nm.
+    /// Given twice, the first one translates OLD and the second one NEW.
+    #[arg(short, long, value_name = "FILE", num_args = 1, action = clap::ArgAction::Append)]
+    symbols: Vec<PathBuf>,
+
+    /// Write the report to FILE instead of stdout
+    #[arg(short, long, value_name = "FILE")]
+    output: Option<PathBuf>,
+}
+
+/// Function start addresses, sorted
+struct SymbolMap(Vec<(u32, String)>);
+
+impl SymbolMap {
+    /// Reads nm output, lines without a hexadecimal address are skipped
+    fn load(path: &PathBuf) -> Self {
+        let raw = fs::read_to_string(path).expect("Can not read symbol map");
+        let mut syms : Vec<(u32, String)> = raw.lines().filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            let addr = u32::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?;
+            Some((addr, fields.last()?.to_string()))
+        }).collect();
+        syms.sort();
+        SymbolMap(syms)
+    }
+
+    /// `addr` as `name+0xoff` of the closest symbol below it
+    fn translate(&self, addr: u32) -> String {
+        match self.0.partition_point(|x| x.0 <= addr) {
+            0 => format!("{:#x}", addr),
+            i => format!("{}+{:#x}", self.0[i-1].1, addr - self.0[i-1].0),
+        }
+    }
+}
+
+/// One line of the report, `old` or `new` is missing if the item only appears in the other trace
+struct Row {
+    section: &'static str,
+    name: String,
+    level: Option<u8>,
+    old: Option<u64>,
+    new: Option<u64>,
+}
+
+impl Row {
+    fn delta(&self) -> Option<i128> {
+        Some(self.new? as i128 - self.old? as i128)
+    }
+}
+
+/// Everything compared of one trace
+struct Summary {
+    /// task -> number of jobs
+    tasks: HashMap<String, usize>,
+    /// task -> worst response time
+    worts: HashMap<String, u64>,
+    /// (start, level) -> (exec time, woet)
+    abbs: HashMap<(String, u8), (u64, u64)>,
+    /// "start_capture -> end_capture" -> number of intervals
+    intervals: HashMap<String, u64>,
+}
+
+fn capture_label(c: &(CaptureEvent, std::borrow::Cow<'static, str>)) -> String {
+    format!("{:?}:{}", c.0, c.1)
+}
+
+fn summarize<T>(trace: &T, symbols: Option<&SymbolMap>) -> Summary
+where
+    T: SystemTraceData,
+{
+    let mut tasks : HashMap<String, usize> = HashMap::new();
+    for j in trace.jobs() {
+        *tasks.entry(j.name.clone()).or_default() += 1;
+    }
+    // tasks which never finished a job still run
+    for i in trace.intervals().iter().filter(|x| x.level == 0) {
+        if let Some(s) = trace.states_map().get(&i.start_state) {
+            tasks.entry(s.current_task().task_name().clone()).or_default();
+        }
+    }
+    let worts = trace.worst_jobs_per_task_by_response_time().into_iter().map(|(k, v)| (k, v.response_time())).collect();
+
+    // one abb instance may be split over several intervals by preemption
+    let mut instances : HashMap<(String, u8, usize), u64> = HashMap::new();
+    for i in trace.intervals() {
+        if let Some(abb) = &i.abb {
+            let start = symbols.map_or_else(|| format!("{:#x}", abb.get_start()), |m| m.translate(abb.get_start()));
+            *instances.entry((start, abb.get_level(), abb.get_instance_id())).or_default() += i.get_exec_time();
+        }
+    }
+    let mut abbs : HashMap<(String, u8), (u64, u64)> = HashMap::new();
+    for ((start, level, _), time) in instances {
+        let e = abbs.entry((start, level)).or_default();
+        e.0 += time;
+        e.1 = e.1.max(time);
+    }
+
+    let mut intervals : HashMap<String, u64> = HashMap::new();
+    for i in trace.intervals() {
+        *intervals.entry(format!("{} -> {}", capture_label(&i.start_capture), capture_label(&i.end_capture))).or_default() += 1;
+    }
+    Summary { tasks, worts, abbs, intervals }
+}
+
+fn compare(old: &Summary, new: &Summary) -> Vec<Row> {
+    let mut rows = Vec::new();
+    let tasks : HashSet<&String> = old.tasks.keys().chain(new.tasks.keys()).collect();
+    for t in tasks.iter().sorted() {
+        let (o, n) = (old.tasks.get(*t), new.tasks.get(*t));
+        if o.is_none() || n.is_none() {
+            rows.push(Row { section: "task", name: t.to_string(), level: Some(0), old: o.map(|x| *x as u64), new: n.map(|x| *x as u64) });
+        }
+    }
+    for t in tasks.iter().sorted() {
+        let (o, n) = (old.worts.get(*t).copied(), new.worts.get(*t).copied());
+        if o.is_some() || n.is_some() {
+            rows.push(Row { section: "wort", name: t.to_string(), level: Some(0), old: o, new: n });
+        }
+    }
+    let abbs : HashSet<&(String, u8)> = old.abbs.keys().chain(new.abbs.keys()).collect();
+    for k in abbs.iter().sorted() {
+        let (o, n) = (old.abbs.get(*k), new.abbs.get(*k));
+        rows.push(Row { section: "abb_time", name: k.0.clone(), level: Some(k.1), old: o.map(|x| x.0), new: n.map(|x| x.0) });
+    }
+    for k in abbs.iter().sorted() {
+        let (o, n) = (old.abbs.get(*k), new.abbs.get(*k));
+        rows.push(Row { section: "abb_woet", name: k.0.clone(), level: Some(k.1), old: o.map(|x| x.1), new: n.map(|x| x.1) });
+    }
+    let intervals : HashSet<&String> = old.intervals.keys().chain(new.intervals.keys()).collect();
+    for s in intervals.iter().sorted() {
+        let (o, n) = (old.intervals.get(*s), new.intervals.get(*s));
+        if o.is_none() || n.is_none() {
+            rows.push(Row { section: "interval", name: s.to_string(), level: None, old: o.copied(), new: n.copied() });
+        }
+    }
+    rows
+}
+
+fn opt<T: ToString>(x: Option<T>) -> String {
+    x.map_or(String::new(), |x| x.to_string())
+}
+
+/// Missing values are shown as "-" in the table
+fn cell(x: Option<u64>) -> String {
+    x.map_or("-".to_string(), |x| x.to_string())
+}
+
+fn to_csv(rows: &[Row]) -> String {
+    let mut out = String::from("section,name,level,old,new,delta\n");
+    for r in rows {
+        out.push_str(&format!("{},\"{}\",{},{},{},{}\n", r.section, r.name.replace('"', "\"\""), opt(r.level), opt(r.old), opt(r.new), opt(r.delta())));
+    }
+    out
+}
+
+fn to_table(rows: &[Row]) -> String {
+    let width = rows.iter().map(|r| r.name.len()).max().unwrap_or(0).max(4);
+    let mut out = String::new();
+    for (section, rows) in &rows.iter().chunk_by(|r| r.section) {
+        out.push_str(&format!("== {}\n{:<width$} {:>5} {:>12} {:>12} {:>12}\n", section, "name", "level", "old", "new", "delta", width = width));
+        for r in rows {
+            out.push_str(&format!("{:<width$} {:>5} {:>12} {:>12} {:>12}\n", r.name, opt(r.level), cell(r.old), cell(r.new), r.delta().map_or("-".to_string(), |d| format!("{:+}", d)), width = width));
+        }
+    }
+    out
+}
+
+fn load<T: SystemTraceData + DeserializeOwned>(path: &PathBuf) -> T {
+    let raw = read_dump(path).expect("Can not read dumped trace");
+    ron::from_str::<T>(&String::from_utf8_lossy(&raw)).expect("Can not parse trace")
+}
+
+fn main() {
+    let conf = Config::parse();
+    let maps : Vec<SymbolMap> = conf.symbols.iter().map(SymbolMap::load).collect();
+    let (old_map, new_map) = (maps.first(), maps.get(1).or(maps.first()));
+
+    let (old, new) = match conf.target_os.as_str() {
+        "osek" => (summarize(&load::<OSEKTraceMetadata>(&conf.old), old_map), summarize(&load::<OSEKTraceMetadata>(&conf.new), new_map)),
+        _ => (summarize(&load::<FreeRTOSTraceMetadata>(&conf.old), old_map), summarize(&load::<FreeRTOSTraceMetadata>(&conf.new), new_map)),
+    };
+    let rows = compare(&old, &new);
+    let report = match conf.format {
+        Format::Csv => to_csv(&rows),
+        Format::Table => to_table(&rows),
+    };
+    match &conf.output {
+        Some(path) => fs::write(path, report).expect("Could not write to file"),
+        None => print!("{}", report),
+    }
+}
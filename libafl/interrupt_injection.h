@@ -1,8 +1,9 @@
-#define LIBAFL_MAX_INTERRUPT_SOURCES 6
-#define LIBAFL_MAX_INTERRUPTS 128
-
-extern volatile uint32_t libafl_interrupt_offsets[LIBAFL_MAX_INTERRUPT_SOURCES][LIBAFL_MAX_INTERRUPTS];
-extern volatile uint64_t libafl_num_interrupts[LIBAFL_MAX_INTERRUPT_SOURCES];
+// Buffers are allocated by the fuzzer, sized by its MAX_NUM_INTERRUPT and NUM_INTERRUPT_SOURCES settings
+// Interrupt times of source i: libafl_interrupt_offsets[i * libafl_max_num_interrupts + j]
+extern volatile uint32_t *libafl_interrupt_offsets;
+extern volatile size_t libafl_max_num_interrupts;
+extern volatile uint64_t *libafl_num_interrupts;
+extern volatile size_t libafl_num_interrupt_sources;
 
 static void libafl_timed_int_hook(void*);
 void libafl_clear_int_timer( void );